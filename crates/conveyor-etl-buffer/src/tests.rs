@@ -14,8 +14,8 @@ mod buffer_manager_tests {
                     idempotency_key: id.as_bytes().to_vec(),
                 }),
                 record_type: "test".to_string(),
-                key: format!("key-{}", id).into_bytes(),
-                payload: vec![1, 2, 3],
+                key: format!("key-{}", id).into_bytes().into(),
+                payload: vec![1, 2, 3].into(),
                 metadata: Default::default(),
                 event_time: None,
                 ingestion_time: None,
@@ -1,3 +1,3 @@
 mod settings;
 
-pub use settings::{Settings, ClusterSettings, BufferSettings, GrpcSettings, MetricsSettings};
+pub use settings::{Settings, ClusterSettings, BufferSettings, GrpcSettings, MetricsSettings, PlacementSettings};
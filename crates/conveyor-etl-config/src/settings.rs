@@ -8,6 +8,8 @@ pub struct Settings {
     pub buffer: BufferSettings,
     pub grpc: GrpcSettings,
     pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub placement: PlacementSettings,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -41,6 +43,14 @@ pub struct MetricsSettings {
     pub listen_addr: String,
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PlacementSettings {
+    /// Label partitioned stages' partitions are spread over, such as
+    /// `topology.kubernetes.io/zone`.
+    #[serde(default)]
+    pub topology_key: Option<String>,
+}
+
 impl Default for Settings {
     fn default() -> Self {
         Self {
@@ -67,6 +77,7 @@ impl Default for Settings {
                 enabled: true,
                 listen_addr: "0.0.0.0:9090".to_string(),
             },
+            placement: PlacementSettings::default(),
         }
     }
 }
@@ -204,8 +204,8 @@ mod tests {
                 idempotency_key: vec![],
             }),
             record_type: "test.event".to_string(),
-            key: id.as_bytes().to_vec(),
-            payload: b"{}".to_vec(),
+            key: id.as_bytes().to_vec().into(),
+            payload: b"{}".to_vec().into(),
             metadata: Default::default(),
             event_time: None,
             ingestion_time: None,
@@ -195,8 +195,8 @@ mod tests {
                 idempotency_key: vec![],
             }),
             record_type: "user.event".to_string(),
-            key: id.as_bytes().to_vec(),
-            payload: br#"{"user_id": 123}"#.to_vec(),
+            key: id.as_bytes().to_vec().into(),
+            payload: br#"{"user_id": 123}"#.to_vec().into(),
             metadata: Default::default(),
             event_time: None,
             ingestion_time: None,
@@ -1,16 +1,17 @@
 use std::time::Duration;
 
 use conveyor_etl_routing::{
-    FanInConfig, FanInSource, FanInWatermark, FanOutConfig, FanOutSink, FieldCastType, FieldMapping,
-    LoadBalanceStrategy, LookupConfig, LookupKeyMapping, LookupMissStrategy, MergeStrategy,
-    Pipeline, ServiceSelector, SourceWatermark, Stage, StageType,
+    CastErrorStrategy, Condition, Expression, FanInConfig, FanInSource, FanInWatermark,
+    FanOutConfig, FanOutSink, FieldCastType, FieldMapping, LoadBalanceStrategy, LookupConfig,
+    LookupKeyMapping, LookupMissStrategy, MergeStrategy, Pipeline, RoutingMode, ServiceSelector,
+    SourceWatermark, Stage, StageType, TrafficSplit, VersionWeight,
 };
 
 use crate::error::{DslError, Result};
 use crate::types::{
-    FanInSourceDsl, FanOutSinkDsl, FieldMappingDsl, FieldType, LoadBalanceDsl, LookupConfigDsl,
-    LookupOnMissDsl, MergeStrategyDsl, PipelineManifest, PipelineSpec, ServiceSelectorDsl,
-    StageDsl, StageConfigDsl, StageTypeDsl,
+    CastErrorDsl, ConditionDsl, FanInSourceDsl, FanOutSinkDsl, FieldMappingDsl, FieldType,
+    LoadBalanceDsl, LookupConfigDsl, LookupOnMissDsl, MergeStrategyDsl, PipelineManifest,
+    PipelineSpec, ServiceSelectorDsl, StageDsl, StageConfigDsl, StageTypeDsl, TrafficSplitDsl,
 };
 
 pub fn convert(manifest: &PipelineManifest) -> Result<Pipeline> {
@@ -38,11 +39,13 @@ fn convert_pipeline(
         }
     }
 
-    // Linear pipeline: each stage flows to the next one in order
+    // Linear pipeline: each stage flows to the next one in order, gated by
+    // the downstream stage's condition if it has one
     for i in 0..spec.stages.len().saturating_sub(1) {
         let from_id = &spec.stages[i].id;
-        let to_id = &spec.stages[i + 1].id;
-        pipeline.add_edge(from_id, to_id, None);
+        let to = &spec.stages[i + 1];
+        let condition = to.condition.as_ref().map(convert_condition).transpose()?;
+        pipeline.add_edge(from_id, &to.id, condition);
     }
 
     Ok(pipeline)
@@ -60,6 +63,8 @@ fn add_fan_in_internal_stages(pipeline: &mut Pipeline, stage_dsl: &StageDsl) ->
                 lookup_config: None,
                 fan_in_config: None,
                 fan_out_config: None,
+                routing_mode: RoutingMode::AllMatches,
+                partition_key: None,
             };
             pipeline.add_stage(internal_stage);
             pipeline.add_edge(&format!("{}:{}", stage_dsl.id, source.id), &stage_dsl.id, None);
@@ -80,6 +85,8 @@ fn add_fan_out_internal_stages(pipeline: &mut Pipeline, stage_dsl: &StageDsl) ->
                 lookup_config: None,
                 fan_in_config: None,
                 fan_out_config: None,
+                routing_mode: RoutingMode::AllMatches,
+                partition_key: None,
             };
             pipeline.add_stage(internal_stage);
             pipeline.add_edge(&stage_dsl.id, &format!("{}:{}", stage_dsl.id, sink.id), None);
@@ -92,6 +99,12 @@ fn convert_stage(dsl: &StageDsl) -> Result<Stage> {
     let lookup_config = extract_lookup_config(&dsl.config, dsl.stage_type)?;
     let fan_in_config = convert_fan_in_config(dsl)?;
     let fan_out_config = convert_fan_out_config(dsl)?;
+    let partition_key = dsl
+        .partition_key
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(|e| DslError::InvalidConfig(format!("Invalid partition key: {}", e)))?;
 
     Ok(Stage {
         id: dsl.id.clone(),
@@ -102,6 +115,8 @@ fn convert_stage(dsl: &StageDsl) -> Result<Stage> {
         lookup_config,
         fan_in_config,
         fan_out_config,
+        routing_mode: RoutingMode::AllMatches,
+        partition_key,
     })
 }
 
@@ -121,6 +136,7 @@ fn convert_fan_in_config(dsl: &StageDsl) -> Result<Option<FanInConfig>> {
 
     let watermark = dsl.watermark.as_ref().map(|w| FanInWatermark {
         allowed_lateness: w.allowed_lateness.as_ref().and_then(|s| parse_duration(s)),
+        late_data_stage: w.late_data_stage.clone(),
     });
 
     Ok(Some(FanInConfig {
@@ -191,10 +207,20 @@ fn convert_field_mapping(dsl: &FieldMappingDsl) -> FieldMapping {
         cast_type: dsl.cast.map(convert_field_cast_type),
         default_value: dsl.default.as_ref().map(|v| serde_json::to_vec(v).unwrap_or_default()),
         literal_value: dsl.literal.as_ref().map(|v| serde_json::to_vec(v).unwrap_or_default()),
+        on_error: convert_cast_error(dsl.on_error),
     }
 }
 
-fn convert_field_cast_type(dsl: FieldType) -> FieldCastType {
+fn convert_cast_error(dsl: CastErrorDsl) -> CastErrorStrategy {
+    match dsl {
+        CastErrorDsl::Fail => CastErrorStrategy::Fail,
+        CastErrorDsl::UseDefault => CastErrorStrategy::UseDefault,
+        CastErrorDsl::Skip => CastErrorStrategy::Skip,
+        CastErrorDsl::PassThrough => CastErrorStrategy::PassThrough,
+    }
+}
+
+pub fn convert_field_cast_type(dsl: FieldType) -> FieldCastType {
     match dsl {
         FieldType::String => FieldCastType::String,
         FieldType::Int => FieldCastType::Int,
@@ -294,6 +320,53 @@ fn convert_lookup_on_miss(dsl: LookupOnMissDsl) -> LookupMissStrategy {
     }
 }
 
+pub fn convert_condition(dsl: &ConditionDsl) -> Result<Condition> {
+    let mut conditions = Vec::new();
+
+    if let Some(record_type) = &dsl.record_type {
+        conditions.push(Condition::RecordType(record_type.clone()));
+    }
+    if let Some(key) = &dsl.metadata_exists {
+        conditions.push(Condition::MetadataExists(key.clone()));
+    }
+    if let Some(eq) = &dsl.metadata_equals {
+        conditions.push(Condition::MetadataEquals {
+            key: eq.key.clone(),
+            value: eq.value.clone(),
+        });
+    }
+    if let Some(m) = &dsl.metadata_matches {
+        conditions.push(Condition::MetadataMatch {
+            key: m.key.clone(),
+            pattern: m.pattern.clone(),
+        });
+    }
+    if let Some(and) = &dsl.and {
+        conditions.push(Condition::And(
+            and.iter().map(convert_condition).collect::<Result<_>>()?,
+        ));
+    }
+    if let Some(or) = &dsl.or {
+        conditions.push(Condition::Or(
+            or.iter().map(convert_condition).collect::<Result<_>>()?,
+        ));
+    }
+    if let Some(not) = &dsl.not {
+        conditions.push(Condition::Not(Box::new(convert_condition(not)?)));
+    }
+    if let Some(source) = &dsl.expr {
+        let expr = Expression::compile(source)
+            .map_err(|e| DslError::InvalidCondition(format!("'{}': {}", source, e)))?;
+        conditions.push(Condition::Expression(expr));
+    }
+
+    Ok(match conditions.len() {
+        0 => Condition::Always,
+        1 => conditions.remove(0),
+        _ => Condition::And(conditions),
+    })
+}
+
 fn convert_stage_type(dsl: StageTypeDsl) -> StageType {
     match dsl {
         StageTypeDsl::Source => StageType::Source,
@@ -311,15 +384,30 @@ fn convert_service_selector(dsl: &ServiceSelectorDsl) -> ServiceSelector {
         group_id: dsl.group.clone(),
         labels: dsl.labels.clone(),
         load_balance: convert_load_balance(dsl.load_balance),
+        traffic_split: dsl.traffic_split.as_ref().map(convert_traffic_split),
     }
 }
 
+fn convert_traffic_split(dsl: &TrafficSplitDsl) -> TrafficSplit {
+    TrafficSplit::new(
+        dsl.versions
+            .iter()
+            .map(|v| VersionWeight {
+                version: v.version.clone(),
+                weight: v.weight,
+            })
+            .collect(),
+        dsl.sticky,
+    )
+}
+
 fn convert_load_balance(dsl: LoadBalanceDsl) -> LoadBalanceStrategy {
     match dsl {
         LoadBalanceDsl::RoundRobin => LoadBalanceStrategy::RoundRobin,
         LoadBalanceDsl::LeastConnections => LoadBalanceStrategy::LeastConnections,
         LoadBalanceDsl::WeightedRandom => LoadBalanceStrategy::WeightedRandom,
         LoadBalanceDsl::ConsistentHash => LoadBalanceStrategy::ConsistentHash,
+        LoadBalanceDsl::PeakEwma => LoadBalanceStrategy::PeakEwma,
     }
 }
 
@@ -404,6 +492,135 @@ spec:
         assert_eq!(sink.service_selector.load_balance, LoadBalanceStrategy::LeastConnections);
     }
 
+    #[test]
+    fn test_convert_peak_ewma_strategy() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: lb
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+        load_balance: peak_ewma
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let pipeline = convert(&manifest).unwrap();
+
+        let sink = pipeline.stages.get("sink").unwrap();
+        assert_eq!(sink.service_selector.load_balance, LoadBalanceStrategy::PeakEwma);
+    }
+
+    #[test]
+    fn test_convert_traffic_split() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: canary
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+        traffic_split:
+          sticky: true
+          versions:
+            - version: v1
+              weight: 95
+            - version: v2
+              weight: 5
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let pipeline = convert(&manifest).unwrap();
+
+        let sink = pipeline.stages.get("sink").unwrap();
+        let split = sink.service_selector.traffic_split.as_ref().unwrap();
+        assert!(split.sticky);
+        assert_eq!(split.versions.len(), 2);
+        assert_eq!(split.versions[0].version, "v1");
+        assert_eq!(split.versions[0].weight, 95);
+        assert_eq!(split.total_weight(), 100);
+
+        let source = pipeline.stages.get("source").unwrap();
+        assert!(source.service_selector.traffic_split.is_none());
+    }
+
+    #[test]
+    fn test_convert_stage_condition() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: routed
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: enrich
+      name: Enrich
+      type: transform
+      service:
+        name: enricher
+      condition:
+        expr: "payload.amount > 100"
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+      condition:
+        record_type: order
+        metadata_exists: tenant
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let pipeline = convert(&manifest).unwrap();
+
+        let into_enrich = pipeline.edges.iter().find(|e| e.to_stage == "enrich").unwrap();
+        match &into_enrich.condition {
+            Some(Condition::Expression(expr)) => assert_eq!(expr.source(), "payload.amount > 100"),
+            other => panic!("expected expression condition, got {:?}", other),
+        }
+
+        let into_sink = pipeline.edges.iter().find(|e| e.to_stage == "sink").unwrap();
+        match &into_sink.condition {
+            Some(Condition::And(conditions)) => assert_eq!(conditions.len(), 2),
+            other => panic!("expected and condition, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_convert_condition_rejects_invalid_expression() {
+        let dsl: ConditionDsl = serde_yaml::from_str("expr: \"payload.amount >\"").unwrap();
+        let err = convert_condition(&dsl).unwrap_err();
+        assert!(matches!(err, DslError::InvalidCondition(_)));
+
+        let empty: ConditionDsl = serde_yaml::from_str("{}").unwrap();
+        assert!(matches!(convert_condition(&empty).unwrap(), Condition::Always));
+    }
+
     #[test]
     fn test_convert_parallelism() {
         let yaml = r#"
@@ -424,6 +641,7 @@ spec:
       service:
         group: workers
       parallelism: 8
+      partition_key: payload.customer.id
     - id: sink
       name: Sink
       type: sink
@@ -438,6 +656,10 @@ spec:
 
         let transform = pipeline.stages.get("transform").unwrap();
         assert_eq!(transform.parallelism, 8);
+        assert_eq!(
+            transform.partition_key.as_ref().map(ToString::to_string),
+            Some("payload.customer.id".to_string())
+        );
         assert_eq!(transform.service_selector.group_id, Some("workers".to_string()));
     }
 
@@ -486,6 +708,7 @@ spec:
       type: fan-in
       watermark:
         allowed_lateness: 5m
+        late_data_stage: sink
       sources:
         - id: web
           name: Web Events
@@ -534,6 +757,10 @@ spec:
             config.watermark.as_ref().unwrap().allowed_lateness,
             Some(Duration::from_secs(300))
         );
+        assert_eq!(
+            config.watermark.as_ref().unwrap().late_data_stage.as_deref(),
+            Some("sink")
+        );
 
         let web_source = &config.sources[0];
         assert_eq!(web_source.id, "web");
@@ -605,6 +832,7 @@ spec:
         assert_eq!(archive_sink.field_mappings.len(), 1);
         assert_eq!(archive_sink.field_mappings[0].source_field, Some("event_id".to_string()));
         assert_eq!(archive_sink.field_mappings[0].target_field, "id");
+        assert_eq!(archive_sink.field_mappings[0].on_error, CastErrorStrategy::Fail);
 
         let internal_archive = pipeline.stages.get("distribute:archive").unwrap();
         assert_eq!(internal_archive.stage_type, StageType::Sink);
@@ -615,6 +843,56 @@ spec:
         assert_eq!(internal_realtime.service_selector.service_name, Some("websocket".to_string()));
     }
 
+    #[test]
+    fn test_convert_lookup_stage() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: enrich
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: kafka
+    - id: users
+      name: Users
+      type: lookup
+      service:
+        name: user-cache
+      config:
+        keys:
+          - record_field: user_id
+            lookup_key: id
+        output_prefix: user
+        merge_strategy: nest
+        on_miss: drop
+        timeout_ms: 50
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: s3
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let pipeline = convert(&manifest).unwrap();
+
+        let lookup = pipeline.stages.get("users").unwrap();
+        assert_eq!(lookup.stage_type, StageType::Lookup);
+
+        let config = lookup.lookup_config.as_ref().unwrap();
+        assert_eq!(config.key_fields.len(), 1);
+        assert_eq!(config.key_fields[0].record_field, "user_id");
+        assert_eq!(config.key_fields[0].lookup_key, "id");
+        assert_eq!(config.output_prefix, Some("user".to_string()));
+        assert_eq!(config.merge_strategy, MergeStrategy::Nest);
+        assert_eq!(config.on_miss, LookupMissStrategy::Drop);
+        assert_eq!(config.timeout_ms, 50);
+    }
+
     #[test]
     fn test_parse_duration() {
         assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
@@ -11,7 +11,7 @@ pub use types::*;
 pub use error::{DslError, Result};
 pub use parser::{parse_yaml, parse_file};
 pub use validation::{validate, validate_backup, validate_restore};
-pub use convert::convert;
+pub use convert::{convert, convert_condition, convert_field_cast_type};
 
 pub use manifest::{
     AnyManifest, DlqConfig, GrpcEndpoint, Manifest, Metadata, PipelineManifest,
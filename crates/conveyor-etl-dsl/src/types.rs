@@ -49,6 +49,9 @@ pub struct StageDsl {
     pub service: ServiceSelectorDsl,
     #[serde(default = "default_parallelism")]
     pub parallelism: u32,
+    /// `record_key`, `metadata.<name>` or `payload.<path>`.
+    #[serde(default)]
+    pub partition_key: Option<String>,
     #[serde(default)]
     pub config: Option<StageConfigDsl>,
     #[serde(default)]
@@ -57,6 +60,8 @@ pub struct StageDsl {
     pub sinks: Option<Vec<FanOutSinkDsl>>,
     #[serde(default)]
     pub watermark: Option<FanInWatermarkDsl>,
+    #[serde(default)]
+    pub condition: Option<ConditionDsl>,
 }
 
 fn default_parallelism() -> u32 {
@@ -84,6 +89,21 @@ pub struct ServiceSelectorDsl {
     pub labels: HashMap<String, String>,
     #[serde(default)]
     pub load_balance: LoadBalanceDsl,
+    #[serde(default)]
+    pub traffic_split: Option<TrafficSplitDsl>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficSplitDsl {
+    pub versions: Vec<VersionWeightDsl>,
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionWeightDsl {
+    pub version: String,
+    pub weight: u32,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
@@ -94,6 +114,7 @@ pub enum LoadBalanceDsl {
     LeastConnections,
     WeightedRandom,
     ConsistentHash,
+    PeakEwma,
 }
 
 
@@ -113,6 +134,8 @@ pub struct ConditionDsl {
     pub or: Option<Vec<ConditionDsl>>,
     #[serde(default)]
     pub not: Option<Box<ConditionDsl>>,
+    #[serde(default)]
+    pub expr: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -407,6 +430,16 @@ pub enum TransformConfigDsl {
     Sessionize(SessionizeTransformConfig),
 }
 
+impl TransformConfigDsl {
+    /// Whether the transform keeps state across records and batches.
+    pub fn is_stateful(&self) -> bool {
+        matches!(
+            self,
+            Self::Dedupe(_) | Self::RateLimit(_) | Self::Aggregate(_) | Self::Join(_) | Self::Sessionize(_)
+        )
+    }
+}
+
 // Stateless transforms
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -841,6 +874,8 @@ pub struct SourceWatermarkDsl {
 pub struct FanInWatermarkDsl {
     #[serde(default)]
     pub allowed_lateness: Option<String>,
+    #[serde(default)]
+    pub late_data_stage: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -854,6 +889,18 @@ pub struct FieldMappingDsl {
     pub default: Option<serde_json::Value>,
     #[serde(default)]
     pub literal: Option<serde_json::Value>,
+    #[serde(default)]
+    pub on_error: CastErrorDsl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CastErrorDsl {
+    #[default]
+    Fail,
+    UseDefault,
+    Skip,
+    PassThrough,
 }
 
 // ============================================================================
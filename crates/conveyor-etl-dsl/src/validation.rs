@@ -1,9 +1,12 @@
 use std::collections::HashSet;
 
+use conveyor_etl_routing::{Expression, FieldPath, PartitionKey};
+
 use crate::error::{DslError, Result};
 use crate::types::{
-    BackupDestination, BackupManifest, FanInSourceDsl, FanOutSinkDsl, FieldMappingDsl,
-    PipelineManifest, PipelineSpec, RestoreManifest, StageDsl, StageConfigDsl, StageTypeDsl,
+    BackupDestination, BackupManifest, ConditionDsl, FanInSourceDsl, FanOutSinkDsl,
+    FieldMappingDsl, PipelineManifest, PipelineSpec, RestoreManifest, StageDsl, StageConfigDsl,
+    StageTypeDsl, TransformConfigDsl,
 };
 
 pub fn validate(manifest: &PipelineManifest) -> Result<()> {
@@ -185,6 +188,9 @@ fn validate_stage(name: &str, stage: &StageDsl, all_stage_ids: &HashSet<&str>) -
     }
 
     validate_stage_config(name, stage)?;
+    validate_partition_key(name, stage)?;
+    validate_traffic_split(name, stage)?;
+    validate_stage_conditions(name, stage)?;
     validate_fan_in(name, stage, all_stage_ids)?;
     validate_fan_out(name, stage, all_stage_ids)?;
 
@@ -216,6 +222,104 @@ fn validate_stage_config(name: &str, stage: &StageDsl) -> Result<()> {
     Ok(())
 }
 
+fn validate_partition_key(name: &str, stage: &StageDsl) -> Result<()> {
+    let Some(key) = &stage.partition_key else {
+        return Ok(());
+    };
+
+    let invalid = |message: String| DslError::InvalidStage {
+        pipeline_id: name.to_string(),
+        stage_id: stage.id.clone(),
+        message,
+    };
+
+    if matches!(stage.stage_type, StageTypeDsl::FanIn | StageTypeDsl::FanOut) {
+        return Err(invalid("Fan-in and fan-out stages cannot be partitioned".to_string()));
+    }
+    key.parse::<PartitionKey>()
+        .map_err(|e| invalid(format!("Invalid partition key: {}", e)))?;
+
+    Ok(())
+}
+
+fn validate_traffic_split(name: &str, stage: &StageDsl) -> Result<()> {
+    let Some(split) = &stage.service.traffic_split else {
+        return Ok(());
+    };
+
+    let invalid = |message: String| DslError::InvalidStage {
+        pipeline_id: name.to_string(),
+        stage_id: stage.id.clone(),
+        message,
+    };
+
+    if split.versions.is_empty() {
+        return Err(invalid("Traffic split must list at least one version".to_string()));
+    }
+
+    let mut seen = HashSet::new();
+    for v in &split.versions {
+        if v.version.is_empty() {
+            return Err(invalid("Traffic split version cannot be empty".to_string()));
+        }
+        if !seen.insert(v.version.as_str()) {
+            return Err(invalid(format!("Duplicate traffic split version '{}'", v.version)));
+        }
+    }
+
+    if split.versions.iter().map(|v| v.weight as u64).sum::<u64>() == 0 {
+        return Err(invalid("Traffic split weights cannot all be zero".to_string()));
+    }
+
+    Ok(())
+}
+
+fn validate_stage_conditions(name: &str, stage: &StageDsl) -> Result<()> {
+    let mut conditions: Vec<(&str, &ConditionDsl)> = Vec::new();
+    if let Some(condition) = &stage.condition {
+        conditions.push(("condition", condition));
+    }
+    match &stage.config {
+        Some(StageConfigDsl::Transform(TransformConfigDsl::Filter(filter))) => {
+            conditions.push(("filter condition", &filter.condition));
+        }
+        Some(StageConfigDsl::Transform(TransformConfigDsl::Split(split))) => {
+            for route in &split.routes {
+                conditions.push(("split route condition", &route.condition));
+            }
+        }
+        _ => {}
+    }
+
+    for (location, condition) in conditions {
+        validate_condition(condition).map_err(|message| DslError::InvalidStage {
+            pipeline_id: name.to_string(),
+            stage_id: stage.id.clone(),
+            message: format!("Invalid {}: {}", location, message),
+        })?;
+    }
+
+    Ok(())
+}
+
+fn validate_condition(condition: &ConditionDsl) -> std::result::Result<(), String> {
+    if let Some(source) = &condition.expr {
+        Expression::compile(source).map_err(|e| format!("expression '{}': {}", source, e))?;
+    }
+
+    let nested = condition
+        .and
+        .iter()
+        .chain(condition.or.iter())
+        .flatten()
+        .chain(condition.not.as_deref());
+    for inner in nested {
+        validate_condition(inner)?;
+    }
+
+    Ok(())
+}
+
 fn validate_stage_types(name: &str, spec: &PipelineSpec) -> Result<()> {
     let has_source = spec.stages.iter().any(|s| s.stage_type == StageTypeDsl::Source);
     let has_sink = spec.stages.iter().any(|s| s.stage_type == StageTypeDsl::Sink);
@@ -314,12 +418,35 @@ fn validate_fan_in(name: &str, stage: &StageDsl, all_stage_ids: &HashSet<&str>)
 
             validate_fan_in_source(name, &stage.id, source)?;
         }
+
+        let late_data_stage = stage
+            .watermark
+            .as_ref()
+            .and_then(|w| w.late_data_stage.as_deref());
+        if let Some(late) = late_data_stage {
+            if late == stage.id || !all_stage_ids.contains(late) {
+                return Err(DslError::InvalidStage {
+                    pipeline_id: name.to_string(),
+                    stage_id: stage.id.clone(),
+                    message: format!("Late data stage not found: {}", late),
+                });
+            }
+        }
     }
 
     Ok(())
 }
 
 fn validate_fan_in_source(name: &str, stage_id: &str, source: &FanInSourceDsl) -> Result<()> {
+    if let Some(watermark) = &source.watermark {
+        if let Err(e) = FieldPath::parse(&watermark.event_time_field) {
+            return Err(DslError::InvalidStage {
+                pipeline_id: name.to_string(),
+                stage_id: stage_id.to_string(),
+                message: format!("Invalid event time field in {}: {}", source.id, e),
+            });
+        }
+    }
     if let Some(mappings) = &source.mapping {
         for mapping in mappings {
             validate_field_mapping(name, stage_id, &source.id, mapping)?;
@@ -429,6 +556,16 @@ fn validate_field_mapping(
         });
     }
 
+    for path in mapping.source.iter().chain(std::iter::once(&mapping.target)) {
+        if let Err(e) = FieldPath::parse(path) {
+            return Err(DslError::InvalidStage {
+                pipeline_id: name.to_string(),
+                stage_id: stage_id.to_string(),
+                message: format!("Invalid field path in {}: {}", sub_id, e),
+            });
+        }
+    }
+
     Ok(())
 }
 
@@ -602,6 +739,58 @@ spec:
         assert!(validate(&manifest).is_ok());
     }
 
+    #[test]
+    fn test_fan_in_watermark_is_validated() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: fan-in-late
+spec:
+  stages:
+    - id: unified
+      name: Unified Source
+      type: fan-in
+      watermark:
+        allowed_lateness: 1m
+        late_data_stage: late-events
+      sources:
+        - id: source1
+          name: Source 1
+          service:
+            name: kafka
+          watermark:
+            event_time_field: meta.ts
+        - id: source2
+          name: Source 2
+          service:
+            name: kafka
+          watermark:
+            event_time_field: "events[latest].ts"
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let err = validate(&manifest).unwrap_err().to_string();
+        assert!(err.contains("Invalid event time field in source2"));
+
+        let manifest = parse_yaml(&yaml.replace("events[latest].ts", "ts")).unwrap();
+        let err = validate(&manifest).unwrap_err().to_string();
+        assert!(err.contains("Late data stage not found: late-events"));
+
+        let manifest = parse_yaml(
+            &yaml
+                .replace("events[latest].ts", "ts")
+                .replace("late_data_stage: late-events", "late_data_stage: sink"),
+        )
+        .unwrap();
+        assert!(validate(&manifest).is_ok());
+    }
+
     #[test]
     fn test_fan_in_requires_sources() {
         let yaml = r#"
@@ -749,4 +938,286 @@ spec:
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("Only fan-in"));
     }
+
+    #[test]
+    fn test_valid_traffic_split() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: canary
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: enrich
+      name: Enrich
+      type: transform
+      service:
+        name: enricher
+        traffic_split:
+          sticky: true
+          versions:
+            - version: v1
+              weight: 95
+            - version: v2
+              weight: 5
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        assert!(validate(&manifest).is_ok());
+    }
+
+    #[test]
+    fn test_traffic_split_rejects_duplicate_versions() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: canary
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: enrich
+      name: Enrich
+      type: transform
+      service:
+        name: enricher
+        traffic_split:
+          versions:
+            - version: v1
+              weight: 50
+            - version: v1
+              weight: 50
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let result = validate(&manifest);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Duplicate traffic split version"));
+    }
+
+    #[test]
+    fn test_traffic_split_rejects_zero_weights() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: canary
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: enrich
+      name: Enrich
+      type: transform
+      service:
+        name: enricher
+        traffic_split:
+          versions:
+            - version: v1
+              weight: 0
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let result = validate(&manifest);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("cannot all be zero"));
+    }
+
+    #[test]
+    fn test_partition_key_is_validated() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: orders
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: enrich
+      name: Enrich
+      type: transform
+      service:
+        name: enricher
+      parallelism: 4
+      partition_key: customer_id
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let result = validate(&manifest);
+        assert!(result.unwrap_err().to_string().contains("Invalid partition key"));
+
+        let manifest = parse_yaml(&yaml.replace("customer_id", "metadata.customer_id")).unwrap();
+        assert!(validate(&manifest).is_ok());
+    }
+
+    #[test]
+    fn test_valid_expression_condition() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: large-orders
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+      condition:
+        record_type: order
+        expr: "payload.amount > 100 && payload.currency in ['EUR', 'USD']"
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        assert!(validate(&manifest).is_ok());
+    }
+
+    #[test]
+    fn test_expression_parse_error_reports_position() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: large-orders
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+      condition:
+        or:
+          - record_type: refund
+          - expr: "payload.amount >"
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let err = validate(&manifest).unwrap_err().to_string();
+        assert!(err.contains("Invalid condition"));
+        assert!(err.contains("line 1, column 17"));
+    }
+
+    #[test]
+    fn test_filter_expression_is_validated() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: filtered
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: src
+    - id: filter
+      name: Filter
+      type: transform
+      config:
+        transform_type: filter
+        condition:
+          expr: "unknown_field == 1"
+    - id: sink
+      name: Sink
+      type: sink
+      service:
+        name: snk
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let err = validate(&manifest).unwrap_err().to_string();
+        assert!(err.contains("Invalid filter condition"));
+        assert!(err.contains("unknown identifier 'unknown_field'"));
+    }
+
+    #[test]
+    fn test_field_mapping_paths_are_validated() {
+        let yaml = r#"
+apiVersion: etl.dev/v1
+kind: Pipeline
+metadata:
+  name: nested-mapping
+spec:
+  stages:
+    - id: source
+      name: Source
+      type: source
+      service:
+        name: kafka
+    - id: distribute
+      name: Distribute
+      type: fan-out
+      sinks:
+        - id: archive
+          name: Archive
+          service:
+            name: s3
+          mapping:
+            - source: "items[0].sku"
+              target: /order/sku
+            - source: "items[first].qty"
+              target: order.qty
+              cast: int
+              on_error: use_default
+              default: 0
+        - id: audit
+          name: Audit
+          service:
+            name: audit-log
+"#;
+
+        let manifest = parse_yaml(yaml).unwrap();
+        let err = validate(&manifest).unwrap_err().to_string();
+        assert!(err.contains("Invalid field path in archive"));
+        assert!(err.contains("invalid array index 'first'"));
+    }
 }
@@ -0,0 +1,805 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use prost::Message;
+use tokio::sync::{Mutex, Notify, RwLock};
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::error::{GrpcError, ResultExt};
+
+use conveyor_etl_proto::router::{
+    condition::Condition as ProtoConditionKind, router_admin_server::RouterAdmin,
+    ClusterHealth, Condition as ProtoCondition, CreatePipelineRequest, CreatePipelineResponse,
+    DeletePipelineRequest, DeletePipelineResponse, DisablePipelineRequest,
+    DisablePipelineResponse, EnablePipelineRequest, EnablePipelineResponse,
+    GetClusterStatusRequest, GetClusterStatusResponse, GetMetricsRequest, GetMetricsResponse,
+    GetPipelineRequest, GetPipelineResponse, ListPipelinesRequest, ListPipelinesResponse,
+    LoadBalanceStrategy as ProtoLoadBalanceStrategy, NodeRole, NodeStatus, PipelineConfig,
+    PipelineStatus, RoutingMode as ProtoRoutingMode, ServiceSelector as ProtoServiceSelector,
+    Stage as ProtoStage, StageType as ProtoStageType, TrafficSplit as ProtoTrafficSplit, UpdatePipelineRequest, UpdatePipelineResponse,
+    UpdateTrafficSplitRequest, UpdateTrafficSplitResponse,
+};
+use conveyor_etl_dsl::TransformConfigDsl;
+use conveyor_etl_raft::{ConveyorRaft, RouterCommand, RouterRequest, RouterState};
+use conveyor_etl_registry::LoadBalancer;
+use conveyor_etl_routing::{
+    Condition, Expression, FanInConfig, FanOutConfig, LoadBalanceStrategy, LookupConfig,
+    PartitionKey, Pipeline, RoutingEngine, RoutingMode, ServiceSelector, Stage, StageType,
+    TrafficSplit, VersionWeight,
+};
+
+/// Read-modify-write attempts for a traffic split before giving up on a
+/// pipeline that keeps changing underneath it.
+const TRAFFIC_SPLIT_ATTEMPTS: usize = 3;
+
+pub struct RouterAdminImpl {
+    raft: Arc<ConveyorRaft>,
+    state: Arc<RwLock<RouterState>>,
+    routing_engine: Arc<RwLock<RoutingEngine>>,
+    load_balancer: Arc<LoadBalancer>,
+    reschedule: Option<Arc<Notify>>,
+    /// Version and enabled flag of each pipeline last installed in the
+    /// routing engine.
+    synced: Mutex<HashMap<String, (u64, bool)>>,
+}
+
+impl RouterAdminImpl {
+    pub fn new(
+        raft: Arc<ConveyorRaft>,
+        state: Arc<RwLock<RouterState>>,
+        routing_engine: Arc<RwLock<RoutingEngine>>,
+        load_balancer: Arc<LoadBalancer>,
+    ) -> Self {
+        Self {
+            raft,
+            state,
+            routing_engine,
+            load_balancer,
+            reschedule: None,
+            synced: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Notified after pipeline changes so sidecar assignments are
+    /// recomputed right away.
+    pub fn with_reschedule_trigger(mut self, trigger: Arc<Notify>) -> Self {
+        self.reschedule = Some(trigger);
+        self
+    }
+
+    async fn propose(&self, command: RouterCommand) -> Result<(), Status> {
+        let response = self
+            .raft
+            .client_write(RouterRequest { command })
+            .await
+            .map_err(|e| Status::internal(format!("Raft error: {}", e)))?;
+
+        if !response.data.success {
+            return Err(GrpcError::internal(
+                response.data.error.unwrap_or_else(|| "Command rejected".to_string()),
+            )
+            .into());
+        }
+        if let Some(trigger) = &self.reschedule {
+            trigger.notify_one();
+        }
+        Ok(())
+    }
+
+    async fn load_config(&self, pipeline_id: &str) -> Result<(PipelineConfig, u64), Status> {
+        let state = self.state.read().await;
+        let pipeline = state
+            .pipelines
+            .get(pipeline_id)
+            .ok_or_else(|| GrpcError::pipeline_not_found(pipeline_id))?;
+
+        let config = PipelineConfig::decode(pipeline.config.as_slice()).map_to_status()?;
+        Ok((config, pipeline.version))
+    }
+
+    async fn pipeline_version(&self, pipeline_id: &str) -> u64 {
+        self.state
+            .read()
+            .await
+            .pipelines
+            .get(pipeline_id)
+            .map(|p| p.version)
+            .unwrap_or(0)
+    }
+
+    /// Brings this node's routing engine in line with the applied Raft
+    /// state, so followers pick up changes proposed through the leader.
+    pub async fn sync_pipelines(&self) {
+        let mut synced = self.synced.lock().await;
+        let state = self.state.read().await;
+        let routing_engine = self.routing_engine.read().await;
+        sync_routing_engine(&state, &routing_engine, &mut synced).await;
+    }
+
+    pub async fn run_pipeline_sync(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.sync_pipelines().await;
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl RouterAdmin for RouterAdminImpl {
+    async fn create_pipeline(
+        &self,
+        request: Request<CreatePipelineRequest>,
+    ) -> Result<Response<CreatePipelineResponse>, Status> {
+        let config = request
+            .into_inner()
+            .config
+            .ok_or_else(|| GrpcError::missing_field("config"))?;
+
+        if config.id.is_empty() {
+            return Err(GrpcError::missing_field("config.id").into());
+        }
+
+        pipeline_from_proto(&config)?;
+
+        if self.state.read().await.pipelines.contains_key(&config.id) {
+            return Err(GrpcError::pipeline_already_exists(&config.id).into());
+        }
+
+        info!(pipeline_id = %config.id, stages = config.stages.len(), "Creating pipeline");
+
+        self.propose(RouterCommand::CreatePipeline {
+            pipeline_id: config.id.clone(),
+            name: config.name.clone(),
+            config: config.encode_to_vec(),
+        })
+        .await?;
+
+        if config.enabled {
+            self.propose(RouterCommand::EnablePipeline {
+                pipeline_id: config.id.clone(),
+            })
+            .await?;
+        }
+
+        self.sync_pipelines().await;
+
+        Ok(Response::new(CreatePipelineResponse {
+            success: true,
+            pipeline_id: config.id,
+            error: String::new(),
+        }))
+    }
+
+    async fn update_pipeline(
+        &self,
+        request: Request<UpdatePipelineRequest>,
+    ) -> Result<Response<UpdatePipelineResponse>, Status> {
+        let req = request.into_inner();
+        let mut config = req.config.ok_or_else(|| GrpcError::missing_field("config"))?;
+        config.id = req.pipeline_id.clone();
+
+        pipeline_from_proto(&config)?;
+
+        if !self.state.read().await.pipelines.contains_key(&req.pipeline_id) {
+            return Err(GrpcError::pipeline_not_found(&req.pipeline_id).into());
+        }
+
+        info!(pipeline_id = %req.pipeline_id, "Updating pipeline");
+
+        self.propose(RouterCommand::UpdatePipeline {
+            pipeline_id: req.pipeline_id.clone(),
+            config: config.encode_to_vec(),
+        })
+        .await?;
+
+        self.sync_pipelines().await;
+
+        Ok(Response::new(UpdatePipelineResponse {
+            success: true,
+            version: self.pipeline_version(&req.pipeline_id).await,
+            error: String::new(),
+        }))
+    }
+
+    async fn delete_pipeline(
+        &self,
+        request: Request<DeletePipelineRequest>,
+    ) -> Result<Response<DeletePipelineResponse>, Status> {
+        let req = request.into_inner();
+
+        {
+            let state = self.state.read().await;
+            let pipeline = state
+                .pipelines
+                .get(&req.pipeline_id)
+                .ok_or_else(|| GrpcError::pipeline_not_found(&req.pipeline_id))?;
+
+            if pipeline.enabled && !req.force {
+                return Err(GrpcError::FailedPrecondition {
+                    reason: format!(
+                        "pipeline is enabled, disable it or use force: {}",
+                        req.pipeline_id
+                    ),
+                }
+                .into());
+            }
+        }
+
+        info!(pipeline_id = %req.pipeline_id, force = req.force, "Deleting pipeline");
+
+        self.propose(RouterCommand::DeletePipeline {
+            pipeline_id: req.pipeline_id.clone(),
+        })
+        .await?;
+
+        self.sync_pipelines().await;
+
+        Ok(Response::new(DeletePipelineResponse {
+            success: true,
+            error: String::new(),
+        }))
+    }
+
+    async fn get_pipeline(
+        &self,
+        request: Request<GetPipelineRequest>,
+    ) -> Result<Response<GetPipelineResponse>, Status> {
+        let req = request.into_inner();
+
+        let state = self.state.read().await;
+        let Some(pipeline) = state.pipelines.get(&req.pipeline_id) else {
+            return Ok(Response::new(GetPipelineResponse {
+                found: false,
+                config: None,
+                status: None,
+            }));
+        };
+
+        let mut config = PipelineConfig::decode(pipeline.config.as_slice()).map_to_status()?;
+        config.enabled = pipeline.enabled;
+
+        Ok(Response::new(GetPipelineResponse {
+            found: true,
+            config: Some(config),
+            status: Some(PipelineStatus {
+                enabled: pipeline.enabled,
+                version: pipeline.version,
+                created_at: None,
+                updated_at: None,
+                stage_statuses: HashMap::new(),
+            }),
+        }))
+    }
+
+    async fn list_pipelines(
+        &self,
+        request: Request<ListPipelinesRequest>,
+    ) -> Result<Response<ListPipelinesResponse>, Status> {
+        let req = request.into_inner();
+
+        let state = self.state.read().await;
+        let mut pipelines = Vec::new();
+        for pipeline in state.pipelines.values() {
+            if !pipeline.enabled && !req.include_disabled {
+                continue;
+            }
+            match PipelineConfig::decode(pipeline.config.as_slice()) {
+                Ok(mut config) => {
+                    config.enabled = pipeline.enabled;
+                    pipelines.push(config);
+                }
+                Err(e) => {
+                    warn!(
+                        pipeline_id = %pipeline.pipeline_id,
+                        error = %e,
+                        "Skipping undecodable pipeline"
+                    );
+                }
+            }
+        }
+        pipelines.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(Response::new(ListPipelinesResponse { pipelines }))
+    }
+
+    async fn enable_pipeline(
+        &self,
+        request: Request<EnablePipelineRequest>,
+    ) -> Result<Response<EnablePipelineResponse>, Status> {
+        let req = request.into_inner();
+        self.load_config(&req.pipeline_id).await?;
+
+        info!(pipeline_id = %req.pipeline_id, "Enabling pipeline");
+
+        self.propose(RouterCommand::EnablePipeline {
+            pipeline_id: req.pipeline_id.clone(),
+        })
+        .await?;
+
+        self.sync_pipelines().await;
+
+        Ok(Response::new(EnablePipelineResponse {
+            success: true,
+            error: String::new(),
+        }))
+    }
+
+    async fn disable_pipeline(
+        &self,
+        request: Request<DisablePipelineRequest>,
+    ) -> Result<Response<DisablePipelineResponse>, Status> {
+        let req = request.into_inner();
+        self.load_config(&req.pipeline_id).await?;
+
+        info!(pipeline_id = %req.pipeline_id, drain = req.drain, "Disabling pipeline");
+
+        self.propose(RouterCommand::DisablePipeline {
+            pipeline_id: req.pipeline_id.clone(),
+        })
+        .await?;
+
+        self.sync_pipelines().await;
+
+        Ok(Response::new(DisablePipelineResponse {
+            success: true,
+            pending_records: 0,
+            error: String::new(),
+        }))
+    }
+
+    async fn get_cluster_status(
+        &self,
+        _request: Request<GetClusterStatusRequest>,
+    ) -> Result<Response<GetClusterStatusResponse>, Status> {
+        let metrics = self.raft.metrics().borrow().clone();
+        let leader_id = metrics.current_leader;
+
+        let nodes = metrics
+            .membership_config
+            .nodes()
+            .map(|(node_id, node)| NodeStatus {
+                node_id: *node_id,
+                address: node.addr.clone(),
+                role: if Some(*node_id) == leader_id {
+                    NodeRole::Leader as i32
+                } else {
+                    NodeRole::Follower as i32
+                },
+                healthy: true,
+                match_index: 0,
+                last_contact: None,
+            })
+            .collect();
+
+        let health = if leader_id.is_some() {
+            ClusterHealth::Healthy
+        } else {
+            ClusterHealth::Unhealthy
+        };
+
+        Ok(Response::new(GetClusterStatusResponse {
+            node_id: metrics.id,
+            leader_id: leader_id.unwrap_or(0),
+            term: metrics.current_term,
+            commit_index: metrics.last_log_index.unwrap_or(0),
+            applied_index: metrics.last_applied.map(|l| l.index).unwrap_or(0),
+            nodes,
+            health: health as i32,
+        }))
+    }
+
+    async fn get_metrics(
+        &self,
+        request: Request<GetMetricsRequest>,
+    ) -> Result<Response<GetMetricsResponse>, Status> {
+        let req = request.into_inner();
+
+        let mut metrics = HashMap::new();
+        for (service_name, version, stats) in self.load_balancer.all_version_stats() {
+            let prefix = format!("version.{}.{}", service_name, version);
+            metrics.insert(format!("{}.requests", prefix), stats.requests as f64);
+            metrics.insert(format!("{}.errors", prefix), stats.errors as f64);
+            metrics.insert(format!("{}.error_rate", prefix), stats.error_rate());
+            metrics.insert(format!("{}.avg_latency_ms", prefix), stats.avg_latency_ms());
+        }
+
+        for (id, queue_depth) in self.load_balancer.queue_depths() {
+            metrics.insert(format!("load.{}.queue_depth", id), queue_depth as f64);
+        }
+
+        let routing_engine = self.routing_engine.read().await;
+        for (pipeline_id, stage_id, count) in routing_engine.unmatched_counts() {
+            metrics.insert(
+                format!("routing.{}.{}.unmatched", pipeline_id, stage_id),
+                count as f64,
+            );
+        }
+        for (pipeline_id, stage_id, counts) in routing_engine.late_record_counts() {
+            let prefix = format!("routing.{}.{}", pipeline_id, stage_id);
+            metrics.insert(format!("{}.late_side_output", prefix), counts.side_output as f64);
+            metrics.insert(format!("{}.late_dropped", prefix), counts.dropped as f64);
+        }
+        for (pipeline_id, stage_id, watermark) in routing_engine.fan_in_watermarks() {
+            metrics.insert(
+                format!("routing.{}.{}.watermark_ms", pipeline_id, stage_id),
+                watermark as f64,
+            );
+        }
+
+        if !req.metric_names.is_empty() {
+            metrics.retain(|name, _| req.metric_names.iter().any(|n| name.starts_with(n.as_str())));
+        }
+
+        Ok(Response::new(GetMetricsResponse { metrics }))
+    }
+
+    async fn update_traffic_split(
+        &self,
+        request: Request<UpdateTrafficSplitRequest>,
+    ) -> Result<Response<UpdateTrafficSplitResponse>, Status> {
+        let req = request.into_inner();
+        let split = req.traffic_split.as_ref().map(traffic_split_from_proto);
+        if let Some(split) = &split {
+            split
+                .validate()
+                .map_err(|e| GrpcError::invalid_field("traffic_split", e.to_string()))?;
+        }
+
+        info!(
+            pipeline_id = %req.pipeline_id,
+            stage_id = %req.stage_id,
+            split = ?split,
+            "Updating traffic split"
+        );
+
+        for _ in 0..TRAFFIC_SPLIT_ATTEMPTS {
+            let (mut config, version) = self.load_config(&req.pipeline_id).await?;
+            let stage = config
+                .stages
+                .iter_mut()
+                .find(|s| s.id == req.stage_id)
+                .ok_or_else(|| {
+                    GrpcError::invalid_field(
+                        "stage_id",
+                        format!("stage not found: {}", req.stage_id),
+                    )
+                })?;
+
+            stage
+                .service_selector
+                .get_or_insert_with(ProtoServiceSelector::default)
+                .traffic_split = req.traffic_split.clone();
+
+            let result = self
+                .propose(RouterCommand::UpdatePipelineIfVersion {
+                    pipeline_id: req.pipeline_id.clone(),
+                    expected_version: version,
+                    config: config.encode_to_vec(),
+                })
+                .await;
+
+            match result {
+                Ok(()) => {
+                    self.sync_pipelines().await;
+                    return Ok(Response::new(UpdateTrafficSplitResponse {
+                        success: true,
+                        version: self.pipeline_version(&req.pipeline_id).await,
+                        error: String::new(),
+                    }));
+                }
+                // Someone else updated the pipeline since it was read, so
+                // apply the split to their version instead.
+                Err(_) if self.pipeline_version(&req.pipeline_id).await != version => {
+                    warn!(
+                        pipeline_id = %req.pipeline_id,
+                        version,
+                        "Pipeline changed during traffic split update, retrying"
+                    );
+                }
+                Err(status) => return Err(status),
+            }
+        }
+
+        Err(Status::aborted(format!(
+            "pipeline {} kept changing, traffic split not applied",
+            req.pipeline_id
+        )))
+    }
+}
+
+/// Installs each pipeline whose version or enabled flag changed since the
+/// last sync, and removes the ones no longer in `state`.
+pub(crate) async fn sync_routing_engine(
+    state: &RouterState,
+    routing_engine: &RoutingEngine,
+    synced: &mut HashMap<String, (u64, bool)>,
+) {
+    let removed: Vec<String> = synced
+        .keys()
+        .filter(|pipeline_id| !state.pipelines.contains_key(*pipeline_id))
+        .cloned()
+        .collect();
+    for pipeline_id in removed {
+        routing_engine.remove_pipeline(&pipeline_id).await;
+        synced.remove(&pipeline_id);
+    }
+
+    for (pipeline_id, pipeline_state) in &state.pipelines {
+        let current = (pipeline_state.version, pipeline_state.enabled);
+        if synced.get(pipeline_id) == Some(&current) {
+            continue;
+        }
+
+        let pipeline = PipelineConfig::decode(pipeline_state.config.as_slice())
+            .map_err(|e| e.to_string())
+            .and_then(|config| pipeline_from_proto(&config).map_err(|s| s.message().to_string()));
+        match pipeline {
+            Ok(mut pipeline) => {
+                pipeline.enabled = pipeline_state.enabled;
+                routing_engine.add_pipeline(pipeline).await;
+            }
+            Err(e) => warn!(pipeline_id = %pipeline_id, error = %e, "Failed to load pipeline"),
+        }
+        synced.insert(pipeline_id.clone(), current);
+    }
+}
+
+pub(crate) fn pipeline_from_proto(config: &PipelineConfig) -> Result<Pipeline, Status> {
+    let mut pipeline = Pipeline::new(config.id.clone(), config.name.clone());
+    pipeline.description = config.description.clone();
+    pipeline.enabled = config.enabled;
+    pipeline.metadata = config.metadata.clone();
+    pipeline.dead_letter_stage = non_empty(config.dead_letter_stage.clone());
+
+    for stage in &config.stages {
+        let stage_type = match ProtoStageType::try_from(stage.stage_type) {
+            Ok(ProtoStageType::Source) => StageType::Source,
+            Ok(ProtoStageType::Transform) => StageType::Transform,
+            Ok(ProtoStageType::Sink) => StageType::Sink,
+            Ok(ProtoStageType::FanIn) => StageType::FanIn,
+            Ok(ProtoStageType::FanOut) => StageType::FanOut,
+            Ok(ProtoStageType::Lookup) => StageType::Lookup,
+            _ => {
+                return Err(GrpcError::invalid_field(
+                    "stage_type",
+                    format!("unsupported stage type for stage {}", stage.id),
+                )
+                .into())
+            }
+        };
+
+        if !stage.transform_config.is_empty() {
+            if stage_type != StageType::Transform {
+                return Err(GrpcError::invalid_field(
+                    "transform_config",
+                    format!("stage {} is not a transform stage", stage.id),
+                )
+                .into());
+            }
+            serde_json::from_str::<TransformConfigDsl>(&stage.transform_config).map_err(|e| {
+                GrpcError::invalid_field("transform_config", format!("stage {}: {}", stage.id, e))
+            })?;
+        }
+
+        let (fan_in_config, fan_out_config) = fan_config_from_proto(stage, stage_type)?;
+        let partition_key = partition_key_from_proto(stage, stage_type)?;
+        let lookup_config = lookup_config_from_proto(stage, stage_type)?;
+
+        let selector = stage.service_selector.clone().unwrap_or_default();
+        let traffic_split = selector.traffic_split.as_ref().map(traffic_split_from_proto);
+        if let Some(split) = &traffic_split {
+            split
+                .validate()
+                .map_err(|e| GrpcError::invalid_field("traffic_split", e.to_string()))?;
+        }
+
+        pipeline.add_stage(Stage {
+            id: stage.id.clone(),
+            name: stage.name.clone(),
+            stage_type,
+            service_selector: ServiceSelector {
+                service_name: non_empty(selector.service_name),
+                group_id: non_empty(selector.group_id),
+                labels: selector.labels,
+                load_balance: load_balance_from_proto(selector.load_balance),
+                traffic_split,
+            },
+            parallelism: stage.parallelism.max(1),
+            lookup_config,
+            fan_in_config,
+            fan_out_config,
+            routing_mode: routing_mode_from_proto(stage.routing_mode),
+            partition_key,
+        });
+    }
+
+    for edge in &config.edges {
+        let condition = edge.condition.as_ref().map(condition_from_proto).transpose()?;
+        pipeline.add_edge(&edge.from_stage, &edge.to_stage, condition);
+    }
+
+    for stage in &config.stages {
+        for rule in &stage.routing_rules {
+            if rule.is_default {
+                if rule.condition.as_ref().is_some_and(|c| c.condition.is_some()) {
+                    return Err(GrpcError::invalid_field(
+                        "routing_rules",
+                        format!("default rule {} on stage {} has a condition", rule.name, stage.id),
+                    )
+                    .into());
+                }
+                for target in &rule.target_stages {
+                    pipeline.add_default_edge(&stage.id, target);
+                }
+                continue;
+            }
+
+            let condition = rule.condition.as_ref().map(condition_from_proto).transpose()?;
+            for target in &rule.target_stages {
+                pipeline.add_edge_with_priority(
+                    &stage.id,
+                    target,
+                    condition.clone(),
+                    rule.priority,
+                );
+            }
+        }
+    }
+
+    Ok(pipeline)
+}
+
+/// Parses the `fan_config` a fan-in or fan-out stage must carry.
+fn fan_config_from_proto(
+    stage: &ProtoStage,
+    stage_type: StageType,
+) -> Result<(Option<FanInConfig>, Option<FanOutConfig>), Status> {
+    let invalid = |e: serde_json::Error| {
+        GrpcError::invalid_field("fan_config", format!("stage {}: {}", stage.id, e))
+    };
+    let config = stage.fan_config.as_str();
+    match stage_type {
+        StageType::FanIn | StageType::FanOut if config.is_empty() => Err(GrpcError::invalid_field(
+            "fan_config",
+            format!("stage {} needs a fan_config", stage.id),
+        )
+        .into()),
+        StageType::FanIn => Ok((Some(serde_json::from_str(config).map_err(invalid)?), None)),
+        StageType::FanOut => Ok((None, Some(serde_json::from_str(config).map_err(invalid)?))),
+        _ if !config.is_empty() => Err(GrpcError::invalid_field(
+            "fan_config",
+            format!("stage {} is not a fan-in or fan-out stage", stage.id),
+        )
+        .into()),
+        _ => Ok((None, None)),
+    }
+}
+
+/// Parses the `lookup_config` a lookup stage must carry.
+fn lookup_config_from_proto(
+    stage: &ProtoStage,
+    stage_type: StageType,
+) -> Result<Option<LookupConfig>, Status> {
+    match stage_type {
+        StageType::Lookup if stage.lookup_config.is_empty() => Err(GrpcError::invalid_field(
+            "lookup_config",
+            format!("stage {} needs a lookup_config", stage.id),
+        )
+        .into()),
+        StageType::Lookup => serde_json::from_str(&stage.lookup_config).map(Some).map_err(|e| {
+            GrpcError::invalid_field("lookup_config", format!("stage {}: {}", stage.id, e)).into()
+        }),
+        _ if !stage.lookup_config.is_empty() => Err(GrpcError::invalid_field(
+            "lookup_config",
+            format!("stage {} is not a lookup stage", stage.id),
+        )
+        .into()),
+        _ => Ok(None),
+    }
+}
+
+/// Parses the key a stage's records are partitioned by across its
+/// parallel instances.
+fn partition_key_from_proto(
+    stage: &ProtoStage,
+    stage_type: StageType,
+) -> Result<Option<PartitionKey>, Status> {
+    if stage.partition_key.is_empty() {
+        return Ok(None);
+    }
+    if matches!(stage_type, StageType::FanIn | StageType::FanOut) {
+        return Err(GrpcError::invalid_field(
+            "partition_key",
+            format!("fan stage {} cannot be partitioned", stage.id),
+        )
+        .into());
+    }
+    stage.partition_key.parse().map(Some).map_err(|e| {
+        GrpcError::invalid_field("partition_key", format!("stage {}: {}", stage.id, e)).into()
+    })
+}
+
+pub(crate) fn traffic_split_from_proto(split: &ProtoTrafficSplit) -> TrafficSplit {
+    TrafficSplit::new(
+        split
+            .versions
+            .iter()
+            .map(|v| VersionWeight {
+                version: v.version.clone(),
+                weight: v.weight,
+            })
+            .collect(),
+        split.sticky,
+    )
+}
+
+fn load_balance_from_proto(strategy: i32) -> LoadBalanceStrategy {
+    match ProtoLoadBalanceStrategy::try_from(strategy) {
+        Ok(ProtoLoadBalanceStrategy::LoadBalanceLeastConnections) => {
+            LoadBalanceStrategy::LeastConnections
+        }
+        Ok(ProtoLoadBalanceStrategy::LoadBalanceWeightedRandom) => {
+            LoadBalanceStrategy::WeightedRandom
+        }
+        Ok(ProtoLoadBalanceStrategy::LoadBalanceConsistentHash) => {
+            LoadBalanceStrategy::ConsistentHash
+        }
+        Ok(ProtoLoadBalanceStrategy::LoadBalancePeakEwma) => LoadBalanceStrategy::PeakEwma,
+        _ => LoadBalanceStrategy::RoundRobin,
+    }
+}
+
+fn routing_mode_from_proto(mode: i32) -> RoutingMode {
+    match ProtoRoutingMode::try_from(mode) {
+        Ok(ProtoRoutingMode::FirstMatch) => RoutingMode::FirstMatch,
+        _ => RoutingMode::AllMatches,
+    }
+}
+
+fn condition_from_proto(condition: &ProtoCondition) -> Result<Condition, Status> {
+    let Some(kind) = &condition.condition else {
+        return Ok(Condition::Always);
+    };
+
+    let converted = match kind {
+        ProtoConditionKind::Always(_) => Condition::Always,
+        ProtoConditionKind::RecordType(record_type) => Condition::RecordType(record_type.clone()),
+        ProtoConditionKind::MetadataMatch(m) => Condition::MetadataMatch {
+            key: m.key.clone(),
+            pattern: m.pattern.clone(),
+        },
+        ProtoConditionKind::Expression(source) => Condition::Expression(
+            Expression::compile(source)
+                .map_err(|e| GrpcError::invalid_field("condition", e.to_string()))?,
+        ),
+        ProtoConditionKind::And(and) => Condition::And(
+            and.conditions
+                .iter()
+                .map(condition_from_proto)
+                .collect::<Result<_, _>>()?,
+        ),
+        ProtoConditionKind::Or(or) => Condition::Or(
+            or.conditions
+                .iter()
+                .map(condition_from_proto)
+                .collect::<Result<_, _>>()?,
+        ),
+        ProtoConditionKind::Not(not) => Condition::Not(Box::new(match &not.condition {
+            Some(inner) => condition_from_proto(inner)?,
+            None => Condition::Always,
+        })),
+    };
+
+    Ok(converted)
+}
+
+fn non_empty(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
@@ -0,0 +1,112 @@
+//! Per-sidecar streams of assignment changes that watchers can resume.
+
+use std::collections::VecDeque;
+
+use dashmap::DashMap;
+use tokio::sync::broadcast;
+
+use conveyor_etl_proto::sidecar::PipelineAssignmentEvent;
+
+/// Events kept per sidecar for watchers resuming after a reconnect. Older
+/// positions resume with a snapshot instead.
+const BACKLOG: usize = 256;
+
+struct Feed {
+    id: String,
+    sequence: u64,
+    backlog: VecDeque<(u64, PipelineAssignmentEvent)>,
+    tx: broadcast::Sender<PipelineAssignmentEvent>,
+}
+
+impl Feed {
+    fn new() -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            sequence: 0,
+            backlog: VecDeque::new(),
+            tx: broadcast::channel(BACKLOG).0,
+        }
+    }
+
+    fn token(&self, sequence: u64) -> String {
+        format!("{}:{}", self.id, sequence)
+    }
+}
+
+/// Assignment events published to each sidecar's watchers, numbered so a
+/// watcher can resume from the last event it applied. Resume tokens are
+/// only valid on the leader that issued them.
+#[derive(Default)]
+pub struct AssignmentFeeds {
+    feeds: DashMap<String, Feed>,
+}
+
+impl AssignmentFeeds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Numbers `event` and sends it to the sidecar's watchers.
+    pub fn publish(&self, sidecar_id: &str, mut event: PipelineAssignmentEvent) {
+        let mut feed = self
+            .feeds
+            .entry(sidecar_id.to_string())
+            .or_insert_with(Feed::new);
+        feed.sequence += 1;
+        event.resume_token = feed.token(feed.sequence);
+
+        let sequence = feed.sequence;
+        feed.backlog.push_back((sequence, event.clone()));
+        if feed.backlog.len() > BACKLOG {
+            feed.backlog.pop_front();
+        }
+        let _ = feed.tx.send(event);
+    }
+
+    /// Token for the sidecar's latest event.
+    pub fn token(&self, sidecar_id: &str) -> String {
+        let feed = self
+            .feeds
+            .entry(sidecar_id.to_string())
+            .or_insert_with(Feed::new);
+        feed.token(feed.sequence)
+    }
+
+    /// Events after `token` and a receiver for later ones, or `None` when
+    /// the token was not issued by this feed or is too old to resume from.
+    pub fn resume(
+        &self,
+        sidecar_id: &str,
+        token: &str,
+    ) -> Option<(Vec<PipelineAssignmentEvent>, broadcast::Receiver<PipelineAssignmentEvent>)> {
+        let (id, sequence) = token.rsplit_once(':')?;
+        let sequence: u64 = sequence.parse().ok()?;
+
+        let feed = self.feeds.get(sidecar_id)?;
+        let oldest = feed.backlog.front().map_or(feed.sequence + 1, |(s, _)| *s);
+        if id != feed.id || sequence > feed.sequence || sequence + 1 < oldest {
+            return None;
+        }
+
+        let events = feed
+            .backlog
+            .iter()
+            .filter(|(s, _)| *s > sequence)
+            .map(|(_, event)| event.clone())
+            .collect();
+        Some((events, feed.tx.subscribe()))
+    }
+
+    /// Starts a new feed for the sidecar, ending its current watches.
+    pub fn reset(&self, sidecar_id: &str) {
+        self.feeds.insert(sidecar_id.to_string(), Feed::new());
+    }
+
+    pub fn remove(&self, sidecar_id: &str) {
+        self.feeds.remove(sidecar_id);
+    }
+
+    pub fn clear(&self) {
+        self.feeds.clear();
+    }
+}
@@ -1,5 +1,8 @@
+use tonic::metadata::MetadataValue;
 use tonic::{Code, Status};
 
+pub use conveyor_etl_proto::LEADER_HINT_KEY;
+
 #[derive(Debug, Clone)]
 pub enum GrpcError {
     NotFound { resource: &'static str, id: String },
@@ -8,7 +11,7 @@ pub enum GrpcError {
     FailedPrecondition { reason: String },
     ResourceExhausted { reason: String },
     Unavailable { reason: String },
-    NotLeader,
+    NotLeader { leader: Option<String> },
     Internal { reason: String },
 }
 
@@ -33,6 +36,10 @@ impl GrpcError {
         Self::NotFound { resource: "sidecar", id: id.into() }
     }
 
+    pub fn schema_not_found(id: impl Into<String>) -> Self {
+        Self::NotFound { resource: "schema", id: id.into() }
+    }
+
     pub fn group_already_exists(id: impl Into<String>) -> Self {
         Self::AlreadyExists { resource: "group", id: id.into() }
     }
@@ -57,8 +64,17 @@ impl GrpcError {
         Self::FailedPrecondition { reason: format!("pipeline is disabled: {}", id.into()) }
     }
 
+    pub fn incompatible_schema(reason: impl Into<String>) -> Self {
+        Self::FailedPrecondition { reason: reason.into() }
+    }
+
     pub fn not_leader() -> Self {
-        Self::NotLeader
+        Self::NotLeader { leader: None }
+    }
+
+    /// Not the leader, pointing the client at the node that is.
+    pub fn leader_is(addr: impl Into<String>) -> Self {
+        Self::NotLeader { leader: Some(addr.into()) }
     }
 
     pub fn unavailable(reason: impl Into<String>) -> Self {
@@ -91,8 +107,13 @@ impl From<GrpcError> for Status {
             GrpcError::Unavailable { reason } => {
                 Status::new(Code::Unavailable, reason)
             }
-            GrpcError::NotLeader => {
-                Status::new(Code::Unavailable, "not the leader, retry on another node")
+            GrpcError::NotLeader { leader } => {
+                let mut status =
+                    Status::new(Code::Unavailable, "not the leader, retry on another node");
+                if let Some(value) = leader.and_then(|l| MetadataValue::try_from(l).ok()) {
+                    status.metadata_mut().insert(LEADER_HINT_KEY, value);
+                }
+                status
             }
             GrpcError::Internal { reason } => {
                 Status::new(Code::Internal, reason)
@@ -225,6 +246,10 @@ mod tests {
         let status: Status = GrpcError::not_leader().into();
         assert_eq!(status.code(), Code::Unavailable);
         assert!(status.message().contains("not the leader"));
+        assert!(status.metadata().get(LEADER_HINT_KEY).is_none());
+
+        let status: Status = GrpcError::leader_is("router-2:50052").into();
+        assert_eq!(status.metadata().get(LEADER_HINT_KEY).unwrap(), "router-2:50052");
     }
 
     #[test]
@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use anyhow::Result;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use conveyor_etl_raft::{
+    ConveyorRaft, GroupState, RouterCommand, RouterRequest, RouterState, StaleGeneration,
+};
+use conveyor_etl_registry::spread_by_topology;
+
+const MAX_REBALANCE_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub service_id: String,
+    pub joined_at: u64,
+    pub assigned_partitions: Vec<u32>,
+    #[serde(default)]
+    pub zone: Option<String>,
+    #[serde(skip)]
+    pub last_heartbeat: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartitionAssignment {
+    pub partition_id: u32,
+    pub assigned_to: Option<String>,
+    pub generation: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceGroup {
+    pub group_id: String,
+    pub stage_id: String,
+    pub members: HashMap<String, GroupMember>,
+    pub partition_assignment: HashMap<u32, String>,
+    pub generation: u64,
+    pub total_partitions: u32,
+    #[serde(default)]
+    pub topology_key: Option<String>,
+}
+
+impl ServiceGroup {
+    pub fn new(group_id: String, stage_id: String, total_partitions: u32) -> Self {
+        Self {
+            group_id,
+            stage_id,
+            members: HashMap::new(),
+            partition_assignment: HashMap::new(),
+            generation: 0,
+            total_partitions,
+            topology_key: None,
+        }
+    }
+
+    fn from_state(state: &GroupState, sessions: Option<&HashMap<String, Instant>>) -> Self {
+        let mut partition_assignment = HashMap::new();
+        for (service_id, partitions) in &state.partition_assignments {
+            for partition in partitions {
+                partition_assignment.insert(*partition, service_id.clone());
+            }
+        }
+
+        let members = state
+            .members
+            .iter()
+            .map(|service_id| {
+                let member = GroupMember {
+                    service_id: service_id.clone(),
+                    joined_at: state.member_joined_at.get(service_id).copied().unwrap_or(0),
+                    assigned_partitions: sorted_partitions(state, service_id),
+                    zone: state.member_zones.get(service_id).cloned(),
+                    last_heartbeat: sessions.and_then(|s| s.get(service_id).copied()),
+                };
+                (service_id.clone(), member)
+            })
+            .collect();
+
+        Self {
+            group_id: state.group_id.clone(),
+            stage_id: state.stage_id.clone(),
+            members,
+            partition_assignment,
+            generation: state.generation,
+            total_partitions: state.total_partitions,
+            topology_key: state.topology_key.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RebalanceEvent {
+    PartitionsRevoked {
+        service_id: String,
+        partitions: Vec<u32>,
+        generation: u64,
+    },
+    PartitionsAssigned {
+        service_id: String,
+        partitions: Vec<u32>,
+        generation: u64,
+    },
+}
+
+pub struct GroupCoordinator {
+    raft: Option<Arc<ConveyorRaft>>,
+    state: Arc<RwLock<RouterState>>,
+    sessions: DashMap<String, HashMap<String, Instant>>,
+    rebalance_lock: Mutex<()>,
+}
+
+impl GroupCoordinator {
+    pub fn new() -> Self {
+        Self::with_state(Arc::new(RwLock::new(RouterState::default())))
+    }
+
+    pub fn with_state(state: Arc<RwLock<RouterState>>) -> Self {
+        Self {
+            raft: None,
+            state,
+            sessions: DashMap::new(),
+            rebalance_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn with_raft(raft: Arc<ConveyorRaft>, state: Arc<RwLock<RouterState>>) -> Self {
+        Self {
+            raft: Some(raft),
+            ..Self::with_state(state)
+        }
+    }
+
+    async fn propose(&self, command: RouterCommand) -> Result<()> {
+        match &self.raft {
+            Some(raft) => {
+                let response = raft
+                    .client_write(RouterRequest { command })
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Raft error: {}", e))?;
+                if let Some(stale) = response.data.stale_generation {
+                    return Err(stale.into());
+                }
+                if !response.data.success {
+                    return Err(anyhow::anyhow!(
+                        "{}",
+                        response.data.error.unwrap_or_else(|| "Command rejected".to_string())
+                    ));
+                }
+                Ok(())
+            }
+            None => self.state.write().await.apply_command(command),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        match &self.raft {
+            Some(raft) => {
+                let metrics = raft.metrics();
+                let metrics = metrics.borrow();
+                metrics.current_leader == Some(metrics.id)
+            }
+            None => true,
+        }
+    }
+
+    async fn group_state(&self, group_id: &str) -> Result<GroupState> {
+        self.state
+            .read()
+            .await
+            .groups
+            .get(group_id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Group not found: {}", group_id))
+    }
+
+    fn touch_session(&self, group_id: &str, service_id: &str) {
+        self.sessions
+            .entry(group_id.to_string())
+            .or_default()
+            .insert(service_id.to_string(), Instant::now());
+    }
+
+    fn drop_session(&self, group_id: &str, service_id: &str) {
+        if let Some(mut sessions) = self.sessions.get_mut(group_id) {
+            sessions.remove(service_id);
+        }
+    }
+
+    pub async fn create_group(
+        &self,
+        group_id: String,
+        stage_id: String,
+        total_partitions: u32,
+    ) -> Result<()> {
+        if self.state.read().await.groups.contains_key(&group_id) {
+            return Err(anyhow::anyhow!("Group already exists: {}", group_id));
+        }
+
+        self.propose(RouterCommand::CreateGroup {
+            group_id: group_id.clone(),
+            stage_id,
+            total_partitions,
+        })
+        .await?;
+
+        info!(group_id = %group_id, "Group created");
+        Ok(())
+    }
+
+    /// Creates the group unless it already exists, so the first member to
+    /// join brings it into being. A concurrent creator winning the race is
+    /// not an error.
+    pub async fn ensure_group(
+        &self,
+        group_id: &str,
+        stage_id: &str,
+        total_partitions: u32,
+    ) -> Result<()> {
+        if self.state.read().await.groups.contains_key(group_id) {
+            return Ok(());
+        }
+
+        match self
+            .create_group(group_id.to_string(), stage_id.to_string(), total_partitions)
+            .await
+        {
+            Err(_) if self.state.read().await.groups.contains_key(group_id) => Ok(()),
+            result => result,
+        }
+    }
+
+    pub async fn set_topology_key(
+        &self,
+        group_id: &str,
+        topology_key: Option<String>,
+    ) -> Result<Vec<RebalanceEvent>> {
+        let group = self.group_state(group_id).await?;
+
+        if group.topology_key == topology_key {
+            return Ok(vec![]);
+        }
+
+        self.propose(RouterCommand::SetGroupTopology {
+            group_id: group_id.to_string(),
+            topology_key: topology_key.clone(),
+        })
+        .await?;
+
+        info!(
+            group_id = %group_id,
+            topology_key = ?topology_key,
+            "Group topology key updated"
+        );
+
+        if group.members.is_empty() {
+            return Ok(vec![]);
+        }
+
+        self.rebalance(group_id).await
+    }
+
+    pub async fn join_group(
+        &self,
+        group_id: &str,
+        service_id: String,
+    ) -> Result<Vec<RebalanceEvent>> {
+        self.join_group_in_zone(group_id, service_id, None).await
+    }
+
+    pub async fn join_group_in_zone(
+        &self,
+        group_id: &str,
+        service_id: String,
+        zone: Option<String>,
+    ) -> Result<Vec<RebalanceEvent>> {
+        let group = self.group_state(group_id).await?;
+
+        if group.members.contains(&service_id)
+            && group.member_zones.get(&service_id) == zone.as_ref()
+        {
+            self.touch_session(group_id, &service_id);
+            return Ok(vec![]);
+        }
+
+        info!(
+            group_id = %group_id,
+            service_id = %service_id,
+            zone = ?zone,
+            "Member joined group"
+        );
+
+        self.propose(RouterCommand::JoinGroup {
+            service_id: service_id.clone(),
+            group_id: group_id.to_string(),
+            stage_id: group.stage_id,
+            zone,
+        })
+        .await?;
+        self.touch_session(group_id, &service_id);
+
+        self.rebalance(group_id).await
+    }
+
+    pub async fn leave_group(
+        &self,
+        group_id: &str,
+        service_id: &str,
+    ) -> Result<Vec<RebalanceEvent>> {
+        let group = self.group_state(group_id).await?;
+
+        if !group.members.iter().any(|m| m == service_id) {
+            return Err(anyhow::anyhow!(
+                "Service {} not in group {}",
+                service_id,
+                group_id
+            ));
+        }
+
+        self.propose(RouterCommand::LeaveGroup {
+            service_id: service_id.to_string(),
+            group_id: group_id.to_string(),
+        })
+        .await?;
+        self.drop_session(group_id, service_id);
+
+        info!(group_id = %group_id, service_id = %service_id, "Member left group");
+
+        self.rebalance(group_id).await
+    }
+
+    async fn rebalance(&self, group_id: &str) -> Result<Vec<RebalanceEvent>> {
+        let _guard = self.rebalance_lock.lock().await;
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+
+            let group = self.group_state(group_id).await?;
+            let new_assignments = compute_assignments(&group);
+            let new_generation = group.generation + 1;
+
+            let result = self
+                .propose(RouterCommand::AssignPartitions {
+                    group_id: group_id.to_string(),
+                    assignments: new_assignments.clone(),
+                    generation: new_generation,
+                })
+                .await;
+
+            match result {
+                Ok(()) => {
+                    info!(
+                        group_id = %group_id,
+                        generation = new_generation,
+                        members = group.members.len(),
+                        "Rebalance completed"
+                    );
+                    return Ok(diff_assignments(
+                        &group.partition_assignments,
+                        &new_assignments,
+                        new_generation,
+                    ));
+                }
+                Err(e) if attempt < MAX_REBALANCE_ATTEMPTS
+                    && e.downcast_ref::<StaleGeneration>().is_some() =>
+                {
+                    warn!(group_id = %group_id, error = %e, "Rebalance raced, retrying");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub async fn get_partitions_by_zone(&self, group_id: &str) -> HashMap<String, Vec<u32>> {
+        let mut by_zone: HashMap<String, Vec<u32>> = HashMap::new();
+        if let Ok(group) = self.group_state(group_id).await {
+            for (service_id, partitions) in &group.partition_assignments {
+                by_zone
+                    .entry(group.member_zones.get(service_id).cloned().unwrap_or_default())
+                    .or_default()
+                    .extend(partitions.iter().copied());
+            }
+        }
+        for partitions in by_zone.values_mut() {
+            partitions.sort_unstable();
+        }
+        by_zone
+    }
+
+    pub async fn get_assignment(&self, group_id: &str, service_id: &str) -> Option<Vec<u32>> {
+        let state = self.state.read().await;
+        let group = state.groups.get(group_id)?;
+        if !group.members.iter().any(|m| m == service_id) {
+            return None;
+        }
+        Some(sorted_partitions(group, service_id))
+    }
+
+    pub async fn get_group(&self, group_id: &str) -> Option<ServiceGroup> {
+        let state = self.state.read().await;
+        let sessions = self.sessions.get(group_id);
+        state
+            .groups
+            .get(group_id)
+            .map(|g| ServiceGroup::from_state(g, sessions.as_deref()))
+    }
+
+    pub async fn get_partition_owner(&self, group_id: &str, partition: u32) -> Option<String> {
+        let state = self.state.read().await;
+        state.groups.get(group_id).and_then(|g| {
+            g.partition_assignments
+                .iter()
+                .find(|(_, partitions)| partitions.contains(&partition))
+                .map(|(service_id, _)| service_id.clone())
+        })
+    }
+
+    pub async fn heartbeat(&self, group_id: &str, service_id: &str) -> Result<()> {
+        let group = self.group_state(group_id).await?;
+
+        if !group.members.iter().any(|m| m == service_id) {
+            return Err(anyhow::anyhow!("Member not found: {}", service_id));
+        }
+
+        self.touch_session(group_id, service_id);
+        Ok(())
+    }
+
+    pub async fn heartbeat_with_generation(
+        &self,
+        group_id: &str,
+        service_id: &str,
+        expected_generation: u64,
+    ) -> Result<()> {
+        let group = self.group_state(group_id).await?;
+
+        if group.generation != expected_generation {
+            return Err(StaleGeneration {
+                expected: group.generation,
+                got: expected_generation,
+            }
+            .into());
+        }
+
+        if !group.members.iter().any(|m| m == service_id) {
+            return Err(anyhow::anyhow!("Member not found: {}", service_id));
+        }
+
+        self.touch_session(group_id, service_id);
+        Ok(())
+    }
+
+    /// Refreshes the session of `service_id` in every group it belongs to,
+    /// returning those groups. Called from the service-level heartbeat so
+    /// members joined over RPC are not timed out.
+    pub async fn heartbeat_member(&self, service_id: &str) -> Vec<String> {
+        let groups: Vec<String> = self
+            .state
+            .read()
+            .await
+            .groups
+            .iter()
+            .filter(|(_, group)| group.members.iter().any(|m| m == service_id))
+            .map(|(group_id, _)| group_id.clone())
+            .collect();
+
+        for group_id in &groups {
+            self.touch_session(group_id, service_id);
+        }
+        groups
+    }
+
+    pub async fn get_current_generation(&self, group_id: &str) -> Option<u64> {
+        self.state.read().await.groups.get(group_id).map(|g| g.generation)
+    }
+
+    pub async fn validate_generation(&self, group_id: &str, generation: u64) -> Result<bool> {
+        let group = self.group_state(group_id).await?;
+        Ok(group.generation == generation)
+    }
+
+    pub async fn leave_group_with_generation(
+        &self,
+        group_id: &str,
+        service_id: &str,
+        expected_generation: u64,
+    ) -> Result<Vec<RebalanceEvent>> {
+        let group = self.group_state(group_id).await?;
+
+        if group.generation != expected_generation {
+            return Err(StaleGeneration {
+                expected: group.generation,
+                got: expected_generation,
+            }
+            .into());
+        }
+
+        self.leave_group(group_id, service_id).await
+    }
+
+    pub async fn list_groups(&self) -> Vec<String> {
+        self.state.read().await.groups.keys().cloned().collect()
+    }
+
+    pub async fn check_member_timeouts(
+        &self,
+        group_id: &str,
+        timeout: Duration,
+    ) -> Result<Vec<RebalanceEvent>> {
+        if !self.is_leader() {
+            return Ok(vec![]);
+        }
+
+        let group = self.group_state(group_id).await?;
+
+        let timed_out_members: Vec<String> = {
+            let mut sessions = self.sessions.entry(group_id.to_string()).or_default();
+            sessions.retain(|service_id, _| group.members.contains(service_id));
+
+            group
+                .members
+                .iter()
+                .filter(|service_id| {
+                    let last_heartbeat = *sessions
+                        .entry((*service_id).clone())
+                        .or_insert_with(Instant::now);
+                    last_heartbeat.elapsed() > timeout
+                })
+                .cloned()
+                .collect()
+        };
+
+        if timed_out_members.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut all_events = Vec::new();
+        for member_id in timed_out_members {
+            info!(group_id = %group_id, member_id = %member_id, "Member timed out, removing from group");
+            match self.leave_group(group_id, &member_id).await {
+                Ok(events) => all_events.extend(events),
+                Err(e) => {
+                    warn!(group_id = %group_id, member_id = %member_id, error = %e, "Failed to remove timed out member");
+                }
+            }
+        }
+
+        Ok(all_events)
+    }
+
+    pub async fn run_session_monitor(self: Arc<Self>, interval: Duration, timeout: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            if !self.is_leader() {
+                self.sessions.clear();
+                continue;
+            }
+
+            for group_id in self.list_groups().await {
+                if let Err(e) = self.check_member_timeouts(&group_id, timeout).await {
+                    warn!(group_id = %group_id, error = %e, "Failed to check member timeouts");
+                }
+            }
+        }
+    }
+
+    pub fn get_members_needing_heartbeat(
+        &self,
+        group_id: &str,
+        threshold: Duration,
+    ) -> Vec<String> {
+        self.sessions
+            .get(group_id)
+            .map(|sessions| {
+                sessions
+                    .iter()
+                    .filter(|(_, last_heartbeat)| last_heartbeat.elapsed() > threshold)
+                    .map(|(id, _)| id.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Default for GroupCoordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn sorted_partitions(group: &GroupState, service_id: &str) -> Vec<u32> {
+    let mut partitions = group
+        .partition_assignments
+        .get(service_id)
+        .cloned()
+        .unwrap_or_default();
+    partitions.sort_unstable();
+    partitions
+}
+
+fn placement_order(group: &GroupState) -> Vec<String> {
+    if group.topology_key.is_none() {
+        return group.members.clone();
+    }
+
+    let mut members = group.members.clone();
+    members.sort();
+
+    spread_by_topology(members, |m| group.member_zones.get(m).cloned())
+}
+
+fn compute_assignments(group: &GroupState) -> HashMap<String, Vec<u32>> {
+    let member_ids = placement_order(group);
+    let mut assignments: HashMap<String, Vec<u32>> = HashMap::new();
+
+    if member_ids.is_empty() {
+        return assignments;
+    }
+
+    for partition in 0..group.total_partitions {
+        let service_id = &member_ids[partition as usize % member_ids.len()];
+        assignments
+            .entry(service_id.clone())
+            .or_default()
+            .push(partition);
+    }
+
+    assignments
+}
+
+fn diff_assignments(
+    old_assignments: &HashMap<String, Vec<u32>>,
+    new_assignments: &HashMap<String, Vec<u32>>,
+    generation: u64,
+) -> Vec<RebalanceEvent> {
+    let mut events = Vec::new();
+
+    for (service_id, old_partitions) in old_assignments {
+        let new_partitions = new_assignments.get(service_id).cloned().unwrap_or_default();
+        let revoked: Vec<u32> = old_partitions
+            .iter()
+            .filter(|p| !new_partitions.contains(p))
+            .copied()
+            .collect();
+
+        if !revoked.is_empty() {
+            events.push(RebalanceEvent::PartitionsRevoked {
+                service_id: service_id.clone(),
+                partitions: revoked,
+                generation,
+            });
+        }
+    }
+
+    for (service_id, new_partitions) in new_assignments {
+        let old_partitions = old_assignments.get(service_id).cloned().unwrap_or_default();
+        let assigned: Vec<u32> = new_partitions
+            .iter()
+            .filter(|p| !old_partitions.contains(p))
+            .copied()
+            .collect();
+
+        if !assigned.is_empty() {
+            events.push(RebalanceEvent::PartitionsAssigned {
+                service_id: service_id.clone(),
+                partitions: assigned,
+                generation,
+            });
+        }
+    }
+
+    events
+}
@@ -1,4 +1,5 @@
 pub mod error;
+pub mod group_coordinator;
 pub mod server;
 pub mod source_handler;
 pub mod transform_client;
@@ -7,9 +8,18 @@ pub mod sink_client;
 pub mod registry_handler;
 pub mod checkpoint_handler;
 pub mod sidecar_handler;
+pub mod assignment_feed;
+pub mod admin_handler;
+pub mod schema_handler;
+pub mod scheduler;
 #[cfg(test)]
 mod tests;
 
 pub use error::{GrpcError, IntoStatus, ResultExt};
 pub use server::RouterServer;
+pub use group_coordinator::{
+    GroupCoordinator, GroupMember, PartitionAssignment, RebalanceEvent, ServiceGroup,
+};
+pub use admin_handler::RouterAdminImpl;
 pub use sidecar_handler::SidecarCoordinatorImpl;
+pub use schema_handler::SchemaRegistryImpl;
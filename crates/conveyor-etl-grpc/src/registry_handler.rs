@@ -8,6 +8,7 @@ use tonic::{Request, Response, Status, Streaming};
 use tracing::info;
 
 use crate::error::{GrpcError, IntoStatus, ResultExt};
+use crate::group_coordinator::GroupCoordinator;
 
 use conveyor_etl_proto::common::{Endpoint, HealthStatus, ServiceIdentity};
 use conveyor_etl_proto::registry::{
@@ -20,7 +21,10 @@ use conveyor_etl_proto::registry::{
     ServiceMetadata, WatchServicesRequest,
 };
 use conveyor_etl_raft::{ConveyorRaft, RouterState};
-use conveyor_etl_registry::{ServiceRegistry, ServiceType};
+use conveyor_etl_registry::{
+    order_by_locality, LoadBalancer, ServiceRegistry, ServiceType, VERSION_LABEL,
+    ZONE_TOPOLOGY_KEY,
+};
 
 pub struct ServiceRegistryImpl {
     #[allow(dead_code)]
@@ -28,6 +32,8 @@ pub struct ServiceRegistryImpl {
     #[allow(dead_code)]
     state: Arc<RwLock<RouterState>>,
     registry: Arc<RwLock<ServiceRegistry>>,
+    load_balancer: Arc<LoadBalancer>,
+    group_coordinator: Arc<GroupCoordinator>,
 }
 
 impl ServiceRegistryImpl {
@@ -35,13 +41,35 @@ impl ServiceRegistryImpl {
         raft: Arc<ConveyorRaft>,
         state: Arc<RwLock<RouterState>>,
         registry: Arc<RwLock<ServiceRegistry>>,
+        load_balancer: Arc<LoadBalancer>,
+        group_coordinator: Arc<GroupCoordinator>,
     ) -> Self {
         Self {
             raft,
             state,
             registry,
+            load_balancer,
+            group_coordinator,
         }
     }
+
+    async fn topology_key(&self, group_id: &str) -> String {
+        self.group_coordinator
+            .get_group(group_id)
+            .await
+            .and_then(|g| g.topology_key)
+            .unwrap_or_else(|| ZONE_TOPOLOGY_KEY.to_string())
+    }
+
+    async fn member_zone(&self, group_id: &str, service_id: &str) -> Option<String> {
+        let topology_key = self.topology_key(group_id).await;
+
+        let registry = self.registry.read().await;
+        registry
+            .get_service(service_id)
+            .await
+            .and_then(|s| s.labels.get(&topology_key).cloned())
+    }
 }
 
 type HeartbeatStream = Pin<Box<dyn Stream<Item = Result<HeartbeatResponse, Status>> + Send>>;
@@ -73,7 +101,11 @@ impl ServiceRegistryTrait for ServiceRegistryImpl {
         let endpoint_str = format!("{}:{}", endpoint.host, endpoint.port);
         let service_type = ServiceType::from_proto(identity.service_type);
 
-        let labels: HashMap<String, String> = req.metadata.map(|m| m.labels).unwrap_or_default();
+        let mut labels: HashMap<String, String> =
+            req.metadata.map(|m| m.labels).unwrap_or_default();
+        if !identity.version.is_empty() {
+            labels.insert(VERSION_LABEL.to_string(), identity.version.clone());
+        }
 
         let group_id = if identity.group_id.is_empty() {
             None
@@ -117,6 +149,7 @@ impl ServiceRegistryTrait for ServiceRegistryImpl {
 
         let registry = self.registry.write().await;
         registry.deregister(&req.service_id).await.map_to_status()?;
+        self.load_balancer.remove_service(&req.service_id);
         Ok(Response::new(DeregisterResponse {
             success: true,
             pending_records: 0,
@@ -129,12 +162,19 @@ impl ServiceRegistryTrait for ServiceRegistryImpl {
     ) -> Result<Response<Self::HeartbeatStream>, Status> {
         let mut stream = request.into_inner();
         let registry = self.registry.clone();
+        let load_balancer = self.load_balancer.clone();
+        let group_coordinator = self.group_coordinator.clone();
 
         let output = async_stream::try_stream! {
             while let Some(req) = stream.message().await? {
+                if let Some(load) = &req.load {
+                    load_balancer.update_load(&req.service_id, load.avg_latency_ms, load.queue_depth);
+                }
+
                 let registry = registry.write().await;
                 match registry.heartbeat(&req.service_id).await {
                     Ok(lease_duration) => {
+                        group_coordinator.heartbeat_member(&req.service_id).await;
                         yield HeartbeatResponse {
                             acknowledged: true,
                             next_heartbeat_deadline: Some(prost_types::Duration {
@@ -172,7 +212,7 @@ impl ServiceRegistryTrait for ServiceRegistryImpl {
                     service_id: s.service_id.clone(),
                     service_type: s.service_type.to_proto(),
                     name: s.service_name.clone(),
-                    version: String::new(),
+                    version: s.version().unwrap_or_default().to_string(),
                     capabilities: Vec::new(),
                     group_id: s.group_id.clone().unwrap_or_default(),
                 }),
@@ -230,9 +270,16 @@ impl ServiceRegistryTrait for ServiceRegistryImpl {
         request: Request<GetServiceEndpointsRequest>,
     ) -> Result<Response<GetServiceEndpointsResponse>, Status> {
         let req = request.into_inner();
-        let registry = self.registry.read().await;
+        let topology_key = if req.topology_key.is_empty() {
+            self.topology_key(&req.group_id).await
+        } else {
+            req.topology_key
+        };
+        let zone = (!req.zone.is_empty()).then_some(req.zone.as_str());
 
+        let registry = self.registry.read().await;
         let services = registry.get_services_by_name(&req.service_name).await;
+        let services = order_by_locality(&services, &topology_key, zone);
 
         let endpoints: Vec<EndpointInfo> = services
             .iter()
@@ -270,10 +317,46 @@ impl ServiceRegistryTrait for ServiceRegistryImpl {
             "Service joining group"
         );
 
+        // The first member creates the group, sized to cover the partitions
+        // it asks for.
+        let total_partitions = req
+            .partition_preferences
+            .iter()
+            .max()
+            .map_or(1, |p| p.saturating_add(1));
+        self.group_coordinator
+            .ensure_group(&req.group_id, &req.stage_id, total_partitions)
+            .await
+            .map_to_status()?;
+
+        if !req.topology_key.is_empty() {
+            self.group_coordinator
+                .set_topology_key(&req.group_id, Some(req.topology_key.clone()))
+                .await
+                .map_to_status()?;
+        }
+
+        let zone = self.member_zone(&req.group_id, &req.service_id).await;
+        self.group_coordinator
+            .join_group_in_zone(&req.group_id, req.service_id.clone(), zone)
+            .await
+            .map_to_status()?;
+
+        let generation = self
+            .group_coordinator
+            .get_current_generation(&req.group_id)
+            .await
+            .ok_or_else(|| GrpcError::group_not_found(&req.group_id))?;
+        let assigned_partitions = self
+            .group_coordinator
+            .get_assignment(&req.group_id, &req.service_id)
+            .await
+            .unwrap_or_default();
+
         Ok(Response::new(JoinGroupResponse {
             success: true,
-            generation: 1,
-            assigned_partitions: vec![0],
+            generation,
+            assigned_partitions,
         }))
     }
 
@@ -289,6 +372,11 @@ impl ServiceRegistryTrait for ServiceRegistryImpl {
             "Service leaving group"
         );
 
+        self.group_coordinator
+            .leave_group(&req.group_id, &req.service_id)
+            .await
+            .map_to_status()?;
+
         Ok(Response::new(LeaveGroupResponse { success: true }))
     }
 }
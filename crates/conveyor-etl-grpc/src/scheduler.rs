@@ -0,0 +1,417 @@
+//! Places pipeline stages on the sidecars whose local services match them.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+use prost::Message;
+use tracing::warn;
+
+use conveyor_etl_dsl::TransformConfigDsl;
+use conveyor_etl_proto::router::{PipelineConfig, ServiceSelector, Stage, StageType};
+use conveyor_etl_raft::{
+    GroupState, RouterState, SidecarLocalService, SidecarStageAssignment, SidecarStageTarget,
+    SidecarState,
+};
+use conveyor_etl_registry::VERSION_LABEL;
+
+/// Stage assignments for one sidecar, by pipeline.
+pub type SidecarPlan = BTreeMap<String, Vec<SidecarStageAssignment>>;
+
+/// A sidecar running one instance of a stage's service.
+struct Instance<'a> {
+    sidecar: &'a SidecarState,
+    service: &'a SidecarLocalService,
+}
+
+/// The consumer group splitting a partitioned stage's partitions among the
+/// sidecars it is placed on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionGroup {
+    pub group_id: String,
+    pub stage_id: String,
+    pub partitions: u32,
+    /// Sidecars the stage is placed on.
+    pub members: Vec<String>,
+    /// Labels of the service each member runs the stage on, which its zone
+    /// is read from.
+    pub member_labels: HashMap<String, HashMap<String, String>>,
+}
+
+/// A sidecar running a stage on one of its local services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageInstance {
+    pub sidecar_id: String,
+    /// The sidecar's own address, for forwarding to it.
+    pub endpoint: String,
+    /// The service's address on that sidecar.
+    pub local_endpoint: String,
+    pub labels: HashMap<String, String>,
+}
+
+/// Instances of each stage by pipeline and stage id, in sidecar id order.
+pub type StageInstances = HashMap<(String, String), Vec<StageInstance>>;
+
+/// Prefix of the ids of partition groups, keeping them apart from the
+/// groups services join themselves.
+pub const PARTITION_GROUP_PREFIX: &str = "stage:";
+
+pub fn partition_group_id(pipeline_id: &str, stage_id: &str) -> String {
+    format!("{}{}/{}", PARTITION_GROUP_PREFIX, pipeline_id, stage_id)
+}
+
+/// Computes the stage assignments of every sidecar.
+///
+/// Each service stage is placed on up to `parallelism` sidecars that
+/// advertise a service matching its selector, keeping the sidecars it is
+/// already placed on. Every sidecar hosting a stage of a pipeline is
+/// assigned the whole pipeline: its own stages locally, built-in transforms
+/// in-process, and other stages on the nearest sidecar running them. Fan-in
+/// and fan-out stages are left out; sidecars run them from the stage graph.
+///
+/// Stateful built-in transforms run on one of those sidecars, the one
+/// already running them if it still hosts the pipeline, so all their
+/// records meet in the same state; the others forward to it.
+///
+/// Stages with a partition key also carry the owner of each of their
+/// partitions, taken from the stage's partition group, so every sidecar
+/// sends a key to the same instance.
+pub fn schedule(state: &RouterState) -> HashMap<String, SidecarPlan> {
+    let sidecars = sorted_sidecars(state);
+
+    let mut plans: HashMap<String, SidecarPlan> = HashMap::new();
+    for (pipeline_id, config) in enabled_pipelines(state) {
+        let placements: Vec<(&Stage, Vec<Instance>)> = config
+            .stages
+            .iter()
+            .map(|stage| (stage, place(pipeline_id, stage, &sidecars)))
+            .collect();
+
+        let hosts: Vec<&SidecarState> = sidecars
+            .iter()
+            .copied()
+            .filter(|sidecar| {
+                placements
+                    .iter()
+                    .any(|(_, instances)| instances.iter().any(|i| i.sidecar.sidecar_id == sidecar.sidecar_id))
+            })
+            .collect();
+
+        for sidecar in &hosts {
+            let stages = placements
+                .iter()
+                .filter_map(|(stage, instances)| {
+                    let home = if is_stateful(stage) {
+                        stateful_home(pipeline_id, stage, &hosts)
+                    } else {
+                        None
+                    };
+                    let target = if let Some(home) = home.filter(|h| h.sidecar_id != sidecar.sidecar_id) {
+                        SidecarStageTarget::Remote {
+                            sidecar_id: home.sidecar_id.clone(),
+                            endpoint: home.endpoint.clone(),
+                        }
+                    } else if !stage.transform_config.is_empty() {
+                        SidecarStageTarget::Builtin {
+                            config: stage.transform_config.clone(),
+                        }
+                    } else if let Some(local) =
+                        instances.iter().find(|i| i.sidecar.sidecar_id == sidecar.sidecar_id)
+                    {
+                        SidecarStageTarget::Local {
+                            endpoint: local.service.local_endpoint.clone(),
+                        }
+                    } else {
+                        let remote = nearest(sidecar, instances)?;
+                        SidecarStageTarget::Remote {
+                            sidecar_id: remote.sidecar.sidecar_id.clone(),
+                            endpoint: remote.sidecar.endpoint.clone(),
+                        }
+                    };
+                    Some(SidecarStageAssignment {
+                        stage_id: stage.id.clone(),
+                        target,
+                        partitions: partition_owners(state, pipeline_id, stage, sidecar, instances),
+                    })
+                })
+                .collect();
+
+            plans
+                .entry(sidecar.sidecar_id.clone())
+                .or_default()
+                .insert(pipeline_id.clone(), stages);
+        }
+    }
+
+    plans
+}
+
+/// The partition groups of every partitioned stage, with the sidecars that
+/// should be their members.
+pub fn partition_groups(state: &RouterState) -> Vec<PartitionGroup> {
+    let sidecars = sorted_sidecars(state);
+
+    let mut groups = Vec::new();
+    for (pipeline_id, config) in enabled_pipelines(state) {
+        for stage in config.stages.iter().filter(|s| is_partitioned(s)) {
+            let instances = place(pipeline_id, stage, &sidecars);
+            groups.push(PartitionGroup {
+                group_id: partition_group_id(pipeline_id, &stage.id),
+                stage_id: stage.id.clone(),
+                partitions: stage.parallelism,
+                members: instances.iter().map(|i| i.sidecar.sidecar_id.clone()).collect(),
+                member_labels: instances
+                    .iter()
+                    .map(|i| (i.sidecar.sidecar_id.clone(), i.service.labels.clone()))
+                    .collect(),
+            });
+        }
+    }
+    groups
+}
+
+/// The instances of the stages in `assignments`, given as (sidecar,
+/// pipeline, stages): every sidecar assigned a stage on a local service.
+pub fn stage_instances<'a>(
+    state: &RouterState,
+    assignments: impl IntoIterator<Item = (&'a str, &'a str, &'a [SidecarStageAssignment])>,
+) -> StageInstances {
+    let mut instances: StageInstances = HashMap::new();
+    for (sidecar_id, pipeline_id, stages) in assignments {
+        let Some(sidecar) = state.sidecars.get(sidecar_id) else {
+            continue;
+        };
+        for stage in stages {
+            let SidecarStageTarget::Local { endpoint } = &stage.target else {
+                continue;
+            };
+            let labels = sidecar
+                .local_services
+                .iter()
+                .find(|s| &s.local_endpoint == endpoint)
+                .map(|s| s.labels.clone())
+                .unwrap_or_default();
+            instances
+                .entry((pipeline_id.to_string(), stage.stage_id.clone()))
+                .or_default()
+                .push(StageInstance {
+                    sidecar_id: sidecar_id.to_string(),
+                    endpoint: sidecar.endpoint.clone(),
+                    local_endpoint: endpoint.clone(),
+                    labels,
+                });
+        }
+    }
+    for stage_instances in instances.values_mut() {
+        stage_instances.sort_by(|a, b| a.sidecar_id.cmp(&b.sidecar_id));
+    }
+    instances
+}
+
+/// Whether every stage of a plan runs on the sidecar itself.
+pub fn is_local_complete(stages: &[SidecarStageAssignment]) -> bool {
+    let is_remote = |target: &SidecarStageTarget| matches!(target, SidecarStageTarget::Remote { .. });
+    stages
+        .iter()
+        .all(|s| !is_remote(&s.target) && !s.partitions.iter().any(is_remote))
+}
+
+fn sorted_sidecars(state: &RouterState) -> Vec<&SidecarState> {
+    let mut sidecars: Vec<&SidecarState> = state.sidecars.values().collect();
+    sidecars.sort_by(|a, b| a.sidecar_id.cmp(&b.sidecar_id));
+    sidecars
+}
+
+fn enabled_pipelines(state: &RouterState) -> Vec<(&String, PipelineConfig)> {
+    state
+        .pipelines
+        .iter()
+        .filter(|(_, pipeline)| pipeline.enabled)
+        .filter_map(|(pipeline_id, pipeline)| {
+            match PipelineConfig::decode(pipeline.config.as_slice()) {
+                Ok(config) => Some((pipeline_id, config)),
+                Err(e) => {
+                    warn!(pipeline = %pipeline_id, error = %e, "Failed to decode pipeline config");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Built-in transforms that keep state across records.
+fn is_stateful(stage: &Stage) -> bool {
+    !stage.transform_config.is_empty()
+        && serde_json::from_str::<TransformConfigDsl>(&stage.transform_config)
+            .is_ok_and(|config| config.is_stateful())
+}
+
+/// The sidecar a stateful stage runs on: of the sidecars hosting its
+/// pipeline, the one already running it, or else the first by id.
+fn stateful_home<'a>(pipeline_id: &str, stage: &Stage, hosts: &[&'a SidecarState]) -> Option<&'a SidecarState> {
+    let runs_stage = |sidecar: &SidecarState| {
+        sidecar
+            .assigned_pipelines
+            .get(pipeline_id)
+            .and_then(|stages| stages.iter().find(|s| s.stage_id == stage.id))
+            .is_some_and(|s| matches!(s.target, SidecarStageTarget::Builtin { .. }))
+    };
+    hosts
+        .iter()
+        .find(|sidecar| runs_stage(sidecar))
+        .or_else(|| hosts.first())
+        .copied()
+}
+
+/// Service stages with a partition key and more than one instance.
+fn is_partitioned(stage: &Stage) -> bool {
+    !stage.partition_key.is_empty()
+        && stage.parallelism > 1
+        && stage.transform_config.is_empty()
+        && !is_fan_stage(stage)
+}
+
+/// Where `sidecar` sends each partition of a partitioned stage, following
+/// the stage's partition group. Empty when the stage is not partitioned or
+/// the group does not yet cover its instances, in which case the stage's
+/// records all go to its target.
+fn partition_owners(
+    state: &RouterState,
+    pipeline_id: &str,
+    stage: &Stage,
+    sidecar: &SidecarState,
+    instances: &[Instance],
+) -> Vec<SidecarStageTarget> {
+    if !is_partitioned(stage) {
+        return Vec::new();
+    }
+    let Some(group) = state.groups.get(&partition_group_id(pipeline_id, &stage.id)) else {
+        return Vec::new();
+    };
+
+    (0..group.total_partitions)
+        .map(|partition| {
+            let owner = partition_owner(group, partition)?;
+            let instance = instances.iter().find(|i| i.sidecar.sidecar_id == owner)?;
+            Some(if owner == sidecar.sidecar_id {
+                SidecarStageTarget::Local {
+                    endpoint: instance.service.local_endpoint.clone(),
+                }
+            } else {
+                SidecarStageTarget::Remote {
+                    sidecar_id: owner.to_string(),
+                    endpoint: instance.sidecar.endpoint.clone(),
+                }
+            })
+        })
+        .collect::<Option<Vec<_>>>()
+        .unwrap_or_default()
+}
+
+fn partition_owner(group: &GroupState, partition: u32) -> Option<&str> {
+    group
+        .partition_assignments
+        .iter()
+        .find(|(_, partitions)| partitions.contains(&partition))
+        .map(|(member, _)| member.as_str())
+}
+
+/// Picks the sidecars that run a service stage: those it already runs on
+/// first, then the rest in id order, up to its parallelism. A stage with a
+/// traffic split also gets an instance of every version it sends traffic
+/// to, even past its parallelism.
+fn place<'a>(pipeline_id: &str, stage: &Stage, sidecars: &[&'a SidecarState]) -> Vec<Instance<'a>> {
+    if !stage.transform_config.is_empty() || is_fan_stage(stage) {
+        return Vec::new();
+    }
+    let Some(selector) = &stage.service_selector else {
+        return Vec::new();
+    };
+
+    let (mut current, mut others): (Vec<_>, Vec<_>) = sidecars
+        .iter()
+        .filter_map(|sidecar| {
+            let service = sidecar.local_services.iter().find(|s| matches(selector, s))?;
+            Some(Instance { sidecar, service })
+        })
+        .partition(|instance| runs_locally(instance.sidecar, pipeline_id, &stage.id));
+
+    current.append(&mut others);
+    let parallelism = stage.parallelism.max(1) as usize;
+    let Some(split) = &selector.traffic_split else {
+        current.truncate(parallelism);
+        return current;
+    };
+
+    let mut placed: Vec<Instance> = Vec::new();
+    let mut remaining = Vec::new();
+    for instance in current {
+        let version = instance.service.labels.get(VERSION_LABEL);
+        let uncovered = version.is_some_and(|version| {
+            split.versions.iter().any(|v| &v.version == version && v.weight > 0)
+                && !placed
+                    .iter()
+                    .any(|p| p.service.labels.get(VERSION_LABEL) == Some(version))
+        });
+        if uncovered {
+            placed.push(instance);
+        } else {
+            remaining.push(instance);
+        }
+    }
+    let room = parallelism.saturating_sub(placed.len());
+    placed.extend(remaining.into_iter().take(room));
+    placed
+}
+
+/// Fan-in and fan-out stages run in every sidecar the records pass through,
+/// so they are not placed or assigned.
+fn is_fan_stage(stage: &Stage) -> bool {
+    matches!(
+        StageType::try_from(stage.stage_type),
+        Ok(StageType::FanIn | StageType::FanOut)
+    )
+}
+
+fn matches(selector: &ServiceSelector, service: &SidecarLocalService) -> bool {
+    if !selector.service_name.is_empty() && selector.service_name != service.service_name {
+        return false;
+    }
+    if !selector.group_id.is_empty() && service.group_id.as_deref() != Some(&selector.group_id) {
+        return false;
+    }
+    selector
+        .labels
+        .iter()
+        .all(|(key, value)| service.labels.get(key) == Some(value))
+}
+
+fn runs_locally(sidecar: &SidecarState, pipeline_id: &str, stage_id: &str) -> bool {
+    sidecar
+        .assigned_pipelines
+        .get(pipeline_id)
+        .and_then(|stages| stages.iter().find(|s| s.stage_id == stage_id))
+        .is_some_and(|s| matches!(s.target, SidecarStageTarget::Local { .. }))
+}
+
+/// The instance `from` should forward to: one on the same node, then in the
+/// same namespace, then any. Ties are spread across sidecars by id.
+fn nearest<'a, 'b>(from: &SidecarState, instances: &'b [Instance<'a>]) -> Option<&'b Instance<'a>> {
+    let distance = |instance: &Instance| {
+        let same_node = from.node_name.is_some() && instance.sidecar.node_name == from.node_name;
+        let same_namespace = instance.sidecar.namespace == from.namespace;
+        match (same_node, same_namespace) {
+            (true, _) => 0,
+            (false, true) => 1,
+            (false, false) => 2,
+        }
+    };
+
+    let closest = instances.iter().map(distance).min()?;
+    let candidates: Vec<_> = instances.iter().filter(|i| distance(i) == closest).collect();
+
+    let mut hasher = DefaultHasher::new();
+    from.sidecar_id.hash(&mut hasher);
+    let index = (hasher.finish() % candidates.len() as u64) as usize;
+    Some(candidates[index])
+}
@@ -0,0 +1,360 @@
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, RwLock};
+use tonic::{Request, Response, Status};
+use tracing::{debug, info};
+
+use crate::error::GrpcError;
+
+use conveyor_etl_proto::schema::{
+    schema_registry_server::SchemaRegistry, CheckCompatibilityRequest,
+    CheckCompatibilityResponse, CompatibilityMode as ProtoCompatibilityMode, DeleteSchemaRequest,
+    DeleteSchemaResponse, GetSchemaRequest, GetSchemaResponse, ListRecordTypesRequest,
+    ListRecordTypesResponse, ListSchemaVersionsRequest, ListSchemaVersionsResponse,
+    RegisterSchemaRequest, RegisterSchemaResponse, Schema, SchemaFormat as ProtoSchemaFormat,
+    SetCompatibilityRequest, SetCompatibilityResponse,
+};
+use conveyor_etl_raft::{
+    ConveyorRaft, RouterCommand, RouterRequest, RouterState, SchemaSubjectState,
+    SchemaVersionState,
+};
+use conveyor_etl_routing::{CompatibilityMode, RecordSchema, SchemaDefinition, SchemaFormat};
+
+pub struct SchemaRegistryImpl {
+    raft: Arc<ConveyorRaft>,
+    state: Arc<RwLock<RouterState>>,
+    register_lock: Mutex<()>,
+}
+
+impl SchemaRegistryImpl {
+    pub fn new(raft: Arc<ConveyorRaft>, state: Arc<RwLock<RouterState>>) -> Self {
+        Self {
+            raft,
+            state,
+            register_lock: Mutex::new(()),
+        }
+    }
+
+    async fn propose(&self, command: RouterCommand) -> Result<(), Status> {
+        let response = self
+            .raft
+            .client_write(RouterRequest { command })
+            .await
+            .map_err(|e| Status::internal(format!("Raft error: {}", e)))?;
+        if !response.data.success {
+            return Err(Status::aborted(
+                response.data.error.unwrap_or_else(|| "Command rejected".to_string()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Compiles `candidate` and checks it against the latest version of the
+    /// record type under the subject's compatibility mode.
+    fn check_against_latest(
+        subject: Option<&SchemaSubjectState>,
+        candidate: &RecordSchema,
+    ) -> Result<(), (u32, CompatibilityMode, Vec<String>)> {
+        let Some(subject) = subject else {
+            return Ok(());
+        };
+        let Some(latest) = subject.latest() else {
+            return Ok(());
+        };
+
+        let mode = subject.compatibility.parse().unwrap_or_default();
+        let previous = match compile_stored(latest) {
+            Ok(previous) => previous,
+            Err(e) => return Err((latest.version, mode, vec![e.message().to_string()])),
+        };
+        candidate
+            .check_compatibility(&previous, mode)
+            .map_err(|errors| (latest.version, mode, errors))
+    }
+}
+
+pub(crate) fn schema_definition_from_proto(
+    format: i32,
+    definition: String,
+    message_name: String,
+) -> Result<SchemaDefinition, Status> {
+    let format = match ProtoSchemaFormat::try_from(format) {
+        Ok(ProtoSchemaFormat::JsonSchema) => SchemaFormat::JsonSchema,
+        Ok(ProtoSchemaFormat::Avro) => SchemaFormat::Avro,
+        Ok(ProtoSchemaFormat::Protobuf) => SchemaFormat::Protobuf,
+        _ => return Err(GrpcError::invalid_field("format", "schema format is required").into()),
+    };
+    if definition.trim().is_empty() {
+        return Err(GrpcError::missing_field("definition").into());
+    }
+
+    Ok(SchemaDefinition {
+        format,
+        definition,
+        message_name: (!message_name.is_empty()).then_some(message_name),
+    })
+}
+
+pub(crate) fn compatibility_from_proto(mode: i32) -> Result<CompatibilityMode, Status> {
+    match ProtoCompatibilityMode::try_from(mode) {
+        Ok(ProtoCompatibilityMode::None) => Ok(CompatibilityMode::None),
+        Ok(ProtoCompatibilityMode::Backward) => Ok(CompatibilityMode::Backward),
+        Ok(ProtoCompatibilityMode::Forward) => Ok(CompatibilityMode::Forward),
+        Ok(ProtoCompatibilityMode::Full) => Ok(CompatibilityMode::Full),
+        _ => Err(GrpcError::invalid_field("compatibility", "compatibility mode is required").into()),
+    }
+}
+
+fn compatibility_to_proto(mode: &str) -> i32 {
+    let proto = match mode.parse::<CompatibilityMode>() {
+        Ok(CompatibilityMode::None) => ProtoCompatibilityMode::None,
+        Ok(CompatibilityMode::Backward) => ProtoCompatibilityMode::Backward,
+        Ok(CompatibilityMode::Forward) => ProtoCompatibilityMode::Forward,
+        Ok(CompatibilityMode::Full) => ProtoCompatibilityMode::Full,
+        Err(_) => ProtoCompatibilityMode::Unspecified,
+    };
+    proto as i32
+}
+
+fn format_to_proto(format: &str) -> i32 {
+    let proto = match format.parse::<SchemaFormat>() {
+        Ok(SchemaFormat::JsonSchema) => ProtoSchemaFormat::JsonSchema,
+        Ok(SchemaFormat::Avro) => ProtoSchemaFormat::Avro,
+        Ok(SchemaFormat::Protobuf) => ProtoSchemaFormat::Protobuf,
+        Err(_) => ProtoSchemaFormat::Unspecified,
+    };
+    proto as i32
+}
+
+fn compile_stored(version: &SchemaVersionState) -> Result<RecordSchema, Status> {
+    let format = version
+        .format
+        .parse()
+        .map_err(|e| GrpcError::internal(format!("stored schema v{}: {}", version.version, e)))?;
+    RecordSchema::compile(SchemaDefinition {
+        format,
+        definition: version.definition.clone(),
+        message_name: version.message_name.clone(),
+    })
+    .map_err(|e| GrpcError::internal(format!("stored schema v{}: {:#}", version.version, e)).into())
+}
+
+fn schema_to_proto(record_type: &str, version: &SchemaVersionState) -> Schema {
+    Schema {
+        record_type: record_type.to_string(),
+        version: version.version,
+        format: format_to_proto(&version.format),
+        definition: version.definition.clone(),
+        message_name: version.message_name.clone().unwrap_or_default(),
+        registered_at: Some(prost_types::Timestamp {
+            seconds: version.registered_at as i64,
+            nanos: 0,
+        }),
+    }
+}
+
+#[tonic::async_trait]
+impl SchemaRegistry for SchemaRegistryImpl {
+    async fn register_schema(
+        &self,
+        request: Request<RegisterSchemaRequest>,
+    ) -> Result<Response<RegisterSchemaResponse>, Status> {
+        let req = request.into_inner();
+        if req.record_type.is_empty() {
+            return Err(GrpcError::missing_field("record_type").into());
+        }
+
+        let definition = schema_definition_from_proto(req.format, req.definition, req.message_name)?;
+        let candidate = RecordSchema::compile(definition)
+            .map_err(|e| GrpcError::invalid_field("definition", format!("{:#}", e)))?;
+
+        let _guard = self.register_lock.lock().await;
+
+        let next_version = {
+            let state = self.state.read().await;
+            let subject = state.schemas.get(&req.record_type);
+
+            if let Some(subject) = subject {
+                for existing in &subject.versions {
+                    if compile_stored(existing)?.same_as(&candidate) {
+                        debug!(
+                            record_type = %req.record_type,
+                            version = existing.version,
+                            "Schema already registered"
+                        );
+                        return Ok(Response::new(RegisterSchemaResponse {
+                            version: existing.version,
+                            already_registered: true,
+                        }));
+                    }
+                }
+            }
+
+            if let Err((version, mode, errors)) = Self::check_against_latest(subject, &candidate) {
+                return Err(GrpcError::incompatible_schema(format!(
+                    "schema for {} is not {} compatible with version {}: {}",
+                    req.record_type,
+                    mode,
+                    version,
+                    errors.join("; ")
+                ))
+                .into());
+            }
+
+            subject
+                .and_then(|s| s.latest())
+                .map(|latest| latest.version + 1)
+                .unwrap_or(1)
+        };
+
+        let definition = candidate.definition().clone();
+        self.propose(RouterCommand::RegisterSchema {
+            record_type: req.record_type.clone(),
+            version: next_version,
+            format: definition.format.as_str().to_string(),
+            definition: definition.definition,
+            message_name: definition.message_name,
+        })
+        .await?;
+
+        info!(
+            record_type = %req.record_type,
+            version = next_version,
+            format = %definition.format,
+            "Registered schema"
+        );
+
+        Ok(Response::new(RegisterSchemaResponse {
+            version: next_version,
+            already_registered: false,
+        }))
+    }
+
+    async fn get_schema(
+        &self,
+        request: Request<GetSchemaRequest>,
+    ) -> Result<Response<GetSchemaResponse>, Status> {
+        let req = request.into_inner();
+        let state = self.state.read().await;
+
+        let subject = state
+            .schemas
+            .get(&req.record_type)
+            .ok_or_else(|| GrpcError::schema_not_found(&req.record_type))?;
+        let version = if req.version == 0 {
+            subject.latest()
+        } else {
+            subject.version(req.version)
+        }
+        .ok_or_else(|| {
+            GrpcError::schema_not_found(format!("{} v{}", req.record_type, req.version))
+        })?;
+
+        Ok(Response::new(GetSchemaResponse {
+            schema: Some(schema_to_proto(&req.record_type, version)),
+            compatibility: compatibility_to_proto(&subject.compatibility),
+        }))
+    }
+
+    async fn list_schema_versions(
+        &self,
+        request: Request<ListSchemaVersionsRequest>,
+    ) -> Result<Response<ListSchemaVersionsResponse>, Status> {
+        let req = request.into_inner();
+        let state = self.state.read().await;
+
+        let subject = state
+            .schemas
+            .get(&req.record_type)
+            .ok_or_else(|| GrpcError::schema_not_found(&req.record_type))?;
+
+        Ok(Response::new(ListSchemaVersionsResponse {
+            versions: subject.versions.iter().map(|v| v.version).collect(),
+            compatibility: compatibility_to_proto(&subject.compatibility),
+        }))
+    }
+
+    async fn list_record_types(
+        &self,
+        _request: Request<ListRecordTypesRequest>,
+    ) -> Result<Response<ListRecordTypesResponse>, Status> {
+        let state = self.state.read().await;
+        let mut record_types: Vec<String> = state.schemas.keys().cloned().collect();
+        record_types.sort();
+
+        Ok(Response::new(ListRecordTypesResponse { record_types }))
+    }
+
+    async fn check_compatibility(
+        &self,
+        request: Request<CheckCompatibilityRequest>,
+    ) -> Result<Response<CheckCompatibilityResponse>, Status> {
+        let req = request.into_inner();
+
+        let definition = schema_definition_from_proto(req.format, req.definition, req.message_name)?;
+        let candidate = RecordSchema::compile(definition)
+            .map_err(|e| GrpcError::invalid_field("definition", format!("{:#}", e)))?;
+
+        let state = self.state.read().await;
+        let subject = state.schemas.get(&req.record_type);
+        let latest_version = subject.and_then(|s| s.latest()).map(|v| v.version).unwrap_or(0);
+
+        let errors = match Self::check_against_latest(subject, &candidate) {
+            Ok(()) => Vec::new(),
+            Err((_, _, errors)) => errors,
+        };
+
+        Ok(Response::new(CheckCompatibilityResponse {
+            compatible: errors.is_empty(),
+            errors,
+            latest_version,
+        }))
+    }
+
+    async fn set_compatibility(
+        &self,
+        request: Request<SetCompatibilityRequest>,
+    ) -> Result<Response<SetCompatibilityResponse>, Status> {
+        let req = request.into_inner();
+        if req.record_type.is_empty() {
+            return Err(GrpcError::missing_field("record_type").into());
+        }
+        let mode = compatibility_from_proto(req.compatibility)?;
+
+        self.propose(RouterCommand::SetSchemaCompatibility {
+            record_type: req.record_type.clone(),
+            compatibility: mode.as_str().to_string(),
+        })
+        .await?;
+
+        info!(record_type = %req.record_type, compatibility = %mode, "Set schema compatibility");
+        Ok(Response::new(SetCompatibilityResponse { success: true }))
+    }
+
+    async fn delete_schema(
+        &self,
+        request: Request<DeleteSchemaRequest>,
+    ) -> Result<Response<DeleteSchemaResponse>, Status> {
+        let req = request.into_inner();
+
+        let deleted_versions = {
+            let state = self.state.read().await;
+            state
+                .schemas
+                .get(&req.record_type)
+                .ok_or_else(|| GrpcError::schema_not_found(&req.record_type))?
+                .versions
+                .iter()
+                .map(|v| v.version)
+                .collect()
+        };
+
+        self.propose(RouterCommand::DeleteSchema {
+            record_type: req.record_type.clone(),
+        })
+        .await?;
+
+        info!(record_type = %req.record_type, "Deleted schema");
+        Ok(Response::new(DeleteSchemaResponse { deleted_versions }))
+    }
+}
@@ -1,32 +1,44 @@
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use openraft::{BasicNode, Config, Raft};
 use tokio::sync::RwLock;
 use tonic::transport::Server;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use conveyor_etl_buffer::BufferManager;
 use conveyor_etl_config::Settings;
 use conveyor_etl_raft::{
-    ConveyorRaft, LogStorage, NetworkFactory, NodeId, RaftServer, RouterState, StateMachine,
-    TypeConfig,
+    ConveyorRaft, LogStorage, NetworkFactory, NodeId, RaftServer, RouterCommand, RouterRequest,
+    RouterState, StateMachine, TypeConfig,
 };
-use conveyor_etl_registry::ServiceRegistry;
+use conveyor_etl_registry::{LoadBalancer, ServiceRegistry};
 use conveyor_etl_routing::RoutingEngine;
 
+use conveyor_etl_proto::router::router_admin_server::RouterAdminServer;
 use conveyor_etl_proto::checkpoint::checkpoint_service_server::CheckpointServiceServer;
 use conveyor_etl_proto::registry::service_registry_server::ServiceRegistryServer;
+use conveyor_etl_proto::schema::schema_registry_server::SchemaRegistryServer;
 use conveyor_etl_proto::sidecar::sidecar_coordinator_server::SidecarCoordinatorServer;
 use conveyor_etl_proto::source::source_router_server::SourceRouterServer;
 
+use super::admin_handler::RouterAdminImpl;
 use super::checkpoint_handler::CheckpointServiceImpl;
+use super::group_coordinator::GroupCoordinator;
 use super::registry_handler::ServiceRegistryImpl;
+use super::schema_handler::SchemaRegistryImpl;
 use super::sidecar_handler::SidecarCoordinatorImpl;
 use super::source_handler::SourceRouterImpl;
 
+const GROUP_SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const GROUP_SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+const SIDECAR_SCHEDULE_INTERVAL: Duration = Duration::from_secs(5);
+const SIDECAR_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+const PIPELINE_SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
 pub struct RouterServer {
     node_id: u64,
     listen_addr: SocketAddr,
@@ -34,6 +46,7 @@ pub struct RouterServer {
     peers: Vec<String>,
     data_dir: String,
     settings: Settings,
+    advertise_addr: String,
 }
 
 impl RouterServer {
@@ -52,9 +65,17 @@ impl RouterServer {
             peers,
             data_dir,
             settings,
+            advertise_addr: listen_addr.to_string(),
         })
     }
 
+    /// The address clients reach this node's gRPC server on, handed out in
+    /// leader hints. Defaults to the listen address.
+    pub fn with_advertise_addr(mut self, addr: impl Into<String>) -> Self {
+        self.advertise_addr = addr.into();
+        self
+    }
+
     fn parse_peers(peers: &[String]) -> BTreeMap<NodeId, BasicNode> {
         peers
             .iter()
@@ -113,10 +134,27 @@ impl RouterServer {
             raft.initialize(members).await?;
         }
 
-        let service_registry = Arc::new(RwLock::new(ServiceRegistry::new(
+        tokio::spawn(publish_advertise_addr(
             raft.clone(),
             router_state.clone(),
-        )));
+            self.node_id,
+            self.advertise_addr.clone(),
+        ));
+
+        let service_registry = Arc::new(RwLock::new(ServiceRegistry::new()));
+
+        let group_coordinator = Arc::new(GroupCoordinator::with_raft(
+            raft.clone(),
+            router_state.clone(),
+        ));
+        tokio::spawn(group_coordinator.clone().run_session_monitor(
+            GROUP_SESSION_CHECK_INTERVAL,
+            GROUP_SESSION_TIMEOUT,
+        ));
+
+        // Only tracks reported load; the router picks no endpoints itself,
+        // so it takes no outlier detector admissions.
+        let load_balancer = Arc::new(LoadBalancer::new());
 
         let buffer_manager = Arc::new(RwLock::new(BufferManager::new(
             self.settings.buffer.clone(),
@@ -131,12 +169,40 @@ impl RouterServer {
             routing_engine.clone(),
         );
 
-        let registry_service =
-            ServiceRegistryImpl::new(raft.clone(), router_state.clone(), service_registry.clone());
+        let registry_service = ServiceRegistryImpl::new(
+            raft.clone(),
+            router_state.clone(),
+            service_registry.clone(),
+            load_balancer.clone(),
+            group_coordinator.clone(),
+        );
 
         let checkpoint_service = CheckpointServiceImpl::new(raft.clone(), router_state.clone());
 
-        let sidecar_coordinator = SidecarCoordinatorImpl::new(raft.clone(), router_state.clone());
+        let sidecar_coordinator = Arc::new(
+            SidecarCoordinatorImpl::new(raft.clone(), router_state.clone())
+                .with_group_coordinator(group_coordinator.clone())
+                .with_topology_key(self.settings.placement.topology_key.clone())
+                .with_load_balancer(load_balancer.clone())
+                .with_routing_engine(routing_engine.clone()),
+        );
+        tokio::spawn(sidecar_coordinator.clone().run_scheduler(
+            SIDECAR_SCHEDULE_INTERVAL,
+            SIDECAR_HEARTBEAT_TIMEOUT,
+        ));
+
+        let schema_registry = SchemaRegistryImpl::new(raft.clone(), router_state.clone());
+
+        let router_admin = Arc::new(
+            RouterAdminImpl::new(
+                raft.clone(),
+                router_state.clone(),
+                routing_engine.clone(),
+                load_balancer.clone(),
+            )
+            .with_reschedule_trigger(sidecar_coordinator.reschedule_trigger()),
+        );
+        tokio::spawn(router_admin.clone().run_pipeline_sync(PIPELINE_SYNC_INTERVAL));
 
         info!("Starting Raft gRPC server on {}...", self.raft_addr);
         let raft_addr = self.raft_addr;
@@ -158,7 +224,9 @@ impl RouterServer {
             .add_service(SourceRouterServer::new(source_router))
             .add_service(ServiceRegistryServer::new(registry_service))
             .add_service(CheckpointServiceServer::new(checkpoint_service))
-            .add_service(SidecarCoordinatorServer::new(sidecar_coordinator))
+            .add_service(SidecarCoordinatorServer::from_arc(sidecar_coordinator))
+            .add_service(SchemaRegistryServer::new(schema_registry))
+            .add_service(RouterAdminServer::from_arc(router_admin))
             .serve(self.listen_addr);
 
         tokio::select! {
@@ -175,3 +243,31 @@ impl RouterServer {
         Ok(())
     }
 }
+
+/// Records this node's client address in the Raft state each time it
+/// becomes leader, so followers can name it in not-leader errors.
+async fn publish_advertise_addr(
+    raft: Arc<ConveyorRaft>,
+    state: Arc<RwLock<RouterState>>,
+    node_id: NodeId,
+    addr: String,
+) {
+    let mut metrics = raft.metrics();
+    loop {
+        let is_leader = metrics.borrow().current_leader == Some(node_id);
+        let published = state.read().await.router_addresses.get(&node_id) == Some(&addr);
+        if is_leader && !published {
+            let command = RouterCommand::SetRouterAddress {
+                node_id,
+                client_addr: addr.clone(),
+            };
+            match raft.client_write(RouterRequest { command }).await {
+                Ok(_) => info!(addr = %addr, "Published router address"),
+                Err(e) => warn!(error = %e, "Failed to publish router address"),
+            }
+        }
+        if metrics.changed().await.is_err() {
+            return;
+        }
+    }
+}
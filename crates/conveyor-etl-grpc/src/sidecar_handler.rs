@@ -1,29 +1,77 @@
+use std::collections::HashSet;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
 use dashmap::DashMap;
-use tokio::sync::RwLock;
+use prost::Message;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, Notify, RwLock};
 use tokio_stream::Stream;
 use tonic::{Request, Response, Status};
 use tracing::{debug, info, warn};
 
+use conveyor_etl_proto::router::PipelineConfig;
 use conveyor_etl_proto::sidecar::{
-    sidecar_command, sidecar_coordinator_server::SidecarCoordinator, HeartbeatResponse,
-    LocalService, PipelineAssignment, PipelineAssignmentEvent, RegisterSidecarRequest,
-    RegisterSidecarResponse, ServiceType, SidecarCommand, SidecarHeartbeatRequest,
-    WatchAssignmentsRequest,
+    partition_owner, sidecar_coordinator_server::SidecarCoordinator, stage_assignment::Target,
+    stage_instance,
+    DrainCompleteRequest, DrainCompleteResponse, EventType, HeartbeatResponse, InstanceLoad, LocalService, PartitionOwner, PipelineAssignment, PipelineAssignmentEvent,
+    RegisterSidecarRequest, RegisterSidecarResponse, RemoteSidecar, ServiceType, SidecarCommand,
+    SidecarHeartbeatRequest, StageAssignment, StageInstance, WatchAssignmentsRequest,
 };
 use conveyor_etl_raft::{
     ConveyorRaft, RouterCommand, RouterRequest, RouterState, SidecarLocalService,
-    SidecarStageTarget,
+    SidecarStageAssignment, SidecarStageTarget,
 };
+use conveyor_etl_registry::{LoadBalancer, VersionStats, ZONE_TOPOLOGY_KEY};
+use conveyor_etl_routing::{LateRecordCounts, RoutingEngine};
+
+use crate::admin_handler::pipeline_from_proto;
+use crate::assignment_feed::AssignmentFeeds;
+use crate::error::GrpcError;
+use crate::group_coordinator::{GroupCoordinator, RebalanceEvent};
+use crate::scheduler::{self, PartitionGroup, StageInstances, PARTITION_GROUP_PREFIX};
 
 type ResponseStream = Pin<Box<dyn Stream<Item = Result<PipelineAssignmentEvent, Status>> + Send>>;
 
+/// A change to one sidecar's assignments found by [`SidecarCoordinatorImpl::reconcile`].
+enum AssignmentChange {
+    Assign {
+        sidecar_id: String,
+        pipeline_id: String,
+        update: bool,
+        version: u64,
+        stages: Vec<SidecarStageAssignment>,
+        assignment: PipelineAssignment,
+    },
+    Revoke {
+        sidecar_id: String,
+        pipeline_id: String,
+    },
+}
+
 pub struct SidecarCoordinatorImpl {
     raft: Arc<ConveyorRaft>,
     state: Arc<RwLock<RouterState>>,
-    pending_assignments: DashMap<String, Vec<PipelineAssignment>>,
+    feeds: Arc<AssignmentFeeds>,
+    /// Commands other than assignment changes, sent with the next heartbeat.
+    pending_commands: DashMap<String, Vec<SidecarCommand>>,
+    /// Pipeline version and stages last pushed to each (sidecar, pipeline),
+    /// so edits and new instances that leave the placement unchanged are
+    /// still pushed.
+    pushed: DashMap<(String, String), (u64, Vec<StageAssignment>)>,
+    reschedule: Arc<Notify>,
+    /// Splits partitioned stages' partitions among their sidecars.
+    groups: Option<Arc<GroupCoordinator>>,
+    /// Label partition groups spread their partitions over.
+    topology_key: Option<String>,
+    /// Keeps the load sidecars report in their heartbeats.
+    load_balancer: Option<Arc<LoadBalancer>>,
+    /// Takes the late records sidecars count at their fan-in stages.
+    routing_engine: Option<Arc<RwLock<RoutingEngine>>>,
+    /// Held for a whole reconcile, so placements computed from different
+    /// states never interleave their proposals.
+    reconcile_lock: Mutex<()>,
 }
 
 impl SidecarCoordinatorImpl {
@@ -31,10 +79,54 @@ impl SidecarCoordinatorImpl {
         Self {
             raft,
             state,
-            pending_assignments: DashMap::new(),
+            feeds: Arc::new(AssignmentFeeds::new()),
+            pending_commands: DashMap::new(),
+            pushed: DashMap::new(),
+            reschedule: Arc::new(Notify::new()),
+            groups: None,
+            topology_key: None,
+            load_balancer: None,
+            routing_engine: None,
+            reconcile_lock: Mutex::new(()),
         }
     }
 
+    /// Partitions stages with a partition key across their sidecars through
+    /// consumer groups in `groups`, rebalancing as sidecars come and go.
+    /// Without it, every sidecar forwards a stage's records to its nearest
+    /// instance.
+    pub fn with_group_coordinator(mut self, groups: Arc<GroupCoordinator>) -> Self {
+        self.groups = Some(groups);
+        self
+    }
+
+    /// Spreads partitioned stages' partitions over the zones their sidecars'
+    /// services are labelled with under `topology_key`.
+    pub fn with_topology_key(mut self, topology_key: Option<String>) -> Self {
+        self.topology_key = topology_key;
+        self
+    }
+
+    /// Records the load each sidecar reports in `load_balancer`, keyed by
+    /// sidecar id, along with its calls to each version of split services.
+    pub fn with_load_balancer(mut self, load_balancer: Arc<LoadBalancer>) -> Self {
+        self.load_balancer = Some(load_balancer);
+        self
+    }
+
+    /// Adds the late records sidecars report to `routing_engine`'s counts,
+    /// next to those of fan-in stages the router runs itself.
+    pub fn with_routing_engine(mut self, routing_engine: Arc<RwLock<RoutingEngine>>) -> Self {
+        self.routing_engine = Some(routing_engine);
+        self
+    }
+
+    /// Wakes the scheduler loop; notify it after pipeline changes so their
+    /// assignments go out without waiting for the next tick.
+    pub fn reschedule_trigger(&self) -> Arc<Notify> {
+        self.reschedule.clone()
+    }
+
     fn convert_local_services(proto_services: Vec<LocalService>) -> Vec<SidecarLocalService> {
         proto_services
             .into_iter()
@@ -44,9 +136,12 @@ impl SidecarCoordinatorImpl {
                     Ok(ServiceType::Source) => "source".to_string(),
                     Ok(ServiceType::Transform) => "transform".to_string(),
                     Ok(ServiceType::Sink) => "sink".to_string(),
+                    Ok(ServiceType::Lookup) => "lookup".to_string(),
                     _ => "unknown".to_string(),
                 },
                 local_endpoint: svc.local_endpoint,
+                group_id: Some(svc.group_id).filter(|g| !g.is_empty()),
+                labels: svc.labels,
             })
             .collect()
     }
@@ -60,67 +155,474 @@ impl SidecarCoordinatorImpl {
         Ok(())
     }
 
-    async fn compute_pipeline_assignments(
-        &self,
-        sidecar_id: &str,
-        local_services: &[SidecarLocalService],
-    ) -> Vec<PipelineAssignment> {
+    fn is_leader(&self) -> bool {
+        let metrics = self.raft.metrics();
+        let metrics = metrics.borrow();
+        metrics.current_leader == Some(metrics.id)
+    }
+
+    /// Fails with a not-leader error naming the leader's client address, if
+    /// it has published one, unless this node is the leader.
+    async fn ensure_leader(&self) -> Result<(), Status> {
+        let (leader, id) = {
+            let metrics = self.raft.metrics();
+            let metrics = metrics.borrow();
+            (metrics.current_leader, metrics.id)
+        };
+        let Some(leader) = leader else {
+            return Err(GrpcError::not_leader().into());
+        };
+        if leader == id {
+            return Ok(());
+        }
+        let error = self
+            .state
+            .read()
+            .await
+            .router_addresses
+            .get(&leader)
+            .map_or_else(GrpcError::not_leader, |addr| GrpcError::leader_is(addr.clone()));
+        Err(error.into())
+    }
+
+    /// Reschedules every pipeline and brings the stored assignments in line,
+    /// publishing events to the sidecars whose assignments changed. Runs
+    /// one at a time, whether called on registration or by the scheduler.
+    pub async fn reconcile(&self) -> Result<(), Status> {
+        let _guard = self.reconcile_lock.lock().await;
+
+        if let Some(groups) = &self.groups {
+            let wanted = scheduler::partition_groups(&*self.state.read().await);
+            sync_partition_groups(groups, wanted, self.topology_key.as_deref()).await;
+        }
+
+        let changes = {
+            let state = self.state.read().await;
+            let mut plans = scheduler::schedule(&state);
+            let instances = scheduler::stage_instances(
+                &state,
+                plans.iter().flat_map(|(sidecar_id, plan)| {
+                    plan.iter()
+                        .map(move |(pipeline_id, stages)| (sidecar_id.as_str(), pipeline_id.as_str(), stages.as_slice()))
+                }),
+            );
+            let mut changes = Vec::new();
+
+            for (sidecar_id, sidecar) in &state.sidecars {
+                let plan = plans.remove(sidecar_id).unwrap_or_default();
+
+                for pipeline_id in sidecar.assigned_pipelines.keys() {
+                    if !plan.contains_key(pipeline_id) {
+                        changes.push(AssignmentChange::Revoke {
+                            sidecar_id: sidecar_id.clone(),
+                            pipeline_id: pipeline_id.clone(),
+                        });
+                    }
+                }
+
+                for (pipeline_id, stages) in plan {
+                    let version = state.pipelines.get(&pipeline_id).map_or(0, |p| p.version);
+                    let assignment = pipeline_assignment(&state, sidecar_id, &pipeline_id, &stages, &instances);
+                    let pushed = self
+                        .pushed
+                        .get(&(sidecar_id.clone(), pipeline_id.clone()))
+                        .is_some_and(|pushed| pushed.0 == version && pushed.1 == assignment.stages);
+                    if sidecar.assigned_pipelines.get(&pipeline_id) == Some(&stages) && pushed {
+                        continue;
+                    }
+                    changes.push(AssignmentChange::Assign {
+                        sidecar_id: sidecar_id.clone(),
+                        update: sidecar.assigned_pipelines.contains_key(&pipeline_id),
+                        pipeline_id,
+                        version,
+                        stages,
+                        assignment,
+                    });
+                }
+            }
+            changes
+        };
+
+        for change in changes {
+            match change {
+                AssignmentChange::Assign {
+                    sidecar_id,
+                    pipeline_id,
+                    update,
+                    version,
+                    stages,
+                    assignment,
+                } => {
+                    debug!(sidecar = %sidecar_id, pipeline = %pipeline_id, "Assigning pipeline to sidecar");
+                    self.propose(RouterCommand::AssignPipelineToSidecar {
+                        pipeline_id: pipeline_id.clone(),
+                        sidecar_id: sidecar_id.clone(),
+                        stage_assignments: stages,
+                    })
+                    .await?;
+                    self.pushed
+                        .insert((sidecar_id.clone(), pipeline_id), (version, assignment.stages.clone()));
+                    let event_type = if update {
+                        EventType::Updated
+                    } else {
+                        EventType::Assigned
+                    };
+                    self.feeds.publish(
+                        &sidecar_id,
+                        PipelineAssignmentEvent {
+                            event_type: event_type as i32,
+                            assignment: Some(assignment),
+                            ..Default::default()
+                        },
+                    );
+                }
+                AssignmentChange::Revoke {
+                    sidecar_id,
+                    pipeline_id,
+                } => {
+                    debug!(sidecar = %sidecar_id, pipeline = %pipeline_id, "Revoking pipeline from sidecar");
+                    self.propose(RouterCommand::RevokePipelineFromSidecar {
+                        pipeline_id: pipeline_id.clone(),
+                        sidecar_id: sidecar_id.clone(),
+                    })
+                    .await?;
+                    self.pushed
+                        .remove(&(sidecar_id.clone(), pipeline_id.clone()));
+                    self.feeds.publish(
+                        &sidecar_id,
+                        PipelineAssignmentEvent {
+                            event_type: EventType::Revoked as i32,
+                            assignment: Some(PipelineAssignment {
+                                pipeline_id,
+                                ..Default::default()
+                            }),
+                            drain_first: true,
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every assignment currently stored for a sidecar.
+    async fn assignments(&self, sidecar_id: &str) -> Vec<PipelineAssignment> {
         let state = self.state.read().await;
+        let instances = scheduler::stage_instances(
+            &state,
+            state.sidecars.values().flat_map(|sidecar| {
+                sidecar.assigned_pipelines.iter().map(|(pipeline_id, stages)| {
+                    (sidecar.sidecar_id.as_str(), pipeline_id.as_str(), stages.as_slice())
+                })
+            }),
+        );
+        state
+            .sidecars
+            .get(sidecar_id)
+            .map(|sidecar| {
+                sidecar
+                    .assigned_pipelines
+                    .iter()
+                    .map(|(pipeline_id, stages)| {
+                        pipeline_assignment(&state, sidecar_id, pipeline_id, stages, &instances)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Deregisters sidecars that missed heartbeats for `timeout` and
+    /// reconciles assignments, on every tick and whenever the reschedule
+    /// trigger fires. Runs on the leader only.
+    pub async fn run_scheduler(self: Arc<Self>, interval: Duration, timeout: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            let tick = tokio::select! {
+                _ = ticker.tick() => true,
+                _ = self.reschedule.notified() => false,
+            };
+
+            if !self.is_leader() {
+                self.feeds.clear();
+                self.pending_commands.clear();
+                self.pushed.clear();
+                continue;
+            }
+
+            if tick {
+                self.expire_sidecars(timeout).await;
+            }
 
-        let mut assignments = Vec::new();
+            if let Err(e) = self.reconcile().await {
+                warn!(error = %e, "Failed to reconcile sidecar assignments");
+            }
+        }
+    }
 
-        for (pipeline_id, pipeline) in &state.pipelines {
-            if !pipeline.enabled {
+    async fn expire_sidecars(&self, timeout: Duration) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let expired: Vec<String> = self
+            .state
+            .read()
+            .await
+            .sidecars
+            .values()
+            .filter(|s| now.saturating_sub(s.last_heartbeat) > timeout.as_secs())
+            .map(|s| s.sidecar_id.clone())
+            .collect();
+
+        for sidecar_id in expired {
+            info!(sidecar = %sidecar_id, "Sidecar missed heartbeats, deregistering");
+            if let Err(e) = self
+                .propose(RouterCommand::DeregisterSidecar {
+                    sidecar_id: sidecar_id.clone(),
+                })
+                .await
+            {
+                warn!(sidecar = %sidecar_id, error = %e, "Failed to deregister sidecar");
                 continue;
             }
+            self.feeds.remove(&sidecar_id);
+            self.pending_commands.remove(&sidecar_id);
+            self.pushed.retain(|(id, _), _| id != &sidecar_id);
+            if let Some(load_balancer) = &self.load_balancer {
+                load_balancer.remove_service(&sidecar_id);
+            }
+        }
+    }
+
+    /// The load last reported by every sidecar but `sidecar_id`, which
+    /// weighs them as instances of the stages it balances.
+    async fn instance_loads(&self, sidecar_id: &str) -> Vec<InstanceLoad> {
+        let Some(load_balancer) = &self.load_balancer else {
+            return Vec::new();
+        };
+        self.state
+            .read()
+            .await
+            .sidecars
+            .values()
+            .filter(|s| s.sidecar_id != sidecar_id)
+            .filter_map(|s| {
+                Some(InstanceLoad {
+                    endpoint: s.endpoint.clone(),
+                    queue_depth: load_balancer.queue_depth(&s.sidecar_id)?,
+                })
+            })
+            .collect()
+    }
+}
 
-            let mut stage_assignments = Vec::new();
-            let mut all_local = true;
-
-            for svc in local_services {
-                stage_assignments.push(conveyor_etl_proto::sidecar::StageAssignment {
-                    stage_id: svc.service_name.clone(),
-                    target: Some(
-                        conveyor_etl_proto::sidecar::stage_assignment::Target::LocalEndpoint(
-                            svc.local_endpoint.clone(),
-                        ),
-                    ),
-                });
+/// The assignment sent to a sidecar for its stages of a pipeline.
+pub(crate) fn pipeline_assignment(
+    state: &RouterState,
+    sidecar_id: &str,
+    pipeline_id: &str,
+    stages: &[SidecarStageAssignment],
+    instances: &StageInstances,
+) -> PipelineAssignment {
+    PipelineAssignment {
+        pipeline_id: pipeline_id.to_string(),
+        is_local_complete: scheduler::is_local_complete(stages),
+        stages: stages
+            .iter()
+            .map(|s| StageAssignment {
+                stage_id: s.stage_id.clone(),
+                target: Some(match &s.target {
+                    SidecarStageTarget::Local { endpoint } => Target::LocalEndpoint(endpoint.clone()),
+                    SidecarStageTarget::Remote {
+                        sidecar_id,
+                        endpoint,
+                    } => Target::RemoteSidecar(RemoteSidecar {
+                        sidecar_id: sidecar_id.clone(),
+                        endpoint: endpoint.clone(),
+                    }),
+                    SidecarStageTarget::Builtin { config } => Target::BuiltinTransform(config.clone()),
+                }),
+                partitions: s.partitions.iter().map(partition_owner).collect(),
+                instances: match s.target {
+                    SidecarStageTarget::Builtin { .. } => Vec::new(),
+                    _ => instances
+                        .get(&(pipeline_id.to_string(), s.stage_id.clone()))
+                        .into_iter()
+                        .flatten()
+                        .map(|instance| stage_instance(sidecar_id, instance))
+                        .collect(),
+                },
+            })
+            .collect(),
+        stage_graph: state
+            .pipelines
+            .get(pipeline_id)
+            .and_then(|p| PipelineConfig::decode(p.config.as_slice()).ok())
+            .map(|config| stage_graph(&config))
+            .unwrap_or_default(),
+    }
+}
+
+/// An instance of a stage as `sidecar_id` reaches it: its own service
+/// directly, any other through the sidecar running it.
+fn stage_instance(sidecar_id: &str, instance: &scheduler::StageInstance) -> StageInstance {
+    StageInstance {
+        target: Some(if instance.sidecar_id == sidecar_id {
+            stage_instance::Target::LocalEndpoint(instance.local_endpoint.clone())
+        } else {
+            stage_instance::Target::RemoteSidecar(RemoteSidecar {
+                sidecar_id: instance.sidecar_id.clone(),
+                endpoint: instance.endpoint.clone(),
+            })
+        }),
+        labels: instance.labels.clone(),
+    }
+}
+
+fn partition_owner(target: &SidecarStageTarget) -> PartitionOwner {
+    PartitionOwner {
+        target: match target {
+            SidecarStageTarget::Local { endpoint } => {
+                Some(partition_owner::Target::LocalEndpoint(endpoint.clone()))
             }
+            SidecarStageTarget::Remote {
+                sidecar_id,
+                endpoint,
+            } => Some(partition_owner::Target::RemoteSidecar(RemoteSidecar {
+                sidecar_id: sidecar_id.clone(),
+                endpoint: endpoint.clone(),
+            })),
+            SidecarStageTarget::Builtin { .. } => None,
+        },
+    }
+}
 
-            for (other_sidecar_id, other_sidecar) in &state.sidecars {
-                if other_sidecar_id == sidecar_id {
+/// Brings the members of partition groups in line with the sidecars their
+/// stages are placed on, creating groups for newly partitioned stages and
+/// emptying those of stages no longer partitioned. Joining and leaving
+/// rebalance a group's partitions; members that stay are heartbeated so the
+/// group's session monitor keeps them.
+///
+/// A group keeps the partition count it was created with, so changing a
+/// stage's parallelism does not change how its keys are partitioned. With a
+/// `topology_key`, groups spread their partitions over the zones members'
+/// services are labelled with.
+pub(crate) async fn sync_partition_groups(
+    groups: &GroupCoordinator,
+    wanted: Vec<PartitionGroup>,
+    topology_key: Option<&str>,
+) {
+    let wanted_ids: HashSet<&str> = wanted.iter().map(|g| g.group_id.as_str()).collect();
+    for group_id in groups.list_groups().await {
+        if !group_id.starts_with(PARTITION_GROUP_PREFIX) || wanted_ids.contains(group_id.as_str()) {
+            continue;
+        }
+        let members = groups
+            .get_group(&group_id)
+            .await
+            .map(|g| g.members.into_keys().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for member in members {
+            log_rebalance(&group_id, groups.leave_group(&group_id, &member).await);
+        }
+    }
+
+    for group in wanted {
+        let current = match groups.get_group(&group.group_id).await {
+            Some(current) => current,
+            None => {
+                if let Err(e) = groups
+                    .create_group(group.group_id.clone(), group.stage_id.clone(), group.partitions)
+                    .await
+                {
+                    warn!(group = %group.group_id, error = %e, "Failed to create partition group");
                     continue;
                 }
-                for svc in &other_sidecar.local_services {
-                    if !local_services
-                        .iter()
-                        .any(|ls| ls.service_name == svc.service_name)
-                    {
-                        all_local = false;
-                        stage_assignments.push(conveyor_etl_proto::sidecar::StageAssignment {
-                            stage_id: svc.service_name.clone(),
-                            target: Some(
-                                conveyor_etl_proto::sidecar::stage_assignment::Target::RemoteSidecar(
-                                    conveyor_etl_proto::sidecar::RemoteSidecar {
-                                        sidecar_id: other_sidecar_id.clone(),
-                                        endpoint: other_sidecar.endpoint.clone(),
-                                    },
-                                ),
-                            ),
-                        });
-                    }
+                info!(group = %group.group_id, partitions = group.partitions, "Created partition group");
+                match groups.get_group(&group.group_id).await {
+                    Some(current) => current,
+                    None => continue,
                 }
             }
+        };
+        if current.total_partitions != group.partitions {
+            warn!(
+                group = %group.group_id,
+                partitions = current.total_partitions,
+                parallelism = group.partitions,
+                "Stage parallelism changed; keeping the partition group's partition count"
+            );
+        }
+
+        if let Some(key) = topology_key.filter(|k| current.topology_key.as_deref() != Some(*k)) {
+            log_rebalance(
+                &group.group_id,
+                groups.set_topology_key(&group.group_id, Some(key.to_string())).await,
+            );
+        }
+        let zone_key = topology_key
+            .or(current.topology_key.as_deref())
+            .unwrap_or(ZONE_TOPOLOGY_KEY);
+
+        for member in current.members.keys().filter(|m| !group.members.contains(m)) {
+            log_rebalance(&group.group_id, groups.leave_group(&group.group_id, member).await);
+        }
+        for member in &group.members {
+            if current.members.contains_key(member) {
+                if let Err(e) = groups.heartbeat(&group.group_id, member).await {
+                    warn!(group = %group.group_id, member = %member, error = %e, "Failed to heartbeat partition group member");
+                }
+            } else {
+                let zone = group
+                    .member_labels
+                    .get(member)
+                    .and_then(|labels| labels.get(zone_key))
+                    .cloned();
+                log_rebalance(
+                    &group.group_id,
+                    groups.join_group_in_zone(&group.group_id, member.clone(), zone).await,
+                );
+            }
+        }
+    }
+}
 
-            assignments.push(PipelineAssignment {
-                pipeline_id: pipeline_id.clone(),
-                is_local_complete: all_local,
-                stages: stage_assignments,
-            });
+fn log_rebalance(group_id: &str, result: anyhow::Result<Vec<RebalanceEvent>>) {
+    match result {
+        Ok(events) => {
+            for event in events {
+                match event {
+                    RebalanceEvent::PartitionsAssigned {
+                        service_id,
+                        partitions,
+                        generation,
+                    } => info!(group = group_id, sidecar = %service_id, ?partitions, generation, "Partitions assigned"),
+                    RebalanceEvent::PartitionsRevoked {
+                        service_id,
+                        partitions,
+                        generation,
+                    } => info!(group = group_id, sidecar = %service_id, ?partitions, generation, "Partitions revoked"),
+                }
+            }
         }
+        Err(e) => warn!(group = group_id, error = %e, "Failed to update partition group"),
+    }
+}
 
-        assignments
+/// The pipeline's stages and edges as JSON, which the sidecar follows to
+/// pass each stage's output on to the next.
+fn stage_graph(config: &PipelineConfig) -> String {
+    let encoded = pipeline_from_proto(config)
+        .map_err(|e| e.message().to_string())
+        .and_then(|pipeline| serde_json::to_string(&pipeline).map_err(|e| e.to_string()));
+    match encoded {
+        Ok(graph) => graph,
+        Err(e) => {
+            warn!(pipeline = %config.id, error = %e, "Failed to encode pipeline stage graph");
+            String::new()
+        }
     }
 }
 
@@ -138,6 +640,7 @@ impl SidecarCoordinator for SidecarCoordinatorImpl {
             req.pod_name,
             req.local_services.len()
         );
+        self.ensure_leader().await?;
 
         let local_services = Self::convert_local_services(req.local_services.clone());
 
@@ -146,22 +649,34 @@ impl SidecarCoordinator for SidecarCoordinatorImpl {
             pod_name: req.pod_name.clone(),
             namespace: req.namespace.clone(),
             endpoint: req.sidecar_endpoint.clone(),
-            local_services: local_services.clone(),
+            local_services,
+            node_name: Some(req.node_name.clone()).filter(|n| !n.is_empty()),
         };
 
-        if let Err(e) = self.propose(command).await {
-            warn!("Failed to propose sidecar registration: {}", e);
+        let registered = match self.propose(command).await {
+            Ok(()) => {
+                self.feeds.reset(&req.sidecar_id);
+                self.pending_commands.remove(&req.sidecar_id);
+                self.pushed.retain(|(id, _), _| id != &req.sidecar_id);
+                self.reconcile().await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = registered {
+            warn!("Failed to register sidecar: {}", e);
             return Ok(Response::new(RegisterSidecarResponse {
                 success: false,
                 error: e.to_string(),
                 registration_id: String::new(),
                 initial_assignments: Vec::new(),
+                resume_token: String::new(),
             }));
         }
 
-        let initial_assignments = self
-            .compute_pipeline_assignments(&req.sidecar_id, &local_services)
-            .await;
+        // Taken before reading the assignments, so watching from it replays
+        // anything published in between.
+        let resume_token = self.feeds.token(&req.sidecar_id);
+        let initial_assignments = self.assignments(&req.sidecar_id).await;
 
         let registration_id = uuid::Uuid::new_v4().to_string();
 
@@ -176,6 +691,7 @@ impl SidecarCoordinator for SidecarCoordinatorImpl {
             error: String::new(),
             registration_id,
             initial_assignments,
+            resume_token,
         }))
     }
 
@@ -186,6 +702,12 @@ impl SidecarCoordinator for SidecarCoordinatorImpl {
         let req = request.into_inner();
         debug!("Heartbeat from sidecar {}", req.sidecar_id);
 
+        self.ensure_leader().await?;
+        // Tells a sidecar the router expired or never knew to register again
+        if !self.state.read().await.sidecars.contains_key(&req.sidecar_id) {
+            return Err(GrpcError::sidecar_not_found(req.sidecar_id).into());
+        }
+
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -200,22 +722,52 @@ impl SidecarCoordinator for SidecarCoordinatorImpl {
             warn!("Failed to update sidecar heartbeat: {}", e);
         }
 
+        if let Some(load_balancer) = &self.load_balancer {
+            if let Some(load) = &req.load {
+                // Calls in flight and batches waiting both add to the sidecar's cost
+                load_balancer.update_load(
+                    &req.sidecar_id,
+                    0.0,
+                    load.total_active_requests + load.local_queue_depth,
+                );
+            }
+            for stats in &req.version_stats {
+                load_balancer.add_version_stats(
+                    &stats.service_name,
+                    &stats.version,
+                    &VersionStats {
+                        requests: stats.requests,
+                        errors: stats.errors,
+                        total_latency_ms: stats.total_latency_ms,
+                    },
+                );
+            }
+        }
+
+        if let Some(routing_engine) = &self.routing_engine {
+            let routing_engine = routing_engine.read().await;
+            for late in &req.late_records {
+                routing_engine.add_late_records(
+                    &late.pipeline_id,
+                    &late.stage_id,
+                    LateRecordCounts {
+                        side_output: late.side_output,
+                        dropped: late.dropped,
+                    },
+                );
+            }
+        }
+
         let commands = self
-            .pending_assignments
+            .pending_commands
             .remove(&req.sidecar_id)
-            .map(|(_, assignments)| {
-                assignments
-                    .into_iter()
-                    .map(|assignment| SidecarCommand {
-                        command: Some(sidecar_command::Command::Assign(assignment)),
-                    })
-                    .collect()
-            })
+            .map(|(_, commands)| commands)
             .unwrap_or_default();
 
         Ok(Response::new(HeartbeatResponse {
             acknowledged: true,
             commands,
+            instance_loads: self.instance_loads(&req.sidecar_id).await,
         }))
     }
 
@@ -231,59 +783,52 @@ impl SidecarCoordinator for SidecarCoordinatorImpl {
             req.sidecar_id
         );
 
-        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        self.ensure_leader().await?;
+        if !self.state.read().await.sidecars.contains_key(&req.sidecar_id) {
+            return Err(GrpcError::sidecar_not_found(req.sidecar_id).into());
+        }
 
-        let state = self.state.clone();
-        let sidecar_id = req.sidecar_id.clone();
+        let (events, mut updates) = match self.feeds.resume(&req.sidecar_id, &req.resume_token) {
+            Some(resumed) => resumed,
+            None => {
+                let token = self.feeds.token(&req.sidecar_id);
+                let snapshot = PipelineAssignmentEvent {
+                    event_type: EventType::Snapshot as i32,
+                    resume_token: token.clone(),
+                    snapshot: self.assignments(&req.sidecar_id).await,
+                    drain_first: true,
+                    ..Default::default()
+                };
+                let (events, updates) = self
+                    .feeds
+                    .resume(&req.sidecar_id, &token)
+                    .ok_or_else(|| Status::unavailable("Assignment feed was reset, retry"))?;
+                (std::iter::once(snapshot).chain(events).collect(), updates)
+            }
+        };
 
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let sidecar_id = req.sidecar_id;
 
+        tokio::spawn(async move {
+            for event in events {
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
             loop {
-                interval.tick().await;
-
-                let state = state.read().await;
-
-                if let Some(sidecar) = state.sidecars.get(&sidecar_id) {
-                    for (pipeline_id, stages) in &sidecar.assigned_pipelines {
-                        let assignment = PipelineAssignment {
-                            pipeline_id: pipeline_id.clone(),
-                            is_local_complete: false,
-                            stages: stages
-                                .iter()
-                                .map(|s| conveyor_etl_proto::sidecar::StageAssignment {
-                                    stage_id: s.stage_id.clone(),
-                                    target: match &s.target {
-                                        SidecarStageTarget::Local { endpoint } => Some(
-                                            conveyor_etl_proto::sidecar::stage_assignment::Target::LocalEndpoint(
-                                                endpoint.clone(),
-                                            ),
-                                        ),
-                                        SidecarStageTarget::Remote {
-                                            sidecar_id,
-                                            endpoint,
-                                        } => Some(
-                                            conveyor_etl_proto::sidecar::stage_assignment::Target::RemoteSidecar(
-                                                conveyor_etl_proto::sidecar::RemoteSidecar {
-                                                    sidecar_id: sidecar_id.clone(),
-                                                    endpoint: endpoint.clone(),
-                                                },
-                                            ),
-                                        ),
-                                    },
-                                })
-                                .collect(),
-                        };
-
-                        let event = PipelineAssignmentEvent {
-                            event_type: conveyor_etl_proto::sidecar::EventType::Assigned as i32,
-                            assignment: Some(assignment),
-                        };
-
+                match updates.recv().await {
+                    Ok(event) => {
                         if tx.send(Ok(event)).await.is_err() {
                             return;
                         }
                     }
+                    // The watcher resumes from its last event on reconnect
+                    Err(RecvError::Lagged(_)) => {
+                        debug!(sidecar = %sidecar_id, "Assignment watcher lagged, closing stream");
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
                 }
             }
         });
@@ -293,4 +838,32 @@ impl SidecarCoordinator for SidecarCoordinatorImpl {
             Box::pin(stream) as Self::WatchPipelineAssignmentsStream
         ))
     }
+
+    async fn report_drain_complete(
+        &self,
+        request: Request<DrainCompleteRequest>,
+    ) -> Result<Response<DrainCompleteResponse>, Status> {
+        let req = request.into_inner();
+        if !self.state.read().await.sidecars.contains_key(&req.sidecar_id) {
+            return Err(GrpcError::sidecar_not_found(req.sidecar_id).into());
+        }
+
+        if req.timed_out {
+            warn!(
+                sidecar = %req.sidecar_id,
+                pipelines = ?req.pipeline_ids,
+                revoked = req.revoked,
+                "Sidecar drain timed out with batches in flight"
+            );
+        } else {
+            info!(
+                sidecar = %req.sidecar_id,
+                pipelines = ?req.pipeline_ids,
+                revoked = req.revoked,
+                "Sidecar drained pipelines"
+            );
+        }
+
+        Ok(Response::new(DrainCompleteResponse { acknowledged: true }))
+    }
 }
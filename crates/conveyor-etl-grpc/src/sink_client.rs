@@ -1,9 +1,14 @@
+use std::sync::Arc;
+use std::time::Instant;
+
 use anyhow::Result;
 use tonic::transport::Channel;
 
+use conveyor_etl_registry::{LoadBalancer, OutlierDetector, RegisteredService};
+
 use conveyor_etl_proto::sink::{
     sink_service_client::SinkServiceClient,
-    WriteBatchRequest, WriteBatchResponse, WriteOptions,
+    WriteBatchRequest, WriteBatchResponse, WriteOptions, WriteStatus,
     CapacityResponse, FlushRequest, FlushResponse,
 };
 use conveyor_etl_proto::common::{RecordBatch, Empty};
@@ -11,12 +16,39 @@ use conveyor_etl_proto::common::{RecordBatch, Empty};
 pub struct SinkClient {
     client: SinkServiceClient<Channel>,
     sink_id: String,
+    endpoint: String,
+    outlier_detector: Option<Arc<OutlierDetector>>,
+    load_balancer: Option<(Arc<LoadBalancer>, RegisteredService)>,
 }
 
 impl SinkClient {
     pub async fn connect(addr: String, sink_id: String) -> Result<Self> {
-        let client = SinkServiceClient::connect(addr).await?;
-        Ok(Self { client, sink_id })
+        let client = SinkServiceClient::connect(addr.clone()).await?;
+        Ok(Self {
+            client,
+            sink_id,
+            endpoint: addr,
+            outlier_detector: None,
+            load_balancer: None,
+        })
+    }
+
+    pub fn with_outlier_detector(mut self, outlier_detector: Arc<OutlierDetector>) -> Self {
+        self.outlier_detector = Some(outlier_detector);
+        self
+    }
+
+    pub fn with_load_balancer(
+        mut self,
+        load_balancer: Arc<LoadBalancer>,
+        service: RegisteredService,
+    ) -> Self {
+        self.load_balancer = Some((load_balancer, service));
+        self
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
     }
 
     pub async fn write_batch(
@@ -30,8 +62,40 @@ impl SinkClient {
             options: Some(options),
         };
 
-        let response = self.client.write_batch(request).await?;
-        Ok(response.into_inner())
+        let started = match &self.load_balancer {
+            Some((lb, service)) => lb.start_request(&service.service_id),
+            None => Instant::now(),
+        };
+        let result = self.client.write_batch(request).await;
+        // A sink that answers but fails the whole write is as unhealthy as
+        // one that does not answer.
+        let success = result
+            .as_ref()
+            .is_ok_and(|response| response.get_ref().status() != WriteStatus::Failed);
+
+        if let Some((lb, service)) = &self.load_balancer {
+            lb.finish_request(&service.service_id, started);
+            if let Some(version) = service.version() {
+                let latency = started.elapsed();
+                lb.record_version_outcome(&service.service_name, version, success, latency);
+                conveyor_etl_metrics::record_version_request(
+                    &service.service_name,
+                    version,
+                    success,
+                    latency.as_secs_f64() * 1000.0,
+                );
+            }
+        }
+
+        if let Some(detector) = &self.outlier_detector {
+            if success {
+                detector.record_success(&self.endpoint, started.elapsed());
+            } else {
+                detector.record_failure(&self.endpoint);
+            }
+        }
+
+        Ok(result?.into_inner())
     }
 
     pub async fn get_capacity(&mut self) -> Result<CapacityResponse> {
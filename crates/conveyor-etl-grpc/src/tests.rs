@@ -213,6 +213,278 @@ mod sink_client_tests {
 
 #[cfg(test)]
 mod end_to_end_tests {
+    use std::collections::HashMap;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+
+    use futures::Stream;
+    use prost::Message;
+    use tokio::sync::RwLock;
+    use tonic::{Request, Response, Status, Streaming};
+
+    use conveyor_etl_proto::common::{Empty, Record, RecordBatch, RecordId};
+    use conveyor_etl_proto::lookup::lookup_service_server::{LookupService, LookupServiceServer};
+    use conveyor_etl_proto::lookup::{
+        BatchLookupRequest, BatchLookupResponse, Capabilities as LookupCapabilities, LookupRequest,
+        LookupResponse, LookupStatus,
+    };
+    use conveyor_etl_proto::router::{Edge, PipelineConfig, ServiceSelector, Stage, StageType};
+    use conveyor_etl_proto::sidecar::sidecar_data_plane_server::SidecarDataPlane;
+    use conveyor_etl_proto::sidecar::{AckStatus, ReceiveRecordsRequest};
+    use conveyor_etl_proto::transform::transform_service_server::{
+        TransformService, TransformServiceServer,
+    };
+    use conveyor_etl_proto::transform::{
+        Capabilities, ProcessBatchRequest, ProcessBatchResponse, ProcessStreamRequest,
+        ProcessStreamResponse, TransformResult, TransformStatus,
+    };
+    use conveyor_etl_raft::{PipelineState, RouterState, SidecarLocalService, SidecarState};
+    use conveyor_etl_sidecar::cluster_client::convert_assignment_to_routes;
+    use conveyor_etl_sidecar::routing::{LocalRouter, RemoteRouter, RoutingTable};
+    use conveyor_etl_sidecar::{SidecarDataPlaneImpl, TransformOptions};
+
+    use crate::admin_handler::pipeline_from_proto;
+    use crate::scheduler::{schedule, stage_instances};
+    use crate::sidecar_handler::pipeline_assignment;
+
+    /// A lookup service knowing only user `u1`, a gold tier user.
+    struct Users;
+
+    impl Users {
+        fn find(record: Record) -> LookupResponse {
+            let found = record.metadata.get("id").is_some_and(|id| id == "u1");
+            LookupResponse {
+                original_id: record.id,
+                status: if found {
+                    LookupStatus::Found as i32
+                } else {
+                    LookupStatus::NotFound as i32
+                },
+                lookup_data: if found {
+                    HashMap::from([("tier".to_string(), b"gold".to_vec())])
+                } else {
+                    HashMap::new()
+                },
+                ..Default::default()
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl LookupService for Users {
+        async fn lookup(&self, request: Request<LookupRequest>) -> Result<Response<LookupResponse>, Status> {
+            let record = request.into_inner().input_record.unwrap_or_default();
+            Ok(Response::new(Self::find(record)))
+        }
+
+        async fn batch_lookup(
+            &self,
+            request: Request<BatchLookupRequest>,
+        ) -> Result<Response<BatchLookupResponse>, Status> {
+            let results = request.into_inner().records.into_iter().map(Self::find).collect();
+            Ok(Response::new(BatchLookupResponse {
+                results,
+                ..Default::default()
+            }))
+        }
+
+        async fn get_capabilities(
+            &self,
+            _request: Request<Empty>,
+        ) -> Result<Response<LookupCapabilities>, Status> {
+            Ok(Response::new(LookupCapabilities::default()))
+        }
+    }
+
+    /// A transform service passing records through, keeping those it saw.
+    struct Capture {
+        seen: Arc<Mutex<Vec<Record>>>,
+    }
+
+    #[tonic::async_trait]
+    impl TransformService for Capture {
+        async fn process_batch(
+            &self,
+            request: Request<ProcessBatchRequest>,
+        ) -> Result<Response<ProcessBatchResponse>, Status> {
+            let batch = request.into_inner().input_batch.unwrap_or_default();
+            self.seen.lock().unwrap().extend(batch.records.iter().cloned());
+            let results = batch
+                .records
+                .into_iter()
+                .map(|record| TransformResult {
+                    original_id: record.id.clone(),
+                    status: TransformStatus::Success as i32,
+                    output_records: vec![record],
+                    error_message: String::new(),
+                })
+                .collect();
+            Ok(Response::new(ProcessBatchResponse {
+                batch_id: batch.batch_id,
+                results,
+            }))
+        }
+
+        type ProcessStreamStream =
+            Pin<Box<dyn Stream<Item = Result<ProcessStreamResponse, Status>> + Send>>;
+
+        async fn process_stream(
+            &self,
+            _request: Request<Streaming<ProcessStreamRequest>>,
+        ) -> Result<Response<Self::ProcessStreamStream>, Status> {
+            Err(Status::unimplemented("streaming"))
+        }
+
+        async fn get_capabilities(&self, _request: Request<Empty>) -> Result<Response<Capabilities>, Status> {
+            Ok(Response::new(Capabilities::default()))
+        }
+    }
+
+    /// Serves `router` on a free local port, returning its endpoint.
+    async fn serve(router: tonic::transport::server::Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let incoming = futures::stream::unfold(listener, |listener| async {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        tokio::spawn(router.serve_with_incoming(incoming));
+        endpoint
+    }
+
+    fn local_service(name: &str, service_type: &str, endpoint: &str) -> SidecarLocalService {
+        SidecarLocalService {
+            service_name: name.to_string(),
+            service_type: service_type.to_string(),
+            local_endpoint: endpoint.to_string(),
+            group_id: None,
+            labels: HashMap::new(),
+        }
+    }
+
+    fn user(seq: u64, user_id: &str) -> Record {
+        Record {
+            id: Some(RecordId {
+                source_id: "orders".to_string(),
+                sequence_number: seq,
+                ..Default::default()
+            }),
+            record_type: "order".to_string(),
+            payload: format!(r#"{{"user_id":"{}"}}"#, user_id).into_bytes().into(),
+            ..Default::default()
+        }
+    }
+
+    /// A lookup stage configured through the admin API is assigned to the
+    /// sidecar hosting its service, which enriches records with it.
+    #[tokio::test]
+    async fn test_lookup_stage_e2e() {
+        let users = LookupServiceServer::new(Users);
+        let lookup_endpoint = serve(tonic::transport::Server::builder().add_service(users)).await;
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let capture = TransformServiceServer::new(Capture { seen: seen.clone() });
+        let audit_endpoint = serve(tonic::transport::Server::builder().add_service(capture)).await;
+
+        let selector = |service: &str| {
+            Some(ServiceSelector {
+                service_name: service.to_string(),
+                ..Default::default()
+            })
+        };
+        let config = PipelineConfig {
+            id: "p1".to_string(),
+            name: "p1".to_string(),
+            stages: vec![
+                Stage {
+                    id: "users".to_string(),
+                    name: "users".to_string(),
+                    stage_type: StageType::Lookup as i32,
+                    service_selector: selector("user-cache"),
+                    lookup_config: r#"{"key_fields":[{"record_field":"user_id","lookup_key":"id"}],"output_prefix":"user_","merge_strategy":"Merge","on_miss":"Drop","timeout_ms":1000}"#.to_string(),
+                    ..Default::default()
+                },
+                Stage {
+                    id: "audit".to_string(),
+                    name: "audit".to_string(),
+                    stage_type: StageType::Transform as i32,
+                    service_selector: selector("auditor"),
+                    ..Default::default()
+                },
+            ],
+            edges: vec![Edge {
+                from_stage: "users".to_string(),
+                to_stage: "audit".to_string(),
+                condition: None,
+            }],
+            enabled: true,
+            ..Default::default()
+        };
+        pipeline_from_proto(&config).unwrap();
+
+        let mut state = RouterState::default();
+        state.pipelines.insert(
+            "p1".to_string(),
+            PipelineState {
+                pipeline_id: "p1".to_string(),
+                name: "p1".to_string(),
+                config: config.encode_to_vec(),
+                enabled: true,
+                version: 1,
+            },
+        );
+        state.sidecars.insert(
+            "a".to_string(),
+            SidecarState {
+                sidecar_id: "a".to_string(),
+                pod_name: "a".to_string(),
+                namespace: "default".to_string(),
+                node_name: None,
+                endpoint: "a:50053".to_string(),
+                local_services: vec![
+                    local_service("user-cache", "lookup", &lookup_endpoint),
+                    local_service("auditor", "transform", &audit_endpoint),
+                ],
+                assigned_pipelines: HashMap::new(),
+                registered_at: 0,
+                last_heartbeat: 0,
+            },
+        );
+
+        let stages = schedule(&state)["a"]["p1"].clone();
+        let instances = stage_instances(&state, [("a", "p1", stages.as_slice())]);
+        let assignment = pipeline_assignment(&state, "a", "p1", &stages, &instances);
+
+        let mut table = RoutingTable::new();
+        table.set_pipeline_routes(convert_assignment_to_routes(assignment, &TransformOptions::default()));
+        let plane = SidecarDataPlaneImpl::new(
+            Arc::new(RwLock::new(table)),
+            Arc::new(LocalRouter::new()),
+            Arc::new(RemoteRouter::new()),
+            "a".to_string(),
+        );
+
+        let response = plane
+            .receive_records(Request::new(ReceiveRecordsRequest {
+                pipeline_id: "p1".to_string(),
+                stage_id: "users".to_string(),
+                source_sidecar_id: "b".to_string(),
+                batch: Some(RecordBatch {
+                    batch_id: "b1".to_string(),
+                    records: vec![user(1, "u1"), user(2, "u2")],
+                    watermark: None,
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert!(response.success, "{}", response.error);
+        assert!(response.record_acks.iter().all(|a| a.status == AckStatus::Success as i32));
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1, "the user without a match is dropped");
+        assert_eq!(seen[0].metadata.get("user_tier").map(String::as_str), Some("gold"));
+    }
+
     #[tokio::test]
     #[ignore = "E2E tests need full integration"]
     async fn test_simple_pipeline_e2e() {
@@ -261,3 +533,1497 @@ mod end_to_end_tests {
         todo!("Implement router failover E2E test")
     }
 }
+
+#[cfg(test)]
+mod admin_handler_tests {
+    use std::collections::HashMap;
+
+    use conveyor_etl_proto::router::{
+        condition::Condition as ProtoConditionKind, Condition, Edge, LoadBalanceStrategy,
+        PipelineConfig, RoutingMode, RoutingRule, ServiceSelector, Stage, StageType, TrafficSplit,
+        VersionWeight,
+    };
+
+    use conveyor_etl_raft::{RouterCommand, RouterState};
+    use conveyor_etl_routing::{PartitionKey, RoutingEngine};
+    use prost::Message;
+
+    use crate::admin_handler::{pipeline_from_proto, sync_routing_engine, traffic_split_from_proto};
+
+    fn stage(id: &str, stage_type: StageType, traffic_split: Option<TrafficSplit>) -> Stage {
+        Stage {
+            id: id.to_string(),
+            name: id.to_string(),
+            stage_type: stage_type as i32,
+            service_selector: Some(ServiceSelector {
+                service_name: format!("{}-service", id),
+                group_id: String::new(),
+                labels: HashMap::new(),
+                load_balance: LoadBalanceStrategy::LoadBalancePeakEwma as i32,
+                traffic_split,
+            }),
+            routing_rules: vec![],
+            parallelism: 0,
+            routing_mode: RoutingMode::Unspecified as i32,
+            transform_config: String::new(),
+            fan_config: String::new(),
+            partition_key: String::new(),
+            lookup_config: String::new(),
+        }
+    }
+
+    fn canary_split() -> TrafficSplit {
+        TrafficSplit {
+            versions: vec![
+                VersionWeight { version: "v1".to_string(), weight: 95 },
+                VersionWeight { version: "v2".to_string(), weight: 5 },
+            ],
+            sticky: true,
+        }
+    }
+
+    #[test]
+    fn test_pipeline_from_proto() {
+        let config = PipelineConfig {
+            id: "p1".to_string(),
+            name: "Pipeline".to_string(),
+            description: String::new(),
+            stages: vec![
+                stage("source", StageType::Source, None),
+                stage("enrich", StageType::Transform, Some(canary_split())),
+                stage("sink", StageType::Sink, None),
+            ],
+            edges: vec![
+                Edge {
+                    from_stage: "source".to_string(),
+                    to_stage: "enrich".to_string(),
+                    condition: Some(Condition {
+                        condition: Some(ProtoConditionKind::RecordType("order".to_string())),
+                    }),
+                },
+                Edge {
+                    from_stage: "enrich".to_string(),
+                    to_stage: "sink".to_string(),
+                    condition: None,
+                },
+            ],
+            enabled: true,
+            metadata: HashMap::new(),
+            dead_letter_stage: String::new(),
+        };
+
+        let pipeline = pipeline_from_proto(&config).unwrap();
+        assert_eq!(pipeline.stages.len(), 3);
+        assert_eq!(pipeline.edges.len(), 2);
+        assert!(pipeline.enabled);
+
+        let enrich = pipeline.stages.get("enrich").unwrap();
+        assert_eq!(enrich.parallelism, 1);
+        assert_eq!(
+            enrich.service_selector.load_balance,
+            conveyor_etl_routing::LoadBalanceStrategy::PeakEwma
+        );
+        assert_eq!(
+            enrich.service_selector.traffic_split,
+            Some(traffic_split_from_proto(&canary_split()))
+        );
+        assert!(pipeline.stages.get("source").unwrap().service_selector.group_id.is_none());
+    }
+
+    #[test]
+    fn test_pipeline_from_proto_rejects_invalid_split() {
+        let mut split = canary_split();
+        split.versions[1].version = "v1".to_string();
+
+        let config = PipelineConfig {
+            id: "p1".to_string(),
+            name: "Pipeline".to_string(),
+            description: String::new(),
+            stages: vec![stage("enrich", StageType::Transform, Some(split))],
+            edges: vec![],
+            enabled: false,
+            metadata: HashMap::new(),
+            dead_letter_stage: String::new(),
+        };
+
+        let status = pipeline_from_proto(&config).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_pipeline_from_proto_validates_transform_config() {
+        let mut transform = stage("mask", StageType::Transform, None);
+        transform.transform_config =
+            r#"{"transform_type":"mask","fields":[{"field":"email","strategy":"hash"}]}"#.to_string();
+        let mut config = PipelineConfig {
+            id: "p1".to_string(),
+            name: "Pipeline".to_string(),
+            description: String::new(),
+            stages: vec![transform],
+            edges: vec![],
+            enabled: false,
+            metadata: HashMap::new(),
+            dead_letter_stage: String::new(),
+        };
+        assert!(pipeline_from_proto(&config).is_ok());
+
+        config.stages[0].transform_config = r#"{"transform_type":"explode"}"#.to_string();
+        let status = pipeline_from_proto(&config).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        config.stages[0].transform_config = r#"{"transform_type":"project","fields":["id"]}"#.to_string();
+        config.stages[0].stage_type = StageType::Sink as i32;
+        assert!(pipeline_from_proto(&config).is_err());
+    }
+
+    #[test]
+    fn test_pipeline_from_proto_parses_fan_config() {
+        let mut fan_out = stage("fan", StageType::FanOut, None);
+        fan_out.service_selector = None;
+        fan_out.fan_config = r#"{"sinks":[{"id":"archive","name":"archive","service_selector":{"service_name":"archiver","group_id":null,"labels":{},"load_balance":"RoundRobin"},"field_mappings":[]}]}"#.to_string();
+        let mut config = PipelineConfig {
+            id: "p1".to_string(),
+            name: "Pipeline".to_string(),
+            description: String::new(),
+            stages: vec![fan_out],
+            edges: vec![],
+            enabled: false,
+            metadata: HashMap::new(),
+            dead_letter_stage: String::new(),
+        };
+        let pipeline = pipeline_from_proto(&config).unwrap();
+        let fan_out = pipeline.stages["fan"].fan_out_config.as_ref().unwrap();
+        assert_eq!(fan_out.sinks[0].id, "archive");
+
+        config.stages[0].fan_config = r#"{"sources":[]}"#.to_string();
+        assert!(pipeline_from_proto(&config).is_err(), "a fan-in config on a fan-out stage");
+
+        config.stages[0].fan_config.clear();
+        assert!(pipeline_from_proto(&config).is_err(), "fan-out stages need a config");
+
+        config.stages[0].stage_type = StageType::Sink as i32;
+        config.stages[0].fan_config = r#"{"sources":[]}"#.to_string();
+        assert!(pipeline_from_proto(&config).is_err(), "only fan stages take a config");
+    }
+
+    #[test]
+    fn test_pipeline_from_proto_parses_lookup_config() {
+        let mut lookup = stage("users", StageType::Lookup, None);
+        lookup.lookup_config = r#"{"key_fields":[{"record_field":"user_id","lookup_key":"id"}],"output_prefix":"user_","merge_strategy":"Merge","on_miss":"Drop","timeout_ms":50}"#.to_string();
+        let mut config = PipelineConfig {
+            id: "p1".to_string(),
+            name: "Pipeline".to_string(),
+            description: String::new(),
+            stages: vec![lookup],
+            edges: vec![],
+            enabled: false,
+            metadata: HashMap::new(),
+            dead_letter_stage: String::new(),
+        };
+        let pipeline = pipeline_from_proto(&config).unwrap();
+        let users = &pipeline.stages["users"];
+        assert_eq!(users.stage_type, conveyor_etl_routing::StageType::Lookup);
+        let lookup = users.lookup_config.as_ref().unwrap();
+        assert_eq!(lookup.key_fields[0].lookup_key, "id");
+        assert_eq!(lookup.on_miss, conveyor_etl_routing::LookupMissStrategy::Drop);
+
+        config.stages[0].lookup_config.clear();
+        assert!(pipeline_from_proto(&config).is_err(), "lookup stages need a config");
+
+        config.stages[0].lookup_config = r#"{"keys":[]}"#.to_string();
+        assert!(pipeline_from_proto(&config).is_err());
+
+        config.stages[0].stage_type = StageType::Transform as i32;
+        config.stages[0].lookup_config = r#"{"key_fields":[],"output_prefix":null,"merge_strategy":"Merge","on_miss":"Drop","timeout_ms":0}"#.to_string();
+        assert!(pipeline_from_proto(&config).is_err(), "only lookup stages take a config");
+    }
+
+    #[test]
+    fn test_pipeline_from_proto_parses_partition_key() {
+        let mut enrich = stage("enrich", StageType::Transform, None);
+        enrich.parallelism = 4;
+        enrich.partition_key = "metadata.customer".to_string();
+        let mut config = PipelineConfig {
+            id: "p1".to_string(),
+            name: "Pipeline".to_string(),
+            description: String::new(),
+            stages: vec![enrich],
+            edges: vec![],
+            enabled: false,
+            metadata: HashMap::new(),
+            dead_letter_stage: String::new(),
+        };
+        let pipeline = pipeline_from_proto(&config).unwrap();
+        assert_eq!(
+            pipeline.stages["enrich"].partition_key,
+            Some(PartitionKey::Metadata("customer".to_string()))
+        );
+
+        config.stages[0].partition_key = "customer".to_string();
+        assert!(pipeline_from_proto(&config).is_err());
+    }
+
+    fn expression_edge(source: &str) -> PipelineConfig {
+        PipelineConfig {
+            id: "p1".to_string(),
+            name: "Pipeline".to_string(),
+            description: String::new(),
+            stages: vec![
+                stage("source", StageType::Source, None),
+                stage("sink", StageType::Sink, None),
+            ],
+            edges: vec![Edge {
+                from_stage: "source".to_string(),
+                to_stage: "sink".to_string(),
+                condition: Some(Condition {
+                    condition: Some(ProtoConditionKind::Expression(source.to_string())),
+                }),
+            }],
+            enabled: true,
+            metadata: HashMap::new(),
+            dead_letter_stage: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_pipeline_from_proto_compiles_expression() {
+        let pipeline = pipeline_from_proto(&expression_edge("payload.amount > 100")).unwrap();
+        assert!(matches!(
+            pipeline.edges[0].condition,
+            Some(conveyor_etl_routing::Condition::Expression(_))
+        ));
+    }
+
+    #[test]
+    fn test_pipeline_from_proto_rejects_invalid_expression() {
+        let status = pipeline_from_proto(&expression_edge("payload.amount >")).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("column"));
+    }
+
+    fn routing_rule(targets: &[&str], record_type: Option<&str>, priority: u32) -> RoutingRule {
+        RoutingRule {
+            name: format!("rule-{}", priority),
+            condition: record_type.map(|t| Condition {
+                condition: Some(ProtoConditionKind::RecordType(t.to_string())),
+            }),
+            target_stages: targets.iter().map(|t| t.to_string()).collect(),
+            priority,
+            is_default: false,
+        }
+    }
+
+    fn routing_rules_config(rules: Vec<RoutingRule>) -> PipelineConfig {
+        let mut source = stage("source", StageType::Source, None);
+        source.routing_mode = RoutingMode::FirstMatch as i32;
+        source.routing_rules = rules;
+
+        PipelineConfig {
+            id: "p1".to_string(),
+            name: "Pipeline".to_string(),
+            description: String::new(),
+            stages: vec![
+                source,
+                stage("orders", StageType::Sink, None),
+                stage("archive", StageType::Sink, None),
+                stage("fallback", StageType::Sink, None),
+                stage("dlq", StageType::Sink, None),
+            ],
+            edges: vec![],
+            enabled: true,
+            metadata: HashMap::new(),
+            dead_letter_stage: "dlq".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_pipeline_from_proto_routing_rules() {
+        let mut default_rule = routing_rule(&["fallback"], None, 0);
+        default_rule.is_default = true;
+        let config = routing_rules_config(vec![
+            routing_rule(&["orders", "archive"], Some("order"), 5),
+            default_rule,
+        ]);
+
+        let pipeline = pipeline_from_proto(&config).unwrap();
+        assert_eq!(
+            pipeline.stages.get("source").unwrap().routing_mode,
+            conveyor_etl_routing::RoutingMode::FirstMatch
+        );
+        assert_eq!(pipeline.dead_letter_stage.as_deref(), Some("dlq"));
+        assert_eq!(pipeline.edges.len(), 3);
+        assert!(pipeline.edges[..2].iter().all(|e| e.priority == 5 && !e.is_default));
+        assert!(pipeline.edges[2].is_default);
+        assert!(pipeline.validate().is_ok());
+    }
+
+    fn pipeline_config(traffic_split: Option<TrafficSplit>) -> PipelineConfig {
+        PipelineConfig {
+            id: "p1".to_string(),
+            name: "Pipeline".to_string(),
+            description: String::new(),
+            stages: vec![
+                stage("source", StageType::Source, None),
+                stage("enrich", StageType::Transform, traffic_split),
+                stage("sink", StageType::Sink, None),
+            ],
+            edges: vec![
+                Edge {
+                    from_stage: "source".to_string(),
+                    to_stage: "enrich".to_string(),
+                    condition: None,
+                },
+                Edge {
+                    from_stage: "enrich".to_string(),
+                    to_stage: "sink".to_string(),
+                    condition: None,
+                },
+            ],
+            enabled: true,
+            metadata: HashMap::new(),
+            dead_letter_stage: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routing_engine_follows_raft_state() {
+        let mut state = RouterState::default();
+        let engine = RoutingEngine::new();
+        let mut synced = HashMap::new();
+
+        state
+            .apply_command(RouterCommand::CreatePipeline {
+                pipeline_id: "p1".to_string(),
+                name: "Pipeline".to_string(),
+                config: pipeline_config(None).encode_to_vec(),
+            })
+            .unwrap();
+        sync_routing_engine(&state, &engine, &mut synced).await;
+        assert!(!engine.get_pipeline("p1").await.unwrap().enabled);
+
+        state
+            .apply_command(RouterCommand::EnablePipeline { pipeline_id: "p1".to_string() })
+            .unwrap();
+        state
+            .apply_command(RouterCommand::UpdatePipelineIfVersion {
+                pipeline_id: "p1".to_string(),
+                expected_version: 1,
+                config: pipeline_config(Some(canary_split())).encode_to_vec(),
+            })
+            .unwrap();
+        sync_routing_engine(&state, &engine, &mut synced).await;
+
+        let pipeline = engine.get_pipeline("p1").await.unwrap();
+        assert!(pipeline.enabled);
+        assert_eq!(
+            pipeline.stages.get("enrich").unwrap().service_selector.traffic_split,
+            Some(traffic_split_from_proto(&canary_split()))
+        );
+
+        state
+            .apply_command(RouterCommand::DeletePipeline { pipeline_id: "p1".to_string() })
+            .unwrap();
+        sync_routing_engine(&state, &engine, &mut synced).await;
+        assert!(engine.get_pipeline("p1").await.is_none());
+        assert!(synced.is_empty());
+    }
+
+    #[test]
+    fn test_pipeline_from_proto_rejects_conditional_default_rule() {
+        let mut default_rule = routing_rule(&["fallback"], Some("order"), 0);
+        default_rule.is_default = true;
+        let config = routing_rules_config(vec![default_rule]);
+
+        let status = pipeline_from_proto(&config).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
+
+#[cfg(test)]
+mod schema_handler_tests {
+    use conveyor_etl_proto::schema::{CompatibilityMode, SchemaFormat};
+    use conveyor_etl_routing::SchemaFormat as RoutingSchemaFormat;
+    use tonic::Code;
+
+    use crate::schema_handler::{compatibility_from_proto, schema_definition_from_proto};
+
+    #[test]
+    fn test_schema_definition_from_proto() {
+        let definition = schema_definition_from_proto(
+            SchemaFormat::Protobuf as i32,
+            "CgA=".to_string(),
+            "shop.Order".to_string(),
+        )
+        .unwrap();
+        assert_eq!(definition.format, RoutingSchemaFormat::Protobuf);
+        assert_eq!(definition.message_name.as_deref(), Some("shop.Order"));
+
+        let definition = schema_definition_from_proto(
+            SchemaFormat::JsonSchema as i32,
+            r#"{"type":"object"}"#.to_string(),
+            String::new(),
+        )
+        .unwrap();
+        assert_eq!(definition.message_name, None);
+
+        let err = schema_definition_from_proto(
+            SchemaFormat::Unspecified as i32,
+            "{}".to_string(),
+            String::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let err =
+            schema_definition_from_proto(SchemaFormat::Avro as i32, " ".to_string(), String::new())
+                .unwrap_err();
+        assert!(err.message().contains("definition"));
+    }
+
+    #[test]
+    fn test_compatibility_from_proto() {
+        assert_eq!(
+            compatibility_from_proto(CompatibilityMode::Full as i32).unwrap(),
+            conveyor_etl_routing::CompatibilityMode::Full
+        );
+        assert!(compatibility_from_proto(CompatibilityMode::Unspecified as i32).is_err());
+    }
+}
+
+#[cfg(test)]
+mod scheduler_tests {
+    use std::collections::HashMap;
+
+    use conveyor_etl_proto::router::{
+        PipelineConfig, ServiceSelector, Stage, StageType, TrafficSplit, VersionWeight,
+    };
+    use conveyor_etl_raft::{
+        PipelineState, RouterState, SidecarLocalService, SidecarStageAssignment,
+        SidecarStageTarget, SidecarState,
+    };
+    use prost::Message;
+
+    use std::sync::Arc;
+
+    use conveyor_etl_registry::{VERSION_LABEL, ZONE_TOPOLOGY_KEY};
+    use tokio::sync::RwLock;
+
+    use crate::group_coordinator::GroupCoordinator;
+
+    use crate::scheduler::{
+        is_local_complete, partition_group_id, partition_groups, schedule, stage_instances,
+        SidecarPlan,
+    };
+    use crate::sidecar_handler::sync_partition_groups;
+
+    fn service(name: &str, labels: &[(&str, &str)]) -> SidecarLocalService {
+        SidecarLocalService {
+            service_name: name.to_string(),
+            service_type: "transform".to_string(),
+            local_endpoint: format!("127.0.0.1:{}", 8000 + name.len()),
+            group_id: None,
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    fn sidecar(id: &str, node: &str, services: Vec<SidecarLocalService>) -> SidecarState {
+        SidecarState {
+            sidecar_id: id.to_string(),
+            pod_name: id.to_string(),
+            namespace: "default".to_string(),
+            node_name: Some(node.to_string()),
+            endpoint: format!("{}:50053", id),
+            local_services: services,
+            assigned_pipelines: HashMap::new(),
+            registered_at: 0,
+            last_heartbeat: 0,
+        }
+    }
+
+    fn stage(id: &str, service: &str, parallelism: u32) -> Stage {
+        Stage {
+            id: id.to_string(),
+            name: id.to_string(),
+            stage_type: StageType::Transform as i32,
+            service_selector: Some(ServiceSelector {
+                service_name: service.to_string(),
+                ..Default::default()
+            }),
+            parallelism,
+            ..Default::default()
+        }
+    }
+
+    fn state(stages: Vec<Stage>, sidecars: Vec<SidecarState>) -> RouterState {
+        let config = PipelineConfig {
+            id: "p1".to_string(),
+            stages,
+            enabled: true,
+            ..Default::default()
+        };
+        let mut state = RouterState::default();
+        state.pipelines.insert(
+            "p1".to_string(),
+            PipelineState {
+                pipeline_id: "p1".to_string(),
+                name: "p1".to_string(),
+                config: config.encode_to_vec(),
+                enabled: true,
+                version: 1,
+            },
+        );
+        for sidecar in sidecars {
+            state.sidecars.insert(sidecar.sidecar_id.clone(), sidecar);
+        }
+        state
+    }
+
+    fn target<'a>(plan: &'a SidecarPlan, stage_id: &str) -> &'a SidecarStageTarget {
+        &plan["p1"].iter().find(|s| s.stage_id == stage_id).unwrap().target
+    }
+
+    #[test]
+    fn test_stages_follow_selectors() {
+        let mut enrich = stage("enrich", "", 1);
+        enrich.service_selector.as_mut().unwrap().labels =
+            HashMap::from([("tier".to_string(), "gold".to_string())]);
+        let mut mask = stage("mask", "", 1);
+        mask.service_selector = None;
+        mask.transform_config = r#"{"transform_type":"project","fields":["id"]}"#.to_string();
+        let mut fan_out = stage("fan", "", 1);
+        fan_out.stage_type = StageType::FanOut as i32;
+
+        let state = state(
+            vec![stage("source", "orders", 1), enrich, mask, fan_out],
+            vec![
+                sidecar("a", "n1", vec![service("orders", &[])]),
+                sidecar("b", "n2", vec![service("enricher", &[("tier", "silver")])]),
+                sidecar("c", "n3", vec![service("enricher", &[("tier", "gold")])]),
+                sidecar("d", "n4", vec![service("unrelated", &[])]),
+            ],
+        );
+
+        let plans = schedule(&state);
+        assert_eq!(plans.len(), 2, "only sidecars hosting a stage are assigned");
+        let a = &plans["a"];
+        assert!(matches!(target(a, "source"), SidecarStageTarget::Local { .. }));
+        assert_eq!(
+            target(a, "enrich"),
+            &SidecarStageTarget::Remote {
+                sidecar_id: "c".to_string(),
+                endpoint: "c:50053".to_string()
+            }
+        );
+        assert!(matches!(target(a, "mask"), SidecarStageTarget::Builtin { .. }));
+        assert!(
+            plans.values().all(|plan| plan["p1"].iter().all(|s| s.stage_id != "fan")),
+            "fan stages are not assigned"
+        );
+        assert!(!is_local_complete(&a["p1"]));
+        assert!(matches!(target(&plans["c"], "enrich"), SidecarStageTarget::Local { .. }));
+    }
+
+    #[test]
+    fn test_parallelism_locality_and_stickiness() {
+        let mut sidecars = vec![
+            sidecar("a", "n1", vec![service("enricher", &[])]),
+            sidecar("b", "n2", vec![service("enricher", &[])]),
+            sidecar("c", "n3", vec![service("enricher", &[])]),
+            sidecar("src", "n3", vec![service("orders", &[])]),
+        ];
+        sidecars[2].assigned_pipelines.insert(
+            "p1".to_string(),
+            vec![SidecarStageAssignment {
+                stage_id: "enrich".to_string(),
+                target: SidecarStageTarget::Local {
+                    endpoint: "127.0.0.1:8008".to_string(),
+                },
+                partitions: Vec::new(),
+            }],
+        );
+        let state = state(vec![stage("source", "orders", 1), stage("enrich", "enricher", 2)], sidecars);
+
+        let plans = schedule(&state);
+        let mut hosts: Vec<_> = plans
+            .iter()
+            .filter(|(_, plan)| matches!(target(plan, "enrich"), SidecarStageTarget::Local { .. }))
+            .map(|(id, _)| id.as_str())
+            .collect();
+        hosts.sort();
+        assert_eq!(hosts, vec!["a", "c"]);
+        assert!(!plans.contains_key("b"));
+
+        // The source's sidecar shares node n3 with c
+        assert!(matches!(
+            target(&plans["src"], "enrich"),
+            SidecarStageTarget::Remote { sidecar_id, .. } if sidecar_id == "c"
+        ));
+    }
+
+    #[test]
+    fn test_stateful_transform_runs_on_one_sidecar() {
+        let mut count = stage("count", "", 1);
+        count.service_selector = None;
+        count.transform_config = r#"{"transform_type":"aggregate","group_by":["user"],"window":{"tumbling":{"seconds":60}},"aggregations":[{"field":"*","function":"count"}]}"#.to_string();
+        let mut mask = stage("mask", "", 1);
+        mask.service_selector = None;
+        mask.transform_config = r#"{"transform_type":"project","fields":["id"]}"#.to_string();
+        let stages = vec![stage("source", "orders", 2), count, mask];
+        let sidecars = || {
+            vec![
+                sidecar("a", "n1", vec![service("orders", &[])]),
+                sidecar("b", "n2", vec![service("orders", &[])]),
+            ]
+        };
+
+        let plans = schedule(&state(stages.clone(), sidecars()));
+        assert!(matches!(target(&plans["a"], "count"), SidecarStageTarget::Builtin { .. }));
+        assert_eq!(
+            target(&plans["b"], "count"),
+            &SidecarStageTarget::Remote {
+                sidecar_id: "a".to_string(),
+                endpoint: "a:50053".to_string()
+            }
+        );
+        assert!(plans.values().all(|plan| matches!(target(plan, "mask"), SidecarStageTarget::Builtin { .. })));
+
+        // The sidecar already running it keeps it
+        let mut assigned = plans["b"]["p1"].clone();
+        assigned.iter_mut().find(|s| s.stage_id == "count").unwrap().target =
+            SidecarStageTarget::Builtin { config: String::new() };
+        let mut sidecars = sidecars();
+        sidecars[1].assigned_pipelines.insert("p1".to_string(), assigned);
+        let plans = schedule(&state(stages, sidecars));
+        assert!(matches!(target(&plans["b"], "count"), SidecarStageTarget::Builtin { .. }));
+        assert!(matches!(
+            target(&plans["a"], "count"),
+            SidecarStageTarget::Remote { sidecar_id, .. } if sidecar_id == "b"
+        ));
+    }
+
+    #[test]
+    fn test_stage_instances_list_every_placement() {
+        let state = state(
+            vec![stage("source", "orders", 1), stage("enrich", "enricher", 2)],
+            vec![
+                sidecar("b", "n2", vec![service("enricher", &[("zone", "z2")])]),
+                sidecar("a", "n1", vec![service("enricher", &[("zone", "z1")])]),
+                sidecar("src", "n3", vec![service("orders", &[])]),
+            ],
+        );
+
+        let plans = schedule(&state);
+        let instances = stage_instances(
+            &state,
+            plans.iter().flat_map(|(sidecar_id, plan)| {
+                plan.iter()
+                    .map(move |(pipeline_id, stages)| (sidecar_id.as_str(), pipeline_id.as_str(), stages.as_slice()))
+            }),
+        );
+
+        let enrich = &instances[&("p1".to_string(), "enrich".to_string())];
+        let hosts: Vec<_> = enrich.iter().map(|i| i.sidecar_id.as_str()).collect();
+        assert_eq!(hosts, vec!["a", "b"]);
+        assert_eq!(enrich[0].endpoint, "a:50053");
+        assert_eq!(enrich[0].labels["zone"], "z1");
+        assert_eq!(instances[&("p1".to_string(), "source".to_string())].len(), 1);
+    }
+
+    #[test]
+    fn test_traffic_split_places_every_version() {
+        let mut enrich = stage("enrich", "enricher", 1);
+        enrich.service_selector.as_mut().unwrap().traffic_split = Some(TrafficSplit {
+            versions: vec![
+                VersionWeight {
+                    version: "v1".to_string(),
+                    weight: 90,
+                },
+                VersionWeight {
+                    version: "v2".to_string(),
+                    weight: 10,
+                },
+                VersionWeight {
+                    version: "v3".to_string(),
+                    weight: 0,
+                },
+            ],
+            sticky: false,
+        });
+        let state = state(
+            vec![stage("source", "orders", 1), enrich],
+            vec![
+                sidecar("a", "n1", vec![service("enricher", &[(VERSION_LABEL, "v1")])]),
+                sidecar("b", "n2", vec![service("enricher", &[(VERSION_LABEL, "v1")])]),
+                sidecar("c", "n3", vec![service("enricher", &[(VERSION_LABEL, "v2")])]),
+                sidecar("d", "n4", vec![service("enricher", &[(VERSION_LABEL, "v3")])]),
+                sidecar("src", "n5", vec![service("orders", &[])]),
+            ],
+        );
+
+        let plans = schedule(&state);
+        let mut hosts: Vec<_> = plans
+            .iter()
+            .filter(|(_, plan)| matches!(target(plan, "enrich"), SidecarStageTarget::Local { .. }))
+            .map(|(id, _)| id.as_str())
+            .collect();
+        hosts.sort();
+        // Past a parallelism of one, but not the version without weight
+        assert_eq!(hosts, vec!["a", "c"]);
+    }
+
+    fn partitions<'a>(plan: &'a SidecarPlan, stage_id: &str) -> &'a [SidecarStageTarget] {
+        &plan["p1"].iter().find(|s| s.stage_id == stage_id).unwrap().partitions
+    }
+
+    /// The sidecar each partition of `enrich` goes to, as seen from `from`.
+    fn owners(plans: &HashMap<String, SidecarPlan>, from: &str) -> Vec<String> {
+        partitions(&plans[from], "enrich")
+            .iter()
+            .map(|target| match target {
+                SidecarStageTarget::Local { .. } => from.to_string(),
+                SidecarStageTarget::Remote { sidecar_id, .. } => sidecar_id.clone(),
+                SidecarStageTarget::Builtin { .. } => panic!("partition run as a built-in"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_stage_follows_group_rebalances() {
+        let mut enrich = stage("enrich", "enricher", 4);
+        enrich.partition_key = "record_key".to_string();
+        let state = Arc::new(RwLock::new(state(
+            vec![stage("source", "orders", 1), enrich],
+            vec![
+                sidecar("a", "n1", vec![service("enricher", &[])]),
+                sidecar("b", "n2", vec![service("enricher", &[])]),
+                sidecar("c", "n3", vec![service("enricher", &[])]),
+                sidecar("src", "n3", vec![service("orders", &[])]),
+            ],
+        )));
+        let groups = GroupCoordinator::with_state(state.clone());
+        let group_id = partition_group_id("p1", "enrich");
+
+        let wanted = partition_groups(&*state.read().await);
+        assert_eq!(wanted.len(), 1);
+        assert_eq!(wanted[0].group_id, group_id);
+        assert_eq!(wanted[0].members, vec!["a", "b", "c"]);
+
+        // Before the group exists, records go to the stage's target
+        let plans = schedule(&*state.read().await);
+        assert!(partitions(&plans["src"], "enrich").is_empty());
+
+        sync_partition_groups(&groups, wanted, None).await;
+        let group = groups.get_group(&group_id).await.unwrap();
+        assert_eq!(group.total_partitions, 4);
+        assert_eq!(group.members.len(), 3);
+
+        let plans = schedule(&*state.read().await);
+        let seen_by_source = owners(&plans, "src");
+        assert_eq!(seen_by_source.len(), 4);
+        for from in ["a", "b", "c"] {
+            assert_eq!(owners(&plans, from), seen_by_source, "every sidecar agrees on owners");
+        }
+        assert!(!is_local_complete(&plans["a"]["p1"]));
+
+        // c goes away: its partitions move to a and b
+        state.write().await.sidecars.remove("c");
+        let wanted = partition_groups(&*state.read().await);
+        sync_partition_groups(&groups, wanted, None).await;
+        let group = groups.get_group(&group_id).await.unwrap();
+        assert!(!group.members.contains_key("c"));
+        let plans = schedule(&*state.read().await);
+        let seen_by_source = owners(&plans, "src");
+        assert_eq!(seen_by_source.len(), 4);
+        assert!(seen_by_source.iter().all(|owner| owner == "a" || owner == "b"));
+
+        // Without a partition key the group is emptied
+        state.write().await.pipelines.clear();
+        let wanted = partition_groups(&*state.read().await);
+        sync_partition_groups(&groups, wanted, None).await;
+        assert!(groups.get_group(&group_id).await.unwrap().members.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_partition_groups_spread_over_zones() {
+        let mut enrich = stage("enrich", "enricher", 4);
+        enrich.partition_key = "record_key".to_string();
+        let state = Arc::new(RwLock::new(state(
+            vec![enrich],
+            vec![
+                sidecar("a", "n1", vec![service("enricher", &[(ZONE_TOPOLOGY_KEY, "zone-a")])]),
+                sidecar("b", "n2", vec![service("enricher", &[(ZONE_TOPOLOGY_KEY, "zone-a")])]),
+                sidecar("c", "n3", vec![service("enricher", &[(ZONE_TOPOLOGY_KEY, "zone-b")])]),
+                sidecar("d", "n4", vec![service("enricher", &[(ZONE_TOPOLOGY_KEY, "zone-b")])]),
+            ],
+        )));
+        let groups = GroupCoordinator::with_state(state.clone());
+        let group_id = partition_group_id("p1", "enrich");
+
+        let wanted = partition_groups(&*state.read().await);
+        sync_partition_groups(&groups, wanted, Some(ZONE_TOPOLOGY_KEY)).await;
+
+        let group = groups.get_group(&group_id).await.unwrap();
+        assert_eq!(group.topology_key.as_deref(), Some(ZONE_TOPOLOGY_KEY));
+        assert_eq!(group.members["c"].zone.as_deref(), Some("zone-b"));
+        let by_zone = groups.get_partitions_by_zone(&group_id).await;
+        assert_eq!(by_zone["zone-a"].len(), 2);
+        assert_eq!(by_zone["zone-b"].len(), 2);
+    }
+}
+
+#[cfg(test)]
+mod assignment_feed_tests {
+    use conveyor_etl_proto::sidecar::{EventType, PipelineAssignment, PipelineAssignmentEvent};
+
+    use crate::assignment_feed::AssignmentFeeds;
+
+    fn event(event_type: EventType, pipeline_id: &str) -> PipelineAssignmentEvent {
+        PipelineAssignmentEvent {
+            event_type: event_type as i32,
+            assignment: Some(PipelineAssignment {
+                pipeline_id: pipeline_id.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn pipeline_ids(events: &[PipelineAssignmentEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|e| e.assignment.as_ref().unwrap().pipeline_id.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_resume_replays_missed_events() {
+        let feeds = AssignmentFeeds::new();
+        let start = feeds.token("s1");
+        feeds.publish("s1", event(EventType::Assigned, "p1"));
+        feeds.publish("s1", event(EventType::Revoked, "p2"));
+
+        let (events, mut updates) = feeds.resume("s1", &start).unwrap();
+        assert_eq!(pipeline_ids(&events), vec!["p1", "p2"]);
+
+        let (events, _) = feeds.resume("s1", &events[0].resume_token).unwrap();
+        assert_eq!(pipeline_ids(&events), vec!["p2"]);
+
+        feeds.publish("s1", event(EventType::Updated, "p3"));
+        let event = updates.recv().await.unwrap();
+        assert_eq!(event.event_type, EventType::Updated as i32);
+        assert_eq!(event.resume_token, feeds.token("s1"));
+    }
+
+    #[test]
+    fn test_unknown_tokens_need_a_snapshot() {
+        let feeds = AssignmentFeeds::new();
+        let token = feeds.token("s1");
+        assert!(feeds.resume("s1", "").is_none());
+        assert!(feeds.resume("s2", &token).is_none());
+
+        feeds.reset("s1");
+        assert!(feeds.resume("s1", &token).is_none());
+
+        let oldest = feeds.token("s1");
+        for i in 0..300 {
+            feeds.publish("s1", event(EventType::Assigned, &format!("p{}", i)));
+        }
+        assert!(feeds.resume("s1", &oldest).is_none(), "events after it were dropped");
+        let (events, _) = feeds.resume("s1", &oldest.replace(":0", ":100")).unwrap();
+        assert_eq!(events.len(), 200);
+    }
+}
+
+#[cfg(test)]
+mod group_coordinator_tests {
+    use conveyor_etl_registry::ZONE_TOPOLOGY_KEY;
+
+    use crate::group_coordinator::GroupCoordinator;
+
+    #[tokio::test]
+    async fn test_create_consumer_group() {
+        let coordinator = GroupCoordinator::new();
+
+        let result = coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await;
+
+        assert!(result.is_ok());
+
+        let group = coordinator.get_group("group-1").await;
+        assert!(group.is_some());
+        let g = group.unwrap();
+        assert_eq!(g.group_id, "group-1");
+        assert_eq!(g.stage_id, "stage-1");
+        assert_eq!(g.total_partitions, 4);
+        assert_eq!(g.generation, 0);
+    }
+
+    #[tokio::test]
+    async fn test_join_group_single_member() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await.unwrap();
+
+        let events = coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+
+        assert!(!events.is_empty());
+
+        let assignment = coordinator.get_assignment("group-1", "service-a").await;
+        assert!(assignment.is_some());
+        let partitions = assignment.unwrap();
+        assert_eq!(partitions.len(), 4);
+        assert!(partitions.contains(&0));
+        assert!(partitions.contains(&1));
+        assert!(partitions.contains(&2));
+        assert!(partitions.contains(&3));
+    }
+
+    #[tokio::test]
+    async fn test_join_group_triggers_rebalance() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await.unwrap();
+
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+
+        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
+        assert_eq!(assignment_a.len(), 4);
+
+        let events = coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+        assert!(!events.is_empty());
+
+        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
+        let assignment_b = coordinator.get_assignment("group-1", "service-b").await.unwrap();
+
+        assert_eq!(assignment_a.len(), 2);
+        assert_eq!(assignment_b.len(), 2);
+
+        let mut all_partitions: Vec<u32> = assignment_a.iter().chain(assignment_b.iter()).copied().collect();
+        all_partitions.sort();
+        assert_eq!(all_partitions, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_leave_group_triggers_rebalance() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await.unwrap();
+
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+
+        let events = coordinator.leave_group("group-1", "service-b").await.unwrap();
+        assert!(!events.is_empty());
+
+        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
+        assert_eq!(assignment_a.len(), 4);
+
+        let assignment_b = coordinator.get_assignment("group-1", "service-b").await;
+        assert!(assignment_b.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_member_timeout_triggers_rebalance() {
+        use std::time::Duration;
+
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await.unwrap();
+
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+
+        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
+        let assignment_b = coordinator.get_assignment("group-1", "service-b").await.unwrap();
+        assert_eq!(assignment_a.len(), 2);
+        assert_eq!(assignment_b.len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        coordinator.heartbeat("group-1", "service-a").await.unwrap();
+
+        let events = coordinator.check_member_timeouts("group-1", Duration::from_millis(25)).await.unwrap();
+        assert!(!events.is_empty());
+
+        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
+        assert_eq!(assignment_a.len(), 4);
+
+        let assignment_b = coordinator.get_assignment("group-1", "service-b").await;
+        assert!(assignment_b.is_none());
+
+        let stale_members = coordinator.get_members_needing_heartbeat("group-1", Duration::from_secs(60));
+        assert!(stale_members.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_first_join_creates_group() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.ensure_group("group-1", "stage-1", 2).await.unwrap();
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        coordinator.ensure_group("group-1", "stage-1", 8).await.unwrap();
+
+        let group = coordinator.get_group("group-1").await.unwrap();
+        assert_eq!(group.stage_id, "stage-1");
+        assert_eq!(group.total_partitions, 2);
+        assert_eq!(coordinator.get_assignment("group-1", "service-a").await.unwrap(), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_rejoin_is_idempotent() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group("group-1".to_string(), "stage-1".to_string(), 4).await.unwrap();
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+        let generation = coordinator.get_current_generation("group-1").await.unwrap();
+
+        let events = coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        assert!(events.is_empty());
+        assert_eq!(coordinator.get_current_generation("group-1").await, Some(generation));
+        assert_eq!(coordinator.get_group("group-1").await.unwrap().members.len(), 2);
+
+        coordinator
+            .join_group_in_zone("group-1", "service-a".to_string(), Some("zone-a".to_string()))
+            .await
+            .unwrap();
+        let group = coordinator.get_group("group-1").await.unwrap();
+        assert_eq!(group.members["service-a"].zone.as_deref(), Some("zone-a"));
+        assert_eq!(group.generation, generation + 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_generation_is_typed() {
+        use conveyor_etl_raft::StaleGeneration;
+
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group("group-1".to_string(), "stage-1".to_string(), 4).await.unwrap();
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+
+        let err = coordinator.heartbeat_with_generation("group-1", "service-a", 0).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StaleGeneration>(),
+            Some(&StaleGeneration { expected: 1, got: 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_service_heartbeat_refreshes_group_sessions() {
+        use std::time::Duration;
+
+        let coordinator = GroupCoordinator::new();
+
+        for group_id in ["group-1", "group-2"] {
+            coordinator.create_group(group_id.to_string(), "stage-1".to_string(), 4).await.unwrap();
+            coordinator.join_group(group_id, "service-a".to_string()).await.unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut groups = coordinator.heartbeat_member("service-a").await;
+        groups.sort();
+        assert_eq!(groups, vec!["group-1", "group-2"]);
+        assert!(coordinator.heartbeat_member("service-b").await.is_empty());
+
+        for group_id in ["group-1", "group-2"] {
+            let events = coordinator.check_member_timeouts(group_id, Duration::from_millis(25)).await.unwrap();
+            assert!(events.is_empty());
+            assert_eq!(coordinator.get_assignment(group_id, "service-a").await.unwrap().len(), 4);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "Sticky assignment not yet implemented"]
+    async fn test_sticky_partition_assignment() {
+        todo!("Implement sticky assignment")
+    }
+
+    #[tokio::test]
+    async fn test_range_assignment_strategy() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            10,
+        ).await.unwrap();
+
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+        coordinator.join_group("group-1", "service-c".to_string()).await.unwrap();
+
+        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
+        let assignment_b = coordinator.get_assignment("group-1", "service-b").await.unwrap();
+        let assignment_c = coordinator.get_assignment("group-1", "service-c").await.unwrap();
+
+        let mut all: Vec<u32> = assignment_a.iter()
+            .chain(assignment_b.iter())
+            .chain(assignment_c.iter())
+            .copied()
+            .collect();
+        all.sort();
+        assert_eq!(all, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+
+        assert!(assignment_a.len() >= 3);
+        assert!(assignment_b.len() >= 3);
+        assert!(assignment_c.len() >= 3);
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_assignment_strategy() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            9,
+        ).await.unwrap();
+
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+        coordinator.join_group("group-1", "service-c".to_string()).await.unwrap();
+
+        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
+        let assignment_b = coordinator.get_assignment("group-1", "service-b").await.unwrap();
+        let assignment_c = coordinator.get_assignment("group-1", "service-c").await.unwrap();
+
+        assert_eq!(assignment_a.len(), 3);
+        assert_eq!(assignment_b.len(), 3);
+        assert_eq!(assignment_c.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_callback_invoked() {
+        use crate::group_coordinator::RebalanceEvent;
+
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await.unwrap();
+
+        let events = coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+
+        let has_assigned = events.iter().any(|e| matches!(e, RebalanceEvent::PartitionsAssigned { .. }));
+        assert!(has_assigned);
+    }
+
+    #[tokio::test]
+    async fn test_group_generation_increments() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await.unwrap();
+
+        let group = coordinator.get_group("group-1").await.unwrap();
+        assert_eq!(group.generation, 0);
+
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        let group = coordinator.get_group("group-1").await.unwrap();
+        assert_eq!(group.generation, 1);
+
+        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+        let group = coordinator.get_group("group-1").await.unwrap();
+        assert_eq!(group.generation, 2);
+    }
+
+    #[tokio::test]
+    async fn test_stale_generation_rejected() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await.unwrap();
+
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        let gen1 = coordinator.get_current_generation("group-1").await.unwrap();
+        assert_eq!(gen1, 1);
+
+        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+        let gen2 = coordinator.get_current_generation("group-1").await.unwrap();
+        assert_eq!(gen2, 2);
+
+        let stale_heartbeat = coordinator.heartbeat_with_generation("group-1", "service-a", gen1).await;
+        assert!(stale_heartbeat.is_err());
+        assert!(stale_heartbeat.unwrap_err().to_string().contains("Stale generation"));
+
+        let valid_heartbeat = coordinator.heartbeat_with_generation("group-1", "service-a", gen2).await;
+        assert!(valid_heartbeat.is_ok());
+
+        let is_valid = coordinator.validate_generation("group-1", gen2).await.unwrap();
+        assert!(is_valid);
+
+        let is_stale = coordinator.validate_generation("group-1", gen1).await.unwrap();
+        assert!(!is_stale);
+
+        let stale_leave = coordinator.leave_group_with_generation("group-1", "service-a", gen1).await;
+        assert!(stale_leave.is_err());
+
+        let valid_leave = coordinator.leave_group_with_generation("group-1", "service-b", gen2).await;
+        assert!(valid_leave.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_joins_handled() {
+        let coordinator = std::sync::Arc::new(GroupCoordinator::new());
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            12,
+        ).await.unwrap();
+
+        let mut handles = vec![];
+        for i in 0..4 {
+            let coord = coordinator.clone();
+            let handle = tokio::spawn(async move {
+                coord.join_group("group-1", format!("service-{}", i)).await
+            });
+            handles.push(handle);
+        }
+
+        for handle in handles {
+            let result = handle.await.unwrap();
+            assert!(result.is_ok());
+        }
+
+        let group = coordinator.get_group("group-1").await.unwrap();
+        assert_eq!(group.members.len(), 4);
+
+        let mut all_partitions = std::collections::HashSet::new();
+        for i in 0..4 {
+            let assignment = coordinator.get_assignment("group-1", &format!("service-{}", i)).await.unwrap();
+            for p in assignment {
+                assert!(!all_partitions.contains(&p), "Partition {} assigned multiple times", p);
+                all_partitions.insert(p);
+            }
+        }
+        assert_eq!(all_partitions.len(), 12);
+    }
+
+    #[tokio::test]
+    async fn test_partitions_spread_across_zones() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            6,
+        ).await.unwrap();
+        coordinator.set_topology_key("group-1", Some(ZONE_TOPOLOGY_KEY.to_string())).await.unwrap();
+
+        coordinator.join_group_in_zone("group-1", "service-a".to_string(), Some("zone-a".to_string())).await.unwrap();
+        coordinator.join_group_in_zone("group-1", "service-b".to_string(), Some("zone-a".to_string())).await.unwrap();
+        coordinator.join_group_in_zone("group-1", "service-c".to_string(), Some("zone-b".to_string())).await.unwrap();
+
+        let by_zone = coordinator.get_partitions_by_zone("group-1").await;
+        assert_eq!(by_zone.get("zone-a"), Some(&vec![0, 2, 3, 5]));
+        assert_eq!(by_zone.get("zone-b"), Some(&vec![1, 4]));
+
+        let zone_a_owner = coordinator.get_partition_owner("group-1", 0).await.unwrap();
+        let zone_b_owner = coordinator.get_partition_owner("group-1", 1).await.unwrap();
+        assert_eq!(zone_a_owner, "service-a");
+        assert_eq!(zone_b_owner, "service-c");
+    }
+
+    #[tokio::test]
+    async fn test_set_topology_key_triggers_rebalance() {
+        let coordinator = GroupCoordinator::new();
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await.unwrap();
+
+        coordinator.join_group_in_zone("group-1", "service-a".to_string(), Some("zone-a".to_string())).await.unwrap();
+        coordinator.join_group_in_zone("group-1", "service-b".to_string(), Some("zone-b".to_string())).await.unwrap();
+        let gen_before = coordinator.get_current_generation("group-1").await.unwrap();
+
+        coordinator.set_topology_key("group-1", Some(ZONE_TOPOLOGY_KEY.to_string())).await.unwrap();
+        let gen_after = coordinator.get_current_generation("group-1").await.unwrap();
+        assert_eq!(gen_after, gen_before + 1);
+
+        let events = coordinator.set_topology_key("group-1", Some(ZONE_TOPOLOGY_KEY.to_string())).await.unwrap();
+        assert!(events.is_empty());
+        assert_eq!(coordinator.get_current_generation("group-1").await.unwrap(), gen_after);
+    }
+
+    #[tokio::test]
+    async fn test_group_coordinator_persisted_in_raft() {
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::sync::RwLock;
+        use conveyor_etl_raft::RouterState;
+
+        let state = Arc::new(RwLock::new(RouterState::default()));
+        let leader = GroupCoordinator::with_state(state.clone());
+
+        leader.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            4,
+        ).await.unwrap();
+        leader.join_group("group-1", "service-a".to_string()).await.unwrap();
+        leader.join_group("group-1", "service-b".to_string()).await.unwrap();
+
+        let generation = leader.get_current_generation("group-1").await.unwrap();
+        let assignment_a = leader.get_assignment("group-1", "service-a").await.unwrap();
+        let assignment_b = leader.get_assignment("group-1", "service-b").await.unwrap();
+
+        let replica = Arc::new(RwLock::new(state.read().await.clone()));
+        let new_leader = GroupCoordinator::with_state(replica);
+
+        assert_eq!(new_leader.get_current_generation("group-1").await, Some(generation));
+        assert_eq!(new_leader.get_assignment("group-1", "service-a").await.unwrap(), assignment_a);
+        assert_eq!(new_leader.get_assignment("group-1", "service-b").await.unwrap(), assignment_b);
+
+        let stale = new_leader.heartbeat_with_generation("group-1", "service-a", generation - 1).await;
+        assert!(stale.is_err());
+        let current = new_leader.heartbeat_with_generation("group-1", "service-a", generation).await;
+        assert!(current.is_ok());
+
+        let events = new_leader.check_member_timeouts("group-1", Duration::from_secs(30)).await.unwrap();
+        assert!(events.is_empty());
+        assert_eq!(new_leader.get_group("group-1").await.unwrap().members.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stale_partition_assignment_fenced() {
+        use std::collections::HashMap;
+        use std::sync::Arc;
+        use tokio::sync::RwLock;
+        use conveyor_etl_raft::{RouterCommand, RouterState};
+
+        let state = Arc::new(RwLock::new(RouterState::default()));
+        let coordinator = GroupCoordinator::with_state(state.clone());
+
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            2,
+        ).await.unwrap();
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+
+        let generation = coordinator.get_current_generation("group-1").await.unwrap();
+
+        let mut assignments = HashMap::new();
+        assignments.insert("service-a".to_string(), vec![0, 1]);
+        let result = state.write().await.apply_command(RouterCommand::AssignPartitions {
+            group_id: "group-1".to_string(),
+            assignments,
+            generation,
+        });
+
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Stale generation"));
+        assert_eq!(coordinator.get_assignment("group-1", "service-b").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_generation_fenced_through_raft() {
+        use std::collections::{BTreeMap, HashMap};
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tempfile::TempDir;
+        use tokio::sync::RwLock;
+        use conveyor_etl_raft::{
+            BasicNode, Config, ConveyorRaft, LogStorage, NetworkFactory, Raft, RouterCommand,
+            RouterRequest, RouterState, StateMachine,
+        };
+
+        let tmp_dir = TempDir::new().unwrap();
+        let config = Arc::new(
+            Config {
+                heartbeat_interval: 50,
+                election_timeout_min: 150,
+                election_timeout_max: 300,
+                ..Default::default()
+            }
+            .validate()
+            .unwrap(),
+        );
+        let state = Arc::new(RwLock::new(RouterState::default()));
+        let raft: ConveyorRaft = Raft::new(
+            1,
+            config,
+            NetworkFactory::new(),
+            LogStorage::new(tmp_dir.path()).unwrap(),
+            StateMachine::new(state.clone()),
+        )
+        .await
+        .unwrap();
+        let raft = Arc::new(raft);
+
+        let mut members = BTreeMap::new();
+        members.insert(1, BasicNode::new("127.0.0.1:0"));
+        raft.initialize(members).await.unwrap();
+        raft.wait(Some(Duration::from_secs(5)))
+            .current_leader(1, "single node becomes leader")
+            .await
+            .unwrap();
+
+        let coordinator = GroupCoordinator::with_raft(raft.clone(), state.clone());
+        coordinator.create_group(
+            "group-1".to_string(),
+            "stage-1".to_string(),
+            2,
+        ).await.unwrap();
+        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
+        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
+
+        let generation = coordinator.get_current_generation("group-1").await.unwrap();
+        assert_eq!(generation, 2);
+
+        let mut assignments = HashMap::new();
+        assignments.insert("service-a".to_string(), vec![0, 1]);
+        let response = raft
+            .client_write(RouterRequest {
+                command: RouterCommand::AssignPartitions {
+                    group_id: "group-1".to_string(),
+                    assignments,
+                    generation,
+                },
+            })
+            .await
+            .unwrap();
+
+        assert!(!response.data.success);
+        assert!(response.data.error.unwrap().contains("Stale generation"));
+        assert_eq!(coordinator.get_current_generation("group-1").await, Some(generation));
+        assert_eq!(coordinator.get_assignment("group-1", "service-b").await.unwrap().len(), 1);
+
+        raft.shutdown().await.unwrap();
+    }
+}
@@ -1,6 +1,11 @@
+use std::sync::Arc;
+use std::time::Instant;
+
 use anyhow::Result;
 use tonic::transport::Channel;
 
+use conveyor_etl_registry::{LoadBalancer, OutlierDetector, RegisteredService};
+
 use conveyor_etl_proto::transform::{
     transform_service_client::TransformServiceClient,
     ProcessBatchRequest, ProcessBatchResponse,
@@ -11,12 +16,39 @@ use conveyor_etl_proto::common::{RecordBatch, Empty};
 pub struct TransformClient {
     client: TransformServiceClient<Channel>,
     transform_id: String,
+    endpoint: String,
+    outlier_detector: Option<Arc<OutlierDetector>>,
+    load_balancer: Option<(Arc<LoadBalancer>, RegisteredService)>,
 }
 
 impl TransformClient {
     pub async fn connect(addr: String, transform_id: String) -> Result<Self> {
-        let client = TransformServiceClient::connect(addr).await?;
-        Ok(Self { client, transform_id })
+        let client = TransformServiceClient::connect(addr.clone()).await?;
+        Ok(Self {
+            client,
+            transform_id,
+            endpoint: addr,
+            outlier_detector: None,
+            load_balancer: None,
+        })
+    }
+
+    pub fn with_outlier_detector(mut self, outlier_detector: Arc<OutlierDetector>) -> Self {
+        self.outlier_detector = Some(outlier_detector);
+        self
+    }
+
+    pub fn with_load_balancer(
+        mut self,
+        load_balancer: Arc<LoadBalancer>,
+        service: RegisteredService,
+    ) -> Self {
+        self.load_balancer = Some((load_balancer, service));
+        self
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
     }
 
     pub async fn process_batch(
@@ -30,8 +62,34 @@ impl TransformClient {
             transform_config: config,
         };
 
-        let response = self.client.process_batch(request).await?;
-        Ok(response.into_inner())
+        let started = match &self.load_balancer {
+            Some((lb, service)) => lb.start_request(&service.service_id),
+            None => Instant::now(),
+        };
+        let result = self.client.process_batch(request).await;
+
+        if let Some((lb, service)) = &self.load_balancer {
+            lb.finish_request(&service.service_id, started);
+            if let Some(version) = service.version() {
+                let latency = started.elapsed();
+                lb.record_version_outcome(&service.service_name, version, result.is_ok(), latency);
+                conveyor_etl_metrics::record_version_request(
+                    &service.service_name,
+                    version,
+                    result.is_ok(),
+                    latency.as_secs_f64() * 1000.0,
+                );
+            }
+        }
+
+        if let Some(detector) = &self.outlier_detector {
+            match &result {
+                Ok(_) => detector.record_success(&self.endpoint, started.elapsed()),
+                Err(_) => detector.record_failure(&self.endpoint),
+            }
+        }
+
+        Ok(result?.into_inner())
     }
 
     pub async fn get_capabilities(&mut self) -> Result<Capabilities> {
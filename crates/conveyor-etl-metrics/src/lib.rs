@@ -83,3 +83,20 @@ pub fn record_group_rebalance(group_id: &str) {
     )
     .increment(1);
 }
+
+pub fn record_version_request(service_name: &str, version: &str, success: bool, latency_ms: f64) {
+    let outcome = if success { "success" } else { "error" };
+    counter!(
+        "conveyor_etl_router_version_requests_total",
+        "service_name" => service_name.to_string(),
+        "version" => version.to_string(),
+        "outcome" => outcome
+    )
+    .increment(1);
+    histogram!(
+        "conveyor_etl_router_version_latency_ms",
+        "service_name" => service_name.to_string(),
+        "version" => version.to_string()
+    )
+    .record(latency_ms);
+}
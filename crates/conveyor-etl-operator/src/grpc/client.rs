@@ -7,7 +7,7 @@ use conveyor_etl_proto::router::{
     CreatePipelineRequest, UpdatePipelineRequest, DeletePipelineRequest,
     EnablePipelineRequest, DisablePipelineRequest, GetPipelineRequest,
     GetClusterStatusRequest, PipelineConfig, Stage, Edge, ServiceSelector,
-    StageType, LoadBalanceStrategy,
+    StageType, LoadBalanceStrategy, RoutingMode,
 };
 
 use crate::error::{Error, Result};
@@ -272,9 +272,15 @@ impl RouterConnection {
                 group_id: String::new(),
                 labels: HashMap::new(),
                 load_balance: LoadBalanceStrategy::LoadBalanceRoundRobin as i32,
+                traffic_split: None,
             }),
             routing_rules: vec![],
             parallelism: 1,
+            routing_mode: RoutingMode::AllMatches as i32,
+            transform_config: String::new(),
+            fan_config: String::new(),
+            partition_key: String::new(),
+            lookup_config: String::new(),
         });
 
         let mut prev_stage_id = source_stage_id;
@@ -290,9 +296,15 @@ impl RouterConnection {
                     group_id: String::new(),
                     labels: HashMap::new(),
                     load_balance: LoadBalanceStrategy::LoadBalanceRoundRobin as i32,
+                    traffic_split: None,
                 }),
                 routing_rules: vec![],
                 parallelism: 1,
+                routing_mode: RoutingMode::AllMatches as i32,
+                transform_config: String::new(),
+                fan_config: String::new(),
+                partition_key: String::new(),
+                lookup_config: String::new(),
             });
 
             edges.push(Edge {
@@ -314,9 +326,15 @@ impl RouterConnection {
                 group_id: String::new(),
                 labels: HashMap::new(),
                 load_balance: LoadBalanceStrategy::LoadBalanceRoundRobin as i32,
+                traffic_split: None,
             }),
             routing_rules: vec![],
             parallelism: 1,
+            routing_mode: RoutingMode::AllMatches as i32,
+            transform_config: String::new(),
+            fan_config: String::new(),
+            partition_key: String::new(),
+            lookup_config: String::new(),
         });
 
         edges.push(Edge {
@@ -339,6 +357,7 @@ impl RouterConnection {
                 .unwrap_or_default()
                 .into_iter()
                 .collect(),
+            dead_letter_stage: String::new(),
         })
     }
 }
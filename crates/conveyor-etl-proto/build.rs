@@ -2,6 +2,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .build_server(true)
         .build_client(true)
+        .bytes([".conveyor_etl.common.Record"])
         .compile_protos(
             &[
                 "proto/common.proto",
@@ -15,6 +16,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 "proto/raft.proto",
                 "proto/backup.proto",
                 "proto/sidecar.proto",
+                "proto/schema.proto",
+                "proto/health.proto",
             ],
             &["proto/"],
         )?;
@@ -1,3 +1,7 @@
+/// Metadata key routers set on not-leader errors to the leader's client
+/// address, so clients can retry there instead of trying each node.
+pub const LEADER_HINT_KEY: &str = "conveyor-leader";
+
 pub mod common {
     tonic::include_proto!("conveyor.common");
 }
@@ -59,3 +63,11 @@ pub mod backup {
 pub mod sidecar {
     tonic::include_proto!("conveyor.sidecar");
 }
+
+pub mod schema {
+    tonic::include_proto!("conveyor.schema");
+}
+
+pub mod health {
+    tonic::include_proto!("grpc.health.v1");
+}
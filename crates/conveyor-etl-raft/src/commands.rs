@@ -98,6 +98,7 @@ pub enum RouterCommand {
         service_id: String,
         group_id: String,
         stage_id: String,
+        zone: Option<String>,
     },
 
     LeaveGroup {
@@ -124,6 +125,7 @@ pub enum RouterCommand {
         namespace: String,
         endpoint: String,
         local_services: Vec<SidecarLocalService>,
+        node_name: Option<String>,
     },
 
     DeregisterSidecar {
@@ -145,6 +147,48 @@ pub enum RouterCommand {
         pipeline_id: String,
         sidecar_id: String,
     },
+
+    RegisterSchema {
+        record_type: String,
+        version: u32,
+        format: String,
+        definition: String,
+        message_name: Option<String>,
+    },
+
+    SetSchemaCompatibility {
+        record_type: String,
+        compatibility: String,
+    },
+
+    DeleteSchema {
+        record_type: String,
+    },
+
+    CreateGroup {
+        group_id: String,
+        stage_id: String,
+        total_partitions: u32,
+    },
+
+    SetGroupTopology {
+        group_id: String,
+        topology_key: Option<String>,
+    },
+
+    SetRouterAddress {
+        node_id: u64,
+        client_addr: String,
+    },
+
+    /// Replaces the config only if the pipeline is still at
+    /// `expected_version`, so read-modify-write edits don't clobber each
+    /// other.
+    UpdatePipelineIfVersion {
+        pipeline_id: String,
+        expected_version: u64,
+        config: Vec<u8>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -152,16 +196,22 @@ pub struct SidecarLocalService {
     pub service_name: String,
     pub service_type: String,
     pub local_endpoint: String,
+    pub group_id: Option<String>,
+    pub labels: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SidecarStageAssignment {
     pub stage_id: String,
     pub target: SidecarStageTarget,
+    /// Where each partition of a partitioned stage runs, by partition.
+    #[serde(default)]
+    pub partitions: Vec<SidecarStageTarget>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SidecarStageTarget {
     Local { endpoint: String },
     Remote { sidecar_id: String, endpoint: String },
+    Builtin { config: String },
 }
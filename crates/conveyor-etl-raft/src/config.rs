@@ -4,10 +4,12 @@ use openraft::BasicNode;
 use serde::{Deserialize, Serialize};
 
 use crate::commands::RouterCommand;
+use crate::router_state::StaleGeneration;
 
 pub type NodeId = u64;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+/// Encoded with a format version header; see [`crate::encoding`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct RouterRequest {
     pub command: RouterCommand,
 }
@@ -16,6 +18,8 @@ pub struct RouterRequest {
 pub struct RouterResponse {
     pub success: bool,
     pub error: Option<String>,
+    #[serde(default)]
+    pub stale_generation: Option<StaleGeneration>,
 }
 
 openraft::declare_raft_types!(
@@ -0,0 +1,196 @@
+//! Versioned bincode encoding for log entries and snapshots.
+//!
+//! bincode is positional: it writes enum variants by index and structs as
+//! bare field sequences, so any reorder or new field changes what existing
+//! bytes mean. Every command and snapshot is therefore written behind a
+//! `(ENCODING_TAG, version)` header. Data written before the header existed
+//! starts with a variant index or a map length instead, and is decoded with
+//! the layouts in [`crate::legacy`] and migrated.
+
+use std::fmt;
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::{
+    self, DeserializeSeed, EnumAccess, IntoDeserializer, SeqAccess, VariantAccess, Visitor,
+};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::commands::RouterCommand;
+use crate::config::RouterRequest;
+use crate::legacy::{RouterCommandV0, RouterStateV0, RouterStateV1};
+use crate::router_state::RouterState;
+
+/// "CNVY". Larger than any variant index or map length an old release wrote.
+pub(crate) const ENCODING_TAG: u32 = 0x434e_5659;
+
+/// Bump whenever `RouterCommand` changes shape, and keep decoding the
+/// previous version.
+pub(crate) const COMMAND_FORMAT_VERSION: u32 = 2;
+
+/// Version 2 only appended `UpdatePipelineIfVersion`, so version 1 entries
+/// decode with the current layout.
+const COMPATIBLE_COMMAND_FORMAT_VERSIONS: [u32; 2] = [1, COMMAND_FORMAT_VERSION];
+
+/// Bump whenever `RouterState` changes shape, and keep decoding the
+/// previous version.
+pub(crate) const STATE_FORMAT_VERSION: u32 = 2;
+
+impl Serialize for RouterRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        tuple.serialize_element(&ENCODING_TAG)?;
+        tuple.serialize_element(&COMMAND_FORMAT_VERSION)?;
+        tuple.serialize_element(&self.command)?;
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for RouterRequest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(3, RequestVisitor)
+    }
+}
+
+struct RequestVisitor;
+
+impl<'de> Visitor<'de> for RequestVisitor {
+    type Value = RouterRequest;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a versioned router request")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let head: u32 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+
+        let command = if head == ENCODING_TAG {
+            let version: u32 = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+            if !COMPATIBLE_COMMAND_FORMAT_VERSIONS.contains(&version) {
+                return Err(de::Error::custom(format!(
+                    "unsupported command format version {}",
+                    version
+                )));
+            }
+            seq.next_element()?
+                .ok_or_else(|| de::Error::invalid_length(2, &self))?
+        } else {
+            seq.next_element_seed(LegacyCommand { index: head })?
+                .ok_or_else(|| de::Error::invalid_length(1, &self))?
+        };
+
+        Ok(RouterRequest { command })
+    }
+}
+
+/// Decodes the rest of an unversioned command whose variant index has
+/// already been read as the header.
+struct LegacyCommand {
+    index: u32,
+}
+
+impl<'de> DeserializeSeed<'de> for LegacyCommand {
+    type Value = RouterCommand;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        RouterCommandV0::deserialize(IndexedEnum {
+            index: self.index,
+            content: deserializer,
+        })
+        .map(Into::into)
+    }
+}
+
+/// Presents an already-read variant index plus the remaining input as an
+/// enum, the way bincode itself would.
+struct IndexedEnum<D> {
+    index: u32,
+    content: D,
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for IndexedEnum<D> {
+    type Error = D::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_enum(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de, D: Deserializer<'de>> EnumAccess<'de> for IndexedEnum<D> {
+    type Error = D::Error;
+    type Variant = VariantContent<D>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), Self::Error> {
+        let index: de::value::U32Deserializer<D::Error> = self.index.into_deserializer();
+        let variant = seed.deserialize(index)?;
+        Ok((variant, VariantContent(self.content)))
+    }
+}
+
+struct VariantContent<D>(D);
+
+impl<'de, D: Deserializer<'de>> VariantAccess<'de> for VariantContent<D> {
+    type Error = D::Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, Self::Error> {
+        seed.deserialize(self.0)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.0.deserialize_tuple(len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.deserialize_tuple(fields.len(), visitor)
+    }
+}
+
+pub(crate) fn encode_state(state: &RouterState) -> Result<Vec<u8>> {
+    bincode::serialize(&(ENCODING_TAG, STATE_FORMAT_VERSION, state))
+        .context("Failed to serialize router state")
+}
+
+pub(crate) fn decode_state(data: &[u8]) -> Result<RouterState> {
+    match bincode::deserialize::<(u32, u32)>(data) {
+        Ok((ENCODING_TAG, STATE_FORMAT_VERSION)) => {
+            let (_, _, state): (u32, u32, RouterState) =
+                bincode::deserialize(data).context("Failed to deserialize router state")?;
+            Ok(state)
+        }
+        Ok((ENCODING_TAG, 1)) => {
+            let (_, _, state): (u32, u32, RouterStateV1) =
+                bincode::deserialize(data).context("Failed to deserialize router state")?;
+            Ok(state.into())
+        }
+        Ok((ENCODING_TAG, version)) => Err(anyhow!("Unsupported state format version {}", version)),
+        _ => {
+            let state: RouterStateV0 =
+                bincode::deserialize(data).context("Failed to deserialize legacy router state")?;
+            Ok(state.into())
+        }
+    }
+}
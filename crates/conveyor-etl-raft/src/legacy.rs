@@ -0,0 +1,360 @@
+//! Layouts written by releases before the versioned encoding, kept so their
+//! logs and snapshots still decode after an upgrade.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::commands::{
+    RouterCommand, SerializableTimestamp, SidecarLocalService, SidecarStageAssignment,
+    SidecarStageTarget,
+};
+use crate::router_state::{
+    CheckpointState, GroupState, PipelineState, RouterState, SchemaSubjectState, ServiceState,
+    SidecarState,
+};
+
+#[derive(Debug, Deserialize)]
+pub(crate) enum RouterCommandV0 {
+    Noop,
+
+    RegisterService {
+        service_id: String,
+        service_name: String,
+        service_type: String,
+        endpoint: String,
+        labels: HashMap<String, String>,
+        group_id: Option<String>,
+    },
+
+    DeregisterService {
+        service_id: String,
+    },
+
+    RenewLease {
+        service_id: String,
+    },
+
+    UpdateServiceHealth {
+        service_id: String,
+        health: String,
+    },
+
+    CreatePipeline {
+        pipeline_id: String,
+        name: String,
+        config: Vec<u8>,
+    },
+
+    UpdatePipeline {
+        pipeline_id: String,
+        config: Vec<u8>,
+    },
+
+    DeletePipeline {
+        pipeline_id: String,
+    },
+
+    EnablePipeline {
+        pipeline_id: String,
+    },
+
+    DisablePipeline {
+        pipeline_id: String,
+    },
+
+    CommitSourceOffset {
+        source_id: String,
+        partition: u32,
+        offset: u64,
+    },
+
+    AdvanceWatermark {
+        source_id: String,
+        partition: u32,
+        position: u64,
+        event_time: Option<SerializableTimestamp>,
+    },
+
+    SaveServiceCheckpoint {
+        service_id: String,
+        checkpoint_id: String,
+        data: Vec<u8>,
+        source_offsets: HashMap<String, u64>,
+    },
+
+    JoinGroup {
+        service_id: String,
+        group_id: String,
+        stage_id: String,
+    },
+
+    LeaveGroup {
+        service_id: String,
+        group_id: String,
+    },
+
+    AssignPartitions {
+        group_id: String,
+        assignments: HashMap<String, Vec<u32>>,
+        generation: u64,
+    },
+
+    CommitGroupOffset {
+        group_id: String,
+        source_id: String,
+        partition: u32,
+        offset: u64,
+    },
+
+    RegisterSidecar {
+        sidecar_id: String,
+        pod_name: String,
+        namespace: String,
+        endpoint: String,
+        local_services: Vec<SidecarLocalServiceV0>,
+    },
+
+    DeregisterSidecar {
+        sidecar_id: String,
+    },
+
+    UpdateSidecarHeartbeat {
+        sidecar_id: String,
+        timestamp: u64,
+    },
+
+    AssignPipelineToSidecar {
+        pipeline_id: String,
+        sidecar_id: String,
+        stage_assignments: Vec<SidecarStageAssignmentV0>,
+    },
+
+    RevokePipelineFromSidecar {
+        pipeline_id: String,
+        sidecar_id: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SidecarLocalServiceV0 {
+    service_name: String,
+    service_type: String,
+    local_endpoint: String,
+}
+
+/// `SidecarStageTarget` only gained variants at the end, so the old
+/// `Local`/`Remote` encodings still decode as the current type.
+#[derive(Debug, Deserialize)]
+pub(crate) struct SidecarStageAssignmentV0 {
+    stage_id: String,
+    target: SidecarStageTarget,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RouterStateV0 {
+    services: HashMap<String, ServiceState>,
+    pipelines: HashMap<String, PipelineState>,
+    checkpoints: CheckpointState,
+    groups: HashMap<String, GroupStateV0>,
+    sidecars: HashMap<String, SidecarStateV0>,
+    service_locations: HashMap<String, String>,
+}
+
+/// State format version 1, before router addresses were recorded.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RouterStateV1 {
+    services: HashMap<String, ServiceState>,
+    pipelines: HashMap<String, PipelineState>,
+    checkpoints: CheckpointState,
+    groups: HashMap<String, GroupState>,
+    sidecars: HashMap<String, SidecarState>,
+    service_locations: HashMap<String, String>,
+    schemas: HashMap<String, SchemaSubjectState>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct GroupStateV0 {
+    group_id: String,
+    stage_id: String,
+    members: Vec<String>,
+    partition_assignments: HashMap<String, Vec<u32>>,
+    generation: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SidecarStateV0 {
+    sidecar_id: String,
+    pod_name: String,
+    namespace: String,
+    endpoint: String,
+    local_services: Vec<SidecarLocalServiceV0>,
+    assigned_pipelines: HashMap<String, Vec<SidecarStageAssignmentV0>>,
+    registered_at: u64,
+    last_heartbeat: u64,
+}
+
+impl From<SidecarLocalServiceV0> for SidecarLocalService {
+    fn from(service: SidecarLocalServiceV0) -> Self {
+        Self {
+            service_name: service.service_name,
+            service_type: service.service_type,
+            local_endpoint: service.local_endpoint,
+            group_id: None,
+            labels: HashMap::new(),
+        }
+    }
+}
+
+impl From<SidecarStageAssignmentV0> for SidecarStageAssignment {
+    fn from(assignment: SidecarStageAssignmentV0) -> Self {
+        Self {
+            stage_id: assignment.stage_id,
+            target: assignment.target,
+            partitions: Vec::new(),
+        }
+    }
+}
+
+impl From<RouterCommandV0> for RouterCommand {
+    fn from(command: RouterCommandV0) -> Self {
+        use RouterCommandV0 as V0;
+
+        match command {
+            V0::Noop => RouterCommand::Noop,
+            V0::RegisterService { service_id, service_name, service_type, endpoint, labels, group_id } => {
+                RouterCommand::RegisterService { service_id, service_name, service_type, endpoint, labels, group_id }
+            }
+            V0::DeregisterService { service_id } => RouterCommand::DeregisterService { service_id },
+            V0::RenewLease { service_id } => RouterCommand::RenewLease { service_id },
+            V0::UpdateServiceHealth { service_id, health } => {
+                RouterCommand::UpdateServiceHealth { service_id, health }
+            }
+            V0::CreatePipeline { pipeline_id, name, config } => {
+                RouterCommand::CreatePipeline { pipeline_id, name, config }
+            }
+            V0::UpdatePipeline { pipeline_id, config } => {
+                RouterCommand::UpdatePipeline { pipeline_id, config }
+            }
+            V0::DeletePipeline { pipeline_id } => RouterCommand::DeletePipeline { pipeline_id },
+            V0::EnablePipeline { pipeline_id } => RouterCommand::EnablePipeline { pipeline_id },
+            V0::DisablePipeline { pipeline_id } => RouterCommand::DisablePipeline { pipeline_id },
+            V0::CommitSourceOffset { source_id, partition, offset } => {
+                RouterCommand::CommitSourceOffset { source_id, partition, offset }
+            }
+            V0::AdvanceWatermark { source_id, partition, position, event_time } => {
+                RouterCommand::AdvanceWatermark { source_id, partition, position, event_time }
+            }
+            V0::SaveServiceCheckpoint { service_id, checkpoint_id, data, source_offsets } => {
+                RouterCommand::SaveServiceCheckpoint { service_id, checkpoint_id, data, source_offsets }
+            }
+            V0::JoinGroup { service_id, group_id, stage_id } => {
+                RouterCommand::JoinGroup { service_id, group_id, stage_id, zone: None }
+            }
+            V0::LeaveGroup { service_id, group_id } => RouterCommand::LeaveGroup { service_id, group_id },
+            V0::AssignPartitions { group_id, assignments, generation } => {
+                RouterCommand::AssignPartitions { group_id, assignments, generation }
+            }
+            V0::CommitGroupOffset { group_id, source_id, partition, offset } => {
+                RouterCommand::CommitGroupOffset { group_id, source_id, partition, offset }
+            }
+            V0::RegisterSidecar { sidecar_id, pod_name, namespace, endpoint, local_services } => {
+                RouterCommand::RegisterSidecar {
+                    sidecar_id,
+                    pod_name,
+                    namespace,
+                    endpoint,
+                    local_services: local_services.into_iter().map(Into::into).collect(),
+                    node_name: None,
+                }
+            }
+            V0::DeregisterSidecar { sidecar_id } => RouterCommand::DeregisterSidecar { sidecar_id },
+            V0::UpdateSidecarHeartbeat { sidecar_id, timestamp } => {
+                RouterCommand::UpdateSidecarHeartbeat { sidecar_id, timestamp }
+            }
+            V0::AssignPipelineToSidecar { pipeline_id, sidecar_id, stage_assignments } => {
+                RouterCommand::AssignPipelineToSidecar {
+                    pipeline_id,
+                    sidecar_id,
+                    stage_assignments: stage_assignments.into_iter().map(Into::into).collect(),
+                }
+            }
+            V0::RevokePipelineFromSidecar { pipeline_id, sidecar_id } => {
+                RouterCommand::RevokePipelineFromSidecar { pipeline_id, sidecar_id }
+            }
+        }
+    }
+}
+
+impl From<GroupStateV0> for GroupState {
+    /// Old groups never recorded their partition count, so it is taken from
+    /// the highest partition they had assigned.
+    fn from(group: GroupStateV0) -> Self {
+        let total_partitions = group
+            .partition_assignments
+            .values()
+            .flatten()
+            .max()
+            .map(|p| p + 1)
+            .unwrap_or(0);
+
+        Self {
+            members: group.members,
+            partition_assignments: group.partition_assignments,
+            generation: group.generation,
+            ..GroupState::new(group.group_id, group.stage_id, total_partitions)
+        }
+    }
+}
+
+impl From<SidecarStateV0> for SidecarState {
+    fn from(sidecar: SidecarStateV0) -> Self {
+        Self {
+            sidecar_id: sidecar.sidecar_id,
+            pod_name: sidecar.pod_name,
+            namespace: sidecar.namespace,
+            node_name: None,
+            endpoint: sidecar.endpoint,
+            local_services: sidecar.local_services.into_iter().map(Into::into).collect(),
+            assigned_pipelines: sidecar
+                .assigned_pipelines
+                .into_iter()
+                .map(|(pipeline_id, stages)| {
+                    (pipeline_id, stages.into_iter().map(Into::into).collect())
+                })
+                .collect(),
+            registered_at: sidecar.registered_at,
+            last_heartbeat: sidecar.last_heartbeat,
+        }
+    }
+}
+
+impl From<RouterStateV0> for RouterState {
+    fn from(state: RouterStateV0) -> Self {
+        Self {
+            services: state.services,
+            pipelines: state.pipelines,
+            checkpoints: state.checkpoints,
+            groups: state.groups.into_iter().map(|(id, g)| (id, g.into())).collect(),
+            sidecars: state.sidecars.into_iter().map(|(id, s)| (id, s.into())).collect(),
+            service_locations: state.service_locations,
+            schemas: HashMap::new(),
+            router_addresses: HashMap::new(),
+        }
+    }
+}
+
+impl From<RouterStateV1> for RouterState {
+    fn from(state: RouterStateV1) -> Self {
+        Self {
+            services: state.services,
+            pipelines: state.pipelines,
+            checkpoints: state.checkpoints,
+            groups: state.groups,
+            sidecars: state.sidecars,
+            service_locations: state.service_locations,
+            schemas: state.schemas,
+            router_addresses: HashMap::new(),
+        }
+    }
+}
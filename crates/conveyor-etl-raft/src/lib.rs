@@ -1,10 +1,15 @@
 mod commands;
 mod config;
+mod encoding;
+mod legacy;
 mod log_storage;
 mod network;
 mod router_state;
 mod state_machine;
 
+#[cfg(test)]
+mod tests;
+
 pub use commands::{
     RouterCommand, SerializableTimestamp, SidecarLocalService, SidecarStageAssignment,
     SidecarStageTarget,
@@ -13,8 +18,9 @@ pub use config::{ConveyorRaft, NodeId, RouterRequest, RouterResponse, TypeConfig
 pub use log_storage::LogStorage;
 pub use network::{Network, NetworkFactory, RaftServer};
 pub use router_state::{
-    CheckpointState, GroupState, PipelineState, RouterState, ServiceCheckpointState, ServiceState,
-    SidecarState, WatermarkState,
+    CheckpointState, GroupState, PipelineState, RouterState, SchemaSubjectState,
+    SchemaVersionState, ServiceCheckpointState, ServiceState, SidecarState, StaleGeneration,
+    WatermarkState,
 };
 pub use state_machine::{StateMachine, StoredSnapshot};
 
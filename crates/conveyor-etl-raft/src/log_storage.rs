@@ -147,17 +147,19 @@ impl RaftLogReader<TypeConfig> for LogStorage {
         let iter = db.iterator_cf(cf, IteratorMode::From(&start_key, rocksdb::Direction::Forward));
 
         for result in iter {
-            match result {
-                Ok((key, value)) => {
-                    if key.as_ref() >= end_key.as_slice() {
-                        break;
-                    }
-                    if let Ok(entry) = bincode::deserialize::<Entry<TypeConfig>>(&value) {
-                        entries.push(entry);
-                    }
-                }
-                Err(_) => break,
+            let (key, value) = result
+                .map_err(|e| StorageError::read_logs(anyhow::anyhow!("Read error: {}", e)))?;
+            if key.as_ref() >= end_key.as_slice() {
+                break;
             }
+            let entry = bincode::deserialize::<Entry<TypeConfig>>(&value).map_err(|e| {
+                StorageError::read_logs(anyhow::anyhow!(
+                    "Undecodable log entry at index {:?}: {}",
+                    Self::key_to_index(&key),
+                    e
+                ))
+            })?;
+            entries.push(entry);
         }
 
         Ok(entries)
@@ -172,13 +174,13 @@ impl RaftLogReader<TypeConfig> for LogStorage {
 
         let bytes = match db.get_cf(cf, VOTE_KEY) {
             Ok(Some(b)) => b,
-            _ => return Ok(None),
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(StorageError::read_vote(anyhow::anyhow!("Read error: {}", e))),
         };
 
-        let stored: StoredVote = match bincode::deserialize(&bytes) {
-            Ok(v) => v,
-            Err(_) => return Ok(None),
-        };
+        let stored: StoredVote = bincode::deserialize(&bytes).map_err(|e| {
+            StorageError::read_vote(anyhow::anyhow!("Undecodable vote: {}", e))
+        })?;
 
         let vote = Vote::new(stored.term, stored.leader_id.unwrap_or(0));
         if stored.committed {
@@ -150,11 +150,11 @@ impl RaftNetwork<TypeConfig> for Network {
     ) -> Result<AppendEntriesResponse<TypeConfig>, RpcError> {
         let mut client = self.get_client().await?;
 
-        let entries_bytes: Vec<Vec<u8>> = rpc
+        let entries_bytes = rpc
             .entries
             .iter()
-            .filter_map(|e| bincode::serialize(e).ok())
-            .collect();
+            .map(serialize)
+            .collect::<Result<Vec<_>, _>>()?;
 
         let proto_req = ProtoAppendEntriesRequest {
             vote: serialize(&rpc.vote)?,
@@ -262,9 +262,9 @@ impl RaftService for RaftServer {
             prev_log_id: deserialize_opt(req.prev_log_id),
             entries: req
                 .entries
-                .into_iter()
-                .filter_map(|bytes| bincode::deserialize(&bytes).ok())
-                .collect(),
+                .iter()
+                .map(|bytes| deser(bytes, "entry"))
+                .collect::<Result<_, _>>()?,
             leader_commit: deserialize_opt(req.leader_commit),
         };
 
@@ -13,6 +13,9 @@ pub struct RouterState {
     pub groups: HashMap<String, GroupState>,
     pub sidecars: HashMap<String, SidecarState>,
     pub service_locations: HashMap<String, String>,
+    pub schemas: HashMap<String, SchemaSubjectState>,
+    /// Client-facing address of each router, by Raft node id.
+    pub router_addresses: HashMap<u64, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -69,13 +72,51 @@ pub struct GroupState {
     pub members: Vec<String>,
     pub partition_assignments: HashMap<String, Vec<u32>>,
     pub generation: u64,
+    pub total_partitions: u32,
+    pub topology_key: Option<String>,
+    pub member_zones: HashMap<String, String>,
+    pub member_joined_at: HashMap<String, u64>,
 }
 
+impl GroupState {
+    pub fn new(group_id: String, stage_id: String, total_partitions: u32) -> Self {
+        Self {
+            group_id,
+            stage_id,
+            members: Vec::new(),
+            partition_assignments: HashMap::new(),
+            generation: 0,
+            total_partitions,
+            topology_key: None,
+            member_zones: HashMap::new(),
+            member_joined_at: HashMap::new(),
+        }
+    }
+}
+
+/// Rejection of a partition assignment proposed against an older group
+/// generation; carried through [`crate::RouterResponse`] so proposers can
+/// tell a lost race from other failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaleGeneration {
+    pub expected: u64,
+    pub got: u64,
+}
+
+impl std::fmt::Display for StaleGeneration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stale generation: expected {}, got {}", self.expected, self.got)
+    }
+}
+
+impl std::error::Error for StaleGeneration {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SidecarState {
     pub sidecar_id: String,
     pub pod_name: String,
     pub namespace: String,
+    pub node_name: Option<String>,
     pub endpoint: String,
     pub local_services: Vec<SidecarLocalService>,
     pub assigned_pipelines: HashMap<String, Vec<SidecarStageAssignment>>,
@@ -83,6 +124,40 @@ pub struct SidecarState {
     pub last_heartbeat: u64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaSubjectState {
+    pub record_type: String,
+    pub compatibility: String,
+    pub versions: Vec<SchemaVersionState>,
+}
+
+impl SchemaSubjectState {
+    pub fn new(record_type: String) -> Self {
+        Self {
+            record_type,
+            compatibility: "backward".to_string(),
+            versions: Vec::new(),
+        }
+    }
+
+    pub fn latest(&self) -> Option<&SchemaVersionState> {
+        self.versions.last()
+    }
+
+    pub fn version(&self, version: u32) -> Option<&SchemaVersionState> {
+        self.versions.iter().find(|v| v.version == version)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaVersionState {
+    pub version: u32,
+    pub format: String,
+    pub definition: String,
+    pub message_name: Option<String>,
+    pub registered_at: u64,
+}
+
 fn current_timestamp() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -129,6 +204,22 @@ impl RouterState {
                     pipeline.version += 1;
                 }
             }
+            RouterCommand::UpdatePipelineIfVersion { pipeline_id, expected_version, config } => {
+                let pipeline = self
+                    .pipelines
+                    .get_mut(&pipeline_id)
+                    .ok_or_else(|| anyhow::anyhow!("Pipeline not found: {}", pipeline_id))?;
+                if pipeline.version != expected_version {
+                    return Err(anyhow::anyhow!(
+                        "Pipeline {} changed: expected version {}, found {}",
+                        pipeline_id,
+                        expected_version,
+                        pipeline.version
+                    ));
+                }
+                pipeline.config = config;
+                pipeline.version += 1;
+            }
             RouterCommand::DeletePipeline { pipeline_id } => {
                 self.pipelines.remove(&pipeline_id);
             }
@@ -156,21 +247,34 @@ impl RouterState {
                 self.apply_commit_group_offset(group_id, source_id, partition, offset);
             }
 
-            RouterCommand::JoinGroup { service_id, group_id, stage_id } => {
-                self.apply_join_group(service_id, group_id, stage_id);
+            RouterCommand::CreateGroup { group_id, stage_id, total_partitions } => {
+                if self.groups.contains_key(&group_id) {
+                    return Err(anyhow::anyhow!("Group already exists: {}", group_id));
+                }
+                self.groups.insert(
+                    group_id.clone(),
+                    GroupState::new(group_id, stage_id, total_partitions),
+                );
+            }
+            RouterCommand::SetGroupTopology { group_id, topology_key } => {
+                let group = self
+                    .groups
+                    .get_mut(&group_id)
+                    .ok_or_else(|| anyhow::anyhow!("Group not found: {}", group_id))?;
+                group.topology_key = topology_key;
+            }
+            RouterCommand::JoinGroup { service_id, group_id, stage_id, zone } => {
+                self.apply_join_group(service_id, group_id, stage_id, zone);
             }
             RouterCommand::LeaveGroup { service_id, group_id } => {
                 self.apply_leave_group(service_id, group_id);
             }
             RouterCommand::AssignPartitions { group_id, assignments, generation } => {
-                if let Some(group) = self.groups.get_mut(&group_id) {
-                    group.partition_assignments = assignments;
-                    group.generation = generation;
-                }
+                self.apply_assign_partitions(group_id, assignments, generation)?;
             }
 
-            RouterCommand::RegisterSidecar { sidecar_id, pod_name, namespace, endpoint, local_services } => {
-                self.apply_register_sidecar(sidecar_id, pod_name, namespace, endpoint, local_services);
+            RouterCommand::RegisterSidecar { sidecar_id, pod_name, namespace, endpoint, local_services, node_name } => {
+                self.apply_register_sidecar(sidecar_id, pod_name, namespace, node_name, endpoint, local_services);
             }
             RouterCommand::DeregisterSidecar { sidecar_id } => {
                 self.apply_deregister_sidecar(sidecar_id);
@@ -190,6 +294,23 @@ impl RouterState {
                     sidecar.assigned_pipelines.remove(&pipeline_id);
                 }
             }
+
+            RouterCommand::RegisterSchema { record_type, version, format, definition, message_name } => {
+                self.apply_register_schema(record_type, version, format, definition, message_name)?;
+            }
+            RouterCommand::SetSchemaCompatibility { record_type, compatibility } => {
+                self.schemas
+                    .entry(record_type.clone())
+                    .or_insert_with(|| SchemaSubjectState::new(record_type))
+                    .compatibility = compatibility;
+            }
+            RouterCommand::DeleteSchema { record_type } => {
+                self.schemas.remove(&record_type);
+            }
+
+            RouterCommand::SetRouterAddress { node_id, client_addr } => {
+                self.router_addresses.insert(node_id, client_addr);
+            }
         }
 
         Ok(())
@@ -295,20 +416,30 @@ impl RouterState {
             .insert(partition, offset);
     }
 
-    fn apply_join_group(&mut self, service_id: String, group_id: String, stage_id: String) {
-        let group = self.groups.entry(group_id.clone()).or_insert_with(|| {
-            GroupState {
-                group_id,
-                stage_id,
-                members: Vec::new(),
-                partition_assignments: HashMap::new(),
-                generation: 0,
-            }
-        });
+    fn apply_join_group(
+        &mut self,
+        service_id: String,
+        group_id: String,
+        stage_id: String,
+        zone: Option<String>,
+    ) {
+        let group = self
+            .groups
+            .entry(group_id.clone())
+            .or_insert_with(|| GroupState::new(group_id, stage_id, 0));
 
         if !group.members.contains(&service_id) {
-            group.members.push(service_id);
-            group.generation += 1;
+            group.members.push(service_id.clone());
+            group.member_joined_at.insert(service_id.clone(), current_timestamp());
+        }
+
+        match zone {
+            Some(zone) => {
+                group.member_zones.insert(service_id, zone);
+            }
+            None => {
+                group.member_zones.remove(&service_id);
+            }
         }
     }
 
@@ -316,15 +447,77 @@ impl RouterState {
         if let Some(group) = self.groups.get_mut(&group_id) {
             group.members.retain(|m| m != &service_id);
             group.partition_assignments.remove(&service_id);
-            group.generation += 1;
+            group.member_zones.remove(&service_id);
+            group.member_joined_at.remove(&service_id);
         }
     }
 
+    fn apply_assign_partitions(
+        &mut self,
+        group_id: String,
+        assignments: HashMap<String, Vec<u32>>,
+        generation: u64,
+    ) -> Result<()> {
+        let group = self
+            .groups
+            .get_mut(&group_id)
+            .ok_or_else(|| anyhow::anyhow!("Group not found: {}", group_id))?;
+
+        if generation != group.generation + 1 {
+            return Err(StaleGeneration {
+                expected: group.generation + 1,
+                got: generation,
+            }
+            .into());
+        }
+
+        group.partition_assignments = assignments
+            .into_iter()
+            .filter(|(member, _)| group.members.contains(member))
+            .collect();
+        group.generation = generation;
+        Ok(())
+    }
+
+    fn apply_register_schema(
+        &mut self,
+        record_type: String,
+        version: u32,
+        format: String,
+        definition: String,
+        message_name: Option<String>,
+    ) -> Result<()> {
+        let subject = self
+            .schemas
+            .entry(record_type.clone())
+            .or_insert_with(|| SchemaSubjectState::new(record_type.clone()));
+
+        let expected = subject.latest().map(|v| v.version + 1).unwrap_or(1);
+        if version != expected {
+            return Err(anyhow::anyhow!(
+                "Stale schema version for {}: expected {}, got {}",
+                record_type,
+                expected,
+                version
+            ));
+        }
+
+        subject.versions.push(SchemaVersionState {
+            version,
+            format,
+            definition,
+            message_name,
+            registered_at: current_timestamp(),
+        });
+        Ok(())
+    }
+
     fn apply_register_sidecar(
         &mut self,
         sidecar_id: String,
         pod_name: String,
         namespace: String,
+        node_name: Option<String>,
         endpoint: String,
         local_services: Vec<SidecarLocalService>,
     ) {
@@ -341,6 +534,7 @@ impl RouterState {
                 sidecar_id,
                 pod_name,
                 namespace,
+                node_name,
                 endpoint,
                 local_services,
                 assigned_pipelines: HashMap::new(),
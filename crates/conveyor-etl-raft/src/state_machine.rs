@@ -6,7 +6,8 @@ use openraft::{Entry, EntryPayload, LogId, Snapshot, SnapshotMeta, StorageError,
 use tokio::sync::RwLock;
 
 use crate::config::{NodeId, RouterRequest, RouterResponse, TypeConfig};
-use crate::router_state::RouterState;
+use crate::encoding::{decode_state, encode_state};
+use crate::router_state::{RouterState, StaleGeneration};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct StoredSnapshot {
@@ -39,9 +40,8 @@ impl StateMachine {
 impl RaftSnapshotBuilder<TypeConfig> for StateMachine {
     async fn build_snapshot(&mut self) -> Result<Snapshot<TypeConfig>, StorageError<TypeConfig>> {
         let state = self.state.read().await;
-        let data = bincode::serialize(&*state).map_err(|e| {
-            StorageError::read_state_machine(anyhow::anyhow!("Serialize error: {}", e))
-        })?;
+        let data = encode_state(&state)
+            .map_err(|e| StorageError::read_state_machine(anyhow::anyhow!("{:#}", e)))?;
         drop(state);
 
         let snapshot_id = format!(
@@ -99,16 +99,19 @@ impl RaftStateMachine<TypeConfig> for StateMachine {
                     results.push(RouterResponse {
                         success: true,
                         error: None,
+                        stale_generation: None,
                     });
                 }
                 EntryPayload::Normal(req) => match state.apply_command(req.command) {
                     Ok(()) => results.push(RouterResponse {
                         success: true,
                         error: None,
+                        stale_generation: None,
                     }),
                     Err(e) => results.push(RouterResponse {
                         success: false,
                         error: Some(e.to_string()),
+                        stale_generation: e.downcast_ref::<StaleGeneration>().copied(),
                     }),
                 },
                 EntryPayload::Membership(m) => {
@@ -116,6 +119,7 @@ impl RaftStateMachine<TypeConfig> for StateMachine {
                     results.push(RouterResponse {
                         success: true,
                         error: None,
+                        stale_generation: None,
                     });
                 }
             }
@@ -146,9 +150,8 @@ impl RaftStateMachine<TypeConfig> for StateMachine {
     ) -> Result<(), StorageError<TypeConfig>> {
         let data = snapshot.into_inner();
 
-        let new_state: RouterState = bincode::deserialize(&data).map_err(|e| {
-            StorageError::read_state_machine(anyhow::anyhow!("Deserialize error: {}", e))
-        })?;
+        let new_state = decode_state(&data)
+            .map_err(|e| StorageError::read_state_machine(anyhow::anyhow!("{:#}", e)))?;
 
         *self.state.write().await = new_state;
         self.last_applied_log = meta.last_log_id;
@@ -0,0 +1,258 @@
+mod encoding_tests {
+    use std::collections::HashMap;
+
+    use crate::encoding::{decode_state, encode_state, COMMAND_FORMAT_VERSION, ENCODING_TAG};
+    use crate::{
+        CheckpointState, GroupState, RouterCommand, RouterRequest, RouterState,
+        SidecarStageTarget,
+    };
+
+    fn decode(bytes: &[u8]) -> bincode::Result<RouterCommand> {
+        bincode::deserialize::<RouterRequest>(bytes).map(|r| r.command)
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        let request = RouterRequest {
+            command: RouterCommand::SetGroupTopology {
+                group_id: "group-1".to_string(),
+                topology_key: Some("topology.kubernetes.io/zone".to_string()),
+            },
+        };
+
+        let bytes = bincode::serialize(&request).unwrap();
+        let header: (u32, u32) = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(header, (ENCODING_TAG, COMMAND_FORMAT_VERSION));
+
+        match decode(&bytes).unwrap() {
+            RouterCommand::SetGroupTopology { group_id, topology_key } => {
+                assert_eq!(group_id, "group-1");
+                assert_eq!(topology_key.as_deref(), Some("topology.kubernetes.io/zone"));
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    // Legacy entries are built field by field so they match what the old
+    // derive wrote, independent of the current types.
+
+    #[test]
+    fn test_legacy_unit_command_decodes() {
+        let bytes = bincode::serialize(&0u32).unwrap();
+        assert!(matches!(decode(&bytes).unwrap(), RouterCommand::Noop));
+    }
+
+    #[test]
+    fn test_legacy_join_group_decodes_without_zone() {
+        let bytes = bincode::serialize(&(13u32, "service-a", "group-1", "stage-1")).unwrap();
+
+        match decode(&bytes).unwrap() {
+            RouterCommand::JoinGroup { service_id, group_id, stage_id, zone } => {
+                assert_eq!(service_id, "service-a");
+                assert_eq!(group_id, "group-1");
+                assert_eq!(stage_id, "stage-1");
+                assert_eq!(zone, None);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_legacy_register_sidecar_decodes() {
+        let local_services = vec![("enricher", "transform", "localhost:9000")];
+        let bytes = bincode::serialize(&(
+            17u32,
+            "sidecar-1",
+            "pod-1",
+            "default",
+            "10.0.0.1:9090",
+            local_services,
+        ))
+        .unwrap();
+
+        match decode(&bytes).unwrap() {
+            RouterCommand::RegisterSidecar { sidecar_id, endpoint, local_services, node_name, .. } => {
+                assert_eq!(sidecar_id, "sidecar-1");
+                assert_eq!(endpoint, "10.0.0.1:9090");
+                assert_eq!(node_name, None);
+                assert_eq!(local_services.len(), 1);
+                assert_eq!(local_services[0].service_name, "enricher");
+                assert_eq!(local_services[0].group_id, None);
+                assert!(local_services[0].labels.is_empty());
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_legacy_stage_assignment_decodes() {
+        let target = (0u32, "localhost:9000");
+        let bytes = bincode::serialize(&(20u32, "pipeline-1", "sidecar-1", vec![("stage-1", target)]))
+            .unwrap();
+
+        match decode(&bytes).unwrap() {
+            RouterCommand::AssignPipelineToSidecar { stage_assignments, .. } => {
+                assert_eq!(stage_assignments.len(), 1);
+                assert_eq!(
+                    stage_assignments[0].target,
+                    SidecarStageTarget::Local { endpoint: "localhost:9000".to_string() }
+                );
+                assert!(stage_assignments[0].partitions.is_empty());
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_command_version_rejected() {
+        let bytes = bincode::serialize(&(ENCODING_TAG, COMMAND_FORMAT_VERSION + 1, 0u32)).unwrap();
+        let err = decode(&bytes).unwrap_err();
+        assert!(err.to_string().contains("unsupported command format version"));
+    }
+
+    #[test]
+    fn test_version_one_command_decodes() {
+        let bytes = bincode::serialize(&(
+            ENCODING_TAG,
+            1u32,
+            RouterCommand::DeletePipeline { pipeline_id: "p1".to_string() },
+        ))
+        .unwrap();
+        assert!(matches!(
+            decode(&bytes).unwrap(),
+            RouterCommand::DeletePipeline { pipeline_id } if pipeline_id == "p1"
+        ));
+    }
+
+    #[test]
+    fn test_unknown_legacy_variant_rejected() {
+        let bytes = bincode::serialize(&40u32).unwrap();
+        assert!(decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_truncated_command_rejected() {
+        let request = RouterRequest {
+            command: RouterCommand::DeleteSchema { record_type: "orders".to_string() },
+        };
+        let bytes = bincode::serialize(&request).unwrap();
+        assert!(decode(&bytes[..bytes.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut state = RouterState::default();
+        state.groups.insert(
+            "group-1".to_string(),
+            GroupState::new("group-1".to_string(), "stage-1".to_string(), 8),
+        );
+
+        state.router_addresses.insert(1, "router-1:50051".to_string());
+
+        let decoded = decode_state(&encode_state(&state).unwrap()).unwrap();
+        assert_eq!(decoded.groups["group-1"].total_partitions, 8);
+        assert_eq!(decoded.router_addresses[&1], "router-1:50051");
+    }
+
+    #[test]
+    fn test_legacy_state_migrates() {
+        let mut assignments = HashMap::new();
+        assignments.insert("service-a", vec![0u32, 1]);
+        assignments.insert("service-b", vec![2u32, 3, 4]);
+        let group = ("group-1", "stage-1", vec!["service-a", "service-b"], assignments, 7u64);
+
+        let mut groups = HashMap::new();
+        groups.insert("group-1", group);
+        let empty: HashMap<String, ()> = HashMap::new();
+        let bytes = bincode::serialize(&(
+            &empty,
+            &empty,
+            CheckpointState::default(),
+            groups,
+            &empty,
+            HashMap::<String, String>::new(),
+        ))
+        .unwrap();
+
+        let state = decode_state(&bytes).unwrap();
+        let group = &state.groups["group-1"];
+        assert_eq!(group.generation, 7);
+        assert_eq!(group.members, vec!["service-a", "service-b"]);
+        assert_eq!(group.total_partitions, 5);
+        assert_eq!(group.topology_key, None);
+        assert!(state.schemas.is_empty());
+    }
+
+    #[test]
+    fn test_version_one_state_migrates() {
+        let mut state = RouterState::default();
+        state.groups.insert(
+            "group-1".to_string(),
+            GroupState::new("group-1".to_string(), "stage-1".to_string(), 8),
+        );
+        state.router_addresses.insert(1, "router-1:50051".to_string());
+
+        let v1 = bincode::serialize(&(
+            ENCODING_TAG,
+            1u32,
+            &state.services,
+            &state.pipelines,
+            &state.checkpoints,
+            &state.groups,
+            &state.sidecars,
+            &state.service_locations,
+            &state.schemas,
+        ))
+        .unwrap();
+
+        let decoded = decode_state(&v1).unwrap();
+        assert_eq!(decoded.groups["group-1"].total_partitions, 8);
+        assert!(decoded.router_addresses.is_empty());
+    }
+
+    #[test]
+    fn test_corrupt_state_rejected() {
+        let bytes = encode_state(&RouterState::default()).unwrap();
+        assert!(decode_state(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode_state(&[0xff; 3]).is_err());
+    }
+}
+
+mod router_state_tests {
+    use crate::{RouterCommand, RouterState};
+
+    #[test]
+    fn test_conditional_pipeline_update() {
+        let mut state = RouterState::default();
+        state
+            .apply_command(RouterCommand::CreatePipeline {
+                pipeline_id: "p1".to_string(),
+                name: "p1".to_string(),
+                config: vec![1],
+            })
+            .unwrap();
+
+        let update = |expected_version, config| RouterCommand::UpdatePipelineIfVersion {
+            pipeline_id: "p1".to_string(),
+            expected_version,
+            config,
+        };
+
+        state.apply_command(update(1, vec![2])).unwrap();
+        assert_eq!(state.pipelines["p1"].version, 2);
+
+        let err = state.apply_command(update(1, vec![3])).unwrap_err();
+        assert!(err.to_string().contains("expected version 1, found 2"));
+        assert_eq!(state.pipelines["p1"].config, vec![2]);
+        assert_eq!(state.pipelines["p1"].version, 2);
+
+        let err = state
+            .apply_command(RouterCommand::UpdatePipelineIfVersion {
+                pipeline_id: "missing".to_string(),
+                expected_version: 1,
+                config: vec![],
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("Pipeline not found"));
+    }
+}
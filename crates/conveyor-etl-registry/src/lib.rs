@@ -1,9 +1,13 @@
 mod service_registry;
-mod group_coordinator;
 mod load_balancer;
+mod outlier_detector;
+mod topology;
+mod traffic_split;
 #[cfg(test)]
 mod tests;
 
 pub use service_registry::{ServiceRegistry, RegisteredService, ServiceHealth, ServiceType, ServiceEvent};
-pub use group_coordinator::{GroupCoordinator, ServiceGroup, GroupMember, PartitionAssignment, RebalanceEvent};
-pub use load_balancer::LoadBalancer;
+pub use load_balancer::{LoadBalancer, LoadBalanceStrategy, Selection};
+pub use outlier_detector::{Admission, OutlierDetector, OutlierDetectionConfig, CircuitState, EjectionReason};
+pub use topology::{Locality, ZONE_TOPOLOGY_KEY, REGION_TOPOLOGY_KEY, order_by_locality, prefer_local, spread_by_topology, spread_services};
+pub use traffic_split::{TrafficSplit, VersionWeight, VersionStats, VERSION_LABEL};
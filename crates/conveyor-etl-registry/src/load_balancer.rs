@@ -1,9 +1,17 @@
 use std::hash::{Hash, Hasher};
+use std::ops::Deref;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use dashmap::DashMap;
+use rand::Rng;
+use tracing::debug;
 
+use super::outlier_detector::{Admission, OutlierDetector};
 use super::service_registry::RegisteredService;
+use super::topology::{prefer_local, Locality};
+use super::traffic_split::{TrafficSplit, VersionStats};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LoadBalanceStrategy {
@@ -11,6 +19,7 @@ pub enum LoadBalanceStrategy {
     LeastConnections,
     WeightedRandom,
     ConsistentHash,
+    PeakEwma,
 }
 
 impl Default for LoadBalanceStrategy {
@@ -19,10 +28,83 @@ impl Default for LoadBalanceStrategy {
     }
 }
 
+const PEAK_EWMA_DECAY: Duration = Duration::from_secs(10);
+const PEAK_EWMA_DEFAULT_RTT_MS: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy)]
+struct PeakEwma {
+    latency_ms: f64,
+    updated_at: Instant,
+}
+
+impl PeakEwma {
+    fn new(latency_ms: f64) -> Self {
+        Self {
+            latency_ms,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn observe(&mut self, latency_ms: f64) {
+        let now = Instant::now();
+        if latency_ms > self.latency_ms {
+            self.latency_ms = latency_ms;
+        } else {
+            let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+            let decay = (-elapsed / PEAK_EWMA_DECAY.as_secs_f64()).exp();
+            self.latency_ms = self.latency_ms * decay + latency_ms * (1.0 - decay);
+        }
+        self.updated_at = now;
+    }
+}
+
+/// A service picked by [`LoadBalancer`]. With an outlier detector it holds
+/// the admission for the call: report the call's outcome through
+/// [`Self::success`] or [`Self::failure`], or drop it to give back the
+/// half-open probe slot it may have taken.
+pub struct Selection<'a> {
+    service: RegisteredService,
+    admission: Option<Admission<'a>>,
+}
+
+impl<'a> Selection<'a> {
+    fn new(service: RegisteredService, admission: Option<Admission<'a>>) -> Self {
+        Self { service, admission }
+    }
+
+    pub fn into_service(self) -> RegisteredService {
+        self.service
+    }
+
+    pub fn success(self, latency: Duration) {
+        if let Some(admission) = self.admission {
+            admission.success(latency);
+        }
+    }
+
+    pub fn failure(self) {
+        if let Some(admission) = self.admission {
+            admission.failure();
+        }
+    }
+}
+
+impl Deref for Selection<'_> {
+    type Target = RegisteredService;
+
+    fn deref(&self) -> &RegisteredService {
+        &self.service
+    }
+}
+
 pub struct LoadBalancer {
     round_robin_counters: DashMap<String, AtomicUsize>,
     connection_counts: DashMap<String, AtomicUsize>,
     weights: DashMap<String, u32>,
+    latencies: DashMap<String, PeakEwma>,
+    queue_depths: DashMap<String, u64>,
+    version_stats: DashMap<(String, String), VersionStats>,
+    outlier_detector: Option<Arc<OutlierDetector>>,
 }
 
 impl LoadBalancer {
@@ -31,14 +113,63 @@ impl LoadBalancer {
             round_robin_counters: DashMap::new(),
             connection_counts: DashMap::new(),
             weights: DashMap::new(),
+            latencies: DashMap::new(),
+            queue_depths: DashMap::new(),
+            version_stats: DashMap::new(),
+            outlier_detector: None,
+        }
+    }
+
+    pub fn with_outlier_detector(outlier_detector: Arc<OutlierDetector>) -> Self {
+        Self {
+            outlier_detector: Some(outlier_detector),
+            ..Self::new()
         }
     }
 
+    pub fn outlier_detector(&self) -> Option<&Arc<OutlierDetector>> {
+        self.outlier_detector.as_ref()
+    }
+
     pub async fn select(
         &self,
         services: &[RegisteredService],
         strategy: LoadBalanceStrategy,
         routing_key: Option<&str>,
+    ) -> Option<Selection<'_>> {
+        let unadmitted = |service| Selection::new(service, None);
+        let Some(detector) = &self.outlier_detector else {
+            return self.select_from(services, strategy, routing_key).map(unadmitted);
+        };
+
+        let mut available: Vec<RegisteredService> = services
+            .iter()
+            .filter(|s| !detector.is_ejected(&s.endpoint))
+            .cloned()
+            .collect();
+
+        // With every endpoint ejected, keep sending somewhere rather than
+        // failing everything.
+        if available.is_empty() {
+            return self.select_from(services, strategy, routing_key).map(unadmitted);
+        }
+
+        // Another caller can take a half-open probe slot between the check
+        // above and here, so pick again without the endpoint.
+        loop {
+            let selected = self.select_from(&available, strategy, routing_key)?;
+            if let Some(admission) = detector.admit(&selected.endpoint) {
+                return Some(Selection::new(selected, Some(admission)));
+            }
+            available.retain(|s| s.endpoint != selected.endpoint);
+        }
+    }
+
+    fn select_from(
+        &self,
+        services: &[RegisteredService],
+        strategy: LoadBalanceStrategy,
+        routing_key: Option<&str>,
     ) -> Option<RegisteredService> {
         if services.is_empty() {
             return None;
@@ -55,9 +186,68 @@ impl LoadBalancer {
             LoadBalanceStrategy::ConsistentHash => {
                 self.consistent_hash(services, routing_key)
             }
+            LoadBalanceStrategy::PeakEwma => self.peak_ewma(services),
+        }
+    }
+
+    pub async fn select_with_locality(
+        &self,
+        services: &[RegisteredService],
+        strategy: LoadBalanceStrategy,
+        routing_key: Option<&str>,
+        locality: Option<&Locality>,
+    ) -> Option<Selection<'_>> {
+        match locality {
+            Some(locality) => {
+                let candidates = prefer_local(services, locality);
+                self.select(&candidates, strategy, routing_key).await
+            }
+            None => self.select(services, strategy, routing_key).await,
         }
     }
 
+    pub async fn select_with_split(
+        &self,
+        services: &[RegisteredService],
+        strategy: LoadBalanceStrategy,
+        routing_key: Option<&str>,
+        split: Option<&TrafficSplit>,
+    ) -> Option<Selection<'_>> {
+        self.select_with_split_and_locality(services, strategy, routing_key, split, None)
+            .await
+    }
+
+    pub async fn select_with_split_and_locality(
+        &self,
+        services: &[RegisteredService],
+        strategy: LoadBalanceStrategy,
+        routing_key: Option<&str>,
+        split: Option<&TrafficSplit>,
+        locality: Option<&Locality>,
+    ) -> Option<Selection<'_>> {
+        let Some(version) = split.and_then(|s| s.choose_version(routing_key)) else {
+            return self
+                .select_with_locality(services, strategy, routing_key, locality)
+                .await;
+        };
+
+        let candidates: Vec<RegisteredService> = services
+            .iter()
+            .filter(|s| s.version() == Some(version))
+            .cloned()
+            .collect();
+
+        if candidates.is_empty() {
+            debug!(version = %version, "No instances of split version, falling back to all versions");
+            return self
+                .select_with_locality(services, strategy, routing_key, locality)
+                .await;
+        }
+
+        self.select_with_locality(&candidates, strategy, routing_key, locality)
+            .await
+    }
+
     fn round_robin(&self, services: &[RegisteredService]) -> Option<RegisteredService> {
         if services.is_empty() {
             return None;
@@ -151,6 +341,134 @@ impl LoadBalancer {
         Some(services[idx].clone())
     }
 
+    fn peak_ewma(&self, services: &[RegisteredService]) -> Option<RegisteredService> {
+        if services.len() < 2 {
+            return services.first().cloned();
+        }
+
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..services.len());
+        let mut second = rng.gen_range(0..services.len() - 1);
+        if second >= first {
+            second += 1;
+        }
+
+        let a = &services[first];
+        let b = &services[second];
+        if self.load_cost(&a.service_id) <= self.load_cost(&b.service_id) {
+            Some(a.clone())
+        } else {
+            Some(b.clone())
+        }
+    }
+
+    fn load_cost(&self, service_id: &str) -> f64 {
+        let latency_ms = self
+            .latencies
+            .get(service_id)
+            .map(|l| l.latency_ms)
+            .unwrap_or(PEAK_EWMA_DEFAULT_RTT_MS);
+        let in_flight = self
+            .connection_counts
+            .get(service_id)
+            .map(|c| c.load(Ordering::Relaxed))
+            .unwrap_or(0);
+        let queue_depth = self
+            .queue_depths
+            .get(service_id)
+            .map(|q| *q)
+            .unwrap_or(0);
+
+        latency_ms.max(PEAK_EWMA_DEFAULT_RTT_MS) * (in_flight as f64 + queue_depth as f64 + 1.0)
+    }
+
+    pub fn record_latency(&self, service_id: &str, latency: Duration) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        self.latencies
+            .entry(service_id.to_string())
+            .and_modify(|l| l.observe(latency_ms))
+            .or_insert_with(|| PeakEwma::new(latency_ms));
+    }
+
+    pub fn update_load(&self, service_id: &str, avg_latency_ms: f32, queue_depth: u64) {
+        if avg_latency_ms > 0.0 {
+            self.record_latency(
+                service_id,
+                Duration::from_secs_f64(avg_latency_ms as f64 / 1000.0),
+            );
+        }
+        self.queue_depths.insert(service_id.to_string(), queue_depth);
+    }
+
+    pub fn start_request(&self, service_id: &str) -> Instant {
+        self.increment_connections(service_id);
+        Instant::now()
+    }
+
+    pub fn finish_request(&self, service_id: &str, started: Instant) {
+        self.decrement_connections(service_id);
+        self.record_latency(service_id, started.elapsed());
+    }
+
+    pub fn record_version_outcome(
+        &self,
+        service_name: &str,
+        version: &str,
+        success: bool,
+        latency: Duration,
+    ) {
+        self.version_stats
+            .entry((service_name.to_string(), version.to_string()))
+            .or_default()
+            .record(success, latency);
+    }
+
+    pub fn add_version_stats(&self, service_name: &str, version: &str, stats: &VersionStats) {
+        self.version_stats
+            .entry((service_name.to_string(), version.to_string()))
+            .or_default()
+            .merge(stats);
+    }
+
+    pub fn version_stats(&self, service_name: &str) -> Vec<(String, VersionStats)> {
+        let mut stats: Vec<(String, VersionStats)> = self
+            .version_stats
+            .iter()
+            .filter(|entry| entry.key().0 == service_name)
+            .map(|entry| (entry.key().1.clone(), entry.value().clone()))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    pub fn all_version_stats(&self) -> Vec<(String, String, VersionStats)> {
+        self.version_stats
+            .iter()
+            .map(|entry| {
+                let (service_name, version) = entry.key().clone();
+                (service_name, version, entry.value().clone())
+            })
+            .collect()
+    }
+
+    pub fn queue_depth(&self, service_id: &str) -> Option<u64> {
+        self.queue_depths.get(service_id).map(|q| *q)
+    }
+
+    pub fn queue_depths(&self) -> Vec<(String, u64)> {
+        self.queue_depths
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
+    pub fn remove_service(&self, service_id: &str) {
+        self.connection_counts.remove(service_id);
+        self.weights.remove(service_id);
+        self.latencies.remove(service_id);
+        self.queue_depths.remove(service_id);
+    }
+
     pub fn increment_connections(&self, service_id: &str) {
         self.connection_counts
             .entry(service_id.to_string())
@@ -160,7 +478,9 @@ impl LoadBalancer {
 
     pub fn decrement_connections(&self, service_id: &str) {
         if let Some(count) = self.connection_counts.get(service_id) {
-            count.fetch_sub(1, Ordering::Relaxed);
+            let _ = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+                Some(c.saturating_sub(1))
+            });
         }
     }
 
@@ -0,0 +1,439 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct OutlierDetectionConfig {
+    pub consecutive_failures: u32,
+    pub failure_rate_threshold: f64,
+    pub failure_rate_window: Duration,
+    pub failure_rate_min_requests: u32,
+    pub latency_outlier_factor: f64,
+    pub latency_min_samples: u32,
+    pub base_ejection_time: Duration,
+    pub max_ejection_time: Duration,
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierDetectionConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            failure_rate_threshold: 0.5,
+            failure_rate_window: Duration::from_secs(10),
+            failure_rate_min_requests: 20,
+            latency_outlier_factor: 3.0,
+            latency_min_samples: 20,
+            base_ejection_time: Duration::from_secs(30),
+            max_ejection_time: Duration::from_secs(300),
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EjectionReason {
+    ConsecutiveFailures,
+    FailureRate,
+    LatencyOutlier,
+    ProbeFailed,
+}
+
+#[derive(Debug)]
+struct EndpointStats {
+    state: CircuitState,
+    consecutive_failures: u32,
+    window_started: Instant,
+    window_requests: u32,
+    window_failures: u32,
+    latency_ewma_ms: f64,
+    latency_samples: u32,
+    ejection_count: u32,
+    ejected_until: Option<Instant>,
+    probe_in_flight: bool,
+}
+
+impl EndpointStats {
+    fn new() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            window_started: Instant::now(),
+            window_requests: 0,
+            window_failures: 0,
+            latency_ewma_ms: 0.0,
+            latency_samples: 0,
+            ejection_count: 0,
+            ejected_until: None,
+            probe_in_flight: false,
+        }
+    }
+
+    fn roll_window(&mut self, window: Duration) {
+        if self.window_started.elapsed() < window {
+            return;
+        }
+
+        if self.state == CircuitState::Closed
+            && self.window_requests > 0
+            && self.window_failures == 0
+        {
+            self.ejection_count = self.ejection_count.saturating_sub(1);
+        }
+
+        self.window_started = Instant::now();
+        self.window_requests = 0;
+        self.window_failures = 0;
+    }
+
+    fn observe_latency(&mut self, latency: Duration) {
+        const ALPHA: f64 = 0.2;
+        let sample = latency.as_secs_f64() * 1000.0;
+        if self.latency_samples == 0 {
+            self.latency_ewma_ms = sample;
+        } else {
+            self.latency_ewma_ms = ALPHA * sample + (1.0 - ALPHA) * self.latency_ewma_ms;
+        }
+        self.latency_samples = self.latency_samples.saturating_add(1);
+    }
+
+    fn is_ejected(&self) -> bool {
+        match self.state {
+            CircuitState::Closed => false,
+            CircuitState::Open => self
+                .ejected_until
+                .map(|until| Instant::now() < until)
+                .unwrap_or(false),
+            CircuitState::HalfOpen => self.probe_in_flight,
+        }
+    }
+}
+
+/// Ejects endpoints that fail or answer much slower than their peers.
+/// Endpoints are compared, and the ejection cap applied, within the
+/// clusters set by [`Self::set_clusters`]; endpoints outside every cluster
+/// are compared with each other.
+#[derive(Debug)]
+pub struct OutlierDetector {
+    config: OutlierDetectionConfig,
+    endpoints: DashMap<String, EndpointStats>,
+    clusters: DashMap<String, HashSet<String>>,
+}
+
+impl OutlierDetector {
+    pub fn new(config: OutlierDetectionConfig) -> Self {
+        Self {
+            config,
+            endpoints: DashMap::new(),
+            clusters: DashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &OutlierDetectionConfig {
+        &self.config
+    }
+
+    pub fn record_success(&self, endpoint: &str, latency: Duration) {
+        let latency_ewma = {
+            let mut stats = self
+                .endpoints
+                .entry(endpoint.to_string())
+                .or_insert_with(EndpointStats::new);
+
+            stats.roll_window(self.config.failure_rate_window);
+            stats.window_requests += 1;
+            stats.consecutive_failures = 0;
+            stats.observe_latency(latency);
+
+            if stats.state == CircuitState::HalfOpen {
+                stats.state = CircuitState::Closed;
+                stats.ejected_until = None;
+                stats.probe_in_flight = false;
+                info!(endpoint = %endpoint, "Endpoint recovered after probe");
+            }
+
+            if stats.state != CircuitState::Closed
+                || stats.latency_samples < self.config.latency_min_samples
+            {
+                return;
+            }
+
+            stats.latency_ewma_ms
+        };
+
+        if let Some(baseline) = self.latency_baseline(endpoint) {
+            if latency_ewma > baseline * self.config.latency_outlier_factor {
+                self.eject(endpoint, EjectionReason::LatencyOutlier);
+            }
+        }
+    }
+
+    pub fn record_failure(&self, endpoint: &str) {
+        let reason = {
+            let mut stats = self
+                .endpoints
+                .entry(endpoint.to_string())
+                .or_insert_with(EndpointStats::new);
+
+            stats.roll_window(self.config.failure_rate_window);
+            stats.window_requests += 1;
+            stats.window_failures += 1;
+            stats.consecutive_failures += 1;
+
+            match stats.state {
+                CircuitState::Open => None,
+                CircuitState::HalfOpen => Some(EjectionReason::ProbeFailed),
+                CircuitState::Closed => {
+                    let failure_rate =
+                        stats.window_failures as f64 / stats.window_requests as f64;
+                    if stats.consecutive_failures >= self.config.consecutive_failures {
+                        Some(EjectionReason::ConsecutiveFailures)
+                    } else if stats.window_requests >= self.config.failure_rate_min_requests
+                        && failure_rate >= self.config.failure_rate_threshold
+                    {
+                        Some(EjectionReason::FailureRate)
+                    } else {
+                        None
+                    }
+                }
+            }
+        };
+
+        if let Some(reason) = reason {
+            self.eject(endpoint, reason);
+        }
+    }
+
+    pub fn is_ejected(&self, endpoint: &str) -> bool {
+        self.endpoints
+            .get(endpoint)
+            .map(|stats| stats.is_ejected())
+            .unwrap_or(false)
+    }
+
+    pub fn acquire(&self, endpoint: &str) -> bool {
+        let Some(mut stats) = self.endpoints.get_mut(endpoint) else {
+            return true;
+        };
+
+        match stats.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                if stats.is_ejected() {
+                    return false;
+                }
+                stats.state = CircuitState::HalfOpen;
+                stats.probe_in_flight = true;
+                info!(endpoint = %endpoint, "Endpoint half-open, sending probe");
+                true
+            }
+            CircuitState::HalfOpen => {
+                if stats.probe_in_flight {
+                    return false;
+                }
+                stats.probe_in_flight = true;
+                true
+            }
+        }
+    }
+
+    /// Acquires the endpoint for one call. Dropping the admission without
+    /// recording an outcome releases a half-open probe slot it held.
+    pub fn admit(&self, endpoint: &str) -> Option<Admission<'_>> {
+        self.acquire(endpoint).then(|| Admission {
+            detector: self,
+            endpoint: endpoint.to_string(),
+            settled: false,
+        })
+    }
+
+    /// Gives back a half-open probe slot whose call never reported an
+    /// outcome, so the next call can probe instead.
+    pub fn release(&self, endpoint: &str) {
+        if let Some(mut stats) = self.endpoints.get_mut(endpoint) {
+            if stats.state == CircuitState::HalfOpen {
+                stats.probe_in_flight = false;
+            }
+        }
+    }
+
+    pub fn state(&self, endpoint: &str) -> CircuitState {
+        self.endpoints
+            .get(endpoint)
+            .map(|stats| stats.state)
+            .unwrap_or(CircuitState::Closed)
+    }
+
+    pub fn ejected_endpoints(&self) -> Vec<String> {
+        self.endpoints
+            .iter()
+            .filter(|entry| entry.is_ejected())
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    pub fn remove(&self, endpoint: &str) {
+        self.endpoints.remove(endpoint);
+    }
+
+    /// Replaces the clusters endpoints are compared within, keyed by
+    /// cluster. Endpoints dropped from every cluster are forgotten.
+    pub fn set_clusters(&self, clusters: HashMap<String, HashSet<String>>) {
+        let previous: HashSet<String> = self
+            .clusters
+            .iter()
+            .flat_map(|cluster| cluster.value().clone())
+            .collect();
+
+        self.clusters.clear();
+        for (cluster, endpoints) in clusters {
+            self.clusters.insert(cluster, endpoints);
+        }
+
+        for endpoint in previous {
+            if !self.is_clustered(&endpoint) {
+                self.remove(&endpoint);
+            }
+        }
+    }
+
+    fn is_clustered(&self, endpoint: &str) -> bool {
+        self.clusters.iter().any(|cluster| cluster.contains(endpoint))
+    }
+
+    /// The clusters holding `endpoint`, or the unclustered endpoints when
+    /// it is in none.
+    fn scopes(&self, endpoint: &str) -> Vec<HashSet<String>> {
+        let scopes: Vec<HashSet<String>> = self
+            .clusters
+            .iter()
+            .filter(|cluster| cluster.contains(endpoint))
+            .map(|cluster| cluster.value().clone())
+            .collect();
+        if !scopes.is_empty() {
+            return scopes;
+        }
+
+        let unclustered = self
+            .endpoints
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|e| !self.is_clustered(e))
+            .collect();
+        vec![unclustered]
+    }
+
+    fn latency_baseline(&self, endpoint: &str) -> Option<f64> {
+        let peers: HashSet<String> = self.scopes(endpoint).into_iter().flatten().collect();
+        let peers: Vec<f64> = self
+            .endpoints
+            .iter()
+            .filter(|entry| entry.key() != endpoint && peers.contains(entry.key()))
+            .filter(|entry| entry.state == CircuitState::Closed)
+            .filter(|entry| entry.latency_samples >= self.config.latency_min_samples)
+            .map(|entry| entry.latency_ewma_ms)
+            .collect();
+
+        if peers.is_empty() {
+            return None;
+        }
+
+        Some(peers.iter().sum::<f64>() / peers.len() as f64)
+    }
+
+    /// Whether ejecting `endpoint` keeps every scope it is in within the
+    /// max ejection percent.
+    fn can_eject(&self, endpoint: &str) -> bool {
+        self.scopes(endpoint).iter().all(|scope| {
+            let total = scope.len();
+            let ejected = scope
+                .iter()
+                .filter(|e| self.state(e) != CircuitState::Closed)
+                .count();
+
+            (ejected + 1) * 100 <= total * self.config.max_ejection_percent as usize
+        })
+    }
+
+    fn ejection_duration(&self, ejection_count: u32) -> Duration {
+        let multiplier = 1u32 << ejection_count.saturating_sub(1).min(16);
+        self.config
+            .base_ejection_time
+            .saturating_mul(multiplier)
+            .min(self.config.max_ejection_time)
+    }
+
+    fn eject(&self, endpoint: &str, reason: EjectionReason) {
+        if reason != EjectionReason::ProbeFailed && !self.can_eject(endpoint) {
+            warn!(
+                endpoint = %endpoint,
+                reason = ?reason,
+                "Max ejection percent reached, keeping outlier in rotation"
+            );
+            return;
+        }
+
+        if let Some(mut stats) = self.endpoints.get_mut(endpoint) {
+            stats.ejection_count += 1;
+            let duration = self.ejection_duration(stats.ejection_count);
+
+            stats.state = CircuitState::Open;
+            stats.ejected_until = Some(Instant::now() + duration);
+            stats.probe_in_flight = false;
+            stats.consecutive_failures = 0;
+            stats.window_started = Instant::now();
+            stats.window_requests = 0;
+            stats.window_failures = 0;
+
+            warn!(
+                endpoint = %endpoint,
+                reason = ?reason,
+                ejection_count = stats.ejection_count,
+                ejection_ms = duration.as_millis() as u64,
+                "Endpoint ejected"
+            );
+        }
+    }
+}
+
+/// A call admitted by [`OutlierDetector::admit`].
+pub struct Admission<'a> {
+    detector: &'a OutlierDetector,
+    endpoint: String,
+    settled: bool,
+}
+
+impl Admission<'_> {
+    pub fn success(mut self, latency: Duration) {
+        self.settled = true;
+        self.detector.record_success(&self.endpoint, latency);
+    }
+
+    pub fn failure(mut self) {
+        self.settled = true;
+        self.detector.record_failure(&self.endpoint);
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.detector.release(&self.endpoint);
+        }
+    }
+}
+
+impl Default for OutlierDetector {
+    fn default() -> Self {
+        Self::new(OutlierDetectionConfig::default())
+    }
+}
@@ -1,15 +1,12 @@
 use std::collections::HashMap;
-use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast, RwLock};
+use tokio::sync::broadcast;
 use tracing::{info, warn};
 
-use conveyor_etl_raft::{ConveyorRaft, RouterState};
-
 #[derive(Debug, Clone)]
 pub enum ServiceEvent {
     Registered {
@@ -101,10 +98,6 @@ impl RegisteredService {
 }
 
 pub struct ServiceRegistry {
-    #[allow(dead_code)]
-    raft: Arc<ConveyorRaft>,
-    #[allow(dead_code)]
-    state: Arc<RwLock<RouterState>>,
     services: DashMap<String, RegisteredService>,
     by_name: DashMap<String, Vec<String>>,
     by_group: DashMap<String, Vec<String>>,
@@ -113,11 +106,9 @@ pub struct ServiceRegistry {
 }
 
 impl ServiceRegistry {
-    pub fn new(raft: Arc<ConveyorRaft>, state: Arc<RwLock<RouterState>>) -> Self {
+    pub fn new() -> Self {
         let (event_tx, _) = broadcast::channel(256);
         Self {
-            raft,
-            state,
             services: DashMap::new(),
             by_name: DashMap::new(),
             by_group: DashMap::new(),
@@ -366,3 +357,9 @@ impl ServiceRegistry {
         expired
     }
 }
+
+impl Default for ServiceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,32 +1,13 @@
 #[cfg(test)]
 mod service_registry_tests {
     use std::collections::HashMap;
-    use std::sync::Arc;
     use std::time::Duration;
-    use tempfile::TempDir;
-    use tokio::sync::RwLock;
 
     use crate::{ServiceRegistry, ServiceType, ServiceHealth};
-    use conveyor_etl_raft::{RaftNode, RaftCore, RaftConfig, LogStorage};
-
-    async fn create_test_registry() -> (ServiceRegistry, TempDir) {
-        let tmp_dir = TempDir::new().unwrap();
-        let log = Arc::new(LogStorage::new(tmp_dir.path()).unwrap());
-        let config = RaftConfig {
-            election_timeout_min: Duration::from_millis(50),
-            election_timeout_max: Duration::from_millis(100),
-            heartbeat_interval: Duration::from_millis(25),
-            max_entries_per_append: 100,
-        };
-        let core = Arc::new(RaftCore::new(1, vec![], log, config).await.unwrap());
-        let raft_node = RaftNode::from_core(1, core);
-        let registry = ServiceRegistry::new(Arc::new(RwLock::new(raft_node)));
-        (registry, tmp_dir)
-    }
 
     #[tokio::test]
     async fn test_register_service() {
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         let result = registry.register(
             "source-1".to_string(),
@@ -51,7 +32,7 @@ mod service_registry_tests {
 
     #[tokio::test]
     async fn test_register_duplicate_service_fails() {
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         let result1 = registry.register(
             "source-1".to_string(),
@@ -81,7 +62,7 @@ mod service_registry_tests {
 
     #[tokio::test]
     async fn test_deregister_service() {
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         registry.register(
             "source-1".to_string(),
@@ -101,7 +82,7 @@ mod service_registry_tests {
 
     #[tokio::test]
     async fn test_heartbeat_updates_last_seen() {
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         registry.register(
             "source-1".to_string(),
@@ -128,7 +109,7 @@ mod service_registry_tests {
 
     #[tokio::test]
     async fn test_service_expires_without_heartbeat() {
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         registry.register(
             "source-1".to_string(),
@@ -145,7 +126,7 @@ mod service_registry_tests {
 
     #[tokio::test]
     async fn test_list_services_by_type() {
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         registry.register(
             "source-1".to_string(),
@@ -195,7 +176,7 @@ mod service_registry_tests {
 
     #[tokio::test]
     async fn test_list_services_by_labels() {
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         let mut labels1 = HashMap::new();
         labels1.insert("env".to_string(), "production".to_string());
@@ -252,7 +233,7 @@ mod service_registry_tests {
     async fn test_watch_services_receives_events() {
         use crate::ServiceEvent;
 
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         let mut receiver = registry.subscribe();
 
@@ -318,7 +299,7 @@ mod service_registry_tests {
 
     #[tokio::test]
     async fn test_service_health_transitions() {
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         registry.register(
             "source-1".to_string(),
@@ -343,7 +324,7 @@ mod service_registry_tests {
 
     #[tokio::test]
     async fn test_service_metadata_update() {
-        let (registry, _tmp) = create_test_registry().await;
+        let registry = ServiceRegistry::new();
 
         let mut initial_labels = HashMap::new();
         initial_labels.insert("env".to_string(), "staging".to_string());
@@ -382,328 +363,7 @@ mod service_registry_tests {
         assert_eq!(removed, Some("us-east".to_string()));
 
         let service = registry.get_service("source-1").await.unwrap();
-        assert!(service.labels.get("region").is_none());
-    }
-}
-
-#[cfg(test)]
-mod group_coordinator_tests {
-    use crate::GroupCoordinator;
-
-    #[tokio::test]
-    async fn test_create_consumer_group() {
-        let coordinator = GroupCoordinator::new();
-
-        let result = coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            4,
-        ).await;
-
-        assert!(result.is_ok());
-
-        let group = coordinator.get_group("group-1").await;
-        assert!(group.is_some());
-        let g = group.unwrap();
-        assert_eq!(g.group_id, "group-1");
-        assert_eq!(g.stage_id, "stage-1");
-        assert_eq!(g.total_partitions, 4);
-        assert_eq!(g.generation, 0);
-    }
-
-    #[tokio::test]
-    async fn test_join_group_single_member() {
-        let coordinator = GroupCoordinator::new();
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            4,
-        ).await.unwrap();
-
-        let events = coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
-
-        assert!(!events.is_empty());
-
-        let assignment = coordinator.get_assignment("group-1", "service-a").await;
-        assert!(assignment.is_some());
-        let partitions = assignment.unwrap();
-        assert_eq!(partitions.len(), 4);
-        assert!(partitions.contains(&0));
-        assert!(partitions.contains(&1));
-        assert!(partitions.contains(&2));
-        assert!(partitions.contains(&3));
-    }
-
-    #[tokio::test]
-    async fn test_join_group_triggers_rebalance() {
-        let coordinator = GroupCoordinator::new();
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            4,
-        ).await.unwrap();
-
-        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
-
-        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
-        assert_eq!(assignment_a.len(), 4);
-
-        let events = coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
-        assert!(!events.is_empty());
-
-        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
-        let assignment_b = coordinator.get_assignment("group-1", "service-b").await.unwrap();
-
-        assert_eq!(assignment_a.len(), 2);
-        assert_eq!(assignment_b.len(), 2);
-
-        let mut all_partitions: Vec<u32> = assignment_a.iter().chain(assignment_b.iter()).copied().collect();
-        all_partitions.sort();
-        assert_eq!(all_partitions, vec![0, 1, 2, 3]);
-    }
-
-    #[tokio::test]
-    async fn test_leave_group_triggers_rebalance() {
-        let coordinator = GroupCoordinator::new();
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            4,
-        ).await.unwrap();
-
-        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
-        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
-
-        let events = coordinator.leave_group("group-1", "service-b").await.unwrap();
-        assert!(!events.is_empty());
-
-        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
-        assert_eq!(assignment_a.len(), 4);
-
-        let assignment_b = coordinator.get_assignment("group-1", "service-b").await;
-        assert!(assignment_b.is_none());
-    }
-
-    #[tokio::test]
-    async fn test_member_timeout_triggers_rebalance() {
-        use std::time::Duration;
-
-        let coordinator = GroupCoordinator::new();
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            4,
-        ).await.unwrap();
-
-        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
-        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
-
-        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
-        let assignment_b = coordinator.get_assignment("group-1", "service-b").await.unwrap();
-        assert_eq!(assignment_a.len(), 2);
-        assert_eq!(assignment_b.len(), 2);
-
-        tokio::time::sleep(Duration::from_millis(50)).await;
-
-        coordinator.heartbeat("group-1", "service-a").await.unwrap();
-
-        let events = coordinator.check_member_timeouts("group-1", Duration::from_millis(25)).await.unwrap();
-        assert!(!events.is_empty());
-
-        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
-        assert_eq!(assignment_a.len(), 4);
-
-        let assignment_b = coordinator.get_assignment("group-1", "service-b").await;
-        assert!(assignment_b.is_none());
-
-        let stale_members = coordinator.get_members_needing_heartbeat("group-1", Duration::from_secs(60));
-        assert!(stale_members.is_empty());
-    }
-
-    #[tokio::test]
-    #[ignore = "Sticky assignment not yet implemented"]
-    async fn test_sticky_partition_assignment() {
-        todo!("Implement sticky assignment")
-    }
-
-    #[tokio::test]
-    async fn test_range_assignment_strategy() {
-        let coordinator = GroupCoordinator::new();
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            10,
-        ).await.unwrap();
-
-        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
-        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
-        coordinator.join_group("group-1", "service-c".to_string()).await.unwrap();
-
-        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
-        let assignment_b = coordinator.get_assignment("group-1", "service-b").await.unwrap();
-        let assignment_c = coordinator.get_assignment("group-1", "service-c").await.unwrap();
-
-        let mut all: Vec<u32> = assignment_a.iter()
-            .chain(assignment_b.iter())
-            .chain(assignment_c.iter())
-            .copied()
-            .collect();
-        all.sort();
-        assert_eq!(all, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
-
-        assert!(assignment_a.len() >= 3);
-        assert!(assignment_b.len() >= 3);
-        assert!(assignment_c.len() >= 3);
-    }
-
-    #[tokio::test]
-    async fn test_round_robin_assignment_strategy() {
-        let coordinator = GroupCoordinator::new();
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            9,
-        ).await.unwrap();
-
-        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
-        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
-        coordinator.join_group("group-1", "service-c".to_string()).await.unwrap();
-
-        let assignment_a = coordinator.get_assignment("group-1", "service-a").await.unwrap();
-        let assignment_b = coordinator.get_assignment("group-1", "service-b").await.unwrap();
-        let assignment_c = coordinator.get_assignment("group-1", "service-c").await.unwrap();
-
-        assert_eq!(assignment_a.len(), 3);
-        assert_eq!(assignment_b.len(), 3);
-        assert_eq!(assignment_c.len(), 3);
-    }
-
-    #[tokio::test]
-    async fn test_rebalance_callback_invoked() {
-        use crate::group_coordinator::RebalanceEvent;
-
-        let coordinator = GroupCoordinator::new();
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            4,
-        ).await.unwrap();
-
-        let events = coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
-
-        let has_assigned = events.iter().any(|e| matches!(e, RebalanceEvent::PartitionsAssigned { .. }));
-        assert!(has_assigned);
-    }
-
-    #[tokio::test]
-    async fn test_group_generation_increments() {
-        let coordinator = GroupCoordinator::new();
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            4,
-        ).await.unwrap();
-
-        let group = coordinator.get_group("group-1").await.unwrap();
-        assert_eq!(group.generation, 0);
-
-        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
-        let group = coordinator.get_group("group-1").await.unwrap();
-        assert_eq!(group.generation, 1);
-
-        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
-        let group = coordinator.get_group("group-1").await.unwrap();
-        assert_eq!(group.generation, 2);
-    }
-
-    #[tokio::test]
-    async fn test_stale_generation_rejected() {
-        let coordinator = GroupCoordinator::new();
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            4,
-        ).await.unwrap();
-
-        coordinator.join_group("group-1", "service-a".to_string()).await.unwrap();
-        let gen1 = coordinator.get_current_generation("group-1").await.unwrap();
-        assert_eq!(gen1, 1);
-
-        coordinator.join_group("group-1", "service-b".to_string()).await.unwrap();
-        let gen2 = coordinator.get_current_generation("group-1").await.unwrap();
-        assert_eq!(gen2, 2);
-
-        let stale_heartbeat = coordinator.heartbeat_with_generation("group-1", "service-a", gen1).await;
-        assert!(stale_heartbeat.is_err());
-        assert!(stale_heartbeat.unwrap_err().to_string().contains("Stale generation"));
-
-        let valid_heartbeat = coordinator.heartbeat_with_generation("group-1", "service-a", gen2).await;
-        assert!(valid_heartbeat.is_ok());
-
-        let is_valid = coordinator.validate_generation("group-1", gen2).await.unwrap();
-        assert!(is_valid);
-
-        let is_stale = coordinator.validate_generation("group-1", gen1).await.unwrap();
-        assert!(!is_stale);
-
-        let stale_leave = coordinator.leave_group_with_generation("group-1", "service-a", gen1).await;
-        assert!(stale_leave.is_err());
-
-        let valid_leave = coordinator.leave_group_with_generation("group-1", "service-b", gen2).await;
-        assert!(valid_leave.is_ok());
-    }
-
-    #[tokio::test]
-    async fn test_concurrent_joins_handled() {
-        let coordinator = std::sync::Arc::new(GroupCoordinator::new());
-
-        coordinator.create_group(
-            "group-1".to_string(),
-            "stage-1".to_string(),
-            12,
-        ).await.unwrap();
-
-        let mut handles = vec![];
-        for i in 0..4 {
-            let coord = coordinator.clone();
-            let handle = tokio::spawn(async move {
-                coord.join_group("group-1", format!("service-{}", i)).await
-            });
-            handles.push(handle);
-        }
-
-        for handle in handles {
-            let result = handle.await.unwrap();
-            assert!(result.is_ok());
-        }
-
-        let group = coordinator.get_group("group-1").await.unwrap();
-        assert_eq!(group.members.len(), 4);
-
-        let mut all_partitions = std::collections::HashSet::new();
-        for i in 0..4 {
-            let assignment = coordinator.get_assignment("group-1", &format!("service-{}", i)).await.unwrap();
-            for p in assignment {
-                assert!(!all_partitions.contains(&p), "Partition {} assigned multiple times", p);
-                all_partitions.insert(p);
-            }
-        }
-        assert_eq!(all_partitions.len(), 12);
-    }
-
-    #[tokio::test]
-    #[ignore = "Raft persistence requires full cluster setup"]
-    async fn test_group_coordinator_persisted_in_raft() {
-        todo!("Implement Raft persistence for groups")
+        assert!(!service.labels.contains_key("region"));
     }
 }
 
@@ -711,7 +371,10 @@ mod group_coordinator_tests {
 mod load_balancer_tests {
     use std::collections::HashMap;
 
-    use crate::{LoadBalancer, RegisteredService, ServiceType, ServiceHealth};
+    use crate::{
+        LoadBalancer, Locality, RegisteredService, ServiceType, ServiceHealth, TrafficSplit,
+        VersionWeight, VERSION_LABEL, ZONE_TOPOLOGY_KEY,
+    };
     use crate::load_balancer::LoadBalanceStrategy;
 
     fn create_test_services(count: usize) -> Vec<RegisteredService> {
@@ -737,7 +400,7 @@ mod load_balancer_tests {
         let mut selections = HashMap::new();
         for _ in 0..6 {
             let selected = lb.select(&services, LoadBalanceStrategy::RoundRobin, None).await.unwrap();
-            *selections.entry(selected.service_id).or_insert(0) += 1;
+            *selections.entry(selected.into_service().service_id).or_insert(0) += 1;
         }
 
         assert_eq!(selections.get("service-0"), Some(&2));
@@ -813,6 +476,55 @@ mod load_balancer_tests {
         assert!(different_key.service_id.starts_with("service-"));
     }
 
+    #[tokio::test]
+    async fn test_peak_ewma_prefers_fast_service() {
+        let lb = LoadBalancer::new();
+        let services = create_test_services(2);
+
+        lb.record_latency("service-0", std::time::Duration::from_millis(5));
+        lb.record_latency("service-1", std::time::Duration::from_millis(200));
+
+        for _ in 0..20 {
+            let selected = lb.select(&services, LoadBalanceStrategy::PeakEwma, None).await.unwrap();
+            assert_eq!(selected.service_id, "service-0");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peak_ewma_accounts_for_in_flight_and_queue_depth() {
+        let lb = LoadBalancer::new();
+        let services = create_test_services(2);
+
+        lb.record_latency("service-0", std::time::Duration::from_millis(10));
+        lb.record_latency("service-1", std::time::Duration::from_millis(10));
+
+        for _ in 0..5 {
+            lb.start_request("service-0");
+        }
+        let selected = lb.select(&services, LoadBalanceStrategy::PeakEwma, None).await.unwrap();
+        assert_eq!(selected.service_id, "service-1");
+
+        lb.update_load("service-1", 10.0, 20);
+        let selected = lb.select(&services, LoadBalanceStrategy::PeakEwma, None).await.unwrap();
+        assert_eq!(selected.service_id, "service-0");
+    }
+
+    #[tokio::test]
+    async fn test_peak_ewma_reacts_to_latency_spike() {
+        let lb = LoadBalancer::new();
+        let services = create_test_services(2);
+
+        lb.record_latency("service-0", std::time::Duration::from_millis(10));
+        lb.record_latency("service-1", std::time::Duration::from_millis(20));
+
+        let started = lb.start_request("service-0");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        lb.finish_request("service-0", started);
+
+        let selected = lb.select(&services, LoadBalanceStrategy::PeakEwma, None).await.unwrap();
+        assert_eq!(selected.service_id, "service-1");
+    }
+
     #[tokio::test]
     #[ignore = "Minimal redistribution test requires hash ring implementation"]
     async fn test_consistent_hash_minimal_redistribution() {
@@ -854,4 +566,479 @@ mod load_balancer_tests {
         let result = lb.select(&healthy_services, LoadBalanceStrategy::RoundRobin, None).await;
         assert!(result.is_none());
     }
+
+    fn with_zones(mut services: Vec<RegisteredService>, zones: &[&str]) -> Vec<RegisteredService> {
+        for (service, zone) in services.iter_mut().zip(zones) {
+            service.labels.insert(ZONE_TOPOLOGY_KEY.to_string(), zone.to_string());
+        }
+        services
+    }
+
+    #[tokio::test]
+    async fn test_locality_prefers_same_zone() {
+        let lb = LoadBalancer::new();
+        let services = with_zones(create_test_services(4), &["zone-a", "zone-b", "zone-a", "zone-b"]);
+        let locality = Locality::new(ZONE_TOPOLOGY_KEY, "zone-b");
+
+        for _ in 0..10 {
+            let selected = lb.select_with_locality(&services, LoadBalanceStrategy::RoundRobin, None, Some(&locality)).await.unwrap();
+            assert!(selected.service_id == "service-1" || selected.service_id == "service-3");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_locality_falls_back_when_zone_unhealthy() {
+        let lb = LoadBalancer::new();
+        let mut services = with_zones(create_test_services(3), &["zone-a", "zone-b", "zone-b"]);
+        services[0].health = ServiceHealth::Unhealthy;
+        let locality = Locality::new(ZONE_TOPOLOGY_KEY, "zone-a");
+
+        for _ in 0..10 {
+            let selected = lb.select_with_locality(&services, LoadBalanceStrategy::RoundRobin, None, Some(&locality)).await.unwrap();
+            assert_ne!(selected.service_id, "service-0");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spread_services_across_zones() {
+        let services = with_zones(create_test_services(4), &["zone-a", "zone-a", "zone-a", "zone-b"]);
+
+        let placed = crate::spread_services(&services, ZONE_TOPOLOGY_KEY, 2);
+        let zones: Vec<_> = placed.iter()
+            .map(|s| s.labels.get(ZONE_TOPOLOGY_KEY).unwrap().as_str())
+            .collect();
+        assert_eq!(zones, vec!["zone-a", "zone-b"]);
+    }
+
+    #[test]
+    fn test_order_by_locality_lists_same_zone_first() {
+        let services = with_zones(create_test_services(5), &["zone-a", "zone-a", "zone-b", "zone-c", "zone-c"]);
+
+        let ordered = crate::order_by_locality(&services, ZONE_TOPOLOGY_KEY, Some("zone-c"));
+        let ids: Vec<_> = ordered.iter().map(|s| s.service_id.as_str()).collect();
+        assert_eq!(ids, vec!["service-3", "service-4", "service-0", "service-2", "service-1"]);
+
+        let spread = crate::order_by_locality(&services, ZONE_TOPOLOGY_KEY, None);
+        let zones: Vec<_> = spread.iter()
+            .take(3)
+            .map(|s| s.labels.get(ZONE_TOPOLOGY_KEY).unwrap().as_str())
+            .collect();
+        assert_eq!(zones, vec!["zone-a", "zone-b", "zone-c"]);
+    }
+
+    fn with_versions(mut services: Vec<RegisteredService>, versions: &[&str]) -> Vec<RegisteredService> {
+        for (service, version) in services.iter_mut().zip(versions) {
+            service.labels.insert(VERSION_LABEL.to_string(), version.to_string());
+        }
+        services
+    }
+
+    fn canary_split(sticky: bool) -> TrafficSplit {
+        TrafficSplit::new(
+            vec![
+                VersionWeight { version: "v1".to_string(), weight: 90 },
+                VersionWeight { version: "v2".to_string(), weight: 10 },
+            ],
+            sticky,
+        )
+    }
+
+    #[test]
+    fn test_traffic_split_weights() {
+        let split = canary_split(false);
+
+        let mut counts = HashMap::new();
+        for _ in 0..2000 {
+            let version = split.choose_version(None).unwrap();
+            *counts.entry(version.to_string()).or_insert(0) += 1;
+        }
+
+        let canary = *counts.get("v2").unwrap_or(&0);
+        assert!(canary > 100 && canary < 300, "canary got {} of 2000", canary);
+    }
+
+    #[test]
+    fn test_traffic_split_sticky_by_key() {
+        let split = canary_split(true);
+
+        for i in 0..50 {
+            let key = format!("customer-{}", i);
+            let first = split.choose_version(Some(&key)).unwrap();
+            for _ in 0..5 {
+                assert_eq!(split.choose_version(Some(&key)), Some(first));
+            }
+        }
+    }
+
+    #[test]
+    fn test_traffic_split_validation() {
+        assert!(canary_split(false).validate().is_ok());
+        assert!(TrafficSplit::default().validate().is_err());
+
+        let zero = TrafficSplit::new(vec![VersionWeight { version: "v1".to_string(), weight: 0 }], false);
+        assert!(zero.validate().is_err());
+
+        let mut duplicate = canary_split(false);
+        duplicate.versions[1].version = "v1".to_string();
+        assert!(duplicate.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_select_with_split_routes_to_version() {
+        let lb = LoadBalancer::new();
+        let services = with_versions(create_test_services(3), &["v1", "v1", "v2"]);
+        let split = TrafficSplit::new(
+            vec![VersionWeight { version: "v2".to_string(), weight: 1 }],
+            false,
+        );
+
+        for _ in 0..10 {
+            let selected = lb.select_with_split(&services, LoadBalanceStrategy::RoundRobin, None, Some(&split)).await.unwrap();
+            assert_eq!(selected.version(), Some("v2"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select_with_split_falls_back_without_instances() {
+        let lb = LoadBalancer::new();
+        let services = with_versions(create_test_services(2), &["v1", "v1"]);
+        let split = TrafficSplit::new(
+            vec![VersionWeight { version: "v3".to_string(), weight: 1 }],
+            false,
+        );
+
+        let selected = lb.select_with_split(&services, LoadBalanceStrategy::RoundRobin, None, Some(&split)).await;
+        assert_eq!(selected.unwrap().version(), Some("v1"));
+    }
+
+    #[tokio::test]
+    async fn test_select_with_split_prefers_same_zone_within_version() {
+        let lb = LoadBalancer::new();
+        let services = with_zones(
+            with_versions(create_test_services(4), &["v1", "v2", "v2", "v1"]),
+            &["zone-a", "zone-a", "zone-b", "zone-b"],
+        );
+        let split = TrafficSplit::new(
+            vec![VersionWeight { version: "v2".to_string(), weight: 1 }],
+            false,
+        );
+        let locality = Locality::new(ZONE_TOPOLOGY_KEY, "zone-b");
+
+        for _ in 0..10 {
+            let selected = lb.select_with_split_and_locality(
+                &services, LoadBalanceStrategy::RoundRobin, None, Some(&split), Some(&locality),
+            ).await.unwrap();
+            assert_eq!(selected.service_id, "service-2");
+        }
+    }
+
+    #[test]
+    fn test_version_stats() {
+        let lb = LoadBalancer::new();
+        let latency = std::time::Duration::from_millis(10);
+
+        for i in 0..10 {
+            lb.record_version_outcome("test-service", "v1", true, latency);
+            lb.record_version_outcome("test-service", "v2", i % 2 == 0, latency);
+        }
+        lb.record_version_outcome("other-service", "v1", false, latency);
+
+        let stats = lb.version_stats("test-service");
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].0, "v1");
+        assert_eq!(stats[0].1.requests, 10);
+        assert_eq!(stats[0].1.error_rate(), 0.0);
+        assert_eq!(stats[1].0, "v2");
+        assert_eq!(stats[1].1.error_rate(), 0.5);
+        assert!((stats[1].1.avg_latency_ms() - 10.0).abs() < 1.0);
+    }
+}
+
+#[cfg(test)]
+mod outlier_detector_tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::{
+        CircuitState, LoadBalancer, OutlierDetectionConfig, OutlierDetector, RegisteredService,
+        ServiceHealth, ServiceType,
+    };
+    use crate::load_balancer::LoadBalanceStrategy;
+
+    fn test_config() -> OutlierDetectionConfig {
+        OutlierDetectionConfig {
+            consecutive_failures: 3,
+            failure_rate_threshold: 0.5,
+            failure_rate_window: Duration::from_secs(60),
+            failure_rate_min_requests: 10,
+            latency_outlier_factor: 3.0,
+            latency_min_samples: 5,
+            base_ejection_time: Duration::from_millis(50),
+            max_ejection_time: Duration::from_millis(500),
+            max_ejection_percent: 50,
+        }
+    }
+
+    fn detector_with_peers(peers: &[&str]) -> OutlierDetector {
+        let detector = OutlierDetector::new(test_config());
+        for peer in peers {
+            detector.record_success(peer, Duration::from_millis(10));
+        }
+        detector
+    }
+
+    #[test]
+    fn test_consecutive_failures_eject() {
+        let detector = detector_with_peers(&["a:1", "b:1", "c:1"]);
+
+        detector.record_failure("a:1");
+        detector.record_failure("a:1");
+        assert!(!detector.is_ejected("a:1"));
+
+        detector.record_failure("a:1");
+        assert!(detector.is_ejected("a:1"));
+        assert_eq!(detector.state("a:1"), CircuitState::Open);
+        assert!(!detector.acquire("a:1"));
+    }
+
+    #[test]
+    fn test_failure_rate_ejects() {
+        let detector = detector_with_peers(&["b:1", "c:1", "d:1"]);
+
+        for _ in 0..5 {
+            assert!(!detector.is_ejected("a:1"));
+            detector.record_success("a:1", Duration::from_millis(10));
+            detector.record_failure("a:1");
+        }
+
+        assert!(detector.is_ejected("a:1"));
+    }
+
+    #[test]
+    fn test_latency_outlier_ejects() {
+        let detector = OutlierDetector::new(test_config());
+
+        for _ in 0..5 {
+            detector.record_success("a:1", Duration::from_millis(10));
+            detector.record_success("b:1", Duration::from_millis(12));
+        }
+        for _ in 0..5 {
+            detector.record_success("c:1", Duration::from_millis(200));
+        }
+
+        assert!(detector.is_ejected("c:1"));
+        assert!(!detector.is_ejected("a:1"));
+    }
+
+    #[test]
+    fn test_half_open_probe_recovers() {
+        let detector = detector_with_peers(&["a:1", "b:1", "c:1"]);
+
+        for _ in 0..3 {
+            detector.record_failure("a:1");
+        }
+        assert!(detector.is_ejected("a:1"));
+
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert!(detector.acquire("a:1"));
+        assert_eq!(detector.state("a:1"), CircuitState::HalfOpen);
+        assert!(!detector.acquire("a:1"));
+
+        detector.record_success("a:1", Duration::from_millis(10));
+        assert_eq!(detector.state("a:1"), CircuitState::Closed);
+        assert!(detector.acquire("a:1"));
+    }
+
+    #[test]
+    fn test_dropped_admission_releases_probe() {
+        let detector = detector_with_peers(&["a:1", "b:1", "c:1"]);
+
+        for _ in 0..3 {
+            detector.record_failure("a:1");
+        }
+        std::thread::sleep(Duration::from_millis(60));
+
+        let probe = detector.admit("a:1").unwrap();
+        assert!(detector.admit("a:1").is_none());
+        drop(probe);
+
+        assert_eq!(detector.state("a:1"), CircuitState::HalfOpen);
+        let probe = detector.admit("a:1").unwrap();
+        probe.success(Duration::from_millis(10));
+        assert_eq!(detector.state("a:1"), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_failed_probe_backs_off_exponentially() {
+        let detector = detector_with_peers(&["a:1", "b:1", "c:1"]);
+
+        for _ in 0..3 {
+            detector.record_failure("a:1");
+        }
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(detector.acquire("a:1"));
+
+        detector.record_failure("a:1");
+        assert_eq!(detector.state("a:1"), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(detector.is_ejected("a:1"), "second ejection should last twice as long");
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!detector.is_ejected("a:1"));
+    }
+
+    #[test]
+    fn test_max_ejection_percent() {
+        let detector = detector_with_peers(&["a:1", "b:1", "c:1", "d:1"]);
+
+        for endpoint in ["a:1", "b:1", "c:1"] {
+            for _ in 0..3 {
+                detector.record_failure(endpoint);
+            }
+        }
+
+        assert_eq!(detector.ejected_endpoints().len(), 2);
+        assert!(!detector.is_ejected("c:1"));
+    }
+
+    fn clusters(clusters: &[(&str, &[&str])]) -> HashMap<String, HashSet<String>> {
+        clusters
+            .iter()
+            .map(|(cluster, endpoints)| {
+                (cluster.to_string(), endpoints.iter().map(|e| e.to_string()).collect())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_latency_compared_within_cluster() {
+        let detector = OutlierDetector::new(test_config());
+        detector.set_clusters(clusters(&[("fast", &["a:1", "b:1"]), ("slow", &["c:1", "d:1"])]));
+
+        for _ in 0..5 {
+            detector.record_success("a:1", Duration::from_millis(10));
+            detector.record_success("b:1", Duration::from_millis(12));
+        }
+        for _ in 0..5 {
+            detector.record_success("c:1", Duration::from_millis(200));
+            detector.record_success("d:1", Duration::from_millis(210));
+        }
+
+        assert!(detector.ejected_endpoints().is_empty());
+    }
+
+    #[test]
+    fn test_max_ejection_percent_per_cluster() {
+        let detector = detector_with_peers(&["a:1", "b:1", "c:1", "d:1"]);
+        detector.set_clusters(clusters(&[("one", &["a:1", "b:1"]), ("two", &["c:1", "d:1"])]));
+
+        for endpoint in ["a:1", "b:1", "c:1"] {
+            for _ in 0..3 {
+                detector.record_failure(endpoint);
+            }
+        }
+
+        assert!(detector.is_ejected("a:1"));
+        assert!(!detector.is_ejected("b:1"));
+        assert!(detector.is_ejected("c:1"));
+    }
+
+    #[test]
+    fn test_endpoints_dropped_from_clusters_are_forgotten() {
+        let detector = detector_with_peers(&["a:1", "b:1", "c:1"]);
+        detector.set_clusters(clusters(&[("stage", &["a:1", "b:1", "c:1"])]));
+
+        for _ in 0..3 {
+            detector.record_failure("a:1");
+        }
+        assert!(detector.is_ejected("a:1"));
+
+        detector.set_clusters(clusters(&[("stage", &["b:1", "c:1"])]));
+        assert!(!detector.is_ejected("a:1"));
+        assert_eq!(detector.state("a:1"), CircuitState::Closed);
+    }
+
+    fn create_test_services(count: usize) -> Vec<RegisteredService> {
+        (0..count).map(|i| RegisteredService {
+            service_id: format!("service-{}", i),
+            service_name: "test-service".to_string(),
+            service_type: ServiceType::Transform,
+            endpoint: format!("localhost:{}", 8080 + i),
+            labels: HashMap::new(),
+            health: ServiceHealth::Healthy,
+            group_id: None,
+            registered_at: None,
+            last_heartbeat: None,
+            lease_duration: Duration::from_secs(30),
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn test_load_balancer_skips_ejected_endpoints() {
+        let detector = Arc::new(detector_with_peers(&["localhost:8080", "localhost:8081", "localhost:8082"]));
+        let lb = LoadBalancer::with_outlier_detector(detector.clone());
+        let services = create_test_services(3);
+
+        for _ in 0..3 {
+            detector.record_failure("localhost:8081");
+        }
+
+        for _ in 0..10 {
+            let selected = lb.select(&services, LoadBalanceStrategy::RoundRobin, None).await.unwrap();
+            assert_ne!(selected.endpoint, "localhost:8081");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_balancer_sends_one_probe() {
+        let detector = Arc::new(detector_with_peers(&["localhost:8080", "localhost:8081", "localhost:8082"]));
+        let lb = LoadBalancer::with_outlier_detector(detector.clone());
+        let services = create_test_services(3);
+
+        for _ in 0..3 {
+            detector.record_failure("localhost:8081");
+        }
+        std::thread::sleep(Duration::from_millis(60));
+
+        let mut selections = Vec::new();
+        for _ in 0..9 {
+            selections.push(lb.select(&services, LoadBalanceStrategy::RoundRobin, None).await.unwrap());
+        }
+        let probes = selections.iter().filter(|s| s.endpoint == "localhost:8081").count();
+        assert_eq!(probes, 1);
+        assert_eq!(detector.state("localhost:8081"), CircuitState::HalfOpen);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_selection_releases_probe() {
+        let detector = Arc::new(detector_with_peers(&["localhost:8080", "localhost:8081", "localhost:8082"]));
+        let lb = LoadBalancer::with_outlier_detector(detector.clone());
+        let services = create_test_services(3);
+
+        for _ in 0..3 {
+            detector.record_failure("localhost:8081");
+        }
+        std::thread::sleep(Duration::from_millis(60));
+
+        let probe = loop {
+            let selected = lb.select(&services, LoadBalanceStrategy::RoundRobin, None).await.unwrap();
+            if selected.endpoint == "localhost:8081" {
+                break selected;
+            }
+        };
+        assert!(!detector.acquire("localhost:8081"));
+        drop(probe);
+
+        let probe = loop {
+            let selected = lb.select(&services, LoadBalanceStrategy::RoundRobin, None).await.unwrap();
+            if selected.endpoint == "localhost:8081" {
+                break selected;
+            }
+        };
+        probe.success(Duration::from_millis(10));
+        assert_eq!(detector.state("localhost:8081"), CircuitState::Closed);
+    }
 }
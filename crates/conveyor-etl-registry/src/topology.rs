@@ -0,0 +1,111 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use super::service_registry::{RegisteredService, ServiceHealth};
+
+pub const ZONE_TOPOLOGY_KEY: &str = "topology.kubernetes.io/zone";
+pub const REGION_TOPOLOGY_KEY: &str = "topology.kubernetes.io/region";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locality {
+    pub topology_key: String,
+    pub zone: String,
+}
+
+impl Locality {
+    pub fn new(topology_key: impl Into<String>, zone: impl Into<String>) -> Self {
+        Self {
+            topology_key: topology_key.into(),
+            zone: zone.into(),
+        }
+    }
+
+    pub fn from_labels(topology_key: &str, labels: &HashMap<String, String>) -> Option<Self> {
+        labels
+            .get(topology_key)
+            .map(|zone| Self::new(topology_key, zone.clone()))
+    }
+
+    pub fn matches(&self, service: &RegisteredService) -> bool {
+        service.labels.get(&self.topology_key) == Some(&self.zone)
+    }
+}
+
+pub fn zone_of(service: &RegisteredService, topology_key: &str) -> Option<String> {
+    service.labels.get(topology_key).cloned()
+}
+
+pub fn prefer_local(
+    services: &[RegisteredService],
+    locality: &Locality,
+) -> Vec<RegisteredService> {
+    let healthy: Vec<RegisteredService> = services
+        .iter()
+        .filter(|s| s.health == ServiceHealth::Healthy)
+        .cloned()
+        .collect();
+
+    let local: Vec<RegisteredService> = healthy
+        .iter()
+        .filter(|s| locality.matches(s))
+        .cloned()
+        .collect();
+
+    if !local.is_empty() {
+        return local;
+    }
+
+    if !healthy.is_empty() {
+        return healthy;
+    }
+
+    services.to_vec()
+}
+
+pub fn spread_by_topology<T, F>(items: Vec<T>, zone_of: F) -> Vec<T>
+where
+    F: Fn(&T) -> Option<String>,
+{
+    let total = items.len();
+    let mut by_zone: BTreeMap<String, VecDeque<T>> = BTreeMap::new();
+    for item in items {
+        let zone = zone_of(&item).unwrap_or_default();
+        by_zone.entry(zone).or_default().push_back(item);
+    }
+
+    let mut spread = Vec::with_capacity(total);
+    while spread.len() < total {
+        for queue in by_zone.values_mut() {
+            if let Some(item) = queue.pop_front() {
+                spread.push(item);
+            }
+        }
+    }
+
+    spread
+}
+
+pub fn spread_services(
+    services: &[RegisteredService],
+    topology_key: &str,
+    replicas: usize,
+) -> Vec<RegisteredService> {
+    let mut sorted = services.to_vec();
+    sorted.sort_by(|a, b| a.service_id.cmp(&b.service_id));
+
+    spread_by_topology(sorted, |s| zone_of(s, topology_key))
+        .into_iter()
+        .take(replicas)
+        .collect()
+}
+
+pub fn order_by_locality(
+    services: &[RegisteredService],
+    topology_key: &str,
+    zone: Option<&str>,
+) -> Vec<RegisteredService> {
+    let mut ordered = spread_services(services, topology_key, services.len());
+    if let Some(zone) = zone {
+        ordered.sort_by_key(|s| zone_of(s, topology_key).as_deref() != Some(zone));
+    }
+    ordered
+}
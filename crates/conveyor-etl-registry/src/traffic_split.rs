@@ -0,0 +1,127 @@
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use anyhow::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::service_registry::RegisteredService;
+
+pub const VERSION_LABEL: &str = "conveyor.io/version";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionWeight {
+    pub version: String,
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficSplit {
+    pub versions: Vec<VersionWeight>,
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+impl TrafficSplit {
+    pub fn new(versions: Vec<VersionWeight>, sticky: bool) -> Self {
+        Self { versions, sticky }
+    }
+
+    pub fn total_weight(&self) -> u32 {
+        self.versions.iter().map(|v| v.weight).sum()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.versions.is_empty() {
+            return Err(anyhow::anyhow!("Traffic split must list at least one version"));
+        }
+
+        let mut seen = HashSet::new();
+        for v in &self.versions {
+            if v.version.is_empty() {
+                return Err(anyhow::anyhow!("Traffic split version must not be empty"));
+            }
+            if !seen.insert(v.version.as_str()) {
+                return Err(anyhow::anyhow!("Duplicate version in traffic split: {}", v.version));
+            }
+        }
+
+        if self.total_weight() == 0 {
+            return Err(anyhow::anyhow!("Traffic split weights must not all be zero"));
+        }
+
+        Ok(())
+    }
+
+    pub fn choose_version(&self, routing_key: Option<&str>) -> Option<&str> {
+        let total = self.total_weight();
+        if total == 0 {
+            return None;
+        }
+
+        let point = match routing_key {
+            Some(key) if self.sticky => (hash_key(key) % total as u64) as u32,
+            _ => rand::thread_rng().gen_range(0..total),
+        };
+
+        let mut cumulative = 0u32;
+        for v in &self.versions {
+            cumulative += v.weight;
+            if point < cumulative {
+                return Some(v.version.as_str());
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VersionStats {
+    pub requests: u64,
+    pub errors: u64,
+    pub total_latency_ms: f64,
+}
+
+impl VersionStats {
+    pub fn record(&mut self, success: bool, latency: Duration) {
+        self.requests += 1;
+        if !success {
+            self.errors += 1;
+        }
+        self.total_latency_ms += latency.as_secs_f64() * 1000.0;
+    }
+
+    pub fn merge(&mut self, other: &VersionStats) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.total_latency_ms += other.total_latency_ms;
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.errors as f64 / self.requests as f64
+    }
+
+    pub fn avg_latency_ms(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.total_latency_ms / self.requests as f64
+    }
+}
+
+impl RegisteredService {
+    pub fn version(&self) -> Option<&str> {
+        self.labels.get(VERSION_LABEL).map(|v| v.as_str())
+    }
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
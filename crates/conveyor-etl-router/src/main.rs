@@ -19,6 +19,10 @@ struct Args {
     #[arg(long, default_value = "127.0.0.1:50052")]
     raft_addr: String,
 
+    /// Address clients reach the gRPC server on, if not `listen_addr`
+    #[arg(long)]
+    advertise_addr: Option<String>,
+
     #[arg(long)]
     peers: Vec<String>,
 
@@ -45,7 +49,7 @@ async fn main() -> Result<()> {
 
     let settings = conveyor_etl_config::Settings::load(&args.config)?;
 
-    let server = conveyor_etl_grpc::RouterServer::new(
+    let mut server = conveyor_etl_grpc::RouterServer::new(
         args.node_id,
         args.listen_addr.parse()?,
         args.raft_addr.parse()?,
@@ -53,6 +57,9 @@ async fn main() -> Result<()> {
         args.data_dir,
         settings,
     ).await?;
+    if let Some(addr) = args.advertise_addr {
+        server = server.with_advertise_addr(addr);
+    }
 
     server.run().await?;
 
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_routing::{
+    Condition, Expression, LoadBalanceStrategy, Pipeline, RoutingMode, RoutingPlan,
+    ServiceSelector, Stage, StageType,
+};
+
+const BATCH_SIZE: usize = 1000;
+
+fn stage(id: &str, stage_type: StageType) -> Stage {
+    Stage {
+        id: id.to_string(),
+        name: id.to_string(),
+        stage_type,
+        service_selector: ServiceSelector {
+            service_name: Some(format!("{}-service", id)),
+            group_id: None,
+            labels: HashMap::new(),
+            load_balance: LoadBalanceStrategy::RoundRobin,
+            traffic_split: None,
+        },
+        parallelism: 1,
+        lookup_config: None,
+        fan_in_config: None,
+        fan_out_config: None,
+        routing_mode: RoutingMode::AllMatches,
+        partition_key: None,
+    }
+}
+
+fn pipeline() -> Pipeline {
+    let mut pipeline = Pipeline::new("bench".to_string(), "bench".to_string());
+    pipeline.enabled = true;
+    pipeline.add_stage(stage("source", StageType::Source));
+
+    let edges = vec![
+        ("archive", None),
+        ("orders", Some(Condition::RecordType("order".to_string()))),
+        (
+            "errors",
+            Some(Condition::MetadataMatch {
+                key: "level".to_string(),
+                pattern: "^(error|fatal)$".to_string(),
+            }),
+        ),
+        (
+            "priority",
+            Some(Condition::And(vec![
+                Condition::MetadataExists("tenant".to_string()),
+                Condition::MetadataGreaterThan {
+                    key: "priority".to_string(),
+                    value: 5.0,
+                },
+            ])),
+        ),
+        (
+            "large",
+            Some(Condition::Expression(
+                Expression::compile("payload.amount > 500").unwrap(),
+            )),
+        ),
+    ];
+
+    for (target, condition) in edges {
+        pipeline.add_stage(stage(target, StageType::Sink));
+        pipeline.add_edge("source", target, condition);
+    }
+
+    pipeline
+}
+
+fn records() -> Vec<Record> {
+    let levels = ["info", "warn", "error", "fatal"];
+    let types = ["order", "click", "view"];
+
+    (0..BATCH_SIZE)
+        .map(|i| {
+            let mut metadata = HashMap::new();
+            metadata.insert("level".to_string(), levels[i % levels.len()].to_string());
+            metadata.insert("tenant".to_string(), format!("tenant-{}", i % 16));
+            metadata.insert("priority".to_string(), (i % 10).to_string());
+
+            let payload = format!(
+                r#"{{"id": {}, "amount": {}, "customer": "c-{}", "padding": "{}"}}"#,
+                i,
+                i % 1000,
+                i % 97,
+                "x".repeat(256)
+            );
+
+            Record {
+                id: None,
+                record_type: types[i % types.len()].to_string(),
+                key: format!("key-{}", i).into_bytes().into(),
+                payload: payload.into_bytes().into(),
+                metadata,
+                event_time: None,
+                ingestion_time: None,
+            }
+        })
+        .collect()
+}
+
+// Mirrors the original per-record interpretation: edges are filtered per
+// batch, conditions are evaluated from their uncompiled form and every
+// matching record is cloned into a freshly built map.
+fn route_interpreted(
+    pipeline: &Pipeline,
+    source_stage_id: &str,
+    records: Vec<Record>,
+) -> HashMap<String, Vec<Record>> {
+    let outgoing_edges: Vec<_> = pipeline
+        .edges
+        .iter()
+        .filter(|e| e.from_stage == source_stage_id)
+        .collect();
+
+    let mut decisions: HashMap<String, Vec<Record>> = HashMap::new();
+    for record in records {
+        for edge in &outgoing_edges {
+            let should_route = match &edge.condition {
+                Some(condition) => condition.evaluate(&record),
+                None => true,
+            };
+            if should_route {
+                decisions
+                    .entry(edge.to_stage.clone())
+                    .or_default()
+                    .push(record.clone());
+            }
+        }
+    }
+    decisions
+}
+
+fn bench_route_batch(c: &mut Criterion) {
+    let pipeline = pipeline();
+    let plan = RoutingPlan::compile(&pipeline);
+    let records = records();
+
+    let mut group = c.benchmark_group("route_batch");
+    group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+
+    group.bench_function("interpreted", |b| {
+        b.iter_batched(
+            || records.clone(),
+            |records| black_box(route_interpreted(&pipeline, "source", records)),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("compiled", |b| {
+        b.iter_batched(
+            || records.clone(),
+            |records| black_box(plan.route("source", records).decisions),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+fn bench_compile(c: &mut Criterion) {
+    let pipeline = pipeline();
+    c.bench_function("compile_plan", |b| b.iter(|| RoutingPlan::compile(black_box(&pipeline))));
+}
+
+criterion_group!(benches, bench_route_batch, bench_compile);
+criterion_main!(benches);
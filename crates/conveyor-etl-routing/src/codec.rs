@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use serde_json::Value;
+
+use conveyor_etl_proto::common::Record;
+
+pub const CONTENT_TYPE_KEY: &str = "content-type";
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+pub const AVRO_CONTENT_TYPE: &str = "application/avro";
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// Converts a wire-format payload to and from the JSON value model that
+/// conditions, field mappings and lookups operate on.
+pub trait PayloadCodec: Send + Sync {
+    fn content_type(&self) -> &str;
+
+    fn decode(&self, payload: &[u8]) -> Result<Value>;
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>>;
+
+    /// Schema-bound codecs can only encode values shaped like their schema,
+    /// so reshaped payloads are written as JSON instead.
+    fn schema_bound(&self) -> bool {
+        false
+    }
+}
+
+pub struct JsonCodec;
+
+impl PayloadCodec for JsonCodec {
+    fn content_type(&self) -> &str {
+        JSON_CONTENT_TYPE
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value> {
+        serde_json::from_slice(payload).context("invalid JSON payload")
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+}
+
+pub struct MessagePackCodec;
+
+impl PayloadCodec for MessagePackCodec {
+    fn content_type(&self) -> &str {
+        MSGPACK_CONTENT_TYPE
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value> {
+        rmp_serde::from_slice(payload).context("invalid MessagePack payload")
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+}
+
+/// Raw Avro datums (no container or single-object header) written with a
+/// known writer schema.
+pub struct AvroCodec {
+    schema: apache_avro::Schema,
+}
+
+impl AvroCodec {
+    pub fn new(writer_schema: &str) -> Result<Self> {
+        let schema = apache_avro::Schema::parse_str(writer_schema)
+            .map_err(|e| anyhow!("invalid Avro schema: {}", e))?;
+        Ok(Self { schema })
+    }
+}
+
+impl PayloadCodec for AvroCodec {
+    fn content_type(&self) -> &str {
+        AVRO_CONTENT_TYPE
+    }
+
+    fn decode(&self, mut payload: &[u8]) -> Result<Value> {
+        let value = apache_avro::from_avro_datum(&self.schema, &mut payload, None)
+            .map_err(|e| anyhow!("invalid Avro payload: {}", e))?;
+        Value::try_from(value).map_err(|e| anyhow!("unsupported Avro value: {}", e))
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        let value = apache_avro::types::Value::from(value.clone())
+            .resolve(&self.schema)
+            .map_err(|e| anyhow!("value does not match Avro schema: {}", e))?;
+        apache_avro::to_avro_datum(&self.schema, value)
+            .map_err(|e| anyhow!("failed to encode Avro payload: {}", e))
+    }
+
+    fn schema_bound(&self) -> bool {
+        true
+    }
+}
+
+/// Protobuf messages described by a serialized `FileDescriptorSet`, as
+/// produced by `protoc --descriptor_set_out --include_imports`.
+pub struct ProtobufCodec {
+    message: MessageDescriptor,
+}
+
+impl ProtobufCodec {
+    pub fn from_descriptor_set(descriptor_set: &[u8], message_name: &str) -> Result<Self> {
+        let pool = DescriptorPool::decode(descriptor_set).context("invalid descriptor set")?;
+        let message = pool
+            .get_message_by_name(message_name)
+            .ok_or_else(|| anyhow!("message type not found in descriptor set: {}", message_name))?;
+        Ok(Self { message })
+    }
+
+    pub fn message_name(&self) -> &str {
+        self.message.full_name()
+    }
+}
+
+impl PayloadCodec for ProtobufCodec {
+    fn content_type(&self) -> &str {
+        PROTOBUF_CONTENT_TYPE
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value> {
+        let message = DynamicMessage::decode(self.message.clone(), payload)
+            .context("invalid Protobuf payload")?;
+        let options = SerializeOptions::new()
+            .stringify_64_bit_integers(false)
+            .use_proto_field_name(true);
+        Ok(message.serialize_with_options(serde_json::value::Serializer, &options)?)
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        let message = DynamicMessage::deserialize(self.message.clone(), value.clone())
+            .context("value does not match Protobuf message")?;
+        Ok(message.encode_to_vec())
+    }
+
+    fn schema_bound(&self) -> bool {
+        true
+    }
+}
+
+/// Picks a codec per record: the `content-type` metadata key wins, then the
+/// record type, then JSON.
+#[derive(Clone)]
+pub struct CodecRegistry {
+    by_content_type: HashMap<String, Arc<dyn PayloadCodec>>,
+    by_record_type: HashMap<String, Arc<dyn PayloadCodec>>,
+    default: Arc<dyn PayloadCodec>,
+}
+
+impl CodecRegistry {
+    pub fn new() -> Self {
+        let json: Arc<dyn PayloadCodec> = Arc::new(JsonCodec);
+        let msgpack: Arc<dyn PayloadCodec> = Arc::new(MessagePackCodec);
+
+        let mut registry = Self {
+            by_content_type: HashMap::new(),
+            by_record_type: HashMap::new(),
+            default: Arc::clone(&json),
+        };
+        registry.register_content_type(JSON_CONTENT_TYPE, json);
+        registry.register_content_type(MSGPACK_CONTENT_TYPE, Arc::clone(&msgpack));
+        registry.register_content_type("application/x-msgpack", msgpack);
+        registry
+    }
+
+    /// The built-in codecs, for callers that were not handed a configured
+    /// registry. Avro and Protobuf need a schema, so they are never here.
+    pub(crate) fn shared() -> &'static CodecRegistry {
+        static SHARED: OnceLock<CodecRegistry> = OnceLock::new();
+        SHARED.get_or_init(CodecRegistry::new)
+    }
+
+    pub fn register_content_type(&mut self, content_type: &str, codec: Arc<dyn PayloadCodec>) {
+        self.by_content_type.insert(content_type.trim().to_ascii_lowercase(), codec);
+    }
+
+    pub fn register_record_type(&mut self, record_type: &str, codec: Arc<dyn PayloadCodec>) {
+        self.by_record_type.insert(record_type.to_string(), codec);
+    }
+
+    pub fn codec_for(&self, record: &Record) -> &dyn PayloadCodec {
+        let by_content_type = record.metadata.get(CONTENT_TYPE_KEY).and_then(|ct| {
+            let ct = ct.trim().to_ascii_lowercase();
+            self.by_content_type.get(&ct).or_else(|| {
+                let essence = ct.split(';').next().unwrap_or_default().trim();
+                self.by_content_type.get(essence)
+            })
+        });
+
+        by_content_type
+            .or_else(|| self.by_record_type.get(&record.record_type))
+            .unwrap_or(&self.default)
+            .as_ref()
+    }
+
+    pub fn decode(&self, record: &Record) -> Result<Value> {
+        let codec = self.codec_for(record);
+        codec
+            .decode(&record.payload)
+            .with_context(|| format!("failed to decode {} payload", codec.content_type()))
+    }
+
+    /// Writes `value` as the record's payload with its codec. Schema-bound
+    /// codecs cannot encode reshaped values, so those records switch to JSON.
+    pub fn encode(&self, record: &mut Record, value: &Value) -> Result<()> {
+        let codec = self.codec_for(record);
+        if codec.schema_bound() {
+            record.payload = JsonCodec.encode(value)?.into();
+            record
+                .metadata
+                .insert(CONTENT_TYPE_KEY.to_string(), JSON_CONTENT_TYPE.to_string());
+        } else {
+            record.payload = codec.encode(value)?.into();
+        }
+        Ok(())
+    }
+}
+
+impl Default for CodecRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for CodecRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut content_types: Vec<_> = self.by_content_type.keys().collect();
+        content_types.sort();
+        let mut record_types: Vec<_> = self.by_record_type.keys().collect();
+        record_types.sort();
+        f.debug_struct("CodecRegistry")
+            .field("content_types", &content_types)
+            .field("record_types", &record_types)
+            .finish()
+    }
+}
@@ -1,7 +1,10 @@
 use std::collections::{HashMap, HashSet};
 use serde::{Deserialize, Serialize};
 
+use conveyor_etl_registry::TrafficSplit;
+
 use super::matcher::Condition;
+use super::partition::PartitionKey;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PipelineValidationError {
@@ -12,6 +15,9 @@ pub enum PipelineValidationError {
     MissingStage { stage_id: String },
     NoSourceStages,
     NoSinkStages,
+    ConditionalDefaultEdge { from_stage: String, to_stage: String },
+    InvalidDeadLetterStage { stage_id: String },
+    InvalidLateDataStage { fan_in_stage: String, stage_id: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +29,8 @@ pub struct Pipeline {
     pub edges: Vec<Edge>,
     pub enabled: bool,
     pub metadata: HashMap<String, String>,
+    #[serde(default)]
+    pub dead_letter_stage: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,6 +43,19 @@ pub struct Stage {
     pub lookup_config: Option<LookupConfig>,
     pub fan_in_config: Option<FanInConfig>,
     pub fan_out_config: Option<FanOutConfig>,
+    #[serde(default)]
+    pub routing_mode: RoutingMode,
+    /// Partitions records across the stage's parallel instances.
+    #[serde(default)]
+    pub partition_key: Option<PartitionKey>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoutingMode {
+    #[default]
+    AllMatches,
+    FirstMatch,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,6 +113,10 @@ pub struct SourceWatermark {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FanInWatermark {
     pub allowed_lateness: Option<std::time::Duration>,
+    /// Stage that receives records arriving behind the watermark. Late
+    /// records are dropped when unset.
+    #[serde(default)]
+    pub late_data_stage: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -114,6 +139,17 @@ pub struct FieldMapping {
     pub cast_type: Option<FieldCastType>,
     pub default_value: Option<Vec<u8>>,
     pub literal_value: Option<Vec<u8>>,
+    #[serde(default)]
+    pub on_error: CastErrorStrategy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CastErrorStrategy {
+    #[default]
+    Fail,
+    UseDefault,
+    Skip,
+    PassThrough,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -146,6 +182,8 @@ pub struct ServiceSelector {
     pub group_id: Option<String>,
     pub labels: HashMap<String, String>,
     pub load_balance: LoadBalanceStrategy,
+    #[serde(default)]
+    pub traffic_split: Option<TrafficSplit>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -154,6 +192,7 @@ pub enum LoadBalanceStrategy {
     LeastConnections,
     WeightedRandom,
     ConsistentHash,
+    PeakEwma,
 }
 
 impl Default for LoadBalanceStrategy {
@@ -167,6 +206,10 @@ pub struct Edge {
     pub from_stage: String,
     pub to_stage: String,
     pub condition: Option<Condition>,
+    #[serde(default)]
+    pub priority: u32,
+    #[serde(default)]
+    pub is_default: bool,
 }
 
 impl Pipeline {
@@ -179,6 +222,7 @@ impl Pipeline {
             edges: Vec::new(),
             enabled: false,
             metadata: HashMap::new(),
+            dead_letter_stage: None,
         }
     }
 
@@ -187,10 +231,32 @@ impl Pipeline {
     }
 
     pub fn add_edge(&mut self, from: &str, to: &str, condition: Option<Condition>) {
+        self.add_edge_with_priority(from, to, condition, 0);
+    }
+
+    pub fn add_edge_with_priority(
+        &mut self,
+        from: &str,
+        to: &str,
+        condition: Option<Condition>,
+        priority: u32,
+    ) {
         self.edges.push(Edge {
             from_stage: from.to_string(),
             to_stage: to.to_string(),
             condition,
+            priority,
+            is_default: false,
+        });
+    }
+
+    pub fn add_default_edge(&mut self, from: &str, to: &str) {
+        self.edges.push(Edge {
+            from_stage: from.to_string(),
+            to_stage: to.to_string(),
+            condition: None,
+            priority: 0,
+            is_default: true,
         });
     }
 
@@ -245,6 +311,39 @@ impl Pipeline {
                     stage_id: edge.to_stage.clone(),
                 });
             }
+            if edge.is_default && edge.condition.is_some() {
+                errors.push(PipelineValidationError::ConditionalDefaultEdge {
+                    from_stage: edge.from_stage.clone(),
+                    to_stage: edge.to_stage.clone(),
+                });
+            }
+        }
+
+        if let Some(dlq) = &self.dead_letter_stage {
+            let is_sink = self
+                .stages
+                .get(dlq)
+                .map(|s| s.stage_type == StageType::Sink)
+                .unwrap_or(false);
+            if !is_sink {
+                errors.push(PipelineValidationError::InvalidDeadLetterStage {
+                    stage_id: dlq.clone(),
+                });
+            }
+        }
+
+        for (stage_id, late_stage) in self.late_data_stages() {
+            let is_sink = self
+                .stages
+                .get(late_stage)
+                .map(|s| s.stage_type == StageType::Sink)
+                .unwrap_or(false);
+            if !is_sink {
+                errors.push(PipelineValidationError::InvalidLateDataStage {
+                    fan_in_stage: stage_id.to_string(),
+                    stage_id: late_stage.to_string(),
+                });
+            }
         }
 
         if let Some(cycle_path) = self.detect_cycle() {
@@ -263,8 +362,18 @@ impl Pipeline {
 
         let reachable_from_sources = self.get_reachable_from_sources();
         let can_reach_sinks = self.get_stages_that_can_reach_sinks();
+        let late_data_stages: HashSet<&str> =
+            self.late_data_stages().map(|(_, late)| late).collect();
 
         for stage_id in self.stages.keys() {
+            // Dead-letter and late-data sinks are fed by the router rather
+            // than by edges
+            if self.dead_letter_stage.as_ref() == Some(stage_id)
+                || late_data_stages.contains(stage_id.as_str())
+            {
+                continue;
+            }
+
             if !reachable_from_sources.contains(stage_id) {
                 let stage = self.stages.get(stage_id).unwrap();
                 if stage.stage_type != StageType::Source {
@@ -307,6 +416,21 @@ impl Pipeline {
         }
     }
 
+    /// Pairs of (fan-in stage, late-data stage) for every fan-in stage that
+    /// routes late records to a side output.
+    pub fn late_data_stages(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.stages.values().filter_map(|stage| {
+            let late = stage
+                .fan_in_config
+                .as_ref()?
+                .watermark
+                .as_ref()?
+                .late_data_stage
+                .as_deref()?;
+            Some((stage.id.as_str(), late))
+        })
+    }
+
     pub fn detect_cycle(&self) -> Option<Vec<String>> {
         let adjacency: HashMap<&str, Vec<&str>> = {
             let mut adj: HashMap<&str, Vec<&str>> = HashMap::new();
@@ -1,47 +1,85 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 use dashmap::DashMap;
 use anyhow::Result;
+use tracing::{debug, warn};
 
 use super::dag::{
-    FanInConfig, FanOutConfig, FieldMapping, LookupConfig, LookupMissStrategy, MergeStrategy,
-    Pipeline, Stage, StageType,
+    FanInConfig, FanOutConfig, FieldMapping, LookupConfig, Pipeline, Stage, StageType,
 };
+use super::codec::CodecRegistry;
+use super::fan;
+use super::lookup::{self, LookupResult};
+use super::mapping::MappingError;
+use super::plan::RoutingPlan;
 use super::watermark::WatermarkTracker;
 use conveyor_etl_proto::common::{Record, RecordBatch};
+use conveyor_etl_registry::TrafficSplit;
 
 pub struct RoutingDecision {
     pub target_stage_id: String,
     pub records: Vec<Record>,
 }
 
-pub enum LookupResult {
-    Found {
-        data: HashMap<String, Vec<u8>>,
-    },
-    NotFound,
-    Error {
-        message: String,
-    },
+/// Records a fan-in stage found behind its watermark, split by what happened
+/// to them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LateRecordCounts {
+    pub side_output: u64,
+    pub dropped: u64,
 }
 
 pub struct RoutingEngine {
     pipelines: DashMap<String, Pipeline>,
+    plans: DashMap<String, Arc<RoutingPlan>>,
+    unmatched: DashMap<(String, String), u64>,
+    fan_in_trackers: DashMap<(String, String), WatermarkTracker>,
+    late_records: DashMap<(String, String), LateRecordCounts>,
+    codecs: Arc<CodecRegistry>,
 }
 
 impl RoutingEngine {
     pub fn new() -> Self {
+        Self::with_codecs(CodecRegistry::new())
+    }
+
+    pub fn with_codecs(codecs: CodecRegistry) -> Self {
         Self {
             pipelines: DashMap::new(),
+            plans: DashMap::new(),
+            unmatched: DashMap::new(),
+            fan_in_trackers: DashMap::new(),
+            late_records: DashMap::new(),
+            codecs: Arc::new(codecs),
         }
     }
 
+    pub fn codecs(&self) -> &CodecRegistry {
+        &self.codecs
+    }
+
     pub async fn add_pipeline(&self, pipeline: Pipeline) {
         let pipeline_id = pipeline.id.clone();
-        self.pipelines.insert(pipeline_id, pipeline);
+        let plan = Arc::new(RoutingPlan::compile_with_codecs(
+            &pipeline,
+            Arc::clone(&self.codecs),
+        ));
+        // Sources or lateness may have changed, so watermarks start over
+        self.fan_in_trackers.retain(|(p, _), _| *p != pipeline_id);
+        self.pipelines.insert(pipeline_id.clone(), pipeline);
+        self.plans.insert(pipeline_id, plan);
     }
 
     pub async fn remove_pipeline(&self, pipeline_id: &str) {
         self.pipelines.remove(pipeline_id);
+        self.plans.remove(pipeline_id);
+        self.unmatched.retain(|(p, _), _| p != pipeline_id);
+        self.fan_in_trackers.retain(|(p, _), _| p != pipeline_id);
+        self.late_records.retain(|(p, _), _| p != pipeline_id);
+    }
+
+    pub async fn get_plan(&self, pipeline_id: &str) -> Option<Arc<RoutingPlan>> {
+        self.plans.get(pipeline_id).map(|p| Arc::clone(p.value()))
     }
 
     pub async fn get_pipeline(&self, pipeline_id: &str) -> Option<Pipeline> {
@@ -52,55 +90,115 @@ impl RoutingEngine {
         self.pipelines.iter().map(|r| r.clone()).collect()
     }
 
+    pub async fn update_traffic_split(
+        &self,
+        pipeline_id: &str,
+        stage_id: &str,
+        traffic_split: Option<TrafficSplit>,
+    ) -> Result<Pipeline> {
+        if let Some(split) = &traffic_split {
+            split.validate()?;
+        }
+
+        let mut pipeline = self
+            .pipelines
+            .get_mut(pipeline_id)
+            .ok_or_else(|| anyhow::anyhow!("Pipeline not found: {}", pipeline_id))?;
+
+        let stage = pipeline
+            .stages
+            .get_mut(stage_id)
+            .ok_or_else(|| anyhow::anyhow!("Stage not found: {}", stage_id))?;
+
+        stage.service_selector.traffic_split = traffic_split;
+        Ok(pipeline.clone())
+    }
+
     pub async fn route_batch(
         &self,
         pipeline_id: &str,
         source_stage_id: &str,
         batch: RecordBatch,
     ) -> Result<Vec<RoutingDecision>> {
-        let pipeline = self.pipelines
-            .get(pipeline_id)
+        let plan = self
+            .get_plan(pipeline_id)
+            .await
             .ok_or_else(|| anyhow::anyhow!("Pipeline not found: {}", pipeline_id))?;
 
-        if !pipeline.enabled {
+        if !plan.is_enabled() {
             return Err(anyhow::anyhow!("Pipeline is disabled: {}", pipeline_id));
         }
 
-        let outgoing_edges: Vec<_> = pipeline
-            .edges
-            .iter()
-            .filter(|e| e.from_stage == source_stage_id)
-            .collect();
-
-        if outgoing_edges.is_empty() {
-            return Ok(vec![]);
+        let routed = plan.route(source_stage_id, batch.records);
+        if routed.unmatched > 0 {
+            debug!(
+                pipeline_id,
+                stage_id = source_stage_id,
+                unmatched = routed.unmatched,
+                dead_letter_stage = plan.dead_letter_stage(),
+                "Records matched no routing edge"
+            );
+            *self
+                .unmatched
+                .entry((pipeline_id.to_string(), source_stage_id.to_string()))
+                .or_insert(0) += routed.unmatched as u64;
         }
 
-        let mut decisions: HashMap<String, Vec<Record>> = HashMap::new();
+        Ok(routed.decisions)
+    }
 
-        for record in batch.records {
-            for edge in &outgoing_edges {
-                let should_route = match &edge.condition {
-                    Some(condition) => condition.evaluate(&record),
-                    None => true,
-                };
+    pub fn unmatched_count(&self, pipeline_id: &str) -> u64 {
+        self.unmatched
+            .iter()
+            .filter(|r| r.key().0 == pipeline_id)
+            .map(|r| *r.value())
+            .sum()
+    }
 
-                if should_route {
-                    decisions
-                        .entry(edge.to_stage.clone())
-                        .or_default()
-                        .push(record.clone());
-                }
-            }
-        }
+    pub fn unmatched_counts(&self) -> Vec<(String, String, u64)> {
+        let mut counts: Vec<_> = self
+            .unmatched
+            .iter()
+            .map(|r| (r.key().0.clone(), r.key().1.clone(), *r.value()))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    pub fn late_record_counts(&self) -> Vec<(String, String, LateRecordCounts)> {
+        let mut counts: Vec<_> = self
+            .late_records
+            .iter()
+            .map(|r| (r.key().0.clone(), r.key().1.clone(), *r.value()))
+            .collect();
+        counts.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        counts
+    }
 
-        Ok(decisions
-            .into_iter()
-            .map(|(target_stage_id, records)| RoutingDecision {
-                target_stage_id,
-                records,
+    /// Adds late records counted where the fan-in stage ran, such as in a
+    /// sidecar.
+    pub fn add_late_records(&self, pipeline_id: &str, stage_id: &str, late: LateRecordCounts) {
+        let mut counts = self
+            .late_records
+            .entry((pipeline_id.to_string(), stage_id.to_string()))
+            .or_default();
+        counts.side_output += late.side_output;
+        counts.dropped += late.dropped;
+    }
+
+    /// Combined watermark (epoch millis) of every fan-in stage that has seen
+    /// records, skipping stages still waiting on a source.
+    pub fn fan_in_watermarks(&self) -> Vec<(String, String, i64)> {
+        let mut watermarks: Vec<_> = self
+            .fan_in_trackers
+            .iter_mut()
+            .filter_map(|mut r| {
+                let watermark = r.value_mut().combined_watermark();
+                (watermark != i64::MIN).then(|| (r.key().0.clone(), r.key().1.clone(), watermark))
             })
-            .collect())
+            .collect();
+        watermarks.sort();
+        watermarks
     }
 
     pub async fn find_pipelines_for_source(&self, source_service_name: &str) -> Vec<String> {
@@ -150,62 +248,19 @@ impl RoutingEngine {
 
     pub fn merge_lookup_result(
         &self,
-        mut record: Record,
+        record: Record,
         lookup_result: LookupResult,
         config: &LookupConfig,
     ) -> Result<Option<Record>> {
-        match lookup_result {
-            LookupResult::Found { data } => {
-                match config.merge_strategy {
-                    MergeStrategy::Merge => {
-                        for (key, value) in data {
-                            let prefixed_key = match &config.output_prefix {
-                                Some(prefix) => format!("{}{}", prefix, key),
-                                None => key,
-                            };
-                            let value_str = String::from_utf8_lossy(&value).to_string();
-                            record.metadata.insert(prefixed_key, value_str);
-                        }
-                        Ok(Some(record))
-                    }
-                    MergeStrategy::Nest => {
-                        let field_name = config
-                            .output_prefix
-                            .clone()
-                            .unwrap_or_else(|| "lookup".to_string());
-                        let json_data: HashMap<String, String> = data
-                            .into_iter()
-                            .map(|(k, v)| (k, String::from_utf8_lossy(&v).to_string()))
-                            .collect();
-                        let json = serde_json::to_string(&json_data)
-                            .map_err(|e| anyhow::anyhow!("Failed to serialize lookup data: {}", e))?;
-                        record.metadata.insert(field_name, json);
-                        Ok(Some(record))
-                    }
-                    MergeStrategy::Replace => {
-                        let json_data: HashMap<String, String> = data
-                            .into_iter()
-                            .map(|(k, v)| (k, String::from_utf8_lossy(&v).to_string()))
-                            .collect();
-                        record.payload = serde_json::to_vec(&json_data)
-                            .map_err(|e| anyhow::anyhow!("Failed to serialize lookup data: {}", e))?;
-                        Ok(Some(record))
-                    }
-                }
-            }
-            LookupResult::NotFound => {
-                match config.on_miss {
-                    LookupMissStrategy::PassThrough => Ok(Some(record)),
-                    LookupMissStrategy::Drop => Ok(None),
-                    LookupMissStrategy::Error => {
-                        Err(anyhow::anyhow!("Lookup miss: no matching record found"))
-                    }
-                }
-            }
-            LookupResult::Error { message } => {
-                Err(anyhow::anyhow!("Lookup error: {}", message))
-            }
-        }
+        lookup::merge_lookup_result(&self.codecs, record, lookup_result, config)
+    }
+
+    pub fn extract_lookup_keys(
+        &self,
+        record: &Record,
+        config: &LookupConfig,
+    ) -> Result<HashMap<String, Vec<u8>>> {
+        lookup::extract_lookup_keys(&self.codecs, record, config)
     }
 
     pub fn get_lookup_key_fields(&self, config: &LookupConfig) -> Vec<String> {
@@ -266,59 +321,163 @@ impl RoutingEngine {
         &self,
         config: &FanInConfig,
     ) -> WatermarkTracker {
-        let source_ids: Vec<String> = config.sources.iter().map(|s| s.id.clone()).collect();
-        let allowed_lateness = config
+        fan::fan_in_tracker(config)
+    }
+
+    /// Event time of `record` in epoch millis, read from `field` in the
+    /// payload when set and falling back to `Record.event_time`. Numbers are
+    /// taken as epoch millis; strings may be RFC 3339 or ISO 8601 timestamps.
+    pub fn extract_event_time(&self, record: &Record, field: Option<&str>) -> Option<i64> {
+        fan::extract_event_time(&self.codecs, record, field)
+    }
+
+    /// Routes a batch arriving through one of a fan-in stage's sources
+    /// (`"{fan_in}:{source}"`). Records behind the fan-in watermark go to the
+    /// late-data stage, or are dropped when none is configured; the rest are
+    /// mapped and routed along the fan-in stage's edges.
+    pub async fn route_fan_in(
+        &self,
+        pipeline_id: &str,
+        source_stage_id: &str,
+        batch: RecordBatch,
+    ) -> Result<Vec<RoutingDecision>> {
+        let (fan_in_stage_id, source_id) = self
+            .get_fan_in_source_id(source_stage_id)
+            .ok_or_else(|| anyhow::anyhow!("Not a fan-in source stage: {}", source_stage_id))?;
+        let config = self
+            .get_fan_in_config(pipeline_id, fan_in_stage_id)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Fan-in config not found for stage: {}", fan_in_stage_id))?;
+        let source = config
+            .sources
+            .iter()
+            .find(|s| s.id == source_id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown fan-in source: {}", source_stage_id))?;
+        let late_data_stage = config
             .watermark
             .as_ref()
-            .and_then(|w| w.allowed_lateness)
-            .unwrap_or(std::time::Duration::from_secs(0));
+            .and_then(|w| w.late_data_stage.clone());
+        let dead_letter_stage = self
+            .pipelines
+            .get(pipeline_id)
+            .and_then(|p| p.dead_letter_stage.clone());
+
+        let RecordBatch {
+            batch_id,
+            records,
+            watermark: source_watermark,
+        } = batch;
+        let key = (pipeline_id.to_string(), fan_in_stage_id.to_string());
+        let mut on_time = Vec::with_capacity(records.len());
+        let mut late = Vec::new();
+
+        {
+            let mut tracker = self
+                .fan_in_trackers
+                .entry(key.clone())
+                .or_insert_with(|| self.create_watermark_tracker_for_fan_in(&config));
+            tracker.advance_idle_sources(chrono::Utc::now().timestamp_millis());
+
+            for mut record in records {
+                match fan::align_fan_in_record(&self.codecs, &mut tracker, source, &mut record) {
+                    Some(watermark) => late.push(fan::late_record(record, fan_in_stage_id, watermark)),
+                    None => on_time.push(record),
+                }
+            }
 
-        let mut tracker = WatermarkTracker::new(source_ids, allowed_lateness);
+            let watermark = source_watermark
+                .as_ref()
+                .and_then(|w| w.timestamp.as_ref())
+                .and_then(fan::timestamp_millis);
+            if let Some(watermark) = watermark {
+                tracker.update(source_id, watermark);
+            }
+        }
 
-        for source in &config.sources {
-            if let Some(wm) = &source.watermark {
-                if let Some(timeout) = wm.idle_timeout {
-                    tracker.set_idle_timeout(&source.id, timeout);
+        let mut extra = Vec::new();
+        if !late.is_empty() {
+            debug!(
+                pipeline_id,
+                stage_id = fan_in_stage_id,
+                source_id,
+                late = late.len(),
+                late_data_stage = late_data_stage.as_deref(),
+                "Records arrived behind the fan-in watermark"
+            );
+            let mut counts = self.late_records.entry(key).or_default();
+            match late_data_stage {
+                Some(stage) => {
+                    counts.side_output += late.len() as u64;
+                    extra.push(RoutingDecision {
+                        target_stage_id: stage,
+                        records: late,
+                    });
                 }
+                None => counts.dropped += late.len() as u64,
             }
         }
 
-        tracker
-    }
-
-    pub fn apply_field_mappings(&self, record: Record, mappings: &[FieldMapping]) -> Record {
-        if mappings.is_empty() {
-            return record;
+        let mut mapped_records = Vec::with_capacity(on_time.len());
+        let mut dead_letters = Vec::new();
+        for record in on_time {
+            match self.apply_field_mappings(record.clone(), &source.field_mappings) {
+                Ok(mapped) => mapped_records.push(mapped),
+                Err(e) => {
+                    warn!(
+                        pipeline_id,
+                        stage_id = source_stage_id,
+                        error = %e,
+                        "Field mapping failed"
+                    );
+                    if dead_letter_stage.is_some() {
+                        dead_letters.push(fan::mapping_failure(record, pipeline_id, source_stage_id, &e));
+                    }
+                }
+            }
+        }
+        if let Some(dlq) = dead_letter_stage {
+            if !dead_letters.is_empty() {
+                extra.push(RoutingDecision {
+                    target_stage_id: dlq,
+                    records: dead_letters,
+                });
+            }
         }
 
-        let mut new_record = record.clone();
-        let original_payload: HashMap<String, serde_json::Value> =
-            serde_json::from_slice(&record.payload).unwrap_or_default();
-
-        let mut new_payload: HashMap<String, serde_json::Value> = HashMap::new();
-
-        for mapping in mappings {
-            let value = if let Some(literal) = &mapping.literal_value {
-                serde_json::from_slice(literal).ok()
-            } else if let Some(source_field) = &mapping.source_field {
-                original_payload.get(source_field).cloned()
-            } else if let Some(default) = &mapping.default_value {
-                serde_json::from_slice(default).ok()
-            } else {
-                original_payload.get(&mapping.target_field).cloned()
-            };
-
-            if let Some(v) = value {
-                new_payload.insert(mapping.target_field.clone(), v);
-            } else if let Some(default) = &mapping.default_value {
-                if let Ok(v) = serde_json::from_slice::<serde_json::Value>(default) {
-                    new_payload.insert(mapping.target_field.clone(), v);
-                }
+        let mut decisions = if mapped_records.is_empty() {
+            Vec::new()
+        } else {
+            self.route_batch(
+                pipeline_id,
+                fan_in_stage_id,
+                RecordBatch {
+                    batch_id,
+                    records: mapped_records,
+                    watermark: source_watermark,
+                },
+            )
+            .await?
+        };
+
+        for decision in extra {
+            match decisions
+                .iter_mut()
+                .find(|d| d.target_stage_id == decision.target_stage_id)
+            {
+                Some(existing) => existing.records.extend(decision.records),
+                None => decisions.push(decision),
             }
         }
 
-        new_record.payload = serde_json::to_vec(&new_payload).unwrap_or(record.payload);
-        new_record
+        Ok(decisions)
+    }
+
+    pub fn apply_field_mappings(
+        &self,
+        record: Record,
+        mappings: &[FieldMapping],
+    ) -> std::result::Result<Record, MappingError> {
+        fan::apply_field_mappings(&self.codecs, record, mappings)
     }
 
     pub async fn route_fan_out(
@@ -331,16 +490,39 @@ impl RoutingEngine {
             .get_fan_out_config(pipeline_id, fan_out_stage_id)
             .await
             .ok_or_else(|| anyhow::anyhow!("Fan-out config not found for stage: {}", fan_out_stage_id))?;
+        let dead_letter_stage = self
+            .pipelines
+            .get(pipeline_id)
+            .and_then(|p| p.dead_letter_stage.clone());
 
         let mut decisions = Vec::with_capacity(config.sinks.len());
+        let mut dead_letters = Vec::new();
 
         for sink in &config.sinks {
             let target_id = format!("{}:{}", fan_out_stage_id, sink.id);
-            let mapped_records: Vec<Record> = batch
-                .records
-                .iter()
-                .map(|r| self.apply_field_mappings(r.clone(), &sink.field_mappings))
-                .collect();
+            let mut mapped_records = Vec::with_capacity(batch.records.len());
+
+            for record in &batch.records {
+                match self.apply_field_mappings(record.clone(), &sink.field_mappings) {
+                    Ok(mapped) => mapped_records.push(mapped),
+                    Err(e) => {
+                        warn!(
+                            pipeline_id,
+                            stage_id = %target_id,
+                            error = %e,
+                            "Field mapping failed"
+                        );
+                        if dead_letter_stage.is_some() {
+                            dead_letters.push(fan::mapping_failure(
+                                record.clone(),
+                                pipeline_id,
+                                &target_id,
+                                &e,
+                            ));
+                        }
+                    }
+                }
+            }
 
             decisions.push(RoutingDecision {
                 target_stage_id: target_id,
@@ -348,6 +530,15 @@ impl RoutingEngine {
             });
         }
 
+        if let Some(dlq) = dead_letter_stage {
+            if !dead_letters.is_empty() {
+                decisions.push(RoutingDecision {
+                    target_stage_id: dlq,
+                    records: dead_letters,
+                });
+            }
+        }
+
         Ok(decisions)
     }
 
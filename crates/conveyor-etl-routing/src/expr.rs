@@ -0,0 +1,1253 @@
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::codec::CodecRegistry;
+use conveyor_etl_proto::common::Record;
+
+const MAX_EXPRESSION_LENGTH: usize = 4096;
+const MAX_NESTING_DEPTH: usize = 64;
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    pub message: String,
+    pub position: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl ExprError {
+    fn new(source: &str, position: usize, message: impl Into<String>) -> Self {
+        let position = position.min(source.len());
+        let before = &source[..position];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().map(|l| l.chars().count()).unwrap_or(0) + 1;
+        Self {
+            message: message.into(),
+            position,
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at line {}, column {}", self.message, self.line, self.column)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+    Timestamp(DateTime<Utc>),
+    Duration(Duration),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "double",
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Timestamp(_) => "timestamp",
+            Value::Duration(_) => "duration",
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    fn loose_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Float(b)) | (Value::Float(b), Value::Int(a)) => {
+                (*a as f64) == *b
+            }
+            (Value::List(a), Value::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.loose_eq(y))
+            }
+            _ => self == other,
+        }
+    }
+
+    fn compare(&self, other: &Value) -> Result<Ordering> {
+        let ordering = match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (Value::Timestamp(a), Value::Timestamp(b)) => Some(a.cmp(b)),
+            (Value::Duration(a), Value::Duration(b)) => Some(a.cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            (a, b) => match (a.as_f64(), b.as_f64()) {
+                (Some(x), Some(y)) => x.partial_cmp(&y),
+                _ => None,
+            },
+        };
+
+        ordering.ok_or_else(|| {
+            anyhow!("cannot compare {} with {}", self.type_name(), other.type_name())
+        })
+    }
+}
+
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(b) => Value::Bool(b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Value::Int(i),
+                None => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::String(s) => Value::String(s),
+            serde_json::Value::Array(items) => {
+                Value::List(items.into_iter().map(Value::from).collect())
+            }
+            serde_json::Value::Object(fields) => {
+                Value::Map(fields.into_iter().map(|(k, v)| (k, Value::from(v))).collect())
+            }
+        }
+    }
+}
+
+impl From<Value> for serde_json::Value {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => serde_json::Value::Null,
+            Value::Bool(b) => serde_json::Value::Bool(b),
+            Value::Int(i) => serde_json::Value::from(i),
+            Value::Float(f) => serde_json::Number::from_f64(f)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            Value::String(s) => serde_json::Value::String(s),
+            Value::List(items) => {
+                serde_json::Value::Array(items.into_iter().map(Into::into).collect())
+            }
+            Value::Map(fields) => serde_json::Value::Object(
+                fields.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            ),
+            Value::Timestamp(t) => serde_json::Value::String(t.to_rfc3339()),
+            Value::Duration(d) => serde_json::Value::String(format!("{}ms", d.num_milliseconds())),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Expression {
+    source: String,
+    root: Arc<Node>,
+}
+
+impl Expression {
+    pub fn compile(source: &str) -> Result<Self, ExprError> {
+        if source.len() > MAX_EXPRESSION_LENGTH {
+            return Err(ExprError::new(
+                source,
+                MAX_EXPRESSION_LENGTH,
+                format!("expression exceeds {} bytes", MAX_EXPRESSION_LENGTH),
+            ));
+        }
+
+        let tokens = Lexer::new(source).tokenize()?;
+        let mut parser = Parser {
+            source,
+            tokens,
+            pos: 0,
+            depth: 0,
+        };
+        let root = parser.parse_expr()?;
+        parser.expect_end()?;
+
+        Ok(Self {
+            source: source.to_string(),
+            root: Arc::new(root),
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Evaluates with the built-in JSON and MessagePack codecs only.
+    pub fn evaluate(&self, record: &Record) -> Result<Value> {
+        self.evaluate_with(record, CodecRegistry::shared())
+    }
+
+    pub fn evaluate_with(&self, record: &Record, codecs: &CodecRegistry) -> Result<Value> {
+        let ctx = Context {
+            record,
+            codecs,
+            payload: OnceCell::new(),
+        };
+        eval(&self.root, &ctx)
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        self.matches_with(record, CodecRegistry::shared())
+    }
+
+    pub fn matches_with(&self, record: &Record, codecs: &CodecRegistry) -> bool {
+        matches!(self.evaluate_with(record, codecs), Ok(Value::Bool(true)))
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Expression").field(&self.source).finish()
+    }
+}
+
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Serialize for Expression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Expression::compile(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+// ============================================================================
+// LEXER
+// ============================================================================
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    True,
+    False,
+    Null,
+    In,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Dot,
+    Question,
+    Colon,
+    Coalesce,
+    Or,
+    And,
+    Not,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "identifier '{}'", name),
+            Token::Int(i) => write!(f, "'{}'", i),
+            Token::Float(x) => write!(f, "'{}'", x),
+            Token::Str(s) => write!(f, "string {:?}", s),
+            Token::End => write!(f, "end of expression"),
+            other => {
+                let symbol = match other {
+                    Token::True => "true",
+                    Token::False => "false",
+                    Token::Null => "null",
+                    Token::In => "in",
+                    Token::LParen => "(",
+                    Token::RParen => ")",
+                    Token::LBracket => "[",
+                    Token::RBracket => "]",
+                    Token::Comma => ",",
+                    Token::Dot => ".",
+                    Token::Question => "?",
+                    Token::Colon => ":",
+                    Token::Coalesce => "??",
+                    Token::Or => "||",
+                    Token::And => "&&",
+                    Token::Not => "!",
+                    Token::Eq => "==",
+                    Token::Ne => "!=",
+                    Token::Lt => "<",
+                    Token::Le => "<=",
+                    Token::Gt => ">",
+                    Token::Ge => ">=",
+                    Token::Plus => "+",
+                    Token::Minus => "-",
+                    Token::Star => "*",
+                    Token::Slash => "/",
+                    Token::Percent => "%",
+                    _ => unreachable!(),
+                };
+                write!(f, "'{}'", symbol)
+            }
+        }
+    }
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            chars: source.char_indices().peekable(),
+        }
+    }
+
+    fn tokenize(mut self) -> Result<Vec<(Token, usize)>, ExprError> {
+        let mut tokens = Vec::new();
+
+        while let Some(&(start, c)) = self.chars.peek() {
+            if c.is_whitespace() {
+                self.chars.next();
+                continue;
+            }
+
+            let token = match c {
+                '0'..='9' => self.number(start)?,
+                '"' | '\'' => self.string(start, c)?,
+                c if c.is_alphabetic() || c == '_' => self.ident(),
+                _ => self.symbol(start, c)?,
+            };
+            tokens.push((token, start));
+        }
+
+        tokens.push((Token::End, self.source.len()));
+        Ok(tokens)
+    }
+
+    fn next_if(&mut self, expected: char) -> bool {
+        if self.chars.peek().map(|&(_, c)| c) == Some(expected) {
+            self.chars.next();
+            return true;
+        }
+        false
+    }
+
+    fn symbol(&mut self, start: usize, c: char) -> Result<Token, ExprError> {
+        self.chars.next();
+        let token = match c {
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            ',' => Token::Comma,
+            '.' => Token::Dot,
+            ':' => Token::Colon,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Star,
+            '/' => Token::Slash,
+            '%' => Token::Percent,
+            '?' if self.next_if('?') => Token::Coalesce,
+            '?' => Token::Question,
+            '|' if self.next_if('|') => Token::Or,
+            '&' if self.next_if('&') => Token::And,
+            '=' if self.next_if('=') => Token::Eq,
+            '!' if self.next_if('=') => Token::Ne,
+            '!' => Token::Not,
+            '<' if self.next_if('=') => Token::Le,
+            '<' => Token::Lt,
+            '>' if self.next_if('=') => Token::Ge,
+            '>' => Token::Gt,
+            '=' => {
+                return Err(ExprError::new(self.source, start, "unexpected '=', use '==' for equality"))
+            }
+            _ => return Err(ExprError::new(self.source, start, format!("unexpected character '{}'", c))),
+        };
+        Ok(token)
+    }
+
+    fn number(&mut self, start: usize) -> Result<Token, ExprError> {
+        let mut end = start;
+        let mut is_float = false;
+
+        while let Some(&(i, c)) = self.chars.peek() {
+            if c.is_ascii_digit() {
+                end = i + 1;
+                self.chars.next();
+            } else if c == '.' && !is_float {
+                let mut lookahead = self.source[i + 1..].chars();
+                if !lookahead.next().is_some_and(|n| n.is_ascii_digit()) {
+                    break;
+                }
+                is_float = true;
+                end = i + 1;
+                self.chars.next();
+            } else if (c == 'e' || c == 'E') && !self.source[start..end].contains(['e', 'E']) {
+                is_float = true;
+                end = i + 1;
+                self.chars.next();
+                if let Some(&(j, sign)) = self.chars.peek() {
+                    if sign == '+' || sign == '-' {
+                        end = j + 1;
+                        self.chars.next();
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+
+        let text = &self.source[start..end];
+        if is_float {
+            text.parse::<f64>()
+                .map(Token::Float)
+                .map_err(|_| ExprError::new(self.source, start, format!("invalid number '{}'", text)))
+        } else {
+            text.parse::<i64>()
+                .map(Token::Int)
+                .map_err(|_| ExprError::new(self.source, start, format!("integer '{}' out of range", text)))
+        }
+    }
+
+    fn string(&mut self, start: usize, quote: char) -> Result<Token, ExprError> {
+        self.chars.next();
+        let mut value = String::new();
+
+        while let Some((i, c)) = self.chars.next() {
+            match c {
+                c if c == quote => return Ok(Token::Str(value)),
+                '\\' => {
+                    let Some((_, escaped)) = self.chars.next() else {
+                        break;
+                    };
+                    value.push(match escaped {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        '\\' | '"' | '\'' => escaped,
+                        _ => {
+                            return Err(ExprError::new(
+                                self.source,
+                                i,
+                                format!("unknown escape sequence '\\{}'", escaped),
+                            ))
+                        }
+                    });
+                }
+                _ => value.push(c),
+            }
+        }
+
+        Err(ExprError::new(self.source, start, "unterminated string literal"))
+    }
+
+    fn ident(&mut self) -> Token {
+        let mut name = String::new();
+        while let Some(&(_, c)) = self.chars.peek() {
+            if c.is_alphanumeric() || c == '_' {
+                name.push(c);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+
+        match name.as_str() {
+            "true" => Token::True,
+            "false" => Token::False,
+            "null" => Token::Null,
+            "in" => Token::In,
+            _ => Token::Ident(name),
+        }
+    }
+}
+
+// ============================================================================
+// PARSER
+// ============================================================================
+
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    RecordType,
+    Key,
+    Metadata,
+    Payload,
+    EventTime,
+    IngestionTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Function {
+    Size,
+    StartsWith,
+    EndsWith,
+    Contains,
+    Lower,
+    Upper,
+    Trim,
+    Int,
+    Double,
+    String,
+    Timestamp,
+    Duration,
+    Now,
+}
+
+impl Function {
+    fn lookup(name: &str) -> Option<(Function, usize)> {
+        let entry = match name {
+            "size" => (Function::Size, 1),
+            "startsWith" => (Function::StartsWith, 2),
+            "endsWith" => (Function::EndsWith, 2),
+            "contains" => (Function::Contains, 2),
+            "lower" => (Function::Lower, 1),
+            "upper" => (Function::Upper, 1),
+            "trim" => (Function::Trim, 1),
+            "int" => (Function::Int, 1),
+            "double" => (Function::Double, 1),
+            "string" => (Function::String, 1),
+            "timestamp" => (Function::Timestamp, 1),
+            "duration" => (Function::Duration, 1),
+            "now" => (Function::Now, 0),
+            _ => return None,
+        };
+        Some(entry)
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Literal(Value),
+    Variable(Variable),
+    List(Vec<Node>),
+    Field(Box<Node>, String),
+    Index(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Coalesce(Box<Node>, Box<Node>),
+    Conditional(Box<Node>, Box<Node>, Box<Node>),
+    Has(Box<Node>),
+    Matches(Box<Node>, Regex),
+    Call(Function, Vec<Node>),
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn advance(&mut self) -> (Token, usize) {
+        let token = self.tokens[self.pos].clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == token {
+            self.advance();
+            return true;
+        }
+        false
+    }
+
+    fn error(&self, offset: usize, message: impl Into<String>) -> ExprError {
+        ExprError::new(self.source, offset, message)
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), ExprError> {
+        if self.eat(&token) {
+            return Ok(());
+        }
+        Err(self.error(
+            self.offset(),
+            format!("expected {}, found {}", token, self.peek()),
+        ))
+    }
+
+    fn expect_end(&mut self) -> Result<(), ExprError> {
+        if *self.peek() == Token::End {
+            return Ok(());
+        }
+        Err(self.error(self.offset(), format!("unexpected {}", self.peek())))
+    }
+
+    fn parse_expr(&mut self) -> Result<Node, ExprError> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING_DEPTH {
+            return Err(self.error(
+                self.offset(),
+                format!("expression nested deeper than {} levels", MAX_NESTING_DEPTH),
+            ));
+        }
+
+        let result = self.parse_conditional();
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_conditional(&mut self) -> Result<Node, ExprError> {
+        let condition = self.parse_coalesce()?;
+        if !self.eat(&Token::Question) {
+            return Ok(condition);
+        }
+
+        let then = self.parse_expr()?;
+        self.expect(Token::Colon)?;
+        let otherwise = self.parse_expr()?;
+        Ok(Node::Conditional(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    fn parse_coalesce(&mut self) -> Result<Node, ExprError> {
+        let mut left = self.parse_or()?;
+        while self.eat(&Token::Coalesce) {
+            let right = self.parse_or()?;
+            left = Node::Coalesce(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_or(&mut self) -> Result<Node, ExprError> {
+        let mut left = self.parse_and()?;
+        while self.eat(&Token::Or) {
+            let right = self.parse_and()?;
+            left = Node::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, ExprError> {
+        let mut left = self.parse_relation()?;
+        while self.eat(&Token::And) {
+            let right = self.parse_relation()?;
+            left = Node::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_relation(&mut self) -> Result<Node, ExprError> {
+        let left = self.parse_additive()?;
+        let op = match self.peek() {
+            Token::Eq => BinaryOp::Eq,
+            Token::Ne => BinaryOp::Ne,
+            Token::Lt => BinaryOp::Lt,
+            Token::Le => BinaryOp::Le,
+            Token::Gt => BinaryOp::Gt,
+            Token::Ge => BinaryOp::Ge,
+            Token::In => BinaryOp::In,
+            _ => return Ok(left),
+        };
+        self.advance();
+
+        let right = self.parse_additive()?;
+        if matches!(
+            self.peek(),
+            Token::Eq | Token::Ne | Token::Lt | Token::Le | Token::Gt | Token::Ge | Token::In
+        ) {
+            return Err(self.error(
+                self.offset(),
+                "comparison operators cannot be chained, use '&&'",
+            ));
+        }
+
+        Ok(Node::Binary(op, Box::new(left), Box::new(right)))
+    }
+
+    fn parse_additive(&mut self) -> Result<Node, ExprError> {
+        let mut left = self.parse_multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Token::Plus => BinaryOp::Add,
+                Token::Minus => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.advance();
+            let right = self.parse_multiplicative()?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Node, ExprError> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Token::Star => BinaryOp::Mul,
+                Token::Slash => BinaryOp::Div,
+                Token::Percent => BinaryOp::Rem,
+                _ => return Ok(left),
+            };
+            self.advance();
+            let right = self.parse_unary()?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Node, ExprError> {
+        if self.eat(&Token::Not) {
+            return Ok(Node::Not(Box::new(self.parse_unary_nested()?)));
+        }
+        if self.eat(&Token::Minus) {
+            return match self.parse_unary_nested()? {
+                Node::Literal(Value::Int(i)) => Ok(Node::Literal(Value::Int(-i))),
+                Node::Literal(Value::Float(f)) => Ok(Node::Literal(Value::Float(-f))),
+                operand => Ok(Node::Negate(Box::new(operand))),
+            };
+        }
+        self.parse_postfix()
+    }
+
+    fn parse_unary_nested(&mut self) -> Result<Node, ExprError> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING_DEPTH {
+            return Err(self.error(
+                self.offset(),
+                format!("expression nested deeper than {} levels", MAX_NESTING_DEPTH),
+            ));
+        }
+        let result = self.parse_unary();
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_postfix(&mut self) -> Result<Node, ExprError> {
+        let mut node = self.parse_primary()?;
+
+        loop {
+            if self.eat(&Token::Dot) {
+                let offset = self.offset();
+                let name = match self.advance().0 {
+                    Token::Ident(name) => name,
+                    other => {
+                        return Err(self.error(offset, format!("expected field name, found {}", other)))
+                    }
+                };
+
+                if *self.peek() == Token::LParen {
+                    self.advance();
+                    let mut args = vec![node];
+                    args.extend(self.parse_args()?);
+                    node = self.build_call(&name, args, offset)?;
+                } else {
+                    node = Node::Field(Box::new(node), name);
+                }
+            } else if self.eat(&Token::LBracket) {
+                let index = self.parse_expr()?;
+                self.expect(Token::RBracket)?;
+                node = Node::Index(Box::new(node), Box::new(index));
+            } else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn parse_args(&mut self) -> Result<Vec<Node>, ExprError> {
+        let mut args = Vec::new();
+        if self.eat(&Token::RParen) {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.parse_expr()?);
+            if self.eat(&Token::RParen) {
+                return Ok(args);
+            }
+            self.expect(Token::Comma)?;
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Node, ExprError> {
+        let (token, offset) = self.advance();
+        match token {
+            Token::Int(i) => Ok(Node::Literal(Value::Int(i))),
+            Token::Float(f) => Ok(Node::Literal(Value::Float(f))),
+            Token::Str(s) => Ok(Node::Literal(Value::String(s))),
+            Token::True => Ok(Node::Literal(Value::Bool(true))),
+            Token::False => Ok(Node::Literal(Value::Bool(false))),
+            Token::Null => Ok(Node::Literal(Value::Null)),
+            Token::LParen => {
+                let inner = self.parse_expr()?;
+                self.expect(Token::RParen)?;
+                Ok(inner)
+            }
+            Token::LBracket => {
+                let mut items = Vec::new();
+                if !self.eat(&Token::RBracket) {
+                    loop {
+                        items.push(self.parse_expr()?);
+                        if self.eat(&Token::RBracket) {
+                            break;
+                        }
+                        self.expect(Token::Comma)?;
+                    }
+                }
+                Ok(Node::List(items))
+            }
+            Token::Ident(name) => {
+                if self.eat(&Token::LParen) {
+                    let args = self.parse_args()?;
+                    return self.build_call(&name, args, offset);
+                }
+
+                let variable = match name.as_str() {
+                    "record_type" => Variable::RecordType,
+                    "key" => Variable::Key,
+                    "metadata" => Variable::Metadata,
+                    "payload" => Variable::Payload,
+                    "event_time" => Variable::EventTime,
+                    "ingestion_time" => Variable::IngestionTime,
+                    _ => return Err(self.error(offset, format!("unknown identifier '{}'", name))),
+                };
+                Ok(Node::Variable(variable))
+            }
+            other => Err(self.error(offset, format!("unexpected {}", other))),
+        }
+    }
+
+    fn build_call(&self, name: &str, mut args: Vec<Node>, offset: usize) -> Result<Node, ExprError> {
+        match name {
+            "has" => {
+                if args.len() != 1 || !matches!(args[0], Node::Field(..) | Node::Index(..)) {
+                    return Err(self.error(offset, "has() expects a single field selection"));
+                }
+                return Ok(Node::Has(Box::new(args.remove(0))));
+            }
+            "matches" => {
+                if args.len() != 2 {
+                    return Err(self.error(offset, "matches() expects 2 arguments"));
+                }
+                let Node::Literal(Value::String(pattern)) = &args[1] else {
+                    return Err(self.error(offset, "matches() pattern must be a string literal"));
+                };
+                let regex = RegexBuilder::new(pattern)
+                    .size_limit(REGEX_SIZE_LIMIT)
+                    .build()
+                    .map_err(|e| self.error(offset, format!("invalid regex: {}", e)))?;
+                return Ok(Node::Matches(Box::new(args.remove(0)), regex));
+            }
+            _ => {}
+        }
+
+        let Some((function, arity)) = Function::lookup(name) else {
+            return Err(self.error(offset, format!("unknown function '{}'", name)));
+        };
+        if args.len() != arity {
+            return Err(self.error(
+                offset,
+                format!("{}() expects {} argument(s), got {}", name, arity, args.len()),
+            ));
+        }
+
+        if matches!(function, Function::Timestamp | Function::Duration) {
+            if let Node::Literal(literal) = &args[0] {
+                return call(function, std::slice::from_ref(literal))
+                    .map(Node::Literal)
+                    .map_err(|e| self.error(offset, e.to_string()));
+            }
+        }
+
+        Ok(Node::Call(function, args))
+    }
+}
+
+// ============================================================================
+// EVALUATION
+// ============================================================================
+
+struct Context<'a> {
+    record: &'a Record,
+    codecs: &'a CodecRegistry,
+    payload: OnceCell<Value>,
+}
+
+impl Context<'_> {
+    fn payload(&self) -> &Value {
+        self.payload.get_or_init(|| {
+            self.codecs
+                .decode(self.record)
+                .map(Value::from)
+                .unwrap_or(Value::Null)
+        })
+    }
+}
+
+fn eval(node: &Node, ctx: &Context<'_>) -> Result<Value> {
+    match node {
+        Node::Literal(value) => Ok(value.clone()),
+        Node::Variable(variable) => Ok(resolve(*variable, ctx)),
+        Node::List(items) => Ok(Value::List(
+            items.iter().map(|n| eval(n, ctx)).collect::<Result<_>>()?,
+        )),
+        Node::Field(target, name) => select_field(eval(target, ctx)?, name),
+        Node::Index(target, index) => select_index(eval(target, ctx)?, eval(index, ctx)?),
+        Node::Not(operand) => match eval(operand, ctx)? {
+            Value::Bool(b) => Ok(Value::Bool(!b)),
+            other => bail!("'!' expects bool, got {}", other.type_name()),
+        },
+        Node::Negate(operand) => match eval(operand, ctx)? {
+            Value::Int(i) => i.checked_neg().map(Value::Int).ok_or_else(|| anyhow!("integer overflow")),
+            Value::Float(f) => Ok(Value::Float(-f)),
+            Value::Duration(d) => Ok(Value::Duration(-d)),
+            other => bail!("'-' expects a number, got {}", other.type_name()),
+        },
+        Node::And(left, right) => {
+            if !expect_bool(eval(left, ctx)?)? {
+                return Ok(Value::Bool(false));
+            }
+            Ok(Value::Bool(expect_bool(eval(right, ctx)?)?))
+        }
+        Node::Or(left, right) => {
+            if expect_bool(eval(left, ctx)?)? {
+                return Ok(Value::Bool(true));
+            }
+            Ok(Value::Bool(expect_bool(eval(right, ctx)?)?))
+        }
+        Node::Coalesce(left, right) => match eval(left, ctx)? {
+            Value::Null => eval(right, ctx),
+            value => Ok(value),
+        },
+        Node::Conditional(condition, then, otherwise) => {
+            if expect_bool(eval(condition, ctx)?)? {
+                eval(then, ctx)
+            } else {
+                eval(otherwise, ctx)
+            }
+        }
+        Node::Has(selection) => Ok(Value::Bool(!matches!(eval(selection, ctx)?, Value::Null))),
+        Node::Matches(target, regex) => match eval(target, ctx)? {
+            Value::String(s) => Ok(Value::Bool(regex.is_match(&s))),
+            Value::Null => Ok(Value::Bool(false)),
+            other => bail!("matches() expects string, got {}", other.type_name()),
+        },
+        Node::Binary(op, left, right) => binary(*op, eval(left, ctx)?, eval(right, ctx)?),
+        Node::Call(function, args) => {
+            let args: Vec<Value> = args.iter().map(|n| eval(n, ctx)).collect::<Result<_>>()?;
+            call(*function, &args)
+        }
+    }
+}
+
+fn resolve(variable: Variable, ctx: &Context<'_>) -> Value {
+    let record = ctx.record;
+    match variable {
+        Variable::RecordType => Value::String(record.record_type.clone()),
+        Variable::Key => Value::String(String::from_utf8_lossy(&record.key).into_owned()),
+        Variable::Metadata => Value::Map(
+            record
+                .metadata
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                .collect(),
+        ),
+        Variable::Payload => ctx.payload().clone(),
+        Variable::EventTime => timestamp_value(record.event_time.as_ref()),
+        Variable::IngestionTime => timestamp_value(record.ingestion_time.as_ref()),
+    }
+}
+
+fn timestamp_value(ts: Option<&prost_types::Timestamp>) -> Value {
+    ts.and_then(|ts| Utc.timestamp_opt(ts.seconds, ts.nanos.max(0) as u32).single())
+        .map(Value::Timestamp)
+        .unwrap_or(Value::Null)
+}
+
+fn expect_bool(value: Value) -> Result<bool> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        other => bail!("expected bool, got {}", other.type_name()),
+    }
+}
+
+fn select_field(target: Value, name: &str) -> Result<Value> {
+    match target {
+        Value::Map(mut fields) => Ok(fields.remove(name).unwrap_or(Value::Null)),
+        Value::Null => Ok(Value::Null),
+        other => bail!("cannot select field '{}' on {}", name, other.type_name()),
+    }
+}
+
+fn select_index(target: Value, index: Value) -> Result<Value> {
+    match (target, index) {
+        (Value::List(mut items), Value::Int(i)) => {
+            let idx = if i < 0 { items.len() as i64 + i } else { i };
+            if idx < 0 || idx as usize >= items.len() {
+                return Ok(Value::Null);
+            }
+            Ok(items.swap_remove(idx as usize))
+        }
+        (Value::Map(mut fields), Value::String(key)) => Ok(fields.remove(&key).unwrap_or(Value::Null)),
+        (Value::Null, _) => Ok(Value::Null),
+        (target, index) => bail!("cannot index {} with {}", target.type_name(), index.type_name()),
+    }
+}
+
+fn binary(op: BinaryOp, left: Value, right: Value) -> Result<Value> {
+    match op {
+        BinaryOp::Eq => Ok(Value::Bool(left.loose_eq(&right))),
+        BinaryOp::Ne => Ok(Value::Bool(!left.loose_eq(&right))),
+        BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+            if matches!(left, Value::Null) || matches!(right, Value::Null) {
+                return Ok(Value::Bool(false));
+            }
+            let ordering = left.compare(&right)?;
+            Ok(Value::Bool(match op {
+                BinaryOp::Lt => ordering == Ordering::Less,
+                BinaryOp::Le => ordering != Ordering::Greater,
+                BinaryOp::Gt => ordering == Ordering::Greater,
+                _ => ordering != Ordering::Less,
+            }))
+        }
+        BinaryOp::In => match right {
+            Value::List(items) => Ok(Value::Bool(items.iter().any(|item| item.loose_eq(&left)))),
+            Value::Map(fields) => match left {
+                Value::String(key) => Ok(Value::Bool(fields.contains_key(&key))),
+                other => bail!("map keys are strings, got {}", other.type_name()),
+            },
+            Value::Null => Ok(Value::Bool(false)),
+            other => bail!("'in' expects list or map, got {}", other.type_name()),
+        },
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => {
+            arithmetic(op, left, right)
+        }
+    }
+}
+
+fn arithmetic(op: BinaryOp, left: Value, right: Value) -> Result<Value> {
+    let overflow = || anyhow!("integer overflow");
+
+    match (op, left, right) {
+        (_, Value::Int(_), Value::Int(0)) if matches!(op, BinaryOp::Div | BinaryOp::Rem) => {
+            bail!("division by zero")
+        }
+        (BinaryOp::Add, Value::Int(a), Value::Int(b)) => a.checked_add(b).map(Value::Int).ok_or_else(overflow),
+        (BinaryOp::Sub, Value::Int(a), Value::Int(b)) => a.checked_sub(b).map(Value::Int).ok_or_else(overflow),
+        (BinaryOp::Mul, Value::Int(a), Value::Int(b)) => a.checked_mul(b).map(Value::Int).ok_or_else(overflow),
+        (BinaryOp::Div, Value::Int(a), Value::Int(b)) => a.checked_div(b).map(Value::Int).ok_or_else(overflow),
+        (BinaryOp::Rem, Value::Int(a), Value::Int(b)) => a.checked_rem(b).map(Value::Int).ok_or_else(overflow),
+        (BinaryOp::Add, Value::String(a), Value::String(b)) => Ok(Value::String(a + &b)),
+        (BinaryOp::Add, Value::List(mut a), Value::List(b)) => {
+            a.extend(b);
+            Ok(Value::List(a))
+        }
+        (BinaryOp::Add, Value::Timestamp(t), Value::Duration(d))
+        | (BinaryOp::Add, Value::Duration(d), Value::Timestamp(t)) => t
+            .checked_add_signed(d)
+            .map(Value::Timestamp)
+            .ok_or_else(|| anyhow!("timestamp overflow")),
+        (BinaryOp::Sub, Value::Timestamp(t), Value::Duration(d)) => t
+            .checked_sub_signed(d)
+            .map(Value::Timestamp)
+            .ok_or_else(|| anyhow!("timestamp overflow")),
+        (BinaryOp::Sub, Value::Timestamp(a), Value::Timestamp(b)) => Ok(Value::Duration(a - b)),
+        (BinaryOp::Add, Value::Duration(a), Value::Duration(b)) => {
+            a.checked_add(&b).map(Value::Duration).ok_or_else(|| anyhow!("duration overflow"))
+        }
+        (BinaryOp::Sub, Value::Duration(a), Value::Duration(b)) => {
+            a.checked_sub(&b).map(Value::Duration).ok_or_else(|| anyhow!("duration overflow"))
+        }
+        (op, left, right) => match (left.as_f64(), right.as_f64()) {
+            (Some(a), Some(b)) => Ok(Value::Float(match op {
+                BinaryOp::Add => a + b,
+                BinaryOp::Sub => a - b,
+                BinaryOp::Mul => a * b,
+                BinaryOp::Div => a / b,
+                _ => a % b,
+            })),
+            _ => bail!(
+                "unsupported operand types {} and {}",
+                left.type_name(),
+                right.type_name()
+            ),
+        },
+    }
+}
+
+fn call(function: Function, args: &[Value]) -> Result<Value> {
+    match (function, args) {
+        (Function::Size, [Value::String(s)]) => Ok(Value::Int(s.chars().count() as i64)),
+        (Function::Size, [Value::List(items)]) => Ok(Value::Int(items.len() as i64)),
+        (Function::Size, [Value::Map(fields)]) => Ok(Value::Int(fields.len() as i64)),
+        (Function::Size, [Value::Null]) => Ok(Value::Int(0)),
+        (Function::StartsWith, [Value::String(s), Value::String(p)]) => Ok(Value::Bool(s.starts_with(p.as_str()))),
+        (Function::EndsWith, [Value::String(s), Value::String(p)]) => Ok(Value::Bool(s.ends_with(p.as_str()))),
+        (Function::Contains, [Value::String(s), Value::String(p)]) => Ok(Value::Bool(s.contains(p.as_str()))),
+        (Function::Contains, [Value::List(items), needle]) => {
+            Ok(Value::Bool(items.iter().any(|item| item.loose_eq(needle))))
+        }
+        (Function::StartsWith | Function::EndsWith | Function::Contains, [Value::Null, _]) => {
+            Ok(Value::Bool(false))
+        }
+        (Function::Lower, [Value::String(s)]) => Ok(Value::String(s.to_lowercase())),
+        (Function::Upper, [Value::String(s)]) => Ok(Value::String(s.to_uppercase())),
+        (Function::Trim, [Value::String(s)]) => Ok(Value::String(s.trim().to_string())),
+        (Function::Lower | Function::Upper | Function::Trim, [Value::Null]) => Ok(Value::Null),
+        (Function::Int, [value]) => to_int(value),
+        (Function::Double, [value]) => to_double(value),
+        (Function::String, [value]) => to_string(value).map(Value::String),
+        (Function::Timestamp, [Value::String(s)]) => DateTime::parse_from_rfc3339(s)
+            .map(|t| Value::Timestamp(t.with_timezone(&Utc)))
+            .map_err(|e| anyhow!("invalid timestamp '{}': {}", s, e)),
+        (Function::Timestamp, [Value::Int(secs)]) => Utc
+            .timestamp_opt(*secs, 0)
+            .single()
+            .map(Value::Timestamp)
+            .ok_or_else(|| anyhow!("timestamp out of range: {}", secs)),
+        (Function::Timestamp, [Value::Timestamp(t)]) => Ok(Value::Timestamp(*t)),
+        (Function::Duration, [Value::String(s)]) => parse_duration(s).map(Value::Duration),
+        (Function::Duration, [Value::Int(secs)]) => Duration::try_seconds(*secs)
+            .map(Value::Duration)
+            .ok_or_else(|| anyhow!("duration out of range: {}s", secs)),
+        (Function::Timestamp | Function::Duration, [Value::Null]) => Ok(Value::Null),
+        (Function::Now, []) => Ok(Value::Timestamp(Utc::now())),
+        (function, args) => bail!(
+            "{:?}() does not accept ({})",
+            function,
+            args.iter().map(Value::type_name).collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+fn to_int(value: &Value) -> Result<Value> {
+    match value {
+        Value::Int(i) => Ok(Value::Int(*i)),
+        Value::Float(f) if f.is_finite() && f.abs() < i64::MAX as f64 => Ok(Value::Int(f.trunc() as i64)),
+        Value::String(s) => s
+            .trim()
+            .parse::<i64>()
+            .map(Value::Int)
+            .map_err(|_| anyhow!("cannot convert '{}' to int", s)),
+        Value::Bool(b) => Ok(Value::Int(*b as i64)),
+        Value::Timestamp(t) => Ok(Value::Int(t.timestamp())),
+        Value::Null => Ok(Value::Null),
+        other => bail!("cannot convert {} to int", other.type_name()),
+    }
+}
+
+fn to_double(value: &Value) -> Result<Value> {
+    match value {
+        Value::Int(i) => Ok(Value::Float(*i as f64)),
+        Value::Float(f) => Ok(Value::Float(*f)),
+        Value::String(s) => s
+            .trim()
+            .parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| anyhow!("cannot convert '{}' to double", s)),
+        Value::Null => Ok(Value::Null),
+        other => bail!("cannot convert {} to double", other.type_name()),
+    }
+}
+
+fn to_string(value: &Value) -> Result<String> {
+    match value {
+        Value::Null => Ok("null".to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Int(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::String(s) => Ok(s.clone()),
+        Value::Timestamp(t) => Ok(t.to_rfc3339()),
+        Value::Duration(d) => Ok(format!("{}ms", d.num_milliseconds())),
+        other => bail!("cannot convert {} to string", other.type_name()),
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || anyhow!("invalid duration '{}', expected e.g. '1h30m' or '500ms'", s);
+    let mut total = Duration::zero();
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(invalid());
+        }
+        let amount: i64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let part = match &rest[..unit_len] {
+            "ms" => Duration::try_milliseconds(amount),
+            "s" => Duration::try_seconds(amount),
+            "m" => Duration::try_minutes(amount),
+            "h" => Duration::try_hours(amount),
+            "d" => Duration::try_days(amount),
+            _ => return Err(invalid()),
+        }
+        .ok_or_else(|| anyhow!("duration out of range: '{}'", s))?;
+        total = total.checked_add(&part).ok_or_else(invalid)?;
+        rest = &rest[unit_len..];
+    }
+
+    Ok(total)
+}
@@ -0,0 +1,140 @@
+use super::codec::CodecRegistry;
+use super::dag::{FanInConfig, FanInSource, FieldMapping};
+use super::mapping::{map_value, to_timestamp, FieldPath, MappingError};
+use super::watermark::WatermarkTracker;
+use conveyor_etl_proto::common::Record;
+
+/// Watermark tracker over a fan-in stage's sources, with each source's idle
+/// timeout applied.
+pub fn fan_in_tracker(config: &FanInConfig) -> WatermarkTracker {
+    let source_ids: Vec<String> = config.sources.iter().map(|s| s.id.clone()).collect();
+    let allowed_lateness = config
+        .watermark
+        .as_ref()
+        .and_then(|w| w.allowed_lateness)
+        .unwrap_or(std::time::Duration::from_secs(0));
+
+    let mut tracker = WatermarkTracker::new(source_ids, allowed_lateness);
+
+    for source in &config.sources {
+        if let Some(wm) = &source.watermark {
+            if let Some(timeout) = wm.idle_timeout {
+                tracker.set_idle_timeout(&source.id, timeout);
+            }
+        }
+    }
+
+    tracker
+}
+
+/// Event time of `record` in epoch millis, read from `field` in the payload
+/// when set and falling back to `Record.event_time`. Numbers are taken as
+/// epoch millis; strings may be RFC 3339 or ISO 8601 timestamps.
+pub fn extract_event_time(codecs: &CodecRegistry, record: &Record, field: Option<&str>) -> Option<i64> {
+    let from_payload = field.and_then(|field| {
+        let path = FieldPath::parse(field).ok()?;
+        let payload = codecs.decode(record).ok()?;
+        let ts = to_timestamp(path.get(&payload)?).ok()?;
+        Some(ts.timestamp_millis())
+    });
+
+    from_payload.or_else(|| record.event_time.as_ref().and_then(timestamp_millis))
+}
+
+/// Checks a record arriving through `source` against the fan-in watermark.
+/// The record's event time is stamped on it and, when it is on time,
+/// advances the source's watermark. Returns the watermark a late record is
+/// behind. Records without an event time are always on time.
+pub fn align_fan_in_record(
+    codecs: &CodecRegistry,
+    tracker: &mut WatermarkTracker,
+    source: &FanInSource,
+    record: &mut Record,
+) -> Option<i64> {
+    let event_time_field = source
+        .watermark
+        .as_ref()
+        .map(|w| w.event_time_field.as_str())
+        .filter(|f| !f.is_empty());
+    let event_time = extract_event_time(codecs, record, event_time_field)?;
+    record.event_time = Some(timestamp_from_millis(event_time));
+
+    if tracker.is_late(event_time) {
+        Some(tracker.combined_watermark())
+    } else {
+        tracker.update(&source.id, event_time);
+        None
+    }
+}
+
+/// Rewrites a record's payload with a fan-in source's or fan-out sink's
+/// field mappings. Records pass unchanged when there are none.
+pub fn apply_field_mappings(
+    codecs: &CodecRegistry,
+    mut record: Record,
+    mappings: &[FieldMapping],
+) -> Result<Record, MappingError> {
+    if mappings.is_empty() {
+        return Ok(record);
+    }
+
+    let original = if record.payload.is_empty() {
+        serde_json::Value::Object(Default::default())
+    } else {
+        codecs
+            .decode(&record)
+            .map_err(|e| MappingError::new("", format!("{:#}", e)))?
+    };
+    let mapped = map_value(&original, mappings)?;
+    codecs
+        .encode(&mut record, &mapped)
+        .map_err(|e| MappingError::new("", format!("failed to encode payload: {:#}", e)))?;
+    Ok(record)
+}
+
+/// Marks a record that arrived behind a fan-in stage's watermark.
+pub fn late_record(mut record: Record, fan_in_stage_id: &str, watermark: i64) -> Record {
+    record
+        .metadata
+        .insert("_late_fan_in_stage".to_string(), fan_in_stage_id.to_string());
+    record
+        .metadata
+        .insert("_late_watermark".to_string(), watermark.to_string());
+    record
+}
+
+/// Marks a record whose field mappings failed for the dead-letter stage.
+pub fn mapping_failure(
+    mut record: Record,
+    pipeline_id: &str,
+    stage_id: &str,
+    error: &MappingError,
+) -> Record {
+    record
+        .metadata
+        .insert("_dlq_error_code".to_string(), "MALFORMED_RECORD".to_string());
+    record
+        .metadata
+        .insert("_dlq_error_message".to_string(), error.to_string());
+    record
+        .metadata
+        .insert("_dlq_failed_stage".to_string(), stage_id.to_string());
+    record
+        .metadata
+        .insert("_dlq_pipeline".to_string(), pipeline_id.to_string());
+    record
+}
+
+/// Epoch millis of `ts`, or `None` when they don't fit in an `i64`.
+pub fn timestamp_millis(ts: &prost_types::Timestamp) -> Option<i64> {
+    ts.seconds
+        .checked_mul(1000)?
+        .checked_add(i64::from(ts.nanos) / 1_000_000)
+}
+
+fn timestamp_from_millis(millis: i64) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: millis.div_euclid(1000),
+        nanos: (millis.rem_euclid(1000) * 1_000_000) as i32,
+    }
+}
@@ -1,15 +1,40 @@
 mod engine;
 mod dag;
+mod fan;
+mod lookup;
 mod matcher;
+mod expr;
+mod plan;
+mod mapping;
+mod codec;
+mod schema;
+mod partition;
 pub mod watermark;
 #[cfg(test)]
 mod tests;
 
-pub use engine::{LookupResult, RoutingEngine};
+pub use engine::{LateRecordCounts, RoutingDecision, RoutingEngine};
+pub use fan::{
+    align_fan_in_record, apply_field_mappings, fan_in_tracker, late_record, mapping_failure,
+    timestamp_millis,
+};
+pub use lookup::{extract_lookup_keys, merge_lookup_result, LookupResult};
 pub use dag::{
-    Edge, FanInConfig, FanInSource, FanInWatermark, FanOutConfig, FanOutSink, FieldCastType,
-    FieldMapping, LoadBalanceStrategy, LookupConfig, LookupKeyMapping, LookupMissStrategy,
-    MergeStrategy, Pipeline, PipelineValidationError, ServiceSelector, SourceWatermark, Stage, StageType,
+    CastErrorStrategy, Edge, FanInConfig, FanInSource, FanInWatermark, FanOutConfig, FanOutSink,
+    FieldCastType, FieldMapping, LoadBalanceStrategy, LookupConfig, LookupKeyMapping,
+    LookupMissStrategy, MergeStrategy, Pipeline, PipelineValidationError, RoutingMode,
+    ServiceSelector, SourceWatermark, Stage, StageType,
 };
 pub use matcher::Condition;
+pub use partition::{partition_for, PartitionKey};
+pub use expr::{ExprError, Expression, Value};
+pub use plan::{RoutedBatch, RoutingPlan};
+pub use mapping::{cast_value, FieldPath, MappingError};
+pub use codec::{
+    AvroCodec, CodecRegistry, JsonCodec, MessagePackCodec, PayloadCodec, ProtobufCodec,
+    AVRO_CONTENT_TYPE, CONTENT_TYPE_KEY, JSON_CONTENT_TYPE, MSGPACK_CONTENT_TYPE,
+    PROTOBUF_CONTENT_TYPE,
+};
+pub use schema::{CompatibilityMode, RecordSchema, SchemaDefinition, SchemaFormat};
+pub use conveyor_etl_registry::{TrafficSplit, VersionWeight};
 pub use watermark::{Watermark, WatermarkTracker};
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use super::codec::CodecRegistry;
+use super::dag::{LookupConfig, LookupMissStrategy, MergeStrategy};
+use super::mapping::FieldPath;
+use conveyor_etl_proto::common::Record;
+
+#[derive(Debug, Clone)]
+pub enum LookupResult {
+    Found {
+        data: HashMap<String, Vec<u8>>,
+    },
+    NotFound,
+    Error {
+        message: String,
+    },
+}
+
+/// Applies a lookup's result to the record it was made for, following the
+/// config's merge and miss strategies. `None` means the record is dropped.
+pub fn merge_lookup_result(
+    codecs: &CodecRegistry,
+    mut record: Record,
+    lookup_result: LookupResult,
+    config: &LookupConfig,
+) -> Result<Option<Record>> {
+    match lookup_result {
+        LookupResult::Found { data } => {
+            match config.merge_strategy {
+                MergeStrategy::Merge => {
+                    for (key, value) in data {
+                        let prefixed_key = match &config.output_prefix {
+                            Some(prefix) => format!("{}{}", prefix, key),
+                            None => key,
+                        };
+                        let value_str = String::from_utf8_lossy(&value).to_string();
+                        record.metadata.insert(prefixed_key, value_str);
+                    }
+                    Ok(Some(record))
+                }
+                MergeStrategy::Nest => {
+                    let field_name = config
+                        .output_prefix
+                        .clone()
+                        .unwrap_or_else(|| "lookup".to_string());
+                    let json_data: HashMap<String, String> = data
+                        .into_iter()
+                        .map(|(k, v)| (k, String::from_utf8_lossy(&v).to_string()))
+                        .collect();
+                    let json = serde_json::to_string(&json_data)
+                        .map_err(|e| anyhow::anyhow!("Failed to serialize lookup data: {}", e))?;
+                    record.metadata.insert(field_name, json);
+                    Ok(Some(record))
+                }
+                MergeStrategy::Replace => {
+                    let value: serde_json::Value = data
+                        .into_iter()
+                        .map(|(k, v)| {
+                            let v = String::from_utf8_lossy(&v).to_string();
+                            (k, serde_json::Value::String(v))
+                        })
+                        .collect::<serde_json::Map<_, _>>()
+                        .into();
+                    codecs
+                        .encode(&mut record, &value)
+                        .map_err(|e| anyhow::anyhow!("Failed to serialize lookup data: {}", e))?;
+                    Ok(Some(record))
+                }
+            }
+        }
+        LookupResult::NotFound => {
+            match config.on_miss {
+                LookupMissStrategy::PassThrough => Ok(Some(record)),
+                LookupMissStrategy::Drop => Ok(None),
+                LookupMissStrategy::Error => {
+                    Err(anyhow::anyhow!("Lookup miss: no matching record found"))
+                }
+            }
+        }
+        LookupResult::Error { message } => {
+            Err(anyhow::anyhow!("Lookup error: {}", message))
+        }
+    }
+}
+
+/// Values of the config's key fields, taken from the record's metadata or,
+/// failing that, its payload, by lookup key.
+pub fn extract_lookup_keys(
+    codecs: &CodecRegistry,
+    record: &Record,
+    config: &LookupConfig,
+) -> Result<HashMap<String, Vec<u8>>> {
+    let mut payload = None;
+    let mut keys = HashMap::with_capacity(config.key_fields.len());
+
+    for field in &config.key_fields {
+        if let Some(value) = record.metadata.get(&field.record_field) {
+            keys.insert(field.lookup_key.clone(), value.as_bytes().to_vec());
+            continue;
+        }
+
+        if payload.is_none() {
+            payload = Some(codecs.decode(record)?);
+        }
+        let path = FieldPath::parse(&field.record_field)
+            .map_err(|e| anyhow::anyhow!("Invalid lookup key field: {}", e))?;
+        let value = path
+            .get(payload.as_ref().unwrap())
+            .filter(|v| !v.is_null())
+            .ok_or_else(|| anyhow::anyhow!("Lookup key field missing: {}", field.record_field))?;
+        let bytes = match value {
+            serde_json::Value::String(s) => s.as_bytes().to_vec(),
+            other => other.to_string().into_bytes(),
+        };
+        keys.insert(field.lookup_key.clone(), bytes);
+    }
+
+    Ok(keys)
+}
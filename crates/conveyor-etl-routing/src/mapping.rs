@@ -0,0 +1,454 @@
+use std::fmt;
+
+use base64::Engine as _;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+
+use super::dag::{CastErrorStrategy, FieldCastType, FieldMapping};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappingError {
+    pub field: String,
+    pub message: String,
+}
+
+impl MappingError {
+    pub(crate) fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.field.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "field '{}': {}", self.field, self.message)
+        }
+    }
+}
+
+impl std::error::Error for MappingError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// A location inside a JSON payload, written either as a dotted path
+/// (`customer.address.city`, `items[0].sku`, `headers["x-id"]`) or as a
+/// JSON pointer (`/customer/address/city`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldPath {
+    source: String,
+    segments: Vec<PathSegment>,
+}
+
+impl FieldPath {
+    pub fn parse(path: &str) -> Result<Self, MappingError> {
+        let segments = if let Some(pointer) = path.strip_prefix('/') {
+            parse_pointer(pointer)
+        } else {
+            parse_dotted(path).map_err(|message| MappingError::new(path, message))?
+        };
+
+        if segments.is_empty() {
+            return Err(MappingError::new(path, "empty field path"));
+        }
+
+        Ok(Self {
+            source: path.to_string(),
+            segments,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn get<'a>(&self, root: &'a Value) -> Option<&'a Value> {
+        self.segments.iter().try_fold(root, |current, segment| match (segment, current) {
+            (PathSegment::Key(key), Value::Object(map)) => map.get(key),
+            (PathSegment::Key(key), Value::Array(items)) => {
+                key.parse::<usize>().ok().and_then(|i| items.get(i))
+            }
+            (PathSegment::Index(i), Value::Array(items)) => items.get(*i),
+            _ => None,
+        })
+    }
+
+    pub fn set(&self, root: &mut Value, value: Value) -> Result<(), MappingError> {
+        let mut current = root;
+        for (i, segment) in self.segments.iter().enumerate() {
+            let last = i + 1 == self.segments.len();
+
+            if current.is_null() {
+                *current = match segment {
+                    PathSegment::Index(_) => Value::Array(Vec::new()),
+                    PathSegment::Key(_) => Value::Object(Map::new()),
+                };
+            }
+
+            let index = match segment {
+                PathSegment::Index(i) => Some(*i),
+                PathSegment::Key(key) if current.is_array() => key.parse::<usize>().ok(),
+                PathSegment::Key(_) => None,
+            };
+
+            let slot = match (segment, index, current) {
+                (_, Some(idx), Value::Array(items)) => array_slot(items, idx),
+                (PathSegment::Key(key), None, Value::Object(map)) => {
+                    map.entry(key.clone()).or_insert(Value::Null)
+                }
+                _ => {
+                    return Err(MappingError::new(
+                        self.source.as_str(),
+                        "path traverses a non-container value",
+                    ))
+                }
+            };
+
+            if last {
+                *slot = value;
+                return Ok(());
+            }
+            current = slot;
+        }
+        Ok(())
+    }
+
+    /// Removes the value at this path, leaving parent containers in place.
+    /// Array elements are taken out, shifting later elements down.
+    pub fn remove(&self, root: &mut Value) -> Option<Value> {
+        let (last, parents) = self.segments.split_last()?;
+        let parent = parents.iter().try_fold(root, |current, segment| match (segment, current) {
+            (PathSegment::Key(key), Value::Object(map)) => map.get_mut(key),
+            (PathSegment::Key(key), Value::Array(items)) => {
+                key.parse::<usize>().ok().and_then(|i| items.get_mut(i))
+            }
+            (PathSegment::Index(i), Value::Array(items)) => items.get_mut(*i),
+            _ => None,
+        })?;
+
+        match (last, parent) {
+            (PathSegment::Key(key), Value::Object(map)) => map.remove(key),
+            (PathSegment::Key(key), Value::Array(items)) => key
+                .parse::<usize>()
+                .ok()
+                .filter(|i| *i < items.len())
+                .map(|i| items.remove(i)),
+            (PathSegment::Index(i), Value::Array(items)) if *i < items.len() => {
+                Some(items.remove(*i))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn array_slot(items: &mut Vec<Value>, idx: usize) -> &mut Value {
+    if idx >= items.len() {
+        items.resize(idx + 1, Value::Null);
+    }
+    &mut items[idx]
+}
+
+fn parse_pointer(pointer: &str) -> Vec<PathSegment> {
+    pointer
+        .split('/')
+        .map(|token| PathSegment::Key(token.replace("~1", "/").replace("~0", "~")))
+        .collect()
+}
+
+fn parse_dotted(path: &str) -> Result<Vec<PathSegment>, String> {
+    let mut segments = Vec::new();
+    let mut chars = path.chars();
+    let mut key = String::new();
+    let mut expect_key = true;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => {
+                if key.is_empty() && expect_key {
+                    return Err("empty path segment".to_string());
+                }
+                if !key.is_empty() {
+                    segments.push(PathSegment::Key(std::mem::take(&mut key)));
+                }
+                expect_key = true;
+            }
+            '[' => {
+                if !key.is_empty() {
+                    segments.push(PathSegment::Key(std::mem::take(&mut key)));
+                }
+                let mut inner = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' && !is_open_quote(&inner) {
+                        closed = true;
+                        break;
+                    }
+                    inner.push(c);
+                }
+                if !closed {
+                    return Err("unclosed '['".to_string());
+                }
+                segments.push(parse_bracket(&inner)?);
+                expect_key = false;
+            }
+            ']' => return Err("unexpected ']'".to_string()),
+            c => {
+                key.push(c);
+                expect_key = false;
+            }
+        }
+    }
+
+    if expect_key && !segments.is_empty() {
+        return Err("path ends with '.'".to_string());
+    }
+    if !key.is_empty() {
+        segments.push(PathSegment::Key(key));
+    }
+    Ok(segments)
+}
+
+fn is_open_quote(inner: &str) -> bool {
+    let mut chars = inner.chars();
+    match chars.next() {
+        Some(q @ ('"' | '\'')) => inner.len() == 1 || !inner.ends_with(q),
+        _ => false,
+    }
+}
+
+fn parse_bracket(inner: &str) -> Result<PathSegment, String> {
+    let inner = inner.trim();
+    for quote in ['"', '\''] {
+        if let Some(key) = inner.strip_prefix(quote).and_then(|s| s.strip_suffix(quote)) {
+            return Ok(PathSegment::Key(key.to_string()));
+        }
+    }
+    inner
+        .parse::<usize>()
+        .map(PathSegment::Index)
+        .map_err(|_| format!("invalid array index '{}'", inner))
+}
+
+pub fn cast_value(value: Value, cast: FieldCastType) -> Result<Value, String> {
+    if value.is_null() {
+        return Ok(value);
+    }
+
+    match cast {
+        FieldCastType::String => Ok(Value::String(match value {
+            Value::String(s) => s,
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            other => other.to_string(),
+        })),
+        FieldCastType::Int => {
+            let n = to_i64(&value)?;
+            i32::try_from(n)
+                .map(Value::from)
+                .map_err(|_| format!("{} is out of range for int", n))
+        }
+        FieldCastType::Int64 => to_i64(&value).map(Value::from),
+        FieldCastType::Float | FieldCastType::Float64 => {
+            let f = to_f64(&value)?;
+            serde_json::Number::from_f64(f)
+                .map(Value::Number)
+                .ok_or_else(|| format!("{} is not a finite number", f))
+        }
+        FieldCastType::Bool => to_bool(&value).map(Value::Bool),
+        FieldCastType::Timestamp => to_timestamp(&value)
+            .map(|ts| Value::String(ts.to_rfc3339_opts(SecondsFormat::AutoSi, true))),
+        FieldCastType::Date => {
+            let date = match &value {
+                Value::String(s) => NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d")
+                    .or_else(|_| to_timestamp(&value).map(|ts| ts.date_naive()))?,
+                _ => to_timestamp(&value)?.date_naive(),
+            };
+            Ok(Value::String(date.format("%Y-%m-%d").to_string()))
+        }
+        FieldCastType::Json => match value {
+            Value::String(s) => {
+                serde_json::from_str(&s).map_err(|e| format!("invalid JSON string: {}", e))
+            }
+            other => Ok(other),
+        },
+        FieldCastType::Bytes => {
+            let bytes = match value {
+                Value::String(s) => s.into_bytes(),
+                Value::Array(items) => items
+                    .iter()
+                    .map(|v| {
+                        v.as_u64()
+                            .and_then(|b| u8::try_from(b).ok())
+                            .ok_or_else(|| format!("{} is not a byte", v))
+                    })
+                    .collect::<Result<_, _>>()?,
+                other => return Err(format!("cannot convert {} to bytes", type_name(&other))),
+            };
+            Ok(Value::String(
+                base64::engine::general_purpose::STANDARD.encode(bytes),
+            ))
+        }
+    }
+}
+
+fn to_i64(value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Number(n) => n
+            .as_i64()
+            .or_else(|| n.as_f64().and_then(integral))
+            .ok_or_else(|| format!("{} is not an integer", n)),
+        Value::String(s) => {
+            let s = s.trim();
+            s.parse::<i64>()
+                .ok()
+                .or_else(|| s.parse::<f64>().ok().and_then(integral))
+                .ok_or_else(|| format!("'{}' is not an integer", s))
+        }
+        Value::Bool(b) => Ok(*b as i64),
+        other => Err(format!("cannot convert {} to int", type_name(other))),
+    }
+}
+
+fn integral(f: f64) -> Option<i64> {
+    (f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64).then_some(f as i64)
+}
+
+fn to_f64(value: &Value) -> Result<f64, String> {
+    match value {
+        Value::Number(n) => n.as_f64().ok_or_else(|| format!("{} is not a number", n)),
+        Value::String(s) => s
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("'{}' is not a number", s)),
+        Value::Bool(b) => Ok(if *b { 1.0 } else { 0.0 }),
+        other => Err(format!("cannot convert {} to float", type_name(other))),
+    }
+}
+
+fn to_bool(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::Number(n) => match n.as_f64() {
+            Some(0.0) => Ok(false),
+            Some(1.0) => Ok(true),
+            _ => Err(format!("{} is not a boolean", n)),
+        },
+        Value::String(s) => match s.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "y" | "on" => Ok(true),
+            "false" | "0" | "no" | "n" | "off" => Ok(false),
+            _ => Err(format!("'{}' is not a boolean", s)),
+        },
+        other => Err(format!("cannot convert {} to bool", type_name(other))),
+    }
+}
+
+/// Numbers are read as milliseconds since the Unix epoch; strings may be
+/// RFC 3339 or a naive `YYYY-MM-DD[ T]HH:MM:SS[.f]` in UTC.
+pub(crate) fn to_timestamp(value: &Value) -> Result<DateTime<Utc>, String> {
+    match value {
+        Value::Number(_) => {
+            let millis = to_i64(value)?;
+            DateTime::from_timestamp_millis(millis)
+                .ok_or_else(|| format!("{} is out of range for a timestamp", millis))
+        }
+        Value::String(s) => {
+            let s = s.trim();
+            if let Ok(ts) = DateTime::parse_from_rfc3339(s) {
+                return Ok(ts.with_timezone(&Utc));
+            }
+            ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+                .iter()
+                .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+                .or_else(|| {
+                    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                        .ok()
+                        .and_then(|d| d.and_hms_opt(0, 0, 0))
+                })
+                .map(|naive| naive.and_utc())
+                .ok_or_else(|| format!("'{}' is not a timestamp", s))
+        }
+        other => Err(format!("cannot convert {} to timestamp", type_name(other))),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn parse_configured(field: &str, bytes: &[u8], what: &str) -> Result<Value, MappingError> {
+    serde_json::from_slice(bytes)
+        .map_err(|e| MappingError::new(field, format!("invalid {} value: {}", what, e)))
+}
+
+/// Builds a new payload from `mappings`. Fields absent from the input are
+/// left out unless a default is configured; cast failures are handled per
+/// mapping by its `on_error` strategy.
+pub fn map_value(original: &Value, mappings: &[FieldMapping]) -> Result<Value, MappingError> {
+    let mut output = Value::Object(Map::new());
+
+    for mapping in mappings {
+        let target = FieldPath::parse(&mapping.target_field)?;
+        let default = mapping
+            .default_value
+            .as_deref()
+            .map(|d| parse_configured(&mapping.target_field, d, "default"))
+            .transpose()?;
+
+        let value = if let Some(literal) = &mapping.literal_value {
+            Some(parse_configured(&mapping.target_field, literal, "literal")?)
+        } else if let Some(source_field) = &mapping.source_field {
+            FieldPath::parse(source_field)?.get(original).cloned()
+        } else if default.is_some() {
+            None
+        } else {
+            target.get(original).cloned()
+        };
+
+        let Some(value) = value.or_else(|| default.clone()) else {
+            continue;
+        };
+
+        let value = match mapping.cast_type {
+            None => value,
+            Some(cast) => match cast_value(value.clone(), cast) {
+                Ok(v) => v,
+                Err(message) => match mapping.on_error {
+                    CastErrorStrategy::Fail => {
+                        return Err(MappingError::new(
+                            mapping.target_field.as_str(),
+                            format!("cannot cast to {:?}: {}", cast, message),
+                        ))
+                    }
+                    CastErrorStrategy::UseDefault => default.clone().unwrap_or(Value::Null),
+                    CastErrorStrategy::Skip => continue,
+                    CastErrorStrategy::PassThrough => value,
+                },
+            },
+        };
+
+        target.set(&mut output, value)?;
+    }
+
+    Ok(output)
+}
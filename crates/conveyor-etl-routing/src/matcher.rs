@@ -3,6 +3,9 @@ use regex::Regex;
 
 use conveyor_etl_proto::common::Record;
 
+use crate::codec::CodecRegistry;
+use crate::expr::Expression;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Condition {
     RecordType(String),
@@ -16,12 +19,18 @@ pub enum Condition {
     And(Vec<Condition>),
     Or(Vec<Condition>),
     Not(Box<Condition>),
+    Expression(Expression),
     Always,
     Never,
 }
 
 impl Condition {
+    /// Evaluates with the built-in JSON and MessagePack codecs only.
     pub fn evaluate(&self, record: &Record) -> bool {
+        self.evaluate_with(record, CodecRegistry::shared())
+    }
+
+    pub fn evaluate_with(&self, record: &Record, codecs: &CodecRegistry) -> bool {
         match self {
             Condition::RecordType(expected) => {
                 record.record_type == *expected
@@ -68,14 +77,15 @@ impl Condition {
                     .unwrap_or(false)
             }
             Condition::And(conditions) => {
-                conditions.iter().all(|c| c.evaluate(record))
+                conditions.iter().all(|c| c.evaluate_with(record, codecs))
             }
             Condition::Or(conditions) => {
-                conditions.iter().any(|c| c.evaluate(record))
+                conditions.iter().any(|c| c.evaluate_with(record, codecs))
             }
             Condition::Not(condition) => {
-                !condition.evaluate(record)
+                !condition.evaluate_with(record, codecs)
             }
+            Condition::Expression(expr) => expr.matches_with(record, codecs),
             Condition::Always => true,
             Condition::Never => false,
         }
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::codec::CodecRegistry;
+use super::mapping::{FieldPath, MappingError};
+use conveyor_etl_proto::common::Record;
+
+/// What records of a stage with parallelism above one are partitioned by.
+/// Records with the same key always go to the same partition, and so to the
+/// same instance, which keeps them in order.
+///
+/// Written as `record_key`, `metadata.<name>` or `payload.<path>`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PartitionKey {
+    RecordKey,
+    Metadata(String),
+    Payload(FieldPath),
+}
+
+impl PartitionKey {
+    /// The key of `record`, or `None` when it has none.
+    pub fn extract(&self, codecs: &CodecRegistry, record: &Record) -> Option<Vec<u8>> {
+        match self {
+            PartitionKey::RecordKey => Some(record.key.to_vec()).filter(|k| !k.is_empty()),
+            PartitionKey::Metadata(name) => record.metadata.get(name).map(|v| v.as_bytes().to_vec()),
+            PartitionKey::Payload(path) => {
+                let payload = codecs.decode(record).ok()?;
+                match path.get(&payload)? {
+                    serde_json::Value::Null => None,
+                    serde_json::Value::String(s) => Some(s.as_bytes().to_vec()),
+                    value => Some(value.to_string().into_bytes()),
+                }
+            }
+        }
+    }
+
+    /// The partition of `record` among `partitions`. Records without a key
+    /// all go to partition 0.
+    pub fn partition(&self, codecs: &CodecRegistry, record: &Record, partitions: u32) -> u32 {
+        match self.extract(codecs, record) {
+            Some(key) => partition_for(&key, partitions),
+            None => 0,
+        }
+    }
+}
+
+/// The partition a key falls in. The hash is fixed, so every sidecar puts a
+/// key in the same partition.
+pub fn partition_for(key: &[u8], partitions: u32) -> u32 {
+    // 64-bit FNV-1a
+    let hash = key.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    (hash % u64::from(partitions.max(1))) as u32
+}
+
+impl FromStr for PartitionKey {
+    type Err = MappingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "record_key" {
+            return Ok(PartitionKey::RecordKey);
+        }
+        if let Some(name) = s.strip_prefix("metadata.").filter(|n| !n.is_empty()) {
+            return Ok(PartitionKey::Metadata(name.to_string()));
+        }
+        if let Some(path) = s.strip_prefix("payload.") {
+            return Ok(PartitionKey::Payload(FieldPath::parse(path)?));
+        }
+        Err(MappingError::new(
+            s,
+            "partition key must be record_key, metadata.<name> or payload.<path>",
+        ))
+    }
+}
+
+impl fmt::Display for PartitionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionKey::RecordKey => write!(f, "record_key"),
+            PartitionKey::Metadata(name) => write!(f, "metadata.{}", name),
+            PartitionKey::Payload(path) => write!(f, "payload.{}", path.as_str()),
+        }
+    }
+}
+
+impl TryFrom<String> for PartitionKey {
+    type Error = MappingError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PartitionKey> for String {
+    fn from(key: PartitionKey) -> Self {
+        key.to_string()
+    }
+}
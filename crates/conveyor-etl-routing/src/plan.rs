@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use super::codec::CodecRegistry;
+use super::dag::{Pipeline, RoutingMode};
+use super::engine::RoutingDecision;
+use super::expr::Expression;
+use super::matcher::Condition;
+use conveyor_etl_proto::common::Record;
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    GreaterThan,
+    LessThan,
+    GreaterThanOrEqual,
+    LessThanOrEqual,
+}
+
+#[derive(Debug)]
+enum CompiledCondition {
+    RecordType(String),
+    MetadataMatch { key: String, regex: Regex },
+    MetadataExists(String),
+    MetadataEquals { key: String, value: String },
+    MetadataCompare { key: String, op: Comparison, value: f64 },
+    And(Vec<CompiledCondition>),
+    Or(Vec<CompiledCondition>),
+    Not(Box<CompiledCondition>),
+    Expression(Expression),
+    Always,
+    Never,
+}
+
+impl CompiledCondition {
+    fn compile(condition: &Condition) -> Self {
+        match condition {
+            Condition::RecordType(expected) => CompiledCondition::RecordType(expected.clone()),
+            Condition::MetadataMatch { key, pattern } => match Regex::new(pattern) {
+                Ok(regex) => CompiledCondition::MetadataMatch { key: key.clone(), regex },
+                Err(_) => CompiledCondition::Never,
+            },
+            Condition::MetadataExists(key) => CompiledCondition::MetadataExists(key.clone()),
+            Condition::MetadataEquals { key, value } => CompiledCondition::MetadataEquals {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            Condition::MetadataGreaterThan { key, value } => {
+                Self::compare(key, Comparison::GreaterThan, *value)
+            }
+            Condition::MetadataLessThan { key, value } => {
+                Self::compare(key, Comparison::LessThan, *value)
+            }
+            Condition::MetadataGreaterThanOrEqual { key, value } => {
+                Self::compare(key, Comparison::GreaterThanOrEqual, *value)
+            }
+            Condition::MetadataLessThanOrEqual { key, value } => {
+                Self::compare(key, Comparison::LessThanOrEqual, *value)
+            }
+            Condition::And(conditions) => {
+                let mut compiled = Vec::with_capacity(conditions.len());
+                for c in conditions.iter().map(Self::compile) {
+                    match c {
+                        CompiledCondition::Always => {}
+                        CompiledCondition::Never => return CompiledCondition::Never,
+                        c => compiled.push(c),
+                    }
+                }
+                match compiled.len() {
+                    0 => CompiledCondition::Always,
+                    1 => compiled.remove(0),
+                    _ => CompiledCondition::And(compiled),
+                }
+            }
+            Condition::Or(conditions) => {
+                let mut compiled = Vec::with_capacity(conditions.len());
+                for c in conditions.iter().map(Self::compile) {
+                    match c {
+                        CompiledCondition::Never => {}
+                        CompiledCondition::Always => return CompiledCondition::Always,
+                        c => compiled.push(c),
+                    }
+                }
+                match compiled.len() {
+                    0 => CompiledCondition::Never,
+                    1 => compiled.remove(0),
+                    _ => CompiledCondition::Or(compiled),
+                }
+            }
+            Condition::Not(inner) => match Self::compile(inner) {
+                CompiledCondition::Always => CompiledCondition::Never,
+                CompiledCondition::Never => CompiledCondition::Always,
+                CompiledCondition::Not(c) => *c,
+                c => CompiledCondition::Not(Box::new(c)),
+            },
+            Condition::Expression(expr) => CompiledCondition::Expression(expr.clone()),
+            Condition::Always => CompiledCondition::Always,
+            Condition::Never => CompiledCondition::Never,
+        }
+    }
+
+    fn compare(key: &str, op: Comparison, value: f64) -> Self {
+        CompiledCondition::MetadataCompare {
+            key: key.to_string(),
+            op,
+            value,
+        }
+    }
+
+    fn evaluate(&self, record: &Record, codecs: &CodecRegistry) -> bool {
+        match self {
+            CompiledCondition::RecordType(expected) => record.record_type == *expected,
+            CompiledCondition::MetadataMatch { key, regex } => record
+                .metadata
+                .get(key)
+                .map(|v| regex.is_match(v))
+                .unwrap_or(false),
+            CompiledCondition::MetadataExists(key) => record.metadata.contains_key(key),
+            CompiledCondition::MetadataEquals { key, value } => {
+                record.metadata.get(key).map(|v| v == value).unwrap_or(false)
+            }
+            CompiledCondition::MetadataCompare { key, op, value } => record
+                .metadata
+                .get(key)
+                .and_then(|v| v.parse::<f64>().ok())
+                .map(|v| match op {
+                    Comparison::GreaterThan => v > *value,
+                    Comparison::LessThan => v < *value,
+                    Comparison::GreaterThanOrEqual => v >= *value,
+                    Comparison::LessThanOrEqual => v <= *value,
+                })
+                .unwrap_or(false),
+            CompiledCondition::And(conditions) => {
+                conditions.iter().all(|c| c.evaluate(record, codecs))
+            }
+            CompiledCondition::Or(conditions) => {
+                conditions.iter().any(|c| c.evaluate(record, codecs))
+            }
+            CompiledCondition::Not(condition) => !condition.evaluate(record, codecs),
+            CompiledCondition::Expression(expr) => expr.matches_with(record, codecs),
+            CompiledCondition::Always => true,
+            CompiledCondition::Never => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CompiledEdge {
+    target: usize,
+    priority: u32,
+    condition: CompiledCondition,
+}
+
+#[derive(Debug, Default)]
+struct StageRoutes {
+    mode: RoutingMode,
+    targets: Vec<String>,
+    edges: Vec<CompiledEdge>,
+    defaults: Vec<(u32, usize)>,
+    dead_letter: Option<usize>,
+}
+
+impl StageRoutes {
+    fn target_index(&mut self, stage_id: &str) -> usize {
+        match self.targets.iter().position(|t| t == stage_id) {
+            Some(idx) => idx,
+            None => {
+                self.targets.push(stage_id.to_string());
+                self.targets.len() - 1
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RoutedBatch {
+    pub decisions: Vec<RoutingDecision>,
+    pub unmatched: usize,
+}
+
+#[derive(Debug)]
+pub struct RoutingPlan {
+    pipeline_id: String,
+    enabled: bool,
+    dead_letter_stage: Option<String>,
+    routes: HashMap<String, StageRoutes>,
+    codecs: Arc<CodecRegistry>,
+}
+
+impl RoutingPlan {
+    pub fn compile(pipeline: &Pipeline) -> Self {
+        Self::compile_with_codecs(pipeline, Arc::new(CodecRegistry::new()))
+    }
+
+    pub fn compile_with_codecs(pipeline: &Pipeline, codecs: Arc<CodecRegistry>) -> Self {
+        let mut routes: HashMap<String, StageRoutes> = HashMap::new();
+
+        for edge in &pipeline.edges {
+            let stage = routes.entry(edge.from_stage.clone()).or_insert_with(|| StageRoutes {
+                mode: pipeline
+                    .stages
+                    .get(&edge.from_stage)
+                    .map(|s| s.routing_mode)
+                    .unwrap_or_default(),
+                ..Default::default()
+            });
+
+            if edge.is_default {
+                let target = stage.target_index(&edge.to_stage);
+                stage.defaults.push((edge.priority, target));
+                continue;
+            }
+
+            let condition = edge
+                .condition
+                .as_ref()
+                .map(CompiledCondition::compile)
+                .unwrap_or(CompiledCondition::Always);
+            if matches!(condition, CompiledCondition::Never) {
+                continue;
+            }
+
+            let target = stage.target_index(&edge.to_stage);
+            stage.edges.push(CompiledEdge {
+                target,
+                priority: edge.priority,
+                condition,
+            });
+        }
+
+        for stage in routes.values_mut() {
+            // Stable sorts keep declaration order among equal priorities
+            stage.edges.sort_by_key(|e| e.priority);
+            stage.defaults.sort_by_key(|(priority, _)| *priority);
+            if let Some(dlq) = &pipeline.dead_letter_stage {
+                stage.dead_letter = Some(stage.target_index(dlq));
+            }
+        }
+
+        Self {
+            pipeline_id: pipeline.id.clone(),
+            enabled: pipeline.enabled,
+            dead_letter_stage: pipeline.dead_letter_stage.clone(),
+            routes,
+            codecs,
+        }
+    }
+
+    pub fn pipeline_id(&self) -> &str {
+        &self.pipeline_id
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dead_letter_stage(&self) -> Option<&str> {
+        self.dead_letter_stage.as_deref()
+    }
+
+    pub fn routing_mode(&self, stage_id: &str) -> RoutingMode {
+        self.routes.get(stage_id).map(|r| r.mode).unwrap_or_default()
+    }
+
+    pub fn targets(&self, stage_id: &str) -> &[String] {
+        self.routes
+            .get(stage_id)
+            .map(|r| r.targets.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn route(&self, source_stage_id: &str, records: Vec<Record>) -> RoutedBatch {
+        let Some(stage) = self.routes.get(source_stage_id) else {
+            return RoutedBatch::default();
+        };
+
+        let mut buckets: Vec<Vec<Record>> = stage.targets.iter().map(|_| Vec::new()).collect();
+        let mut matched: Vec<usize> = Vec::with_capacity(stage.edges.len());
+        let mut unmatched = 0;
+
+        for record in records {
+            matched.clear();
+            let mut edges = stage
+                .edges
+                .iter()
+                .filter(|e| e.condition.evaluate(&record, &self.codecs));
+            match stage.mode {
+                RoutingMode::AllMatches => matched.extend(edges.map(|e| e.target)),
+                RoutingMode::FirstMatch => matched.extend(edges.next().map(|e| e.target)),
+            }
+
+            if matched.is_empty() {
+                match stage.mode {
+                    RoutingMode::AllMatches => {
+                        matched.extend(stage.defaults.iter().map(|(_, target)| *target))
+                    }
+                    RoutingMode::FirstMatch => {
+                        matched.extend(stage.defaults.first().map(|(_, target)| *target))
+                    }
+                }
+            }
+
+            if matched.is_empty() {
+                unmatched += 1;
+                if let Some(dead_letter) = stage.dead_letter {
+                    buckets[dead_letter].push(record);
+                }
+                continue;
+            }
+
+            // Record payloads are reference-counted, so only the final match
+            // takes ownership and the others share the same buffers.
+            let (&last, rest) = matched.split_last().unwrap();
+            for &target in rest {
+                buckets[target].push(record.clone());
+            }
+            buckets[last].push(record);
+        }
+
+        let decisions = stage
+            .targets
+            .iter()
+            .zip(buckets)
+            .filter(|(_, records)| !records.is_empty())
+            .map(|(target, records)| RoutingDecision {
+                target_stage_id: target.clone(),
+                records,
+            })
+            .collect();
+
+        RoutedBatch {
+            decisions,
+            unmatched,
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use apache_avro::schema_compatibility::SchemaCompatibility;
+use base64::Engine as _;
+use prost_reflect::{DescriptorPool, DynamicMessage, Kind, MessageDescriptor};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use conveyor_etl_proto::common::Record;
+
+use super::codec::{CodecRegistry, AVRO_CONTENT_TYPE, CONTENT_TYPE_KEY, PROTOBUF_CONTENT_TYPE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaFormat {
+    JsonSchema,
+    Avro,
+    Protobuf,
+}
+
+impl SchemaFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SchemaFormat::JsonSchema => "json_schema",
+            SchemaFormat::Avro => "avro",
+            SchemaFormat::Protobuf => "protobuf",
+        }
+    }
+}
+
+impl FromStr for SchemaFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json_schema" => Ok(SchemaFormat::JsonSchema),
+            "avro" => Ok(SchemaFormat::Avro),
+            "protobuf" => Ok(SchemaFormat::Protobuf),
+            other => bail!("unknown schema format: {}", other),
+        }
+    }
+}
+
+impl fmt::Display for SchemaFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Which previously registered data a new schema version must stay
+/// compatible with. Checks run against the latest registered version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompatibilityMode {
+    None,
+    /// Readers using the new schema can read data written with the old one.
+    #[default]
+    Backward,
+    /// Readers using the old schema can read data written with the new one.
+    Forward,
+    Full,
+}
+
+impl CompatibilityMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompatibilityMode::None => "none",
+            CompatibilityMode::Backward => "backward",
+            CompatibilityMode::Forward => "forward",
+            CompatibilityMode::Full => "full",
+        }
+    }
+}
+
+impl FromStr for CompatibilityMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(CompatibilityMode::None),
+            "backward" => Ok(CompatibilityMode::Backward),
+            "forward" => Ok(CompatibilityMode::Forward),
+            "full" => Ok(CompatibilityMode::Full),
+            other => bail!("unknown compatibility mode: {}", other),
+        }
+    }
+}
+
+impl fmt::Display for CompatibilityMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A schema as stored in the registry. Protobuf definitions are a
+/// base64-encoded `FileDescriptorSet` plus the full name of the message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaDefinition {
+    pub format: SchemaFormat,
+    pub definition: String,
+    #[serde(default)]
+    pub message_name: Option<String>,
+}
+
+enum CompiledSchema {
+    Json {
+        schema: Value,
+        validator: jsonschema::Validator,
+    },
+    Avro(apache_avro::Schema),
+    Protobuf(MessageDescriptor),
+}
+
+pub struct RecordSchema {
+    definition: SchemaDefinition,
+    compiled: CompiledSchema,
+}
+
+impl RecordSchema {
+    pub fn compile(definition: SchemaDefinition) -> Result<Self> {
+        let compiled = match definition.format {
+            SchemaFormat::JsonSchema => {
+                let schema: Value = serde_json::from_str(&definition.definition)
+                    .context("JSON Schema is not valid JSON")?;
+                let validator = jsonschema::validator_for(&schema)
+                    .map_err(|e| anyhow!("invalid JSON Schema: {}", e))?;
+                CompiledSchema::Json { schema, validator }
+            }
+            SchemaFormat::Avro => CompiledSchema::Avro(
+                apache_avro::Schema::parse_str(&definition.definition)
+                    .map_err(|e| anyhow!("invalid Avro schema: {}", e))?,
+            ),
+            SchemaFormat::Protobuf => {
+                let message_name = definition
+                    .message_name
+                    .as_deref()
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| anyhow!("Protobuf schemas require a message name"))?;
+                let descriptor_set = base64::engine::general_purpose::STANDARD
+                    .decode(definition.definition.trim())
+                    .context("Protobuf descriptor set is not valid base64")?;
+                let pool = DescriptorPool::decode(descriptor_set.as_slice())
+                    .context("invalid descriptor set")?;
+                let message = pool.get_message_by_name(message_name).ok_or_else(|| {
+                    anyhow!("message type not found in descriptor set: {}", message_name)
+                })?;
+                CompiledSchema::Protobuf(message)
+            }
+        };
+
+        Ok(Self { definition, compiled })
+    }
+
+    pub fn definition(&self) -> &SchemaDefinition {
+        &self.definition
+    }
+
+    pub fn format(&self) -> SchemaFormat {
+        self.definition.format
+    }
+
+    /// True when both schemas describe the same thing, ignoring JSON
+    /// whitespace and key order.
+    pub fn same_as(&self, other: &RecordSchema) -> bool {
+        match (&self.compiled, &other.compiled) {
+            (CompiledSchema::Json { schema: a, .. }, CompiledSchema::Json { schema: b, .. }) => a == b,
+            (CompiledSchema::Avro(a), CompiledSchema::Avro(b)) => a == b,
+            (CompiledSchema::Protobuf(_), CompiledSchema::Protobuf(_)) => {
+                self.definition == other.definition
+            }
+            _ => false,
+        }
+    }
+
+    pub fn validate(&self, record: &Record) -> std::result::Result<(), String> {
+        self.validate_with(record, CodecRegistry::shared())
+    }
+
+    /// Checks a record's payload against the schema. Binary Avro and
+    /// Protobuf payloads are decoded directly; payloads tagged with another
+    /// content type (e.g. JSON after a field mapping) are decoded with
+    /// `codecs` and checked against the schema's shape.
+    pub fn validate_with(
+        &self,
+        record: &Record,
+        codecs: &CodecRegistry,
+    ) -> std::result::Result<(), String> {
+        match &self.compiled {
+            CompiledSchema::Json { validator, .. } => {
+                let value = codecs.decode(record).map_err(|e| format!("{:#}", e))?;
+                validator.validate(&value).map_err(|e| {
+                    let path = e.instance_path.to_string();
+                    if path.is_empty() {
+                        e.to_string()
+                    } else {
+                        format!("{} (at {})", e, path)
+                    }
+                })
+            }
+            CompiledSchema::Avro(schema) => {
+                if is_foreign_content_type(record, AVRO_CONTENT_TYPE) {
+                    let value = codecs.decode(record).map_err(|e| format!("{:#}", e))?;
+                    return apache_avro::types::Value::from(value)
+                        .resolve(schema)
+                        .map(|_| ())
+                        .map_err(|e| format!("value does not match Avro schema: {}", e));
+                }
+                let mut payload: &[u8] = &record.payload;
+                apache_avro::from_avro_datum(schema, &mut payload, None)
+                    .map_err(|e| format!("invalid Avro payload: {}", e))?;
+                if !payload.is_empty() {
+                    return Err(format!("{} trailing bytes after Avro datum", payload.len()));
+                }
+                Ok(())
+            }
+            CompiledSchema::Protobuf(message) => {
+                if is_foreign_content_type(record, PROTOBUF_CONTENT_TYPE) {
+                    let value = codecs.decode(record).map_err(|e| format!("{:#}", e))?;
+                    return DynamicMessage::deserialize(message.clone(), value)
+                        .map(|_| ())
+                        .map_err(|e| format!("value does not match {}: {}", message.full_name(), e));
+                }
+                DynamicMessage::decode(message.clone(), record.payload.as_ref())
+                    .map(|_| ())
+                    .map_err(|e| format!("invalid {} payload: {}", message.full_name(), e))
+            }
+        }
+    }
+
+    /// Checks whether `self` may be registered after `previous`. Returns
+    /// every incompatibility found.
+    pub fn check_compatibility(
+        &self,
+        previous: &RecordSchema,
+        mode: CompatibilityMode,
+    ) -> std::result::Result<(), Vec<String>> {
+        if mode == CompatibilityMode::None {
+            return Ok(());
+        }
+        if self.format() != previous.format() {
+            return Err(vec![format!(
+                "schema format changed from {} to {}",
+                previous.format(),
+                self.format()
+            )]);
+        }
+
+        let mut errors = Vec::new();
+        if matches!(mode, CompatibilityMode::Backward | CompatibilityMode::Full) {
+            errors.extend(can_read(self, previous).into_iter().map(|e| format!("backward: {}", e)));
+        }
+        if matches!(mode, CompatibilityMode::Forward | CompatibilityMode::Full) {
+            errors.extend(can_read(previous, self).into_iter().map(|e| format!("forward: {}", e)));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl fmt::Debug for RecordSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordSchema")
+            .field("format", &self.definition.format)
+            .field("message_name", &self.definition.message_name)
+            .finish()
+    }
+}
+
+fn is_foreign_content_type(record: &Record, native: &str) -> bool {
+    record.metadata.get(CONTENT_TYPE_KEY).is_some_and(|ct| {
+        let essence = ct.split(';').next().unwrap_or_default().trim();
+        !essence.eq_ignore_ascii_case(native)
+    })
+}
+
+/// Lists the reasons data written with `writer` cannot be read with `reader`.
+fn can_read(reader: &RecordSchema, writer: &RecordSchema) -> Vec<String> {
+    let mut errors = Vec::new();
+    match (&reader.compiled, &writer.compiled) {
+        (CompiledSchema::Json { schema: r, .. }, CompiledSchema::Json { schema: w, .. }) => {
+            json_can_read(r, w, "", &mut errors);
+        }
+        (CompiledSchema::Avro(r), CompiledSchema::Avro(w)) => {
+            if let Err(e) = SchemaCompatibility::can_read(w, r) {
+                errors.push(e.to_string());
+            }
+        }
+        (CompiledSchema::Protobuf(r), CompiledSchema::Protobuf(w)) => {
+            proto_can_read(r, w, &mut HashSet::new(), &mut errors);
+        }
+        _ => errors.push("schema formats differ".to_string()),
+    }
+    errors
+}
+
+fn json_types(schema: &Value) -> Option<Vec<&str>> {
+    match schema.get("type")? {
+        Value::String(t) => Some(vec![t.as_str()]),
+        Value::Array(types) => Some(types.iter().filter_map(Value::as_str).collect()),
+        _ => None,
+    }
+}
+
+fn json_required(schema: &Value) -> Vec<&str> {
+    schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|r| r.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+fn json_enum(schema: &Value) -> Option<Vec<&Value>> {
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        return Some(values.iter().collect());
+    }
+    schema.get("const").map(|value| vec![value])
+}
+
+/// A structural check over the JSON Schema keywords that decide whether a
+/// document is accepted: `type`, `enum`/`const`, `required`, `properties`,
+/// `additionalProperties` and `items`.
+fn json_can_read(reader: &Value, writer: &Value, path: &str, errors: &mut Vec<String>) {
+    let at = if path.is_empty() { "/" } else { path };
+
+    if reader == &Value::Bool(true) || writer == &Value::Bool(false) {
+        return;
+    }
+
+    if let Some(reader_types) = json_types(reader) {
+        match json_types(writer) {
+            Some(writer_types) => {
+                for t in writer_types {
+                    let accepted = reader_types.contains(&t)
+                        || (t == "integer" && reader_types.contains(&"number"));
+                    if !accepted {
+                        errors.push(format!("{}: type '{}' is no longer accepted", at, t));
+                    }
+                }
+            }
+            None => errors.push(format!(
+                "{}: type is restricted to {}",
+                at,
+                reader_types.join(", ")
+            )),
+        }
+    }
+
+    if let Some(reader_values) = json_enum(reader) {
+        match json_enum(writer) {
+            Some(writer_values) => {
+                for value in writer_values {
+                    if !reader_values.contains(&value) {
+                        errors.push(format!("{}: value {} is no longer allowed", at, value));
+                    }
+                }
+            }
+            None => errors.push(format!("{}: values are restricted to an enum", at)),
+        }
+    }
+
+    let writer_required = json_required(writer);
+    for name in json_required(reader) {
+        if !writer_required.contains(&name) {
+            errors.push(format!("{}: property '{}' is now required", at, name));
+        }
+    }
+
+    let reader_props = reader.get("properties").and_then(Value::as_object);
+    let writer_props = writer.get("properties").and_then(Value::as_object);
+    if let Some(writer_props) = writer_props {
+        let closed = reader.get("additionalProperties") == Some(&Value::Bool(false));
+        for (name, writer_prop) in writer_props {
+            match reader_props.and_then(|props| props.get(name)) {
+                Some(reader_prop) => {
+                    json_can_read(reader_prop, writer_prop, &format!("{}/{}", path, name), errors);
+                }
+                None if closed => {
+                    errors.push(format!("{}: property '{}' is no longer allowed", at, name));
+                }
+                None => {}
+            }
+        }
+    }
+
+    if let (Some(reader_items), Some(writer_items)) = (reader.get("items"), writer.get("items")) {
+        json_can_read(reader_items, writer_items, &format!("{}/items", path), errors);
+    }
+}
+
+/// Field numbers, not names, identify Protobuf fields on the wire, so a
+/// field is compatible when it keeps its number, cardinality and a
+/// wire-compatible type. Added and removed fields are always readable.
+fn proto_can_read(
+    reader: &MessageDescriptor,
+    writer: &MessageDescriptor,
+    visited: &mut HashSet<(String, String)>,
+    errors: &mut Vec<String>,
+) {
+    if !visited.insert((reader.full_name().to_string(), writer.full_name().to_string())) {
+        return;
+    }
+
+    for reader_field in reader.fields() {
+        let Some(writer_field) = writer.get_field(reader_field.number()) else {
+            continue;
+        };
+        let field = format!("{}.{} (#{})", reader.full_name(), reader_field.name(), reader_field.number());
+
+        if reader_field.is_map() != writer_field.is_map()
+            || reader_field.is_list() != writer_field.is_list()
+        {
+            errors.push(format!("{}: cardinality changed", field));
+            continue;
+        }
+
+        match (reader_field.kind(), writer_field.kind()) {
+            (Kind::Message(r), Kind::Message(w)) => proto_can_read(&r, &w, visited, errors),
+            (r, w) if wire_class(&r) != wire_class(&w) => {
+                errors.push(format!(
+                    "{}: type changed from {} to {}",
+                    field,
+                    kind_name(&w),
+                    kind_name(&r)
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn wire_class(kind: &Kind) -> u8 {
+    match kind {
+        Kind::Int32 | Kind::Int64 | Kind::Uint32 | Kind::Uint64 | Kind::Bool | Kind::Enum(_) => 0,
+        Kind::Sint32 | Kind::Sint64 => 1,
+        Kind::Fixed32 | Kind::Sfixed32 => 2,
+        Kind::Fixed64 | Kind::Sfixed64 => 3,
+        Kind::Float => 4,
+        Kind::Double => 5,
+        Kind::String | Kind::Bytes => 6,
+        Kind::Message(_) => 7,
+    }
+}
+
+fn kind_name(kind: &Kind) -> String {
+    match kind {
+        Kind::Message(message) => message.full_name().to_string(),
+        Kind::Enum(enumeration) => enumeration.full_name().to_string(),
+        other => format!("{:?}", other).to_lowercase(),
+    }
+}
@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod dag_tests {
     use std::collections::HashMap;
-    use crate::{Pipeline, Stage, StageType, ServiceSelector, LoadBalanceStrategy};
+    use crate::{Pipeline, Stage, StageType, ServiceSelector, LoadBalanceStrategy, RoutingMode};
 
     fn create_stage(id: &str, stage_type: StageType) -> Stage {
         Stage {
@@ -13,11 +13,14 @@ mod dag_tests {
                 group_id: None,
                 labels: HashMap::new(),
                 load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
             },
             parallelism: 1,
             lookup_config: None,
             fan_in_config: None,
             fan_out_config: None,
+            routing_mode: RoutingMode::AllMatches,
+            partition_key: None,
         }
     }
 
@@ -237,8 +240,8 @@ mod condition_tests {
         Record {
             id: None,
             record_type: record_type.to_string(),
-            key: vec![],
-            payload: vec![],
+            key: Default::default(),
+            payload: Default::default(),
             metadata,
             event_time: None,
             ingestion_time: None,
@@ -469,10 +472,187 @@ mod condition_tests {
     }
 }
 
+#[cfg(test)]
+mod expression_tests {
+    use std::collections::HashMap;
+    use crate::{Condition, Expression, Value};
+    use conveyor_etl_proto::common::Record;
+
+    fn create_json_record(payload: &str) -> Record {
+        let mut metadata = HashMap::new();
+        metadata.insert("region".to_string(), "eu-west-1".to_string());
+        metadata.insert("priority".to_string(), "7".to_string());
+
+        Record {
+            id: None,
+            record_type: "order".to_string(),
+            key: b"customer-42".to_vec().into(),
+            payload: payload.as_bytes().to_vec().into(),
+            metadata,
+            event_time: Some(prost_types::Timestamp { seconds: 1_700_000_000, nanos: 0 }),
+            ingestion_time: None,
+        }
+    }
+
+    fn eval(source: &str, record: &Record) -> Value {
+        Expression::compile(source).unwrap().evaluate(record).unwrap()
+    }
+
+    #[test]
+    fn test_payload_field_access() {
+        let record = create_json_record(
+            r#"{"amount": 250.5, "customer": {"tier": "gold", "tags": ["vip", "b2b"]}}"#,
+        );
+
+        assert!(Expression::compile("payload.amount > 100").unwrap().matches(&record));
+        assert_eq!(eval("payload.customer.tier", &record), Value::String("gold".to_string()));
+        assert_eq!(eval("payload.customer.tags[0]", &record), Value::String("vip".to_string()));
+        assert_eq!(eval("payload.customer.tags[-1]", &record), Value::String("b2b".to_string()));
+        assert_eq!(eval("payload['customer']['tier']", &record), Value::String("gold".to_string()));
+    }
+
+    #[test]
+    fn test_arithmetic_and_precedence() {
+        let record = create_json_record(r#"{"qty": 3, "price": 2.5}"#);
+
+        assert_eq!(eval("1 + 2 * 3", &record), Value::Int(7));
+        assert_eq!(eval("(1 + 2) * 3", &record), Value::Int(9));
+        assert_eq!(eval("7 % 4 - -1", &record), Value::Int(4));
+        assert_eq!(eval("payload.qty * payload.price", &record), Value::Float(7.5));
+        assert!(Expression::compile("payload.qty == 3.0").unwrap().matches(&record));
+        assert!(Expression::compile("1 / 0 == 0").unwrap().evaluate(&record).is_err());
+        assert!(!Expression::compile("1 / 0 == 0").unwrap().matches(&record));
+    }
+
+    #[test]
+    fn test_string_functions() {
+        let record = create_json_record(r#"{"email": "  Alice@Example.com "}"#);
+
+        assert!(Expression::compile("metadata.region.startsWith('eu-')").unwrap().matches(&record));
+        assert!(Expression::compile("endsWith(metadata.region, '-1')").unwrap().matches(&record));
+        assert!(Expression::compile("payload.email.trim().lower() == 'alice@example.com'")
+            .unwrap()
+            .matches(&record));
+        assert!(Expression::compile(r#"key.matches("^customer-[0-9]+$")"#).unwrap().matches(&record));
+        assert_eq!(eval("size(record_type)", &record), Value::Int(5));
+        assert_eq!(eval("'a' + 'b'", &record), Value::String("ab".to_string()));
+        assert!(Expression::compile("int(metadata.priority) >= 5").unwrap().matches(&record));
+    }
+
+    #[test]
+    fn test_in_lists_and_maps() {
+        let record = create_json_record(r#"{"status": "shipped"}"#);
+
+        assert!(Expression::compile("payload.status in ['shipped', 'delivered']").unwrap().matches(&record));
+        assert!(!Expression::compile("record_type in ['click', 'view']").unwrap().matches(&record));
+        assert!(Expression::compile("'region' in metadata").unwrap().matches(&record));
+        assert!(Expression::compile("3 in [1, 2, 3.0]").unwrap().matches(&record));
+    }
+
+    #[test]
+    fn test_timestamps_and_durations() {
+        let record = create_json_record(r#"{"created": "2023-11-14T22:00:00Z"}"#);
+
+        assert!(Expression::compile("event_time > timestamp('2023-01-01T00:00:00Z')")
+            .unwrap()
+            .matches(&record));
+        assert!(Expression::compile("event_time - timestamp(payload.created) < duration('1h')")
+            .unwrap()
+            .matches(&record));
+        assert!(Expression::compile("timestamp(payload.created) + duration('1h30m') > event_time")
+            .unwrap()
+            .matches(&record));
+        assert_eq!(eval("ingestion_time", &record), Value::Null);
+    }
+
+    #[test]
+    fn test_out_of_range_durations_are_errors() {
+        let record = create_json_record(r#"{"ttl": 9223372036854775807, "window": "9999999999999999h"}"#);
+
+        let ttl = Expression::compile("duration(payload.ttl) > duration('1s')").unwrap();
+        assert!(ttl.evaluate(&record).is_err());
+        assert!(!ttl.matches(&record));
+
+        let window = Expression::compile("duration(payload.window)").unwrap();
+        assert!(window.evaluate(&record).unwrap_err().to_string().contains("out of range"));
+
+        assert!(Expression::compile("duration('9999999999999999h')").is_err());
+    }
+
+    #[test]
+    fn test_null_handling() {
+        let record = create_json_record(r#"{"customer": null}"#);
+
+        assert_eq!(eval("payload.customer.tier", &record), Value::Null);
+        assert_eq!(eval("payload.missing.deeply.nested", &record), Value::Null);
+        assert_eq!(eval("payload.customer.tier ?? 'standard'", &record), Value::String("standard".to_string()));
+        assert!(Expression::compile("payload.customer == null").unwrap().matches(&record));
+        assert!(!Expression::compile("has(payload.customer)").unwrap().matches(&record));
+        assert!(Expression::compile("has(metadata.region)").unwrap().matches(&record));
+        assert!(!Expression::compile("payload.amount > 10").unwrap().matches(&record));
+
+        let invalid_json = create_json_record("not json");
+        assert_eq!(eval("payload", &invalid_json), Value::Null);
+    }
+
+    #[test]
+    fn test_logic_and_ternary() {
+        let record = create_json_record(r#"{"amount": 50}"#);
+
+        assert!(Expression::compile("record_type == 'order' && !(payload.amount > 100)")
+            .unwrap()
+            .matches(&record));
+        assert!(Expression::compile("false || metadata.region == 'eu-west-1'").unwrap().matches(&record));
+        assert_eq!(
+            eval("payload.amount > 100 ? 'large' : 'small'", &record),
+            Value::String("small".to_string())
+        );
+        assert!(Expression::compile("false && payload.amount.nope()").is_err());
+        assert!(!Expression::compile("payload.amount").unwrap().matches(&record));
+    }
+
+    #[test]
+    fn test_compile_errors_report_position() {
+        let err = Expression::compile("payload.amount >").unwrap_err();
+        assert_eq!(err.position, 16);
+        assert_eq!(err.column, 17);
+
+        let err = Expression::compile("record_type == 'a' &&\n  bogus > 1").unwrap_err();
+        assert!(err.message.contains("unknown identifier 'bogus'"));
+        assert_eq!((err.line, err.column), (2, 3));
+        assert!(err.to_string().ends_with("at line 2, column 3"));
+
+        assert!(Expression::compile("lower('a', 'b')").is_err());
+        assert!(Expression::compile("frobnicate(1)").is_err());
+        assert!(Expression::compile("key.matches('(')").is_err());
+        assert!(Expression::compile("1 < 2 < 3").is_err());
+        assert!(Expression::compile("record_type = 'a'").is_err());
+        assert!(Expression::compile("'unterminated").is_err());
+        assert!(Expression::compile("duration('soon')").is_err());
+        assert!(Expression::compile(&"(".repeat(100)).is_err());
+        assert!(Expression::compile(&"!".repeat(100)).is_err());
+    }
+
+    #[test]
+    fn test_expression_condition_serde() {
+        let condition = Condition::Expression(Expression::compile("payload.amount > 100").unwrap());
+        let json = serde_json::to_string(&condition).unwrap();
+        assert_eq!(json, r#"{"Expression":"payload.amount > 100"}"#);
+
+        let restored: Condition = serde_json::from_str(&json).unwrap();
+        assert!(restored.evaluate(&create_json_record(r#"{"amount": 101}"#)));
+
+        assert!(serde_json::from_str::<Condition>(r#"{"Expression":"payload >"}"#).is_err());
+    }
+}
+
 #[cfg(test)]
 mod routing_engine_tests {
     use std::collections::HashMap;
-    use crate::{RoutingEngine, Pipeline, Stage, StageType, ServiceSelector, LoadBalanceStrategy};
+    use crate::{
+        RoutingEngine, Pipeline, Stage, StageType, ServiceSelector, LoadBalanceStrategy,
+        RoutingMode, TrafficSplit, VersionWeight,
+    };
     use conveyor_etl_proto::common::RecordBatch;
 
     fn create_stage(id: &str, stage_type: StageType, service_name: Option<&str>) -> Stage {
@@ -485,11 +665,14 @@ mod routing_engine_tests {
                 group_id: None,
                 labels: HashMap::new(),
                 load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
             },
             parallelism: 1,
             lookup_config: None,
             fan_in_config: None,
             fan_out_config: None,
+            routing_mode: RoutingMode::AllMatches,
+            partition_key: None,
         }
     }
 
@@ -514,8 +697,8 @@ mod routing_engine_tests {
             records: vec![Record {
                 id: None,
                 record_type: "test".to_string(),
-                key: vec![],
-                payload: vec![1, 2, 3],
+                key: Default::default(),
+                payload: vec![1, 2, 3].into(),
                 metadata: HashMap::new(),
                 event_time: None,
                 ingestion_time: None,
@@ -567,8 +750,8 @@ mod routing_engine_tests {
                 Record {
                     id: None,
                     record_type: "log".to_string(),
-                    key: vec![],
-                    payload: vec![],
+                    key: Default::default(),
+                    payload: Default::default(),
                     metadata: error_metadata,
                     event_time: None,
                     ingestion_time: None,
@@ -576,8 +759,8 @@ mod routing_engine_tests {
                 Record {
                     id: None,
                     record_type: "log".to_string(),
-                    key: vec![],
-                    payload: vec![],
+                    key: Default::default(),
+                    payload: Default::default(),
                     metadata: info_metadata,
                     event_time: None,
                     ingestion_time: None,
@@ -622,8 +805,8 @@ mod routing_engine_tests {
                 Record {
                     id: None,
                     record_type: "event".to_string(),
-                    key: vec![],
-                    payload: vec![1, 2, 3],
+                    key: Default::default(),
+                    payload: vec![1, 2, 3].into(),
                     metadata: HashMap::new(),
                     event_time: None,
                     ingestion_time: None,
@@ -693,8 +876,8 @@ mod routing_engine_tests {
                 Record {
                     id: None,
                     record_type: "event".to_string(),
-                    key: vec![],
-                    payload: vec![],
+                    key: Default::default(),
+                    payload: Default::default(),
                     metadata: critical_error_meta,
                     event_time: None,
                     ingestion_time: None,
@@ -702,8 +885,8 @@ mod routing_engine_tests {
                 Record {
                     id: None,
                     record_type: "event".to_string(),
-                    key: vec![],
-                    payload: vec![],
+                    key: Default::default(),
+                    payload: Default::default(),
                     metadata: normal_error_meta,
                     event_time: None,
                     ingestion_time: None,
@@ -711,8 +894,8 @@ mod routing_engine_tests {
                 Record {
                     id: None,
                     record_type: "event".to_string(),
-                    key: vec![],
-                    payload: vec![],
+                    key: Default::default(),
+                    payload: Default::default(),
                     metadata: HashMap::new(),
                     event_time: None,
                     ingestion_time: None,
@@ -811,11 +994,14 @@ mod routing_engine_tests {
                 group_id: None,
                 labels: labels.clone(),
                 load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
             },
             parallelism: 1,
             lookup_config: None,
             fan_in_config: None,
             fan_out_config: None,
+            routing_mode: RoutingMode::AllMatches,
+            partition_key: None,
         };
 
         let mut pipeline = Pipeline::new("p1".to_string(), "Test Pipeline".to_string());
@@ -830,6 +1016,44 @@ mod routing_engine_tests {
         assert_eq!(s.service_selector.labels.get("region"), Some(&"us".to_string()));
     }
 
+    #[tokio::test]
+    async fn test_update_traffic_split() {
+        let engine = RoutingEngine::new();
+
+        let mut pipeline = Pipeline::new("p1".to_string(), "Test Pipeline".to_string());
+        pipeline.add_stage(create_stage("transform", StageType::Transform, Some("my-transform")));
+        engine.add_pipeline(pipeline).await;
+
+        let split = TrafficSplit::new(
+            vec![
+                VersionWeight { version: "v1".to_string(), weight: 95 },
+                VersionWeight { version: "v2".to_string(), weight: 5 },
+            ],
+            true,
+        );
+
+        engine
+            .update_traffic_split("p1", "transform", Some(split.clone()))
+            .await
+            .unwrap();
+
+        let p = engine.get_pipeline("p1").await.unwrap();
+        let s = p.stages.get("transform").unwrap();
+        assert_eq!(s.service_selector.traffic_split, Some(split));
+
+        let invalid = TrafficSplit::new(
+            vec![VersionWeight { version: "v1".to_string(), weight: 0 }],
+            false,
+        );
+        assert!(engine.update_traffic_split("p1", "transform", Some(invalid)).await.is_err());
+        assert!(engine.update_traffic_split("p1", "missing", None).await.is_err());
+        assert!(engine.update_traffic_split("missing", "transform", None).await.is_err());
+
+        engine.update_traffic_split("p1", "transform", None).await.unwrap();
+        let p = engine.get_pipeline("p1").await.unwrap();
+        assert!(p.stages.get("transform").unwrap().service_selector.traffic_split.is_none());
+    }
+
     #[tokio::test]
     #[ignore = "Load balancing requires registry integration"]
     async fn test_load_balance_across_service_instances() {
@@ -843,6 +1067,1492 @@ mod routing_engine_tests {
     }
 }
 
+#[cfg(test)]
+mod routing_plan_tests {
+    use std::collections::HashMap;
+    use crate::{
+        Condition, Expression, LoadBalanceStrategy, Pipeline, RoutingEngine, RoutingMode,
+        RoutingPlan, ServiceSelector, Stage, StageType,
+    };
+    use conveyor_etl_proto::common::{Record, RecordBatch};
+
+    fn create_stage(id: &str, stage_type: StageType) -> Stage {
+        Stage {
+            id: id.to_string(),
+            name: format!("{}-stage", id),
+            stage_type,
+            service_selector: ServiceSelector {
+                service_name: Some(format!("{}-service", id)),
+                group_id: None,
+                labels: HashMap::new(),
+                load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
+            },
+            parallelism: 1,
+            lookup_config: None,
+            fan_in_config: None,
+            fan_out_config: None,
+            routing_mode: RoutingMode::AllMatches,
+            partition_key: None,
+        }
+    }
+
+    fn create_record(record_type: &str, level: &str) -> Record {
+        let mut metadata = HashMap::new();
+        metadata.insert("level".to_string(), level.to_string());
+        Record {
+            id: None,
+            record_type: record_type.to_string(),
+            key: b"k".to_vec().into(),
+            payload: br#"{"amount": 42}"#.to_vec().into(),
+            metadata,
+            event_time: None,
+            ingestion_time: None,
+        }
+    }
+
+    fn fan_out_pipeline(conditions: Vec<(&str, Option<Condition>)>) -> Pipeline {
+        let mut pipeline = Pipeline::new("p1".to_string(), "Plan Pipeline".to_string());
+        pipeline.add_stage(create_stage("source", StageType::Source));
+        for (target, condition) in conditions {
+            pipeline.add_stage(create_stage(target, StageType::Sink));
+            pipeline.add_edge("source", target, condition);
+        }
+        pipeline.enabled = true;
+        pipeline
+    }
+
+    #[test]
+    fn test_plan_preserves_edge_order() {
+        let plan = RoutingPlan::compile(&fan_out_pipeline(vec![
+            ("c", None),
+            ("a", None),
+            ("b", Some(Condition::RecordType("order".to_string()))),
+        ]));
+
+        assert_eq!(plan.targets("source"), ["c", "a", "b"]);
+        assert!(plan.targets("c").is_empty());
+
+        let decisions = plan.route("source", vec![create_record("order", "info")]).decisions;
+        let targets: Vec<_> = decisions.iter().map(|d| d.target_stage_id.as_str()).collect();
+        assert_eq!(targets, ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn test_plan_shares_payload_between_targets() {
+        let plan = RoutingPlan::compile(&fan_out_pipeline(vec![("a", None), ("b", None)]));
+
+        let record = create_record("order", "info");
+        let payload_ptr = record.payload.as_ptr();
+        let decisions = plan.route("source", vec![record]).decisions;
+
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[0].records[0].payload.as_ptr(), payload_ptr);
+        assert_eq!(decisions[1].records[0].payload.as_ptr(), payload_ptr);
+    }
+
+    #[test]
+    fn test_plan_metadata_match_and_invalid_regex() {
+        let plan = RoutingPlan::compile(&fan_out_pipeline(vec![
+            ("errors", Some(Condition::MetadataMatch {
+                key: "level".to_string(),
+                pattern: "^(error|fatal)$".to_string(),
+            })),
+            ("broken", Some(Condition::MetadataMatch {
+                key: "level".to_string(),
+                pattern: "(".to_string(),
+            })),
+            ("not_broken", Some(Condition::Not(Box::new(Condition::MetadataMatch {
+                key: "level".to_string(),
+                pattern: "(".to_string(),
+            })))),
+        ]));
+
+        assert!(plan.targets("source").iter().all(|t| t != "broken"));
+
+        let decisions = plan.route(
+            "source",
+            vec![create_record("log", "fatal"), create_record("log", "info")],
+        ).decisions;
+        let errors = decisions.iter().find(|d| d.target_stage_id == "errors").unwrap();
+        assert_eq!(errors.records.len(), 1);
+        assert!(decisions.iter().all(|d| d.target_stage_id != "broken"));
+        let not_broken = decisions.iter().find(|d| d.target_stage_id == "not_broken").unwrap();
+        assert_eq!(not_broken.records.len(), 2);
+    }
+
+    #[test]
+    fn test_plan_matches_interpreted_conditions() {
+        let conditions = vec![
+            Condition::And(vec![
+                Condition::Always,
+                Condition::MetadataEquals { key: "level".to_string(), value: "error".to_string() },
+            ]),
+            Condition::Or(vec![Condition::Never, Condition::RecordType("metric".to_string())]),
+            Condition::Not(Box::new(Condition::Not(Box::new(Condition::MetadataExists(
+                "level".to_string(),
+            ))))),
+            Condition::Expression(Expression::compile("payload.amount >= 42").unwrap()),
+            Condition::And(vec![]),
+            Condition::Or(vec![]),
+        ];
+        let records = vec![
+            create_record("log", "error"),
+            create_record("metric", "info"),
+            create_record("log", "debug"),
+        ];
+
+        for condition in conditions {
+            let plan = RoutingPlan::compile(&fan_out_pipeline(vec![("t", Some(condition.clone()))]));
+            let routed = plan
+                .route("source", records.clone())
+                .decisions
+                .into_iter()
+                .map(|d| d.records.len())
+                .sum::<usize>();
+            let expected = records.iter().filter(|r| condition.evaluate(r)).count();
+            assert_eq!(routed, expected, "condition {:?}", condition);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_engine_recompiles_plan_on_update() {
+        let engine = RoutingEngine::new();
+        let mut pipeline = fan_out_pipeline(vec![("a", None)]);
+        engine.add_pipeline(pipeline.clone()).await;
+        assert!(engine.get_plan("p1").await.unwrap().is_enabled());
+
+        pipeline.enabled = false;
+        engine.add_pipeline(pipeline).await;
+
+        let batch = RecordBatch {
+            batch_id: "b1".to_string(),
+            records: vec![create_record("log", "info")],
+            watermark: None,
+        };
+        let err = engine.route_batch("p1", "source", batch).await.err().unwrap();
+        assert!(err.to_string().contains("disabled"));
+
+        engine.remove_pipeline("p1").await;
+        assert!(engine.get_plan("p1").await.is_none());
+    }
+}
+
+#[cfg(test)]
+mod routing_mode_tests {
+    use std::collections::HashMap;
+    use crate::{
+        Condition, LoadBalanceStrategy, Pipeline, PipelineValidationError, RoutingEngine,
+        RoutingMode, RoutingPlan, ServiceSelector, Stage, StageType,
+    };
+    use conveyor_etl_proto::common::{Record, RecordBatch};
+
+    fn create_stage(id: &str, stage_type: StageType) -> Stage {
+        Stage {
+            id: id.to_string(),
+            name: format!("{}-stage", id),
+            stage_type,
+            service_selector: ServiceSelector {
+                service_name: Some(format!("{}-service", id)),
+                group_id: None,
+                labels: HashMap::new(),
+                load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
+            },
+            parallelism: 1,
+            lookup_config: None,
+            fan_in_config: None,
+            fan_out_config: None,
+            routing_mode: RoutingMode::AllMatches,
+            partition_key: None,
+        }
+    }
+
+    fn create_record(record_type: &str) -> Record {
+        Record {
+            id: None,
+            record_type: record_type.to_string(),
+            key: Default::default(),
+            payload: Default::default(),
+            metadata: HashMap::new(),
+            event_time: None,
+            ingestion_time: None,
+        }
+    }
+
+    fn record_type(name: &str) -> Option<Condition> {
+        Some(Condition::RecordType(name.to_string()))
+    }
+
+    fn routing_pipeline(mode: RoutingMode) -> Pipeline {
+        let mut pipeline = Pipeline::new("p1".to_string(), "Routing Modes".to_string());
+        let mut source = create_stage("source", StageType::Source);
+        source.routing_mode = mode;
+        pipeline.add_stage(source);
+        for id in ["orders", "audit", "fallback", "dlq"] {
+            pipeline.add_stage(create_stage(id, StageType::Sink));
+        }
+        pipeline.add_edge_with_priority("source", "audit", None, 10);
+        pipeline.add_edge_with_priority("source", "orders", record_type("order"), 1);
+        pipeline
+    }
+
+    fn targets_of(plan: &RoutingPlan, record: &str) -> Vec<String> {
+        plan.route("source", vec![create_record(record)])
+            .decisions
+            .into_iter()
+            .map(|d| d.target_stage_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_all_matches_delivers_to_every_matching_edge() {
+        let plan = RoutingPlan::compile(&routing_pipeline(RoutingMode::AllMatches));
+        assert_eq!(plan.routing_mode("source"), RoutingMode::AllMatches);
+        assert_eq!(targets_of(&plan, "order"), ["audit", "orders"]);
+        assert_eq!(targets_of(&plan, "click"), ["audit"]);
+    }
+
+    #[test]
+    fn test_first_match_uses_lowest_priority() {
+        let plan = RoutingPlan::compile(&routing_pipeline(RoutingMode::FirstMatch));
+        assert_eq!(targets_of(&plan, "order"), ["orders"]);
+        assert_eq!(targets_of(&plan, "click"), ["audit"]);
+    }
+
+    #[test]
+    fn test_first_match_ties_keep_declaration_order() {
+        let mut pipeline = routing_pipeline(RoutingMode::FirstMatch);
+        pipeline.edges.clear();
+        pipeline.add_edge("source", "fallback", None);
+        pipeline.add_edge("source", "orders", None);
+
+        let plan = RoutingPlan::compile(&pipeline);
+        assert_eq!(targets_of(&plan, "order"), ["fallback"]);
+    }
+
+    #[test]
+    fn test_default_edge_only_when_nothing_matches() {
+        let mut pipeline = routing_pipeline(RoutingMode::FirstMatch);
+        pipeline.edges.clear();
+        pipeline.add_edge("source", "orders", record_type("order"));
+        pipeline.add_default_edge("source", "fallback");
+
+        let plan = RoutingPlan::compile(&pipeline);
+        assert_eq!(targets_of(&plan, "order"), ["orders"]);
+        assert_eq!(targets_of(&plan, "click"), ["fallback"]);
+
+        let routed = plan.route("source", vec![create_record("click")]);
+        assert_eq!(routed.unmatched, 0);
+    }
+
+    #[test]
+    fn test_unmatched_records_go_to_dead_letter_stage() {
+        let mut pipeline = routing_pipeline(RoutingMode::AllMatches);
+        pipeline.edges.clear();
+        pipeline.add_edge("source", "orders", record_type("order"));
+
+        let routed = RoutingPlan::compile(&pipeline)
+            .route("source", vec![create_record("order"), create_record("click")]);
+        assert_eq!(routed.unmatched, 1);
+        assert_eq!(routed.decisions.len(), 1);
+
+        pipeline.dead_letter_stage = Some("dlq".to_string());
+        let routed = RoutingPlan::compile(&pipeline)
+            .route("source", vec![create_record("order"), create_record("click")]);
+        assert_eq!(routed.unmatched, 1);
+        let dlq = routed
+            .decisions
+            .iter()
+            .find(|d| d.target_stage_id == "dlq")
+            .unwrap();
+        assert_eq!(dlq.records[0].record_type, "click");
+    }
+
+    #[tokio::test]
+    async fn test_engine_counts_unmatched_records() {
+        let mut pipeline = routing_pipeline(RoutingMode::FirstMatch);
+        pipeline.edges.clear();
+        pipeline.add_edge("source", "orders", record_type("order"));
+        pipeline.enabled = true;
+
+        let engine = RoutingEngine::new();
+        engine.add_pipeline(pipeline).await;
+
+        let batch = RecordBatch {
+            batch_id: "b1".to_string(),
+            records: vec![create_record("click"), create_record("order"), create_record("view")],
+            watermark: None,
+        };
+        let decisions = engine.route_batch("p1", "source", batch).await.unwrap();
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(engine.unmatched_count("p1"), 2);
+        assert_eq!(
+            engine.unmatched_counts(),
+            vec![("p1".to_string(), "source".to_string(), 2)]
+        );
+
+        engine.remove_pipeline("p1").await;
+        assert_eq!(engine.unmatched_count("p1"), 0);
+    }
+
+    #[test]
+    fn test_validation_rejects_conditional_default_edge() {
+        let mut pipeline = routing_pipeline(RoutingMode::FirstMatch);
+        pipeline.edges.clear();
+        pipeline.add_edge("source", "orders", None);
+        pipeline.add_edge("source", "audit", None);
+        pipeline.add_edge("source", "dlq", None);
+        pipeline.add_default_edge("source", "fallback");
+        assert!(pipeline.validate().is_ok());
+
+        pipeline.edges.last_mut().unwrap().condition = record_type("order");
+        assert!(pipeline
+            .validate()
+            .unwrap_err()
+            .iter()
+            .any(|e| matches!(e, PipelineValidationError::ConditionalDefaultEdge { .. })));
+    }
+
+    #[test]
+    fn test_validation_requires_sink_dead_letter_stage() {
+        let mut pipeline = routing_pipeline(RoutingMode::AllMatches);
+        pipeline.edges.clear();
+        pipeline.add_edge("source", "orders", None);
+        pipeline.add_edge("source", "audit", None);
+        pipeline.add_edge("source", "fallback", None);
+
+        pipeline.dead_letter_stage = Some("dlq".to_string());
+        assert!(pipeline.validate().is_ok());
+
+        pipeline.dead_letter_stage = Some("missing".to_string());
+        assert!(pipeline
+            .validate()
+            .unwrap_err()
+            .iter()
+            .any(|e| matches!(e, PipelineValidationError::InvalidDeadLetterStage { .. })));
+
+        pipeline.dead_letter_stage = Some("source".to_string());
+        assert!(pipeline
+            .validate()
+            .unwrap_err()
+            .iter()
+            .any(|e| matches!(e, PipelineValidationError::InvalidDeadLetterStage { .. })));
+    }
+}
+
+#[cfg(test)]
+mod field_mapping_tests {
+    use std::collections::HashMap;
+    use crate::{
+        cast_value, CastErrorStrategy, FanOutConfig, FanOutSink, FieldCastType, FieldMapping,
+        FieldPath, LoadBalanceStrategy, Pipeline, RoutingEngine, RoutingMode, ServiceSelector,
+        Stage, StageType,
+    };
+    use conveyor_etl_proto::common::{Record, RecordBatch};
+    use serde_json::{json, Value};
+
+    fn mapping(source: Option<&str>, target: &str, cast: Option<FieldCastType>) -> FieldMapping {
+        FieldMapping {
+            source_field: source.map(String::from),
+            target_field: target.to_string(),
+            cast_type: cast,
+            default_value: None,
+            literal_value: None,
+            on_error: CastErrorStrategy::Fail,
+        }
+    }
+
+    fn record(payload: &[u8]) -> Record {
+        Record {
+            id: None,
+            record_type: "order".to_string(),
+            key: Default::default(),
+            payload: payload.to_vec().into(),
+            metadata: HashMap::new(),
+            event_time: None,
+            ingestion_time: None,
+        }
+    }
+
+    fn order() -> Record {
+        record(
+            br#"{"id": "42", "customer": {"name": "Ada", "tags": ["vip", "eu"]},
+                 "items": [{"sku": "a-1", "qty": "3"}], "paid": "yes",
+                 "created": 1700000000000, "attrs": "{\"color\": \"red\"}"}"#,
+        )
+    }
+
+    fn apply(mappings: &[FieldMapping]) -> Value {
+        let mapped = RoutingEngine::new().apply_field_mappings(order(), mappings).unwrap();
+        serde_json::from_slice(&mapped.payload).unwrap()
+    }
+
+    #[test]
+    fn test_field_path_get() {
+        let value = json!({"a": {"b": [10, {"c": "x"}]}, "x.y": 1, "a/b": 2});
+        let get = |p: &str| FieldPath::parse(p).unwrap().get(&value).cloned();
+
+        assert_eq!(get("a.b[0]"), Some(json!(10)));
+        assert_eq!(get("a.b[1].c"), Some(json!("x")));
+        assert_eq!(get("/a/b/1/c"), Some(json!("x")));
+        assert_eq!(get(r#"["x.y"]"#), Some(json!(1)));
+        assert_eq!(get("/a~1b"), Some(json!(2)));
+        assert_eq!(get("a.b[5]"), None);
+        assert_eq!(get("a.b.c"), None);
+
+        assert!(FieldPath::parse("a..b").is_err());
+        assert!(FieldPath::parse("a[x]").is_err());
+        assert!(FieldPath::parse("a[0").is_err());
+        assert!(FieldPath::parse("").is_err());
+    }
+
+    #[test]
+    fn test_field_path_set_creates_containers() {
+        let mut value = Value::Null;
+        FieldPath::parse("order.lines[1].sku").unwrap().set(&mut value, json!("b")).unwrap();
+        FieldPath::parse("/order/id").unwrap().set(&mut value, json!(7)).unwrap();
+        assert_eq!(value, json!({"order": {"id": 7, "lines": [null, {"sku": "b"}]}}));
+
+        let err = FieldPath::parse("order.id.x").unwrap().set(&mut value, json!(1));
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_cast_conversions() {
+        use FieldCastType::*;
+
+        assert_eq!(cast_value(json!(12), String), Ok(json!("12")));
+        assert_eq!(cast_value(json!({"a": 1}), String), Ok(json!(r#"{"a":1}"#)));
+        assert_eq!(cast_value(json!(" 12 "), Int), Ok(json!(12)));
+        assert_eq!(cast_value(json!(3.0), Int64), Ok(json!(3)));
+        assert!(cast_value(json!(3.5), Int).is_err());
+        assert!(cast_value(json!(5_000_000_000i64), Int).is_err());
+        assert_eq!(cast_value(json!(5_000_000_000i64), Int64), Ok(json!(5_000_000_000i64)));
+        assert_eq!(cast_value(json!("2.5"), Float), Ok(json!(2.5)));
+        assert_eq!(cast_value(json!("off"), Bool), Ok(json!(false)));
+        assert!(cast_value(json!("maybe"), Bool).is_err());
+        assert_eq!(
+            cast_value(json!(1_700_000_000_000i64), Timestamp),
+            Ok(json!("2023-11-14T22:13:20Z"))
+        );
+        assert_eq!(
+            cast_value(json!("2024-03-01T12:00:00+02:00"), Timestamp),
+            Ok(json!("2024-03-01T10:00:00Z"))
+        );
+        assert_eq!(cast_value(json!("2024-03-01 23:59:59"), Date), Ok(json!("2024-03-01")));
+        assert_eq!(cast_value(json!("[1, 2]"), Json), Ok(json!([1, 2])));
+        assert!(cast_value(json!("{oops"), Json).is_err());
+        assert_eq!(cast_value(json!("hi"), Bytes), Ok(json!("aGk=")));
+        assert_eq!(cast_value(json!([104, 105]), Bytes), Ok(json!("aGk=")));
+        assert_eq!(cast_value(Value::Null, Int), Ok(Value::Null));
+    }
+
+    #[test]
+    fn test_nested_mappings_with_casts() {
+        let mut literal = mapping(None, "meta.source", None);
+        literal.literal_value = Some(br#""fan-out""#.to_vec());
+
+        let payload = apply(&[
+            mapping(Some("id"), "order.id", Some(FieldCastType::Int64)),
+            mapping(Some("customer.name"), "order.customer", None),
+            mapping(Some("/customer/tags/0"), "order.tier", None),
+            mapping(Some("items[0].qty"), "order.lines[0].quantity", Some(FieldCastType::Int)),
+            mapping(Some("paid"), "order.paid", Some(FieldCastType::Bool)),
+            mapping(Some("created"), "order.created_on", Some(FieldCastType::Date)),
+            mapping(Some("attrs"), "order.attrs", Some(FieldCastType::Json)),
+            mapping(Some("missing"), "order.missing", None),
+            literal,
+        ]);
+
+        assert_eq!(
+            payload,
+            json!({
+                "order": {
+                    "id": 42,
+                    "customer": "Ada",
+                    "tier": "vip",
+                    "lines": [{"quantity": 3}],
+                    "paid": true,
+                    "created_on": "2023-11-14",
+                    "attrs": {"color": "red"},
+                },
+                "meta": {"source": "fan-out"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_cast_error_strategies() {
+        let failing = |on_error: CastErrorStrategy| {
+            let mut m = mapping(Some("customer.name"), "n", Some(FieldCastType::Int));
+            m.default_value = Some(b"-1".to_vec());
+            m.on_error = on_error;
+            m
+        };
+
+        let err = RoutingEngine::new()
+            .apply_field_mappings(order(), &[failing(CastErrorStrategy::Fail)])
+            .unwrap_err();
+        assert_eq!(err.field, "n");
+        assert!(err.to_string().contains("not an integer"));
+
+        assert_eq!(apply(&[failing(CastErrorStrategy::UseDefault)]), json!({"n": -1}));
+        assert_eq!(apply(&[failing(CastErrorStrategy::Skip)]), json!({}));
+        assert_eq!(apply(&[failing(CastErrorStrategy::PassThrough)]), json!({"n": "Ada"}));
+    }
+
+    #[test]
+    fn test_invalid_payload_is_an_error() {
+        let engine = RoutingEngine::new();
+        let mappings = [mapping(Some("id"), "id", None)];
+
+        assert!(engine.apply_field_mappings(record(b"not json"), &mappings).is_err());
+        let unchanged = engine.apply_field_mappings(record(b"not json"), &[]).unwrap();
+        assert_eq!(&unchanged.payload[..], b"not json");
+    }
+
+    fn create_stage(id: &str, stage_type: StageType) -> Stage {
+        Stage {
+            id: id.to_string(),
+            name: id.to_string(),
+            stage_type,
+            service_selector: ServiceSelector {
+                service_name: Some(format!("{}-service", id)),
+                group_id: None,
+                labels: HashMap::new(),
+                load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
+            },
+            parallelism: 1,
+            lookup_config: None,
+            fan_in_config: None,
+            fan_out_config: None,
+            routing_mode: RoutingMode::AllMatches,
+            partition_key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_sends_mapping_failures_to_dead_letter_stage() {
+        let mut fan_out = create_stage("split", StageType::FanOut);
+        fan_out.fan_out_config = Some(FanOutConfig {
+            sinks: vec![FanOutSink {
+                id: "ids".to_string(),
+                name: "ids".to_string(),
+                service_selector: fan_out.service_selector.clone(),
+                field_mappings: vec![mapping(Some("id"), "id", Some(FieldCastType::Int))],
+            }],
+        });
+
+        let mut pipeline = Pipeline::new("p1".to_string(), "Fan Out".to_string());
+        pipeline.add_stage(fan_out);
+        pipeline.add_stage(create_stage("dlq", StageType::Sink));
+        pipeline.dead_letter_stage = Some("dlq".to_string());
+
+        let engine = RoutingEngine::new();
+        engine.add_pipeline(pipeline).await;
+
+        let batch = RecordBatch {
+            batch_id: "b1".to_string(),
+            records: vec![record(br#"{"id": "7"}"#), record(br#"{"id": "seven"}"#)],
+            watermark: None,
+        };
+        let decisions = engine.route_fan_out("p1", "split", batch).await.unwrap();
+
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[0].target_stage_id, "split:ids");
+        assert_eq!(&decisions[0].records[0].payload[..], br#"{"id":7}"#);
+
+        assert_eq!(decisions[1].target_stage_id, "dlq");
+        let failed = &decisions[1].records[0];
+        assert_eq!(&failed.payload[..], br#"{"id": "seven"}"#);
+        assert_eq!(failed.metadata["_dlq_failed_stage"], "split:ids");
+        assert!(failed.metadata["_dlq_error_message"].contains("field 'id'"));
+    }
+}
+
+#[cfg(test)]
+mod fan_in_tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use crate::{
+        CastErrorStrategy, FanInConfig, FanInSource, FanInWatermark, FieldMapping,
+        LateRecordCounts, LoadBalanceStrategy, Pipeline, PipelineValidationError, RoutingEngine,
+        RoutingMode, ServiceSelector, SourceWatermark, Stage, StageType,
+    };
+    use conveyor_etl_proto::common::{Record, RecordBatch};
+
+    fn create_stage(id: &str, stage_type: StageType) -> Stage {
+        Stage {
+            id: id.to_string(),
+            name: id.to_string(),
+            stage_type,
+            service_selector: ServiceSelector {
+                service_name: Some(format!("{}-service", id)),
+                group_id: None,
+                labels: HashMap::new(),
+                load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
+            },
+            parallelism: 1,
+            lookup_config: None,
+            fan_in_config: None,
+            fan_out_config: None,
+            routing_mode: RoutingMode::AllMatches,
+            partition_key: None,
+        }
+    }
+
+    fn source(id: &str, idle_timeout: Option<Duration>) -> FanInSource {
+        FanInSource {
+            id: id.to_string(),
+            name: id.to_string(),
+            service_selector: create_stage(id, StageType::Source).service_selector,
+            watermark: Some(SourceWatermark {
+                event_time_field: "ts".to_string(),
+                idle_timeout,
+            }),
+            field_mappings: vec![
+                FieldMapping {
+                    source_field: Some("ts".to_string()),
+                    target_field: "ts".to_string(),
+                    cast_type: None,
+                    default_value: None,
+                    literal_value: None,
+                    on_error: CastErrorStrategy::Fail,
+                },
+                FieldMapping {
+                    source_field: None,
+                    target_field: "origin".to_string(),
+                    cast_type: None,
+                    default_value: None,
+                    literal_value: Some(format!("\"{}\"", id).into_bytes()),
+                    on_error: CastErrorStrategy::Fail,
+                },
+            ],
+        }
+    }
+
+    fn fan_in_pipeline(late_data_stage: Option<&str>, idle_timeout: Option<Duration>) -> Pipeline {
+        let mut merge = create_stage("merge", StageType::FanIn);
+        merge.fan_in_config = Some(FanInConfig {
+            sources: vec![source("web", None), source("mobile", idle_timeout)],
+            watermark: Some(FanInWatermark {
+                allowed_lateness: Some(Duration::from_millis(100)),
+                late_data_stage: late_data_stage.map(String::from),
+            }),
+        });
+
+        let mut pipeline = Pipeline::new("p1".to_string(), "Fan In".to_string());
+        pipeline.enabled = true;
+        pipeline.add_stage(create_stage("merge:web", StageType::Source));
+        pipeline.add_stage(create_stage("merge:mobile", StageType::Source));
+        pipeline.add_stage(merge);
+        pipeline.add_stage(create_stage("sink", StageType::Sink));
+        pipeline.add_stage(create_stage("late", StageType::Sink));
+        pipeline.add_edge("merge:web", "merge", None);
+        pipeline.add_edge("merge:mobile", "merge", None);
+        pipeline.add_edge("merge", "sink", None);
+        pipeline
+    }
+
+    fn batch(events: &[i64]) -> RecordBatch {
+        RecordBatch {
+            batch_id: "b1".to_string(),
+            records: events
+                .iter()
+                .map(|ts| Record {
+                    id: None,
+                    record_type: "event".to_string(),
+                    key: Default::default(),
+                    payload: format!(r#"{{"ts": {}}}"#, ts).into_bytes().into(),
+                    metadata: HashMap::new(),
+                    event_time: None,
+                    ingestion_time: None,
+                })
+                .collect(),
+            watermark: None,
+        }
+    }
+
+    fn payload_ts(record: &Record) -> i64 {
+        let value: serde_json::Value = serde_json::from_slice(&record.payload).unwrap();
+        value["ts"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn test_extract_event_time() {
+        let engine = RoutingEngine::new();
+        let mut record = batch(&[1_700_000_000_000]).records.remove(0);
+        assert_eq!(engine.extract_event_time(&record, Some("ts")), Some(1_700_000_000_000));
+
+        record.payload = br#"{"meta": {"at": "2023-11-14T22:13:20.5Z"}}"#.to_vec().into();
+        assert_eq!(
+            engine.extract_event_time(&record, Some("meta.at")),
+            Some(1_700_000_000_500)
+        );
+
+        assert_eq!(engine.extract_event_time(&record, Some("missing")), None);
+        record.event_time = Some(prost_types::Timestamp {
+            seconds: 1_700_000_000,
+            nanos: 250_000_000,
+        });
+        assert_eq!(
+            engine.extract_event_time(&record, Some("missing")),
+            Some(1_700_000_000_250)
+        );
+        assert_eq!(engine.extract_event_time(&record, None), Some(1_700_000_000_250));
+    }
+
+    #[tokio::test]
+    async fn test_out_of_range_timestamps_have_no_event_time() {
+        let engine = RoutingEngine::new();
+        engine.add_pipeline(fan_in_pipeline(None, None)).await;
+        let far_future = prost_types::Timestamp {
+            seconds: i64::MAX / 100,
+            nanos: 0,
+        };
+
+        let mut record = batch(&[0]).records.remove(0);
+        record.event_time = Some(far_future);
+        assert_eq!(engine.extract_event_time(&record, None), None);
+
+        let mut records = batch(&[1000]);
+        records.watermark = Some(conveyor_etl_proto::common::Watermark {
+            source_id: "web".to_string(),
+            timestamp: Some(far_future),
+            ..Default::default()
+        });
+        engine.route_fan_in("p1", "merge:web", records).await.unwrap();
+        engine.route_fan_in("p1", "merge:mobile", batch(&[2000])).await.unwrap();
+        assert_eq!(engine.fan_in_watermarks(), vec![("p1".into(), "merge".into(), 1000)]);
+    }
+
+    #[tokio::test]
+    async fn test_late_records_go_to_side_output() {
+        let engine = RoutingEngine::new();
+        engine.add_pipeline(fan_in_pipeline(Some("late"), None)).await;
+
+        let decisions = engine.route_fan_in("p1", "merge:web", batch(&[1000])).await.unwrap();
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].target_stage_id, "sink");
+        let merged: serde_json::Value = serde_json::from_slice(&decisions[0].records[0].payload).unwrap();
+        assert_eq!(merged["origin"], "web");
+        assert_eq!(decisions[0].records[0].event_time.as_ref().unwrap().seconds, 1);
+
+        // Mobile has not reported yet, so nothing can be late
+        engine.route_fan_in("p1", "merge:web", batch(&[5000])).await.unwrap();
+        assert!(engine.fan_in_watermarks().is_empty());
+
+        engine.route_fan_in("p1", "merge:mobile", batch(&[2000])).await.unwrap();
+        assert_eq!(engine.fan_in_watermarks(), vec![("p1".into(), "merge".into(), 2000)]);
+
+        let decisions = engine
+            .route_fan_in("p1", "merge:mobile", batch(&[1950, 1850, 2500]))
+            .await
+            .unwrap();
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[0].target_stage_id, "sink");
+        let on_time: Vec<i64> = decisions[0].records.iter().map(payload_ts).collect();
+        assert_eq!(on_time, vec![1950, 2500]);
+
+        assert_eq!(decisions[1].target_stage_id, "late");
+        let late = &decisions[1].records[0];
+        assert_eq!(payload_ts(late), 1850);
+        assert_eq!(late.metadata["_late_fan_in_stage"], "merge");
+        assert_eq!(late.metadata["_late_watermark"], "2000");
+
+        assert_eq!(
+            engine.late_record_counts(),
+            vec![(
+                "p1".into(),
+                "merge".into(),
+                LateRecordCounts {
+                    side_output: 1,
+                    dropped: 0
+                }
+            )]
+        );
+
+        // Counts from stages run in sidecars add to the same totals
+        engine.add_late_records(
+            "p1",
+            "merge",
+            LateRecordCounts {
+                side_output: 2,
+                dropped: 3,
+            },
+        );
+        assert_eq!(
+            engine.late_record_counts()[0].2,
+            LateRecordCounts {
+                side_output: 3,
+                dropped: 3
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_sources_advance_and_late_records_drop() {
+        let engine = RoutingEngine::new();
+        engine
+            .add_pipeline(fan_in_pipeline(None, Some(Duration::ZERO)))
+            .await;
+
+        // The idle mobile source is moved to processing time, so the web
+        // source alone holds the watermark back
+        engine.route_fan_in("p1", "merge:web", batch(&[1000])).await.unwrap();
+        let decisions = engine
+            .route_fan_in("p1", "merge:web", batch(&[950, 800]))
+            .await
+            .unwrap();
+        assert_eq!(decisions.len(), 1);
+        let on_time: Vec<i64> = decisions[0].records.iter().map(payload_ts).collect();
+        assert_eq!(on_time, vec![950]);
+        assert_eq!(engine.late_record_counts()[0].2.dropped, 1);
+
+        engine.remove_pipeline("p1").await;
+        assert!(engine.late_record_counts().is_empty());
+        assert!(engine.fan_in_watermarks().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_route_fan_in_rejects_unknown_source() {
+        let engine = RoutingEngine::new();
+        engine.add_pipeline(fan_in_pipeline(None, None)).await;
+
+        assert!(engine.route_fan_in("p1", "merge", batch(&[1])).await.is_err());
+        assert!(engine.route_fan_in("p1", "merge:tv", batch(&[1])).await.is_err());
+    }
+
+    #[test]
+    fn test_validation_requires_sink_late_data_stage() {
+        assert!(fan_in_pipeline(Some("late"), None).validate().is_ok());
+
+        for stage in ["missing", "merge"] {
+            assert!(fan_in_pipeline(Some(stage), None)
+                .validate()
+                .unwrap_err()
+                .iter()
+                .any(|e| matches!(e, PipelineValidationError::InvalidLateDataStage { .. })));
+        }
+    }
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use crate::{
+        AvroCodec, CastErrorStrategy, CodecRegistry, Condition, Expression, FieldMapping,
+        LookupConfig, LookupKeyMapping, LookupMissStrategy, MergeStrategy, MessagePackCodec,
+        PayloadCodec, ProtobufCodec, RoutingEngine, CONTENT_TYPE_KEY, JSON_CONTENT_TYPE,
+    };
+    use conveyor_etl_proto::common::Record;
+    use prost::Message;
+    use prost_types::{
+        field_descriptor_proto::{Label, Type},
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+    use serde_json::json;
+
+    fn record(record_type: &str, payload: Vec<u8>, content_type: Option<&str>) -> Record {
+        let mut metadata = HashMap::new();
+        if let Some(ct) = content_type {
+            metadata.insert(CONTENT_TYPE_KEY.to_string(), ct.to_string());
+        }
+        Record {
+            id: None,
+            record_type: record_type.to_string(),
+            key: Default::default(),
+            payload: payload.into(),
+            metadata,
+            event_time: None,
+            ingestion_time: None,
+        }
+    }
+
+    fn field(name: &str, number: i32, field_type: Type, label: Label) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(field_type as i32),
+            label: Some(label as i32),
+            json_name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn order_codec() -> ProtobufCodec {
+        let file = FileDescriptorProto {
+            name: Some("orders.proto".to_string()),
+            package: Some("shop".to_string()),
+            syntax: Some("proto3".to_string()),
+            message_type: vec![DescriptorProto {
+                name: Some("Order".to_string()),
+                field: vec![
+                    field("id", 1, Type::String, Label::Optional),
+                    field("amount", 2, Type::Int64, Label::Optional),
+                    field("tags", 3, Type::String, Label::Repeated),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let set = FileDescriptorSet { file: vec![file] }.encode_to_vec();
+        ProtobufCodec::from_descriptor_set(&set, "shop.Order").unwrap()
+    }
+
+    // id = "o1", amount = 150, tags = ["vip"]
+    const ORDER_BYTES: &[u8] = &[0x0a, 0x02, b'o', b'1', 0x10, 0x96, 0x01, 0x1a, 0x03, b'v', b'i', b'p'];
+
+    #[test]
+    fn test_protobuf_codec_round_trip() {
+        let codec = order_codec();
+        assert_eq!(codec.message_name(), "shop.Order");
+
+        let value = codec.decode(ORDER_BYTES).unwrap();
+        assert_eq!(value, json!({"id": "o1", "amount": 150, "tags": ["vip"]}));
+        assert_eq!(codec.decode(&codec.encode(&value).unwrap()).unwrap(), value);
+
+        assert!(codec.encode(&json!({"unknown": 1})).is_err());
+        assert!(ProtobufCodec::from_descriptor_set(b"garbage", "shop.Order").is_err());
+    }
+
+    #[test]
+    fn test_messagepack_codec_round_trip() {
+        let value = json!({"id": 7, "nested": {"ok": true, "items": [1.5, "x"]}});
+        let bytes = MessagePackCodec.encode(&value).unwrap();
+        assert_eq!(MessagePackCodec.decode(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn test_avro_codec_round_trip() {
+        let codec = AvroCodec::new(
+            r#"{
+                "type": "record",
+                "name": "Order",
+                "fields": [
+                    {"name": "id", "type": "string"},
+                    {"name": "amount", "type": "long"},
+                    {"name": "status", "type": {"type": "enum", "name": "Status", "symbols": ["NEW", "PAID"]}},
+                    {"name": "note", "type": ["null", "string"]}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let value = json!({"id": "o1", "amount": 150, "status": "PAID", "note": null});
+        let bytes = codec.encode(&value).unwrap();
+        assert_eq!(codec.decode(&bytes).unwrap(), value);
+        assert!(codec.encode(&json!({"id": "o1"})).is_err());
+        assert!(AvroCodec::new("{not a schema").is_err());
+    }
+
+    #[test]
+    fn test_registry_selects_codec() {
+        let mut codecs = CodecRegistry::new();
+        codecs.register_record_type("order", Arc::new(order_codec()));
+
+        let ct = |r: &Record| codecs.codec_for(r).content_type().to_string();
+        assert_eq!(ct(&record("click", vec![], None)), JSON_CONTENT_TYPE);
+        assert_eq!(ct(&record("order", vec![], None)), "application/x-protobuf");
+        assert_eq!(
+            ct(&record("order", vec![], Some("Application/MsgPack; charset=binary"))),
+            "application/msgpack"
+        );
+        assert_eq!(ct(&record("order", vec![], Some("text/unknown"))), "application/x-protobuf");
+    }
+
+    #[test]
+    fn test_expressions_use_record_codec() {
+        let msgpack = MessagePackCodec.encode(&json!({"amount": 150})).unwrap();
+        let expr = Expression::compile("payload.amount > 100").unwrap();
+        assert!(expr.matches(&record("event", msgpack, Some("application/x-msgpack"))));
+
+        let mut codecs = CodecRegistry::new();
+        codecs.register_record_type("order", Arc::new(order_codec()));
+        let order = record("order", ORDER_BYTES.to_vec(), None);
+        assert!(!expr.matches(&order));
+        assert!(expr.matches_with(&order, &codecs));
+
+        let condition = Condition::Expression(Expression::compile("'vip' in payload.tags").unwrap());
+        let mut pipeline = crate::Pipeline::new("p1".to_string(), "Codecs".to_string());
+        pipeline.add_edge("source", "vip", Some(condition));
+        let plan = crate::RoutingPlan::compile_with_codecs(&pipeline, Arc::new(codecs));
+        assert_eq!(plan.route("source", vec![order]).decisions.len(), 1);
+    }
+
+    fn mapping(source: &str, target: &str) -> FieldMapping {
+        FieldMapping {
+            source_field: Some(source.to_string()),
+            target_field: target.to_string(),
+            cast_type: None,
+            default_value: None,
+            literal_value: None,
+            on_error: CastErrorStrategy::Fail,
+        }
+    }
+
+    #[test]
+    fn test_field_mappings_keep_schemaless_codec() {
+        let engine = RoutingEngine::new();
+        let payload = MessagePackCodec.encode(&json!({"user": {"id": 9}})).unwrap();
+        let mapped = engine
+            .apply_field_mappings(
+                record("event", payload, Some("application/msgpack")),
+                &[mapping("user.id", "user_id")],
+            )
+            .unwrap();
+
+        assert_eq!(MessagePackCodec.decode(&mapped.payload).unwrap(), json!({"user_id": 9}));
+        assert_eq!(mapped.metadata[CONTENT_TYPE_KEY], "application/msgpack");
+    }
+
+    #[test]
+    fn test_field_mappings_write_schema_bound_payloads_as_json() {
+        let mut codecs = CodecRegistry::new();
+        codecs.register_record_type("order", Arc::new(order_codec()));
+        let engine = RoutingEngine::with_codecs(codecs);
+
+        let mapped = engine
+            .apply_field_mappings(
+                record("order", ORDER_BYTES.to_vec(), None),
+                &[mapping("amount", "total"), mapping("tags[0]", "tier")],
+            )
+            .unwrap();
+
+        assert_eq!(mapped.metadata[CONTENT_TYPE_KEY], JSON_CONTENT_TYPE);
+        let payload: serde_json::Value = serde_json::from_slice(&mapped.payload).unwrap();
+        assert_eq!(payload, json!({"total": 150, "tier": "vip"}));
+
+        let err = engine
+            .apply_field_mappings(record("order", b"\xff\xff".to_vec(), None), &[mapping("id", "id")])
+            .unwrap_err();
+        assert!(err.to_string().contains("application/x-protobuf"));
+    }
+
+    #[test]
+    fn test_lookup_keys_and_replace_use_codec() {
+        let mut codecs = CodecRegistry::new();
+        codecs.register_record_type("order", Arc::new(order_codec()));
+        let engine = RoutingEngine::with_codecs(codecs);
+
+        let mut order = record("order", ORDER_BYTES.to_vec(), None);
+        order.metadata.insert("region".to_string(), "eu".to_string());
+        let config = LookupConfig {
+            key_fields: vec![
+                LookupKeyMapping { record_field: "id".to_string(), lookup_key: "order_id".to_string() },
+                LookupKeyMapping { record_field: "amount".to_string(), lookup_key: "amount".to_string() },
+                LookupKeyMapping { record_field: "region".to_string(), lookup_key: "region".to_string() },
+            ],
+            output_prefix: None,
+            merge_strategy: MergeStrategy::Replace,
+            on_miss: LookupMissStrategy::PassThrough,
+            timeout_ms: 100,
+        };
+
+        let keys = engine.extract_lookup_keys(&order, &config).unwrap();
+        assert_eq!(keys["order_id"], b"o1");
+        assert_eq!(keys["amount"], b"150");
+        assert_eq!(keys["region"], b"eu");
+
+        let mut missing = config.clone();
+        missing.key_fields[0].record_field = "customer.id".to_string();
+        assert!(engine.extract_lookup_keys(&order, &missing).is_err());
+
+        let data = HashMap::from([("name".to_string(), b"Ada".to_vec())]);
+        let merged = engine
+            .merge_lookup_result(order, crate::LookupResult::Found { data }, &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(merged.metadata[CONTENT_TYPE_KEY], JSON_CONTENT_TYPE);
+        assert_eq!(&merged.payload[..], br#"{"name":"Ada"}"#);
+    }
+}
+
+#[cfg(test)]
+mod schema_tests {
+    use std::collections::HashMap;
+    use crate::{
+        CompatibilityMode, RecordSchema, SchemaDefinition, SchemaFormat, CONTENT_TYPE_KEY,
+        MSGPACK_CONTENT_TYPE,
+    };
+    use base64::Engine as _;
+    use conveyor_etl_proto::common::Record;
+    use prost::Message;
+    use prost_types::{
+        field_descriptor_proto::{Label, Type},
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+    use serde_json::json;
+
+    fn record(payload: Vec<u8>, content_type: Option<&str>) -> Record {
+        let mut metadata = HashMap::new();
+        if let Some(ct) = content_type {
+            metadata.insert(CONTENT_TYPE_KEY.to_string(), ct.to_string());
+        }
+        Record {
+            id: None,
+            record_type: "order".to_string(),
+            key: Default::default(),
+            payload: payload.into(),
+            metadata,
+            event_time: None,
+            ingestion_time: None,
+        }
+    }
+
+    fn json_schema(schema: serde_json::Value) -> RecordSchema {
+        RecordSchema::compile(SchemaDefinition {
+            format: SchemaFormat::JsonSchema,
+            definition: schema.to_string(),
+            message_name: None,
+        })
+        .unwrap()
+    }
+
+    fn proto_schema(fields: Vec<(&str, i32, Type, Label)>) -> RecordSchema {
+        let file = FileDescriptorProto {
+            name: Some("orders.proto".to_string()),
+            package: Some("shop".to_string()),
+            syntax: Some("proto3".to_string()),
+            message_type: vec![DescriptorProto {
+                name: Some("Order".to_string()),
+                field: fields
+                    .into_iter()
+                    .map(|(name, number, field_type, label)| FieldDescriptorProto {
+                        name: Some(name.to_string()),
+                        number: Some(number),
+                        r#type: Some(field_type as i32),
+                        label: Some(label as i32),
+                        json_name: Some(name.to_string()),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let set = FileDescriptorSet { file: vec![file] }.encode_to_vec();
+        RecordSchema::compile(SchemaDefinition {
+            format: SchemaFormat::Protobuf,
+            definition: base64::engine::general_purpose::STANDARD.encode(set),
+            message_name: Some("shop.Order".to_string()),
+        })
+        .unwrap()
+    }
+
+    fn order_v1() -> RecordSchema {
+        json_schema(json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "amount": {"type": "integer"},
+                "status": {"enum": ["NEW", "PAID"]}
+            },
+            "required": ["id"]
+        }))
+    }
+
+    #[test]
+    fn test_json_schema_validates_payloads() {
+        let schema = order_v1();
+
+        assert!(schema.validate(&record(br#"{"id":"o1","amount":5}"#.to_vec(), None)).is_ok());
+
+        let err = schema
+            .validate(&record(br#"{"id":"o1","amount":"five"}"#.to_vec(), None))
+            .unwrap_err();
+        assert!(err.contains("/amount"), "{}", err);
+
+        assert!(schema.validate(&record(br#"{"amount":5}"#.to_vec(), None)).is_err());
+        assert!(schema.validate(&record(b"not json".to_vec(), None)).is_err());
+
+        let packed = rmp_serde::to_vec_named(&json!({"id": "o1"})).unwrap();
+        assert!(schema.validate(&record(packed, Some(MSGPACK_CONTENT_TYPE))).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_definitions_are_rejected() {
+        let compile = |format, definition: &str, message_name: Option<&str>| {
+            RecordSchema::compile(SchemaDefinition {
+                format,
+                definition: definition.to_string(),
+                message_name: message_name.map(str::to_string),
+            })
+        };
+
+        assert!(compile(SchemaFormat::JsonSchema, "{not json", None).is_err());
+        assert!(compile(SchemaFormat::JsonSchema, r#"{"type": 12}"#, None).is_err());
+        assert!(compile(SchemaFormat::Avro, r#"{"type": "nope"}"#, None).is_err());
+        assert!(compile(SchemaFormat::Protobuf, "!!!", Some("shop.Order")).is_err());
+        assert!(compile(SchemaFormat::Protobuf, "", None).is_err());
+    }
+
+    #[test]
+    fn test_json_schema_backward_compatibility() {
+        let v1 = order_v1();
+
+        let optional_field = json_schema(json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "amount": {"type": "number"},
+                "status": {"enum": ["NEW", "PAID", "SHIPPED"]},
+                "note": {"type": "string"}
+            },
+            "required": ["id"]
+        }));
+        assert!(optional_field.check_compatibility(&v1, CompatibilityMode::Backward).is_ok());
+
+        let required_field = json_schema(json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "amount": {"type": "integer"},
+                "customer": {"type": "string"}
+            },
+            "required": ["id", "customer"]
+        }));
+        let errors = required_field
+            .check_compatibility(&v1, CompatibilityMode::Backward)
+            .unwrap_err();
+        assert!(errors.iter().any(|e| e.contains("'customer' is now required")));
+
+        let narrowed = json_schema(json!({
+            "type": "object",
+            "properties": {"id": {"type": "string"}, "amount": {"type": "string"}},
+            "required": ["id"]
+        }));
+        let errors = narrowed
+            .check_compatibility(&v1, CompatibilityMode::Backward)
+            .unwrap_err();
+        assert!(errors.iter().any(|e| e.contains("/amount")));
+    }
+
+    #[test]
+    fn test_json_schema_forward_and_full_compatibility() {
+        let v1 = order_v1();
+
+        // Widening amount to number is backward compatible, but readers of
+        // v1 would reject fractional amounts written with v2.
+        let widened = json_schema(json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "amount": {"type": "number"},
+                "status": {"enum": ["NEW", "PAID"]}
+            },
+            "required": ["id"]
+        }));
+        assert!(widened.check_compatibility(&v1, CompatibilityMode::Backward).is_ok());
+        assert!(widened.check_compatibility(&v1, CompatibilityMode::Forward).is_err());
+
+        let errors = widened
+            .check_compatibility(&v1, CompatibilityMode::Full)
+            .unwrap_err();
+        assert!(errors.iter().all(|e| e.starts_with("forward:")));
+        assert!(widened.check_compatibility(&v1, CompatibilityMode::None).is_ok());
+
+        let closed = json_schema(json!({
+            "type": "object",
+            "properties": {"id": {"type": "string"}},
+            "required": ["id"],
+            "additionalProperties": false
+        }));
+        assert!(closed.check_compatibility(&v1, CompatibilityMode::Backward).is_err());
+        assert!(closed.check_compatibility(&v1, CompatibilityMode::Forward).is_ok());
+    }
+
+    #[test]
+    fn test_format_change_is_incompatible() {
+        let proto = proto_schema(vec![("id", 1, Type::String, Label::Optional)]);
+        let errors = proto
+            .check_compatibility(&order_v1(), CompatibilityMode::Backward)
+            .unwrap_err();
+        assert!(errors[0].contains("json_schema to protobuf"));
+        assert!(proto.check_compatibility(&order_v1(), CompatibilityMode::None).is_ok());
+    }
+
+    #[test]
+    fn test_same_as_ignores_formatting() {
+        let compact = order_v1();
+        let parsed: serde_json::Value = serde_json::from_str(&compact.definition().definition).unwrap();
+        let pretty = RecordSchema::compile(SchemaDefinition {
+            format: SchemaFormat::JsonSchema,
+            definition: serde_json::to_string_pretty(&parsed).unwrap(),
+            message_name: None,
+        })
+        .unwrap();
+        assert!(pretty.same_as(&compact));
+
+        let reordered = json_schema(json!({
+            "required": ["id"],
+            "properties": {
+                "status": {"enum": ["NEW", "PAID"]},
+                "amount": {"type": "integer"},
+                "id": {"type": "string"}
+            },
+            "type": "object"
+        }));
+        assert!(reordered.same_as(&compact));
+        assert!(!json_schema(json!({"type": "object"})).same_as(&compact));
+    }
+
+    #[test]
+    fn test_protobuf_compatibility() {
+        let v1 = proto_schema(vec![
+            ("id", 1, Type::String, Label::Optional),
+            ("amount", 2, Type::Int64, Label::Optional),
+        ]);
+
+        let added = proto_schema(vec![
+            ("id", 1, Type::String, Label::Optional),
+            ("total", 2, Type::Uint64, Label::Optional),
+            ("tags", 3, Type::String, Label::Repeated),
+        ]);
+        assert!(added.check_compatibility(&v1, CompatibilityMode::Full).is_ok());
+
+        let retyped = proto_schema(vec![
+            ("id", 1, Type::String, Label::Optional),
+            ("amount", 2, Type::Double, Label::Optional),
+        ]);
+        let errors = retyped
+            .check_compatibility(&v1, CompatibilityMode::Backward)
+            .unwrap_err();
+        assert!(errors[0].contains("shop.Order.amount (#2)"), "{:?}", errors);
+
+        let repeated = proto_schema(vec![
+            ("id", 1, Type::String, Label::Repeated),
+            ("amount", 2, Type::Int64, Label::Optional),
+        ]);
+        assert!(repeated.check_compatibility(&v1, CompatibilityMode::Forward).is_err());
+    }
+
+    #[test]
+    fn test_protobuf_validation() {
+        let schema = proto_schema(vec![
+            ("id", 1, Type::String, Label::Optional),
+            ("amount", 2, Type::Int64, Label::Optional),
+        ]);
+
+        assert!(schema.validate(&record(vec![0x0a, 0x02, b'o', b'1', 0x10, 0x05], None)).is_ok());
+        assert!(schema.validate(&record(vec![0x0a, 0x09, b'o'], None)).is_err());
+
+        let json_payload = br#"{"id":"o1","amount":5}"#.to_vec();
+        assert!(schema.validate(&record(json_payload, Some("application/json"))).is_ok());
+        let unknown = br#"{"customer":"c1"}"#.to_vec();
+        assert!(schema.validate(&record(unknown, Some("application/json"))).is_err());
+    }
+
+    #[test]
+    fn test_avro_compatibility_and_validation() {
+        let avro = |definition: &str| {
+            RecordSchema::compile(SchemaDefinition {
+                format: SchemaFormat::Avro,
+                definition: definition.to_string(),
+                message_name: None,
+            })
+            .unwrap()
+        };
+
+        let v1 = avro(
+            r#"{"type": "record", "name": "Order", "fields": [
+                {"name": "id", "type": "string"}
+            ]}"#,
+        );
+        let with_default = avro(
+            r#"{"type": "record", "name": "Order", "fields": [
+                {"name": "id", "type": "string"},
+                {"name": "amount", "type": "long", "default": 0}
+            ]}"#,
+        );
+        let without_default = avro(
+            r#"{"type": "record", "name": "Order", "fields": [
+                {"name": "id", "type": "string"},
+                {"name": "amount", "type": "long"}
+            ]}"#,
+        );
+
+        assert!(with_default.check_compatibility(&v1, CompatibilityMode::Full).is_ok());
+        assert!(without_default.check_compatibility(&v1, CompatibilityMode::Backward).is_err());
+        assert!(without_default.check_compatibility(&v1, CompatibilityMode::Forward).is_ok());
+
+        // "o1" as an Avro string: zigzag length 2, then the bytes.
+        assert!(v1.validate(&record(vec![0x04, b'o', b'1'], None)).is_ok());
+        assert!(v1.validate(&record(vec![0x04, b'o', b'1', 0xff], None)).is_err());
+        assert!(v1
+            .validate(&record(br#"{"id":"o1"}"#.to_vec(), Some("application/json")))
+            .is_ok());
+    }
+}
+
+#[cfg(test)]
+mod partition_tests {
+    use std::collections::HashMap;
+    use crate::{partition_for, CodecRegistry, PartitionKey};
+    use conveyor_etl_proto::common::Record;
+
+    fn record(key: &[u8], payload: &str) -> Record {
+        Record {
+            key: key.to_vec().into(),
+            payload: payload.as_bytes().to_vec().into(),
+            metadata: HashMap::from([("tenant".to_string(), "acme".to_string())]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_partition_key_parse_and_display() {
+        for key in ["record_key", "metadata.tenant", "payload.customer.id"] {
+            assert_eq!(key.parse::<PartitionKey>().unwrap().to_string(), key);
+        }
+        assert!("customer_id".parse::<PartitionKey>().is_err());
+        assert!("metadata.".parse::<PartitionKey>().is_err());
+
+        let key: PartitionKey = serde_json::from_str("\"metadata.tenant\"").unwrap();
+        assert_eq!(key, PartitionKey::Metadata("tenant".to_string()));
+        assert!(serde_json::from_str::<PartitionKey>("\"tenant\"").is_err());
+    }
+
+    #[test]
+    fn test_partition_key_extract() {
+        let codecs = CodecRegistry::new();
+        let record = record(b"k1", r#"{"customer": {"id": 42, "name": "ada"}}"#);
+
+        let extract = |key: &str| key.parse::<PartitionKey>().unwrap().extract(&codecs, &record);
+        assert_eq!(extract("record_key"), Some(b"k1".to_vec()));
+        assert_eq!(extract("metadata.tenant"), Some(b"acme".to_vec()));
+        assert_eq!(extract("payload.customer.id"), Some(b"42".to_vec()));
+        assert_eq!(extract("payload.customer.name"), Some(b"ada".to_vec()));
+        assert_eq!(extract("payload.customer.email"), None);
+        assert_eq!(extract("metadata.region"), None);
+    }
+
+    #[test]
+    fn test_partition_is_stable_and_spread() {
+        let codecs = CodecRegistry::new();
+        // Fixed so that every sidecar agrees, whatever it was built with
+        assert_eq!(partition_for(b"", 8), 0xcbf2_9ce4_8422_2325u64 as u32 % 8);
+        assert_eq!(partition_for(b"customer-1", 1), 0);
+
+        let mut seen = [0; 4];
+        for i in 0..400 {
+            let key = format!("customer-{}", i);
+            let partition = partition_for(key.as_bytes(), 4);
+            assert_eq!(partition, partition_for(key.as_bytes(), 4));
+            seen[partition as usize] += 1;
+        }
+        assert!(seen.iter().all(|&n| n > 50), "{:?}", seen);
+
+        // Records without a key stay together in partition 0
+        let keyless = record(b"", "{}");
+        assert_eq!(PartitionKey::RecordKey.partition(&codecs, &keyless, 4), 0);
+    }
+}
+
 #[cfg(test)]
 mod routing_persistence_tests {
     #[tokio::test]
@@ -0,0 +1,291 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tracing::{debug, info, warn};
+
+use conveyor_etl_proto::sidecar::{EventType, PipelineAssignmentEvent, WatchAssignmentsRequest};
+
+use crate::drain::PipelineDrains;
+use crate::routing::{RoutingTable, SharedRoutingTable};
+use crate::transforms::TransformOptions;
+use super::connection::ClusterConnection;
+use super::conversions::convert_assignment_to_routes;
+use super::drainer::{Drainer, REVOKE_DRAIN_TIMEOUT};
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Keeps a `WatchPipelineAssignments` stream open and applies its events to
+/// the routing table as they arrive. After a disconnect it reconnects with
+/// backoff and resumes from the last applied event, failing over to another
+/// router when this one is down or no longer the leader.
+pub struct AssignmentWatch {
+    connection: ClusterConnection,
+    sidecar_id: String,
+    routing_table: SharedRoutingTable,
+    transforms: TransformOptions,
+    drains: Arc<PipelineDrains>,
+    resume_token: String,
+}
+
+impl AssignmentWatch {
+    pub fn new(
+        connection: ClusterConnection,
+        sidecar_id: String,
+        routing_table: SharedRoutingTable,
+    ) -> Self {
+        Self {
+            connection,
+            sidecar_id,
+            routing_table,
+            transforms: TransformOptions::default(),
+            drains: Arc::new(PipelineDrains::new()),
+            resume_token: String::new(),
+        }
+    }
+
+    /// Options used to compile built-in transforms in assignments.
+    pub fn with_transform_options(mut self, transforms: TransformOptions) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// In-flight tracking shared with the data plane, so revocations can
+    /// wait for batches to finish.
+    pub fn with_drains(mut self, drains: Arc<PipelineDrains>) -> Self {
+        self.drains = drains;
+        self
+    }
+
+    /// Position to start from, such as the token returned at registration.
+    pub fn with_resume_token(mut self, token: String) -> Self {
+        self.resume_token = token;
+        self
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        info!("Watching pipeline assignments for sidecar {}", self.sidecar_id);
+
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let epoch = self.connection.epoch();
+            match self.watch().await {
+                Ok(applied) => {
+                    debug!(applied, "Assignment stream ended, reconnecting");
+                    if applied > 0 {
+                        backoff = MIN_BACKOFF;
+                    }
+                }
+                Err(status) => {
+                    warn!(
+                        code = ?status.code(),
+                        error = %status.message(),
+                        "Assignment watch failed, retrying in {:?}",
+                        backoff
+                    );
+                    // NOT_FOUND means the sidecar is re-registering through
+                    // the heartbeat loop; the retry then gets a snapshot
+                    if self.connection.failover(&status, epoch).await {
+                        backoff = MIN_BACKOFF;
+                    }
+                }
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Streams events until the stream ends, returning how many were applied.
+    async fn watch(&mut self) -> Result<usize, tonic::Status> {
+        let mut stream = self
+            .connection
+            .client()
+            .watch_pipeline_assignments(WatchAssignmentsRequest {
+                sidecar_id: self.sidecar_id.clone(),
+                resume_token: self.resume_token.clone(),
+            })
+            .await?
+            .into_inner();
+
+        let mut applied = 0;
+        while let Some(event) = stream.message().await? {
+            let token = event.resume_token.clone();
+            let draining = {
+                let mut table = self.routing_table.write().await;
+                apply_event(&mut table, &self.drains, event, &self.transforms)
+            };
+            if !draining.is_empty() {
+                self.drainer().spawn(draining, REVOKE_DRAIN_TIMEOUT, true);
+            }
+            self.resume_token = token;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    fn drainer(&self) -> Drainer {
+        Drainer {
+            client: self.connection.client(),
+            sidecar_id: self.sidecar_id.clone(),
+            routing_table: self.routing_table.clone(),
+            drains: self.drains.clone(),
+        }
+    }
+}
+
+/// Applies an event to the routing table, returning the revoked pipelines
+/// that must drain before their routes are removed. Their drains are
+/// started here, under the table lock, and returned with their generations.
+pub(crate) fn apply_event(
+    table: &mut RoutingTable,
+    drains: &PipelineDrains,
+    event: PipelineAssignmentEvent,
+    transforms: &TransformOptions,
+) -> Vec<(String, u64)> {
+    let mut revoked = Vec::new();
+    match EventType::try_from(event.event_type) {
+        Ok(EventType::Assigned) | Ok(EventType::Updated) => {
+            if let Some(assignment) = event.assignment {
+                info!(pipeline = %assignment.pipeline_id, "Pipeline assigned");
+                drains.cancel(&assignment.pipeline_id);
+                table.set_pipeline_routes(convert_assignment_to_routes(assignment, transforms));
+            }
+        }
+        Ok(EventType::Revoked) => {
+            if let Some(assignment) = event.assignment {
+                info!(pipeline = %assignment.pipeline_id, "Pipeline revoked");
+                revoked.push(assignment.pipeline_id);
+            }
+        }
+        Ok(EventType::Snapshot) => {
+            let assigned: HashSet<String> =
+                event.snapshot.iter().map(|a| a.pipeline_id.clone()).collect();
+            let stale: Vec<String> = table
+                .pipeline_ids()
+                .filter(|id| !assigned.contains(*id))
+                .map(str::to_string)
+                .collect();
+            for pipeline_id in stale {
+                info!(pipeline = %pipeline_id, "Pipeline no longer assigned");
+                revoked.push(pipeline_id);
+            }
+            for assignment in event.snapshot {
+                drains.cancel(&assignment.pipeline_id);
+                table.set_pipeline_routes(convert_assignment_to_routes(assignment, transforms));
+            }
+            info!(pipelines = assigned.len(), "Applied assignment snapshot");
+        }
+        _ => warn!(event_type = event.event_type, "Ignoring unknown assignment event"),
+    }
+
+    if event.drain_first {
+        return revoked
+            .into_iter()
+            .map(|id| {
+                let generation = drains.start(&id);
+                (id, generation)
+            })
+            .collect();
+    }
+    for pipeline_id in revoked {
+        table.remove_pipeline(&pipeline_id);
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conveyor_etl_proto::sidecar::PipelineAssignment;
+
+    fn event(event_type: EventType, pipeline_id: &str) -> PipelineAssignmentEvent {
+        PipelineAssignmentEvent {
+            event_type: event_type as i32,
+            assignment: Some(PipelineAssignment {
+                pipeline_id: pipeline_id.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn pipelines(table: &RoutingTable) -> Vec<&str> {
+        let mut ids: Vec<_> = table.pipeline_ids().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_apply_events() {
+        let transforms = TransformOptions::default();
+        let drains = PipelineDrains::new();
+        let mut table = RoutingTable::new();
+
+        apply_event(&mut table, &drains, event(EventType::Assigned, "p1"), &transforms);
+        apply_event(&mut table, &drains, event(EventType::Assigned, "p2"), &transforms);
+        apply_event(&mut table, &drains, event(EventType::Revoked, "p1"), &transforms);
+        assert_eq!(pipelines(&table), vec!["p2"]);
+
+        let snapshot = PipelineAssignmentEvent {
+            event_type: EventType::Snapshot as i32,
+            snapshot: vec![PipelineAssignment {
+                pipeline_id: "p3".to_string(),
+                ..Default::default()
+            }],
+            drain_first: true,
+            ..Default::default()
+        };
+        let draining = apply_event(&mut table, &drains, snapshot, &transforms);
+        assert_eq!(draining.len(), 1);
+        assert_eq!(draining[0].0, "p2");
+        assert!(drains.is_draining("p2"), "drain starts before the event returns");
+        assert_eq!(pipelines(&table), vec!["p2", "p3"], "p2 is removed after draining");
+    }
+
+    #[tokio::test]
+    async fn test_revoke_then_immediate_reassign_keeps_routes() {
+        use conveyor_etl_proto::sidecar::sidecar_coordinator_client::SidecarCoordinatorClient;
+        use tokio::sync::RwLock;
+        use tonic::transport::Channel;
+
+        let transforms = TransformOptions::default();
+        let drains = Arc::new(PipelineDrains::new());
+        let routing_table: SharedRoutingTable = Arc::new(RwLock::new(RoutingTable::new()));
+        let drainer = Drainer {
+            client: SidecarCoordinatorClient::new(
+                Channel::from_static("http://127.0.0.1:1").connect_lazy(),
+            ),
+            sidecar_id: "sidecar-1".to_string(),
+            routing_table: routing_table.clone(),
+            drains: drains.clone(),
+        };
+
+        let batch = {
+            let mut table = routing_table.write().await;
+            apply_event(&mut table, &drains, event(EventType::Assigned, "p1"), &transforms);
+            drains.begin("p1").unwrap()
+        };
+
+        let revoke = PipelineAssignmentEvent {
+            drain_first: true,
+            ..event(EventType::Revoked, "p1")
+        };
+        let draining = {
+            let mut table = routing_table.write().await;
+            apply_event(&mut table, &drains, revoke, &transforms)
+        };
+        let drain = drainer.spawn(draining, Duration::from_secs(5), true);
+        {
+            let mut table = routing_table.write().await;
+            apply_event(&mut table, &drains, event(EventType::Assigned, "p1"), &transforms);
+        }
+        drop(batch);
+        drain.await.unwrap();
+
+        assert_eq!(pipelines(&*routing_table.read().await), vec!["p1"]);
+        assert!(!drains.is_draining("p1"));
+        assert!(drains.begin("p1").is_some());
+    }
+}
@@ -0,0 +1,217 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::sync::mpsc;
+use tonic::transport::channel::Change;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+use tracing::{debug, info, warn};
+
+use conveyor_etl_proto::sidecar::sidecar_coordinator_client::SidecarCoordinatorClient;
+use conveyor_etl_proto::LEADER_HINT_KEY;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The channel to the router cluster, shared by every control-plane client.
+///
+/// It talks to one router at a time. When a call fails with `UNAVAILABLE`
+/// (the router is down or is not the leader), [`failover`](Self::failover)
+/// moves it to the leader the router named, or else to the next reachable
+/// router, and every client built from the channel follows.
+#[derive(Clone)]
+pub struct ClusterConnection {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Router addresses as configured; DNS names may resolve to several.
+    targets: Vec<String>,
+    channel: Channel,
+    changes: mpsc::Sender<Change<String, Endpoint>>,
+    current: Mutex<String>,
+    /// Bumped on every switch, so concurrent failovers move only once.
+    epoch: AtomicU64,
+    switching: tokio::sync::Mutex<()>,
+}
+
+impl ClusterConnection {
+    /// Connects to the first reachable router among `targets`.
+    pub async fn connect(targets: Vec<String>) -> Result<Self> {
+        let candidates = resolve(&targets).await;
+        let mut connected = None;
+        for addr in &candidates {
+            match probe(addr).await {
+                Ok(endpoint) => {
+                    connected = Some((addr.clone(), endpoint));
+                    break;
+                }
+                Err(e) => warn!(router = %addr, error = %e, "Router unreachable"),
+            }
+        }
+        let (addr, endpoint) = connected
+            .with_context(|| format!("No router reachable at {}", targets.join(", ")))?;
+        info!(router = %addr, "Connected to cluster");
+
+        let (channel, changes) = Channel::balance_channel(16);
+        changes
+            .send(Change::Insert(addr.clone(), endpoint))
+            .await
+            .context("Cluster channel closed")?;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                targets,
+                channel,
+                changes,
+                current: Mutex::new(addr),
+                epoch: AtomicU64::new(0),
+                switching: tokio::sync::Mutex::new(()),
+            }),
+        })
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.inner.channel.clone()
+    }
+
+    pub fn client(&self) -> SidecarCoordinatorClient<Channel> {
+        SidecarCoordinatorClient::new(self.channel())
+    }
+
+    /// The router currently in use.
+    pub fn current(&self) -> String {
+        self.inner.current.lock().unwrap().clone()
+    }
+
+    /// Identifies the current router; pass it to [`failover`](Self::failover)
+    /// for errors from calls made now.
+    pub fn epoch(&self) -> u64 {
+        self.inner.epoch.load(Ordering::Acquire)
+    }
+
+    /// Moves to another router after `status` from a call made at `epoch`,
+    /// unless another failover already did. Returns false if no other router
+    /// was reachable, leaving the current one in place.
+    pub async fn failover(&self, status: &Status, epoch: u64) -> bool {
+        if status.code() != Code::Unavailable {
+            return false;
+        }
+        let _switching = self.inner.switching.lock().await;
+        if self.epoch() != epoch {
+            return true;
+        }
+
+        let current = self.current();
+        let hint = status
+            .metadata()
+            .get(LEADER_HINT_KEY)
+            .and_then(|v| v.to_str().ok());
+        let resolved = resolve(&self.inner.targets).await;
+        let candidates = failover_candidates(&current, hint, &resolved);
+
+        for addr in candidates {
+            match probe(&addr).await {
+                Ok(endpoint) => {
+                    self.switch(&current, addr, endpoint).await;
+                    return true;
+                }
+                Err(e) => debug!(router = %addr, error = %e, "Router unreachable"),
+            }
+        }
+        warn!(router = %current, "No other router reachable");
+        false
+    }
+
+    async fn switch(&self, from: &str, to: String, endpoint: Endpoint) {
+        info!(from = %from, to = %to, "Failing over to router");
+        let _ = self.inner.changes.send(Change::Insert(to.clone(), endpoint)).await;
+        if from != to {
+            let _ = self.inner.changes.send(Change::Remove(from.to_string())).await;
+        }
+        *self.inner.current.lock().unwrap() = to;
+        self.inner.epoch.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+async fn probe(addr: &str) -> Result<Endpoint> {
+    let endpoint = Endpoint::from_shared(format!("http://{}", addr))
+        .context("Invalid cluster endpoint")?
+        .connect_timeout(CONNECT_TIMEOUT);
+    endpoint.connect().await?;
+    Ok(endpoint)
+}
+
+/// Expands each target to the addresses its name resolves to, so a headless
+/// service name reaches every router. Targets that fail to resolve are kept
+/// as they are.
+async fn resolve(targets: &[String]) -> Vec<String> {
+    let mut addrs: Vec<String> = Vec::new();
+    for target in targets {
+        match tokio::net::lookup_host(target.as_str()).await {
+            Ok(resolved) => addrs.extend(resolved.map(|a| a.to_string())),
+            Err(e) => {
+                debug!(target = %target, error = %e, "Failed to resolve router address");
+                addrs.push(target.clone());
+            }
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    addrs.retain(|a| seen.insert(a.clone()));
+    addrs
+}
+
+/// Routers to try in order after `current` failed: the hinted leader first,
+/// then the others starting after `current`.
+fn failover_candidates(current: &str, hint: Option<&str>, routers: &[String]) -> Vec<String> {
+    let mut candidates = Vec::new();
+    if let Some(leader) = hint.filter(|leader| *leader != current) {
+        candidates.push(leader.to_string());
+    }
+
+    let start = routers.iter().position(|r| r == current).map_or(0, |i| i + 1);
+    for router in routers[start..].iter().chain(&routers[..start]) {
+        if router != current && !candidates.contains(router) {
+            candidates.push(router.clone());
+        }
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routers() -> Vec<String> {
+        vec!["10.0.0.1:50051".into(), "10.0.0.2:50051".into(), "10.0.0.3:50051".into()]
+    }
+
+    #[test]
+    fn test_failover_follows_leader_hint() {
+        let routers = routers();
+        assert_eq!(
+            failover_candidates("10.0.0.1:50051", Some("10.0.0.3:50051"), &routers),
+            vec!["10.0.0.3:50051", "10.0.0.2:50051"]
+        );
+        assert_eq!(
+            failover_candidates("10.0.0.1:50051", Some("router-2:50061"), &routers)[0],
+            "router-2:50061",
+            "the hint is used as given, even for routers not configured"
+        );
+    }
+
+    #[test]
+    fn test_failover_without_hint_tries_next_router() {
+        let routers = routers();
+        assert_eq!(
+            failover_candidates("10.0.0.2:50051", None, &routers),
+            vec!["10.0.0.3:50051", "10.0.0.1:50051"]
+        );
+        assert_eq!(
+            failover_candidates("10.0.0.1:50051", Some("10.0.0.1:50051"), &routers),
+            vec!["10.0.0.2:50051", "10.0.0.3:50051"],
+            "a hint naming the current router is ignored"
+        );
+    }
+}
@@ -1,12 +1,41 @@
-use crate::routing::{PipelineRoutes, StageRoute, RouteDecision};
+use std::sync::Arc;
 
+use anyhow::{Context, Result};
+use tracing::warn;
+
+use conveyor_etl_dsl::TransformConfigDsl;
+
+use crate::routing::{PipelineRoutes, StageGraph, StageInstance, StageRoute, RouteDecision};
+use crate::operators::{is_stateful, StatefulOperator};
+use crate::transforms::{BuiltinTransform, TransformOptions};
+
+/// Stages whose built-in transform fails to compile are left out of the
+/// routes, so records for them are not acknowledged as processed.
 pub fn convert_assignment_to_routes(
     assignment: conveyor_etl_proto::sidecar::PipelineAssignment,
+    transforms: &TransformOptions,
 ) -> PipelineRoutes {
+    let pipeline_id = assignment.pipeline_id;
+    let graph = if assignment.stage_graph.is_empty() {
+        warn!(pipeline = %pipeline_id, "Assignment has no stage graph");
+        None
+    } else {
+        match StageGraph::from_json(&assignment.stage_graph, transforms.codecs.clone()) {
+            Ok(graph) => Some(Arc::new(graph)),
+            Err(e) => {
+                warn!(
+                    pipeline = %pipeline_id,
+                    error = %format!("{:#}", e),
+                    "Ignoring invalid stage graph"
+                );
+                None
+            }
+        }
+    };
     let stages = assignment
         .stages
         .into_iter()
-        .map(|stage| {
+        .filter_map(|stage| {
             let decision = match stage.target {
                 Some(conveyor_etl_proto::sidecar::stage_assignment::Target::LocalEndpoint(ep)) => {
                     RouteDecision::Local { endpoint: ep }
@@ -17,24 +46,133 @@ pub fn convert_assignment_to_routes(
                         endpoint: remote.endpoint,
                     }
                 }
+                Some(conveyor_etl_proto::sidecar::stage_assignment::Target::BuiltinTransform(config)) => {
+                    match compile_transform(&pipeline_id, &stage.stage_id, &config, transforms) {
+                        Ok(decision) => decision,
+                        Err(e) => {
+                            warn!(
+                                pipeline = %pipeline_id,
+                                stage = %stage.stage_id,
+                                error = %format!("{:#}", e),
+                                "Skipping stage with invalid built-in transform"
+                            );
+                            return None;
+                        }
+                    }
+                }
                 None => RouteDecision::Local {
                     endpoint: String::new(),
                 },
             };
+            let decision = if !stage.partitions.is_empty() {
+                match partition_owners(stage.partitions) {
+                    Some(owners) => RouteDecision::Partitioned { owners },
+                    None => {
+                        warn!(
+                            pipeline = %pipeline_id,
+                            stage = %stage.stage_id,
+                            "Stage has a partition without an owner, not partitioning it"
+                        );
+                        decision
+                    }
+                }
+            } else if stage.instances.len() > 1
+                && (matches!(decision, RouteDecision::Remote { .. }) || has_split(&graph, &stage.stage_id))
+            {
+                RouteDecision::Balanced {
+                    instances: stage_instances(stage.instances),
+                }
+            } else {
+                decision
+            };
 
-            (
+            Some((
                 stage.stage_id.clone(),
                 StageRoute {
                     stage_id: stage.stage_id,
                     decision,
                 },
-            )
+            ))
         })
         .collect();
 
     PipelineRoutes {
-        pipeline_id: assignment.pipeline_id,
+        pipeline_id,
         is_local_complete: assignment.is_local_complete,
         stages,
+        graph,
     }
 }
+
+fn partition_owners(
+    owners: Vec<conveyor_etl_proto::sidecar::PartitionOwner>,
+) -> Option<Vec<RouteDecision>> {
+    owners
+        .into_iter()
+        .map(|owner| match owner.target? {
+            conveyor_etl_proto::sidecar::partition_owner::Target::LocalEndpoint(endpoint) => {
+                Some(RouteDecision::Local { endpoint })
+            }
+            conveyor_etl_proto::sidecar::partition_owner::Target::RemoteSidecar(remote) => {
+                Some(RouteDecision::Remote {
+                    sidecar_id: remote.sidecar_id,
+                    endpoint: remote.endpoint,
+                })
+            }
+        })
+        .collect()
+}
+
+/// Whether the stage's traffic is split between versions, in which case it
+/// is balanced even when it also runs on this sidecar.
+fn has_split(graph: &Option<Arc<StageGraph>>, stage_id: &str) -> bool {
+    graph
+        .as_ref()
+        .and_then(|g| g.service_selector(stage_id))
+        .is_some_and(|s| s.traffic_split.is_some())
+}
+
+/// Instances without a target are left out.
+fn stage_instances(instances: Vec<conveyor_etl_proto::sidecar::StageInstance>) -> Vec<StageInstance> {
+    instances
+        .into_iter()
+        .filter_map(|instance| {
+            let target = match instance.target? {
+                conveyor_etl_proto::sidecar::stage_instance::Target::LocalEndpoint(endpoint) => {
+                    RouteDecision::Local { endpoint }
+                }
+                conveyor_etl_proto::sidecar::stage_instance::Target::RemoteSidecar(remote) => {
+                    RouteDecision::Remote {
+                        sidecar_id: remote.sidecar_id,
+                        endpoint: remote.endpoint,
+                    }
+                }
+            };
+            Some(StageInstance {
+                target,
+                labels: instance.labels,
+            })
+        })
+        .collect()
+}
+
+fn compile_transform(
+    pipeline_id: &str,
+    stage_id: &str,
+    config: &str,
+    transforms: &TransformOptions,
+) -> Result<RouteDecision> {
+    let config: TransformConfigDsl =
+        serde_json::from_str(config).context("Invalid transform config")?;
+
+    if is_stateful(&config) {
+        let operator = StatefulOperator::compile(pipeline_id, stage_id, &config, transforms)?;
+        return Ok(RouteDecision::Stateful {
+            operator: Arc::new(operator),
+        });
+    }
+
+    Ok(RouteDecision::Builtin {
+        transform: Arc::new(BuiltinTransform::compile(&config, transforms)?),
+    })
+}
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tonic::transport::Channel;
+use tracing::{info, warn};
+
+use conveyor_etl_proto::sidecar::{
+    sidecar_coordinator_client::SidecarCoordinatorClient, DrainCompleteRequest,
+};
+
+use crate::drain::PipelineDrains;
+use crate::routing::SharedRoutingTable;
+
+/// How long a revocation with `drain_first` waits for in-flight batches.
+pub(crate) const REVOKE_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Drains pipelines in the background and reports back to the router.
+#[derive(Clone)]
+pub(crate) struct Drainer {
+    pub client: SidecarCoordinatorClient<Channel>,
+    pub sidecar_id: String,
+    pub routing_table: SharedRoutingTable,
+    pub drains: Arc<PipelineDrains>,
+}
+
+impl Drainer {
+    /// Waits up to `timeout` for in-flight batches of pipelines whose drains
+    /// the caller already started with [`PipelineDrains::start`], paired
+    /// with the generation it returned. Starting them before this spawns
+    /// means an assignment applied right after the revoke always cancels
+    /// the drain. With `revoke`, routes are removed afterwards unless that
+    /// drain was cancelled or superseded in the meantime.
+    pub fn spawn(
+        &self,
+        pipelines: Vec<(String, u64)>,
+        timeout: Duration,
+        revoke: bool,
+    ) -> JoinHandle<()> {
+        let drainer = self.clone();
+        tokio::spawn(async move { drainer.run(pipelines, timeout, revoke).await })
+    }
+
+    async fn run(mut self, pipelines: Vec<(String, u64)>, timeout: Duration, revoke: bool) {
+        let (pipeline_ids, generations): (Vec<String>, Vec<u64>) = pipelines.into_iter().unzip();
+        info!(pipelines = ?pipeline_ids, ?timeout, revoke, "Draining pipelines");
+
+        let idle = self.drains.wait_idle(&pipeline_ids, timeout).await;
+        if !idle {
+            warn!(pipelines = ?pipeline_ids, "Drain timed out with batches in flight");
+        }
+
+        if revoke {
+            let mut table = self.routing_table.write().await;
+            for (pipeline_id, generation) in pipeline_ids.iter().zip(generations) {
+                if self.drains.finish(pipeline_id, generation) {
+                    table.remove_pipeline(pipeline_id);
+                }
+            }
+        }
+
+        let report = DrainCompleteRequest {
+            sidecar_id: self.sidecar_id.clone(),
+            pipeline_ids,
+            timed_out: !idle,
+            revoked: revoke,
+        };
+        if let Err(e) = self.client.report_drain_complete(report).await {
+            warn!(error = %e, "Failed to report drain completion");
+        }
+    }
+}
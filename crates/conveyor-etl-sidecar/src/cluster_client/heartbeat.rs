@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::RwLock;
@@ -6,38 +7,90 @@ use anyhow::Result;
 use tracing::{info, warn, debug, error};
 
 use conveyor_etl_proto::sidecar::{
-    sidecar_coordinator_client::SidecarCoordinatorClient,
-    SidecarHeartbeatRequest, LocalServiceHealth,
-    sidecar_command,
+    SidecarHeartbeatRequest, SidecarLoad, LocalServiceHealth, ServiceVersionStats,
+    LateRecordStats, sidecar_command,
 };
-use tonic::transport::Channel;
+use tonic::Code;
 
 use crate::discovery::LocalServiceRegistry;
-use crate::routing::SharedRoutingTable;
+use crate::drain::PipelineDrains;
+use crate::fan::FanStages;
+use crate::routing::{LocalRouter, SharedRoutingTable, StageBalancer};
+use crate::transforms::TransformOptions;
 use super::conversions::convert_assignment_to_routes;
+use super::drainer::{Drainer, REVOKE_DRAIN_TIMEOUT};
+use super::registration::ClusterRegistration;
 
+/// Sends heartbeats and carries out the commands they return. Fails over
+/// to another router when this one is down or no longer the leader, and
+/// registers again when the router no longer knows the sidecar.
 pub struct HeartbeatLoop {
-    client: SidecarCoordinatorClient<Channel>,
+    registration: ClusterRegistration,
     sidecar_id: String,
     routing_table: SharedRoutingTable,
     interval: Duration,
+    transforms: TransformOptions,
+    drains: Arc<PipelineDrains>,
+    local_router: Arc<LocalRouter>,
+    balancer: Arc<StageBalancer>,
+    fan: Arc<FanStages>,
+    /// Registry revision last registered with the cluster.
+    registered_revision: u64,
 }
 
 impl HeartbeatLoop {
     pub fn new(
-        client: SidecarCoordinatorClient<Channel>,
-        sidecar_id: String,
+        registration: ClusterRegistration,
         routing_table: SharedRoutingTable,
         interval: Duration,
     ) -> Self {
         Self {
-            client,
-            sidecar_id,
+            sidecar_id: registration.config().sidecar_id.clone(),
+            registration,
             routing_table,
             interval,
+            transforms: TransformOptions::default(),
+            drains: Arc::new(PipelineDrains::new()),
+            local_router: Arc::new(LocalRouter::new()),
+            balancer: Arc::new(StageBalancer::new()),
+            fan: Arc::new(FanStages::default()),
+            registered_revision: 0,
         }
     }
 
+    /// Options used to compile built-in transforms in pushed assignments.
+    pub fn with_transform_options(mut self, transforms: TransformOptions) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// In-flight tracking shared with the data plane, used to carry out
+    /// drain commands.
+    pub fn with_drains(mut self, drains: Arc<PipelineDrains>) -> Self {
+        self.drains = drains;
+        self
+    }
+
+    /// Router used for local calls, whose in-flight counts are reported.
+    /// Batches in flight in `drains` are reported as the queue depth.
+    pub fn with_local_router(mut self, local_router: Arc<LocalRouter>) -> Self {
+        self.local_router = local_router;
+        self
+    }
+
+    /// Balancer whose calls to each version of split services are reported,
+    /// and which takes the other sidecars' load from the responses.
+    pub fn with_stage_balancer(mut self, balancer: Arc<StageBalancer>) -> Self {
+        self.balancer = balancer;
+        self
+    }
+
+    /// Fan-in stages whose late records are reported.
+    pub fn with_fan_stages(mut self, fan: Arc<FanStages>) -> Self {
+        self.fan = fan;
+        self
+    }
+
     pub async fn run(
         mut self,
         registry: Arc<RwLock<LocalServiceRegistry>>,
@@ -54,11 +107,17 @@ impl HeartbeatLoop {
 
             let service_health = {
                 let reg = registry.read().await;
+                if reg.revision() != self.registered_revision {
+                    drop(reg);
+                    info!("Local services changed, registering again");
+                    self.reregister(&registry).await;
+                    continue;
+                }
                 reg.all_services()
                     .map(|svc| LocalServiceHealth {
                         service_name: svc.name.clone(),
-                        healthy: true,
-                        active_requests: 0,
+                        healthy: reg.is_healthy(&svc.name),
+                        active_requests: self.local_router.in_flight(&svc.endpoint),
                     })
                     .collect()
             };
@@ -66,24 +125,68 @@ impl HeartbeatLoop {
             let request = SidecarHeartbeatRequest {
                 sidecar_id: self.sidecar_id.clone(),
                 service_health,
-                load: None,
+                load: Some(SidecarLoad {
+                    total_active_requests: self.local_router.total_in_flight(),
+                    local_queue_depth: self.drains.total_in_flight() as u64,
+                    ..Default::default()
+                }),
+                version_stats: self
+                    .balancer
+                    .take_version_stats()
+                    .into_iter()
+                    .map(|(service_name, version, stats)| ServiceVersionStats {
+                        service_name,
+                        version,
+                        requests: stats.requests,
+                        errors: stats.errors,
+                        total_latency_ms: stats.total_latency_ms,
+                    })
+                    .collect(),
+                late_records: self
+                    .fan
+                    .take_late_record_counts()
+                    .into_iter()
+                    .map(|(pipeline_id, stage_id, counts)| LateRecordStats {
+                        pipeline_id,
+                        stage_id,
+                        side_output: counts.side_output,
+                        dropped: counts.dropped,
+                    })
+                    .collect(),
             };
 
-            match self.client.heartbeat(request).await {
+            let epoch = self.registration.connection().epoch();
+            match self.registration.client().heartbeat(request).await {
                 Ok(response) => {
                     let resp = response.into_inner();
 
                     if !resp.acknowledged {
-                        warn!("Heartbeat not acknowledged");
+                        warn!("Heartbeat not acknowledged, registering again");
+                        self.reregister(&registry).await;
                         continue;
                     }
 
                     for command in resp.commands {
                         self.handle_command(command).await;
                     }
+                    for load in resp.instance_loads {
+                        self.balancer.update_load(&load.endpoint, load.queue_depth);
+                    }
 
                     debug!("Heartbeat successful");
                 }
+                Err(status) if status.code() == Code::NotFound => {
+                    warn!("Router does not know this sidecar, registering again");
+                    self.reregister(&registry).await;
+                }
+                Err(status) if status.code() == Code::Unavailable => {
+                    warn!(
+                        router = %self.registration.connection().current(),
+                        error = %status.message(),
+                        "Heartbeat failed, trying another router"
+                    );
+                    self.registration.connection().failover(&status, epoch).await;
+                }
                 Err(e) => {
                     error!("Heartbeat failed: {}", e);
                 }
@@ -91,6 +194,43 @@ impl HeartbeatLoop {
         }
     }
 
+    /// Registers again and replaces the routing table with the assignments
+    /// returned, draining pipelines no longer assigned. If registration
+    /// fails the routes stay as they are, so traffic keeps flowing.
+    async fn reregister(&mut self, registry: &RwLock<LocalServiceRegistry>) {
+        let routes = {
+            let reg = registry.read().await;
+            match self.registration.register(&reg, &self.transforms).await {
+                Ok(routes) => {
+                    self.registered_revision = reg.revision();
+                    routes
+                }
+                Err(e) => {
+                    error!("Re-registration failed: {:#}", e);
+                    return;
+                }
+            }
+        };
+
+        let assigned: HashSet<String> = routes.iter().map(|r| r.pipeline_id.clone()).collect();
+        let stale: Vec<(String, u64)> = {
+            let mut table = self.routing_table.write().await;
+            for routes in routes {
+                self.drains.cancel(&routes.pipeline_id);
+                table.set_pipeline_routes(routes);
+            }
+            table
+                .pipeline_ids()
+                .filter(|id| !assigned.contains(*id))
+                .map(|id| (id.to_string(), self.drains.start(id)))
+                .collect()
+        };
+        info!(pipelines = assigned.len(), stale = stale.len(), "Registered again");
+        if !stale.is_empty() {
+            self.drainer().spawn(stale, REVOKE_DRAIN_TIMEOUT, true);
+        }
+    }
+
     async fn handle_command(&self, command: conveyor_etl_proto::sidecar::SidecarCommand) {
         let cmd = match command.command {
             Some(c) => c,
@@ -100,18 +240,52 @@ impl HeartbeatLoop {
         match cmd {
             sidecar_command::Command::Assign(assignment) => {
                 info!("Received pipeline assignment: {}", assignment.pipeline_id);
-                let routes = convert_assignment_to_routes(assignment);
+                self.drains.cancel(&assignment.pipeline_id);
+                let routes = convert_assignment_to_routes(assignment, &self.transforms);
                 let mut table = self.routing_table.write().await;
                 table.set_pipeline_routes(routes);
             }
             sidecar_command::Command::Revoke(revocation) => {
                 info!("Received pipeline revocation: {}", revocation.pipeline_id);
-                let mut table = self.routing_table.write().await;
-                table.remove_pipeline(&revocation.pipeline_id);
+                if revocation.drain_first {
+                    let generation = self.drains.start(&revocation.pipeline_id);
+                    self.drainer().spawn(
+                        vec![(revocation.pipeline_id, generation)],
+                        REVOKE_DRAIN_TIMEOUT,
+                        true,
+                    );
+                } else {
+                    let mut table = self.routing_table.write().await;
+                    table.remove_pipeline(&revocation.pipeline_id);
+                }
             }
-            sidecar_command::Command::Drain(_drain) => {
-                info!("Received drain command");
+            sidecar_command::Command::Drain(drain) => {
+                let pipeline_ids = if drain.pipeline_ids.is_empty() {
+                    let table = self.routing_table.read().await;
+                    table.pipeline_ids().map(str::to_string).collect()
+                } else {
+                    drain.pipeline_ids
+                };
+                info!(pipelines = ?pipeline_ids, "Received drain command");
+                let pipelines = pipeline_ids
+                    .into_iter()
+                    .map(|id| {
+                        let generation = self.drains.start(&id);
+                        (id, generation)
+                    })
+                    .collect();
+                let timeout = Duration::from_millis(drain.timeout_ms);
+                self.drainer().spawn(pipelines, timeout, false);
             }
         }
     }
+
+    fn drainer(&self) -> Drainer {
+        Drainer {
+            client: self.registration.client(),
+            sidecar_id: self.sidecar_id.clone(),
+            routing_table: self.routing_table.clone(),
+            drains: self.drains.clone(),
+        }
+    }
 }
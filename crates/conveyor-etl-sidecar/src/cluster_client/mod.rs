@@ -1,6 +1,12 @@
+mod assignment_watch;
+mod connection;
 mod conversions;
+mod drainer;
 mod heartbeat;
 mod registration;
 
+pub use assignment_watch::AssignmentWatch;
+pub use connection::ClusterConnection;
+pub use conversions::convert_assignment_to_routes;
 pub use heartbeat::HeartbeatLoop;
 pub use registration::ClusterRegistration;
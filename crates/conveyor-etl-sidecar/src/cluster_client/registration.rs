@@ -1,6 +1,7 @@
 use anyhow::{Result, Context};
 use tonic::transport::Channel;
-use tracing::info;
+use tonic::Code;
+use tracing::{info, warn};
 
 use conveyor_etl_proto::sidecar::{
     sidecar_coordinator_client::SidecarCoordinatorClient,
@@ -11,32 +12,39 @@ use conveyor_etl_proto::sidecar::{
 use crate::config::SidecarConfig;
 use crate::discovery::{LocalServiceRegistry, ServiceType};
 use crate::routing::PipelineRoutes;
+use crate::transforms::TransformOptions;
+use super::connection::ClusterConnection;
 use super::conversions::convert_assignment_to_routes;
 
+/// Attempts at registering, failing over between routers in between.
+const REGISTER_ATTEMPTS: usize = 3;
+
+#[derive(Clone)]
 pub struct ClusterRegistration {
-    client: SidecarCoordinatorClient<Channel>,
+    connection: ClusterConnection,
     config: SidecarConfig,
+    resume_token: String,
 }
 
 impl ClusterRegistration {
     pub async fn connect(config: SidecarConfig) -> Result<Self> {
-        let endpoint = format!("http://{}", config.cluster_endpoint);
-        info!("Connecting to cluster at {}", endpoint);
+        info!("Connecting to cluster at {}", config.cluster_endpoints.join(", "));
 
-        let channel = Channel::from_shared(endpoint)
-            .context("Invalid cluster endpoint")?
-            .connect()
+        let connection = ClusterConnection::connect(config.cluster_endpoints.clone())
             .await
             .context("Failed to connect to cluster")?;
 
-        let client = SidecarCoordinatorClient::new(channel);
-
-        Ok(Self { client, config })
+        Ok(Self {
+            connection,
+            config,
+            resume_token: String::new(),
+        })
     }
 
     pub async fn register(
         &mut self,
         registry: &LocalServiceRegistry,
+        transforms: &TransformOptions,
     ) -> Result<Vec<PipelineRoutes>> {
         let local_services: Vec<ProtoLocalService> = registry
             .all_services()
@@ -46,9 +54,12 @@ impl ClusterRegistration {
                     ServiceType::Source => ProtoServiceType::Source as i32,
                     ServiceType::Transform => ProtoServiceType::Transform as i32,
                     ServiceType::Sink => ProtoServiceType::Sink as i32,
+                    ServiceType::Lookup => ProtoServiceType::Lookup as i32,
                     ServiceType::Unknown => ProtoServiceType::Unspecified as i32,
                 },
                 local_endpoint: svc.endpoint.clone(),
+                group_id: self.config.service_group.clone().unwrap_or_default(),
+                labels: self.config.service_labels.clone(),
             })
             .collect();
 
@@ -58,18 +69,34 @@ impl ClusterRegistration {
             local_services.len()
         );
 
-        let response = self
-            .client
-            .register_sidecar(RegisterSidecarRequest {
-                sidecar_id: self.config.sidecar_id.clone(),
-                pod_name: self.config.pod_name.clone(),
-                namespace: self.config.namespace.clone(),
-                sidecar_endpoint: self.config.sidecar_endpoint(),
-                local_services,
-            })
-            .await
-            .context("Failed to register with cluster")?
-            .into_inner();
+        let request = RegisterSidecarRequest {
+            sidecar_id: self.config.sidecar_id.clone(),
+            pod_name: self.config.pod_name.clone(),
+            namespace: self.config.namespace.clone(),
+            sidecar_endpoint: self.config.sidecar_endpoint(),
+            local_services,
+            node_name: self.config.node_name.clone().unwrap_or_default(),
+        };
+
+        let mut attempt = 1;
+        let response = loop {
+            let epoch = self.connection.epoch();
+            match self.client().register_sidecar(request.clone()).await {
+                Ok(response) => break response.into_inner(),
+                Err(status) if status.code() == Code::Unavailable && attempt < REGISTER_ATTEMPTS => {
+                    warn!(
+                        router = %self.connection.current(),
+                        error = %status.message(),
+                        "Registration failed, trying another router"
+                    );
+                    self.connection.failover(&status, epoch).await;
+                    attempt += 1;
+                }
+                Err(status) => {
+                    return Err(anyhow::Error::new(status).context("Failed to register with cluster"));
+                }
+            }
+        };
 
         if !response.success {
             return Err(anyhow::anyhow!(
@@ -83,17 +110,32 @@ impl ClusterRegistration {
             response.initial_assignments.len()
         );
 
+        self.resume_token = response.resume_token;
+
         let routes = response
             .initial_assignments
             .into_iter()
-            .map(convert_assignment_to_routes)
+            .map(|assignment| convert_assignment_to_routes(assignment, transforms))
             .collect();
 
         Ok(routes)
     }
 
-    pub fn client(&mut self) -> &mut SidecarCoordinatorClient<Channel> {
-        &mut self.client
+    pub fn client(&self) -> SidecarCoordinatorClient<Channel> {
+        self.connection.client()
+    }
+
+    pub fn connection(&self) -> &ClusterConnection {
+        &self.connection
+    }
+
+    /// Where the assignment watch continues after the initial assignments.
+    pub fn resume_token(&self) -> &str {
+        &self.resume_token
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.connection.channel()
     }
 
     pub fn config(&self) -> &SidecarConfig {
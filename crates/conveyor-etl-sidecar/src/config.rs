@@ -1,16 +1,71 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use anyhow::{Result, Context};
 
+use conveyor_etl_registry::{Locality, ZONE_TOPOLOGY_KEY};
+use conveyor_etl_routing::{AvroCodec, CodecRegistry, ProtobufCodec};
+
+/// What the data plane does with records that fail validation against the
+/// schema registered for their record type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchemaEnforcement {
+    #[default]
+    Off,
+    Reject,
+    DeadLetter,
+}
+
+impl SchemaEnforcement {
+    fn parse(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "off" => Ok(Self::Off),
+            "reject" => Ok(Self::Reject),
+            "dead_letter" | "dlq" => Ok(Self::DeadLetter),
+            other => anyhow::bail!("Invalid CONVEYOR_SCHEMA_ENFORCEMENT: {}", other),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SidecarConfig {
     pub sidecar_id: String,
     pub pod_name: String,
     pub namespace: String,
     pub node_name: Option<String>,
+    pub service_group: Option<String>,
+    pub service_labels: HashMap<String, String>,
+    /// Label zones are read from and advertised under.
+    pub topology_key: String,
+    /// Zone this sidecar runs in; its stages prefer instances in it.
+    pub zone: Option<String>,
     pub local_ports: Vec<u16>,
-    pub cluster_endpoint: String,
+    pub discovery_interval: Duration,
+    pub health_check_interval: Duration,
+    pub cluster_endpoints: Vec<String>,
     pub listen_addr: SocketAddr,
     pub pod_ip: String,
+    pub schema_enforcement: SchemaEnforcement,
+    pub schema_dead_letter_stage: Option<String>,
+    pub schema_cache_ttl: Duration,
+    pub lookup_cache_size: usize,
+    pub lookup_cache_ttl: Duration,
+    pub lookup_negative_ttl: Duration,
+    pub fan_out_delivery_ttl: Duration,
+    pub mask_token_key: Option<String>,
+    /// Avro writer schema file per record type.
+    pub avro_schemas: HashMap<String, PathBuf>,
+    /// `FileDescriptorSet` that `protobuf_messages` are looked up in.
+    pub protobuf_descriptor_set: Option<PathBuf>,
+    /// Fully qualified Protobuf message name per record type.
+    pub protobuf_messages: HashMap<String, String>,
+    pub state_dir: Option<PathBuf>,
+    pub allowed_lateness: Duration,
+    pub emit_interval: Duration,
+    pub idle_source_timeout: Duration,
+    pub checkpoint_interval: Duration,
 }
 
 impl SidecarConfig {
@@ -23,12 +78,42 @@ impl SidecarConfig {
 
         let node_name = std::env::var("CONVEYOR_NODE_NAME").ok();
 
+        let service_group = std::env::var("CONVEYOR_SERVICE_GROUP")
+            .ok()
+            .filter(|g| !g.is_empty());
+        let mut service_labels = parse_labels(
+            &std::env::var("CONVEYOR_SERVICE_LABELS").unwrap_or_default()
+        );
+
+        let topology_key = std::env::var("CONVEYOR_TOPOLOGY_KEY")
+            .ok()
+            .filter(|k| !k.is_empty())
+            .unwrap_or_else(|| ZONE_TOPOLOGY_KEY.to_string());
+        let zone = match std::env::var("CONVEYOR_ZONE").ok().filter(|z| !z.is_empty()) {
+            Some(zone) => {
+                service_labels.insert(topology_key.clone(), zone.clone());
+                Some(zone)
+            }
+            None => service_labels.get(&topology_key).cloned(),
+        };
+
         let local_ports = parse_ports(
             &std::env::var("CONVEYOR_LOCAL_PORTS").unwrap_or_default()
         );
 
-        let cluster_endpoint = std::env::var("CONVEYOR_CLUSTER_ENDPOINT")
-            .context("CONVEYOR_CLUSTER_ENDPOINT must be set")?;
+        let discovery_interval =
+            Duration::from_secs(env_u64("CONVEYOR_DISCOVERY_INTERVAL_SECS", 30).max(1));
+        let health_check_interval =
+            Duration::from_secs(env_u64("CONVEYOR_HEALTH_CHECK_INTERVAL_SECS", 5).max(1));
+
+        let cluster_endpoints = parse_endpoints(
+            &std::env::var("CONVEYOR_CLUSTER_ENDPOINTS")
+                .or_else(|_| std::env::var("CONVEYOR_CLUSTER_ENDPOINT"))
+                .context("CONVEYOR_CLUSTER_ENDPOINTS must be set")?
+        );
+        if cluster_endpoints.is_empty() {
+            anyhow::bail!("CONVEYOR_CLUSTER_ENDPOINTS must list at least one router");
+        }
 
         let listen_addr: SocketAddr = std::env::var("CONVEYOR_SIDECAR_LISTEN")
             .unwrap_or_else(|_| "0.0.0.0:50053".to_string())
@@ -39,6 +124,61 @@ impl SidecarConfig {
             .or_else(|_| get_pod_ip())
             .unwrap_or_else(|_| "127.0.0.1".to_string());
 
+        let schema_enforcement = SchemaEnforcement::parse(
+            &std::env::var("CONVEYOR_SCHEMA_ENFORCEMENT").unwrap_or_default()
+        )?;
+
+        let schema_dead_letter_stage = std::env::var("CONVEYOR_SCHEMA_DLQ_STAGE").ok();
+        if schema_enforcement == SchemaEnforcement::DeadLetter && schema_dead_letter_stage.is_none() {
+            anyhow::bail!("CONVEYOR_SCHEMA_DLQ_STAGE must be set when dead-lettering invalid records");
+        }
+
+        let schema_cache_ttl = Duration::from_secs(
+            std::env::var("CONVEYOR_SCHEMA_CACHE_TTL_SECS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(60)
+        );
+
+        let lookup_cache_size = env_u64("CONVEYOR_LOOKUP_CACHE_SIZE", 10_000) as usize;
+        let lookup_cache_ttl = Duration::from_secs(env_u64("CONVEYOR_LOOKUP_CACHE_TTL_SECS", 60));
+        let lookup_negative_ttl =
+            Duration::from_secs(env_u64("CONVEYOR_LOOKUP_NEGATIVE_TTL_SECS", 10));
+
+        let fan_out_delivery_ttl =
+            Duration::from_secs(env_u64("CONVEYOR_FAN_OUT_DELIVERY_TTL_SECS", 600));
+
+        let mask_token_key = std::env::var("CONVEYOR_MASK_TOKEN_KEY")
+            .ok()
+            .filter(|k| !k.is_empty());
+
+        let avro_schemas = parse_labels(&std::env::var("CONVEYOR_AVRO_SCHEMAS").unwrap_or_default())
+            .into_iter()
+            .map(|(record_type, path)| (record_type, PathBuf::from(path)))
+            .collect();
+
+        let protobuf_descriptor_set = std::env::var("CONVEYOR_PROTOBUF_DESCRIPTOR_SET")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from);
+        let protobuf_messages =
+            parse_labels(&std::env::var("CONVEYOR_PROTOBUF_MESSAGES").unwrap_or_default());
+        if !protobuf_messages.is_empty() && protobuf_descriptor_set.is_none() {
+            anyhow::bail!("CONVEYOR_PROTOBUF_DESCRIPTOR_SET must be set when CONVEYOR_PROTOBUF_MESSAGES is");
+        }
+
+        let state_dir = std::env::var("CONVEYOR_STATE_DIR")
+            .ok()
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from);
+
+        let allowed_lateness = Duration::from_millis(env_u64("CONVEYOR_ALLOWED_LATENESS_MS", 0));
+        let emit_interval = Duration::from_secs(env_u64("CONVEYOR_EMIT_INTERVAL_SECS", 10));
+        let idle_source_timeout =
+            Duration::from_secs(env_u64("CONVEYOR_IDLE_SOURCE_TIMEOUT_SECS", 60));
+        let checkpoint_interval =
+            Duration::from_secs(env_u64("CONVEYOR_CHECKPOINT_INTERVAL_SECS", 30).max(1));
+
         let sidecar_id = format!("{}/{}", namespace, pod_name);
 
         Ok(Self {
@@ -46,16 +186,82 @@ impl SidecarConfig {
             pod_name,
             namespace,
             node_name,
+            service_group,
+            service_labels,
+            topology_key,
+            zone,
             local_ports,
-            cluster_endpoint,
+            discovery_interval,
+            health_check_interval,
+            cluster_endpoints,
             listen_addr,
             pod_ip,
+            schema_enforcement,
+            schema_dead_letter_stage,
+            schema_cache_ttl,
+            lookup_cache_size,
+            lookup_cache_ttl,
+            lookup_negative_ttl,
+            fan_out_delivery_ttl,
+            mask_token_key,
+            avro_schemas,
+            protobuf_descriptor_set,
+            protobuf_messages,
+            state_dir,
+            allowed_lateness,
+            emit_interval,
+            idle_source_timeout,
+            checkpoint_interval,
         })
     }
 
     pub fn sidecar_endpoint(&self) -> String {
         format!("{}:{}", self.pod_ip, self.listen_addr.port())
     }
+
+    pub fn locality(&self) -> Option<Locality> {
+        self.zone
+            .as_ref()
+            .map(|zone| Locality::new(self.topology_key.clone(), zone.clone()))
+    }
+
+    /// The built-in codecs plus an Avro or Protobuf codec for every record
+    /// type configured with a writer schema or message name.
+    pub fn codec_registry(&self) -> Result<CodecRegistry> {
+        build_codec_registry(
+            &self.avro_schemas,
+            self.protobuf_descriptor_set.as_deref(),
+            &self.protobuf_messages,
+        )
+    }
+}
+
+fn build_codec_registry(
+    avro_schemas: &HashMap<String, PathBuf>,
+    protobuf_descriptor_set: Option<&Path>,
+    protobuf_messages: &HashMap<String, String>,
+) -> Result<CodecRegistry> {
+    let mut codecs = CodecRegistry::new();
+
+    for (record_type, path) in avro_schemas {
+        let schema = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read Avro schema {}", path.display()))?;
+        let codec = AvroCodec::new(&schema)
+            .with_context(|| format!("Invalid Avro schema for record type {}", record_type))?;
+        codecs.register_record_type(record_type, Arc::new(codec));
+    }
+
+    if let Some(path) = protobuf_descriptor_set {
+        let descriptor_set = std::fs::read(path)
+            .with_context(|| format!("Failed to read descriptor set {}", path.display()))?;
+        for (record_type, message) in protobuf_messages {
+            let codec = ProtobufCodec::from_descriptor_set(&descriptor_set, message)
+                .with_context(|| format!("Invalid Protobuf codec for record type {}", record_type))?;
+            codecs.register_record_type(record_type, Arc::new(codec));
+        }
+    }
+
+    Ok(codecs)
 }
 
 fn parse_ports(s: &str) -> Vec<u16> {
@@ -64,6 +270,33 @@ fn parse_ports(s: &str) -> Vec<u16> {
         .collect()
 }
 
+/// Parses a comma-separated list of router addresses, dropping any
+/// `http://` scheme.
+fn parse_endpoints(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|e| e.trim())
+        .map(|e| e.strip_prefix("http://").unwrap_or(e))
+        .filter(|e| !e.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses `key=value` pairs separated by commas.
+fn parse_labels(s: &str) -> HashMap<String, String> {
+    s.split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .filter(|(k, _)| !k.is_empty())
+        .collect()
+}
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
 fn hostname() -> String {
     std::env::var("HOSTNAME")
         .unwrap_or_else(|_| "unknown".to_string())
@@ -89,4 +322,74 @@ mod tests {
         assert_eq!(parse_ports("invalid"), Vec::<u16>::new());
         assert_eq!(parse_ports("8080,invalid,8082"), vec![8080, 8082]);
     }
+
+    #[test]
+    fn test_parse_endpoints() {
+        assert_eq!(
+            parse_endpoints("router-0:50051, http://router-1:50051,,"),
+            vec!["router-0:50051", "router-1:50051"]
+        );
+        assert_eq!(parse_endpoints("conveyor-router:50051"), vec!["conveyor-router:50051"]);
+        assert!(parse_endpoints(" ").is_empty());
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        let labels = parse_labels("tier=gold, region = eu,invalid,=x");
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["tier"], "gold");
+        assert_eq!(labels["region"], "eu");
+        assert!(parse_labels("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_schema_enforcement() {
+        assert_eq!(SchemaEnforcement::parse("").unwrap(), SchemaEnforcement::Off);
+        assert_eq!(SchemaEnforcement::parse("Reject").unwrap(), SchemaEnforcement::Reject);
+        assert_eq!(SchemaEnforcement::parse("dlq").unwrap(), SchemaEnforcement::DeadLetter);
+        assert_eq!(SchemaEnforcement::parse("dead_letter").unwrap(), SchemaEnforcement::DeadLetter);
+        assert!(SchemaEnforcement::parse("drop").is_err());
+    }
+
+    #[test]
+    fn test_codec_registry_registers_protobuf_record_types() {
+        use conveyor_etl_proto::common::Record;
+        use prost::Message;
+        use prost_types::field_descriptor_proto::{Label, Type};
+        use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet};
+
+        let file = FileDescriptorProto {
+            name: Some("shop.proto".to_string()),
+            package: Some("shop".to_string()),
+            message_type: vec![DescriptorProto {
+                name: Some("Order".to_string()),
+                field: vec![FieldDescriptorProto {
+                    name: Some("id".to_string()),
+                    number: Some(1),
+                    label: Some(Label::Optional as i32),
+                    r#type: Some(Type::String as i32),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            syntax: Some("proto3".to_string()),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shop.desc");
+        std::fs::write(&path, FileDescriptorSet { file: vec![file] }.encode_to_vec()).unwrap();
+
+        let messages = parse_labels("orders=shop.Order");
+        let codecs = build_codec_registry(&HashMap::new(), Some(&path), &messages).unwrap();
+
+        let record = Record {
+            record_type: "orders".to_string(),
+            payload: vec![0x0a, 0x02, b'o', b'1'].into(),
+            ..Default::default()
+        };
+        assert_eq!(codecs.decode(&record).unwrap(), serde_json::json!({"id": "o1"}));
+
+        let missing = parse_labels("orders=shop.Missing");
+        assert!(build_codec_registry(&HashMap::new(), Some(&path), &missing).is_err());
+    }
 }
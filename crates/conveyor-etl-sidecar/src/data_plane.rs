@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use futures::future::join_all;
 use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
 use tracing::{info, warn, debug, instrument};
@@ -13,9 +14,25 @@ use conveyor_etl_proto::sidecar::{
     PushAck, PushBackpressure, PushCredits,
     RecordAck, AckStatus,
 };
-use conveyor_etl_proto::common::RecordBatch;
+use conveyor_etl_proto::common::{Record, RecordBatch, RecordId};
+use conveyor_etl_proto::sink::WriteStatus;
+use conveyor_etl_proto::transform::TransformStatus;
+use conveyor_etl_routing::{
+    apply_field_mappings, mapping_failure, timestamp_millis, CodecRegistry, PartitionKey,
+    StageType,
+};
 
-use crate::routing::{SharedRoutingTable, RouteDecision, LocalRouter, RemoteRouter};
+use crate::config::SchemaEnforcement;
+use crate::drain::{InFlight, PipelineDrains};
+use crate::fan::FanStages;
+use crate::lookup::{LookupOutcome, LookupStages};
+use crate::operators::{OperatorCheckpoints, StatefulOperator, STREAM_KEY};
+use crate::routing::{
+    LocalRouter, PipelineRoutes, RemoteRouter, RouteDecision, SharedRoutingTable, StageBalancer,
+    StageGraph, StageInstance,
+};
+use crate::schema_cache::SchemaCache;
+use crate::transforms::TransformOutput;
 
 type PushStream = Pin<Box<dyn Stream<Item = Result<PushRecordsResponse, Status>> + Send>>;
 
@@ -24,6 +41,20 @@ pub struct SidecarDataPlaneImpl {
     local_router: Arc<LocalRouter>,
     remote_router: Arc<RemoteRouter>,
     sidecar_id: String,
+    schema_validation: Option<SchemaValidation>,
+    codecs: Arc<CodecRegistry>,
+    checkpoints: Option<Arc<OperatorCheckpoints>>,
+    drains: Arc<PipelineDrains>,
+    lookups: Arc<LookupStages>,
+    fan: Arc<FanStages>,
+    balancer: Arc<StageBalancer>,
+}
+
+#[derive(Clone)]
+struct SchemaValidation {
+    cache: Arc<SchemaCache>,
+    enforcement: SchemaEnforcement,
+    dead_letter_stage: Option<String>,
 }
 
 impl SidecarDataPlaneImpl {
@@ -38,119 +69,1353 @@ impl SidecarDataPlaneImpl {
             local_router,
             remote_router,
             sidecar_id,
+            schema_validation: None,
+            codecs: Arc::new(CodecRegistry::new()),
+            checkpoints: None,
+            drains: Arc::new(PipelineDrains::new()),
+            lookups: Arc::new(LookupStages::default()),
+            fan: Arc::new(FanStages::default()),
+            balancer: Arc::new(StageBalancer::new()),
+        }
+    }
+
+    /// Tracks batches in flight so pipelines can be drained, refusing new
+    /// batches for draining pipelines.
+    pub fn with_drains(mut self, drains: Arc<PipelineDrains>) -> Self {
+        self.drains = drains;
+        self
+    }
+
+    /// Runs lookup stages through a shared result cache.
+    pub fn with_lookups(mut self, lookups: Arc<LookupStages>) -> Self {
+        self.lookups = lookups;
+        self
+    }
+
+    /// Picks which instance runs the records of stages placed on several
+    /// sidecars.
+    pub fn with_stage_balancer(mut self, balancer: Arc<StageBalancer>) -> Self {
+        self.balancer = balancer;
+        self
+    }
+
+    /// Keeps fan-in watermarks and fan-out deliveries in `fan`.
+    pub fn with_fan_stages(mut self, fan: Arc<FanStages>) -> Self {
+        self.fan = fan;
+        self
+    }
+
+    /// Restores stateful operators from their last checkpoint before they
+    /// process their first batch.
+    pub fn with_operator_checkpoints(mut self, checkpoints: Arc<OperatorCheckpoints>) -> Self {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    /// Decodes payloads for schema validation. Should be the registry the
+    /// stage graphs were compiled with.
+    pub fn with_codecs(mut self, codecs: Arc<CodecRegistry>) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// Validates pushed records against the schema registered for their
+    /// record type before routing them.
+    pub fn with_schema_validation(
+        mut self,
+        cache: Arc<SchemaCache>,
+        enforcement: SchemaEnforcement,
+        dead_letter_stage: Option<String>,
+    ) -> Self {
+        self.schema_validation = (enforcement != SchemaEnforcement::Off).then(|| SchemaValidation {
+            cache,
+            enforcement,
+            dead_letter_stage,
+        });
+        self
+    }
+
+    /// Splits a batch into records that pass schema validation and those
+    /// that do not. Records whose schema cannot be fetched are let through.
+    async fn validate_batch(
+        &self,
+        validation: &SchemaValidation,
+        pipeline_id: &str,
+        mut batch: RecordBatch,
+    ) -> (RecordBatch, Vec<(Record, String)>) {
+        let mut invalid = Vec::new();
+        let mut valid = Vec::with_capacity(batch.records.len());
+
+        for record in std::mem::take(&mut batch.records) {
+            let schema = match validation.cache.schema_for(&record).await {
+                Ok(Some(schema)) => schema,
+                Ok(None) => {
+                    valid.push(record);
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        pipeline = pipeline_id,
+                        record_type = %record.record_type,
+                        error = %e,
+                        "Schema lookup failed, skipping validation"
+                    );
+                    valid.push(record);
+                    continue;
+                }
+            };
+
+            match schema.schema.validate_with(&record, &self.codecs) {
+                Ok(()) => valid.push(record),
+                Err(e) => {
+                    let error = format!(
+                        "record does not match {} schema v{}: {}",
+                        schema.record_type, schema.version, e
+                    );
+                    invalid.push((record, error));
+                }
+            }
         }
+
+        batch.records = valid;
+        (batch, invalid)
+    }
+
+    async fn reject_invalid(
+        &self,
+        validation: &SchemaValidation,
+        pipeline_id: &str,
+        batch_id: &str,
+        invalid: Vec<(Record, String)>,
+    ) -> Vec<RecordAck> {
+        debug!(
+            pipeline = pipeline_id,
+            batch = batch_id,
+            invalid = invalid.len(),
+            "Records failed schema validation"
+        );
+
+        let dead_letter_stage = match (validation.enforcement, &validation.dead_letter_stage) {
+            (SchemaEnforcement::DeadLetter, Some(stage)) => stage,
+            _ => {
+                return invalid
+                    .into_iter()
+                    .map(|(record, error)| RecordAck {
+                        record_id: record.id,
+                        status: AckStatus::Failed as i32,
+                        error,
+                    })
+                    .collect();
+            }
+        };
+
+        let record_ids: Vec<_> = invalid.iter().map(|(r, _)| r.id.clone()).collect();
+        let records = invalid
+            .into_iter()
+            .map(|(record, error)| schema_failure(record, pipeline_id, &error))
+            .collect();
+        let dead_letters = RecordBatch {
+            batch_id: format!("{}-dlq", batch_id),
+            records,
+            watermark: None,
+        };
+
+        let route = {
+            let table = self.routing_table.read().await;
+            table
+                .active_routes(pipeline_id)
+                .and_then(|routes| routes.stage(dead_letter_stage).map(|stage| stage.decision.clone()))
+                .and_then(|decision| match decision {
+                    // Dead letters need no ordering, so any owner takes them
+                    RouteDecision::Partitioned { owners } => RouteDecision::local_owner(&owners)
+                        .or(owners.first())
+                        .cloned(),
+                    RouteDecision::Balanced { instances } => instances
+                        .iter()
+                        .find(|i| i.is_local())
+                        .or(instances.first())
+                        .map(|i| i.target.clone()),
+                    decision => Some(decision),
+                })
+        };
+        let result = match route {
+            Some(RouteDecision::Local { endpoint }) => self
+                .local_router
+                .route_to_sink(&endpoint, dead_letter_stage, dead_letters)
+                .await
+                .map(|response| response.results.iter().all(|r| r.success)),
+            Some(RouteDecision::Remote { endpoint, .. }) => self
+                .remote_router
+                .forward_to_sidecar(&endpoint, pipeline_id, dead_letter_stage, dead_letters)
+                .await
+                .map(|response| response.success),
+            Some(
+                RouteDecision::Builtin { .. }
+                | RouteDecision::Stateful { .. }
+                | RouteDecision::Partitioned { .. }
+                | RouteDecision::Balanced { .. },
+            ) => {
+                Err(anyhow::anyhow!(
+                    "Dead-letter stage {} is a built-in transform",
+                    dead_letter_stage
+                ))
+            }
+            None => Err(anyhow::anyhow!(
+                "Dead-letter stage {} is not routed for pipeline {}",
+                dead_letter_stage,
+                pipeline_id
+            )),
+        };
+
+        let (status, error) = match result {
+            Ok(true) => (AckStatus::Success, String::new()),
+            Ok(false) => (AckStatus::Retry, "dead-letter stage rejected records".to_string()),
+            Err(e) => {
+                warn!(
+                    pipeline = pipeline_id,
+                    stage = %dead_letter_stage,
+                    error = %e,
+                    "Dead-lettering invalid records failed"
+                );
+                (AckStatus::Retry, e.to_string())
+            }
+        };
+
+        record_ids
+            .into_iter()
+            .map(|record_id| RecordAck {
+                record_id,
+                status: status as i32,
+                error: error.clone(),
+            })
+            .collect()
     }
 
+    fn begin(&self, pipeline_id: &str) -> Result<InFlight, Status> {
+        self.drains
+            .begin(pipeline_id)
+            .ok_or_else(|| Status::unavailable(format!("Pipeline {} is draining", pipeline_id)))
+    }
+
+    async fn process_pushed_batch(
+        &self,
+        pipeline_id: &str,
+        source_id: &str,
+        batch: RecordBatch,
+    ) -> Result<Vec<RecordAck>, Status> {
+        let _in_flight = self.begin(pipeline_id)?;
+        let entry = Entry::Source(source_id);
+        let Some(validation) = &self.schema_validation else {
+            return self.process_batch(pipeline_id, entry, batch).await;
+        };
+
+        let batch_id = batch.batch_id.clone();
+        let (valid, invalid) = self.validate_batch(validation, pipeline_id, batch).await;
+
+        let mut acks = if valid.records.is_empty() {
+            Vec::new()
+        } else {
+            self.process_batch(pipeline_id, entry, valid).await?
+        };
+        if !invalid.is_empty() {
+            acks.extend(self.reject_invalid(validation, pipeline_id, &batch_id, invalid).await);
+        }
+        Ok(acks)
+    }
+
+    /// Carries a batch through the pipeline's stage graph, feeding each
+    /// stage's output to the stages its edges lead to, until it reaches
+    /// sinks, stages without edges, or a remote sidecar. Each input record is
+    /// acked with the outcome of everything it turned into.
     async fn process_batch(
         &self,
         pipeline_id: &str,
+        entry: Entry<'_>,
         batch: RecordBatch,
     ) -> Result<Vec<RecordAck>, Status> {
-        let table = self.routing_table.read().await;
+        let routes = {
+            let table = self.routing_table.read().await;
+            table
+                .active_routes(pipeline_id)
+                .ok_or_else(|| Status::not_found(format!("Pipeline {} not found", pipeline_id)))?
+        };
+        let graph = routes.graph.clone().ok_or_else(|| {
+            Status::failed_precondition(format!("Pipeline {} has no stage graph", pipeline_id))
+        })?;
 
-        let routes = table
-            .get_pipeline_routes(pipeline_id)
-            .ok_or_else(|| Status::not_found(format!("Pipeline {} not found", pipeline_id)))?;
+        let mut outcomes = Outcomes::new(&batch.records);
+        let records: Flow = batch
+            .records
+            .into_iter()
+            .enumerate()
+            .map(|(i, record)| (Some(i), record))
+            .collect();
+        let template = RecordBatch {
+            batch_id: batch.batch_id,
+            records: Vec::new(),
+            watermark: batch.watermark,
+        };
 
-        let mut acks = Vec::new();
+        let mut queue = VecDeque::new();
+        match entry {
+            Entry::Source(source_id) => {
+                let stage_id = graph.source_stage(source_id).ok_or_else(|| {
+                    Status::invalid_argument(format!(
+                        "{} is not a source of pipeline {}",
+                        source_id, pipeline_id
+                    ))
+                })?;
+                follow_edges(&graph, stage_id, StageOutput::pass(records), 0, &mut queue);
+            }
+            Entry::Stage(stage_id) => queue.push_back(Hop {
+                stage_id: stage_id.to_string(),
+                upstream: None,
+                records,
+                depth: 0,
+            }),
+        }
 
-        for stage in routes.stages.values() {
-            match &stage.decision {
-                RouteDecision::Local { endpoint } => {
-                    debug!(
-                        pipeline = pipeline_id,
-                        stage = %stage.stage_id,
-                        endpoint = %endpoint,
-                        "Routing batch to local service"
-                    );
+        while let Some(hop) = queue.pop_front() {
+            if hop.depth > graph.stage_count() {
+                outcomes.fail_all(&hop.records, AckStatus::Failed, "stage graph has a cycle");
+                continue;
+            }
 
-                    let result = self
-                        .local_router
-                        .route_to_transform(&endpoint, &stage.stage_id, batch.clone(), HashMap::new())
+            match graph.stage_type(&hop.stage_id) {
+                Some(StageType::FanIn) => {
+                    self.run_fan_in(pipeline_id, &graph, &template, hop, &mut outcomes, &mut queue);
+                    continue;
+                }
+                Some(StageType::FanOut) => {
+                    self.run_fan_out(pipeline_id, &routes, &graph, &template, hop, &mut outcomes, &mut queue)
                         .await;
+                    continue;
+                }
+                _ => {}
+            }
 
-                    match result {
-                        Ok(_output_batches) => {
-                            for record in &batch.records {
-                                acks.push(RecordAck {
-                                    record_id: record.id.clone(),
-                                    status: AckStatus::Success as i32,
-                                    error: String::new(),
-                                });
-                            }
+            let stage_id = hop.stage_id.clone();
+            let depth = hop.depth;
+            let output = self
+                .run_stage(pipeline_id, &routes, &graph, &template, hop, &mut outcomes)
+                .await;
+            if let Some(output) = output {
+                follow_edges(&graph, &stage_id, output, depth, &mut queue);
+            }
+        }
+
+        self.settle_held(pipeline_id, &mut outcomes).await;
+        Ok(outcomes.into_acks(pipeline_id))
+    }
+
+    /// Settles what operators emitted for this batch once it has gone as
+    /// far as it goes. Outputs that were delivered, or failed for good,
+    /// leave the operator's state; those to retry stay held and are emitted
+    /// again with the operator's next batch.
+    async fn settle_held(&self, pipeline_id: &str, outcomes: &mut Outcomes) {
+        let mut settled: Vec<(Arc<StatefulOperator>, Vec<u64>, Vec<u64>)> = Vec::new();
+        for held in outcomes.resolve_held() {
+            let index = match settled.iter().position(|(op, _, _)| Arc::ptr_eq(op, &held.operator)) {
+                Some(index) => index,
+                None => {
+                    settled.push((held.operator.clone(), Vec::new(), Vec::new()));
+                    settled.len() - 1
+                }
+            };
+            match held.status {
+                AckStatus::Retry => settled[index].2.push(held.seq),
+                _ => settled[index].1.push(held.seq),
+            }
+        }
+
+        for (operator, done, retry) in settled {
+            if let Err(e) = operator.settle(done, retry).await {
+                warn!(
+                    pipeline = pipeline_id,
+                    stage = operator.stage_id(),
+                    error = %format!("{:#}", e),
+                    "Failed to settle operator output"
+                );
+            }
+        }
+    }
+
+    /// Merges records arriving through one of a fan-in stage's sources.
+    /// Records behind the stage's watermark go to its late-data stage, or
+    /// are dropped without one. The rest are mapped with the source's field
+    /// mappings and follow the stage's edges.
+    fn run_fan_in(
+        &self,
+        pipeline_id: &str,
+        graph: &StageGraph,
+        template: &RecordBatch,
+        hop: Hop,
+        outcomes: &mut Outcomes,
+        queue: &mut VecDeque<Hop>,
+    ) {
+        let Some(config) = graph.fan_in_config(&hop.stage_id) else {
+            outcomes.fail_all(&hop.records, AckStatus::Failed, "fan-in stage has no fan-in config");
+            return;
+        };
+        let source_stage = hop.upstream.clone().unwrap_or_default();
+        let source = source_stage
+            .strip_prefix(&format!("{}:", hop.stage_id))
+            .and_then(|id| config.sources.iter().find(|s| s.id == id));
+        let Some(source) = source else {
+            let error = format!(
+                "records reached fan-in stage {} from {}, which is not one of its sources",
+                hop.stage_id, source_stage
+            );
+            outcomes.fail_all(&hop.records, AckStatus::Failed, &error);
+            return;
+        };
+
+        let source_watermark = template
+            .watermark
+            .as_ref()
+            .and_then(|w| w.timestamp.as_ref())
+            .and_then(timestamp_millis);
+        let (on_time, late) = self.fan.align(
+            pipeline_id,
+            &hop.stage_id,
+            config,
+            source,
+            graph.codecs(),
+            hop.records,
+            source_watermark,
+        );
+
+        let late_data_stage = config.watermark.as_ref().and_then(|w| w.late_data_stage.clone());
+        if let Some(stage_id) = late_data_stage.filter(|_| !late.is_empty()) {
+            queue.push_back(Hop {
+                stage_id,
+                upstream: Some(hop.stage_id.clone()),
+                records: late,
+                depth: hop.depth + 1,
+            });
+        }
+
+        let mut mapped = Vec::with_capacity(on_time.len());
+        let mut dead_letters = Vec::new();
+        for (origin, record) in on_time {
+            match apply_field_mappings(graph.codecs(), record.clone(), &source.field_mappings) {
+                Ok(record) => mapped.push((origin, record)),
+                Err(e) => {
+                    warn!(pipeline = pipeline_id, stage = %source_stage, error = %e, "Field mapping failed");
+                    match graph.dead_letter_stage() {
+                        Some(_) => dead_letters.push((origin, mapping_failure(record, pipeline_id, &source_stage, &e))),
+                        None => outcomes.set(origin, AckStatus::Failed, &e.to_string()),
+                    }
+                }
+            }
+        }
+        queue_dead_letters(graph, &hop.stage_id, dead_letters, hop.depth, queue);
+        follow_edges(graph, &hop.stage_id, StageOutput::pass(mapped), hop.depth, queue);
+    }
+
+    /// Delivers records to each of a fan-out stage's sinks with the sink's
+    /// field mappings. Sinks are written concurrently, and each input record
+    /// is acked with the worst outcome across them. When a record is to be
+    /// retried, the sinks that took it are remembered and skipped on retry.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_fan_out(
+        &self,
+        pipeline_id: &str,
+        routes: &PipelineRoutes,
+        graph: &StageGraph,
+        template: &RecordBatch,
+        hop: Hop,
+        outcomes: &mut Outcomes,
+        queue: &mut VecDeque<Hop>,
+    ) {
+        let Some(config) = graph.fan_out_config(&hop.stage_id) else {
+            outcomes.fail_all(&hop.records, AckStatus::Failed, "fan-out stage has no fan-out config");
+            return;
+        };
+
+        // Each sink's records carry their index in `records` as origin, so
+        // every sink's outcome can be told apart per record
+        let (origins, records): (Vec<_>, Vec<_>) = hop.records.into_iter().unzip();
+        let mut hops = Vec::with_capacity(config.sinks.len());
+        let mut dead_letters = Vec::new();
+        for sink in &config.sinks {
+            let target = format!("{}:{}", hop.stage_id, sink.id);
+            let mut flow = Vec::new();
+            for (i, record) in records.iter().enumerate() {
+                if self.fan.is_delivered(pipeline_id, &target, record) {
+                    continue;
+                }
+                match apply_field_mappings(graph.codecs(), record.clone(), &sink.field_mappings) {
+                    Ok(mapped) => flow.push((Some(i), mapped)),
+                    Err(e) => {
+                        warn!(pipeline = pipeline_id, stage = %target, error = %e, "Field mapping failed");
+                        match graph.dead_letter_stage() {
+                            Some(_) => dead_letters.push((
+                                origins[i],
+                                mapping_failure(record.clone(), pipeline_id, &target, &e),
+                            )),
+                            None => outcomes.set(origins[i], AckStatus::Failed, &e.to_string()),
                         }
-                        Err(e) => {
-                            warn!(
-                                pipeline = pipeline_id,
-                                stage = %stage.stage_id,
-                                error = %e,
-                                "Local routing failed"
-                            );
-                            for record in &batch.records {
-                                acks.push(RecordAck {
-                                    record_id: record.id.clone(),
-                                    status: AckStatus::Retry as i32,
-                                    error: e.to_string(),
-                                });
+                    }
+                }
+            }
+            hops.push(Hop {
+                stage_id: target,
+                upstream: Some(hop.stage_id.clone()),
+                records: flow,
+                depth: hop.depth + 1,
+            });
+        }
+        queue_dead_letters(graph, &hop.stage_id, dead_letters, hop.depth, queue);
+
+        let targets: Vec<String> = hops.iter().map(|hop| hop.stage_id.clone()).collect();
+        let mut sink_outcomes: Vec<Outcomes> = hops.iter().map(|_| Outcomes::new(&records)).collect();
+        let outputs = join_all(
+            hops.into_iter()
+                .zip(sink_outcomes.iter_mut())
+                .filter(|(hop, _)| !hop.records.is_empty())
+                .map(|(hop, sink_outcomes)| async move {
+                    let stage_id = hop.stage_id.clone();
+                    let output = self
+                        .run_stage(pipeline_id, routes, graph, template, hop, sink_outcomes)
+                        .await;
+                    (stage_id, output)
+                }),
+        )
+        .await;
+
+        for (i, record) in records.iter().enumerate() {
+            let acks: Vec<&RecordAck> = sink_outcomes.iter().map(|o| &o.acks[i]).collect();
+            let delivered_all = acks.iter().all(|a| a.status == AckStatus::Success as i32);
+            for (target, ack) in targets.iter().zip(&acks) {
+                match AckStatus::try_from(ack.status) {
+                    Ok(AckStatus::Success) if delivered_all => {
+                        self.fan.forget_delivered(pipeline_id, target, record)
+                    }
+                    Ok(AckStatus::Success) => self.fan.mark_delivered(pipeline_id, target, record),
+                    Ok(status) => outcomes.set(origins[i], status, &ack.error),
+                    Err(_) => outcomes.set(origins[i], AckStatus::Retry, &ack.error),
+                }
+            }
+        }
+
+        let remap = |flow: Flow| {
+            flow.into_iter()
+                .map(|(i, record)| (i.and_then(|i| origins[i]), record))
+                .collect()
+        };
+        for (stage_id, output) in outputs {
+            if let Some(output) = output {
+                let output = StageOutput {
+                    records: remap(output.records),
+                    routed: output.routed.into_iter().map(|(name, flow)| (name, remap(flow))).collect(),
+                };
+                follow_edges(graph, &stage_id, output, hop.depth + 1, queue);
+            }
+        }
+    }
+
+    /// Runs one stage, returning what it passes on, or `None` when the
+    /// records end here.
+    async fn run_stage(
+        &self,
+        pipeline_id: &str,
+        routes: &PipelineRoutes,
+        graph: &StageGraph,
+        template: &RecordBatch,
+        hop: Hop,
+        outcomes: &mut Outcomes,
+    ) -> Option<StageOutput> {
+        let stage_type = graph.stage_type(&hop.stage_id);
+        if stage_type == Some(StageType::Source) {
+            return Some(StageOutput::pass(hop.records));
+        }
+
+        let Some(stage) = routes.stage(&hop.stage_id) else {
+            warn!(pipeline = pipeline_id, stage = %hop.stage_id, "Stage is not routed");
+            let error = format!("stage {} is not routed", hop.stage_id);
+            outcomes.fail_all(&hop.records, AckStatus::Retry, &error);
+            return None;
+        };
+
+        match &stage.decision {
+            RouteDecision::Partitioned { owners } => {
+                self.run_partitioned(pipeline_id, graph, template, &stage.stage_id, owners, hop, outcomes)
+                    .await
+            }
+            RouteDecision::Balanced { instances } => {
+                self.run_balanced(pipeline_id, graph, template, &stage.stage_id, instances, hop, outcomes)
+                    .await
+            }
+            decision => {
+                self.run_route(pipeline_id, graph, template, &stage.stage_id, decision, hop, outcomes)
+                    .await
+            }
+        }
+    }
+
+    /// Runs a partitioned stage, sending each record to the owner of the
+    /// partition its key falls in. Each owner gets its records in the order
+    /// they arrived, so records with the same key keep their order; owners
+    /// run concurrently. Records another sidecar forwarded here were sent to
+    /// this sidecar's partitions and run locally without being partitioned
+    /// again, so sidecars briefly disagreeing on owners during a rebalance
+    /// cannot bounce them back and forth.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_partitioned(
+        &self,
+        pipeline_id: &str,
+        graph: &StageGraph,
+        template: &RecordBatch,
+        route_id: &str,
+        owners: &[RouteDecision],
+        hop: Hop,
+        outcomes: &mut Outcomes,
+    ) -> Option<StageOutput> {
+        let Hop {
+            stage_id,
+            upstream,
+            records,
+            depth,
+        } = hop;
+
+        let batches = match RouteDecision::local_owner(owners).filter(|_| upstream.is_none()) {
+            Some(local) => vec![(local, records)],
+            None => {
+                let key = graph.partition_key(&stage_id).unwrap_or(&PartitionKey::RecordKey);
+                split_by_owner(key, graph.codecs(), owners, records)
+            }
+        };
+        debug!(
+            pipeline = pipeline_id,
+            stage = %stage_id,
+            owners = batches.len(),
+            "Partitioned batch"
+        );
+
+        let mut owner_outcomes: Vec<Outcomes> = batches.iter().map(|_| outcomes.fork()).collect();
+        let outputs = join_all(batches.into_iter().zip(owner_outcomes.iter_mut()).map(
+            |((owner, records), owner_outcomes)| {
+                let hop = Hop {
+                    stage_id: stage_id.clone(),
+                    upstream: upstream.clone(),
+                    records,
+                    depth,
+                };
+                self.run_route(pipeline_id, graph, template, route_id, owner, hop, owner_outcomes)
+            },
+        ))
+        .await;
+        for owner_outcomes in owner_outcomes {
+            outcomes.join(owner_outcomes);
+        }
+
+        merge_outputs(outputs)
+    }
+
+    /// Runs a stage placed on several sidecars, on the instances the
+    /// stage's load balancing picks. Records another sidecar forwarded here
+    /// run on this sidecar's instance, like those of partitioned stages.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_balanced(
+        &self,
+        pipeline_id: &str,
+        graph: &StageGraph,
+        template: &RecordBatch,
+        route_id: &str,
+        instances: &[StageInstance],
+        hop: Hop,
+        outcomes: &mut Outcomes,
+    ) -> Option<StageOutput> {
+        let Hop {
+            stage_id,
+            upstream,
+            records,
+            depth,
+        } = hop;
+
+        let service_name = graph.service_name(&stage_id).unwrap_or(route_id);
+        let local = instances.iter().find(|i| i.is_local()).filter(|_| upstream.is_none());
+        let batches = match local {
+            Some(local) => vec![(local, records)],
+            None => {
+                self.balancer
+                    .assign(service_name, graph.service_selector(&stage_id), instances, records)
+                    .await
+            }
+        };
+        debug!(
+            pipeline = pipeline_id,
+            stage = %stage_id,
+            instances = batches.len(),
+            "Balanced batch"
+        );
+
+        let mut instance_outcomes: Vec<Outcomes> = batches.iter().map(|_| outcomes.fork()).collect();
+        let outputs = join_all(batches.into_iter().zip(instance_outcomes.iter_mut()).map(
+            |((instance, records), instance_outcomes)| {
+                let hop = Hop {
+                    stage_id: stage_id.clone(),
+                    upstream: upstream.clone(),
+                    records,
+                    depth,
+                };
+                async move {
+                    let started = self.balancer.start(instance);
+                    let output = self
+                        .run_route(pipeline_id, graph, template, route_id, &instance.target, hop, instance_outcomes)
+                        .await;
+                    let success = instance_outcomes.all_succeeded();
+                    self.balancer.finish(service_name, instance, started, success);
+                    output
+                }
+            },
+        ))
+        .await;
+        for instance_outcomes in instance_outcomes {
+            outcomes.join(instance_outcomes);
+        }
+
+        merge_outputs(outputs)
+    }
+
+    /// Runs one stage's records on one of its routes.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_route(
+        &self,
+        pipeline_id: &str,
+        graph: &StageGraph,
+        template: &RecordBatch,
+        route_id: &str,
+        decision: &RouteDecision,
+        hop: Hop,
+        outcomes: &mut Outcomes,
+    ) -> Option<StageOutput> {
+        let stage_type = graph.stage_type(&hop.stage_id);
+        let (origins, records): (Vec<_>, Vec<_>) = hop.records.into_iter().unzip();
+        let ids: Vec<_> = records.iter().map(|r| r.id.clone()).collect();
+        let batch = RecordBatch {
+            records,
+            ..template.clone()
+        };
+
+        match decision {
+            RouteDecision::Local { endpoint } if stage_type == Some(StageType::Sink) => {
+                debug!(
+                    pipeline = pipeline_id,
+                    stage = %hop.stage_id,
+                    endpoint = %endpoint,
+                    "Writing batch to local sink"
+                );
+
+                match self.local_router.route_to_sink(endpoint, route_id, batch).await {
+                    Ok(response) => {
+                        let results = match_results(&ids, &response.results, |r| r.record_id.as_ref());
+                        let rejected = matches!(
+                            WriteStatus::try_from(response.status),
+                            Ok(WriteStatus::Failed | WriteStatus::Partial)
+                        );
+                        for (origin, result) in origins.into_iter().zip(results) {
+                            match result {
+                                Some(r) if r.success => {}
+                                Some(r) if r.retryable => outcomes.set(origin, AckStatus::Retry, &r.error),
+                                Some(r) => outcomes.set(origin, AckStatus::Failed, &r.error),
+                                None if response.results.is_empty() && !rejected => {}
+                                None => outcomes.set(origin, AckStatus::Retry, "sink did not write record"),
                             }
                         }
                     }
+                    Err(e) => {
+                        warn!(
+                            pipeline = pipeline_id,
+                            stage = %hop.stage_id,
+                            error = %e,
+                            "Local sink failed"
+                        );
+                        outcomes.set_all(origins, AckStatus::Retry, &e.to_string());
+                    }
                 }
-                RouteDecision::Remote {
-                    sidecar_id: remote_sidecar_id,
-                    endpoint,
-                } => {
-                    debug!(
-                        pipeline = pipeline_id,
-                        stage = %stage.stage_id,
-                        remote_sidecar = %remote_sidecar_id,
-                        endpoint = %endpoint,
-                        "Forwarding batch to remote sidecar"
-                    );
+                None
+            }
+            RouteDecision::Local { endpoint } if stage_type == Some(StageType::Lookup) => {
+                let Some(config) = graph.lookup_config(&hop.stage_id) else {
+                    outcomes.set_all(origins, AckStatus::Failed, "lookup stage has no lookup config");
+                    return None;
+                };
+                debug!(
+                    pipeline = pipeline_id,
+                    stage = %hop.stage_id,
+                    endpoint = %endpoint,
+                    "Looking up batch in local service"
+                );
 
-                    let result = self
-                        .remote_router
-                        .forward_to_sidecar(&endpoint, pipeline_id, &stage.stage_id, batch.clone())
-                        .await;
+                let lookup_id = graph.service_name(&hop.stage_id).unwrap_or(&hop.stage_id);
+                let results = self
+                    .lookups
+                    .run(&self.local_router, endpoint, lookup_id, config, graph.codecs(), batch.records)
+                    .await;
 
+                let mut output = StageOutput::default();
+                for (origin, result) in origins.into_iter().zip(results) {
                     match result {
-                        Ok(success) => {
-                            for record in &batch.records {
-                                acks.push(RecordAck {
-                                    record_id: record.id.clone(),
-                                    status: if success {
-                                        AckStatus::Success as i32
-                                    } else {
-                                        AckStatus::Failed as i32
-                                    },
-                                    error: String::new(),
-                                });
+                        LookupOutcome::Pass(record) => output.records.push((origin, *record)),
+                        LookupOutcome::Dropped => {}
+                        LookupOutcome::Failed(error) => outcomes.set(origin, AckStatus::Failed, &error),
+                        LookupOutcome::Retry(error) => outcomes.set(origin, AckStatus::Retry, &error),
+                    }
+                }
+                Some(output)
+            }
+            RouteDecision::Local { endpoint } => {
+                debug!(
+                    pipeline = pipeline_id,
+                    stage = %hop.stage_id,
+                    endpoint = %endpoint,
+                    "Routing batch to local service"
+                );
+
+                let results = match self
+                    .local_router
+                    .route_to_transform(endpoint, route_id, batch, HashMap::new())
+                    .await
+                {
+                    Ok(results) => results,
+                    Err(e) => {
+                        warn!(
+                            pipeline = pipeline_id,
+                            stage = %hop.stage_id,
+                            error = %e,
+                            "Local routing failed"
+                        );
+                        outcomes.set_all(origins, AckStatus::Retry, &e.to_string());
+                        return None;
+                    }
+                };
+
+                let mut output = StageOutput::default();
+                let matched = match_results(&ids, &results, |r| r.original_id.as_ref());
+                for (origin, result) in origins.into_iter().zip(matched) {
+                    let Some(result) = result else {
+                        outcomes.set(origin, AckStatus::Retry, "transform returned no result for record");
+                        continue;
+                    };
+                    match TransformStatus::try_from(result.status) {
+                        Ok(TransformStatus::Success | TransformStatus::Split) => output
+                            .records
+                            .extend(result.output_records.iter().map(|r| (origin, r.clone()))),
+                        Ok(TransformStatus::Filtered) => {}
+                        _ => outcomes.set(origin, AckStatus::Failed, &result.error_message),
+                    }
+                }
+                Some(output)
+            }
+            RouteDecision::Remote {
+                sidecar_id: remote_sidecar_id,
+                endpoint,
+            } => {
+                debug!(
+                    pipeline = pipeline_id,
+                    stage = %hop.stage_id,
+                    remote_sidecar = %remote_sidecar_id,
+                    endpoint = %endpoint,
+                    "Forwarding batch to remote sidecar"
+                );
+
+                match self
+                    .remote_router
+                    .forward_to_sidecar(endpoint, pipeline_id, &hop.stage_id, batch)
+                    .await
+                {
+                    Ok(response) => {
+                        let acks = match_results(&ids, &response.record_acks, |a| a.record_id.as_ref());
+                        for (origin, ack) in origins.into_iter().zip(acks) {
+                            match ack.map(|a| (AckStatus::try_from(a.status), a)) {
+                                Some((Ok(AckStatus::Success), _)) => {}
+                                Some((Ok(AckStatus::Failed), a)) => {
+                                    outcomes.set(origin, AckStatus::Failed, &a.error)
+                                }
+                                Some((_, a)) => outcomes.set(origin, AckStatus::Retry, &a.error),
+                                None if response.success => {}
+                                None => outcomes.set(origin, AckStatus::Retry, &response.error),
                             }
                         }
-                        Err(e) => {
-                            warn!(
-                                pipeline = pipeline_id,
-                                stage = %stage.stage_id,
-                                error = %e,
-                                "Remote routing failed"
-                            );
-                            for record in &batch.records {
-                                acks.push(RecordAck {
-                                    record_id: record.id.clone(),
-                                    status: AckStatus::Retry as i32,
-                                    error: e.to_string(),
-                                });
+                    }
+                    Err(e) => {
+                        warn!(
+                            pipeline = pipeline_id,
+                            stage = %hop.stage_id,
+                            error = %e,
+                            "Remote routing failed"
+                        );
+                        outcomes.set_all(origins, AckStatus::Retry, &e.to_string());
+                    }
+                }
+                None
+            }
+            RouteDecision::Builtin { transform } => {
+                // Applied per input record so outputs keep their lineage
+                let mut groups: BTreeMap<Option<usize>, Vec<Record>> = BTreeMap::new();
+                for (origin, record) in origins.into_iter().zip(batch.records) {
+                    groups.entry(origin).or_default().push(record);
+                }
+
+                let mut output = StageOutput::default();
+                let (mut emitted, mut failed) = (0, 0);
+                for (origin, records) in groups {
+                    let applied = transform.apply(records).await;
+                    emitted += applied.records.len();
+                    failed += applied.failed.len();
+                    output.extend(origin, applied, outcomes);
+                }
+
+                debug!(
+                    pipeline = pipeline_id,
+                    stage = %hop.stage_id,
+                    transform = transform.name(),
+                    emitted,
+                    failed,
+                    "Applied built-in transform"
+                );
+                Some(output)
+            }
+            RouteDecision::Partitioned { .. } => {
+                outcomes.set_all(origins, AckStatus::Failed, "partition owner is itself partitioned");
+                None
+            }
+            RouteDecision::Balanced { .. } => {
+                outcomes.set_all(origins, AckStatus::Failed, "stage instance is itself balanced");
+                None
+            }
+            RouteDecision::Stateful { operator } => {
+                let restored = match &self.checkpoints {
+                    Some(checkpoints) => checkpoints.restore_once(operator).await,
+                    None => Ok(()),
+                };
+
+                let mut batch = batch;
+                if let Some(upstream) = &hop.upstream {
+                    for record in &mut batch.records {
+                        record
+                            .metadata
+                            .entry(STREAM_KEY.to_string())
+                            .or_insert_with(|| upstream.clone());
+                    }
+                }
+
+                let applied = match restored {
+                    Ok(()) => operator.apply(batch).await,
+                    Err(e) => Err(e),
+                };
+                match applied {
+                    Ok(applied) => {
+                        debug!(
+                            pipeline = pipeline_id,
+                            stage = %hop.stage_id,
+                            operator = operator.name(),
+                            emitted = applied.held.len(),
+                            failed = applied.failed.len(),
+                            "Applied stateful operator"
+                        );
+
+                        let failed = match_results(&ids, &applied.failed, |(r, _)| r.id.as_ref());
+                        for (origin, failure) in origins.iter().zip(failed) {
+                            if let Some((_, error)) = failure {
+                                outcomes.set(*origin, AckStatus::Failed, error);
                             }
                         }
+
+                        // The state is committed with what the operator emitted
+                        // held in it, so the inputs are acked with how those
+                        // outputs fare downstream.
+                        let mut output = StageOutput::default();
+                        for held in applied.held {
+                            let origin = outcomes.hold(operator, held.seq, origins.clone());
+                            let flow = match held.output {
+                                Some(name) => output.routed.entry(name).or_default(),
+                                None => &mut output.records,
+                            };
+                            flow.push((Some(origin), held.record));
+                        }
+                        Some(output)
+                    }
+                    Err(e) => {
+                        warn!(
+                            pipeline = pipeline_id,
+                            stage = %hop.stage_id,
+                            error = %format!("{:#}", e),
+                            "Stateful operator failed"
+                        );
+                        outcomes.set_all(origins, AckStatus::Retry, &e.to_string());
+                        None
                     }
                 }
             }
         }
+    }
+}
 
-        Ok(acks)
+/// Where a batch enters the pipeline's stage graph.
+#[derive(Debug, Clone, Copy)]
+enum Entry<'a> {
+    /// Pushed by a source, entering at the edges out of its source stage.
+    Source(&'a str),
+    /// Forwarded by another sidecar, entering at this stage.
+    Stage(&'a str),
+}
+
+/// Records moving through the stage graph, each with the index of the input
+/// record it came from. Records emitted by operators are indexed after the
+/// input records, see [`Outcomes::hold`].
+type Flow = Vec<(Option<usize>, Record)>;
+
+struct Hop {
+    stage_id: String,
+    /// Stage the records come from, set as their `_stream` for operators.
+    upstream: Option<String>,
+    records: Flow,
+    depth: usize,
+}
+
+#[derive(Default)]
+struct StageOutput {
+    /// Records sent along the stage's edges.
+    records: Flow,
+    /// Records a transform sent to a named output.
+    routed: BTreeMap<String, Flow>,
+}
+
+impl StageOutput {
+    fn pass(records: Flow) -> Self {
+        Self {
+            records,
+            routed: BTreeMap::new(),
+        }
+    }
+
+    fn extend(&mut self, origin: Option<usize>, output: TransformOutput, outcomes: &mut Outcomes) {
+        self.records
+            .extend(output.records.into_iter().map(|r| (origin, r)));
+        for (name, records) in output.routed {
+            self.routed
+                .entry(name)
+                .or_default()
+                .extend(records.into_iter().map(|r| (origin, r)));
+        }
+        for (_, error) in output.failed {
+            outcomes.set(origin, AckStatus::Failed, &error);
+        }
+    }
+}
+
+/// Queues a stage's output for the stages it leads to. A named output goes
+/// to the downstream stage of the same name, and otherwise follows the
+/// edges like the default output. Records no edge matches are dropped.
+fn follow_edges(
+    graph: &StageGraph,
+    stage_id: &str,
+    output: StageOutput,
+    depth: usize,
+    queue: &mut VecDeque<Hop>,
+) {
+    let mut targets: BTreeMap<String, Flow> = BTreeMap::new();
+    let mut records = output.records;
+    for (name, routed) in output.routed {
+        if graph.has_edge(stage_id, &name) {
+            targets.entry(name).or_default().extend(routed);
+        } else {
+            records.extend(routed);
+        }
+    }
+
+    let mut unmatched = 0;
+    for (origin, record) in records {
+        let routed = graph.route(stage_id, vec![record]);
+        unmatched += routed.unmatched;
+        for decision in routed.decisions {
+            targets
+                .entry(decision.target_stage_id)
+                .or_default()
+                .extend(decision.records.into_iter().map(|r| (origin, r)));
+        }
+    }
+    if unmatched > 0 {
+        debug!(stage = stage_id, unmatched, "Records matched no edge");
+    }
+
+    for (target, records) in targets {
+        queue.push_back(Hop {
+            stage_id: target,
+            upstream: Some(stage_id.to_string()),
+            records,
+            depth: depth + 1,
+        });
     }
 }
 
+/// Queues records whose field mappings failed for the pipeline's
+/// dead-letter stage.
+fn queue_dead_letters(
+    graph: &StageGraph,
+    stage_id: &str,
+    records: Flow,
+    depth: usize,
+    queue: &mut VecDeque<Hop>,
+) {
+    if let Some(dead_letter_stage) = graph.dead_letter_stage().filter(|_| !records.is_empty()) {
+        queue.push_back(Hop {
+            stage_id: dead_letter_stage.to_string(),
+            upstream: Some(stage_id.to_string()),
+            records,
+            depth: depth + 1,
+        });
+    }
+}
+
+/// The ack for each input record. A record that takes several paths gets
+/// the worst of their outcomes: retry, then failed, then success.
+struct Outcomes {
+    acks: Vec<RecordAck>,
+    /// Outcomes of operator outputs, shared with forks so each output keeps
+    /// one index across them.
+    held: Arc<Mutex<Vec<HeldOutcome>>>,
+    detached_failures: usize,
+}
+
+/// How a record an operator emitted fared downstream, and the records of
+/// the batch that emitted it.
+struct HeldOutcome {
+    operator: Arc<StatefulOperator>,
+    seq: u64,
+    inputs: Vec<Option<usize>>,
+    status: AckStatus,
+    error: String,
+}
+
+impl Outcomes {
+    fn new(records: &[Record]) -> Self {
+        Self {
+            acks: records
+                .iter()
+                .map(|record| RecordAck {
+                    record_id: record.id.clone(),
+                    status: AckStatus::Success as i32,
+                    error: String::new(),
+                })
+                .collect(),
+            held: Arc::new(Mutex::new(Vec::new())),
+            detached_failures: 0,
+        }
+    }
+
+    fn set(&mut self, origin: Option<usize>, status: AckStatus, error: &str) {
+        let Some(i) = origin else {
+            self.detached_failures += 1;
+            return;
+        };
+        if let Some(ack) = self.acks.get_mut(i) {
+            let current = AckStatus::try_from(ack.status).unwrap_or(AckStatus::Success);
+            if severity(status) > severity(current) {
+                ack.status = status as i32;
+                ack.error = error.to_string();
+            }
+            return;
+        }
+
+        if status != AckStatus::Success {
+            self.detached_failures += 1;
+        }
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(held) = held.get_mut(i - self.acks.len()) {
+            if severity(status) > severity(held.status) {
+                held.status = status;
+                held.error = error.to_string();
+            }
+        }
+    }
+
+    /// Tracks a record `operator` emitted and holds under `seq`, returning
+    /// the origin it flows on with. `inputs` are the origins of the batch
+    /// that emitted it, which are acked no better than it is.
+    fn hold(&mut self, operator: &Arc<StatefulOperator>, seq: u64, inputs: Vec<Option<usize>>) -> usize {
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        held.push(HeldOutcome {
+            operator: operator.clone(),
+            seq,
+            inputs,
+            status: AckStatus::Success,
+            error: String::new(),
+        });
+        self.acks.len() + held.len() - 1
+    }
+
+    /// Passes each failed operator output's outcome on to the records of
+    /// the batch that emitted it, latest first, so outputs of an operator
+    /// fed by another reach that operator's inputs too.
+    fn resolve_held(&mut self) -> Vec<HeldOutcome> {
+        let mut held = std::mem::take(&mut *self.held.lock().unwrap_or_else(|e| e.into_inner()));
+        let inputs = self.acks.len();
+        for i in (0..held.len()).rev() {
+            let status = held[i].status;
+            if status == AckStatus::Success {
+                continue;
+            }
+            let error = format!("output of {} failed: {}", held[i].operator.stage_id(), held[i].error);
+            for origin in held[i].inputs.clone() {
+                match origin {
+                    Some(j) if j >= inputs => {
+                        let upstream = &mut held[j - inputs];
+                        if severity(status) > severity(upstream.status) {
+                            upstream.status = status;
+                            upstream.error = error.clone();
+                        }
+                    }
+                    origin => self.set(origin, status, &error),
+                }
+            }
+        }
+        held
+    }
+
+    /// Outcomes for the same input records, all successful so far, for a
+    /// share of the work run alongside this one and joined back after.
+    fn fork(&self) -> Self {
+        Self {
+            acks: self
+                .acks
+                .iter()
+                .map(|ack| RecordAck {
+                    record_id: ack.record_id.clone(),
+                    status: AckStatus::Success as i32,
+                    error: String::new(),
+                })
+                .collect(),
+            held: self.held.clone(),
+            detached_failures: 0,
+        }
+    }
+
+    fn all_succeeded(&self) -> bool {
+        self.detached_failures == 0
+            && self.acks.iter().all(|ack| ack.status == AckStatus::Success as i32)
+    }
+
+    /// Takes the worse of each record's outcome here and in `other`.
+    fn join(&mut self, other: Outcomes) {
+        for (i, ack) in other.acks.into_iter().enumerate() {
+            let status = AckStatus::try_from(ack.status).unwrap_or(AckStatus::Retry);
+            self.set(Some(i), status, &ack.error);
+        }
+        self.detached_failures += other.detached_failures;
+    }
+
+    fn set_all(&mut self, origins: Vec<Option<usize>>, status: AckStatus, error: &str) {
+        for origin in origins {
+            self.set(origin, status, error);
+        }
+    }
+
+    fn fail_all(&mut self, records: &Flow, status: AckStatus, error: &str) {
+        for (origin, _) in records {
+            self.set(*origin, status, error);
+        }
+    }
+
+    fn into_acks(self, pipeline_id: &str) -> Vec<RecordAck> {
+        if self.detached_failures > 0 {
+            warn!(
+                pipeline = pipeline_id,
+                records = self.detached_failures,
+                "Operator output failed downstream"
+            );
+        }
+        self.acks
+    }
+}
+
+/// Splits records among the owners of the partitions their keys fall in,
+/// keeping their order within each owner's share.
+/// Merges what the instances of a stage passed on.
+fn merge_outputs(outputs: Vec<Option<StageOutput>>) -> Option<StageOutput> {
+    outputs.into_iter().flatten().reduce(|mut merged, output| {
+        merged.records.extend(output.records);
+        for (name, flow) in output.routed {
+            merged.routed.entry(name).or_default().extend(flow);
+        }
+        merged
+    })
+}
+
+fn split_by_owner<'a>(
+    key: &PartitionKey,
+    codecs: &CodecRegistry,
+    owners: &'a [RouteDecision],
+    records: Flow,
+) -> Vec<(&'a RouteDecision, Flow)> {
+    let mut batches: Vec<(&RouteDecision, Flow)> = Vec::new();
+    for (origin, record) in records {
+        let owner = &owners[key.partition(codecs, &record, owners.len() as u32) as usize];
+        match batches.iter_mut().find(|(o, _)| same_owner(o, owner)) {
+            Some((_, flow)) => flow.push((origin, record)),
+            None => batches.push((owner, vec![(origin, record)])),
+        }
+    }
+    batches
+}
+
+/// Whether two partition owners are the same instance.
+fn same_owner(a: &RouteDecision, b: &RouteDecision) -> bool {
+    match (a, b) {
+        (RouteDecision::Local { endpoint: a }, RouteDecision::Local { endpoint: b }) => a == b,
+        (
+            RouteDecision::Remote { sidecar_id: a, .. },
+            RouteDecision::Remote { sidecar_id: b, .. },
+        ) => a == b,
+        _ => false,
+    }
+}
+
+fn severity(status: AckStatus) -> u8 {
+    match status {
+        AckStatus::Retry => 2,
+        AckStatus::Failed => 1,
+        AckStatus::Success | AckStatus::Unspecified => 0,
+    }
+}
+
+/// Pairs each record id with the result reported for it, expecting results
+/// in input order but not relying on it.
+fn match_results<'a, T>(
+    ids: &[Option<RecordId>],
+    results: &'a [T],
+    id_of: impl Fn(&T) -> Option<&RecordId>,
+) -> Vec<Option<&'a T>> {
+    ids.iter()
+        .enumerate()
+        .map(|(i, id)| {
+            results
+                .get(i)
+                .filter(|r| id_of(r) == id.as_ref())
+                .or_else(|| results.iter().find(|r| id_of(r) == id.as_ref()))
+        })
+        .collect()
+}
+
+fn schema_failure(mut record: Record, pipeline_id: &str, error: &str) -> Record {
+    record
+        .metadata
+        .insert("_dlq_error_code".to_string(), "VALIDATION_FAILED".to_string());
+    record
+        .metadata
+        .insert("_dlq_error_message".to_string(), error.to_string());
+    record
+        .metadata
+        .insert("_dlq_failed_stage".to_string(), "schema-validation".to_string());
+    record
+        .metadata
+        .insert("_dlq_pipeline".to_string(), pipeline_id.to_string());
+    record
+}
+
 #[tonic::async_trait]
 impl SidecarDataPlane for SidecarDataPlaneImpl {
     type PushRecordsStream = PushStream;
@@ -196,12 +1461,26 @@ impl SidecarDataPlane for SidecarDataPlaneImpl {
             .await;
 
         let sidecar_id = self.sidecar_id.clone();
+        let schema_validation = self.schema_validation.clone();
+        let codecs = self.codecs.clone();
+        let checkpoints = self.checkpoints.clone();
+        let drains = self.drains.clone();
+        let lookups = self.lookups.clone();
+        let fan = self.fan.clone();
+        let balancer = self.balancer.clone();
         tokio::spawn(async move {
             let handler = SidecarDataPlaneImpl {
                 routing_table,
                 local_router,
                 remote_router,
                 sidecar_id,
+                schema_validation,
+                codecs,
+                checkpoints,
+                drains,
+                lookups,
+                fan,
+                balancer,
             };
 
             while let Some(result) = stream.next().await {
@@ -210,7 +1489,7 @@ impl SidecarDataPlane for SidecarDataPlaneImpl {
                         Some(push_records_request::Msg::Batch(batch)) => {
                             let batch_id = batch.batch_id.clone();
 
-                            match handler.process_batch(&pipeline_id, batch).await {
+                            match handler.process_pushed_batch(&pipeline_id, &source_id, batch).await {
                                 Ok(record_acks) => {
                                     let ack = PushRecordsResponse {
                                         msg: Some(push_records_response::Msg::Ack(PushAck {
@@ -291,7 +1570,10 @@ impl SidecarDataPlane for SidecarDataPlaneImpl {
             .batch
             .ok_or_else(|| Status::invalid_argument("Missing batch"))?;
 
-        let record_acks = self.process_batch(&req.pipeline_id, batch).await?;
+        let _in_flight = self.begin(&req.pipeline_id)?;
+        let record_acks = self
+            .process_batch(&req.pipeline_id, Entry::Stage(&req.stage_id), batch)
+            .await?;
 
         let success = record_acks
             .iter()
@@ -308,3 +1590,666 @@ impl SidecarDataPlane for SidecarDataPlaneImpl {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conveyor_etl_routing::{
+        CodecRegistry, Condition, FanInConfig, FanInSource, FanInWatermark, FanOutConfig,
+        FanOutSink, FieldMapping, LoadBalanceStrategy, Pipeline, RoutingMode, ServiceSelector,
+        Stage, TrafficSplit, VersionWeight,
+    };
+    use serde_json::{json, Value};
+    use tokio::sync::RwLock;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use conveyor_etl_proto::common::Empty;
+    use conveyor_etl_registry::{Locality, VERSION_LABEL, ZONE_TOPOLOGY_KEY};
+    use conveyor_etl_proto::transform::transform_service_server::{
+        TransformService, TransformServiceServer,
+    };
+    use conveyor_etl_proto::transform::{
+        Capabilities, ProcessBatchRequest, ProcessBatchResponse, ProcessStreamRequest,
+        ProcessStreamResponse, TransformResult,
+    };
+
+    use crate::operators::StateStore;
+    use crate::routing::{RoutingTable, StageRoute};
+    use crate::transforms::{BuiltinTransform, TransformOptions};
+
+    fn stage(id: &str, stage_type: StageType) -> Stage {
+        Stage {
+            id: id.to_string(),
+            name: id.to_string(),
+            stage_type,
+            service_selector: ServiceSelector {
+                service_name: None,
+                group_id: None,
+                labels: HashMap::new(),
+                load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
+            },
+            parallelism: 1,
+            lookup_config: None,
+            fan_in_config: None,
+            fan_out_config: None,
+            routing_mode: RoutingMode::AllMatches,
+            partition_key: None,
+        }
+    }
+
+    fn builtin(id: &str, config: Value) -> (String, StageRoute) {
+        let config = serde_json::from_value(config).unwrap();
+        let transform = BuiltinTransform::compile(&config, &TransformOptions::default()).unwrap();
+        (
+            id.to_string(),
+            StageRoute {
+                stage_id: id.to_string(),
+                decision: RouteDecision::Builtin {
+                    transform: Arc::new(transform),
+                },
+            },
+        )
+    }
+
+    fn record(seq: u64, record_type: &str, payload: Value) -> Record {
+        Record {
+            id: Some(RecordId {
+                source_id: "src".to_string(),
+                sequence_number: seq,
+                ..Default::default()
+            }),
+            record_type: record_type.to_string(),
+            payload: serde_json::to_vec(&payload).unwrap().into(),
+            ..Default::default()
+        }
+    }
+
+    /// src -> filter -> map -> split, where split's `large` output feeds a
+    /// cast and refunds go to `archive`, which has no route.
+    fn data_plane() -> SidecarDataPlaneImpl {
+        let mut pipeline = Pipeline::new("p".to_string(), "p".to_string());
+        pipeline.add_stage(stage("src", StageType::Source));
+        for id in ["filter", "map", "split", "large", "archive"] {
+            pipeline.add_stage(stage(id, StageType::Transform));
+        }
+        pipeline.add_edge("src", "filter", None);
+        pipeline.add_edge("filter", "map", None);
+        pipeline.add_edge("map", "split", None);
+        pipeline.add_edge("split", "large", Some(Condition::MetadataExists("large".to_string())));
+        pipeline.add_edge("split", "archive", Some(Condition::RecordType("refund".to_string())));
+
+        let stages = HashMap::from([
+            builtin(
+                "filter",
+                json!({"transform_type": "filter", "condition": {"expr": "payload.amount > 0"}}),
+            ),
+            builtin(
+                "map",
+                json!({
+                    "transform_type": "map",
+                    "mappings": [{"target": "count", "source": "qty"}]
+                }),
+            ),
+            builtin(
+                "split",
+                json!({
+                    "transform_type": "split",
+                    "routes": [{"condition": {"expr": "payload.amount > 100"}, "output": "large"}]
+                }),
+            ),
+            builtin("large", json!({"transform_type": "cast", "casts": {"count": "int"}})),
+        ]);
+
+        let mut table = RoutingTable::new();
+        table.set_pipeline_routes(PipelineRoutes {
+            pipeline_id: "p".to_string(),
+            is_local_complete: true,
+            stages,
+            graph: Some(Arc::new(StageGraph::new(pipeline, Arc::new(CodecRegistry::new())))),
+        });
+
+        SidecarDataPlaneImpl::new(
+            Arc::new(RwLock::new(table)),
+            Arc::new(LocalRouter::new()),
+            Arc::new(RemoteRouter::new()),
+            "sidecar-1".to_string(),
+        )
+    }
+
+    fn statuses(acks: &[RecordAck]) -> Vec<AckStatus> {
+        acks.iter()
+            .map(|a| AckStatus::try_from(a.status).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_batch_follows_edges() {
+        let plane = data_plane();
+        let batch = RecordBatch {
+            batch_id: "b1".to_string(),
+            records: vec![
+                record(1, "order", json!({"amount": -1})),
+                record(2, "order", json!({"amount": 500, "qty": "many"})),
+                record(3, "order", json!({"amount": 500, "qty": "3"})),
+                record(4, "refund", json!({"amount": 5})),
+                record(5, "order", json!({"amount": 5})),
+            ],
+            watermark: None,
+        };
+
+        let acks = plane
+            .process_batch("p", Entry::Source("src"), batch)
+            .await
+            .unwrap();
+        assert_eq!(
+            statuses(&acks),
+            vec![
+                AckStatus::Success,
+                AckStatus::Failed,
+                AckStatus::Success,
+                AckStatus::Retry,
+                AckStatus::Success,
+            ]
+        );
+        assert!(acks[1].error.contains("count"));
+        assert!(acks[3].error.contains("archive"));
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_batch_starts_at_stage() {
+        let plane = data_plane();
+        let batch = RecordBatch {
+            batch_id: "b1".to_string(),
+            records: vec![record(1, "order", json!({"amount": -1, "count": "x"}))],
+            watermark: None,
+        };
+
+        let acks = plane
+            .process_batch("p", Entry::Stage("large"), batch.clone())
+            .await
+            .unwrap();
+        assert_eq!(statuses(&acks), vec![AckStatus::Failed]);
+
+        let err = plane
+            .process_batch("missing", Entry::Stage("large"), batch)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+    }
+
+    fn selector() -> ServiceSelector {
+        stage("", StageType::Sink).service_selector
+    }
+
+    fn fan_plane(pipeline: Pipeline, stages: HashMap<String, StageRoute>) -> SidecarDataPlaneImpl {
+        let mut table = RoutingTable::new();
+        table.set_pipeline_routes(PipelineRoutes {
+            pipeline_id: "p".to_string(),
+            is_local_complete: true,
+            stages,
+            graph: Some(Arc::new(StageGraph::new(pipeline, Arc::new(CodecRegistry::new())))),
+        });
+        SidecarDataPlaneImpl::new(
+            Arc::new(RwLock::new(table)),
+            Arc::new(LocalRouter::new()),
+            Arc::new(RemoteRouter::new()),
+            "sidecar-1".to_string(),
+        )
+    }
+
+    fn timed(seq: u64, payload: Value, event_time: i64) -> Record {
+        Record {
+            event_time: Some(prost_types::Timestamp {
+                seconds: event_time,
+                nanos: 0,
+            }),
+            ..record(seq, "order", payload)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fan_in_maps_sources_and_diverts_late_records() {
+        let source = |id: &str, mappings: Vec<FieldMapping>| FanInSource {
+            id: id.to_string(),
+            name: id.to_string(),
+            service_selector: selector(),
+            watermark: None,
+            field_mappings: mappings,
+        };
+        let mut pipeline = Pipeline::new("p".to_string(), "p".to_string());
+        let mut merge = stage("merge", StageType::FanIn);
+        merge.fan_in_config = Some(FanInConfig {
+            sources: vec![
+                source(
+                    "web",
+                    vec![FieldMapping {
+                        source_field: Some("amt".to_string()),
+                        target_field: "amount".to_string(),
+                        cast_type: None,
+                        default_value: None,
+                        literal_value: None,
+                        on_error: Default::default(),
+                    }],
+                ),
+                source("mobile", Vec::new()),
+            ],
+            watermark: Some(FanInWatermark {
+                allowed_lateness: None,
+                late_data_stage: Some("late".to_string()),
+            }),
+        });
+        pipeline.add_stage(merge);
+        pipeline.add_stage(stage("merge:web", StageType::Source));
+        pipeline.add_stage(stage("merge:mobile", StageType::Source));
+        pipeline.add_stage(stage("check", StageType::Transform));
+        pipeline.add_stage(stage("late", StageType::Sink));
+        pipeline.add_edge("merge:web", "merge", None);
+        pipeline.add_edge("merge:mobile", "merge", None);
+        pipeline.add_edge("merge", "check", None);
+        let plane = fan_plane(
+            pipeline,
+            HashMap::from([builtin("check", json!({"transform_type": "cast", "casts": {"amount": "int"}}))]),
+        );
+        let push = |source: &'static str, records: Vec<Record>| {
+            let batch = RecordBatch {
+                batch_id: "b".to_string(),
+                records,
+                watermark: None,
+            };
+            let plane = &plane;
+            async move { statuses(&plane.process_batch("p", Entry::Source(source), batch).await.unwrap()) }
+        };
+
+        assert_eq!(
+            push("merge:web", vec![timed(1, json!({"amt": "7"}), 10), timed(2, json!({"amt": "x"}), 10)]).await,
+            vec![AckStatus::Success, AckStatus::Failed],
+            "web records are mapped before the cast"
+        );
+        assert_eq!(push("merge:mobile", vec![timed(3, json!({"amount": 1}), 20)]).await, vec![AckStatus::Success]);
+        assert_eq!(
+            push("merge:mobile", vec![timed(4, json!({"amount": 1}), 5)]).await,
+            vec![AckStatus::Retry],
+            "records behind web's watermark go to the unrouted late stage"
+        );
+    }
+
+    #[test]
+    fn test_split_by_owner_keeps_per_key_order() {
+        let local = |endpoint: &str| RouteDecision::Local {
+            endpoint: endpoint.to_string(),
+        };
+        let remote = |sidecar_id: &str| RouteDecision::Remote {
+            sidecar_id: sidecar_id.to_string(),
+            endpoint: format!("{}:50053", sidecar_id),
+        };
+        // Partitions 1 and 3 are both owned by sidecar-2
+        let owners = vec![
+            local("127.0.0.1:9000"),
+            remote("sidecar-2"),
+            remote("sidecar-3"),
+            remote("sidecar-2"),
+        ];
+        let key = PartitionKey::Payload(conveyor_etl_routing::FieldPath::parse("customer").unwrap());
+        let codecs = CodecRegistry::new();
+
+        let records: Flow = (0..200)
+            .map(|seq| {
+                let customer = format!("c{}", seq % 7);
+                (Some(seq as usize), record(seq, "order", json!({"customer": customer})))
+            })
+            .collect();
+        let batches = split_by_owner(&key, &codecs, &owners, records);
+        assert!(batches.len() <= 3);
+        assert_eq!(batches.iter().map(|(_, flow)| flow.len()).sum::<usize>(), 200);
+
+        // Every customer goes to one owner, in the order it arrived
+        let mut batch_of = HashMap::new();
+        let mut last_seq = HashMap::new();
+        for (i, (_, flow)) in batches.iter().enumerate() {
+            for (origin, record) in flow {
+                let customer = key.extract(&codecs, record).unwrap();
+                assert_eq!(*batch_of.entry(customer.clone()).or_insert(i), i);
+                if let Some(last) = last_seq.insert(customer, origin.unwrap()) {
+                    assert!(last < origin.unwrap());
+                }
+            }
+        }
+        assert_eq!(batch_of.len(), 7);
+
+        // Records without the key all stay on partition 0's owner
+        let keyless = vec![
+            (Some(0), record(0, "order", json!({}))),
+            (Some(1), record(1, "order", json!({}))),
+        ];
+        let batches = split_by_owner(&key, &codecs, &owners, keyless);
+        assert_eq!(batches.len(), 1);
+        assert!(same_owner(batches[0].0, &owners[0]));
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_isolates_failing_sink() {
+        let sink = |id: &str| FanOutSink {
+            id: id.to_string(),
+            name: id.to_string(),
+            service_selector: selector(),
+            field_mappings: Vec::new(),
+        };
+        let mut pipeline = Pipeline::new("p".to_string(), "p".to_string());
+        pipeline.add_stage(stage("src", StageType::Source));
+        let mut fan = stage("fan", StageType::FanOut);
+        fan.fan_out_config = Some(FanOutConfig {
+            sinks: vec![sink("a"), sink("b")],
+        });
+        pipeline.add_stage(fan);
+        pipeline.add_stage(stage("fan:a", StageType::Sink));
+        pipeline.add_stage(stage("fan:b", StageType::Sink));
+        pipeline.add_edge("src", "fan", None);
+        pipeline.add_edge("fan", "fan:a", None);
+        pipeline.add_edge("fan", "fan:b", None);
+        let project = json!({"transform_type": "project", "fields": ["note"]});
+        let batch = RecordBatch {
+            batch_id: "b".to_string(),
+            records: vec![record(1, "order", json!({"note": "x"}))],
+            watermark: None,
+        };
+
+        // b has no route, so the record is retried after reaching a
+        let plane = fan_plane(pipeline.clone(), HashMap::from([builtin("fan:a", project.clone())]));
+        let acks = plane.process_batch("p", Entry::Source("src"), batch.clone()).await.unwrap();
+        assert_eq!(statuses(&acks), vec![AckStatus::Retry]);
+        assert!(acks[0].error.contains("fan:b"));
+        assert!(plane.fan.is_delivered("p", "fan:a", &batch.records[0]));
+        assert!(!plane.fan.is_delivered("p", "fan:b", &batch.records[0]));
+
+        // On retry a is skipped, so its now failing route is not reached
+        let fan = plane.fan.clone();
+        let plane = fan_plane(
+            pipeline,
+            HashMap::from([
+                builtin("fan:a", json!({"transform_type": "cast", "casts": {"note": "int"}})),
+                builtin("fan:b", project),
+            ]),
+        )
+        .with_fan_stages(fan);
+        let acks = plane.process_batch("p", Entry::Source("src"), batch.clone()).await.unwrap();
+        assert_eq!(statuses(&acks), vec![AckStatus::Success]);
+        assert!(!plane.fan.is_delivered("p", "fan:a", &batch.records[0]));
+    }
+
+    #[tokio::test]
+    async fn test_operator_output_failing_downstream_retries_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = TransformOptions {
+            state_store: Some(Arc::new(StateStore::open(dir.path()).unwrap())),
+            ..Default::default()
+        };
+        let config = serde_json::from_value(json!({
+            "transform_type": "dedupe",
+            "key_fields": ["note"],
+            "window": {"tumbling": {"seconds": 10}}
+        }))
+        .unwrap();
+        let operator = Arc::new(StatefulOperator::compile("p", "dedupe", &config, &options).unwrap());
+        let stateful = (
+            "dedupe".to_string(),
+            StageRoute {
+                stage_id: "dedupe".to_string(),
+                decision: RouteDecision::Stateful {
+                    operator: operator.clone(),
+                },
+            },
+        );
+
+        let mut pipeline = Pipeline::new("p".to_string(), "p".to_string());
+        pipeline.add_stage(stage("src", StageType::Source));
+        pipeline.add_stage(stage("dedupe", StageType::Transform));
+        pipeline.add_stage(stage("out", StageType::Transform));
+        pipeline.add_edge("src", "dedupe", None);
+        pipeline.add_edge("dedupe", "out", None);
+        let batch = RecordBatch {
+            batch_id: "b".to_string(),
+            records: vec![record(1, "order", json!({"note": "x"}))],
+            watermark: None,
+        };
+
+        // The state is committed, but out has no route yet
+        let plane = fan_plane(pipeline.clone(), HashMap::from([stateful.clone()]));
+        let acks = plane.process_batch("p", Entry::Source("src"), batch.clone()).await.unwrap();
+        assert_eq!(statuses(&acks), vec![AckStatus::Retry]);
+        assert!(acks[0].error.contains("dedupe"));
+
+        // The redelivered input is a replay, and the held output is emitted again
+        let project = json!({"transform_type": "project", "fields": ["note"]});
+        let plane = fan_plane(pipeline, HashMap::from([stateful, builtin("out", project)]));
+        let acks = plane.process_batch("p", Entry::Source("src"), batch.clone()).await.unwrap();
+        assert_eq!(statuses(&acks), vec![AckStatus::Success]);
+        assert!(operator.apply(RecordBatch::default()).await.unwrap().held.is_empty());
+    }
+
+    /// A transform service passing records through, counting those it saw.
+    struct CountingTransform {
+        seen: Arc<AtomicUsize>,
+    }
+
+    #[tonic::async_trait]
+    impl TransformService for CountingTransform {
+        async fn process_batch(
+            &self,
+            request: Request<ProcessBatchRequest>,
+        ) -> Result<Response<ProcessBatchResponse>, Status> {
+            let batch = request.into_inner().input_batch.unwrap_or_default();
+            self.seen.fetch_add(batch.records.len(), Ordering::SeqCst);
+            let results = batch
+                .records
+                .into_iter()
+                .map(|record| TransformResult {
+                    original_id: record.id.clone(),
+                    status: TransformStatus::Success as i32,
+                    output_records: vec![record],
+                    error_message: String::new(),
+                })
+                .collect();
+            Ok(Response::new(ProcessBatchResponse {
+                batch_id: batch.batch_id,
+                results,
+            }))
+        }
+
+        type ProcessStreamStream =
+            Pin<Box<dyn Stream<Item = Result<ProcessStreamResponse, Status>> + Send>>;
+
+        async fn process_stream(
+            &self,
+            _request: Request<Streaming<ProcessStreamRequest>>,
+        ) -> Result<Response<Self::ProcessStreamStream>, Status> {
+            Err(Status::unimplemented("streaming"))
+        }
+
+        async fn get_capabilities(&self, _request: Request<Empty>) -> Result<Response<Capabilities>, Status> {
+            Ok(Response::new(Capabilities::default()))
+        }
+    }
+
+    /// Serves a counting transform on a free local port, returning its
+    /// endpoint and the count of records it saw.
+    async fn serve_transform() -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let service = TransformServiceServer::new(CountingTransform { seen: seen.clone() });
+        let incoming = futures::stream::unfold(listener, |listener| async {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+        (endpoint, seen)
+    }
+
+    fn instance(endpoint: &str, labels: &[(&str, &str)]) -> StageInstance {
+        StageInstance {
+            target: RouteDecision::Local {
+                endpoint: endpoint.to_string(),
+            },
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    fn balanced(id: &str, instances: Vec<StageInstance>) -> (String, StageRoute) {
+        (
+            id.to_string(),
+            StageRoute {
+                stage_id: id.to_string(),
+                decision: RouteDecision::Balanced { instances },
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_balanced_stage_spreads_batches_over_instances() {
+        let (first, first_seen) = serve_transform().await;
+        let (second, second_seen) = serve_transform().await;
+        let mut pipeline = Pipeline::new("p".to_string(), "p".to_string());
+        pipeline.add_stage(stage("src", StageType::Source));
+        pipeline.add_stage(stage("enrich", StageType::Transform));
+        pipeline.add_edge("src", "enrich", None);
+        let plane = fan_plane(
+            pipeline,
+            HashMap::from([balanced("enrich", vec![instance(&first, &[]), instance(&second, &[])])]),
+        );
+
+        for seq in 0..4 {
+            let batch = RecordBatch {
+                batch_id: format!("b{}", seq),
+                records: vec![record(seq, "order", json!({"amount": 1}))],
+                watermark: None,
+            };
+            let acks = plane.process_batch("p", Entry::Source("src"), batch).await.unwrap();
+            assert_eq!(statuses(&acks), vec![AckStatus::Success]);
+        }
+
+        // Round robin, the stage's strategy, alternates between instances
+        assert_eq!(first_seen.load(Ordering::SeqCst), 2);
+        assert_eq!(second_seen.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_balanced_stage_prefers_same_zone() {
+        let (far, far_seen) = serve_transform().await;
+        let (near, near_seen) = serve_transform().await;
+        let mut pipeline = Pipeline::new("p".to_string(), "p".to_string());
+        pipeline.add_stage(stage("src", StageType::Source));
+        pipeline.add_stage(stage("enrich", StageType::Transform));
+        pipeline.add_edge("src", "enrich", None);
+        let instances = vec![
+            instance(&far, &[(ZONE_TOPOLOGY_KEY, "zone-a")]),
+            instance(&near, &[(ZONE_TOPOLOGY_KEY, "zone-b")]),
+        ];
+        let locality = Locality::new(ZONE_TOPOLOGY_KEY, "zone-b");
+        let plane = fan_plane(pipeline, HashMap::from([balanced("enrich", instances)]))
+            .with_stage_balancer(Arc::new(StageBalancer::new().with_locality(Some(locality))));
+
+        for seq in 0..4 {
+            let batch = RecordBatch {
+                batch_id: format!("b{}", seq),
+                records: vec![record(seq, "order", json!({"amount": 1}))],
+                watermark: None,
+            };
+            let acks = plane.process_batch("p", Entry::Source("src"), batch).await.unwrap();
+            assert_eq!(statuses(&acks), vec![AckStatus::Success]);
+        }
+
+        assert_eq!(near_seen.load(Ordering::SeqCst), 4);
+        assert_eq!(far_seen.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_peak_ewma_weighs_reported_instance_load() {
+        let (busy, busy_seen) = serve_transform().await;
+        let (idle, idle_seen) = serve_transform().await;
+        let mut enrich = stage("enrich", StageType::Transform);
+        enrich.service_selector.load_balance = LoadBalanceStrategy::PeakEwma;
+        let mut pipeline = Pipeline::new("p".to_string(), "p".to_string());
+        pipeline.add_stage(stage("src", StageType::Source));
+        pipeline.add_stage(enrich);
+        pipeline.add_edge("src", "enrich", None);
+        let balancer = Arc::new(StageBalancer::new());
+        balancer.update_load(&busy, 50);
+        let plane = fan_plane(
+            pipeline,
+            HashMap::from([balanced("enrich", vec![instance(&busy, &[]), instance(&idle, &[])])]),
+        )
+        .with_stage_balancer(balancer);
+
+        for seq in 0..4 {
+            let batch = RecordBatch {
+                batch_id: format!("b{}", seq),
+                records: vec![record(seq, "order", json!({"amount": 1}))],
+                watermark: None,
+            };
+            let acks = plane.process_batch("p", Entry::Source("src"), batch).await.unwrap();
+            assert_eq!(statuses(&acks), vec![AckStatus::Success]);
+        }
+
+        assert_eq!(idle_seen.load(Ordering::SeqCst), 4);
+        assert_eq!(busy_seen.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_traffic_split_weights_records_by_version() {
+        let (stable, stable_seen) = serve_transform().await;
+        let (canary, canary_seen) = serve_transform().await;
+        let mut enrich = stage("enrich", StageType::Transform);
+        enrich.service_selector.service_name = Some("enricher".to_string());
+        enrich.service_selector.traffic_split = Some(TrafficSplit::new(
+            vec![
+                VersionWeight {
+                    version: "v1".to_string(),
+                    weight: 80,
+                },
+                VersionWeight {
+                    version: "v2".to_string(),
+                    weight: 20,
+                },
+            ],
+            false,
+        ));
+        let mut pipeline = Pipeline::new("p".to_string(), "p".to_string());
+        pipeline.add_stage(stage("src", StageType::Source));
+        pipeline.add_stage(enrich);
+        pipeline.add_edge("src", "enrich", None);
+        let instances = vec![
+            instance(&stable, &[(VERSION_LABEL, "v1")]),
+            instance(&canary, &[(VERSION_LABEL, "v2")]),
+        ];
+        let plane = fan_plane(pipeline, HashMap::from([balanced("enrich", instances)]));
+
+        let batch = RecordBatch {
+            batch_id: "b".to_string(),
+            records: (0..1000).map(|seq| record(seq, "order", json!({"amount": 1}))).collect(),
+            watermark: None,
+        };
+        let acks = plane.process_batch("p", Entry::Source("src"), batch).await.unwrap();
+        assert!(statuses(&acks).iter().all(|s| *s == AckStatus::Success));
+
+        let stable_seen = stable_seen.load(Ordering::SeqCst);
+        let canary_seen = canary_seen.load(Ordering::SeqCst);
+        assert_eq!(stable_seen + canary_seen, 1000);
+        assert!((700..=900).contains(&stable_seen), "v1 saw {} of 1000", stable_seen);
+
+        // One call to each version, recorded under the stage's service
+        let stats = plane.balancer.version_stats("enricher");
+        let versions: Vec<_> = stats.iter().map(|(v, s)| (v.as_str(), s.requests, s.errors)).collect();
+        assert_eq!(versions, vec![("v1", 1, 0), ("v2", 1, 0)]);
+        assert_eq!(plane.balancer.take_version_stats().len(), 2);
+        assert!(plane.balancer.take_version_stats().is_empty());
+    }
+}
@@ -8,9 +8,18 @@ use tonic_reflection::pb::v1::{
     ServerReflectionRequest,
 };
 use tokio_stream::StreamExt;
+use tonic::Code;
 use tracing::{debug, warn, info};
 
-use super::{LocalService, LocalServiceRegistry, ServiceType};
+use conveyor_etl_proto::health::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
+
+use conveyor_etl_proto::common::Empty;
+use conveyor_etl_proto::lookup::lookup_service_client::LookupServiceClient;
+use conveyor_etl_proto::transform::transform_service_client::TransformServiceClient;
+
+use super::{LocalService, LocalServiceRegistry, ServiceCapabilities, ServiceType};
 
 pub struct GrpcReflectionDiscovery {
     ports: Vec<u16>,
@@ -31,26 +40,71 @@ impl GrpcReflectionDiscovery {
     }
 
     pub async fn discover(&self) -> Result<LocalServiceRegistry> {
+        Ok(self.rediscover(&LocalServiceRegistry::new()).await)
+    }
+
+    /// Scans the ports again. Services on ports that cannot be probed are
+    /// kept from `previous`, so a restarting service is marked unhealthy by
+    /// its health checks rather than dropped.
+    pub async fn rediscover(&self, previous: &LocalServiceRegistry) -> LocalServiceRegistry {
         let mut registry = LocalServiceRegistry::new();
 
         for port in &self.ports {
             match self.probe_port(*port).await {
                 Ok(services) => {
                     for service in services {
-                        info!(
-                            "Discovered local service: {} ({:?}) at {}",
-                            service.name, service.service_type, service.endpoint
-                        );
+                        if previous.get(&service.name) != Some(&service) {
+                            info!(
+                                "Discovered local service: {} ({:?}) at {}",
+                                service.name, service.service_type, service.endpoint
+                            );
+                        }
                         registry.register(service);
                     }
                 }
                 Err(e) => {
-                    warn!("Failed to probe port {}: {}", port, e);
+                    warn!("Failed to probe port {}: {:#}", port, e);
+                    let endpoint = format!("127.0.0.1:{}", port);
+                    for service in previous.all_services().filter(|s| s.endpoint == endpoint) {
+                        if !registry.has_service(&service.name) {
+                            registry.register(service.clone());
+                        }
+                    }
                 }
             }
         }
 
-        Ok(registry)
+        registry
+    }
+
+    /// Checks a service with the `grpc.health.v1` protocol. Services that do
+    /// not implement it count as healthy while they accept connections.
+    pub async fn check_health(&self, service: &LocalService) -> bool {
+        let result = async {
+            let channel = Channel::from_shared(format!("http://{}", service.endpoint))?
+                .connect_timeout(self.timeout)
+                .timeout(self.timeout)
+                .connect()
+                .await?;
+            let response = HealthClient::new(channel)
+                .check(HealthCheckRequest {
+                    service: service.proto_service.clone(),
+                })
+                .await;
+            Ok::<_, anyhow::Error>(match response {
+                Ok(response) => response.into_inner().status() == ServingStatus::Serving,
+                Err(status) => matches!(status.code(), Code::Unimplemented | Code::NotFound),
+            })
+        }
+        .await;
+
+        match result {
+            Ok(healthy) => healthy,
+            Err(e) => {
+                debug!(service = %service.name, error = %e, "Health check failed");
+                false
+            }
+        }
     }
 
     async fn probe_port(&self, port: u16) -> Result<Vec<LocalService>> {
@@ -65,7 +119,7 @@ impl GrpcReflectionDiscovery {
             .await
             .context("Failed to connect")?;
 
-        let mut client = ServerReflectionClient::new(channel);
+        let mut client = ServerReflectionClient::new(channel.clone());
 
         let request = ServerReflectionRequest {
             host: String::new(),
@@ -99,13 +153,19 @@ impl GrpcReflectionDiscovery {
                         continue;
                     }
 
-                    let local_name = extract_service_name(service_name);
+                    let capabilities = self.capabilities(channel.clone(), service_type).await;
+                    let local_name = capabilities
+                        .as_ref()
+                        .map(|(id, _)| id.clone())
+                        .filter(|id| !id.is_empty())
+                        .unwrap_or_else(|| extract_service_name(service_name));
 
                     services.push(LocalService {
                         name: local_name,
                         service_type,
                         endpoint: format!("127.0.0.1:{}", port),
                         proto_service: service_name.clone(),
+                        capabilities: capabilities.map(|(_, c)| c).unwrap_or_default(),
                     });
                 }
             }
@@ -113,6 +173,53 @@ impl GrpcReflectionDiscovery {
 
         Ok(services)
     }
+
+    /// Asks a transform or lookup service for its id and capabilities.
+    async fn capabilities(
+        &self,
+        channel: Channel,
+        service_type: ServiceType,
+    ) -> Option<(String, ServiceCapabilities)> {
+        let result = match service_type {
+            ServiceType::Transform => TransformServiceClient::new(channel)
+                .get_capabilities(Empty {})
+                .await
+                .map(|response| {
+                    let caps = response.into_inner();
+                    let capabilities = ServiceCapabilities {
+                        record_types: caps.supported_record_types,
+                        max_batch_size: Some(caps.max_batch_size).filter(|n| *n > 0),
+                        max_concurrent_batches: Some(caps.max_concurrent_batches).filter(|n| *n > 0),
+                        supports_streaming: caps.supports_streaming,
+                        supports_batch: true,
+                        ..Default::default()
+                    };
+                    (caps.transform_id, capabilities)
+                }),
+            ServiceType::Lookup => LookupServiceClient::new(channel)
+                .get_capabilities(Empty {})
+                .await
+                .map(|response| {
+                    let caps = response.into_inner();
+                    let capabilities = ServiceCapabilities {
+                        key_types: caps.supported_key_types,
+                        max_batch_size: Some(caps.max_batch_size).filter(|n| *n > 0),
+                        supports_batch: caps.supports_batch,
+                        ..Default::default()
+                    };
+                    (caps.lookup_id, capabilities)
+                }),
+            _ => return None,
+        };
+
+        match result {
+            Ok(capabilities) => Some(capabilities),
+            Err(status) => {
+                debug!(?service_type, error = %status, "GetCapabilities failed");
+                None
+            }
+        }
+    }
 }
 
 fn extract_service_name(proto_service: &str) -> String {
@@ -130,26 +237,31 @@ mod tests {
 
     #[test]
     fn test_extract_service_name() {
-        assert_eq!(extract_service_name("etl.source.SourceService"), "source");
-        assert_eq!(extract_service_name("etl.transform.TransformService"), "transform");
-        assert_eq!(extract_service_name("etl.sink.SinkService"), "sink");
+        assert_eq!(extract_service_name("conveyor_etl.transform.TransformService"), "transform");
+        assert_eq!(extract_service_name("conveyor_etl.sink.SinkService"), "sink");
+        assert_eq!(extract_service_name("conveyor_etl.lookup.LookupService"), "lookup");
         assert_eq!(extract_service_name("MyService"), "my");
     }
 
     #[test]
     fn test_service_type_from_proto() {
         assert_eq!(
-            ServiceType::from_proto_service("etl.source.SourceService"),
-            ServiceType::Source
-        );
-        assert_eq!(
-            ServiceType::from_proto_service("etl.transform.TransformService"),
+            ServiceType::from_proto_service("conveyor_etl.transform.TransformService"),
             ServiceType::Transform
         );
         assert_eq!(
-            ServiceType::from_proto_service("etl.sink.SinkService"),
+            ServiceType::from_proto_service("conveyor_etl.sink.SinkService"),
             ServiceType::Sink
         );
+        assert_eq!(
+            ServiceType::from_proto_service("conveyor_etl.lookup.LookupService"),
+            ServiceType::Lookup
+        );
+        assert_eq!(
+            ServiceType::from_proto_service("acme.ResourceService"),
+            ServiceType::Unknown,
+            "names are not matched by substring"
+        );
         assert_eq!(
             ServiceType::from_proto_service("grpc.health.v1.Health"),
             ServiceType::Unknown
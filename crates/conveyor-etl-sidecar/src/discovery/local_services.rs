@@ -1,39 +1,62 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+pub const TRANSFORM_SERVICE: &str = "conveyor_etl.transform.TransformService";
+pub const SINK_SERVICE: &str = "conveyor_etl.sink.SinkService";
+pub const LOOKUP_SERVICE: &str = "conveyor_etl.lookup.LookupService";
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ServiceType {
     Source,
     Transform,
     Sink,
+    Lookup,
     Unknown,
 }
 
 impl ServiceType {
+    /// Type of a service from its fully qualified gRPC name. Sources push
+    /// to the sidecar rather than serve an API, so none is discovered.
     pub fn from_proto_service(service_name: &str) -> Self {
-        if service_name.contains("Source") || service_name.contains("source") {
-            ServiceType::Source
-        } else if service_name.contains("Transform") || service_name.contains("transform") {
-            ServiceType::Transform
-        } else if service_name.contains("Sink") || service_name.contains("sink") {
-            ServiceType::Sink
-        } else {
-            ServiceType::Unknown
+        match service_name {
+            TRANSFORM_SERVICE => ServiceType::Transform,
+            SINK_SERVICE => ServiceType::Sink,
+            LOOKUP_SERVICE => ServiceType::Lookup,
+            _ => ServiceType::Unknown,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// What a service reported from `GetCapabilities`. Services without the
+/// call, and sinks, which have none, keep the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceCapabilities {
+    /// Record types a transform accepts; empty accepts any.
+    pub record_types: Vec<String>,
+    /// Key types a lookup supports.
+    pub key_types: Vec<String>,
+    pub max_batch_size: Option<u32>,
+    pub max_concurrent_batches: Option<u32>,
+    pub supports_streaming: bool,
+    pub supports_batch: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LocalService {
     pub name: String,
     pub service_type: ServiceType,
     pub endpoint: String,
     pub proto_service: String,
+    pub capabilities: ServiceCapabilities,
 }
 
 #[derive(Debug, Default)]
 pub struct LocalServiceRegistry {
     services: HashMap<String, LocalService>,
     by_type: HashMap<ServiceType, Vec<String>>,
+    unhealthy: HashSet<String>,
+    /// Bumped whenever the set of services changes, so the cluster can be
+    /// told about them again.
+    revision: u64,
 }
 
 impl LocalServiceRegistry {
@@ -52,6 +75,48 @@ impl LocalServiceRegistry {
             .push(name);
     }
 
+    /// Replaces the services with newly discovered ones, keeping the health
+    /// of services that are unchanged. Returns whether anything changed.
+    pub fn replace(&mut self, discovered: LocalServiceRegistry) -> bool {
+        if discovered.services == self.services {
+            return false;
+        }
+        let previous = std::mem::replace(self, discovered);
+        self.unhealthy = previous
+            .unhealthy
+            .into_iter()
+            .filter(|name| previous.services.get(name) == self.services.get(name))
+            .collect();
+        self.revision = previous.revision + 1;
+        true
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Records a health check result. Returns whether the health changed.
+    pub fn set_healthy(&mut self, name: &str, healthy: bool) -> bool {
+        if healthy {
+            self.unhealthy.remove(name)
+        } else {
+            self.services.contains_key(name) && self.unhealthy.insert(name.to_string())
+        }
+    }
+
+    pub fn is_healthy(&self, name: &str) -> bool {
+        !self.unhealthy.contains(name)
+    }
+
+    /// Endpoints serving at least one unhealthy service.
+    pub fn unhealthy_endpoints(&self) -> HashSet<String> {
+        self.unhealthy
+            .iter()
+            .filter_map(|name| self.services.get(name))
+            .map(|svc| svc.endpoint.clone())
+            .collect()
+    }
+
     pub fn get(&self, name: &str) -> Option<&LocalService> {
         self.services.get(name)
     }
@@ -87,4 +152,52 @@ impl LocalServiceRegistry {
     pub fn sinks(&self) -> Vec<&LocalService> {
         self.get_by_type(ServiceType::Sink)
     }
+
+    pub fn lookups(&self) -> Vec<&LocalService> {
+        self.get_by_type(ServiceType::Lookup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str, port: u16) -> LocalService {
+        LocalService {
+            name: name.to_string(),
+            service_type: ServiceType::Transform,
+            endpoint: format!("127.0.0.1:{}", port),
+            proto_service: TRANSFORM_SERVICE.to_string(),
+            capabilities: ServiceCapabilities::default(),
+        }
+    }
+
+    fn registry(services: &[LocalService]) -> LocalServiceRegistry {
+        let mut registry = LocalServiceRegistry::new();
+        for svc in services {
+            registry.register(svc.clone());
+        }
+        registry
+    }
+
+    #[test]
+    fn test_replace_tracks_changes_and_health() {
+        let mut current = registry(&[service("enrich", 50051), service("score", 50052)]);
+        assert!(current.set_healthy("enrich", false));
+        assert!(current.set_healthy("score", false));
+        assert!(!current.set_healthy("score", false));
+        assert!(!current.set_healthy("missing", false));
+
+        assert!(!current.replace(registry(&[service("enrich", 50051), service("score", 50052)])));
+        assert_eq!(current.revision(), 0);
+
+        assert!(current.replace(registry(&[service("enrich", 50051), service("score", 50062)])));
+        assert_eq!(current.revision(), 1);
+        assert!(!current.is_healthy("enrich"), "unchanged services keep their health");
+        assert!(current.is_healthy("score"), "moved services start healthy");
+        assert_eq!(
+            current.unhealthy_endpoints(),
+            HashSet::from(["127.0.0.1:50051".to_string()])
+        );
+    }
 }
@@ -1,5 +1,10 @@
 mod grpc_reflection;
 mod local_services;
+mod monitor;
 
 pub use grpc_reflection::GrpcReflectionDiscovery;
-pub use local_services::{LocalServiceRegistry, LocalService, ServiceType};
+pub use local_services::{
+    LocalServiceRegistry, LocalService, ServiceCapabilities, ServiceType, LOOKUP_SERVICE,
+    SINK_SERVICE, TRANSFORM_SERVICE,
+};
+pub use monitor::ServiceMonitor;
@@ -0,0 +1,108 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::join_all;
+use tokio::sync::RwLock;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+use crate::routing::SharedRoutingTable;
+use super::{GrpcReflectionDiscovery, LocalService, LocalServiceRegistry};
+
+/// Keeps the local service registry current. Ports are scanned again every
+/// `discovery_interval` to pick up services that start late or move, and
+/// every service is health checked every `health_interval`. Routes to
+/// endpoints with an unhealthy service are withdrawn until it recovers.
+pub struct ServiceMonitor {
+    discovery: GrpcReflectionDiscovery,
+    registry: Arc<RwLock<LocalServiceRegistry>>,
+    routing_table: SharedRoutingTable,
+    discovery_interval: Duration,
+    health_interval: Duration,
+}
+
+impl ServiceMonitor {
+    pub fn new(
+        discovery: GrpcReflectionDiscovery,
+        registry: Arc<RwLock<LocalServiceRegistry>>,
+        routing_table: SharedRoutingTable,
+    ) -> Self {
+        Self {
+            discovery,
+            registry,
+            routing_table,
+            discovery_interval: Duration::from_secs(30),
+            health_interval: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_intervals(mut self, discovery: Duration, health: Duration) -> Self {
+        self.discovery_interval = discovery;
+        self.health_interval = health;
+        self
+    }
+
+    pub async fn run(self) {
+        info!(
+            discovery_interval = ?self.discovery_interval,
+            health_interval = ?self.health_interval,
+            "Monitoring local services"
+        );
+
+        let mut discover = interval(self.discovery_interval);
+        discover.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut health = interval(self.health_interval);
+        health.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Services were just discovered at startup
+        discover.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = discover.tick() => self.rediscover().await,
+                _ = health.tick() => self.check_health().await,
+            }
+        }
+    }
+
+    async fn rediscover(&self) {
+        let discovered = {
+            let registry = self.registry.read().await;
+            self.discovery.rediscover(&registry).await
+        };
+        let changed = self.registry.write().await.replace(discovered);
+        if changed {
+            info!("Local services changed");
+            self.withdraw_unhealthy().await;
+        }
+    }
+
+    async fn check_health(&self) {
+        let services: Vec<LocalService> =
+            self.registry.read().await.all_services().cloned().collect();
+        let results = join_all(services.iter().map(|svc| self.discovery.check_health(svc))).await;
+
+        let mut changed = false;
+        {
+            let mut registry = self.registry.write().await;
+            for (service, healthy) in services.iter().zip(results) {
+                if !registry.set_healthy(&service.name, healthy) {
+                    continue;
+                }
+                changed = true;
+                if healthy {
+                    info!(service = %service.name, endpoint = %service.endpoint, "Local service recovered");
+                } else {
+                    warn!(service = %service.name, endpoint = %service.endpoint, "Local service unhealthy, withdrawing its routes");
+                }
+            }
+        }
+        if changed {
+            self.withdraw_unhealthy().await;
+        }
+    }
+
+    async fn withdraw_unhealthy(&self) {
+        let endpoints = self.registry.read().await.unhealthy_endpoints();
+        self.routing_table.write().await.set_withdrawn_endpoints(endpoints);
+    }
+}
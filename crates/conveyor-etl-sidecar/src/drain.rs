@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct Activity {
+    in_flight: usize,
+    /// Generation of the drain in progress, if any.
+    draining: Option<u64>,
+}
+
+/// Tracks batches in flight per pipeline and which pipelines are draining.
+/// Draining pipelines refuse new batches so in-flight ones can finish before
+/// their routes are removed.
+#[derive(Debug, Default)]
+pub struct PipelineDrains {
+    pipelines: Mutex<HashMap<String, Activity>>,
+    generation: AtomicU64,
+    idle: Notify,
+}
+
+/// Marks a batch as in flight until dropped.
+pub struct InFlight {
+    drains: Arc<PipelineDrains>,
+    pipeline_id: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut pipelines = self.drains.pipelines.lock().unwrap();
+        if let Some(activity) = pipelines.get_mut(&self.pipeline_id) {
+            activity.in_flight -= 1;
+            if activity.in_flight == 0 {
+                if activity.draining.is_none() {
+                    pipelines.remove(&self.pipeline_id);
+                }
+                self.drains.idle.notify_waiters();
+            }
+        }
+    }
+}
+
+impl PipelineDrains {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a batch, or returns `None` while the pipeline is draining.
+    pub fn begin(self: &Arc<Self>, pipeline_id: &str) -> Option<InFlight> {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        let activity = pipelines.entry(pipeline_id.to_string()).or_default();
+        if activity.draining.is_some() {
+            return None;
+        }
+        activity.in_flight += 1;
+        Some(InFlight {
+            drains: self.clone(),
+            pipeline_id: pipeline_id.to_string(),
+        })
+    }
+
+    /// Stops the pipeline taking new batches. Returns the drain's generation
+    /// for [`finish`](Self::finish).
+    pub fn start(&self, pipeline_id: &str) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        self.pipelines
+            .lock()
+            .unwrap()
+            .entry(pipeline_id.to_string())
+            .or_default()
+            .draining = Some(generation);
+        generation
+    }
+
+    /// Ends a drain so the pipeline takes batches again. Returns false if
+    /// it was cancelled or superseded since `generation` started.
+    pub fn finish(&self, pipeline_id: &str, generation: u64) -> bool {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        let Some(activity) = pipelines.get_mut(pipeline_id) else {
+            return false;
+        };
+        if activity.draining != Some(generation) {
+            return false;
+        }
+        activity.draining = None;
+        if activity.in_flight == 0 {
+            pipelines.remove(pipeline_id);
+        }
+        true
+    }
+
+    /// Ends any drain of the pipeline, as when it is assigned again.
+    pub fn cancel(&self, pipeline_id: &str) {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        if let Some(activity) = pipelines.get_mut(pipeline_id) {
+            activity.draining = None;
+            if activity.in_flight == 0 {
+                pipelines.remove(pipeline_id);
+            }
+        }
+    }
+
+    pub fn is_draining(&self, pipeline_id: &str) -> bool {
+        self.pipelines
+            .lock()
+            .unwrap()
+            .get(pipeline_id)
+            .is_some_and(|a| a.draining.is_some())
+    }
+
+    pub fn in_flight(&self, pipeline_id: &str) -> usize {
+        self.pipelines
+            .lock()
+            .unwrap()
+            .get(pipeline_id)
+            .map_or(0, |a| a.in_flight)
+    }
+
+    /// Batches in flight across every pipeline.
+    pub fn total_in_flight(&self) -> usize {
+        self.pipelines.lock().unwrap().values().map(|a| a.in_flight).sum()
+    }
+
+    /// Waits until none of the pipelines has batches in flight. Returns
+    /// false if some still do after `timeout`.
+    pub async fn wait_idle(&self, pipeline_ids: &[String], timeout: Duration) -> bool {
+        let idle = async {
+            loop {
+                let notified = self.idle.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if pipeline_ids.iter().all(|id| self.in_flight(id) == 0) {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        tokio::time::timeout(timeout, idle).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_in_flight_batches() {
+        let drains = Arc::new(PipelineDrains::new());
+        let batch = drains.begin("p1").unwrap();
+
+        let generation = drains.start("p1");
+        assert!(drains.begin("p1").is_none(), "draining pipelines refuse batches");
+        assert!(drains.begin("p2").is_some());
+
+        let ids = vec!["p1".to_string()];
+        assert!(!drains.wait_idle(&ids, Duration::from_millis(10)).await);
+
+        let waiter = {
+            let drains = drains.clone();
+            let ids = ids.clone();
+            tokio::spawn(async move { drains.wait_idle(&ids, Duration::from_secs(5)).await })
+        };
+        tokio::task::yield_now().await;
+        drop(batch);
+        assert!(waiter.await.unwrap());
+
+        assert!(drains.finish("p1", generation));
+        assert!(drains.begin("p1").is_some());
+    }
+
+    #[test]
+    fn test_cancelled_drain_does_not_finish() {
+        let drains = Arc::new(PipelineDrains::new());
+        let first = drains.start("p1");
+        drains.cancel("p1");
+        assert!(!drains.is_draining("p1"));
+        assert!(!drains.finish("p1", first));
+
+        let _ = drains.start("p1");
+        let second = drains.start("p1");
+        assert!(!drains.finish("p1", first));
+        assert!(drains.finish("p1", second));
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tracing::debug;
+
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_routing::{
+    align_fan_in_record, fan_in_tracker, late_record, CodecRegistry, FanInConfig, FanInSource,
+    LateRecordCounts, WatermarkTracker,
+};
+
+/// The fields of a `RecordId`, which prost does not make hashable.
+type RecordKey = (String, u32, u64, Vec<u8>);
+
+/// Pipeline, fan-out sink stage and record.
+type DeliveryKey = (String, String, RecordKey);
+
+/// Records, each with whatever the caller tracks it by.
+type Tagged<T> = Vec<(T, Record)>;
+
+struct Alignment {
+    sources: Vec<String>,
+    tracker: WatermarkTracker,
+}
+
+#[derive(Default)]
+struct Deliveries {
+    delivered: HashMap<DeliveryKey, Instant>,
+    /// Keys by when they expire, oldest first.
+    expiry: VecDeque<(Instant, DeliveryKey)>,
+}
+
+/// State of the fan-in and fan-out stages the data plane runs.
+///
+/// Fan-in stages keep a watermark over their sources. Fan-out stages
+/// remember which sinks already have a record that is to be retried because
+/// another sink failed, so the retry skips them. Deliveries are remembered
+/// for `delivery_ttl`.
+pub struct FanStages {
+    alignments: DashMap<(String, String), Alignment>,
+    /// Late records by pipeline and fan-in stage since they were last taken.
+    late_records: Mutex<HashMap<(String, String), LateRecordCounts>>,
+    deliveries: Mutex<Deliveries>,
+    delivery_ttl: Duration,
+}
+
+impl FanStages {
+    pub fn new(delivery_ttl: Duration) -> Self {
+        Self {
+            alignments: DashMap::new(),
+            late_records: Mutex::new(HashMap::new()),
+            deliveries: Mutex::new(Deliveries::default()),
+            delivery_ttl,
+        }
+    }
+
+    /// Splits records arriving at a fan-in stage through `source` into those
+    /// on time and those behind the stage's watermark, which are marked as
+    /// late. `source_watermark` is the watermark the source sent with them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn align<T>(
+        &self,
+        pipeline_id: &str,
+        stage_id: &str,
+        config: &FanInConfig,
+        source: &FanInSource,
+        codecs: &CodecRegistry,
+        records: Tagged<T>,
+        source_watermark: Option<i64>,
+    ) -> (Tagged<T>, Tagged<T>) {
+        let sources: Vec<String> = config.sources.iter().map(|s| s.id.clone()).collect();
+        let mut alignment = self
+            .alignments
+            .entry((pipeline_id.to_string(), stage_id.to_string()))
+            .or_insert_with(|| Alignment {
+                sources: sources.clone(),
+                tracker: fan_in_tracker(config),
+            });
+        if alignment.sources != sources {
+            // The stage was reassigned with other sources
+            *alignment = Alignment {
+                sources,
+                tracker: fan_in_tracker(config),
+            };
+        }
+        let tracker = &mut alignment.tracker;
+        tracker.advance_idle_sources(chrono::Utc::now().timestamp_millis());
+
+        let mut on_time = Vec::with_capacity(records.len());
+        let mut late = Vec::new();
+        for (origin, mut record) in records {
+            match align_fan_in_record(codecs, tracker, source, &mut record) {
+                Some(watermark) => late.push((origin, late_record(record, stage_id, watermark))),
+                None => on_time.push((origin, record)),
+            }
+        }
+        if let Some(watermark) = source_watermark {
+            tracker.update(&source.id, watermark);
+        }
+
+        if !late.is_empty() {
+            debug!(
+                pipeline = pipeline_id,
+                stage = stage_id,
+                source = %source.id,
+                late = late.len(),
+                watermark = tracker.combined_watermark(),
+                "Records arrived behind the fan-in watermark"
+            );
+            let mut late_records = self.late_records.lock().unwrap();
+            let counts = late_records
+                .entry((pipeline_id.to_string(), stage_id.to_string()))
+                .or_default();
+            match config.watermark.as_ref().and_then(|w| w.late_data_stage.as_ref()) {
+                Some(_) => counts.side_output += late.len() as u64,
+                None => counts.dropped += late.len() as u64,
+            }
+        }
+        (on_time, late)
+    }
+
+    /// Late records counted since the last call, by pipeline and stage.
+    pub fn take_late_record_counts(&self) -> Vec<(String, String, LateRecordCounts)> {
+        std::mem::take(&mut *self.late_records.lock().unwrap())
+            .into_iter()
+            .map(|((pipeline_id, stage_id), counts)| (pipeline_id, stage_id, counts))
+            .collect()
+    }
+
+    /// Whether `record` already reached the fan-out sink stage `target`.
+    pub fn is_delivered(&self, pipeline_id: &str, target: &str, record: &Record) -> bool {
+        let Some(key) = delivery_key(pipeline_id, target, record) else {
+            return false;
+        };
+        let mut deliveries = self.deliveries.lock().unwrap();
+        deliveries.expire(Instant::now());
+        deliveries.delivered.contains_key(&key)
+    }
+
+    /// Remembers that `record` reached `target` while another sink failed.
+    pub fn mark_delivered(&self, pipeline_id: &str, target: &str, record: &Record) {
+        let Some(key) = delivery_key(pipeline_id, target, record) else {
+            return;
+        };
+        let expires = Instant::now() + self.delivery_ttl;
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if deliveries.delivered.insert(key.clone(), expires).is_none() {
+            deliveries.expiry.push_back((expires, key));
+        }
+    }
+
+    /// Forgets a record once every sink has it.
+    pub fn forget_delivered(&self, pipeline_id: &str, target: &str, record: &Record) {
+        if let Some(key) = delivery_key(pipeline_id, target, record) {
+            self.deliveries.lock().unwrap().delivered.remove(&key);
+        }
+    }
+}
+
+impl Default for FanStages {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(600))
+    }
+}
+
+impl Deliveries {
+    fn expire(&mut self, now: Instant) {
+        while let Some((expires, _)) = self.expiry.front() {
+            if *expires > now {
+                break;
+            }
+            let (expires, key) = self.expiry.pop_front().unwrap();
+            // A key delivered again expires later and is kept
+            if self.delivered.get(&key) == Some(&expires) {
+                self.delivered.remove(&key);
+            }
+        }
+    }
+}
+
+/// `None` when the record's id doesn't tell it apart from other records
+/// of its partition, so it is always delivered rather than maybe skipped.
+fn delivery_key(pipeline_id: &str, target: &str, record: &Record) -> Option<DeliveryKey> {
+    let id = record
+        .id
+        .as_ref()
+        .filter(|id| id.sequence_number != 0 || !id.idempotency_key.is_empty())?;
+    Some((
+        pipeline_id.to_string(),
+        target.to_string(),
+        (
+            id.source_id.clone(),
+            id.partition,
+            id.sequence_number,
+            id.idempotency_key.clone(),
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conveyor_etl_proto::common::RecordId;
+    use conveyor_etl_routing::{
+        FanInWatermark, LoadBalanceStrategy, ServiceSelector, SourceWatermark,
+    };
+
+    fn record(seq: u64, event_time: i64) -> Record {
+        Record {
+            id: Some(RecordId {
+                source_id: "orders".to_string(),
+                sequence_number: seq,
+                ..Default::default()
+            }),
+            event_time: Some(prost_types::Timestamp {
+                seconds: event_time / 1000,
+                nanos: 0,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn source(id: &str) -> FanInSource {
+        FanInSource {
+            id: id.to_string(),
+            name: id.to_string(),
+            service_selector: ServiceSelector {
+                service_name: Some(id.to_string()),
+                group_id: None,
+                labels: Default::default(),
+                load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
+            },
+            watermark: Some(SourceWatermark {
+                event_time_field: String::new(),
+                idle_timeout: None,
+            }),
+            field_mappings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_align_holds_back_to_slowest_source() {
+        let config = FanInConfig {
+            sources: vec![source("web"), source("mobile")],
+            watermark: Some(FanInWatermark {
+                allowed_lateness: None,
+                late_data_stage: None,
+            }),
+        };
+        let fan = FanStages::default();
+        let codecs = CodecRegistry::new();
+        let align = |source: &FanInSource, records: Vec<(usize, Record)>| {
+            fan.align("p", "merge", &config, source, &codecs, records, None)
+        };
+
+        let (on_time, late) = align(&config.sources[0], vec![(0, record(1, 10_000))]);
+        assert_eq!((on_time.len(), late.len()), (1, 0));
+        let (on_time, late) = align(&config.sources[1], vec![(0, record(2, 20_000))]);
+        assert_eq!((on_time.len(), late.len()), (1, 0));
+
+        // The watermark is web's 10s, so mobile's 5s record is late
+        let (on_time, late) = align(&config.sources[1], vec![(0, record(3, 5_000)), (1, record(4, 12_000))]);
+        assert_eq!(on_time.len(), 1);
+        assert_eq!(late[0].0, 0);
+        assert_eq!(late[0].1.metadata["_late_fan_in_stage"], "merge");
+
+        // Without a late-data stage the late record is counted as dropped
+        let counts = fan.take_late_record_counts();
+        assert_eq!(
+            counts,
+            vec![("p".to_string(), "merge".to_string(), LateRecordCounts { side_output: 0, dropped: 1 })]
+        );
+        assert!(fan.take_late_record_counts().is_empty());
+    }
+
+    #[test]
+    fn test_deliveries_expire() {
+        let fan = FanStages::new(Duration::from_millis(20));
+        let record = record(1, 0);
+        assert!(!fan.is_delivered("p", "fan:archive", &record));
+
+        fan.mark_delivered("p", "fan:archive", &record);
+        assert!(fan.is_delivered("p", "fan:archive", &record));
+        assert!(!fan.is_delivered("p", "fan:search", &record));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!fan.is_delivered("p", "fan:archive", &record));
+
+        fan.mark_delivered("p", "fan:archive", &record);
+        fan.forget_delivered("p", "fan:archive", &record);
+        assert!(!fan.is_delivered("p", "fan:archive", &record));
+    }
+
+    #[test]
+    fn test_records_without_unique_id_are_never_skipped() {
+        let fan = FanStages::default();
+        let first = record(0, 0);
+        let second = record(0, 1_000);
+
+        fan.mark_delivered("p", "fan:archive", &first);
+        assert!(!fan.is_delivered("p", "fan:archive", &second));
+
+        let mut keyed = record(0, 0);
+        keyed.id.as_mut().unwrap().idempotency_key = b"order-1".to_vec();
+        fan.mark_delivered("p", "fan:archive", &keyed);
+        assert!(fan.is_delivered("p", "fan:archive", &keyed));
+    }
+}
@@ -3,6 +3,18 @@ pub mod discovery;
 pub mod routing;
 pub mod cluster_client;
 pub mod data_plane;
+pub mod drain;
+pub mod fan;
+pub mod lookup;
+pub mod operators;
+pub mod schema_cache;
+pub mod transforms;
 
-pub use config::SidecarConfig;
+pub use config::{SchemaEnforcement, SidecarConfig};
 pub use data_plane::SidecarDataPlaneImpl;
+pub use drain::PipelineDrains;
+pub use fan::FanStages;
+pub use lookup::{LookupCache, LookupStages};
+pub use operators::{OperatorCheckpoints, OperatorOptions, StateStore, StatefulOperator};
+pub use schema_cache::{SchemaCache, VersionedSchema, SCHEMA_VERSION_KEY};
+pub use transforms::{BuiltinTransform, TransformOptions, TransformOutput};
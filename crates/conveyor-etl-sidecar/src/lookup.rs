@@ -0,0 +1,398 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+
+use conveyor_etl_proto::common::{Record, RecordId};
+use conveyor_etl_proto::lookup::{LookupResponse, LookupStatus};
+use conveyor_etl_routing::{
+    extract_lookup_keys, merge_lookup_result, CodecRegistry, LookupConfig, LookupResult,
+};
+
+use crate::discovery::{LocalServiceRegistry, ServiceType};
+use crate::routing::LocalRouter;
+
+/// Records sent per lookup call when the service does not report a limit.
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// Lookup service and key values, sorted by key.
+type CacheKey = (String, Vec<(String, Vec<u8>)>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Cached {
+    Found(HashMap<String, Vec<u8>>),
+    NotFound,
+}
+
+struct Entry {
+    value: Cached,
+    expires: Instant,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<CacheKey, Entry>,
+    /// Keys by when they were last used, oldest first.
+    recency: BTreeMap<u64, CacheKey>,
+    clock: u64,
+}
+
+/// Lookup results kept for `ttl`, and misses for `negative_ttl`, evicting
+/// the least recently used beyond `capacity` entries.
+pub struct LookupCache {
+    lru: Mutex<Lru>,
+    capacity: usize,
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+impl LookupCache {
+    pub fn new(capacity: usize, ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            lru: Mutex::new(Lru::default()),
+            capacity,
+            ttl,
+            negative_ttl,
+        }
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Cached> {
+        let mut lru = self.lru.lock().unwrap();
+        let lru = &mut *lru;
+        let entry = lru.entries.get_mut(key)?;
+        if entry.expires <= Instant::now() {
+            lru.recency.remove(&entry.used);
+            lru.entries.remove(key);
+            return None;
+        }
+        lru.clock += 1;
+        let key = lru.recency.remove(&entry.used)?;
+        entry.used = lru.clock;
+        lru.recency.insert(lru.clock, key);
+        Some(entry.value.clone())
+    }
+
+    fn insert(&self, key: CacheKey, value: Cached) {
+        let ttl = match value {
+            Cached::Found(_) => self.ttl,
+            Cached::NotFound => self.negative_ttl,
+        };
+        if self.capacity == 0 || ttl.is_zero() {
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.clock += 1;
+        let used = lru.clock;
+        let entry = Entry {
+            value,
+            expires: Instant::now() + ttl,
+            used,
+        };
+        if let Some(previous) = lru.entries.insert(key.clone(), entry) {
+            lru.recency.remove(&previous.used);
+        }
+        lru.recency.insert(used, key);
+
+        while lru.entries.len() > self.capacity {
+            let Some((_, oldest)) = lru.recency.pop_first() else {
+                break;
+            };
+            lru.entries.remove(&oldest);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for LookupCache {
+    fn default() -> Self {
+        Self::new(10_000, Duration::from_secs(60), Duration::from_secs(10))
+    }
+}
+
+/// What a lookup stage does with one record.
+#[derive(Debug)]
+pub enum LookupOutcome {
+    /// Passed on, enriched unless the lookup missed.
+    Pass(Box<Record>),
+    /// Dropped on a miss.
+    Dropped,
+    /// Failed on a miss, or the result could not be merged.
+    Failed(String),
+    /// The lookup failed and may succeed if retried.
+    Retry(String),
+}
+
+/// Runs lookup stages against local lookup services, through a shared
+/// cache. Records with the same keys share one lookup, and lookups are sent
+/// in batches of up to the service's `max_batch_size`.
+#[derive(Default)]
+pub struct LookupStages {
+    cache: LookupCache,
+    services: Option<Arc<RwLock<LocalServiceRegistry>>>,
+}
+
+impl LookupStages {
+    pub fn new(cache: LookupCache) -> Self {
+        Self {
+            cache,
+            services: None,
+        }
+    }
+
+    /// Local services whose capabilities set batch sizes and whether
+    /// lookups are batched.
+    pub fn with_services(mut self, services: Arc<RwLock<LocalServiceRegistry>>) -> Self {
+        self.services = Some(services);
+        self
+    }
+
+    pub fn cache(&self) -> &LookupCache {
+        &self.cache
+    }
+
+    /// Looks up every record and merges the results as `config` says.
+    /// Misses and timeouts follow the config's miss strategy.
+    pub async fn run(
+        &self,
+        router: &LocalRouter,
+        endpoint: &str,
+        lookup_id: &str,
+        config: &LookupConfig,
+        codecs: &CodecRegistry,
+        records: Vec<Record>,
+    ) -> Vec<LookupOutcome> {
+        let mut results: Vec<Option<LookupResult>> = Vec::with_capacity(records.len());
+        let mut pending: Vec<(CacheKey, Vec<usize>)> = Vec::new();
+        let mut pending_index: HashMap<CacheKey, usize> = HashMap::new();
+
+        for (i, record) in records.iter().enumerate() {
+            let keys = match extract_lookup_keys(codecs, record, config) {
+                Ok(keys) => keys,
+                Err(e) => {
+                    debug!(lookup = lookup_id, error = %e, "Treating record without lookup keys as a miss");
+                    results.push(Some(LookupResult::NotFound));
+                    continue;
+                }
+            };
+            let mut keys: Vec<_> = keys.into_iter().collect();
+            keys.sort();
+            let key = (lookup_id.to_string(), keys);
+
+            match self.cache.get(&key) {
+                Some(cached) => results.push(Some(cached.into())),
+                None => {
+                    results.push(None);
+                    match pending_index.get(&key) {
+                        Some(&p) => pending[p].1.push(i),
+                        None => {
+                            pending_index.insert(key.clone(), pending.len());
+                            pending.push((key, vec![i]));
+                        }
+                    }
+                }
+            }
+        }
+
+        if !pending.is_empty() {
+            let (batch, max_batch_size) = self.limits(endpoint).await;
+            let key_fields: Vec<String> =
+                config.key_fields.iter().map(|k| k.lookup_key.clone()).collect();
+            let timeout = Duration::from_millis(config.timeout_ms as u64);
+
+            let chunks = pending.chunks(max_batch_size).map(|chunk| {
+                let requests = chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(n, (key, indices))| request_record(&records[indices[0]], key, n as u64))
+                    .collect();
+                let call = router.route_to_lookup(endpoint, lookup_id, requests, key_fields.clone(), batch);
+                async move {
+                    let outcome = if timeout.is_zero() {
+                        Ok(call.await)
+                    } else {
+                        tokio::time::timeout(timeout, call).await
+                    };
+                    (chunk, outcome)
+                }
+            });
+
+            for (chunk, outcome) in join_all(chunks).await {
+                let mut responses: HashMap<u64, LookupResponse> = match outcome {
+                    Ok(Ok(responses)) => responses
+                        .into_iter()
+                        .filter_map(|r| Some((r.original_id.as_ref()?.sequence_number, r)))
+                        .collect(),
+                    Ok(Err(e)) => {
+                        warn!(lookup = lookup_id, error = %e, "Lookup failed");
+                        let message = format!("{:#}", e);
+                        for (_, indices) in chunk {
+                            for &i in indices {
+                                results[i] = Some(LookupResult::Error { message: message.clone() });
+                            }
+                        }
+                        continue;
+                    }
+                    Err(_) => {
+                        warn!(lookup = lookup_id, timeout_ms = config.timeout_ms, "Lookup timed out");
+                        for (_, indices) in chunk {
+                            for &i in indices {
+                                results[i] = Some(LookupResult::NotFound);
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                for (n, (key, indices)) in chunk.iter().enumerate() {
+                    let result = match responses.remove(&(n as u64)) {
+                        Some(response) => self.settle(key, response),
+                        None => LookupResult::Error {
+                            message: "lookup returned no result for key".to_string(),
+                        },
+                    };
+                    for &i in indices {
+                        results[i] = Some(result.clone());
+                    }
+                }
+            }
+        }
+
+        records
+            .into_iter()
+            .zip(results)
+            .map(|(record, result)| {
+                let result = result.unwrap_or(LookupResult::NotFound);
+                if let LookupResult::Error { message } = result {
+                    return LookupOutcome::Retry(format!("Lookup error: {}", message));
+                }
+                match merge_lookup_result(codecs, record, result, config) {
+                    Ok(Some(record)) => LookupOutcome::Pass(Box::new(record)),
+                    Ok(None) => LookupOutcome::Dropped,
+                    Err(e) => LookupOutcome::Failed(e.to_string()),
+                }
+            })
+            .collect()
+    }
+
+    /// Converts a response, caching found and not-found results.
+    fn settle(&self, key: &CacheKey, response: LookupResponse) -> LookupResult {
+        match response.status() {
+            LookupStatus::Found => {
+                self.cache.insert(key.clone(), Cached::Found(response.lookup_data.clone()));
+                LookupResult::Found {
+                    data: response.lookup_data,
+                }
+            }
+            LookupStatus::NotFound => {
+                self.cache.insert(key.clone(), Cached::NotFound);
+                LookupResult::NotFound
+            }
+            LookupStatus::Timeout => LookupResult::NotFound,
+            LookupStatus::Error | LookupStatus::Unspecified => LookupResult::Error {
+                message: response.error_message,
+            },
+        }
+    }
+
+    /// Whether the service at `endpoint` takes batches, and how large.
+    async fn limits(&self, endpoint: &str) -> (bool, usize) {
+        let Some(services) = &self.services else {
+            return (true, DEFAULT_BATCH_SIZE);
+        };
+        let services = services.read().await;
+        let service = services
+            .all_services()
+            .find(|s| s.service_type == ServiceType::Lookup && s.endpoint == endpoint);
+        match service {
+            Some(service) => (
+                service.capabilities.supports_batch,
+                service
+                    .capabilities
+                    .max_batch_size
+                    .map_or(DEFAULT_BATCH_SIZE, |n| n as usize),
+            ),
+            None => (true, DEFAULT_BATCH_SIZE),
+        }
+    }
+}
+
+impl From<Cached> for LookupResult {
+    fn from(cached: Cached) -> Self {
+        match cached {
+            Cached::Found(data) => LookupResult::Found { data },
+            Cached::NotFound => LookupResult::NotFound,
+        }
+    }
+}
+
+/// The record sent to look up `key`: the first record with it, carrying
+/// the key values in its metadata under their lookup keys and numbered to
+/// match the response.
+fn request_record(record: &Record, key: &CacheKey, n: u64) -> Record {
+    let mut request = record.clone();
+    for (name, value) in &key.1 {
+        request
+            .metadata
+            .insert(name.clone(), String::from_utf8_lossy(value).into_owned());
+    }
+    request.id = Some(RecordId {
+        source_id: key.0.clone(),
+        sequence_number: n,
+        ..Default::default()
+    });
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str) -> CacheKey {
+        ("customers".to_string(), vec![("id".to_string(), id.as_bytes().to_vec())])
+    }
+
+    fn found(name: &str) -> Cached {
+        Cached::Found(HashMap::from([("name".to_string(), name.as_bytes().to_vec())]))
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let cache = LookupCache::new(2, Duration::from_secs(60), Duration::from_secs(60));
+        cache.insert(key("1"), found("ada"));
+        cache.insert(key("2"), Cached::NotFound);
+        assert_eq!(cache.get(&key("1")), Some(found("ada")));
+
+        cache.insert(key("3"), found("grace"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&key("2")), None, "least recently used is evicted");
+        assert_eq!(cache.get(&key("1")), Some(found("ada")));
+        assert_eq!(cache.get(&key("3")), Some(found("grace")));
+    }
+
+    #[test]
+    fn test_cache_expires_entries() {
+        let cache = LookupCache::new(10, Duration::from_secs(60), Duration::ZERO);
+        cache.insert(key("1"), Cached::NotFound);
+        assert!(cache.is_empty(), "misses are not cached without a negative TTL");
+
+        let cache = LookupCache::new(10, Duration::from_millis(1), Duration::from_secs(60));
+        cache.insert(key("1"), found("ada"));
+        cache.insert(key("2"), Cached::NotFound);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.get(&key("1")), None);
+        assert_eq!(cache.get(&key("2")), Some(Cached::NotFound));
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -7,12 +7,16 @@ use tracing::{info, error, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use conveyor_etl_proto::sidecar::sidecar_data_plane_server::SidecarDataPlaneServer;
+use conveyor_etl_registry::OutlierDetector;
 
 use conveyor_etl_sidecar::config::SidecarConfig;
-use conveyor_etl_sidecar::discovery::GrpcReflectionDiscovery;
-use conveyor_etl_sidecar::routing::{RoutingTable, LocalRouter, RemoteRouter};
-use conveyor_etl_sidecar::cluster_client::{ClusterRegistration, HeartbeatLoop};
-use conveyor_etl_sidecar::SidecarDataPlaneImpl;
+use conveyor_etl_sidecar::discovery::{GrpcReflectionDiscovery, ServiceMonitor};
+use conveyor_etl_sidecar::routing::{RoutingTable, LocalRouter, RemoteRouter, StageBalancer};
+use conveyor_etl_sidecar::cluster_client::{AssignmentWatch, ClusterRegistration, HeartbeatLoop};
+use conveyor_etl_sidecar::{
+    FanStages, LookupCache, LookupStages, OperatorCheckpoints, OperatorOptions, PipelineDrains, SchemaCache,
+    SchemaEnforcement, SidecarDataPlaneImpl, StateStore, TransformOptions,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -58,15 +62,43 @@ async fn main() -> Result<()> {
 
     let registry = Arc::new(RwLock::new(registry));
 
-    info!("Connecting to cluster at {}...", config.cluster_endpoint);
+    info!("Connecting to cluster at {}...", config.cluster_endpoints.join(", "));
     let mut cluster_registration = ClusterRegistration::connect(config.clone())
         .await
         .context("Failed to connect to cluster")?;
 
+    let schema_cache = Arc::new(SchemaCache::new(
+        cluster_registration.channel(),
+        config.schema_cache_ttl,
+    ));
+
+    let state_store = match &config.state_dir {
+        Some(dir) => {
+            info!(dir = %dir.display(), "Opening operator state store");
+            Some(Arc::new(StateStore::open(dir)?))
+        }
+        None => None,
+    };
+
+    let codecs = Arc::new(config.codec_registry().context("Failed to load payload codecs")?);
+    info!(codecs = ?codecs, "Payload codecs loaded");
+
+    let transform_options = TransformOptions {
+        codecs: codecs.clone(),
+        token_key: config.mask_token_key.as_deref().map(|k| Arc::from(k.as_bytes())),
+        schema_cache: Some(schema_cache.clone()),
+        state_store: state_store.clone(),
+        operators: OperatorOptions {
+            allowed_lateness: config.allowed_lateness,
+            emit_interval: config.emit_interval,
+            idle_source_timeout: config.idle_source_timeout,
+        },
+    };
+
     let initial_routes = {
         let reg = registry.read().await;
         cluster_registration
-            .register(&reg)
+            .register(&reg, &transform_options)
             .await
             .context("Failed to register with cluster")?
     };
@@ -76,7 +108,10 @@ async fn main() -> Result<()> {
         initial_routes.len()
     );
 
-    let routing_table = Arc::new(RwLock::new(RoutingTable::new()));
+    let outlier_detector = Arc::new(OutlierDetector::default());
+    let routing_table = Arc::new(RwLock::new(
+        RoutingTable::new().with_outlier_detector(outlier_detector.clone()),
+    ));
 
     {
         let mut table = routing_table.write().await;
@@ -91,15 +126,44 @@ async fn main() -> Result<()> {
         }
     }
 
-    let local_router = Arc::new(LocalRouter::new());
-    let remote_router = Arc::new(RemoteRouter::new());
+    let local_router = Arc::new(LocalRouter::with_outlier_detector(outlier_detector.clone()));
+    let remote_router = Arc::new(RemoteRouter::with_outlier_detector(outlier_detector.clone()));
+    let stage_balancer = Arc::new(
+        StageBalancer::with_outlier_detector(outlier_detector).with_locality(config.locality()),
+    );
 
-    let heartbeat_loop = HeartbeatLoop::new(
-        cluster_registration.client().clone(),
+    let service_monitor = ServiceMonitor::new(discovery, registry.clone(), routing_table.clone())
+        .with_intervals(config.discovery_interval, config.health_check_interval);
+    tokio::spawn(service_monitor.run());
+
+    let drains = Arc::new(PipelineDrains::new());
+
+    let assignment_watch = AssignmentWatch::new(
+        cluster_registration.connection().clone(),
         config.sidecar_id.clone(),
         routing_table.clone(),
+    )
+    .with_transform_options(transform_options.clone())
+    .with_drains(drains.clone())
+    .with_resume_token(cluster_registration.resume_token().to_string());
+    let watch_handle = tokio::spawn(async move {
+        if let Err(e) = assignment_watch.run().await {
+            error!("Assignment watch failed: {}", e);
+        }
+    });
+
+    let fan_stages = Arc::new(FanStages::new(config.fan_out_delivery_ttl));
+
+    let heartbeat_loop = HeartbeatLoop::new(
+        cluster_registration.clone(),
+        routing_table.clone(),
         Duration::from_secs(10),
-    );
+    )
+    .with_transform_options(transform_options)
+    .with_drains(drains.clone())
+    .with_local_router(local_router.clone())
+    .with_stage_balancer(stage_balancer.clone())
+    .with_fan_stages(fan_stages.clone());
 
     let heartbeat_registry = registry.clone();
     let heartbeat_handle = tokio::spawn(async move {
@@ -108,12 +172,43 @@ async fn main() -> Result<()> {
         }
     });
 
-    let data_plane = SidecarDataPlaneImpl::new(
+    let mut data_plane = SidecarDataPlaneImpl::new(
         routing_table.clone(),
         local_router.clone(),
         remote_router.clone(),
         config.sidecar_id.clone(),
-    );
+    )
+    .with_drains(drains)
+    .with_codecs(codecs)
+    .with_stage_balancer(stage_balancer)
+    .with_lookups(Arc::new(
+        LookupStages::new(LookupCache::new(
+            config.lookup_cache_size,
+            config.lookup_cache_ttl,
+            config.lookup_negative_ttl,
+        ))
+        .with_services(registry.clone()),
+    ))
+    .with_fan_stages(fan_stages);
+
+    if state_store.is_some() {
+        let checkpoints = Arc::new(OperatorCheckpoints::new(cluster_registration.channel()));
+        data_plane = data_plane.with_operator_checkpoints(checkpoints.clone());
+        tokio::spawn(checkpoints.run(routing_table.clone(), config.checkpoint_interval));
+    }
+
+    if config.schema_enforcement != SchemaEnforcement::Off {
+        info!(
+            enforcement = ?config.schema_enforcement,
+            dead_letter_stage = ?config.schema_dead_letter_stage,
+            "Schema validation enabled"
+        );
+        data_plane = data_plane.with_schema_validation(
+            schema_cache,
+            config.schema_enforcement,
+            config.schema_dead_letter_stage.clone(),
+        );
+    }
 
     info!("Starting SidecarDataPlane gRPC server on {}...", config.listen_addr);
 
@@ -130,6 +225,9 @@ async fn main() -> Result<()> {
         _ = heartbeat_handle => {
             warn!("Heartbeat loop stopped");
         }
+        _ = watch_handle => {
+            warn!("Assignment watch stopped");
+        }
         _ = tokio::signal::ctrl_c() => {
             info!("Received shutdown signal");
         }
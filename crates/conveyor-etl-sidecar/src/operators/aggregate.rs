@@ -0,0 +1,561 @@
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use conveyor_etl_dsl::{AggregateEmit, AggregateFunction, AggregateTransformConfig};
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_routing::{CodecRegistry, FieldPath};
+
+use super::state::{key, ts_from_key, ts_key, StateTxn};
+use super::window::{SessionWindows, WindowKind};
+use super::{emit, group_key, rfc3339, Operator, WINDOW_FINAL_KEY};
+use crate::transforms::{decode, TransformOutput};
+
+struct Aggregation {
+    /// `None` for `*`, which aggregates the whole payload.
+    field: Option<FieldPath>,
+    function: AggregateFunction,
+    output: String,
+}
+
+pub(super) struct Aggregate {
+    id: String,
+    group_by: Vec<FieldPath>,
+    window: WindowKind,
+    sessions: Option<SessionWindows>,
+    aggregations: Vec<Aggregation>,
+    emit: AggregateEmit,
+    codecs: Arc<CodecRegistry>,
+}
+
+/// Accumulated state of one group in one window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WindowAgg {
+    record_type: String,
+    group: String,
+    accs: Vec<Accumulator>,
+}
+
+impl Aggregate {
+    pub(super) fn compile(
+        id: &str,
+        config: &AggregateTransformConfig,
+        codecs: Arc<CodecRegistry>,
+    ) -> Result<Self> {
+        if config.aggregations.is_empty() {
+            anyhow::bail!("aggregate needs at least one aggregation");
+        }
+
+        let group_by = config
+            .group_by
+            .iter()
+            .map(|f| FieldPath::parse(f).map_err(|e| anyhow!("{}", e)))
+            .collect::<Result<_>>()?;
+        let aggregations = config
+            .aggregations
+            .iter()
+            .map(|a| {
+                let field = match a.field.as_str() {
+                    "*" => None,
+                    f => Some(FieldPath::parse(f).map_err(|e| anyhow!("{}", e))?),
+                };
+                let output = a.output_field.clone().unwrap_or_else(|| match &field {
+                    None => function_name(a.function).to_string(),
+                    Some(_) => format!("{}_{}", a.field, function_name(a.function)),
+                });
+                Ok(Aggregation {
+                    field,
+                    function: a.function,
+                    output,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let window = WindowKind::from_config(&config.window)?;
+        let sessions = match window {
+            WindowKind::Session { gap } => Some(SessionWindows {
+                gap,
+                max_duration: None,
+            }),
+            _ => None,
+        };
+
+        Ok(Self {
+            id: id.to_string(),
+            group_by,
+            window,
+            sessions,
+            aggregations,
+            emit: config.emit,
+            codecs,
+        })
+    }
+
+    fn empty(&self, record_type: &str, group: &str) -> WindowAgg {
+        WindowAgg {
+            record_type: record_type.to_string(),
+            group: group.to_string(),
+            accs: self
+                .aggregations
+                .iter()
+                .map(|a| Accumulator::new(a.function))
+                .collect(),
+        }
+    }
+
+    fn accumulate(&self, agg: &mut WindowAgg, value: &Value, ts: i64) {
+        for (aggregation, acc) in self.aggregations.iter().zip(&mut agg.accs) {
+            let input = match &aggregation.field {
+                None => Some(value),
+                Some(path) => path.get(value).filter(|v| !v.is_null()),
+            };
+            if let Some(input) = input {
+                acc.add(input, ts);
+            }
+        }
+    }
+
+    fn result(&self, agg: &WindowAgg, start: i64, end: i64, last: bool) -> Result<Record> {
+        let mut payload = Value::Object(Map::new());
+        let group: Vec<Value> = serde_json::from_str(&agg.group)?;
+        for (path, value) in self.group_by.iter().zip(group) {
+            path.set(&mut payload, value)?;
+        }
+
+        let fields = payload.as_object_mut().expect("payload is an object");
+        fields.insert("window_start".to_string(), Value::String(rfc3339(start)));
+        fields.insert("window_end".to_string(), Value::String(rfc3339(end)));
+        for (aggregation, acc) in self.aggregations.iter().zip(&agg.accs) {
+            fields.insert(aggregation.output.clone(), acc.result());
+        }
+
+        let key = format!("{}:{}:{}", start, end, agg.group);
+        let mut record = emit(&self.codecs, &self.id, &agg.record_type, &key, end, &payload)?;
+        record
+            .metadata
+            .insert(WINDOW_FINAL_KEY.to_string(), last.to_string());
+        Ok(record)
+    }
+}
+
+impl Operator for Aggregate {
+    fn name(&self) -> &'static str {
+        "aggregate"
+    }
+
+    fn is_late(&self, ts: i64, watermark: i64) -> bool {
+        match self.window {
+            WindowKind::Session { gap } => ts.saturating_add(gap) <= watermark,
+            _ => self.window.assign(ts).iter().all(|(_, end)| *end <= watermark),
+        }
+    }
+
+    fn process(
+        &self,
+        txn: &mut StateTxn<'_>,
+        record: Record,
+        ts: i64,
+        watermark: i64,
+        out: &mut TransformOutput,
+    ) -> Result<()> {
+        let value = match decode(&self.codecs, &record) {
+            Ok(value) => value,
+            Err(e) => {
+                out.fail(record, e);
+                return Ok(());
+            }
+        };
+        let group: Vec<Value> = self
+            .group_by
+            .iter()
+            .map(|path| path.get(&value).cloned().unwrap_or(Value::Null))
+            .collect();
+        let group = group_key(&group);
+        let on_update = self.emit == AggregateEmit::OnUpdate;
+
+        if let Some(sessions) = &self.sessions {
+            let session = sessions.add(
+                txn,
+                &group,
+                ts,
+                || self.empty(&record.record_type, &group),
+                |agg, other| {
+                    for (acc, other) in agg.accs.iter_mut().zip(other.accs) {
+                        acc.merge(other);
+                    }
+                },
+                |agg| self.accumulate(agg, &value, ts),
+            )?;
+            if on_update {
+                let end = sessions.close_at(&session);
+                out.records.push(self.result(&session.state, session.start, end, false)?);
+            }
+            return Ok(());
+        }
+
+        for (start, end) in self.window.assign(ts) {
+            if end <= watermark {
+                continue;
+            }
+            let state_key = key(&[b"w", &ts_key(end), &ts_key(start), group.as_bytes()]);
+            let mut agg = txn
+                .get::<WindowAgg>(&state_key)?
+                .unwrap_or_else(|| self.empty(&record.record_type, &group));
+            self.accumulate(&mut agg, &value, ts);
+            if on_update {
+                out.records.push(self.result(&agg, start, end, false)?);
+            }
+            txn.put(&state_key, &agg)?;
+        }
+        Ok(())
+    }
+
+    fn advance(&self, txn: &mut StateTxn<'_>, watermark: i64, out: &mut TransformOutput) -> Result<()> {
+        if let Some(sessions) = &self.sessions {
+            for (_, session) in sessions.close::<WindowAgg>(txn, watermark)? {
+                let end = sessions.close_at(&session);
+                out.records.push(self.result(&session.state, session.start, end, true)?);
+            }
+            return Ok(());
+        }
+
+        let closed = txn.scan_until::<WindowAgg>(b"w", Some(&ts_key(watermark.saturating_add(1))))?;
+        for (state_key, agg) in closed {
+            let (end, start) = (ts_from_key(&state_key[1..]), ts_from_key(&state_key[9..]));
+            out.records.push(self.result(&agg, start, end, true)?);
+            txn.delete(&state_key);
+        }
+        Ok(())
+    }
+
+    fn flush(&self, txn: &mut StateTxn<'_>, out: &mut TransformOutput) -> Result<()> {
+        if self.emit != AggregateEmit::Periodic {
+            return Ok(());
+        }
+
+        if let Some(sessions) = &self.sessions {
+            for (_, session) in sessions.all::<WindowAgg>(txn)? {
+                let end = sessions.close_at(&session);
+                out.records.push(self.result(&session.state, session.start, end, false)?);
+            }
+            return Ok(());
+        }
+
+        for (state_key, agg) in txn.scan::<WindowAgg>(b"w")? {
+            let (end, start) = (ts_from_key(&state_key[1..]), ts_from_key(&state_key[9..]));
+            out.records.push(self.result(&agg, start, end, false)?);
+        }
+        Ok(())
+    }
+}
+
+fn function_name(function: AggregateFunction) -> &'static str {
+    match function {
+        AggregateFunction::Count => "count",
+        AggregateFunction::Sum => "sum",
+        AggregateFunction::Avg => "avg",
+        AggregateFunction::Min => "min",
+        AggregateFunction::Max => "max",
+        AggregateFunction::First => "first",
+        AggregateFunction::Last => "last",
+        AggregateFunction::CountDistinct => "count_distinct",
+        AggregateFunction::Collect => "collect",
+    }
+}
+
+/// Running value of one aggregation. Values are kept as JSON text so the
+/// state stays bincode-serializable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Accumulator {
+    Count(u64),
+    Sum { total: f64, integral: bool },
+    Avg { total: f64, count: u64 },
+    Min(Option<String>),
+    Max(Option<String>),
+    First(Option<(i64, String)>),
+    Last(Option<(i64, String)>),
+    CountDistinct(BTreeSet<String>),
+    Collect(Vec<String>),
+}
+
+impl Accumulator {
+    fn new(function: AggregateFunction) -> Self {
+        match function {
+            AggregateFunction::Count => Self::Count(0),
+            AggregateFunction::Sum => Self::Sum { total: 0.0, integral: true },
+            AggregateFunction::Avg => Self::Avg { total: 0.0, count: 0 },
+            AggregateFunction::Min => Self::Min(None),
+            AggregateFunction::Max => Self::Max(None),
+            AggregateFunction::First => Self::First(None),
+            AggregateFunction::Last => Self::Last(None),
+            AggregateFunction::CountDistinct => Self::CountDistinct(BTreeSet::new()),
+            AggregateFunction::Collect => Self::Collect(Vec::new()),
+        }
+    }
+
+    fn add(&mut self, value: &Value, ts: i64) {
+        match self {
+            Self::Count(n) => *n += 1,
+            Self::Sum { total, integral } => {
+                if let Some(n) = value.as_f64() {
+                    *total += n;
+                    *integral &= value.is_i64() || value.is_u64();
+                }
+            }
+            Self::Avg { total, count } => {
+                if let Some(n) = value.as_f64() {
+                    *total += n;
+                    *count += 1;
+                }
+            }
+            Self::Min(current) => keep_extreme(current, value, Ordering::Less),
+            Self::Max(current) => keep_extreme(current, value, Ordering::Greater),
+            Self::First(current) => {
+                if current.as_ref().is_none_or(|(first, _)| ts < *first) {
+                    *current = Some((ts, value.to_string()));
+                }
+            }
+            Self::Last(current) => {
+                if current.as_ref().is_none_or(|(last, _)| ts >= *last) {
+                    *current = Some((ts, value.to_string()));
+                }
+            }
+            Self::CountDistinct(seen) => {
+                seen.insert(value.to_string());
+            }
+            Self::Collect(values) => values.push(value.to_string()),
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        match (self, other) {
+            (Self::Count(a), Self::Count(b)) => *a += b,
+            (Self::Sum { total, integral }, Self::Sum { total: t, integral: i }) => {
+                *total += t;
+                *integral &= i;
+            }
+            (Self::Avg { total, count }, Self::Avg { total: t, count: c }) => {
+                *total += t;
+                *count += c;
+            }
+            (Self::Min(a), Self::Min(Some(b))) => keep_extreme(a, &parse(&b), Ordering::Less),
+            (Self::Max(a), Self::Max(Some(b))) => keep_extreme(a, &parse(&b), Ordering::Greater),
+            (Self::First(a), Self::First(Some(b)))
+                if a.as_ref().is_none_or(|(first, _)| b.0 < *first) =>
+            {
+                *a = Some(b);
+            }
+            (Self::Last(a), Self::Last(Some(b)))
+                if a.as_ref().is_none_or(|(last, _)| b.0 >= *last) =>
+            {
+                *a = Some(b);
+            }
+            (Self::CountDistinct(a), Self::CountDistinct(b)) => a.extend(b),
+            (Self::Collect(a), Self::Collect(b)) => a.extend(b),
+            _ => {}
+        }
+    }
+
+    fn result(&self) -> Value {
+        match self {
+            Self::Count(n) => Value::from(*n),
+            Self::Sum { total, integral: true } if total.fract() == 0.0 => Value::from(*total as i64),
+            Self::Sum { total, .. } => Value::from(*total),
+            Self::Avg { count: 0, .. } => Value::Null,
+            Self::Avg { total, count } => Value::from(total / *count as f64),
+            Self::Min(v) | Self::Max(v) => v.as_deref().map(parse).unwrap_or(Value::Null),
+            Self::First(v) | Self::Last(v) => {
+                v.as_ref().map(|(_, text)| parse(text)).unwrap_or(Value::Null)
+            }
+            Self::CountDistinct(seen) => Value::from(seen.len()),
+            Self::Collect(values) => Value::Array(values.iter().map(|v| parse(v)).collect()),
+        }
+    }
+}
+
+fn parse(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or(Value::Null)
+}
+
+fn keep_extreme(current: &mut Option<String>, value: &Value, wanted: Ordering) {
+    let replace = match current {
+        None => true,
+        Some(text) => compare(value, &parse(text)) == wanted,
+    };
+    if replace {
+        *current = Some(value.to_string());
+    }
+}
+
+/// Orders numbers numerically and strings lexically; values of different
+/// types order by type.
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => {
+            let (x, y) = (x.as_f64().unwrap_or(0.0), y.as_f64().unwrap_or(0.0));
+            x.partial_cmp(&y).unwrap_or(Ordering::Equal)
+        }
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+fn type_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::super::tests::{batch, event, payloads, Harness};
+    use super::super::WINDOW_FINAL_KEY;
+
+    #[test]
+    fn test_tumbling_aggregate_on_close() {
+        let harness = Harness::new();
+        let op = harness.compile(json!({
+            "transform_type": "aggregate",
+            "group_by": ["user"],
+            "window": {"tumbling": {"seconds": 10}},
+            "aggregations": [
+                {"field": "*", "function": "count"},
+                {"field": "amount", "function": "sum"},
+                {"field": "amount", "function": "avg", "output_field": "mean"},
+                {"field": "amount", "function": "max"},
+                {"field": "item", "function": "first"},
+                {"field": "item", "function": "countdistinct"}
+            ]
+        }));
+
+        let out = op
+            .process(batch(vec![
+                event(1, 3_000, json!({"user": "a", "amount": 5, "item": "x"})),
+                event(2, 1_000, json!({"user": "a", "amount": 3, "item": "y"})),
+                event(3, 4_000, json!({"user": "b", "amount": 1.5, "item": "x"})),
+                event(4, 8_000, json!({"user": "a", "item": "x"})),
+            ]))
+            .unwrap();
+        assert!(out.records.is_empty());
+
+        let out = op.process(batch(vec![event(5, 10_500, json!({"user": "a", "amount": 1}))])).unwrap();
+        assert_eq!(
+            payloads(&out.records),
+            vec![
+                json!({
+                    "user": "a",
+                    "window_start": "1970-01-01T00:00:00.000Z",
+                    "window_end": "1970-01-01T00:00:10.000Z",
+                    "count": 3,
+                    "amount_sum": 8,
+                    "mean": 4.0,
+                    "amount_max": 5,
+                    "item_first": "y",
+                    "item_count_distinct": 2
+                }),
+                json!({
+                    "user": "b",
+                    "window_start": "1970-01-01T00:00:00.000Z",
+                    "window_end": "1970-01-01T00:00:10.000Z",
+                    "count": 1,
+                    "amount_sum": 1.5,
+                    "mean": 1.5,
+                    "amount_max": 1.5,
+                    "item_first": "x",
+                    "item_count_distinct": 1
+                }),
+            ]
+        );
+        assert_eq!(out.records[0].metadata[WINDOW_FINAL_KEY], "true");
+    }
+
+    #[test]
+    fn test_sliding_aggregate_on_update() {
+        let harness = Harness::new();
+        let op = harness.compile(json!({
+            "transform_type": "aggregate",
+            "group_by": [],
+            "window": {"sliding": {"size": {"seconds": 10}, "slide": {"seconds": 5}}},
+            "aggregations": [{"field": "n", "function": "collect"}],
+            "emit": "on_update"
+        }));
+
+        let out = op.process(batch(vec![event(1, 7_000, json!({"n": 1}))])).unwrap();
+        let results = payloads(&out.records);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["window_start"], "1970-01-01T00:00:00.000Z");
+        assert_eq!(results[1]["window_start"], "1970-01-01T00:00:05.000Z");
+        assert_eq!(out.records[0].metadata[WINDOW_FINAL_KEY], "false");
+
+        let out = op.process(batch(vec![event(2, 12_000, json!({"n": 2}))])).unwrap();
+        let results = payloads(&out.records);
+        assert_eq!(results[0]["n_collect"], json!([1, 2]));
+        assert_eq!(results[1]["n_collect"], json!([2]));
+        assert_eq!(results[2]["window_end"], "1970-01-01T00:00:10.000Z");
+        assert_eq!(results[2]["n_collect"], json!([1]));
+    }
+
+    #[test]
+    fn test_session_aggregate_merges_out_of_order_events() {
+        let mut harness = Harness::new();
+        harness.options.operators.allowed_lateness = Duration::from_secs(5);
+        let op = harness.compile(json!({
+            "transform_type": "aggregate",
+            "group_by": ["user"],
+            "window": {"session": {"seconds": 5}},
+            "aggregations": [{"field": "*", "function": "count"}]
+        }));
+
+        op.process(batch(vec![
+            event(1, 1_000, json!({"user": "a"})),
+            event(2, 9_000, json!({"user": "a"})),
+        ]))
+        .unwrap();
+        // Bridges the two sessions into one.
+        op.process(batch(vec![event(3, 5_000, json!({"user": "a"}))])).unwrap();
+
+        let out = op.process(batch(vec![event(4, 20_000, json!({"user": "b"}))])).unwrap();
+        assert_eq!(
+            payloads(&out.records),
+            vec![json!({
+                "user": "a",
+                "window_start": "1970-01-01T00:00:01.000Z",
+                "window_end": "1970-01-01T00:00:14.000Z",
+                "count": 3
+            })]
+        );
+    }
+
+    #[test]
+    fn test_periodic_emit() {
+        let mut harness = Harness::new();
+        harness.options.operators.emit_interval = Duration::ZERO;
+        let op = harness.compile(json!({
+            "transform_type": "aggregate",
+            "group_by": [],
+            "window": {"tumbling": {"minutes": 1}},
+            "aggregations": [{"field": "*", "function": "count"}],
+            "emit": "periodic"
+        }));
+
+        let out = op.process(batch(vec![event(1, 1_000, json!({}))])).unwrap();
+        assert_eq!(payloads(&out.records)[0]["count"], 1);
+        assert_eq!(out.records[0].metadata[WINDOW_FINAL_KEY], "false");
+
+        let out = op.process(batch(vec![event(2, 2_000, json!({}))])).unwrap();
+        assert_eq!(payloads(&out.records)[0]["count"], 2);
+    }
+}
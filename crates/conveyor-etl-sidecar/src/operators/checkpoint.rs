@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tonic::transport::Channel;
+use tracing::{debug, info, warn};
+
+use conveyor_etl_proto::checkpoint::{
+    checkpoint_service_client::CheckpointServiceClient, GetCheckpointRequest, PartitionOffsets,
+    SaveCheckpointRequest,
+};
+
+use super::{blocking, StatefulOperator};
+use crate::routing::{RouteDecision, SharedRoutingTable};
+
+/// The partition of a stage's state. A stateful stage runs on one sidecar
+/// at a time, so its state is a single partition.
+const STATE_PARTITION: u32 = 0;
+
+/// Saves operator state to the router's `CheckpointService` and restores it
+/// on the sidecar running the stage. Checkpoints are keyed by
+/// `{pipeline}/{stage}/{partition}`, so a stage that moves to another
+/// sidecar picks up where it left off.
+pub struct OperatorCheckpoints {
+    client: CheckpointServiceClient<Channel>,
+}
+
+impl OperatorCheckpoints {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            client: CheckpointServiceClient::new(channel),
+        }
+    }
+
+    fn service_id(&self, operator: &StatefulOperator) -> String {
+        format!("{}/{}", operator.id, STATE_PARTITION)
+    }
+
+    pub async fn save(&self, operator: &Arc<StatefulOperator>) -> Result<()> {
+        let state = blocking(operator, |operator| {
+            if operator.state.is_empty()? {
+                return Ok(None);
+            }
+            Ok(Some((operator.snapshot()?, operator.source_offsets()?)))
+        })
+        .await?;
+        let Some((data, source_offsets)) = state else {
+            return Ok(());
+        };
+
+        let request = SaveCheckpointRequest {
+            service_id: self.service_id(operator),
+            checkpoint_id: uuid::Uuid::new_v4().to_string(),
+            data,
+            source_offsets,
+        };
+        let response = self
+            .client
+            .clone()
+            .save_checkpoint(request)
+            .await
+            .context("SaveCheckpoint failed")?
+            .into_inner();
+        if !response.success {
+            anyhow::bail!("Router rejected checkpoint for {}", operator.id);
+        }
+
+        debug!(
+            operator = %operator.id,
+            checkpoint = %response.checkpoint_id,
+            "Saved operator checkpoint"
+        );
+        Ok(())
+    }
+
+    /// Restores the operator's last checkpoint the first time it is called
+    /// for this operator. State already on disk is kept unless the
+    /// checkpoint has processed further on some source partition, as it has
+    /// when the stage ran on another sidecar since.
+    pub async fn restore_once(&self, operator: &Arc<StatefulOperator>) -> Result<()> {
+        operator
+            .restored
+            .get_or_try_init(|| self.restore(operator))
+            .await
+            .map(|_| ())
+    }
+
+    async fn restore(&self, operator: &Arc<StatefulOperator>) -> Result<()> {
+        let local = blocking(operator, |operator| {
+            if operator.state.is_empty()? {
+                return Ok(None);
+            }
+            operator.source_offsets().map(Some)
+        })
+        .await?;
+
+        let response = self
+            .client
+            .clone()
+            .get_checkpoint(GetCheckpointRequest {
+                service_id: self.service_id(operator),
+            })
+            .await
+            .context("GetCheckpoint failed")?
+            .into_inner();
+        if !response.found || response.data.is_empty() {
+            return Ok(());
+        }
+        if local.is_some_and(|local| !is_ahead(&response.source_offsets, &local)) {
+            return Ok(());
+        }
+
+        let checkpoint_id = response.checkpoint_id;
+        let data = response.data;
+        blocking(operator, move |operator| operator.state.restore(&data)).await?;
+        info!(
+            operator = %operator.id,
+            checkpoint = %checkpoint_id,
+            "Restored operator state from checkpoint"
+        );
+        Ok(())
+    }
+
+    /// Checkpoints every stateful operator in the routing table on `interval`.
+    pub async fn run(self: Arc<Self>, routing_table: SharedRoutingTable, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let operators: Vec<Arc<StatefulOperator>> = {
+                let table = routing_table.read().await;
+                table
+                    .pipeline_ids()
+                    .filter_map(|id| table.get_pipeline_routes(id))
+                    .flat_map(|routes| routes.stages.values())
+                    .filter_map(|stage| match &stage.decision {
+                        RouteDecision::Stateful { operator } => Some(operator.clone()),
+                        _ => None,
+                    })
+                    .collect()
+            };
+
+            for operator in operators {
+                if let Err(e) = self.save(&operator).await {
+                    warn!(
+                        operator = %operator.id,
+                        error = %format!("{:#}", e),
+                        "Operator checkpoint failed"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Whether `checkpoint` is past `local` on any source partition.
+fn is_ahead(
+    checkpoint: &HashMap<String, PartitionOffsets>,
+    local: &HashMap<String, PartitionOffsets>,
+) -> bool {
+    checkpoint.iter().any(|(source, offsets)| {
+        offsets.offsets.iter().any(|(partition, seq)| {
+            let seen = local
+                .get(source)
+                .and_then(|local| local.offsets.get(partition))
+                .copied()
+                .unwrap_or(0);
+            *seq > seen
+        })
+    })
+}
@@ -0,0 +1,228 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use conveyor_etl_dsl::{DedupeKeep, DedupeTransformConfig};
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_routing::{CodecRegistry, FieldPath};
+
+use super::state::{key, ts_key, StateTxn};
+use super::window::WindowKind;
+use super::{group_key, Operator};
+use crate::transforms::{decode, TransformOutput};
+
+pub(super) struct Dedupe {
+    key_fields: Vec<FieldPath>,
+    window: WindowKind,
+    keep: DedupeKeep,
+    codecs: Arc<CodecRegistry>,
+}
+
+/// A key seen within its window. `keep: last` holds the latest record
+/// (prost-encoded) until the entry expires.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    expires: i64,
+    ts: i64,
+    record: Option<Vec<u8>>,
+}
+
+impl Dedupe {
+    pub(super) fn compile(config: &DedupeTransformConfig, codecs: Arc<CodecRegistry>) -> Result<Self> {
+        if config.key_fields.is_empty() {
+            anyhow::bail!("dedupe needs at least one key field");
+        }
+        let key_fields = config
+            .key_fields
+            .iter()
+            .map(|f| FieldPath::parse(f).map_err(|e| anyhow!("{}", e)))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            key_fields,
+            window: WindowKind::from_config(&config.window)?,
+            keep: config.keep,
+            codecs,
+        })
+    }
+
+    /// The entry's key suffix and expiry for an event at `ts`. Tumbling
+    /// windows dedupe within each window; sliding windows for `size` after
+    /// the first kept event; session windows until `gap` passes without a
+    /// duplicate.
+    fn scope(&self, ts: i64, group: &str) -> (Vec<u8>, i64) {
+        match self.window {
+            WindowKind::Tumbling { size } => {
+                let start = ts.div_euclid(size) * size;
+                (key(&[&ts_key(start), group.as_bytes()]), start + size)
+            }
+            WindowKind::Sliding { size, .. } => (group.as_bytes().to_vec(), ts + size),
+            WindowKind::Session { gap } => (group.as_bytes().to_vec(), ts + gap),
+        }
+    }
+
+    fn release(&self, entry: Entry, out: &mut TransformOutput) -> Result<()> {
+        if let Some(bytes) = entry.record {
+            let record = Record::decode(bytes.as_slice()).context("Corrupt deduplicated record")?;
+            out.records.push(record);
+        }
+        Ok(())
+    }
+}
+
+impl Operator for Dedupe {
+    fn name(&self) -> &'static str {
+        "dedupe"
+    }
+
+    fn is_late(&self, ts: i64, watermark: i64) -> bool {
+        match self.window {
+            WindowKind::Tumbling { .. } => self.scope(ts, "").1 <= watermark,
+            _ => ts.saturating_add(self.window.span()) <= watermark,
+        }
+    }
+
+    fn process(
+        &self,
+        txn: &mut StateTxn<'_>,
+        record: Record,
+        ts: i64,
+        _watermark: i64,
+        out: &mut TransformOutput,
+    ) -> Result<()> {
+        let value = match decode(&self.codecs, &record) {
+            Ok(value) => value,
+            Err(e) => {
+                out.fail(record, e);
+                return Ok(());
+            }
+        };
+        let values: Vec<Value> = self
+            .key_fields
+            .iter()
+            .map(|path| path.get(&value).cloned().unwrap_or(Value::Null))
+            .collect();
+        let (suffix, expires) = self.scope(ts, &group_key(&values));
+        let entry_key = key(&[b"d", &suffix]);
+
+        match txn.get::<Entry>(&entry_key)? {
+            Some(mut entry) if ts < entry.expires => {
+                if self.keep == DedupeKeep::Last && ts >= entry.ts {
+                    entry.record = Some(record.encode_to_vec());
+                    entry.ts = ts;
+                }
+                if let WindowKind::Session { gap } = self.window {
+                    let extended = entry.expires.max(ts + gap);
+                    if extended != entry.expires {
+                        txn.delete(&index_key(entry.expires, &suffix));
+                        txn.put(&index_key(extended, &suffix), &())?;
+                        entry.expires = extended;
+                    }
+                }
+                txn.put(&entry_key, &entry)
+            }
+            stale => {
+                // Expired, but the watermark has not evicted it yet.
+                if let Some(stale) = stale {
+                    txn.delete(&index_key(stale.expires, &suffix));
+                    self.release(stale, out)?;
+                }
+
+                let held = match self.keep {
+                    DedupeKeep::First => {
+                        out.records.push(record);
+                        None
+                    }
+                    DedupeKeep::Last => Some(record.encode_to_vec()),
+                };
+                txn.put(&index_key(expires, &suffix), &())?;
+                txn.put(
+                    &entry_key,
+                    &Entry {
+                        expires,
+                        ts,
+                        record: held,
+                    },
+                )
+            }
+        }
+    }
+
+    fn advance(&self, txn: &mut StateTxn<'_>, watermark: i64, out: &mut TransformOutput) -> Result<()> {
+        let expired = txn.scan_until::<()>(b"x", Some(&ts_key(watermark.saturating_add(1))))?;
+        for (index, ()) in expired {
+            txn.delete(&index);
+            let entry_key = key(&[b"d", &index[9..]]);
+            if let Some(entry) = txn.get::<Entry>(&entry_key)? {
+                txn.delete(&entry_key);
+                self.release(entry, out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn index_key(expires: i64, suffix: &[u8]) -> Vec<u8> {
+    key(&[b"x", &ts_key(expires), suffix])
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::tests::{batch, event, payloads, Harness};
+
+    #[test]
+    fn test_keep_first_in_tumbling_window() {
+        let harness = Harness::new();
+        let op = harness.compile(json!({
+            "transform_type": "dedupe",
+            "key_fields": ["id"],
+            "window": {"tumbling": {"seconds": 10}}
+        }));
+
+        let out = op
+            .process(batch(vec![
+                event(1, 1_000, json!({"id": 1, "v": "a"})),
+                event(2, 2_000, json!({"id": 1, "v": "b"})),
+                event(3, 3_000, json!({"id": 2, "v": "c"})),
+                event(4, 11_000, json!({"id": 1, "v": "d"})),
+            ]))
+            .unwrap();
+
+        let values: Vec<_> = payloads(&out.records).into_iter().map(|p| p["v"].clone()).collect();
+        assert_eq!(values, vec![json!("a"), json!("c"), json!("d")]);
+    }
+
+    #[test]
+    fn test_keep_last_emits_on_expiry() {
+        let harness = Harness::new();
+        let op = harness.compile(json!({
+            "transform_type": "dedupe",
+            "key_fields": ["id"],
+            "window": {"session": {"seconds": 5}},
+            "keep": "last"
+        }));
+
+        let out = op
+            .process(batch(vec![
+                event(1, 1_000, json!({"id": 1, "v": "a"})),
+                event(2, 4_000, json!({"id": 1, "v": "b"})),
+            ]))
+            .unwrap();
+        assert!(out.records.is_empty());
+
+        // Out of order, so it does not replace the later record.
+        let out = op.process(batch(vec![event(3, 3_000, json!({"id": 1, "v": "c"}))])).unwrap();
+        assert!(out.records.is_empty());
+
+        let out = op.process(batch(vec![event(4, 8_500, json!({"id": 2, "v": "d"}))])).unwrap();
+        assert!(out.records.is_empty());
+
+        let out = op.process(batch(vec![event(5, 9_000, json!({"id": 3, "v": "e"}))])).unwrap();
+        assert_eq!(payloads(&out.records), vec![json!({"id": 1, "v": "b"})]);
+    }
+}
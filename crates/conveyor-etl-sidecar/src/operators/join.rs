@@ -0,0 +1,450 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use conveyor_etl_dsl::{JoinOutputFields, JoinTransformConfig, JoinType};
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_routing::{timestamp_millis, CodecRegistry, FieldPath};
+
+use super::state::{key, ts_key, StateTxn};
+use super::window::WindowKind;
+use super::{emit, group_key, Operator};
+use crate::transforms::{decode, TransformOutput};
+
+/// Metadata key naming the stream a record arrived on. Records whose stream
+/// (or, without one, whose `RecordId.source_id`) equals the join's
+/// `right_stream` are its right side; everything else is the left side.
+pub const STREAM_KEY: &str = "_stream";
+
+const SEQ_KEY: &[u8] = b"j:seq";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    fn tag(self) -> u8 {
+        match self {
+            Side::Left => b'l',
+            Side::Right => b'r',
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        }
+    }
+}
+
+/// A buffered input row, waiting for matches until it expires.
+#[derive(Debug, Serialize, Deserialize)]
+struct Row {
+    ts: i64,
+    expires: i64,
+    matched: bool,
+    record: Vec<u8>,
+}
+
+pub(super) struct Join {
+    id: String,
+    join_type: JoinType,
+    right_stream: String,
+    left_key: FieldPath,
+    right_key: FieldPath,
+    window: WindowKind,
+    output: JoinOutputFields,
+    codecs: Arc<CodecRegistry>,
+}
+
+impl Join {
+    pub(super) fn compile(
+        id: &str,
+        config: &JoinTransformConfig,
+        codecs: Arc<CodecRegistry>,
+    ) -> Result<Self> {
+        if config.right_stream.is_empty() {
+            anyhow::bail!("join needs a right_stream");
+        }
+
+        Ok(Self {
+            id: id.to_string(),
+            join_type: config.join_type,
+            right_stream: config.right_stream.clone(),
+            left_key: FieldPath::parse(&config.on.left_key).map_err(|e| anyhow!("{}", e))?,
+            right_key: FieldPath::parse(&config.on.right_key).map_err(|e| anyhow!("{}", e))?,
+            window: WindowKind::from_config(&config.window)?,
+            output: config.output_fields.clone().unwrap_or(JoinOutputFields {
+                left_prefix: None,
+                right_prefix: None,
+                include: None,
+                exclude: None,
+            }),
+            codecs,
+        })
+    }
+
+    fn side(&self, record: &Record) -> Side {
+        let stream = record
+            .metadata
+            .get(STREAM_KEY)
+            .map(String::as_str)
+            .or_else(|| record.id.as_ref().map(|id| id.source_id.as_str()));
+        if stream == Some(self.right_stream.as_str()) {
+            Side::Right
+        } else {
+            Side::Left
+        }
+    }
+
+    /// Whether unmatched rows of `side` are emitted.
+    fn preserves(&self, side: Side) -> bool {
+        matches!(
+            (self.join_type, side),
+            (JoinType::Full, _) | (JoinType::Left, Side::Left) | (JoinType::Right, Side::Right)
+        )
+    }
+
+    /// Key prefix shared by rows that can match an event at `ts`, and when
+    /// such a row stops being matchable. Tumbling windows join rows in the
+    /// same window; sliding and session windows join rows less than the
+    /// window size (or gap) apart.
+    fn scope(&self, ts: i64) -> (Vec<u8>, i64) {
+        match self.window {
+            WindowKind::Tumbling { size } => {
+                let start = ts.div_euclid(size) * size;
+                (ts_key(start).to_vec(), start + size)
+            }
+            _ => (Vec::new(), ts + self.window.span()),
+        }
+    }
+
+    fn in_range(&self, a: i64, b: i64) -> bool {
+        match self.window {
+            WindowKind::Tumbling { .. } => true,
+            _ => (a - b).abs() < self.window.span(),
+        }
+    }
+
+    fn joined(&self, left: Option<&Record>, right: Option<&Record>) -> Result<Record> {
+        let mut fields = Map::new();
+        for (record, prefix) in [
+            (right, self.output.right_prefix.as_deref()),
+            (left, self.output.left_prefix.as_deref()),
+        ] {
+            let Some(record) = record else { continue };
+            let value = decode(&self.codecs, record).map_err(|e| anyhow!(e))?;
+            let Value::Object(object) = value else {
+                anyhow::bail!("join input payload must be an object");
+            };
+            for (name, value) in object {
+                fields.insert(format!("{}{}", prefix.unwrap_or_default(), name), value);
+            }
+        }
+
+        if let Some(include) = &self.output.include {
+            fields.retain(|name, _| include.contains(name));
+        }
+        if let Some(exclude) = &self.output.exclude {
+            fields.retain(|name, _| !exclude.contains(name));
+        }
+
+        let base = left.or(right).expect("at least one side");
+        let ts = [left, right]
+            .into_iter()
+            .flatten()
+            .filter_map(|r| r.event_time.as_ref().and_then(timestamp_millis))
+            .max()
+            .unwrap_or_default();
+        let key = format!(
+            "{}|{}",
+            left.map(record_ref).unwrap_or_default(),
+            right.map(record_ref).unwrap_or_default()
+        );
+
+        let mut record = emit(
+            &self.codecs,
+            &self.id,
+            &base.record_type,
+            &key,
+            ts,
+            &Value::Object(fields),
+        )?;
+        for (k, v) in &base.metadata {
+            if k != STREAM_KEY {
+                record.metadata.entry(k.clone()).or_insert_with(|| v.clone());
+            }
+        }
+        Ok(record)
+    }
+
+    fn emit_unmatched(&self, side: Side, record: &Record, out: &mut TransformOutput) -> Result<()> {
+        let joined = match side {
+            Side::Left => self.joined(Some(record), None)?,
+            Side::Right => self.joined(None, Some(record))?,
+        };
+        out.records.push(joined);
+        Ok(())
+    }
+}
+
+impl Operator for Join {
+    fn name(&self) -> &'static str {
+        "join"
+    }
+
+    fn is_late(&self, ts: i64, watermark: i64) -> bool {
+        self.scope(ts).1 <= watermark
+    }
+
+    fn process(
+        &self,
+        txn: &mut StateTxn<'_>,
+        record: Record,
+        ts: i64,
+        _watermark: i64,
+        out: &mut TransformOutput,
+    ) -> Result<()> {
+        let value = match decode(&self.codecs, &record) {
+            Ok(value) => value,
+            Err(e) => {
+                out.fail(record, e);
+                return Ok(());
+            }
+        };
+        let side = self.side(&record);
+        let key_path = match side {
+            Side::Left => &self.left_key,
+            Side::Right => &self.right_key,
+        };
+
+        // A null key never matches anything.
+        let Some(join_key) = key_path.get(&value).filter(|v| !v.is_null()) else {
+            if self.preserves(side) {
+                if let Err(e) = self.emit_unmatched(side, &record, out) {
+                    out.fail(record, format!("{:#}", e));
+                }
+            }
+            return Ok(());
+        };
+
+        let (scope, expires) = self.scope(ts);
+        let join_key = group_key(std::slice::from_ref(join_key));
+        let group = key(&[&scope, join_key.as_bytes(), b"\0"]);
+
+        let mut matched = false;
+        for (row_key, mut row) in txn.scan::<Row>(&key(&[b"b", &[side.other().tag()], &group]))? {
+            if !self.in_range(ts, row.ts) {
+                continue;
+            }
+            let other = Record::decode(row.record.as_slice()).context("Corrupt buffered join row")?;
+            let joined = match side {
+                Side::Left => self.joined(Some(&record), Some(&other)),
+                Side::Right => self.joined(Some(&other), Some(&record)),
+            };
+            match joined {
+                Ok(joined) => out.records.push(joined),
+                Err(e) => {
+                    out.fail(record, format!("{:#}", e));
+                    return Ok(());
+                }
+            }
+            matched = true;
+            if !row.matched {
+                row.matched = true;
+                txn.put(&row_key, &row)?;
+            }
+        }
+
+        let seq = txn.get::<u64>(SEQ_KEY)?.unwrap_or_default() + 1;
+        txn.put(SEQ_KEY, &seq)?;
+        let suffix = key(&[&[side.tag()], &group, &ts_key(ts), &seq.to_be_bytes()]);
+        txn.put(&key(&[b"x", &ts_key(expires), &suffix]), &())?;
+        txn.put(
+            &key(&[b"b", &suffix]),
+            &Row {
+                ts,
+                expires,
+                matched,
+                record: record.encode_to_vec(),
+            },
+        )
+    }
+
+    fn advance(&self, txn: &mut StateTxn<'_>, watermark: i64, out: &mut TransformOutput) -> Result<()> {
+        let expired = txn.scan_until::<()>(b"x", Some(&ts_key(watermark.saturating_add(1))))?;
+        for (index, ()) in expired {
+            txn.delete(&index);
+            let row_key = key(&[b"b", &index[9..]]);
+            let Some(row) = txn.get::<Row>(&row_key)? else {
+                continue;
+            };
+            txn.delete(&row_key);
+
+            let side = if index[9] == Side::Right.tag() { Side::Right } else { Side::Left };
+            if row.matched || !self.preserves(side) {
+                continue;
+            }
+            let record = Record::decode(row.record.as_slice()).context("Corrupt buffered join row")?;
+            if let Err(e) = self.emit_unmatched(side, &record, out) {
+                out.fail(record, format!("{:#}", e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `source:partition:sequence` of a record, and its idempotency key when it
+/// has one, identifying it in the idempotency key of the rows it joins into.
+fn record_ref(record: &Record) -> String {
+    record
+        .id
+        .as_ref()
+        .map(|id| {
+            let mut r = format!("{}:{}:{}", id.source_id, id.partition, id.sequence_number);
+            if !id.idempotency_key.is_empty() {
+                r.push(':');
+                r.push_str(&String::from_utf8_lossy(&id.idempotency_key));
+            }
+            r
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use conveyor_etl_proto::common::{Record, RecordId};
+
+    use super::super::tests::{batch, event, payloads, Harness};
+    use super::super::{StatefulOperator, LATE_OUTPUT};
+    use super::STREAM_KEY;
+
+    fn join(join_type: &str, window: Value, output_fields: Value) -> (Harness, StatefulOperator) {
+        let harness = Harness::new();
+        let op = harness.compile(json!({
+            "transform_type": "join",
+            "join_type": join_type,
+            "right_stream": "users",
+            "on": {"left_key": "user_id", "right_key": "id"},
+            "window": window,
+            "output_fields": output_fields
+        }));
+        (harness, op)
+    }
+
+    fn user(seq: u64, ts: i64, payload: Value) -> Record {
+        let mut record = event(seq, ts, payload);
+        record.id = Some(RecordId {
+            source_id: "users".to_string(),
+            sequence_number: seq,
+            ..Default::default()
+        });
+        record
+    }
+
+    fn buffered_rows(op: &StatefulOperator) -> usize {
+        op.state.begin().scan::<super::Row>(b"b").unwrap().len()
+    }
+
+    #[test]
+    fn test_inner_join_with_prefixes() {
+        let (_h, op) = join(
+            "inner",
+            json!({"tumbling": {"seconds": 10}}),
+            json!({"left_prefix": "order_", "right_prefix": "user_", "exclude": ["user_id"]}),
+        );
+
+        // The right side arrives first.
+        let out = op
+            .process(batch(vec![
+                user(1, 2_000, json!({"id": 7, "name": "ann"})),
+                event(1, 3_000, json!({"user_id": 7, "total": 10})),
+                event(2, 4_000, json!({"user_id": 8, "total": 5})),
+            ]))
+            .unwrap();
+
+        assert_eq!(
+            payloads(&out.records),
+            vec![json!({"order_user_id": 7, "order_total": 10, "user_name": "ann"})]
+        );
+        assert!(!out.records[0].metadata.contains_key(STREAM_KEY));
+    }
+
+    #[test]
+    fn test_left_join_emits_unmatched_on_expiry() {
+        let (_h, op) = join(
+            "left",
+            json!({"tumbling": {"seconds": 10}}),
+            json!({"include": ["user_id", "total", "name"]}),
+        );
+
+        let out = op
+            .process(batch(vec![
+                event(1, 1_000, json!({"user_id": 1, "total": 3})),
+                event(2, 2_000, json!({"user_id": 2, "total": 4})),
+                user(1, 5_000, json!({"id": 2, "name": "bo", "email": "b@x"})),
+                user(2, 6_000, json!({"id": 3, "name": "cy"})),
+            ]))
+            .unwrap();
+        assert_eq!(payloads(&out.records), vec![json!({"user_id": 2, "total": 4, "name": "bo"})]);
+
+        let mut tick = event(3, 10_000, json!({"user_id": 9}));
+        tick.metadata.insert(STREAM_KEY.to_string(), "users".to_string());
+        let out = op.process(batch(vec![tick, user(3, 10_000, json!({"id": 9}))])).unwrap();
+        assert_eq!(payloads(&out.records), vec![json!({"user_id": 1, "total": 3})]);
+        assert_eq!(buffered_rows(&op), 1);
+    }
+
+    #[test]
+    fn test_full_join_with_interval_window() {
+        let (_h, op) = join("full", json!({"sliding": {"size": {"seconds": 5}, "slide": {"seconds": 1}}}), Value::Null);
+
+        let out = op
+            .process(batch(vec![
+                event(1, 10_000, json!({"user_id": 1, "total": 1})),
+                user(1, 14_000, json!({"id": 1, "name": "a"})),
+                user(2, 16_000, json!({"id": 1, "name": "b"})),
+            ]))
+            .unwrap();
+        assert_eq!(payloads(&out.records), vec![json!({"user_id": 1, "total": 1, "id": 1, "name": "a"})]);
+
+        // Out of order but within range of both right rows.
+        let out = op.process(batch(vec![event(2, 12_000, json!({"user_id": 1, "total": 2}))])).unwrap();
+        assert_eq!(out.records.len(), 2);
+
+        let out = op
+            .process(batch(vec![
+                event(3, 30_000, json!({"user_id": 5})),
+                user(3, 30_000, json!({"id": 6})),
+            ]))
+            .unwrap();
+        assert!(out.records.is_empty());
+        assert_eq!(buffered_rows(&op), 2);
+    }
+
+    #[test]
+    fn test_late_rows_and_unmatched_right() {
+        let (_h, op) = join("right", json!({"tumbling": {"seconds": 10}}), Value::Null);
+
+        let out = op
+            .process(batch(vec![
+                user(1, 1_000, json!({"id": 1, "name": "a"})),
+                event(1, 25_000, json!({"user_id": 4})),
+                user(2, 25_000, json!({"id": 5})),
+            ]))
+            .unwrap();
+        assert_eq!(payloads(&out.records), vec![json!({"id": 1, "name": "a"})]);
+
+        let out = op.process(batch(vec![event(2, 2_000, json!({"user_id": 1}))])).unwrap();
+        assert!(out.records.is_empty());
+        assert_eq!(out.routed[LATE_OUTPUT].len(), 1);
+    }
+}
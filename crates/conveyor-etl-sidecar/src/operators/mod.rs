@@ -0,0 +1,803 @@
+//! Stateful stream operators (`aggregate`, `dedupe`, `sessionize`, `join`)
+//! run by the sidecar. Keyed state lives in an embedded RocksDB store and windows
+//! close on event-time watermarks.
+
+mod aggregate;
+mod checkpoint;
+mod dedupe;
+mod join;
+mod sessionize;
+mod state;
+mod window;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+use conveyor_etl_dsl::TransformConfigDsl;
+use conveyor_etl_proto::checkpoint::PartitionOffsets;
+use conveyor_etl_proto::common::{Record, RecordBatch, RecordId};
+use conveyor_etl_routing::{timestamp_millis, CodecRegistry};
+
+use crate::transforms::{TransformOptions, TransformOutput};
+use aggregate::Aggregate;
+use dedupe::Dedupe;
+use join::Join;
+use sessionize::Sessionize;
+use state::{OperatorState, StateTxn};
+
+pub use checkpoint::OperatorCheckpoints;
+pub use join::STREAM_KEY;
+pub use sessionize::{SESSION_ID_KEY, SESSION_START_KEY};
+pub use state::StateStore;
+
+/// Output that records arriving behind the watermark are sent to.
+pub const LATE_OUTPUT: &str = "late";
+
+/// Metadata key set to `"true"` on a window's final result and `"false"` on
+/// early results from `on_update` and `periodic` emission.
+pub const WINDOW_FINAL_KEY: &str = "_window_final";
+
+const OFFSETS_KEY: &[u8] = b"m:offsets";
+const SOURCE_TIMES_KEY: &[u8] = b"m:sources";
+const SOURCE_SEEN_KEY: &[u8] = b"m:seen";
+const WATERMARK_KEY: &[u8] = b"m:watermark";
+const EMITTED_KEY: &[u8] = b"m:emitted";
+const HELD_SEQ_KEY: &[u8] = b"m:held";
+const HELD_PREFIX: &[u8] = b"o:";
+
+#[derive(Debug, Clone)]
+pub struct OperatorOptions {
+    /// How far behind the watermark an event may arrive and still be
+    /// counted. Windows close this long after the watermark passes them.
+    pub allowed_lateness: Duration,
+    /// Interval between early results of `periodic` aggregates.
+    pub emit_interval: Duration,
+    /// Sources not heard from for this long stop holding the watermark
+    /// back until they send again. Zero keeps every source.
+    pub idle_source_timeout: Duration,
+}
+
+impl Default for OperatorOptions {
+    fn default() -> Self {
+        Self {
+            allowed_lateness: Duration::ZERO,
+            emit_interval: Duration::from_secs(10),
+            idle_source_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+trait Operator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether state for an event at `ts` has already been closed.
+    fn is_late(&self, ts: i64, watermark: i64) -> bool;
+
+    fn process(
+        &self,
+        txn: &mut StateTxn<'_>,
+        record: Record,
+        ts: i64,
+        watermark: i64,
+        out: &mut TransformOutput,
+    ) -> Result<()>;
+
+    /// Closes everything that ends at or before `watermark`.
+    fn advance(&self, txn: &mut StateTxn<'_>, watermark: i64, out: &mut TransformOutput) -> Result<()>;
+
+    /// Emits early results on the periodic emit interval.
+    fn flush(&self, _txn: &mut StateTxn<'_>, _out: &mut TransformOutput) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What a batch run through an operator emitted. Emitted records are held
+/// in the operator's state under their `seq` until [`settle`] releases them.
+///
+/// [`settle`]: StatefulOperator::settle
+#[derive(Debug, Default)]
+pub struct OperatorOutput {
+    pub held: Vec<HeldRecord>,
+    /// Input records the operator rejected, with the reason.
+    pub failed: Vec<(Record, String)>,
+}
+
+#[derive(Debug)]
+pub struct HeldRecord {
+    pub seq: u64,
+    /// Named output the record goes to, or `None` for the default output.
+    pub output: Option<String>,
+    pub record: Record,
+}
+
+impl OperatorOutput {
+    fn into_transform_output(self) -> TransformOutput {
+        let mut out = TransformOutput {
+            failed: self.failed,
+            ..Default::default()
+        };
+        for held in self.held {
+            match &held.output {
+                Some(name) => out.route(name, held.record),
+                None => out.records.push(held.record),
+            }
+        }
+        out
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredOutput {
+    output: Option<String>,
+    record: Vec<u8>,
+}
+
+/// A compiled stateful `TransformConfigDsl` bound to one pipeline stage.
+pub struct StatefulOperator {
+    id: String,
+    pipeline_id: String,
+    stage_id: String,
+    operator: Box<dyn Operator>,
+    state: OperatorState,
+    options: OperatorOptions,
+    last_flush: Mutex<Instant>,
+    restored: OnceCell<()>,
+    /// Held records handed out and not yet settled, which later batches do
+    /// not emit again.
+    in_flight: Mutex<HashSet<u64>>,
+}
+
+impl StatefulOperator {
+    pub fn compile(
+        pipeline_id: &str,
+        stage_id: &str,
+        config: &TransformConfigDsl,
+        options: &TransformOptions,
+    ) -> Result<Self> {
+        let store = options
+            .state_store
+            .as_ref()
+            .context("stateful transforms require a state directory (CONVEYOR_STATE_DIR)")?;
+        let id = format!("{}/{}", pipeline_id, stage_id);
+        let codecs = options.codecs.clone();
+
+        let operator: Box<dyn Operator> = match config {
+            TransformConfigDsl::Aggregate(c) => Box::new(Aggregate::compile(&id, c, codecs)?),
+            TransformConfigDsl::Dedupe(c) => Box::new(Dedupe::compile(c, codecs)?),
+            TransformConfigDsl::Sessionize(c) => Box::new(Sessionize::compile(&id, c, codecs)?),
+            TransformConfigDsl::Join(c) => Box::new(Join::compile(&id, c, codecs)?),
+            TransformConfigDsl::RateLimit(_) => {
+                anyhow::bail!("ratelimit transforms are not supported by the sidecar")
+            }
+            _ => anyhow::bail!("stateless transforms run as a BuiltinTransform"),
+        };
+
+        Ok(Self {
+            state: store.namespace(&id),
+            id,
+            pipeline_id: pipeline_id.to_string(),
+            stage_id: stage_id.to_string(),
+            operator,
+            options: options.operators.clone(),
+            last_flush: Mutex::new(Instant::now()),
+            restored: OnceCell::new(),
+            in_flight: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.operator.name()
+    }
+
+    pub fn pipeline_id(&self) -> &str {
+        &self.pipeline_id
+    }
+
+    pub fn stage_id(&self) -> &str {
+        &self.stage_id
+    }
+
+    /// Runs a batch through the operator on the blocking pool, since it
+    /// does RocksDB I/O under the operator's state lock. What the operator
+    /// emits is committed with its state and held there until it is
+    /// settled; held records left unsettled by an earlier batch, or by a
+    /// sidecar that stopped before settling them, are emitted again first.
+    pub async fn apply(self: &Arc<Self>, batch: RecordBatch) -> Result<OperatorOutput> {
+        let now = chrono::Utc::now().timestamp_millis();
+        blocking(self, move |operator| operator.process_at(batch, now)).await
+    }
+
+    /// Releases held records once downstream stages are done with them:
+    /// those `done` leave the state, and those to `retry` are emitted again
+    /// with the next batch.
+    pub async fn settle(self: &Arc<Self>, done: Vec<u64>, retry: Vec<u64>) -> Result<()> {
+        blocking(self, move |operator| operator.release(&done, &retry)).await
+    }
+
+    /// Runs a batch through the operator and commits the resulting state in
+    /// one write. Records at or below the last sequence number committed for
+    /// their source partition are replays and are skipped, so a batch that
+    /// is redelivered after a crash or restore is not counted twice. Records
+    /// without a sequence number are never skipped, and records in one batch
+    /// may share one, as the elements split from a record do. Emitted
+    /// records are numbered in sequence under the operator's id. On error
+    /// nothing is committed and the whole batch can be retried.
+    pub fn process(&self, batch: RecordBatch) -> Result<TransformOutput> {
+        self.process_at(batch, chrono::Utc::now().timestamp_millis())
+            .map(OperatorOutput::into_transform_output)
+    }
+
+    fn process_at(&self, batch: RecordBatch, now: i64) -> Result<OperatorOutput> {
+        let mut txn = self.state.begin();
+        let committed: BTreeMap<(String, u32), u64> = txn.get(OFFSETS_KEY)?.unwrap_or_default();
+        let mut offsets = committed.clone();
+        let mut source_times: BTreeMap<String, i64> =
+            txn.get(SOURCE_TIMES_KEY)?.unwrap_or_default();
+        let mut source_seen: BTreeMap<String, i64> =
+            txn.get(SOURCE_SEEN_KEY)?.unwrap_or_default();
+        let watermark: i64 = txn.get(WATERMARK_KEY)?.unwrap_or(i64::MIN);
+
+        for source in source_times.keys() {
+            source_seen.entry(source.clone()).or_insert(now);
+        }
+
+        let mut out = TransformOutput::default();
+        for record in batch.records {
+            let source = match &record.id {
+                Some(id) if !id.source_id.is_empty() => {
+                    if id.sequence_number > 0 {
+                        let partition = (id.source_id.clone(), id.partition);
+                        if committed.get(&partition).is_some_and(|seen| id.sequence_number <= *seen) {
+                            continue;
+                        }
+                        let latest = offsets.entry(partition).or_default();
+                        *latest = (*latest).max(id.sequence_number);
+                    }
+                    id.source_id.clone()
+                }
+                _ => String::new(),
+            };
+
+            let ts = event_time(&record);
+            source_seen.insert(source.clone(), now);
+            let latest = source_times.entry(source).or_insert(ts);
+            *latest = (*latest).max(ts);
+
+            if self.operator.is_late(ts, watermark) {
+                out.route(LATE_OUTPUT, record);
+                continue;
+            }
+            self.operator.process(&mut txn, record, ts, watermark, &mut out)?;
+        }
+
+        if let Some(wm) = &batch.watermark {
+            if let Some(ts) = wm.timestamp.as_ref().and_then(timestamp_millis) {
+                source_seen.insert(wm.source_id.clone(), now);
+                let latest = source_times.entry(wm.source_id.clone()).or_insert(ts);
+                *latest = (*latest).max(ts);
+            }
+        }
+
+        let closing = active_watermark(
+            &source_times,
+            &source_seen,
+            now,
+            self.options.idle_source_timeout,
+        )
+        .map(|wm| wm.saturating_sub(self.options.allowed_lateness.as_millis() as i64));
+        if let Some(closing) = closing.filter(|closing| *closing > watermark) {
+            self.operator.advance(&mut txn, closing, &mut out)?;
+            txn.put(WATERMARK_KEY, &closing)?;
+        }
+
+        {
+            let mut last_flush = self.last_flush.lock().unwrap_or_else(|e| e.into_inner());
+            if last_flush.elapsed() >= self.options.emit_interval {
+                self.operator.flush(&mut txn, &mut out)?;
+                *last_flush = Instant::now();
+            }
+        }
+
+        let mut emitted: u64 = txn.get(EMITTED_KEY)?.unwrap_or_default();
+        let ids = out
+            .records
+            .iter_mut()
+            .chain(out.routed.values_mut().flatten())
+            .filter_map(|r| r.id.as_mut())
+            .filter(|id| id.source_id == self.id && id.sequence_number == 0);
+        for id in ids {
+            emitted += 1;
+            id.sequence_number = emitted;
+        }
+
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        let mut held = Vec::new();
+        for (key, stored) in txn.scan::<StoredOutput>(HELD_PREFIX)? {
+            let seq = held_seq(&key)?;
+            if in_flight.contains(&seq) {
+                continue;
+            }
+            let record = Record::decode(stored.record.as_slice()).context("Corrupt held record")?;
+            held.push(HeldRecord {
+                seq,
+                output: stored.output,
+                record,
+            });
+        }
+
+        let mut next: u64 = txn.get(HELD_SEQ_KEY)?.unwrap_or_default();
+        let routed = out
+            .routed
+            .into_iter()
+            .flat_map(|(name, records)| records.into_iter().map(move |r| (Some(name.clone()), r)));
+        for (output, record) in out.records.into_iter().map(|r| (None, r)).chain(routed) {
+            next += 1;
+            txn.put(
+                &held_key(next),
+                &StoredOutput {
+                    output: output.clone(),
+                    record: record.encode_to_vec(),
+                },
+            )?;
+            held.push(HeldRecord {
+                seq: next,
+                output,
+                record,
+            });
+        }
+
+        txn.put(HELD_SEQ_KEY, &next)?;
+        txn.put(EMITTED_KEY, &emitted)?;
+        txn.put(OFFSETS_KEY, &offsets)?;
+        txn.put(SOURCE_TIMES_KEY, &source_times)?;
+        txn.put(SOURCE_SEEN_KEY, &source_seen)?;
+        txn.commit()?;
+
+        in_flight.extend(held.iter().map(|h| h.seq));
+        Ok(OperatorOutput {
+            held,
+            failed: out.failed,
+        })
+    }
+
+    fn release(&self, done: &[u64], retry: &[u64]) -> Result<()> {
+        let mut txn = self.state.begin();
+        for seq in done {
+            txn.delete(&held_key(*seq));
+        }
+        txn.commit()?;
+
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        for seq in done.iter().chain(retry) {
+            in_flight.remove(seq);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn snapshot(&self) -> Result<Vec<u8>> {
+        self.state.snapshot()
+    }
+
+    /// Highest sequence number processed per source partition, as carried
+    /// in `SaveCheckpointRequest.source_offsets`.
+    pub(crate) fn source_offsets(&self) -> Result<HashMap<String, PartitionOffsets>> {
+        let txn = self.state.begin();
+        let offsets: BTreeMap<(String, u32), u64> = txn.get(OFFSETS_KEY)?.unwrap_or_default();
+
+        let mut by_source: HashMap<String, PartitionOffsets> = HashMap::new();
+        for ((source, partition), seq) in offsets {
+            by_source.entry(source).or_default().offsets.insert(partition, seq);
+        }
+        Ok(by_source)
+    }
+}
+
+impl fmt::Debug for StatefulOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatefulOperator")
+            .field("id", &self.id)
+            .field("operator", &self.name())
+            .finish()
+    }
+}
+
+/// Runs synchronous state store work for `operator` on the blocking pool,
+/// so neither the RocksDB I/O nor the state lock held across it stalls the
+/// async executor.
+async fn blocking<T, F>(operator: &Arc<StatefulOperator>, f: F) -> Result<T>
+where
+    F: FnOnce(&StatefulOperator) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let operator = operator.clone();
+    tokio::task::spawn_blocking(move || f(&operator))
+        .await
+        .context("State store task failed")?
+}
+
+/// The least of the latest event times of the sources heard from within
+/// `idle_timeout` of `now` (epoch millis), so a source that has gone quiet
+/// does not hold the others back. `None` when every source is idle.
+fn active_watermark(
+    source_times: &BTreeMap<String, i64>,
+    source_seen: &BTreeMap<String, i64>,
+    now: i64,
+    idle_timeout: Duration,
+) -> Option<i64> {
+    let idle_ms = idle_timeout.as_millis() as i64;
+    source_times
+        .iter()
+        .filter(|(source, _)| {
+            idle_ms == 0 || source_seen.get(*source).is_some_and(|seen| now - seen < idle_ms)
+        })
+        .map(|(_, ts)| *ts)
+        .min()
+}
+
+fn held_key(seq: u64) -> Vec<u8> {
+    [HELD_PREFIX, &seq.to_be_bytes()].concat()
+}
+
+fn held_seq(key: &[u8]) -> Result<u64> {
+    key.strip_prefix(HELD_PREFIX)
+        .and_then(|seq| seq.try_into().ok())
+        .map(u64::from_be_bytes)
+        .context("Corrupt held record key")
+}
+
+/// Whether a transform config needs a `StatefulOperator`.
+pub fn is_stateful(config: &TransformConfigDsl) -> bool {
+    config.is_stateful()
+}
+
+/// Event time of a record in epoch millis, or the current time when the
+/// record has none.
+fn event_time(record: &Record) -> i64 {
+    record
+        .event_time
+        .as_ref()
+        .and_then(timestamp_millis)
+        .unwrap_or_else(|| chrono::Utc::now().timestamp_millis())
+}
+
+fn timestamp(millis: i64) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: millis.div_euclid(1000),
+        nanos: (millis.rem_euclid(1000) * 1_000_000) as i32,
+    }
+}
+
+fn rfc3339(millis: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(millis)
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+        .unwrap_or_else(|| millis.to_string())
+}
+
+/// Builds a record emitted by an operator. `key` identifies the result, so
+/// a sink can upsert early and final results for the same window; the
+/// record is given its own sequence number when the batch commits.
+fn emit(
+    codecs: &CodecRegistry,
+    operator_id: &str,
+    record_type: &str,
+    key: &str,
+    event_time: i64,
+    value: &Value,
+) -> Result<Record> {
+    let mut record = Record {
+        id: Some(RecordId {
+            source_id: operator_id.to_string(),
+            idempotency_key: key.as_bytes().to_vec(),
+            ..Default::default()
+        }),
+        record_type: record_type.to_string(),
+        event_time: Some(timestamp(event_time)),
+        ..Default::default()
+    };
+    codecs.encode(&mut record, value)?;
+    Ok(record)
+}
+
+/// The JSON text of the values at `fields`, used as a state key.
+fn group_key(values: &[Value]) -> String {
+    Value::Array(values.to_vec()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use conveyor_etl_proto::common::Watermark;
+
+    use super::*;
+    use crate::transforms::{BuiltinTransform, TransformOptions};
+
+    pub(super) struct Harness {
+        _dir: tempfile::TempDir,
+        pub options: TransformOptions,
+    }
+
+    impl Harness {
+        pub fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let store = StateStore::open(dir.path()).unwrap();
+            Self {
+                _dir: dir,
+                options: TransformOptions {
+                    state_store: Some(Arc::new(store)),
+                    ..Default::default()
+                },
+            }
+        }
+
+        pub fn compile(&self, config: Value) -> StatefulOperator {
+            let config: TransformConfigDsl = serde_json::from_value(config).unwrap();
+            StatefulOperator::compile("p", "stage", &config, &self.options).unwrap()
+        }
+    }
+
+    /// A record from source `src` partition 0 with the given sequence
+    /// number and event time in millis.
+    pub(super) fn event(seq: u64, ts: i64, payload: Value) -> Record {
+        Record {
+            id: Some(RecordId {
+                source_id: "src".to_string(),
+                sequence_number: seq,
+                ..Default::default()
+            }),
+            record_type: "event".to_string(),
+            payload: serde_json::to_vec(&payload).unwrap().into(),
+            event_time: Some(timestamp(ts)),
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn batch(records: Vec<Record>) -> RecordBatch {
+        RecordBatch {
+            batch_id: "b".to_string(),
+            records,
+            watermark: None,
+        }
+    }
+
+    pub(super) fn payloads(records: &[Record]) -> Vec<Value> {
+        records
+            .iter()
+            .map(|r| serde_json::from_slice(&r.payload).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_requires_state_store() {
+        let config: TransformConfigDsl = serde_json::from_value(json!({
+            "transform_type": "dedupe",
+            "key_fields": ["id"],
+            "window": {"tumbling": {"seconds": 1}}
+        }))
+        .unwrap();
+        assert!(StatefulOperator::compile("p", "s", &config, &TransformOptions::default()).is_err());
+        assert!(is_stateful(&config));
+    }
+
+    #[test]
+    fn test_late_records_are_routed() {
+        let harness = Harness::new();
+        let op = harness.compile(json!({
+            "transform_type": "aggregate",
+            "group_by": [],
+            "window": {"tumbling": {"seconds": 10}},
+            "aggregations": [{"field": "*", "function": "count"}]
+        }));
+
+        op.process(batch(vec![event(1, 25_000, json!({}))])).unwrap();
+        let out = op.process(batch(vec![event(2, 5_000, json!({}))])).unwrap();
+
+        assert!(out.records.is_empty());
+        assert_eq!(out.routed[LATE_OUTPUT].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_held_outputs_are_emitted_until_settled() {
+        let harness = Harness::new();
+        let config = json!({
+            "transform_type": "dedupe",
+            "key_fields": ["id"],
+            "window": {"tumbling": {"seconds": 10}}
+        });
+        let op = Arc::new(harness.compile(config.clone()));
+
+        let out = op.apply(batch(vec![event(1, 1_000, json!({"id": 1}))])).await.unwrap();
+        assert_eq!(out.held.len(), 1);
+        let seq = out.held[0].seq;
+        assert!(op.apply(batch(vec![])).await.unwrap().held.is_empty(), "still in flight");
+
+        op.settle(Vec::new(), vec![seq]).await.unwrap();
+        let out = op.apply(batch(vec![])).await.unwrap();
+        assert_eq!(out.held.iter().map(|h| h.seq).collect::<Vec<_>>(), vec![seq]);
+        assert_eq!(payloads(&[out.held[0].record.clone()]), vec![json!({"id": 1})]);
+
+        op.settle(vec![seq], Vec::new()).await.unwrap();
+        op.apply(batch(vec![event(2, 2_000, json!({"id": 2}))])).await.unwrap();
+
+        // Restarted before settling, the operator emits the record again
+        let restarted = Arc::new(harness.compile(config));
+        let out = restarted.apply(batch(vec![])).await.unwrap();
+        assert_eq!(payloads(&[out.held[0].record.clone()]), vec![json!({"id": 2})]);
+        assert_eq!(out.held.len(), 1);
+    }
+
+    #[test]
+    fn test_idle_source_stops_holding_back_watermark() {
+        let mut harness = Harness::new();
+        harness.options.operators.idle_source_timeout = Duration::from_secs(60);
+        let op = harness.compile(json!({
+            "transform_type": "aggregate",
+            "group_by": [],
+            "window": {"tumbling": {"seconds": 10}},
+            "aggregations": [{"field": "*", "function": "count"}]
+        }));
+        let from = |source: &str, seq: u64, ts: i64| {
+            let mut record = event(seq, ts, json!({}));
+            record.id.as_mut().unwrap().source_id = source.to_string();
+            record
+        };
+
+        op.process_at(batch(vec![from("slow", 1, 1_000), from("fast", 1, 2_000)]), 0)
+            .unwrap();
+        let out = op.process_at(batch(vec![from("fast", 2, 15_000)]), 30_000)
+            .unwrap()
+            .into_transform_output();
+        assert!(out.records.is_empty(), "the slow source is not idle yet");
+
+        let out = op.process_at(batch(vec![from("fast", 3, 16_000)]), 61_000)
+            .unwrap()
+            .into_transform_output();
+        let results = payloads(&out.records);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["count"], json!(2));
+        assert_eq!(results[0]["window_start"], json!("1970-01-01T00:00:00.000Z"));
+    }
+
+    fn count_by_user(harness: &Harness, stage_id: &str, window: Value) -> StatefulOperator {
+        let config: TransformConfigDsl = serde_json::from_value(json!({
+            "transform_type": "aggregate",
+            "group_by": ["user"],
+            "window": window,
+            "aggregations": [{"field": "*", "function": "count"}]
+        }))
+        .unwrap();
+        StatefulOperator::compile("p", stage_id, &config, &harness.options).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_flat_map_elements_are_all_aggregated() {
+        let harness = Harness::new();
+        let flat_map: TransformConfigDsl =
+            serde_json::from_value(json!({"transform_type": "flatmap", "field": "items"})).unwrap();
+        let flat_map = BuiltinTransform::compile(&flat_map, &harness.options).unwrap();
+        let op = count_by_user(&harness, "count", json!({"tumbling": {"seconds": 10}}));
+
+        let orders = vec![
+            event(1, 1_000, json!({"items": [{"user": "a"}, {"user": "a"}, {"user": "b"}]})),
+            event(2, 2_000, json!({"items": [{"user": "a"}]})),
+        ];
+        let elements = flat_map.apply(orders).await.records;
+        assert_eq!(elements.len(), 4);
+        let keys: std::collections::HashSet<_> =
+            elements.iter().map(|r| r.id.as_ref().unwrap().idempotency_key.clone()).collect();
+        assert_eq!(keys.len(), 4, "every element has its own id");
+
+        op.process(batch(elements.clone())).unwrap();
+        // A redelivered batch is still recognised as a replay
+        op.process(batch(elements)).unwrap();
+        let closing = flat_map
+            .apply(vec![event(3, 25_000, json!({"items": [{"user": "c"}]}))])
+            .await
+            .records;
+        let out = op.process(batch(closing)).unwrap();
+
+        let counts: Vec<_> = payloads(&out.records)
+            .iter()
+            .map(|r| (r["user"].clone(), r["count"].clone()))
+            .collect();
+        assert_eq!(counts, vec![(json!("a"), json!(3)), (json!("b"), json!(1))]);
+    }
+
+    #[test]
+    fn test_aggregate_results_feed_another_aggregate() {
+        let harness = Harness::new();
+        let per_user = count_by_user(&harness, "per-user", json!({"tumbling": {"seconds": 10}}));
+        let total: TransformConfigDsl = serde_json::from_value(json!({
+            "transform_type": "aggregate",
+            "group_by": [],
+            "window": {"tumbling": {"minutes": 1}},
+            "aggregations": [{"field": "count", "function": "sum", "output_field": "total"}]
+        }))
+        .unwrap();
+        let total = StatefulOperator::compile("p", "total", &total, &harness.options).unwrap();
+
+        let first = per_user
+            .process(batch(vec![
+                event(1, 1_000, json!({"user": "a"})),
+                event(2, 2_000, json!({"user": "b"})),
+                event(3, 3_000, json!({"user": "b"})),
+                event(4, 4_000, json!({"user": "c"})),
+                event(5, 25_000, json!({"user": "d"})),
+            ]))
+            .unwrap();
+        assert_eq!(first.records.len(), 3);
+        let seqs: Vec<_> = first.records.iter().map(|r| r.id.as_ref().unwrap().sequence_number).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+
+        let second = per_user
+            .process(batch(vec![event(6, 45_000, json!({"user": "e"}))]))
+            .unwrap();
+        assert_eq!(second.records.len(), 1);
+        assert_eq!(second.records[0].id.as_ref().unwrap().sequence_number, 4);
+
+        total.process(batch(first.records)).unwrap();
+        let out = total
+            .process(RecordBatch {
+                batch_id: "b".to_string(),
+                records: second.records,
+                watermark: Some(Watermark {
+                    source_id: "p/per-user".to_string(),
+                    timestamp: Some(timestamp(120_000)),
+                    ..Default::default()
+                }),
+            })
+            .unwrap();
+
+        // a, b, c and d: every per-user result is summed
+        let results = payloads(&out.records);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["total"], json!(5));
+    }
+
+    #[test]
+    fn test_restore_does_not_double_count() {
+        let config = json!({
+            "transform_type": "aggregate",
+            "group_by": ["user"],
+            "window": {"tumbling": {"seconds": 10}},
+            "aggregations": [{"field": "*", "function": "count"}]
+        });
+
+        let first = Harness::new();
+        let op = first.compile(config.clone());
+        let early = vec![event(1, 1_000, json!({"user": "a"})), event(2, 2_000, json!({"user": "a"}))];
+        op.process(batch(early.clone())).unwrap();
+        let snapshot = op.snapshot().unwrap();
+
+        let second = Harness::new();
+        let op = second.compile(config);
+        op.state.restore(&snapshot).unwrap();
+
+        let mut replay = early;
+        replay.push(event(3, 3_000, json!({"user": "a"})));
+        replay.push(event(4, 12_000, json!({"user": "b"})));
+        let out = op.process(batch(replay)).unwrap();
+
+        assert_eq!(
+            payloads(&out.records),
+            vec![json!({
+                "user": "a",
+                "window_start": "1970-01-01T00:00:00.000Z",
+                "window_end": "1970-01-01T00:00:10.000Z",
+                "count": 3
+            })]
+        );
+        let offsets = op.source_offsets().unwrap();
+        assert_eq!(offsets["src"].offsets[&0], 4);
+    }
+}
@@ -0,0 +1,236 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use conveyor_etl_dsl::{SessionEmit, SessionizeTransformConfig};
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_routing::{CodecRegistry, FieldPath};
+
+use super::state::StateTxn;
+use super::window::{duration_ms, Session, SessionWindows};
+use super::{emit, rfc3339, Operator};
+use crate::transforms::{decode, TransformOutput};
+
+/// Metadata keys set on records passed through by `on_event` and `both`.
+pub const SESSION_ID_KEY: &str = "_session_id";
+pub const SESSION_START_KEY: &str = "_session_start";
+
+pub(super) struct Sessionize {
+    id: String,
+    key_field: FieldPath,
+    sessions: SessionWindows,
+    emit: SessionEmit,
+    codecs: Arc<CodecRegistry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SessionInfo {
+    /// JSON text of the key value.
+    key: String,
+    record_type: String,
+    count: u64,
+}
+
+impl Sessionize {
+    pub(super) fn compile(
+        id: &str,
+        config: &SessionizeTransformConfig,
+        codecs: Arc<CodecRegistry>,
+    ) -> Result<Self> {
+        let gap = duration_ms(&config.gap)?;
+        let max_duration = config.max_duration.as_ref().map(duration_ms).transpose()?;
+        if max_duration.is_some_and(|max| max < gap) {
+            anyhow::bail!("session max_duration is shorter than its gap");
+        }
+
+        Ok(Self {
+            id: id.to_string(),
+            key_field: FieldPath::parse(&config.key_field).map_err(|e| anyhow!("{}", e))?,
+            sessions: SessionWindows { gap, max_duration },
+            emit: config.emit,
+            codecs,
+        })
+    }
+
+    fn summary(&self, group: &str, session: &Session<SessionInfo>) -> Result<Record> {
+        let session_id = session_id(group, session.start);
+        let mut payload = json!({
+            "session_id": session_id,
+            "session_start": rfc3339(session.start),
+            "session_end": rfc3339(session.last),
+            "event_count": session.state.count,
+            "duration_ms": session.last - session.start,
+        });
+        self.key_field
+            .set(&mut payload, serde_json::from_str(&session.state.key)?)?;
+
+        emit(
+            &self.codecs,
+            &self.id,
+            &session.state.record_type,
+            &session_id,
+            session.last,
+            &payload,
+        )
+    }
+}
+
+impl Operator for Sessionize {
+    fn name(&self) -> &'static str {
+        "sessionize"
+    }
+
+    fn is_late(&self, ts: i64, watermark: i64) -> bool {
+        ts.saturating_add(self.sessions.gap) <= watermark
+    }
+
+    fn process(
+        &self,
+        txn: &mut StateTxn<'_>,
+        mut record: Record,
+        ts: i64,
+        _watermark: i64,
+        out: &mut TransformOutput,
+    ) -> Result<()> {
+        let value = match decode(&self.codecs, &record) {
+            Ok(value) => value,
+            Err(e) => {
+                out.fail(record, e);
+                return Ok(());
+            }
+        };
+        let Some(key) = self.key_field.get(&value).filter(|v| !v.is_null()) else {
+            out.fail(record, format!("missing session key {}", self.key_field));
+            return Ok(());
+        };
+        let group = match key {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+
+        let session = self.sessions.add(
+            txn,
+            &group,
+            ts,
+            || SessionInfo {
+                key: key.to_string(),
+                record_type: record.record_type.clone(),
+                count: 0,
+            },
+            |info, other| info.count += other.count,
+            |info| info.count += 1,
+        )?;
+
+        if self.emit != SessionEmit::OnClose {
+            record
+                .metadata
+                .insert(SESSION_ID_KEY.to_string(), session_id(&group, session.start));
+            record
+                .metadata
+                .insert(SESSION_START_KEY.to_string(), rfc3339(session.start));
+            out.records.push(record);
+        }
+        Ok(())
+    }
+
+    fn advance(&self, txn: &mut StateTxn<'_>, watermark: i64, out: &mut TransformOutput) -> Result<()> {
+        let closed = self.sessions.close::<SessionInfo>(txn, watermark)?;
+        if self.emit == SessionEmit::OnEvent {
+            return Ok(());
+        }
+        for (group, session) in closed {
+            out.records.push(self.summary(&group, &session)?);
+        }
+        Ok(())
+    }
+}
+
+fn session_id(group: &str, start: i64) -> String {
+    format!("{}:{}", group, start)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::tests::{batch, event, payloads, Harness};
+    use super::{SESSION_ID_KEY, SESSION_START_KEY};
+
+    #[test]
+    fn test_sessions_close_after_gap() {
+        let harness = Harness::new();
+        let op = harness.compile(json!({
+            "transform_type": "sessionize",
+            "key_field": "user",
+            "gap": {"seconds": 30}
+        }));
+
+        let out = op
+            .process(batch(vec![
+                event(1, 0, json!({"user": "a"})),
+                event(2, 20_000, json!({"user": "a"})),
+                event(3, 25_000, json!({"user": "b"})),
+                event(4, 40_000, json!({"user": "a"})),
+            ]))
+            .unwrap();
+        assert!(out.records.is_empty());
+
+        let out = op.process(batch(vec![event(5, 100_000, json!({"user": "c"}))])).unwrap();
+        assert_eq!(
+            payloads(&out.records),
+            vec![
+                json!({
+                    "user": "a",
+                    "session_id": "a:0",
+                    "session_start": "1970-01-01T00:00:00.000Z",
+                    "session_end": "1970-01-01T00:00:40.000Z",
+                    "event_count": 3,
+                    "duration_ms": 40_000
+                }),
+                json!({
+                    "user": "b",
+                    "session_id": "b:25000",
+                    "session_start": "1970-01-01T00:00:25.000Z",
+                    "session_end": "1970-01-01T00:00:25.000Z",
+                    "event_count": 1,
+                    "duration_ms": 0
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_duration_and_on_event() {
+        let harness = Harness::new();
+        let op = harness.compile(json!({
+            "transform_type": "sessionize",
+            "key_field": "user",
+            "gap": {"seconds": 10},
+            "max_duration": {"seconds": 15},
+            "emit": "both"
+        }));
+
+        let out = op
+            .process(batch(vec![
+                event(1, 0, json!({"user": "a"})),
+                event(2, 8_000, json!({"user": "a"})),
+                event(3, 16_000, json!({"user": "a"})),
+                event(4, 1_000, json!({"user": "b"})),
+                event(5, 2_000, json!({})),
+            ]))
+            .unwrap();
+
+        let ids: Vec<_> = out.records[..4].iter().map(|r| r.metadata[SESSION_ID_KEY].as_str()).collect();
+        assert_eq!(ids, vec!["a:0", "a:0", "a:16000", "b:1000"]);
+        assert_eq!(out.records[0].metadata[SESSION_START_KEY], "1970-01-01T00:00:00.000Z");
+        assert_eq!(out.failed.len(), 1);
+
+        let summaries = payloads(&out.records[4..]);
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0]["session_id"], "a:0");
+        assert_eq!(summaries[0]["event_count"], 2);
+        assert_eq!(summaries[1]["session_id"], "b:1000");
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use rocksdb::{Direction, IteratorMode, Options, WriteBatch, DB};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Embedded RocksDB store holding the keyed state of every stateful operator
+/// in this sidecar. Each operator gets its own key namespace.
+pub struct StateStore {
+    db: Arc<DB>,
+    locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+impl StateStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        let db = DB::open(&options, path)
+            .with_context(|| format!("Failed to open state store at {}", path.display()))?;
+
+        Ok(Self {
+            db: Arc::new(db),
+            locks: DashMap::new(),
+        })
+    }
+
+    /// State of the operator with the given id. Handles for the same id share
+    /// a lock, so an operator recompiled from a new assignment does not race
+    /// the one it replaces.
+    pub(crate) fn namespace(&self, operator_id: &str) -> OperatorState {
+        let mut prefix = operator_id.as_bytes().to_vec();
+        prefix.push(0);
+
+        OperatorState {
+            db: self.db.clone(),
+            prefix,
+            lock: self.locks.entry(operator_id.to_string()).or_default().clone(),
+        }
+    }
+}
+
+pub(crate) struct OperatorState {
+    db: Arc<DB>,
+    prefix: Vec<u8>,
+    lock: Arc<Mutex<()>>,
+}
+
+impl OperatorState {
+    /// Starts a transaction. Writes are buffered until `commit` and applied
+    /// atomically; other transactions on this namespace wait until then.
+    pub(crate) fn begin(&self) -> StateTxn<'_> {
+        StateTxn {
+            state: self,
+            _guard: self.lock.lock().unwrap_or_else(|e| e.into_inner()),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> Result<bool> {
+        Ok(self.raw_scan(&[])?.is_empty())
+    }
+
+    /// Every key and value in the namespace, serialized for a checkpoint.
+    pub(crate) fn snapshot(&self) -> Result<Vec<u8>> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let entries = self.raw_scan(&[])?;
+        bincode::serialize(&entries).context("Failed to serialize operator state")
+    }
+
+    /// Replaces the namespace's contents with a `snapshot`.
+    pub(crate) fn restore(&self, snapshot: &[u8]) -> Result<()> {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            bincode::deserialize(snapshot).context("Invalid operator state snapshot")?;
+
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut batch = WriteBatch::default();
+        for (key, _) in self.raw_scan(&[])? {
+            batch.delete(self.full_key(&key));
+        }
+        for (key, value) in entries {
+            batch.put(self.full_key(&key), value);
+        }
+        self.db.write(batch).context("Failed to restore operator state")
+    }
+
+    fn full_key(&self, key: &[u8]) -> Vec<u8> {
+        [self.prefix.as_slice(), key].concat()
+    }
+
+    /// Committed entries whose key starts with `prefix`, with the namespace
+    /// stripped from the keys.
+    fn raw_scan(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let start = self.full_key(prefix);
+        let mut entries = Vec::new();
+        for item in self.db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+            let (key, value) = item.map_err(|e| anyhow!("State store read failed: {}", e))?;
+            if !key.starts_with(&start) {
+                break;
+            }
+            entries.push((key[self.prefix.len()..].to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+}
+
+pub(crate) struct StateTxn<'a> {
+    state: &'a OperatorState,
+    _guard: MutexGuard<'a, ()>,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl StateTxn<'_> {
+    pub(crate) fn get<T: DeserializeOwned>(&self, key: &[u8]) -> Result<Option<T>> {
+        let bytes = match self.writes.get(key) {
+            Some(pending) => pending.clone(),
+            None => self
+                .state
+                .db
+                .get(self.state.full_key(key))
+                .map_err(|e| anyhow!("State store read failed: {}", e))?,
+        };
+        bytes.map(|b| decode(key, &b)).transpose()
+    }
+
+    pub(crate) fn put<T: Serialize>(&mut self, key: &[u8], value: &T) -> Result<()> {
+        let bytes = bincode::serialize(value).context("Failed to serialize operator state")?;
+        self.writes.insert(key.to_vec(), Some(bytes));
+        Ok(())
+    }
+
+    pub(crate) fn delete(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    /// Entries whose key starts with `prefix`, in key order, including
+    /// uncommitted writes.
+    pub(crate) fn scan<T: DeserializeOwned>(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, T)>> {
+        self.scan_until(prefix, None)
+    }
+
+    /// Like `scan`, stopping before the first key at or past `prefix + end`.
+    pub(crate) fn scan_until<T: DeserializeOwned>(
+        &self,
+        prefix: &[u8],
+        end: Option<&[u8]>,
+    ) -> Result<Vec<(Vec<u8>, T)>> {
+        let mut merged: BTreeMap<Vec<u8>, Vec<u8>> =
+            self.state.raw_scan(prefix)?.into_iter().collect();
+        for (key, value) in self.writes.range(prefix.to_vec()..) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            match value {
+                Some(value) => merged.insert(key.clone(), value.clone()),
+                None => merged.remove(key),
+            };
+        }
+
+        let end = end.map(|end| [prefix, end].concat());
+        merged
+            .into_iter()
+            .take_while(|(key, _)| end.as_ref().is_none_or(|end| key < end))
+            .map(|(key, value)| {
+                let value = decode(&key, &value)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    pub(crate) fn commit(self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::default();
+        for (key, value) in &self.writes {
+            let key = self.state.full_key(key);
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+        self.state
+            .db
+            .write(batch)
+            .map_err(|e| anyhow!("State store write failed: {}", e))
+    }
+}
+
+fn decode<T: DeserializeOwned>(key: &[u8], bytes: &[u8]) -> Result<T> {
+    bincode::deserialize(bytes)
+        .with_context(|| format!("Corrupt operator state at {}", String::from_utf8_lossy(key)))
+}
+
+/// Big-endian encoding of an epoch-millis timestamp that sorts the same way
+/// as the timestamps, negative ones included.
+pub(crate) fn ts_key(ts: i64) -> [u8; 8] {
+    ((ts as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+pub(crate) fn ts_from_key(bytes: &[u8]) -> i64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    (u64::from_be_bytes(buf) ^ (1 << 63)) as i64
+}
+
+/// Joins key parts into a state key.
+pub(crate) fn key(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_txn_overlay_and_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::open(dir.path()).unwrap();
+        let state = store.namespace("p/agg");
+        let other = store.namespace("p/agg2");
+
+        let mut txn = state.begin();
+        txn.put(b"a1", &1u64).unwrap();
+        txn.put(b"a2", &2u64).unwrap();
+        txn.put(b"b1", &3u64).unwrap();
+        assert_eq!(txn.get::<u64>(b"a2").unwrap(), Some(2));
+        txn.commit().unwrap();
+
+        let mut txn = state.begin();
+        txn.delete(b"a1");
+        txn.put(b"a3", &4u64).unwrap();
+        let scanned: Vec<_> = txn.scan::<u64>(b"a").unwrap().into_iter().map(|(_, v)| v).collect();
+        assert_eq!(scanned, vec![2, 4]);
+        drop(txn);
+
+        let txn = state.begin();
+        assert_eq!(txn.get::<u64>(b"a1").unwrap(), Some(1));
+        assert_eq!(txn.get::<u64>(b"a3").unwrap(), None);
+        drop(txn);
+
+        assert!(other.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::open(dir.path()).unwrap();
+        let state = store.namespace("p/dedupe");
+
+        let mut txn = state.begin();
+        txn.put(b"k", &"v".to_string()).unwrap();
+        txn.commit().unwrap();
+        let snapshot = state.snapshot().unwrap();
+
+        let mut txn = state.begin();
+        txn.delete(b"k");
+        txn.put(b"stale", &1u8).unwrap();
+        txn.commit().unwrap();
+
+        state.restore(&snapshot).unwrap();
+        let txn = state.begin();
+        assert_eq!(txn.get::<String>(b"k").unwrap().as_deref(), Some("v"));
+        assert_eq!(txn.get::<u8>(b"stale").unwrap(), None);
+    }
+
+    #[test]
+    fn test_ts_key_ordering() {
+        let keys: Vec<_> = [-5i64, -1, 0, 3, i64::MAX].iter().map(|t| ts_key(*t)).collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(ts_from_key(&ts_key(-42)), -42);
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use conveyor_etl_dsl::{DurationConfig, WindowConfig};
+
+use super::state::{key, ts_key, StateTxn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WindowKind {
+    Tumbling { size: i64 },
+    Sliding { size: i64, slide: i64 },
+    Session { gap: i64 },
+}
+
+impl WindowKind {
+    pub(crate) fn from_config(config: &WindowConfig) -> Result<Self> {
+        match (&config.tumbling, &config.sliding, &config.session) {
+            (Some(size), None, None) => Ok(Self::Tumbling { size: duration_ms(size)? }),
+            (None, Some(sliding), None) => {
+                let size = duration_ms(&sliding.size)?;
+                let slide = duration_ms(&sliding.slide)?;
+                if slide > size {
+                    anyhow::bail!("window slide ({}ms) is larger than its size ({}ms)", slide, size);
+                }
+                Ok(Self::Sliding { size, slide })
+            }
+            (None, None, Some(gap)) => Ok(Self::Session { gap: duration_ms(gap)? }),
+            _ => anyhow::bail!("window must set exactly one of tumbling, sliding or session"),
+        }
+    }
+
+    /// `[start, end)` bounds of the fixed windows containing `ts`. Session
+    /// windows depend on other events and have none.
+    pub(crate) fn assign(&self, ts: i64) -> Vec<(i64, i64)> {
+        match *self {
+            Self::Tumbling { size } => {
+                let start = ts.div_euclid(size) * size;
+                vec![(start, start + size)]
+            }
+            Self::Sliding { size, slide } => {
+                let mut start = ts.div_euclid(slide) * slide;
+                let mut windows = Vec::new();
+                while start > ts - size {
+                    windows.push((start, start + size));
+                    start -= slide;
+                }
+                windows.reverse();
+                windows
+            }
+            Self::Session { .. } => Vec::new(),
+        }
+    }
+
+    /// How long after `ts` state for an event can still change.
+    pub(crate) fn span(&self) -> i64 {
+        match *self {
+            Self::Tumbling { size } | Self::Sliding { size, .. } => size,
+            Self::Session { gap } => gap,
+        }
+    }
+}
+
+pub(crate) fn duration_ms(config: &DurationConfig) -> Result<i64> {
+    let seconds = config.seconds.unwrap_or(0)
+        + config.minutes.unwrap_or(0) * 60
+        + config.hours.unwrap_or(0) * 3600;
+    if seconds == 0 {
+        anyhow::bail!("duration must be positive");
+    }
+    Ok(seconds as i64 * 1000)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Session<T> {
+    pub start: i64,
+    pub last: i64,
+    pub state: T,
+}
+
+/// Per-key session windows kept under `s<key>`, with an `x<close><key>`
+/// index so closing only visits keys whose sessions are due.
+pub(crate) struct SessionWindows {
+    pub gap: i64,
+    pub max_duration: Option<i64>,
+}
+
+impl SessionWindows {
+    /// When a session closes: `gap` after its last event, or when it reaches
+    /// `max_duration`.
+    pub(crate) fn close_at<T>(&self, session: &Session<T>) -> i64 {
+        let idle = session.last + self.gap;
+        match self.max_duration {
+            Some(max) => idle.min(session.start + max),
+            None => idle,
+        }
+    }
+
+    /// Adds an event at `ts` to `key`'s sessions, merging every session it
+    /// bridges, and returns the session it landed in after `update` ran.
+    pub(crate) fn add<T>(
+        &self,
+        txn: &mut StateTxn<'_>,
+        group: &str,
+        ts: i64,
+        init: impl FnOnce() -> T,
+        merge: impl Fn(&mut T, T),
+        update: impl FnOnce(&mut T),
+    ) -> Result<Session<T>>
+    where
+        T: Clone + Serialize + DeserializeOwned,
+    {
+        let mut sessions = self.load::<T>(txn, group)?;
+        self.unindex(txn, group, &sessions);
+
+        let mut current = Session {
+            start: ts,
+            last: ts,
+            state: init(),
+        };
+        let mut kept = Vec::with_capacity(sessions.len());
+        sessions.sort_by_key(|s| s.start);
+        for session in sessions {
+            let overlaps = ts < session.last + self.gap && session.start < ts + self.gap;
+            let start = current.start.min(session.start);
+            let last = current.last.max(session.last);
+            let fits = self.max_duration.is_none_or(|max| last - start < max);
+            if overlaps && fits {
+                current.start = start;
+                current.last = last;
+                merge(&mut current.state, session.state);
+            } else {
+                kept.push(session);
+            }
+        }
+        update(&mut current.state);
+        kept.push(current.clone());
+
+        self.store(txn, group, kept)?;
+        Ok(current)
+    }
+
+    /// Removes and returns every session that closes at or before
+    /// `watermark`, with its key.
+    pub(crate) fn close<T>(
+        &self,
+        txn: &mut StateTxn<'_>,
+        watermark: i64,
+    ) -> Result<Vec<(String, Session<T>)>>
+    where
+        T: Clone + Serialize + DeserializeOwned,
+    {
+        let due = txn.scan_until::<()>(b"x", Some(&ts_key(watermark.saturating_add(1))))?;
+        let mut groups: Vec<String> = due
+            .into_iter()
+            .map(|(k, ())| String::from_utf8_lossy(&k[9..]).into_owned())
+            .collect();
+        groups.sort();
+        groups.dedup();
+
+        let mut closed = Vec::new();
+        for group in groups {
+            let sessions = self.load::<T>(txn, &group)?;
+            self.unindex(txn, &group, &sessions);
+            let (done, open): (Vec<_>, Vec<_>) = sessions
+                .into_iter()
+                .partition(|s| self.close_at(s) <= watermark);
+            self.store(txn, &group, open)?;
+            closed.extend(done.into_iter().map(|s| (group.clone(), s)));
+        }
+        closed.sort_by_key(|(_, s)| s.start);
+        Ok(closed)
+    }
+
+    /// Every open session, for periodic emission.
+    pub(crate) fn all<T: DeserializeOwned>(
+        &self,
+        txn: &StateTxn<'_>,
+    ) -> Result<Vec<(String, Session<T>)>> {
+        let mut all = Vec::new();
+        for (k, sessions) in txn.scan::<Vec<Session<T>>>(b"s")? {
+            let group = String::from_utf8_lossy(&k[1..]).into_owned();
+            all.extend(sessions.into_iter().map(|s| (group.clone(), s)));
+        }
+        Ok(all)
+    }
+
+    fn load<T: DeserializeOwned>(&self, txn: &StateTxn<'_>, group: &str) -> Result<Vec<Session<T>>> {
+        Ok(txn
+            .get(&key(&[b"s", group.as_bytes()]))?
+            .unwrap_or_default())
+    }
+
+    fn store<T: Serialize>(
+        &self,
+        txn: &mut StateTxn<'_>,
+        group: &str,
+        sessions: Vec<Session<T>>,
+    ) -> Result<()> {
+        let state_key = key(&[b"s", group.as_bytes()]);
+        if sessions.is_empty() {
+            txn.delete(&state_key);
+            return Ok(());
+        }
+        for session in &sessions {
+            txn.put(&index_key(self.close_at(session), group), &())?;
+        }
+        txn.put(&state_key, &sessions)
+    }
+
+    fn unindex<T>(&self, txn: &mut StateTxn<'_>, group: &str, sessions: &[Session<T>]) {
+        for session in sessions {
+            txn.delete(&index_key(self.close_at(session), group));
+        }
+    }
+}
+
+fn index_key(close_at: i64, group: &str) -> Vec<u8> {
+    key(&[b"x", &ts_key(close_at), group.as_bytes()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conveyor_etl_dsl::SlidingWindowConfig;
+
+    fn seconds(s: u64) -> DurationConfig {
+        DurationConfig {
+            seconds: Some(s),
+            minutes: None,
+            hours: None,
+        }
+    }
+
+    #[test]
+    fn test_window_assignment() {
+        let tumbling = WindowKind::Tumbling { size: 10 };
+        assert_eq!(tumbling.assign(15), vec![(10, 20)]);
+        assert_eq!(tumbling.assign(-1), vec![(-10, 0)]);
+
+        let sliding = WindowKind::Sliding { size: 10, slide: 5 };
+        assert_eq!(sliding.assign(12), vec![(5, 15), (10, 20)]);
+        assert_eq!(sliding.assign(10), vec![(5, 15), (10, 20)]);
+    }
+
+    #[test]
+    fn test_window_config() {
+        let config = WindowConfig {
+            tumbling: None,
+            sliding: Some(SlidingWindowConfig {
+                size: seconds(60),
+                slide: seconds(10),
+            }),
+            session: None,
+        };
+        assert_eq!(
+            WindowKind::from_config(&config).unwrap(),
+            WindowKind::Sliding { size: 60_000, slide: 10_000 }
+        );
+
+        let both = WindowConfig {
+            tumbling: Some(seconds(1)),
+            session: Some(seconds(1)),
+            sliding: None,
+        };
+        assert!(WindowKind::from_config(&both).is_err());
+        assert!(duration_ms(&seconds(0)).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_registry::{
+    LoadBalanceStrategy, LoadBalancer, Locality, OutlierDetector, RegisteredService,
+    ServiceHealth, ServiceType, VersionStats, VERSION_LABEL,
+};
+use conveyor_etl_routing::ServiceSelector;
+
+use super::RouteDecision;
+
+/// One of the sidecars running a stage: this one, or another to forward to.
+#[derive(Debug, Clone)]
+pub struct StageInstance {
+    /// A `Local` or `Remote` route.
+    pub target: RouteDecision,
+    pub labels: HashMap<String, String>,
+}
+
+impl StageInstance {
+    /// Endpoint the instance is called at, which its load is tracked under.
+    pub fn endpoint(&self) -> &str {
+        match &self.target {
+            RouteDecision::Local { endpoint } | RouteDecision::Remote { endpoint, .. } => endpoint,
+            _ => "",
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self.target, RouteDecision::Local { .. })
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.labels.get(VERSION_LABEL).map(String::as_str)
+    }
+}
+
+/// Spreads a stage's records over its instances by the stage's traffic
+/// split and load balancing strategy, preferring instances in this
+/// sidecar's zone and passing over instances the outlier detector ejected.
+/// Tracks the calls in flight to each instance and their
+/// latency, which least connections and peak EWMA go by, and the outcome
+/// of calls to each version of a split service.
+pub struct StageBalancer {
+    // Holds no outlier detector: the routers take an admission for each
+    // call themselves, so selecting must not take one too.
+    balancer: LoadBalancer,
+    outlier_detector: Arc<OutlierDetector>,
+    locality: Option<Locality>,
+    /// Version outcomes not yet reported to the router.
+    unreported: Mutex<HashMap<(String, String), VersionStats>>,
+}
+
+impl StageBalancer {
+    pub fn new() -> Self {
+        Self::with_outlier_detector(Arc::new(OutlierDetector::default()))
+    }
+
+    /// Skips instances ejected by `outlier_detector`, which should be the
+    /// routers' detector.
+    pub fn with_outlier_detector(outlier_detector: Arc<OutlierDetector>) -> Self {
+        Self {
+            balancer: LoadBalancer::new(),
+            outlier_detector,
+            locality: None,
+            unreported: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Prefers instances in `locality`'s zone, falling back to the others
+    /// when none there can be picked.
+    pub fn with_locality(mut self, locality: Option<Locality>) -> Self {
+        self.locality = locality;
+        self
+    }
+
+    /// Groups `records` by the instance chosen for each, keeping their
+    /// order. A traffic split picks a version per record, by its key when
+    /// sticky, and falls back to every instance when none runs that
+    /// version; the zone is preferred among the version's instances.
+    /// Consistent hashing picks per record by its key; the other
+    /// strategies pick once for the whole batch when there is no split.
+    pub async fn assign<'a, T>(
+        &self,
+        service_name: &str,
+        selector: Option<&ServiceSelector>,
+        instances: &'a [StageInstance],
+        records: Vec<(T, Record)>,
+    ) -> Vec<(&'a StageInstance, Vec<(T, Record)>)> {
+        let mut available: Vec<&StageInstance> = instances
+            .iter()
+            .filter(|i| !self.outlier_detector.is_ejected(i.endpoint()))
+            .collect();
+        // With every instance ejected, keep sending somewhere rather than
+        // failing everything.
+        if available.is_empty() {
+            available = instances.iter().collect();
+        }
+        let services: Vec<RegisteredService> = available
+            .iter()
+            .map(|i| registered_service(service_name, i))
+            .collect();
+        let strategy = selector.map_or(LoadBalanceStrategy::RoundRobin, |s| strategy(s.load_balance));
+        let split = selector.and_then(|s| s.traffic_split.as_ref());
+
+        let mut batches: Vec<(&StageInstance, Vec<(T, Record)>)> = Vec::new();
+        if split.is_none() && strategy != LoadBalanceStrategy::ConsistentHash {
+            if let Some(instance) = self.select(&services, &available, strategy, None).await {
+                batches.push((instance, records));
+            }
+            return batches;
+        }
+
+        for (tag, record) in records {
+            let key = routing_key(&record);
+            let selected = self
+                .balancer
+                .select_with_split_and_locality(
+                    &services,
+                    strategy,
+                    key.as_deref(),
+                    split,
+                    self.locality.as_ref(),
+                )
+                .await;
+            let Some(instance) = selected.and_then(|s| find(&available, &s)) else {
+                continue;
+            };
+            match batches.iter_mut().find(|(i, _)| std::ptr::eq(*i, instance)) {
+                Some((_, batch)) => batch.push((tag, record)),
+                None => batches.push((instance, vec![(tag, record)])),
+            }
+        }
+        batches
+    }
+
+    /// Takes the queue depth the router last heard from the sidecar at
+    /// `endpoint`, which peak EWMA weighs its instances by.
+    pub fn update_load(&self, endpoint: &str, queue_depth: u64) {
+        self.balancer.update_load(endpoint, 0.0, queue_depth);
+    }
+
+    /// Counts a call to the instance as in flight until [`Self::finish`].
+    pub fn start(&self, instance: &StageInstance) -> Instant {
+        self.balancer.start_request(instance.endpoint())
+    }
+
+    /// Ends a call, recording its outcome against the instance's version.
+    pub fn finish(&self, service_name: &str, instance: &StageInstance, started: Instant, success: bool) {
+        self.balancer.finish_request(instance.endpoint(), started);
+        let Some(version) = instance.version() else {
+            return;
+        };
+        let latency = started.elapsed();
+        self.balancer
+            .record_version_outcome(service_name, version, success, latency);
+        self.unreported
+            .lock()
+            .unwrap()
+            .entry((service_name.to_string(), version.to_string()))
+            .or_default()
+            .record(success, latency);
+    }
+
+    /// Outcomes of calls to each version since the last call, by service
+    /// name and version.
+    pub fn take_version_stats(&self) -> Vec<(String, String, VersionStats)> {
+        std::mem::take(&mut *self.unreported.lock().unwrap())
+            .into_iter()
+            .map(|((service_name, version), stats)| (service_name, version, stats))
+            .collect()
+    }
+
+    /// Outcomes of every call to each version of `service_name`.
+    pub fn version_stats(&self, service_name: &str) -> Vec<(String, VersionStats)> {
+        self.balancer.version_stats(service_name)
+    }
+
+    async fn select<'a>(
+        &self,
+        services: &[RegisteredService],
+        available: &[&'a StageInstance],
+        strategy: LoadBalanceStrategy,
+        routing_key: Option<&str>,
+    ) -> Option<&'a StageInstance> {
+        let selected = self
+            .balancer
+            .select_with_locality(services, strategy, routing_key, self.locality.as_ref())
+            .await?;
+        find(available, &selected)
+    }
+}
+
+impl Default for StageBalancer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The instance as the registry's balancer sees it. Only the fields the
+/// balancer goes by are filled in.
+fn registered_service(service_name: &str, instance: &StageInstance) -> RegisteredService {
+    RegisteredService {
+        service_id: instance.endpoint().to_string(),
+        service_name: service_name.to_string(),
+        service_type: ServiceType::Transform,
+        endpoint: instance.endpoint().to_string(),
+        labels: instance.labels.clone(),
+        health: ServiceHealth::Healthy,
+        group_id: None,
+        registered_at: None,
+        last_heartbeat: None,
+        lease_duration: Duration::ZERO,
+    }
+}
+
+fn find<'a>(available: &[&'a StageInstance], selected: &RegisteredService) -> Option<&'a StageInstance> {
+    available
+        .iter()
+        .find(|i| i.endpoint() == selected.service_id)
+        .copied()
+}
+
+fn strategy(strategy: conveyor_etl_routing::LoadBalanceStrategy) -> LoadBalanceStrategy {
+    use conveyor_etl_routing::LoadBalanceStrategy as Selector;
+    match strategy {
+        Selector::RoundRobin => LoadBalanceStrategy::RoundRobin,
+        Selector::LeastConnections => LoadBalanceStrategy::LeastConnections,
+        Selector::WeightedRandom => LoadBalanceStrategy::WeightedRandom,
+        Selector::ConsistentHash => LoadBalanceStrategy::ConsistentHash,
+        Selector::PeakEwma => LoadBalanceStrategy::PeakEwma,
+    }
+}
+
+fn routing_key(record: &Record) -> Option<String> {
+    (!record.key.is_empty()).then(|| String::from_utf8_lossy(&record.key).into_owned())
+}
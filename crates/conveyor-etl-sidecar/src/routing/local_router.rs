@@ -1,33 +1,68 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
 use anyhow::{Result, Context};
+use dashmap::DashMap;
+use futures::future::join_all;
 use tonic::transport::Channel;
 use tracing::instrument;
 
 use conveyor_etl_proto::transform::{
     transform_service_client::TransformServiceClient,
-    ProcessBatchRequest, TransformStatus,
+    ProcessBatchRequest, TransformResult,
 };
 use conveyor_etl_proto::sink::{
     sink_service_client::SinkServiceClient,
-    WriteBatchRequest,
+    WriteBatchRequest, WriteBatchResponse, WriteStatus,
 };
-use conveyor_etl_proto::common::RecordBatch;
+use conveyor_etl_proto::lookup::{
+    lookup_service_client::LookupServiceClient,
+    BatchLookupRequest, LookupRequest, LookupResponse,
+};
+use conveyor_etl_proto::common::{Record, RecordBatch};
+use conveyor_etl_registry::{Admission, OutlierDetector};
 
 use super::ClientPool;
 
 pub struct LocalRouter {
     transform_clients: ClientPool<TransformServiceClient<Channel>>,
     sink_clients: ClientPool<SinkServiceClient<Channel>>,
+    lookup_clients: ClientPool<LookupServiceClient<Channel>>,
+    outlier_detector: Arc<OutlierDetector>,
+    in_flight: DashMap<String, u64>,
+}
+
+/// Counts a call to an endpoint as in flight until dropped.
+struct Active<'a> {
+    in_flight: &'a DashMap<String, u64>,
+    endpoint: &'a str,
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.in_flight.remove_if_mut(self.endpoint, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
 }
 
 impl LocalRouter {
     pub fn new() -> Self {
+        Self::with_outlier_detector(Arc::new(OutlierDetector::default()))
+    }
+
+    pub fn with_outlier_detector(outlier_detector: Arc<OutlierDetector>) -> Self {
         Self {
             transform_clients: ClientPool::new(),
             sink_clients: ClientPool::new(),
+            lookup_clients: ClientPool::new(),
+            outlier_detector,
+            in_flight: DashMap::new(),
         }
     }
 
+    /// Calls the transform, returning its result for each input record.
     #[instrument(skip(self, batch))]
     pub async fn route_to_transform(
         &self,
@@ -35,35 +70,30 @@ impl LocalRouter {
         transform_id: &str,
         batch: RecordBatch,
         config: HashMap<String, String>,
-    ) -> Result<Vec<RecordBatch>> {
-        let mut client = self.transform_clients
-            .get_or_create(endpoint, TransformServiceClient::new)
-            .await?;
-
-        let response = client
-            .process_batch(ProcessBatchRequest {
-                transform_id: transform_id.to_string(),
-                input_batch: Some(batch),
-                transform_config: config,
-            })
-            .await
-            .context("Transform call failed")?
-            .into_inner();
-
-        let output_records: Vec<_> = response
-            .results
-            .into_iter()
-            .filter(|r| r.status == TransformStatus::Success as i32)
-            .flat_map(|r| r.output_records)
-            .collect();
-
-        let output_batch = RecordBatch {
-            batch_id: format!("out-{}", chrono::Utc::now().timestamp_millis()),
-            records: output_records,
-            watermark: None,
+    ) -> Result<Vec<TransformResult>> {
+        let Some(admission) = self.outlier_detector.admit(endpoint) else {
+            anyhow::bail!("Endpoint {} is ejected", endpoint);
         };
 
-        Ok(vec![output_batch])
+        let _active = self.start(endpoint);
+        let started = Instant::now();
+        let result = async {
+            let mut client = self.transform_clients
+                .get_or_create(endpoint, TransformServiceClient::new)
+                .await?;
+
+            client
+                .process_batch(ProcessBatchRequest {
+                    transform_id: transform_id.to_string(),
+                    input_batch: Some(batch),
+                    transform_config: config,
+                })
+                .await
+                .context("Transform call failed")
+        }
+        .await;
+        record_outcome(admission, result.is_ok(), started);
+        Ok(result?.into_inner().results)
     }
 
     #[instrument(skip(self, batch))]
@@ -72,27 +102,120 @@ impl LocalRouter {
         endpoint: &str,
         sink_id: &str,
         batch: RecordBatch,
-    ) -> Result<bool> {
-        let mut client = self.sink_clients
-            .get_or_create(endpoint, SinkServiceClient::new)
-            .await?;
-
-        let response = client
-            .write_batch(WriteBatchRequest {
-                sink_id: sink_id.to_string(),
-                batch: Some(batch),
-                options: None,
-            })
-            .await
-            .context("Sink call failed")?
-            .into_inner();
-
-        let all_delivered = response
-            .results
-            .iter()
-            .all(|r| r.success);
-
-        Ok(all_delivered)
+    ) -> Result<WriteBatchResponse> {
+        let Some(admission) = self.outlier_detector.admit(endpoint) else {
+            anyhow::bail!("Endpoint {} is ejected", endpoint);
+        };
+
+        let _active = self.start(endpoint);
+        let started = Instant::now();
+        let result = async {
+            let mut client = self.sink_clients
+                .get_or_create(endpoint, SinkServiceClient::new)
+                .await?;
+
+            client
+                .write_batch(WriteBatchRequest {
+                    sink_id: sink_id.to_string(),
+                    batch: Some(batch),
+                    options: None,
+                })
+                .await
+                .context("Sink call failed")
+        }
+        .await;
+        // A sink that answers but fails the whole write is as unhealthy as
+        // one that does not answer.
+        let success = result
+            .as_ref()
+            .is_ok_and(|response| response.get_ref().status() != WriteStatus::Failed);
+        record_outcome(admission, success, started);
+        Ok(result?.into_inner())
+    }
+
+    /// Looks up the records' keys with one `BatchLookup` call, or a
+    /// `Lookup` call per record when `batch` is false.
+    #[instrument(skip(self, records, key_fields))]
+    pub async fn route_to_lookup(
+        &self,
+        endpoint: &str,
+        lookup_id: &str,
+        records: Vec<Record>,
+        key_fields: Vec<String>,
+        batch: bool,
+    ) -> Result<Vec<LookupResponse>> {
+        let Some(admission) = self.outlier_detector.admit(endpoint) else {
+            anyhow::bail!("Endpoint {} is ejected", endpoint);
+        };
+
+        let _active = self.start(endpoint);
+        let started = Instant::now();
+        let result = async {
+            let client = self.lookup_clients
+                .get_or_create(endpoint, LookupServiceClient::new)
+                .await?;
+
+            if batch {
+                let response = client
+                    .clone()
+                    .batch_lookup(BatchLookupRequest {
+                        lookup_id: lookup_id.to_string(),
+                        records,
+                        key_fields,
+                    })
+                    .await
+                    .context("Lookup call failed")?;
+                return Ok(response.into_inner().results);
+            }
+
+            let calls = records.into_iter().map(|record| {
+                let mut client = client.clone();
+                let request = LookupRequest {
+                    lookup_id: lookup_id.to_string(),
+                    input_record: Some(record),
+                    key_fields: key_fields.clone(),
+                };
+                async move { client.lookup(request).await.map(|r| r.into_inner()) }
+            });
+            join_all(calls)
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .context("Lookup call failed")
+        }
+        .await;
+        record_outcome(admission, result.is_ok(), started);
+        result
+    }
+
+    pub fn outlier_detector(&self) -> &Arc<OutlierDetector> {
+        &self.outlier_detector
+    }
+
+    /// Calls to the endpoint that have not returned yet.
+    pub fn in_flight(&self, endpoint: &str) -> u64 {
+        self.in_flight.get(endpoint).map_or(0, |count| *count)
+    }
+
+    /// Calls to every endpoint that have not returned yet.
+    pub fn total_in_flight(&self) -> u64 {
+        self.in_flight.iter().map(|count| *count).sum()
+    }
+
+    fn start<'a>(&'a self, endpoint: &'a str) -> Active<'a> {
+        *self.in_flight.entry(endpoint.to_string()).or_default() += 1;
+        Active {
+            in_flight: &self.in_flight,
+            endpoint,
+        }
+    }
+}
+
+fn record_outcome(admission: Admission<'_>, success: bool, started: Instant) {
+    if success {
+        admission.success(started.elapsed());
+    } else {
+        admission.failure();
     }
 }
 
@@ -1,9 +1,13 @@
+mod balancer;
 mod client_pool;
 mod local_router;
 mod remote_router;
 mod routing_table;
+mod stage_graph;
 
+pub use balancer::{StageBalancer, StageInstance};
 pub use client_pool::ClientPool;
 pub use local_router::LocalRouter;
 pub use remote_router::RemoteRouter;
 pub use routing_table::{PipelineRoutes, RouteDecision, RoutingTable, SharedRoutingTable, StageRoute};
+pub use stage_graph::StageGraph;
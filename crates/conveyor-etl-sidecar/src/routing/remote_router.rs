@@ -1,3 +1,5 @@
+use std::sync::Arc;
+use std::time::Instant;
 use anyhow::{Result, Context};
 use tonic::transport::Channel;
 use tracing::instrument;
@@ -5,22 +7,31 @@ use tracing::instrument;
 use conveyor_etl_proto::common::RecordBatch;
 use conveyor_etl_proto::sidecar::{
     sidecar_data_plane_client::SidecarDataPlaneClient,
-    ReceiveRecordsRequest,
+    ReceiveRecordsRequest, ReceiveRecordsResponse,
 };
+use conveyor_etl_registry::OutlierDetector;
 
 use super::ClientPool;
 
 pub struct RemoteRouter {
     sidecar_clients: ClientPool<SidecarDataPlaneClient<Channel>>,
+    outlier_detector: Arc<OutlierDetector>,
 }
 
 impl RemoteRouter {
     pub fn new() -> Self {
+        Self::with_outlier_detector(Arc::new(OutlierDetector::default()))
+    }
+
+    pub fn with_outlier_detector(outlier_detector: Arc<OutlierDetector>) -> Self {
         Self {
             sidecar_clients: ClientPool::new(),
+            outlier_detector,
         }
     }
 
+    /// Hands the batch to the sidecar running `stage_id`, which carries it
+    /// through the rest of the pipeline and acks each record.
     #[instrument(skip(self, batch), fields(target = %sidecar_endpoint))]
     pub async fn forward_to_sidecar(
         &self,
@@ -28,29 +39,50 @@ impl RemoteRouter {
         pipeline_id: &str,
         stage_id: &str,
         batch: RecordBatch,
-    ) -> Result<bool> {
-        let mut client = self.sidecar_clients
-            .get_or_create(sidecar_endpoint, SidecarDataPlaneClient::new)
-            .await?;
-
-        let response = client
-            .receive_records(ReceiveRecordsRequest {
-                pipeline_id: pipeline_id.to_string(),
-                stage_id: stage_id.to_string(),
-                batch: Some(batch),
-                source_sidecar_id: String::new(),
-            })
-            .await
-            .context("Forward to sidecar failed")?
-            .into_inner();
-
-        Ok(response.success)
+    ) -> Result<ReceiveRecordsResponse> {
+        let Some(admission) = self.outlier_detector.admit(sidecar_endpoint) else {
+            anyhow::bail!("Sidecar endpoint {} is ejected", sidecar_endpoint);
+        };
+
+        let started = Instant::now();
+        let result = async {
+            let mut client = self.sidecar_clients
+                .get_or_create(sidecar_endpoint, SidecarDataPlaneClient::new)
+                .await?;
+
+            client
+                .receive_records(ReceiveRecordsRequest {
+                    pipeline_id: pipeline_id.to_string(),
+                    stage_id: stage_id.to_string(),
+                    batch: Some(batch),
+                    source_sidecar_id: String::new(),
+                })
+                .await
+                .context("Forward to sidecar failed")
+        }
+        .await;
+
+        match &result {
+            Ok(_) => admission.success(started.elapsed()),
+            Err(_) => {
+                admission.failure();
+                if self.outlier_detector.is_ejected(sidecar_endpoint) {
+                    self.sidecar_clients.remove(sidecar_endpoint);
+                }
+            }
+        }
+
+        Ok(result?.into_inner())
     }
 
     pub fn remove_client(&self, endpoint: &str) {
         self.sidecar_clients.remove(endpoint);
     }
 
+    pub fn outlier_detector(&self) -> &Arc<OutlierDetector> {
+        &self.outlier_detector
+    }
+
     pub fn clear_clients(&self) {
         self.sidecar_clients.clear();
     }
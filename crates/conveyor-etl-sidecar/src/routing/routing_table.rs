@@ -1,7 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+use conveyor_etl_registry::OutlierDetector;
+
+use super::{StageGraph, StageInstance};
+use crate::operators::StatefulOperator;
+use crate::transforms::BuiltinTransform;
+
 #[derive(Debug, Clone)]
 pub enum RouteDecision {
     Local {
@@ -11,6 +17,54 @@ pub enum RouteDecision {
         sidecar_id: String,
         endpoint: String,
     },
+    /// Stage runs as a built-in transform inside this sidecar.
+    Builtin {
+        transform: Arc<BuiltinTransform>,
+    },
+    /// Stage runs as a stateful operator inside this sidecar.
+    Stateful {
+        operator: Arc<StatefulOperator>,
+    },
+    /// Stage is split across instances by its partition key; `owners[p]`
+    /// runs partition `p`, here or on another sidecar.
+    Partitioned {
+        owners: Vec<RouteDecision>,
+    },
+    /// Stage runs on several sidecars; each batch goes to the instance the
+    /// stage's load balancing picks.
+    Balanced {
+        instances: Vec<StageInstance>,
+    },
+}
+
+impl RouteDecision {
+    /// The owner of a partitioned stage's partitions that runs on this
+    /// sidecar, if any.
+    pub fn local_owner(owners: &[RouteDecision]) -> Option<&RouteDecision> {
+        owners.iter().find(|o| matches!(o, RouteDecision::Local { .. }))
+    }
+
+    /// Endpoints the stage is called at.
+    fn endpoints(&self, out: &mut HashSet<String>) {
+        match self {
+            RouteDecision::Local { endpoint } | RouteDecision::Remote { endpoint, .. } => {
+                if !endpoint.is_empty() {
+                    out.insert(endpoint.clone());
+                }
+            }
+            RouteDecision::Partitioned { owners } => {
+                for owner in owners {
+                    owner.endpoints(out);
+                }
+            }
+            RouteDecision::Balanced { instances } => {
+                for instance in instances {
+                    instance.target.endpoints(out);
+                }
+            }
+            RouteDecision::Builtin { .. } | RouteDecision::Stateful { .. } => {}
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -24,12 +78,30 @@ pub struct PipelineRoutes {
     pub pipeline_id: String,
     pub is_local_complete: bool,
     pub stages: HashMap<String, StageRoute>,
+    /// The pipeline's stages and edges. Batches cannot be routed without it.
+    pub graph: Option<Arc<StageGraph>>,
+}
+
+impl PipelineRoutes {
+    /// Route for a pipeline stage. Stages run by a service are assigned
+    /// under the service name rather than the stage id.
+    pub fn stage(&self, stage_id: &str) -> Option<&StageRoute> {
+        self.stages.get(stage_id).or_else(|| {
+            self.graph
+                .as_ref()
+                .and_then(|g| g.service_name(stage_id))
+                .and_then(|service| self.stages.get(service))
+        })
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct RoutingTable {
     pipelines: HashMap<String, PipelineRoutes>,
     local_services: HashMap<String, String>,
+    /// Local endpoints whose services failed health checks.
+    withdrawn: HashSet<String>,
+    outlier_detector: Option<Arc<OutlierDetector>>,
 }
 
 impl RoutingTable {
@@ -37,16 +109,44 @@ impl RoutingTable {
         Self::default()
     }
 
+    /// Keeps `outlier_detector`'s clusters in step with the routes: each
+    /// stage's endpoints are compared with each other, and endpoints no
+    /// stage calls any more are forgotten.
+    pub fn with_outlier_detector(mut self, outlier_detector: Arc<OutlierDetector>) -> Self {
+        self.outlier_detector = Some(outlier_detector);
+        self.sync_outlier_clusters();
+        self
+    }
+
+    fn sync_outlier_clusters(&self) {
+        let Some(detector) = &self.outlier_detector else {
+            return;
+        };
+        let mut clusters = HashMap::new();
+        for routes in self.pipelines.values() {
+            for stage in routes.stages.values() {
+                let mut endpoints = HashSet::new();
+                stage.decision.endpoints(&mut endpoints);
+                if !endpoints.is_empty() {
+                    clusters.insert(format!("{}/{}", routes.pipeline_id, stage.stage_id), endpoints);
+                }
+            }
+        }
+        detector.set_clusters(clusters);
+    }
+
     pub fn register_local_service(&mut self, service_name: String, endpoint: String) {
         self.local_services.insert(service_name, endpoint);
     }
 
     pub fn set_pipeline_routes(&mut self, routes: PipelineRoutes) {
         self.pipelines.insert(routes.pipeline_id.clone(), routes);
+        self.sync_outlier_clusters();
     }
 
     pub fn remove_pipeline(&mut self, pipeline_id: &str) {
         self.pipelines.remove(pipeline_id);
+        self.sync_outlier_clusters();
     }
 
     pub fn get_route(&self, pipeline_id: &str, stage_id: &str) -> Option<&RouteDecision> {
@@ -89,9 +189,102 @@ impl RoutingTable {
         self.pipelines.get(pipeline_id)
     }
 
+    /// The pipeline's routes without stages on withdrawn local endpoints,
+    /// so records for them are retried rather than sent to an unhealthy
+    /// service.
+    pub fn active_routes(&self, pipeline_id: &str) -> Option<PipelineRoutes> {
+        let mut routes = self.pipelines.get(pipeline_id)?.clone();
+        if !self.withdrawn.is_empty() {
+            let active = |decision: &RouteDecision| match decision {
+                RouteDecision::Local { endpoint } => !self.withdrawn.contains(endpoint),
+                _ => true,
+            };
+            routes.stages.retain(|_, stage| match &mut stage.decision {
+                RouteDecision::Partitioned { owners } => owners.iter().all(active),
+                RouteDecision::Balanced { instances } => {
+                    instances.retain(|i| active(&i.target));
+                    !instances.is_empty()
+                }
+                decision => active(decision),
+            });
+        }
+        Some(routes)
+    }
+
+    /// Withdraws routes to these local endpoints, restoring any others.
+    pub fn set_withdrawn_endpoints(&mut self, endpoints: HashSet<String>) {
+        self.withdrawn = endpoints;
+    }
+
+    pub fn is_withdrawn(&self, endpoint: &str) -> bool {
+        self.withdrawn.contains(endpoint)
+    }
+
     pub fn get_local_endpoint(&self, service_name: &str) -> Option<&String> {
         self.local_services.get(service_name)
     }
 }
 
 pub type SharedRoutingTable = Arc<RwLock<RoutingTable>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use conveyor_etl_registry::{CircuitState, OutlierDetectionConfig};
+
+    fn routes(pipeline_id: &str, stages: &[(&str, RouteDecision)]) -> PipelineRoutes {
+        PipelineRoutes {
+            pipeline_id: pipeline_id.to_string(),
+            is_local_complete: false,
+            stages: stages
+                .iter()
+                .map(|(stage_id, decision)| {
+                    let route = StageRoute {
+                        stage_id: stage_id.to_string(),
+                        decision: decision.clone(),
+                    };
+                    (stage_id.to_string(), route)
+                })
+                .collect(),
+            graph: None,
+        }
+    }
+
+    fn remote(endpoint: &str) -> RouteDecision {
+        RouteDecision::Remote {
+            sidecar_id: endpoint.to_string(),
+            endpoint: endpoint.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_outlier_detector_forgets_endpoints_no_stage_calls() {
+        let detector = Arc::new(OutlierDetector::new(OutlierDetectionConfig {
+            consecutive_failures: 1,
+            ..OutlierDetectionConfig::default()
+        }));
+        let mut table = RoutingTable::new().with_outlier_detector(detector.clone());
+        let balanced = RouteDecision::Balanced {
+            instances: ["a:1", "b:1"]
+                .into_iter()
+                .map(|endpoint| StageInstance {
+                    target: remote(endpoint),
+                    labels: HashMap::new(),
+                })
+                .collect(),
+        };
+        table.set_pipeline_routes(routes("p", &[("enrich", balanced)]));
+
+        detector.record_success("b:1", Duration::from_millis(1));
+        detector.record_failure("a:1");
+        assert_eq!(detector.state("a:1"), CircuitState::Open);
+
+        table.set_pipeline_routes(routes("p", &[("enrich", remote("b:1"))]));
+        assert_eq!(detector.state("a:1"), CircuitState::Closed);
+
+        detector.record_failure("b:1");
+        table.remove_pipeline("p");
+        assert_eq!(detector.state("b:1"), CircuitState::Closed);
+    }
+}
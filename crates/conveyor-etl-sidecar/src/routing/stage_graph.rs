@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_routing::{
+    CodecRegistry, FanInConfig, FanOutConfig, LookupConfig, PartitionKey, Pipeline, RoutedBatch,
+    RoutingPlan, ServiceSelector, StageType,
+};
+
+/// A pipeline's stages and edges, as sent by the router with an assignment.
+/// The data plane walks it to pass each stage's output on to the next.
+#[derive(Debug)]
+pub struct StageGraph {
+    pipeline: Pipeline,
+    plan: RoutingPlan,
+    codecs: Arc<CodecRegistry>,
+}
+
+impl StageGraph {
+    pub fn new(pipeline: Pipeline, codecs: Arc<CodecRegistry>) -> Self {
+        let plan = RoutingPlan::compile_with_codecs(&pipeline, codecs.clone());
+        Self { pipeline, plan, codecs }
+    }
+
+    /// Parses the JSON `Pipeline` carried in `PipelineAssignment.stage_graph`.
+    pub fn from_json(graph: &str, codecs: Arc<CodecRegistry>) -> Result<Self> {
+        let pipeline: Pipeline = serde_json::from_str(graph).context("Invalid stage graph")?;
+        Ok(Self::new(pipeline, codecs))
+    }
+
+    pub fn stage_type(&self, stage_id: &str) -> Option<StageType> {
+        self.pipeline.stages.get(stage_id).map(|s| s.stage_type)
+    }
+
+    /// Service the stage is routed to, for stages assigned by service name.
+    pub fn service_name(&self, stage_id: &str) -> Option<&str> {
+        self.pipeline
+            .stages
+            .get(stage_id)
+            .and_then(|s| s.service_selector.service_name.as_deref())
+    }
+
+    pub fn service_selector(&self, stage_id: &str) -> Option<&ServiceSelector> {
+        self.pipeline
+            .stages
+            .get(stage_id)
+            .map(|s| &s.service_selector)
+    }
+
+    pub fn lookup_config(&self, stage_id: &str) -> Option<&LookupConfig> {
+        self.pipeline
+            .stages
+            .get(stage_id)
+            .and_then(|s| s.lookup_config.as_ref())
+    }
+
+    pub fn fan_in_config(&self, stage_id: &str) -> Option<&FanInConfig> {
+        self.pipeline
+            .stages
+            .get(stage_id)
+            .and_then(|s| s.fan_in_config.as_ref())
+    }
+
+    pub fn fan_out_config(&self, stage_id: &str) -> Option<&FanOutConfig> {
+        self.pipeline
+            .stages
+            .get(stage_id)
+            .and_then(|s| s.fan_out_config.as_ref())
+    }
+
+    pub fn partition_key(&self, stage_id: &str) -> Option<&PartitionKey> {
+        self.pipeline
+            .stages
+            .get(stage_id)
+            .and_then(|s| s.partition_key.as_ref())
+    }
+
+    pub fn dead_letter_stage(&self) -> Option<&str> {
+        self.pipeline.dead_letter_stage.as_deref()
+    }
+
+    pub fn codecs(&self) -> &CodecRegistry {
+        &self.codecs
+    }
+
+    pub fn stage_count(&self) -> usize {
+        self.pipeline.stages.len()
+    }
+
+    pub fn has_edge(&self, from: &str, to: &str) -> bool {
+        self.plan.targets(from).iter().any(|t| t == to)
+    }
+
+    /// The source stage records pushed by `source_id` enter at: the source
+    /// stage with that id or service name, or the only source stage.
+    pub fn source_stage(&self, source_id: &str) -> Option<&str> {
+        let sources: Vec<_> = self
+            .pipeline
+            .stages
+            .values()
+            .filter(|s| s.stage_type == StageType::Source)
+            .collect();
+
+        sources
+            .iter()
+            .find(|s| s.id == source_id || s.service_selector.service_name.as_deref() == Some(source_id))
+            .or_else(|| (sources.len() == 1).then(|| &sources[0]))
+            .map(|s| s.id.as_str())
+    }
+
+    /// Sends records leaving `stage_id` along its matching edges.
+    pub fn route(&self, stage_id: &str, records: Vec<Record>) -> RoutedBatch {
+        self.plan.route(stage_id, records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conveyor_etl_routing::{Condition, LoadBalanceStrategy, RoutingMode, ServiceSelector, Stage};
+
+    fn stage(id: &str, stage_type: StageType) -> Stage {
+        Stage {
+            id: id.to_string(),
+            name: id.to_string(),
+            stage_type,
+            service_selector: ServiceSelector {
+                service_name: Some(format!("{}-svc", id)),
+                group_id: None,
+                labels: Default::default(),
+                load_balance: LoadBalanceStrategy::RoundRobin,
+                traffic_split: None,
+            },
+            parallelism: 1,
+            lookup_config: None,
+            fan_in_config: None,
+            fan_out_config: None,
+            routing_mode: RoutingMode::AllMatches,
+            partition_key: None,
+        }
+    }
+
+    #[test]
+    fn test_source_stage() {
+        let mut pipeline = Pipeline::new("p".to_string(), "p".to_string());
+        pipeline.add_stage(stage("orders", StageType::Source));
+        pipeline.add_stage(stage("sink", StageType::Sink));
+        pipeline.add_edge("orders", "sink", Some(Condition::RecordType("order".to_string())));
+
+        let json = serde_json::to_string(&pipeline).unwrap();
+        let graph = StageGraph::from_json(&json, Arc::new(CodecRegistry::new())).unwrap();
+        assert_eq!(graph.source_stage("orders-svc"), Some("orders"));
+        assert_eq!(graph.source_stage("unknown"), Some("orders"));
+        assert!(graph.has_edge("orders", "sink"));
+        assert_eq!(graph.service_name("sink"), Some("sink-svc"));
+
+        pipeline.add_stage(stage("refunds", StageType::Source));
+        let graph = StageGraph::new(pipeline, Arc::new(CodecRegistry::new()));
+        assert_eq!(graph.source_stage("refunds"), Some("refunds"));
+        assert_eq!(graph.source_stage("unknown"), None);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use tonic::transport::Channel;
+use tonic::Code;
+use tracing::{debug, warn};
+
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_proto::schema::{
+    schema_registry_client::SchemaRegistryClient, GetSchemaRequest,
+    SchemaFormat as ProtoSchemaFormat,
+};
+use conveyor_etl_routing::{RecordSchema, SchemaDefinition, SchemaFormat};
+
+/// Metadata key that pins a record to a specific schema version instead of
+/// the latest one.
+pub const SCHEMA_VERSION_KEY: &str = "schema-version";
+
+pub struct VersionedSchema {
+    pub record_type: String,
+    pub version: u32,
+    pub schema: RecordSchema,
+}
+
+struct CachedSchema {
+    schema: Option<Arc<VersionedSchema>>,
+    fetched_at: Instant,
+}
+
+/// Schemas fetched from the router's `SchemaRegistry`, keyed by record type
+/// and version. Misses are cached too, so record types without a schema do
+/// not hit the registry on every batch.
+pub struct SchemaCache {
+    client: SchemaRegistryClient<Channel>,
+    entries: DashMap<(String, u32), CachedSchema>,
+    ttl: Duration,
+}
+
+impl SchemaCache {
+    pub fn new(channel: Channel, ttl: Duration) -> Self {
+        Self {
+            client: SchemaRegistryClient::new(channel),
+            entries: DashMap::new(),
+            ttl,
+        }
+    }
+
+    pub async fn schema_for(&self, record: &Record) -> Result<Option<Arc<VersionedSchema>>> {
+        if record.record_type.is_empty() {
+            return Ok(None);
+        }
+        let version = match record.metadata.get(SCHEMA_VERSION_KEY) {
+            Some(v) => v
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid {} metadata: {}", SCHEMA_VERSION_KEY, v))?,
+            None => 0,
+        };
+        self.get(&record.record_type, version).await
+    }
+
+    /// Returns the schema for `record_type` at `version` (0 = latest). If the
+    /// registry cannot be reached, an expired entry keeps being served.
+    pub async fn get(&self, record_type: &str, version: u32) -> Result<Option<Arc<VersionedSchema>>> {
+        let key = (record_type.to_string(), version);
+        if let Some(entry) = self.entries.get(&key) {
+            if entry.fetched_at.elapsed() < self.ttl {
+                return Ok(entry.schema.clone());
+            }
+        }
+
+        match self.fetch(record_type, version).await {
+            Ok(schema) => {
+                self.entries.insert(
+                    key,
+                    CachedSchema {
+                        schema: schema.clone(),
+                        fetched_at: Instant::now(),
+                    },
+                );
+                Ok(schema)
+            }
+            Err(e) => match self.entries.get(&key) {
+                Some(entry) => {
+                    warn!(record_type, version, error = %e, "Schema refresh failed, using cached schema");
+                    Ok(entry.schema.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn fetch(&self, record_type: &str, version: u32) -> Result<Option<Arc<VersionedSchema>>> {
+        let mut client = self.client.clone();
+        let response = match client
+            .get_schema(GetSchemaRequest {
+                record_type: record_type.to_string(),
+                version,
+            })
+            .await
+        {
+            Ok(response) => response.into_inner(),
+            Err(status) if status.code() == Code::NotFound => return Ok(None),
+            Err(status) => return Err(anyhow!("Schema registry error: {}", status)),
+        };
+
+        let schema = response
+            .schema
+            .ok_or_else(|| anyhow!("Schema registry returned no schema for {}", record_type))?;
+        let format = match ProtoSchemaFormat::try_from(schema.format) {
+            Ok(ProtoSchemaFormat::JsonSchema) => SchemaFormat::JsonSchema,
+            Ok(ProtoSchemaFormat::Avro) => SchemaFormat::Avro,
+            Ok(ProtoSchemaFormat::Protobuf) => SchemaFormat::Protobuf,
+            _ => return Err(anyhow!("Unknown schema format for {} v{}", record_type, schema.version)),
+        };
+
+        let compiled = RecordSchema::compile(SchemaDefinition {
+            format,
+            definition: schema.definition,
+            message_name: (!schema.message_name.is_empty()).then_some(schema.message_name),
+        })
+        .with_context(|| format!("Invalid schema for {} v{}", record_type, schema.version))?;
+
+        debug!(record_type, version = schema.version, %format, "Fetched schema");
+
+        Ok(Some(Arc::new(VersionedSchema {
+            record_type: record_type.to_string(),
+            version: schema.version,
+            schema: compiled,
+        })))
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use conveyor_etl_dsl::{MaskStrategy, MaskTransformConfig};
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_routing::{CodecRegistry, FieldPath};
+
+use super::{rewrite_payload, TransformOutput};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const DEFAULT_REPLACEMENT: &str = "***";
+const PARTIAL_VISIBLE_CHARS: usize = 4;
+
+struct MaskField {
+    path: FieldPath,
+    strategy: MaskStrategy,
+    replacement: Option<String>,
+    preserve_length: bool,
+}
+
+pub(super) struct Mask {
+    fields: Vec<MaskField>,
+    token_key: Option<Arc<[u8]>>,
+}
+
+impl Mask {
+    pub(super) fn compile(config: &MaskTransformConfig, token_key: Option<Arc<[u8]>>) -> Result<Self> {
+        let fields = config
+            .fields
+            .iter()
+            .map(|f| {
+                if f.strategy == MaskStrategy::Tokenize && token_key.is_none() {
+                    anyhow::bail!(
+                        "tokenizing {} requires a token key (CONVEYOR_MASK_TOKEN_KEY)",
+                        f.field
+                    );
+                }
+                Ok(MaskField {
+                    path: FieldPath::parse(&f.field).map_err(|e| anyhow!("{}", e))?,
+                    strategy: f.strategy,
+                    replacement: f.replacement.clone(),
+                    preserve_length: f.preserve_length.unwrap_or(false),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { fields, token_key })
+    }
+
+    pub(super) fn apply(&self, record: Record, codecs: &CodecRegistry, output: &mut TransformOutput) {
+        rewrite_payload(record, codecs, output, |_, value| {
+            for field in &self.fields {
+                let Some(current) = field.path.get(value).filter(|v| !v.is_null()) else {
+                    continue;
+                };
+                let masked = self.mask(field, current);
+                field.path.set(value, masked).map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        });
+    }
+
+    fn mask(&self, field: &MaskField, value: &Value) -> Value {
+        let text = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        let mask_char = field
+            .replacement
+            .as_deref()
+            .and_then(|r| r.chars().next())
+            .unwrap_or('*');
+
+        let masked = match field.strategy {
+            MaskStrategy::Nullify => return Value::Null,
+            MaskStrategy::Redact if field.preserve_length => {
+                std::iter::repeat_n(mask_char, text.chars().count()).collect()
+            }
+            MaskStrategy::Redact => field
+                .replacement
+                .clone()
+                .unwrap_or_else(|| DEFAULT_REPLACEMENT.to_string()),
+            MaskStrategy::Partial => {
+                let len = text.chars().count();
+                let visible = if len > PARTIAL_VISIBLE_CHARS { PARTIAL_VISIBLE_CHARS } else { 0 };
+                text.chars()
+                    .enumerate()
+                    .map(|(i, c)| if i < len - visible { mask_char } else { c })
+                    .collect()
+            }
+            MaskStrategy::Hash => {
+                let mut digest = hex(&Sha256::digest(text.as_bytes()));
+                if field.preserve_length {
+                    digest.truncate(text.chars().count());
+                }
+                digest
+            }
+            MaskStrategy::Tokenize => {
+                let key = self.token_key.as_deref().expect("checked at compile time");
+                if field.preserve_length {
+                    tokenize_preserving_format(key, &text)
+                } else {
+                    format!("tok_{}", hex(&keystream(key, &text, 16)))
+                }
+            }
+        };
+        Value::String(masked)
+    }
+}
+
+/// Replaces every digit and ASCII letter with a keyed pseudo-random one of
+/// the same class, leaving separators in place.
+fn tokenize_preserving_format(key: &[u8], text: &str) -> String {
+    let stream = keystream(key, text, text.chars().count());
+    text.chars()
+        .zip(stream)
+        .map(|(c, b)| match c {
+            '0'..='9' => (b'0' + b % 10) as char,
+            'a'..='z' => (b'a' + b % 26) as char,
+            'A'..='Z' => (b'A' + b % 26) as char,
+            other => other,
+        })
+        .collect()
+}
+
+/// `len` bytes of HMAC-SHA256 output over `text`, extended with a block
+/// counter when more than one digest is needed.
+fn keystream(key: &[u8], text: &str, len: usize) -> Vec<u8> {
+    let mut stream = Vec::with_capacity(len);
+    let mut block = 0u32;
+    while stream.len() < len {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(&block.to_be_bytes());
+        mac.update(text.as_bytes());
+        stream.extend_from_slice(&mac.finalize().into_bytes());
+        block += 1;
+    }
+    stream.truncate(len);
+    stream
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+
+    use super::super::tests::{compile, compile_with, payload, record};
+    use super::super::TransformOptions;
+
+    fn with_key() -> TransformOptions {
+        TransformOptions {
+            token_key: Some(Arc::from(&b"test-key"[..])),
+            ..Default::default()
+        }
+    }
+
+    async fn mask_one(field: Value, options: &TransformOptions, input: Value) -> Value {
+        let transform = compile_with(
+            json!({"transform_type": "mask", "fields": [field]}),
+            options,
+        )
+        .unwrap();
+        let output = transform.apply(vec![record("r1", input)]).await;
+        payload(&output.records[0])
+    }
+
+    #[tokio::test]
+    async fn test_redact_partial_and_nullify() {
+        let transform = compile(json!({
+            "transform_type": "mask",
+            "fields": [
+                {"field": "password"},
+                {"field": "pin", "preserve_length": true, "replacement": "#"},
+                {"field": "card", "strategy": "partial"},
+                {"field": "ssn", "strategy": "nullify"},
+                {"field": "absent", "strategy": "redact"}
+            ]
+        }));
+        let output = transform
+            .apply(vec![record(
+                "r1",
+                json!({"password": "hunter2", "pin": "1234", "card": "4111111111111111", "ssn": "123"}),
+            )])
+            .await;
+
+        assert_eq!(
+            payload(&output.records[0]),
+            json!({
+                "password": "***",
+                "pin": "####",
+                "card": "************1111",
+                "ssn": null
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_is_deterministic() {
+        let options = TransformOptions::default();
+        let field = json!({"field": "email", "strategy": "hash"});
+
+        let first = mask_one(field.clone(), &options, json!({"email": "a@example.com"})).await;
+        let second = mask_one(field.clone(), &options, json!({"email": "a@example.com"})).await;
+        let other = mask_one(field, &options, json!({"email": "b@example.com"})).await;
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_eq!(first["email"].as_str().unwrap().len(), 64);
+
+        let short = mask_one(
+            json!({"field": "email", "strategy": "hash", "preserve_length": true}),
+            &options,
+            json!({"email": "abc"}),
+        )
+        .await;
+        assert_eq!(short["email"].as_str().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_tokenize() {
+        let field = json!({"field": "phone", "strategy": "tokenize", "preserve_length": true});
+        let input = json!({"phone": "555-0100 ext A"});
+
+        let token = mask_one(field.clone(), &with_key(), input.clone()).await;
+        let again = mask_one(field.clone(), &with_key(), input.clone()).await;
+        assert_eq!(token, again);
+
+        let token = token["phone"].as_str().unwrap().to_string();
+        assert_ne!(token, "555-0100 ext A");
+        assert_eq!(token.len(), "555-0100 ext A".len());
+        assert_eq!(&token[3..4], "-");
+        assert!(token[..3].chars().all(|c| c.is_ascii_digit()));
+        assert!(token[9..12].chars().all(|c| c.is_ascii_lowercase()));
+
+        let other_key = TransformOptions {
+            token_key: Some(Arc::from(&b"other-key"[..])),
+            ..Default::default()
+        };
+        assert_ne!(mask_one(field, &other_key, input.clone()).await["phone"], json!(token));
+
+        let opaque = mask_one(
+            json!({"field": "phone", "strategy": "tokenize"}),
+            &with_key(),
+            input,
+        )
+        .await;
+        assert!(opaque["phone"].as_str().unwrap().starts_with("tok_"));
+    }
+
+    #[test]
+    fn test_tokenize_requires_key() {
+        let config = json!({
+            "transform_type": "mask",
+            "fields": [{"field": "phone", "strategy": "tokenize"}]
+        });
+        assert!(compile_with(config, &TransformOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! Built-in stateless transforms that the sidecar runs in-process instead of
+//! calling out to a transform service.
+
+mod mask;
+mod stateless;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+
+use conveyor_etl_dsl::TransformConfigDsl;
+use conveyor_etl_proto::common::Record;
+use conveyor_etl_routing::CodecRegistry;
+
+use crate::operators::{OperatorOptions, StateStore};
+use crate::schema_cache::SchemaCache;
+use mask::Mask;
+use stateless::{Cast, Filter, FlatMap, MapFields, Project, Rename, Split, Validate};
+
+/// Output that `validate` sends records to when `on_invalid: route`.
+pub const INVALID_OUTPUT: &str = "invalid";
+
+/// Metadata key holding the validation error of a tagged or routed record.
+pub const VALIDATION_ERROR_KEY: &str = "_validation_error";
+
+/// Shared state that built-in transforms are compiled against.
+#[derive(Clone, Default)]
+pub struct TransformOptions {
+    pub codecs: Arc<CodecRegistry>,
+    /// Key for `tokenize` masking. Without one, tokenize configs are rejected.
+    pub token_key: Option<Arc<[u8]>>,
+    /// Resolves `validate` configs that name a registry subject.
+    pub schema_cache: Option<Arc<SchemaCache>>,
+    /// Holds the state of stateful operators. Without one, stateful
+    /// transform configs are rejected.
+    pub state_store: Option<Arc<StateStore>>,
+    pub operators: OperatorOptions,
+}
+
+#[derive(Debug, Default)]
+pub struct TransformOutput {
+    /// Records for the stage's default output.
+    pub records: Vec<Record>,
+    /// Records sent to a named output by `split` or `validate`.
+    pub routed: HashMap<String, Vec<Record>>,
+    /// Records the transform rejected, with the reason.
+    pub failed: Vec<(Record, String)>,
+}
+
+impl TransformOutput {
+    pub(crate) fn route(&mut self, output: &str, record: Record) {
+        self.routed.entry(output.to_string()).or_default().push(record);
+    }
+
+    pub(crate) fn fail(&mut self, record: Record, error: impl Into<String>) {
+        self.failed.push((record, error.into()));
+    }
+}
+
+enum TransformKind {
+    Filter(Filter),
+    Map(MapFields),
+    Project(Project),
+    Rename(Rename),
+    Cast(Cast),
+    Mask(Mask),
+    Validate(Validate),
+    FlatMap(FlatMap),
+    Split(Split),
+}
+
+/// A compiled stateless `TransformConfigDsl`. Stateful ones compile to a
+/// `StatefulOperator` instead.
+pub struct BuiltinTransform {
+    kind: TransformKind,
+    codecs: Arc<CodecRegistry>,
+}
+
+impl BuiltinTransform {
+    pub fn compile(config: &TransformConfigDsl, options: &TransformOptions) -> Result<Self> {
+        let kind = match config {
+            TransformConfigDsl::Filter(c) => TransformKind::Filter(Filter::compile(c)?),
+            TransformConfigDsl::Map(c) => TransformKind::Map(MapFields::compile(c)?),
+            TransformConfigDsl::Project(c) => TransformKind::Project(Project::compile(c)?),
+            TransformConfigDsl::Rename(c) => TransformKind::Rename(Rename::compile(c)?),
+            TransformConfigDsl::Cast(c) => TransformKind::Cast(Cast::compile(c)?),
+            TransformConfigDsl::Mask(c) => {
+                TransformKind::Mask(Mask::compile(c, options.token_key.clone())?)
+            }
+            TransformConfigDsl::Validate(c) => {
+                TransformKind::Validate(Validate::compile(c, options.schema_cache.clone())?)
+            }
+            TransformConfigDsl::FlatMap(c) => TransformKind::FlatMap(FlatMap::compile(c)?),
+            TransformConfigDsl::Split(c) => TransformKind::Split(Split::compile(c)?),
+            TransformConfigDsl::Dedupe(_)
+            | TransformConfigDsl::RateLimit(_)
+            | TransformConfigDsl::Aggregate(_)
+            | TransformConfigDsl::Join(_)
+            | TransformConfigDsl::Sessionize(_) => {
+                anyhow::bail!("stateful transforms run as a StatefulOperator")
+            }
+        };
+
+        Ok(Self {
+            kind,
+            codecs: options.codecs.clone(),
+        })
+    }
+
+    /// Compiles a transform from its JSON-encoded `TransformConfigDsl`, as
+    /// carried in stage assignments.
+    pub fn from_json(config: &str, options: &TransformOptions) -> Result<Self> {
+        let config: TransformConfigDsl =
+            serde_json::from_str(config).context("Invalid transform config")?;
+        Self::compile(&config, options)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match &self.kind {
+            TransformKind::Filter(_) => "filter",
+            TransformKind::Map(_) => "map",
+            TransformKind::Project(_) => "project",
+            TransformKind::Rename(_) => "rename",
+            TransformKind::Cast(_) => "cast",
+            TransformKind::Mask(_) => "mask",
+            TransformKind::Validate(_) => "validate",
+            TransformKind::FlatMap(_) => "flatmap",
+            TransformKind::Split(_) => "split",
+        }
+    }
+
+    pub async fn apply(&self, records: Vec<Record>) -> TransformOutput {
+        let mut output = TransformOutput::default();
+        let codecs = self.codecs.as_ref();
+
+        for record in records {
+            match &self.kind {
+                TransformKind::Filter(t) => t.apply(record, codecs, &mut output),
+                TransformKind::Map(t) => t.apply(record, codecs, &mut output),
+                TransformKind::Project(t) => t.apply(record, codecs, &mut output),
+                TransformKind::Rename(t) => t.apply(record, codecs, &mut output),
+                TransformKind::Cast(t) => t.apply(record, codecs, &mut output),
+                TransformKind::Mask(t) => t.apply(record, codecs, &mut output),
+                TransformKind::Validate(t) => t.apply(record, codecs, &mut output).await,
+                TransformKind::FlatMap(t) => t.apply(record, codecs, &mut output),
+                TransformKind::Split(t) => t.apply(record, codecs, &mut output),
+            }
+        }
+
+        output
+    }
+}
+
+impl fmt::Debug for BuiltinTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BuiltinTransform").field(&self.name()).finish()
+    }
+}
+
+pub(crate) fn decode(codecs: &CodecRegistry, record: &Record) -> Result<Value, String> {
+    if record.payload.is_empty() {
+        return Ok(Value::Object(Map::new()));
+    }
+    codecs
+        .decode(record)
+        .map_err(|e| format!("failed to decode payload: {:#}", e))
+}
+
+/// Decodes the record's payload, lets `f` rewrite it and re-encodes the
+/// result, failing the record if any step errors.
+fn rewrite_payload<F>(record: Record, codecs: &CodecRegistry, output: &mut TransformOutput, f: F)
+where
+    F: FnOnce(&Record, &mut Value) -> Result<(), String>,
+{
+    let mut record = record;
+    let result = decode(codecs, &record)
+        .and_then(|mut value| f(&record, &mut value).map(|()| value))
+        .and_then(|value| {
+            codecs
+                .encode(&mut record, &value)
+                .map_err(|e| format!("failed to encode payload: {:#}", e))
+        });
+
+    match result {
+        Ok(()) => output.records.push(record),
+        Err(e) => output.fail(record, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conveyor_etl_proto::common::RecordId;
+    use serde_json::json;
+
+    pub(super) fn record(id: &str, payload: Value) -> Record {
+        Record {
+            id: Some(RecordId {
+                source_id: id.to_string(),
+                ..Default::default()
+            }),
+            record_type: "event".to_string(),
+            payload: serde_json::to_vec(&payload).unwrap().into(),
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn id(record: &Record) -> &str {
+        record.id.as_ref().map(|id| id.source_id.as_str()).unwrap_or_default()
+    }
+
+    pub(super) fn payload(record: &Record) -> Value {
+        serde_json::from_slice(&record.payload).unwrap()
+    }
+
+    pub(super) fn compile(config: Value) -> BuiltinTransform {
+        compile_with(config, &TransformOptions::default()).unwrap()
+    }
+
+    pub(super) fn compile_with(config: Value, options: &TransformOptions) -> Result<BuiltinTransform> {
+        let config: TransformConfigDsl = serde_json::from_value(config).unwrap();
+        BuiltinTransform::compile(&config, options)
+    }
+
+    #[test]
+    fn test_from_json() {
+        let transform = BuiltinTransform::from_json(
+            r#"{"transform_type":"project","fields":["id"]}"#,
+            &TransformOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(transform.name(), "project");
+
+        assert!(BuiltinTransform::from_json("{", &TransformOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_stateful_transforms_are_rejected() {
+        let err = compile_with(
+            json!({
+                "transform_type": "ratelimit",
+                "max_rate": 10,
+                "window": {"seconds": 1}
+            }),
+            &TransformOptions::default(),
+        );
+        assert!(err.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_payload_fails_record() {
+        let transform = compile(json!({"transform_type": "project", "fields": ["id"]}));
+        let mut bad = record("r1", Value::Null);
+        bad.payload = b"not json".to_vec().into();
+
+        let output = transform.apply(vec![bad]).await;
+        assert!(output.records.is_empty());
+        assert_eq!(output.failed.len(), 1);
+        assert!(output.failed[0].1.contains("decode"));
+    }
+}
@@ -0,0 +1,648 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use conveyor_etl_dsl::{
+    convert_condition, convert_field_cast_type, CastTransformConfig, FilterTransformConfig,
+    FlatMapTransformConfig, MapTransformConfig, ProjectTransformConfig, RenameTransformConfig,
+    SplitTransformConfig, ValidateTransformConfig, ValidationAction,
+};
+use conveyor_etl_proto::common::{Record, RecordId};
+use conveyor_etl_routing::{
+    cast_value, CodecRegistry, Condition, Expression, FieldCastType, FieldPath, RecordSchema,
+    SchemaDefinition, SchemaFormat,
+};
+
+use super::{rewrite_payload, TransformOutput, INVALID_OUTPUT, VALIDATION_ERROR_KEY};
+use crate::schema_cache::SchemaCache;
+
+fn parse_path(path: &str) -> Result<FieldPath> {
+    FieldPath::parse(path).map_err(|e| anyhow!("{}", e))
+}
+
+pub(super) struct Filter {
+    condition: Condition,
+    negate: bool,
+}
+
+impl Filter {
+    pub(super) fn compile(config: &FilterTransformConfig) -> Result<Self> {
+        Ok(Self {
+            condition: convert_condition(&config.condition)?,
+            negate: config.negate,
+        })
+    }
+
+    pub(super) fn apply(&self, record: Record, codecs: &CodecRegistry, output: &mut TransformOutput) {
+        if self.condition.evaluate_with(&record, codecs) != self.negate {
+            output.records.push(record);
+        }
+    }
+}
+
+struct MapField {
+    target: FieldPath,
+    source: Option<FieldPath>,
+    expression: Option<Expression>,
+    default: Option<Value>,
+}
+
+pub(super) struct MapFields {
+    mappings: Vec<MapField>,
+    drop_unmapped: bool,
+}
+
+impl MapFields {
+    pub(super) fn compile(config: &MapTransformConfig) -> Result<Self> {
+        let mappings = config
+            .mappings
+            .iter()
+            .map(|m| {
+                if m.source.is_some() && m.expression.is_some() {
+                    anyhow::bail!("mapping for {} sets both source and expression", m.target);
+                }
+                Ok(MapField {
+                    target: parse_path(&m.target)?,
+                    source: m.source.as_deref().map(parse_path).transpose()?,
+                    expression: m
+                        .expression
+                        .as_deref()
+                        .map(Expression::compile)
+                        .transpose()
+                        .map_err(|e| anyhow!("mapping for {}: {}", m.target, e))?,
+                    default: m.default.clone(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            mappings,
+            drop_unmapped: config.drop_unmapped,
+        })
+    }
+
+    /// Sources and expressions read the incoming record, so mappings never
+    /// see each other's output.
+    pub(super) fn apply(&self, record: Record, codecs: &CodecRegistry, output: &mut TransformOutput) {
+        rewrite_payload(record, codecs, output, |record, value| {
+            let original = value.clone();
+            if self.drop_unmapped {
+                *value = Value::Object(Map::new());
+            }
+
+            for mapping in &self.mappings {
+                let mapped = if let Some(source) = &mapping.source {
+                    source.get(&original).cloned()
+                } else if let Some(expression) = &mapping.expression {
+                    match expression.evaluate_with(record, codecs) {
+                        Ok(v) => Some(Value::from(v)),
+                        Err(_) if mapping.default.is_some() => None,
+                        Err(e) => {
+                            return Err(format!("mapping for {}: {:#}", mapping.target, e))
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let mapped = match mapped {
+                    Some(v) if !v.is_null() => Some(v),
+                    _ => mapping.default.clone(),
+                };
+                if let Some(v) = mapped {
+                    mapping.target.set(value, v).map_err(|e| e.to_string())?;
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+pub(super) struct Project {
+    fields: Vec<FieldPath>,
+    exclude: bool,
+}
+
+impl Project {
+    pub(super) fn compile(config: &ProjectTransformConfig) -> Result<Self> {
+        Ok(Self {
+            fields: config.fields.iter().map(|f| parse_path(f)).collect::<Result<_>>()?,
+            exclude: config.exclude,
+        })
+    }
+
+    pub(super) fn apply(&self, record: Record, codecs: &CodecRegistry, output: &mut TransformOutput) {
+        rewrite_payload(record, codecs, output, |_, value| {
+            if self.exclude {
+                for field in &self.fields {
+                    field.remove(value);
+                }
+                return Ok(());
+            }
+
+            let mut projected = Value::Object(Map::new());
+            for field in &self.fields {
+                if let Some(v) = field.get(value) {
+                    field.set(&mut projected, v.clone()).map_err(|e| e.to_string())?;
+                }
+            }
+            *value = projected;
+            Ok(())
+        });
+    }
+}
+
+pub(super) struct Rename {
+    renames: Vec<(FieldPath, FieldPath)>,
+}
+
+impl Rename {
+    pub(super) fn compile(config: &RenameTransformConfig) -> Result<Self> {
+        let mut renames = config
+            .renames
+            .iter()
+            .map(|(from, to)| Ok((parse_path(from)?, parse_path(to)?)))
+            .collect::<Result<Vec<_>>>()?;
+        renames.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        Ok(Self { renames })
+    }
+
+    /// All sources are taken out before any target is written, so swapping
+    /// two fields works.
+    pub(super) fn apply(&self, record: Record, codecs: &CodecRegistry, output: &mut TransformOutput) {
+        rewrite_payload(record, codecs, output, |_, value| {
+            let moved: Vec<_> = self
+                .renames
+                .iter()
+                .filter_map(|(from, to)| from.remove(value).map(|v| (to, v)))
+                .collect();
+            for (to, v) in moved {
+                to.set(value, v).map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        });
+    }
+}
+
+pub(super) struct Cast {
+    casts: Vec<(FieldPath, FieldCastType)>,
+}
+
+impl Cast {
+    pub(super) fn compile(config: &CastTransformConfig) -> Result<Self> {
+        let mut casts = config
+            .casts
+            .iter()
+            .map(|(field, ty)| Ok((parse_path(field)?, convert_field_cast_type(*ty))))
+            .collect::<Result<Vec<_>>>()?;
+        casts.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        Ok(Self { casts })
+    }
+
+    pub(super) fn apply(&self, record: Record, codecs: &CodecRegistry, output: &mut TransformOutput) {
+        rewrite_payload(record, codecs, output, |_, value| {
+            for (field, cast) in &self.casts {
+                let Some(current) = field.get(value) else {
+                    continue;
+                };
+                let cast = cast_value(current.clone(), *cast)
+                    .map_err(|e| format!("field {}: {}", field, e))?;
+                field.set(value, cast).map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        });
+    }
+}
+
+enum SchemaSource {
+    Inline(Box<RecordSchema>),
+    Subject {
+        subject: String,
+        cache: Arc<SchemaCache>,
+    },
+}
+
+pub(super) struct Validate {
+    schema: SchemaSource,
+    on_invalid: ValidationAction,
+}
+
+impl Validate {
+    pub(super) fn compile(
+        config: &ValidateTransformConfig,
+        cache: Option<Arc<SchemaCache>>,
+    ) -> Result<Self> {
+        if config.schema_registry_url.is_some() {
+            anyhow::bail!("schema_registry_url is not supported; register the schema and use subject");
+        }
+
+        let schema = match (&config.schema, &config.subject) {
+            (Some(schema), None) => SchemaSource::Inline(Box::new(RecordSchema::compile(SchemaDefinition {
+                format: SchemaFormat::JsonSchema,
+                definition: schema.to_string(),
+                message_name: None,
+            })?)),
+            (None, Some(subject)) => SchemaSource::Subject {
+                subject: subject.clone(),
+                cache: cache.ok_or_else(|| anyhow!("validating against a subject needs a schema cache"))?,
+            },
+            (Some(_), Some(_)) => anyhow::bail!("validate sets both schema and subject"),
+            (None, None) => anyhow::bail!("validate needs a schema or subject"),
+        };
+
+        Ok(Self {
+            schema,
+            on_invalid: config.on_invalid,
+        })
+    }
+
+    /// Records are let through when the subject's schema cannot be fetched,
+    /// matching the data plane's own schema enforcement.
+    pub(super) async fn apply(&self, record: Record, codecs: &CodecRegistry, output: &mut TransformOutput) {
+        let result = match &self.schema {
+            SchemaSource::Inline(schema) => schema.validate_with(&record, codecs),
+            SchemaSource::Subject { subject, cache } => match cache.get(subject, 0).await {
+                Ok(Some(schema)) => schema.schema.validate_with(&record, codecs),
+                Ok(None) => Ok(()),
+                Err(e) => {
+                    warn!(subject = %subject, error = %e, "Schema lookup failed, skipping validation");
+                    Ok(())
+                }
+            },
+        };
+
+        let Err(error) = result else {
+            output.records.push(record);
+            return;
+        };
+
+        let mut record = record;
+        match self.on_invalid {
+            ValidationAction::Drop => {}
+            ValidationAction::Error => output.fail(record, error),
+            ValidationAction::Tag => {
+                record.metadata.insert(VALIDATION_ERROR_KEY.to_string(), error);
+                output.records.push(record);
+            }
+            ValidationAction::Route => {
+                record.metadata.insert(VALIDATION_ERROR_KEY.to_string(), error);
+                output.route(INVALID_OUTPUT, record);
+            }
+        }
+    }
+}
+
+/// The id of the `index`th element split from the record with `parent`.
+fn element_id(parent: &RecordId, index: usize) -> RecordId {
+    let mut key = if parent.idempotency_key.is_empty() {
+        format!("{}:{}:{}", parent.source_id, parent.partition, parent.sequence_number).into_bytes()
+    } else {
+        parent.idempotency_key.clone()
+    };
+    key.extend_from_slice(format!("#{}", index).as_bytes());
+    RecordId {
+        idempotency_key: key,
+        ..parent.clone()
+    }
+}
+
+pub(super) struct FlatMap {
+    field: FieldPath,
+    target: Option<FieldPath>,
+    keep_parent: bool,
+}
+
+impl FlatMap {
+    pub(super) fn compile(config: &FlatMapTransformConfig) -> Result<Self> {
+        Ok(Self {
+            field: parse_path(&config.field)?,
+            target: config.target_field.as_deref().map(parse_path).transpose()?,
+            keep_parent: config.keep_parent,
+        })
+    }
+
+    /// Emits one record per element of the array field. A missing or null
+    /// field emits nothing; any other value is treated as a single element.
+    /// Each element keeps the parent's source position, so it is replayed
+    /// with it, and gets its own idempotency key.
+    pub(super) fn apply(&self, record: Record, codecs: &CodecRegistry, output: &mut TransformOutput) {
+        let mut parent = match super::decode(codecs, &record) {
+            Ok(value) => value,
+            Err(e) => {
+                output.fail(record, e);
+                return;
+            }
+        };
+
+        let elements = match self.field.remove(&mut parent) {
+            None | Some(Value::Null) => return,
+            Some(Value::Array(items)) => items,
+            Some(other) => vec![other],
+        };
+
+        for (index, element) in elements.into_iter().enumerate() {
+            let payload = if self.keep_parent {
+                let mut payload = parent.clone();
+                let target = self.target.as_ref().unwrap_or(&self.field);
+                target.set(&mut payload, element).map(|()| payload)
+            } else if let Some(target) = &self.target {
+                let mut payload = Value::Object(Map::new());
+                target.set(&mut payload, element).map(|()| payload)
+            } else {
+                Ok(element)
+            };
+
+            let mut child = record.clone();
+            child.id = record.id.as_ref().map(|id| element_id(id, index));
+            let result = payload
+                .map_err(|e| e.to_string())
+                .and_then(|payload| {
+                    codecs
+                        .encode(&mut child, &payload)
+                        .map_err(|e| format!("failed to encode payload: {:#}", e))
+                });
+            match result {
+                Ok(()) => output.records.push(child),
+                Err(e) => output.fail(child, e),
+            }
+        }
+    }
+}
+
+pub(super) struct Split {
+    routes: Vec<(Condition, String)>,
+    default_output: Option<String>,
+}
+
+impl Split {
+    pub(super) fn compile(config: &SplitTransformConfig) -> Result<Self> {
+        Ok(Self {
+            routes: config
+                .routes
+                .iter()
+                .map(|r| Ok((convert_condition(&r.condition)?, r.output.clone())))
+                .collect::<Result<_>>()?,
+            default_output: config.default_output.clone(),
+        })
+    }
+
+    /// The first matching route wins. Unmatched records go to the default
+    /// output, or the stage's main output when none is set.
+    pub(super) fn apply(&self, record: Record, codecs: &CodecRegistry, output: &mut TransformOutput) {
+        let matched = self
+            .routes
+            .iter()
+            .find(|(condition, _)| condition.evaluate_with(&record, codecs))
+            .map(|(_, name)| name)
+            .or(self.default_output.as_ref());
+
+        match matched {
+            Some(name) => output.route(name, record),
+            None => output.records.push(record),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::tests::{compile, compile_with, id, payload, record};
+    use super::super::{TransformOptions, INVALID_OUTPUT, VALIDATION_ERROR_KEY};
+
+    #[tokio::test]
+    async fn test_filter() {
+        let transform = compile(json!({
+            "transform_type": "filter",
+            "condition": {"expr": "payload.amount > 100"}
+        }));
+        let output = transform
+            .apply(vec![
+                record("small", json!({"amount": 5})),
+                record("large", json!({"amount": 500})),
+            ])
+            .await;
+        let ids: Vec<_> = output.records.iter().map(id).collect();
+        assert_eq!(ids, vec!["large"]);
+
+        let negated = compile(json!({
+            "transform_type": "filter",
+            "condition": {"record_type": "event"},
+            "negate": true
+        }));
+        assert!(negated.apply(vec![record("r1", json!({}))]).await.records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_map() {
+        let transform = compile(json!({
+            "transform_type": "map",
+            "drop_unmapped": true,
+            "mappings": [
+                {"target": "customer.id", "source": "user_id"},
+                {"target": "total", "expression": "payload.price * payload.qty"},
+                {"target": "region", "source": "geo.region", "default": "unknown"},
+                {"target": "ignored", "source": "missing"}
+            ]
+        }));
+        let output = transform
+            .apply(vec![record("r1", json!({"user_id": 7, "price": 2.5, "qty": 4, "extra": true}))])
+            .await;
+
+        assert_eq!(
+            payload(&output.records[0]),
+            json!({"customer": {"id": 7}, "total": 10.0, "region": "unknown"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_map_keeps_unmapped_fields_and_fails_bad_expressions() {
+        let transform = compile(json!({
+            "transform_type": "map",
+            "mappings": [{"target": "upper", "expression": "payload.name + 1"}]
+        }));
+        let output = transform.apply(vec![record("r1", json!({"name": "x"}))]).await;
+        assert!(output.records.is_empty());
+        assert!(output.failed[0].1.contains("upper"));
+
+        let transform = compile(json!({
+            "transform_type": "map",
+            "mappings": [{"target": "copy", "source": "name"}]
+        }));
+        let output = transform.apply(vec![record("r1", json!({"name": "x"}))]).await;
+        assert_eq!(payload(&output.records[0]), json!({"name": "x", "copy": "x"}));
+
+        assert!(compile_with(
+            json!({
+                "transform_type": "map",
+                "mappings": [{"target": "a", "source": "b", "expression": "1"}]
+            }),
+            &TransformOptions::default(),
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_project() {
+        let input = json!({"id": 1, "user": {"name": "a", "email": "a@x"}, "debug": true});
+
+        let keep = compile(json!({
+            "transform_type": "project",
+            "fields": ["id", "user.name"]
+        }));
+        let output = keep.apply(vec![record("r1", input.clone())]).await;
+        assert_eq!(payload(&output.records[0]), json!({"id": 1, "user": {"name": "a"}}));
+
+        let exclude = compile(json!({
+            "transform_type": "project",
+            "fields": ["debug", "user.email"],
+            "exclude": true
+        }));
+        let output = exclude.apply(vec![record("r1", input)]).await;
+        assert_eq!(payload(&output.records[0]), json!({"id": 1, "user": {"name": "a"}}));
+    }
+
+    #[tokio::test]
+    async fn test_rename() {
+        let transform = compile(json!({
+            "transform_type": "rename",
+            "renames": {"a": "b", "b": "a", "user.mail": "email"}
+        }));
+        let output = transform
+            .apply(vec![record("r1", json!({"a": 1, "b": 2, "user": {"mail": "m"}}))])
+            .await;
+        assert_eq!(
+            payload(&output.records[0]),
+            json!({"a": 2, "b": 1, "user": {}, "email": "m"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cast() {
+        let transform = compile(json!({
+            "transform_type": "cast",
+            "casts": {"count": "int", "active": "bool", "missing": "float"}
+        }));
+        let output = transform
+            .apply(vec![
+                record("ok", json!({"count": "42", "active": "true"})),
+                record("bad", json!({"count": "many"})),
+            ])
+            .await;
+
+        assert_eq!(payload(&output.records[0]), json!({"count": 42, "active": true}));
+        assert_eq!(output.failed.len(), 1);
+        assert_eq!(id(&output.failed[0].0), "bad");
+        assert!(output.failed[0].1.contains("count"));
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "required": ["id"],
+            "properties": {"id": {"type": "integer"}}
+        });
+        let inputs = || vec![record("good", json!({"id": 1})), record("bad", json!({"id": "x"}))];
+        let validate = |on_invalid: &str| {
+            compile(json!({
+                "transform_type": "validate",
+                "schema": schema.clone(),
+                "on_invalid": on_invalid
+            }))
+        };
+
+        let output = validate("drop").apply(inputs()).await;
+        assert_eq!(output.records.len(), 1);
+        assert!(output.failed.is_empty());
+
+        let output = validate("error").apply(inputs()).await;
+        assert_eq!(output.records.len(), 1);
+        assert_eq!(id(&output.failed[0].0), "bad");
+
+        let output = validate("tag").apply(inputs()).await;
+        assert_eq!(output.records.len(), 2);
+        assert!(output.records[1].metadata.contains_key(VALIDATION_ERROR_KEY));
+
+        let output = validate("route").apply(inputs()).await;
+        assert_eq!(output.records.len(), 1);
+        assert_eq!(id(&output.routed[INVALID_OUTPUT][0]), "bad");
+    }
+
+    #[test]
+    fn test_validate_needs_a_usable_schema_source() {
+        let options = TransformOptions::default();
+        for config in [
+            json!({"transform_type": "validate"}),
+            json!({"transform_type": "validate", "subject": "orders"}),
+            json!({"transform_type": "validate", "schema_registry_url": "http://registry"}),
+            json!({"transform_type": "validate", "schema": {"type": 3}}),
+        ] {
+            assert!(compile_with(config, &options).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flatmap() {
+        let input = json!({"order": 9, "items": [{"sku": "a"}, {"sku": "b"}]});
+
+        let elements = compile(json!({"transform_type": "flatmap", "field": "items"}));
+        let output = elements.apply(vec![record("r1", input.clone())]).await;
+        let payloads: Vec<_> = output.records.iter().map(payload).collect();
+        assert_eq!(payloads, vec![json!({"sku": "a"}), json!({"sku": "b"})]);
+        assert!(output.records.iter().all(|r| id(r) == "r1"));
+
+        let wrapped = compile(json!({
+            "transform_type": "flatmap",
+            "field": "items",
+            "target_field": "item"
+        }));
+        let output = wrapped.apply(vec![record("r1", input.clone())]).await;
+        assert_eq!(payload(&output.records[1]), json!({"item": {"sku": "b"}}));
+
+        let with_parent = compile(json!({
+            "transform_type": "flatmap",
+            "field": "items",
+            "target_field": "item",
+            "keep_parent": true
+        }));
+        let output = with_parent.apply(vec![record("r1", input)]).await;
+        assert_eq!(payload(&output.records[0]), json!({"order": 9, "item": {"sku": "a"}}));
+
+        let output = elements
+            .apply(vec![record("none", json!({"items": null})), record("one", json!({"items": 5}))])
+            .await;
+        assert_eq!(output.records.len(), 1);
+        assert_eq!(payload(&output.records[0]), json!(5));
+    }
+
+    #[tokio::test]
+    async fn test_split() {
+        let routes = json!([
+            {"condition": {"expr": "payload.amount > 1000"}, "output": "large"},
+            {"condition": {"expr": "payload.amount > 100"}, "output": "medium"}
+        ]);
+        let transform = compile(json!({
+            "transform_type": "split",
+            "routes": routes.clone(),
+            "default_output": "small"
+        }));
+        let output = transform
+            .apply(vec![
+                record("a", json!({"amount": 5000})),
+                record("b", json!({"amount": 500})),
+                record("c", json!({"amount": 5})),
+            ])
+            .await;
+        assert!(output.records.is_empty());
+        assert_eq!(id(&output.routed["large"][0]), "a");
+        assert_eq!(id(&output.routed["medium"][0]), "b");
+        assert_eq!(id(&output.routed["small"][0]), "c");
+
+        let no_default = compile(json!({"transform_type": "split", "routes": routes}));
+        let output = no_default.apply(vec![record("c", json!({"amount": 5}))]).await;
+        assert_eq!(id(&output.records[0]), "c");
+    }
+}